@@ -0,0 +1,115 @@
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
+use serde::Serialize;
+use tracing::debug;
+
+/// The suffix of a recorded response body
+const RESPONSE_SUFFIX: &str = ".response";
+/// The suffix of the empty file recording that the server had no such file
+const MISSING_SUFFIX: &str = ".missing";
+
+/// Whether a cassette records the responses of the servers or plays them back
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CassetteMode {
+    /// Download as usual and record every response
+    Record,
+    /// Only play recorded responses back, without any requests. Downloads which were
+    /// not recorded fail
+    #[default]
+    Replay,
+}
+
+/// Recorded responses of the servers, like the cassettes of VCR
+///
+/// A run against the OSM sandbox API at `master.apis.dev.openstreetmap.org` with the
+/// `sandbox` profile can be recorded once and played back afterwards, so checks of the
+/// code talking to the API run offline and never touch the production servers.
+///
+/// Every URL is stored as one file in the directory, named after the URL, so cassettes
+/// can be reviewed and committed next to the setup they were recorded with.
+#[derive(Debug, Clone)]
+pub struct Cassette {
+    pub directory: PathBuf,
+    pub mode: CassetteMode,
+}
+
+impl Cassette {
+    /// The path of the recording of a URL, without its suffix
+    fn path(&self, url: &str) -> PathBuf {
+        let name = url
+            .split_once("://")
+            .map_or(url, |(_, rest)| rest)
+            .trim_end_matches('/')
+            .split('/')
+            .filter(|segment| !segment.is_empty() && *segment != "." && *segment != "..")
+            .map(|segment| {
+                segment
+                    .chars()
+                    .map(|c| match c {
+                        'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+                        _ => '_',
+                    })
+                    .collect::<String>()
+            })
+            .collect::<Vec<String>>();
+        name.iter()
+            .fold(self.directory.clone(), |path, segment| path.join(segment))
+    }
+
+    /// The recorded response of a URL
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Bytes>>` - `None` if the server had no such file
+    pub fn play(&self, url: &str) -> Result<Option<Bytes>> {
+        let path = self.path(url);
+        let response = with_suffix(&path, RESPONSE_SUFFIX);
+        if response.exists() {
+            debug!("Playing back {} from {}", url, response.display());
+            return Ok(Some(Bytes::from(std::fs::read(response)?)));
+        }
+        if with_suffix(&path, MISSING_SUFFIX).exists() {
+            return Ok(None);
+        }
+        Err(eyre!(
+            "The cassette {} has no recording of {}",
+            self.directory.display(),
+            url
+        ))
+    }
+
+    /// Record the response of a URL, `None` if the server had no such file
+    pub fn record(&self, url: &str, data: Option<&Bytes>) -> Result<()> {
+        let path = self.path(url);
+        std::fs::create_dir_all(path.parent().unwrap_or(&self.directory))?;
+        let (recording, outdated) = match data {
+            Some(_) => (
+                with_suffix(&path, RESPONSE_SUFFIX),
+                with_suffix(&path, MISSING_SUFFIX),
+            ),
+            None => (
+                with_suffix(&path, MISSING_SUFFIX),
+                with_suffix(&path, RESPONSE_SUFFIX),
+            ),
+        };
+        std::fs::write(
+            &recording,
+            data.map(|data| data.as_ref()).unwrap_or_default(),
+        )?;
+        if outdated.exists() {
+            std::fs::remove_file(outdated)?;
+        }
+        debug!("Recorded {} to {}", url, recording.display());
+        Ok(())
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
@@ -0,0 +1,131 @@
+#[cfg(feature = "http")]
+use std::ffi::OsString;
+use std::fmt::Display;
+
+#[cfg(feature = "http")]
+use clap::ArgAction;
+use clap::{Arg, Command};
+use color_eyre::eyre::{eyre, Result};
+use serde::Serializer;
+use serde_yaml::Value;
+
+/// Use the values of a YAML config file as defaults for the options
+///
+/// The config file is a mapping of option names (in snake_case, as printed by
+/// `--dump-config`) to values. Options of subcommands are set for every subcommand which
+/// has them. As the values only become defaults, explicitly passed flags override the
+/// file.
+pub fn apply_config_file(mut command: Command, config_path: &str) -> Result<Command> {
+    let config_file = std::fs::read_to_string(config_path)?;
+    let config: Value = serde_yaml::from_str(&config_file)?;
+    let Value::Mapping(config) = config else {
+        return Err(eyre!("Config file {} must be a mapping", config_path));
+    };
+
+    for (key, value) in config {
+        let key = key
+            .as_str()
+            .ok_or_else(|| eyre!("Invalid key {:?} in config file", key))?;
+        // Loading a config file from a config file is not supported
+        if key == "config" {
+            continue;
+        }
+        let values = value_strings(key, value)?;
+        if values.is_empty() {
+            continue;
+        }
+
+        let long_name = key.replace('_', "-");
+        let mut found = false;
+        if let Some(argument) = find_argument(&command, &long_name) {
+            let id = argument.get_id().clone();
+            command = command.mut_arg(id, |argument| argument.default_values(values.clone()));
+            found = true;
+        }
+        let subcommands = command
+            .get_subcommands()
+            .filter_map(|subcommand| {
+                find_argument(subcommand, &long_name)
+                    .map(|argument| (subcommand.get_name().to_string(), argument.get_id().clone()))
+            })
+            .collect::<Vec<_>>();
+        for (subcommand, id) in subcommands {
+            command = command.mut_subcommand(subcommand, |subcommand| {
+                subcommand.mut_arg(id, |argument| argument.default_values(values.clone()))
+            });
+            found = true;
+        }
+        if !found {
+            return Err(eyre!("Unknown option {} in config file", key));
+        }
+    }
+    Ok(command)
+}
+
+/// Convert a mapping of option names to values into command line arguments
+///
+/// This is the inverse of `--dump-config`. Options without a value are left out.
+#[cfg(feature = "http")]
+pub fn config_args(command: &Command, config: Value) -> Result<Vec<OsString>> {
+    let Value::Mapping(config) = config else {
+        return Err(eyre!("The config must be a mapping"));
+    };
+
+    let mut args = Vec::new();
+    for (key, value) in config {
+        let key = key
+            .as_str()
+            .ok_or_else(|| eyre!("Invalid key {:?} in config", key))?;
+        let long_name = key.replace('_', "-");
+        let argument = find_argument(command, &long_name)
+            .ok_or_else(|| eyre!("Unknown option {} in config", key))?;
+
+        for value in value_strings(key, value)? {
+            if matches!(argument.get_action(), ArgAction::SetTrue) {
+                // Plain flags don't take a value
+                if value == "true" {
+                    args.push(OsString::from(format!("--{}", long_name)));
+                }
+            } else {
+                args.push(OsString::from(format!("--{}={}", long_name, value)));
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Serialize an option in the format it is passed on the command line, so values like
+/// bounding boxes can be read back from a config file
+pub fn serialize_display<S: Serializer, T: Display>(
+    value: &Option<T>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.collect_str(value),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn find_argument<'a>(command: &'a Command, long_name: &str) -> Option<&'a Arg> {
+    command
+        .get_arguments()
+        .find(|argument| argument.get_long() == Some(long_name))
+}
+
+/// The values of an option as strings. Options without a value have none.
+fn value_strings(key: &str, value: Value) -> Result<Vec<String>> {
+    let values = match value {
+        Value::Null => return Ok(Vec::new()),
+        Value::Sequence(values) => values,
+        value => vec![value],
+    };
+    values
+        .into_iter()
+        .map(|value| match value {
+            Value::Bool(value) => Ok(value.to_string()),
+            Value::Number(value) => Ok(value.to_string()),
+            Value::String(value) => Ok(value),
+            value => Err(eyre!("Unsupported value {:?} for {}", value, key)),
+        })
+        .collect()
+}
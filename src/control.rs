@@ -0,0 +1,293 @@
+use std::{
+    fmt,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use clap::Subcommand;
+use color_eyre::eyre::{eyre, Result};
+use serde::{Serialize, Serializer};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, Notify},
+};
+use tracing::{info, warn};
+
+use crate::{polite::OffPeakWindow, tuning::TuningBounds, watch::Watchlist};
+
+/// The settings of a running replay which can be reloaded without restarting it
+pub struct RuntimeSettings {
+    pub wait_time: Duration,
+    pub follow: Option<Duration>,
+    pub tuning: TuningBounds,
+    pub off_peak: Option<OffPeakWindow>,
+    pub watchlist: Option<Watchlist>,
+}
+
+/// Whether a replay applies sequences
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RunState {
+    #[default]
+    Running,
+    Paused,
+    /// Apply this many more sequences, then pause
+    Stepping(u32),
+}
+
+impl fmt::Display for RunState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunState::Running => write!(f, "running"),
+            RunState::Paused => write!(f, "paused"),
+            RunState::Stepping(remaining) => write!(f, "stepping ({} left)", remaining),
+        }
+    }
+}
+
+impl Serialize for RunState {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// What a running replay reports to `osm-git ctl status`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReplayStatus {
+    pub state: RunState,
+    /// The last sequence which was applied
+    pub last_sequence: Option<String>,
+    /// The number of sequences applied since the replay started
+    pub sequences_applied: u64,
+    /// The number of commits created since the replay started
+    pub commits_created: u64,
+    /// How often the settings were reloaded
+    pub reloads: u64,
+}
+
+/// State shared between a running replay and the ways to control it
+#[derive(Default)]
+pub struct ReplayControl {
+    /// Reloaded settings which the replay did not pick up yet
+    reloaded: Mutex<Option<RuntimeSettings>>,
+    status: Mutex<ReplayStatus>,
+    /// Wakes the replay when it waits while paused
+    state_changed: Notify,
+    shutdown_requested: AtomicBool,
+    /// Wakes the replay when it sleeps or waits while a shutdown is requested
+    shutdown: Notify,
+}
+
+impl ReplayControl {
+    /// Hand reloaded settings to the replay. It picks them up before the next file
+    pub fn reload(&self, settings: RuntimeSettings) {
+        *self.reloaded.lock().unwrap() = Some(settings);
+        self.status.lock().unwrap().reloads += 1;
+    }
+
+    /// The settings reloaded since the last call, if any
+    pub fn take_reloaded(&self) -> Option<RuntimeSettings> {
+        self.reloaded.lock().unwrap().take()
+    }
+
+    pub fn update_status(&self, update: impl FnOnce(&mut ReplayStatus)) {
+        update(&mut self.status.lock().unwrap());
+    }
+
+    pub fn status(&self) -> ReplayStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn set_state(&self, state: RunState) {
+        self.status.lock().unwrap().state = state;
+        self.state_changed.notify_waiters();
+    }
+
+    /// Stop before the next sequence. The sequence which is being applied is finished
+    pub fn pause(&self) {
+        self.set_state(RunState::Paused);
+    }
+
+    pub fn resume(&self) {
+        self.set_state(RunState::Running);
+    }
+
+    /// Apply the given number of sequences, then pause again
+    pub fn step(&self, sequences: u32) {
+        self.set_state(match sequences {
+            0 => RunState::Paused,
+            sequences => RunState::Stepping(sequences),
+        });
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.status.lock().unwrap().state == RunState::Paused
+    }
+
+    /// Wait until the replay is resumed or stepped, or a shutdown is requested
+    pub async fn wait_while_paused(&self) {
+        loop {
+            // Registered before checking the state, so a change in between is not missed
+            let state_changed = self.state_changed.notified();
+            let shutdown = self.shutdown.notified();
+            if !self.is_paused() || self.shutdown_requested() {
+                return;
+            }
+            tokio::select! {
+                _ = state_changed => {}
+                _ = shutdown => {}
+            }
+        }
+    }
+
+    /// Ask the replay to stop after the replication file it is applying
+    pub fn request_shutdown(&self) {
+        self.shutdown_requested.store(true, Ordering::SeqCst);
+        self.shutdown.notify_waiters();
+    }
+
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown_requested.load(Ordering::SeqCst)
+    }
+
+    /// Sleep for `duration`, returning early if a shutdown is requested
+    pub async fn sleep(&self, duration: Duration) {
+        let shutdown = self.shutdown.notified();
+        if self.shutdown_requested() {
+            return;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {}
+            _ = shutdown => {}
+        }
+    }
+
+    /// Count a sequence applied while stepping, pausing after the last step
+    pub fn sequence_applied(&self) {
+        let mut status = self.status.lock().unwrap();
+        if let RunState::Stepping(remaining) = status.state {
+            status.state = match remaining {
+                0 | 1 => RunState::Paused,
+                remaining => RunState::Stepping(remaining - 1),
+            };
+        }
+    }
+}
+
+/// The commands of `osm-git ctl`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Subcommand)]
+pub enum ControlCommand {
+    /// Print the progress of the replay
+    Status,
+    /// Re-read the config file and the watchlist, like sending SIGHUP
+    Reload,
+    /// Stop before the next sequence, for example before maintenance of the git repo
+    Pause,
+    /// Continue a paused replay
+    Resume,
+    /// Apply some sequences of a paused replay, then pause again
+    Step {
+        /// How many sequences to apply
+        #[arg(long, default_value = "1")]
+        sequences: u32,
+    },
+}
+
+impl ControlCommand {
+    /// The line sent to the control socket
+    fn line(self) -> String {
+        match self {
+            ControlCommand::Status => "status".to_string(),
+            ControlCommand::Reload => "reload".to_string(),
+            ControlCommand::Pause => "pause".to_string(),
+            ControlCommand::Resume => "resume".to_string(),
+            ControlCommand::Step { sequences } => format!("step {}", sequences),
+        }
+    }
+}
+
+/// Listen on the control socket of a running replay
+///
+/// A socket file left behind by a replay which was killed is replaced.
+pub async fn bind_control_socket(socket_path: &str) -> Result<UnixListener> {
+    if Path::new(socket_path).exists() {
+        if UnixStream::connect(socket_path).await.is_ok() {
+            return Err(eyre!(
+                "Another replay is listening on the control socket {}",
+                socket_path
+            ));
+        }
+        std::fs::remove_file(socket_path)?;
+    }
+    let listener = UnixListener::bind(socket_path)?;
+    info!("Listening for control commands on {}", socket_path);
+    Ok(listener)
+}
+
+/// Answer commands sent to the control socket
+///
+/// The protocol is one command per connection: the client writes the name of the
+/// command and a newline, the replay writes the answer and closes the connection.
+/// Reloads are passed on to `reload`, where the replay re-reads its config.
+pub async fn serve_control_socket(
+    listener: UnixListener,
+    control: Arc<ReplayControl>,
+    reload: mpsc::Sender<()>,
+) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let mut stream = BufReader::new(stream);
+        let mut command = String::new();
+        if let Err(err) = stream.read_line(&mut command).await {
+            warn!("Unable to read control command: {}", err);
+            continue;
+        }
+        let answer = match command.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["status"] => serde_yaml::to_string(&control.status())?,
+            ["reload"] => {
+                reload.send(()).await?;
+                "Reload requested\n".to_string()
+            }
+            ["pause"] => {
+                control.pause();
+                "Pausing before the next sequence\n".to_string()
+            }
+            ["resume"] => {
+                control.resume();
+                "Resumed\n".to_string()
+            }
+            ["step", sequences] => match sequences.parse() {
+                Ok(sequences) => {
+                    control.step(sequences);
+                    format!("Applying {} sequences, then pausing\n", sequences)
+                }
+                Err(err) => format!("Invalid number of sequences {:?}: {}\n", sequences, err),
+            },
+            _ => format!("Unknown command {:?}\n", command.trim()),
+        };
+        if let Err(err) = stream.get_mut().write_all(answer.as_bytes()).await {
+            warn!("Unable to answer control command: {}", err);
+        }
+    }
+}
+
+/// Send a command to the control socket of a running replay and return the answer
+pub async fn send_command(socket_path: &str, command: ControlCommand) -> Result<String> {
+    let mut stream = UnixStream::connect(socket_path).await.map_err(|err| {
+        eyre!(
+            "Unable to connect to the control socket {}: {}. Is the replay running with --control-socket?",
+            socket_path,
+            err
+        )
+    })?;
+    stream
+        .write_all(format!("{}\n", command.line()).as_bytes())
+        .await?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).await?;
+    Ok(answer)
+}
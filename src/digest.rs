@@ -0,0 +1,403 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
+use git2::{Repository, Sort};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use serde::Deserialize;
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
+use tracing::info;
+
+use crate::{git::CHANGESET_EVENT_TRAILER, osm::admin_areas::ADMIN_AREA_TRAILER};
+
+/// How many users and changesets the digest lists
+const TOP_ENTRIES: usize = 10;
+
+/// Squashed and late changesets are committed out of order, so the history is walked
+/// this far past the start of the period
+const OUT_OF_ORDER_SLACK: Duration = Duration::DAY;
+
+/// The time span a digest covers, ending now
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DigestPeriod {
+    Daily,
+    Weekly,
+}
+
+impl DigestPeriod {
+    fn duration(self) -> Duration {
+        match self {
+            DigestPeriod::Daily => Duration::DAY,
+            DigestPeriod::Weekly => Duration::WEEK,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DigestFormat {
+    #[default]
+    Markdown,
+    Html,
+}
+
+/// The SMTP server digests are sent through
+#[derive(Debug, Deserialize)]
+struct SmtpConfig {
+    server: String,
+    /// Defaults to the submission port with STARTTLS
+    port: Option<u16>,
+    username: Option<String>,
+    password: Option<String>,
+    from: String,
+    to: Vec<String>,
+}
+
+/// What a digest covers and where it goes, read from a YAML file:
+///
+/// ```yaml
+/// title: OSM changes in Berlin
+/// areas: [Berlin]
+/// format: html
+/// watch_report: watch-report.yaml
+/// output_dir: digests
+/// smtp:
+///   server: smtp.example.com
+///   username: osm-git
+///   password: ...
+///   from: osm-git <osm-git@example.com>
+///   to: [mappers@example.com]
+/// ```
+///
+/// Without `output_dir` and `smtp` the digest is printed.
+#[derive(Debug, Deserialize)]
+pub struct DigestConfig {
+    title: String,
+    /// The `Admin-Area` trailers of the commits to include. Empty includes all commits
+    #[serde(default)]
+    areas: BTreeSet<String>,
+    #[serde(default)]
+    format: DigestFormat,
+    /// The report file of the watch rules, to list their matches
+    watch_report: Option<String>,
+    output_dir: Option<String>,
+    smtp: Option<SmtpConfig>,
+}
+
+impl DigestConfig {
+    pub fn load(path: &str) -> Result<Self> {
+        let config: DigestConfig = serde_yaml::from_reader(std::fs::File::open(path)?)?;
+        if let Some(smtp) = &config.smtp {
+            if smtp.to.is_empty() {
+                return Err(eyre!("The digest config {} has no recipients", path));
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// A match from the report file of the watch rules
+#[derive(Debug, Deserialize)]
+struct ReportedMatch {
+    rule: String,
+    action: String,
+    object_type: String,
+    id: u64,
+    changeset: u64,
+    commit: String,
+}
+
+/// A changeset commit within the period of a digest
+struct DigestCommit {
+    changesets: Vec<u64>,
+    users: Vec<String>,
+    subject: String,
+    objects_changed: usize,
+}
+
+/// The summary of the commits within a period
+pub struct Digest {
+    title: String,
+    since: OffsetDateTime,
+    until: OffsetDateTime,
+    commits: Vec<DigestCommit>,
+    /// Changesets per user, most active first
+    top_users: Vec<(String, usize)>,
+    watch_matches: Vec<ReportedMatch>,
+}
+
+impl Digest {
+    /// Summarize the commits of HEAD made within the period
+    ///
+    /// Commits are dated by their author time, which is the time of their changesets.
+    /// Only commits with changeset trailers count, so README, gap and metadata commits
+    /// are left out, as are the empty commits of changesets being opened or closed.
+    pub fn collect(
+        repository: &Repository,
+        config: &DigestConfig,
+        period: DigestPeriod,
+    ) -> Result<Self> {
+        let until = OffsetDateTime::now_utc();
+        let since = until - period.duration();
+
+        let mut revwalk = repository.revwalk()?;
+        revwalk.push_head()?;
+        revwalk.simplify_first_parent()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL)?;
+
+        let mut commits = Vec::new();
+        let mut commit_ids = BTreeSet::new();
+        for oid in revwalk {
+            let commit = repository.find_commit(oid?)?;
+            let authored_at =
+                OffsetDateTime::from_unix_timestamp(commit.author().when().seconds())?;
+            if authored_at < since - OUT_OF_ORDER_SLACK {
+                break;
+            }
+            if authored_at < since || authored_at > until {
+                continue;
+            }
+
+            let message = commit.message().unwrap_or_default();
+            let mut changesets = Vec::new();
+            let mut users = Vec::new();
+            let mut areas = BTreeSet::new();
+            let mut lifecycle_event = false;
+            for (key, value) in git2::message_trailers_strs(message)?.iter() {
+                match key {
+                    CHANGESET_EVENT_TRAILER => lifecycle_event = true,
+                    "Changeset-Id" => changesets.push(value.parse()?),
+                    "Changeset-User" => users.push(value.to_string()),
+                    ADMIN_AREA_TRAILER => {
+                        areas.insert(value.to_string());
+                    }
+                    _ => {}
+                }
+            }
+            if changesets.is_empty()
+                || lifecycle_event
+                || (!config.areas.is_empty() && config.areas.is_disjoint(&areas))
+            {
+                continue;
+            }
+
+            let parent_tree = match commit.parents().next() {
+                Some(parent) => Some(parent.tree()?),
+                None => None,
+            };
+            let diff =
+                repository.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+            commit_ids.insert(commit.id().to_string());
+            commits.push(DigestCommit {
+                changesets,
+                users,
+                subject: commit.summary().unwrap_or_default().to_string(),
+                objects_changed: diff.deltas().len(),
+            });
+        }
+
+        let mut changesets_per_user = BTreeMap::new();
+        for commit in &commits {
+            for user in &commit.users {
+                *changesets_per_user.entry(user.clone()).or_insert(0) += 1;
+            }
+        }
+        let mut top_users = changesets_per_user.into_iter().collect::<Vec<_>>();
+        top_users.sort_by(|(a_user, a_count), (b_user, b_count)| {
+            b_count.cmp(a_count).then_with(|| a_user.cmp(b_user))
+        });
+        top_users.truncate(TOP_ENTRIES);
+
+        let watch_matches = match &config.watch_report {
+            Some(report) if Path::new(report).exists() => {
+                let matches: Vec<ReportedMatch> =
+                    serde_yaml::from_reader(std::fs::File::open(report)?)?;
+                matches
+                    .into_iter()
+                    .filter(|watch_match| commit_ids.contains(&watch_match.commit))
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+
+        Ok(Digest {
+            title: config.title.clone(),
+            since,
+            until,
+            commits,
+            top_users,
+            watch_matches,
+        })
+    }
+
+    /// The changesets which changed the most objects
+    fn notable_commits(&self) -> Vec<&DigestCommit> {
+        let mut notable = self.commits.iter().collect::<Vec<_>>();
+        notable.sort_by_key(|commit| Reverse(commit.objects_changed));
+        notable.truncate(TOP_ENTRIES);
+        notable
+    }
+
+    fn subject(&self) -> String {
+        format!(
+            "{} ({} to {})",
+            self.title,
+            self.since.date(),
+            self.until.date()
+        )
+    }
+
+    fn render_markdown(&self) -> Result<String> {
+        let mut out = format!("# {}\n\n", self.subject());
+        out.push_str(&format!(
+            "{} commits from {} to {}.\n",
+            self.commits.len(),
+            self.since.format(&Rfc3339)?,
+            self.until.format(&Rfc3339)?
+        ));
+
+        out.push_str("\n## Top users\n\n");
+        for (user, changesets) in &self.top_users {
+            out.push_str(&format!("- {}: {} changesets\n", user, changesets));
+        }
+        out.push_str("\n## Notable changesets\n\n");
+        for commit in self.notable_commits() {
+            out.push_str(&format!(
+                "- {} ({}): {} objects, {}\n",
+                join_ids(&commit.changesets),
+                commit.users.join(", "),
+                commit.objects_changed,
+                commit.subject
+            ));
+        }
+        if !self.watch_matches.is_empty() {
+            out.push_str("\n## Watch rule matches\n\n");
+            for watch_match in &self.watch_matches {
+                out.push_str(&format!(
+                    "- [{}] {} {}/{} in changeset {}\n",
+                    watch_match.rule,
+                    watch_match.action,
+                    watch_match.object_type,
+                    watch_match.id,
+                    watch_match.changeset
+                ));
+            }
+        }
+        Ok(out)
+    }
+
+    fn render_html(&self) -> Result<String> {
+        let mut out = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n",
+            escape_html(&self.subject())
+        );
+        out.push_str(&format!(
+            "<p>{} commits from {} to {}.</p>\n",
+            self.commits.len(),
+            self.since.format(&Rfc3339)?,
+            self.until.format(&Rfc3339)?
+        ));
+
+        out.push_str("<h2>Top users</h2>\n<ul>\n");
+        for (user, changesets) in &self.top_users {
+            out.push_str(&format!(
+                "<li>{}: {} changesets</li>\n",
+                escape_html(user),
+                changesets
+            ));
+        }
+        out.push_str("</ul>\n<h2>Notable changesets</h2>\n<ul>\n");
+        for commit in self.notable_commits() {
+            out.push_str(&format!(
+                "<li>{} ({}): {} objects, {}</li>\n",
+                join_ids(&commit.changesets),
+                escape_html(&commit.users.join(", ")),
+                commit.objects_changed,
+                escape_html(&commit.subject)
+            ));
+        }
+        out.push_str("</ul>\n");
+        if !self.watch_matches.is_empty() {
+            out.push_str("<h2>Watch rule matches</h2>\n<ul>\n");
+            for watch_match in &self.watch_matches {
+                out.push_str(&format!(
+                    "<li>[{}] {} {}/{} in changeset {}</li>\n",
+                    escape_html(&watch_match.rule),
+                    escape_html(&watch_match.action),
+                    escape_html(&watch_match.object_type),
+                    watch_match.id,
+                    watch_match.changeset
+                ));
+            }
+            out.push_str("</ul>\n");
+        }
+        out.push_str("</body>\n</html>\n");
+        Ok(out)
+    }
+
+    /// Write the digest to the output directory and send it, as configured
+    pub async fn deliver(&self, config: &DigestConfig) -> Result<()> {
+        let (body, extension, content_type) = match config.format {
+            DigestFormat::Markdown => (self.render_markdown()?, "md", ContentType::TEXT_PLAIN),
+            DigestFormat::Html => (self.render_html()?, "html", ContentType::TEXT_HTML),
+        };
+
+        if config.output_dir.is_none() && config.smtp.is_none() {
+            print!("{}", body);
+            return Ok(());
+        }
+
+        if let Some(output_dir) = &config.output_dir {
+            std::fs::create_dir_all(output_dir)?;
+            let path =
+                Path::new(output_dir).join(format!("digest-{}.{}", self.until.date(), extension));
+            std::fs::write(&path, &body)?;
+            info!("Wrote digest to {}", path.display());
+        }
+
+        if let Some(smtp) = &config.smtp {
+            let mut message = Message::builder()
+                .from(smtp.from.parse::<Mailbox>()?)
+                .subject(self.subject())
+                .header(content_type);
+            for to in &smtp.to {
+                message = message.to(to.parse::<Mailbox>()?);
+            }
+            let message = message.body(body)?;
+
+            let mut transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.server)?;
+            if let Some(port) = smtp.port {
+                transport = transport.port(port);
+            }
+            if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+                transport =
+                    transport.credentials(Credentials::new(username.clone(), password.clone()));
+            }
+            transport.build().send(message).await?;
+            info!("Sent digest to {}", smtp.to.join(", "));
+        }
+        Ok(())
+    }
+}
+
+fn join_ids(ids: &[u64]) -> String {
+    ids.iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
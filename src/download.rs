@@ -0,0 +1,170 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Result};
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    Response, StatusCode,
+};
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+use tracing::warn;
+
+use crate::cassette::{Cassette, CassetteMode};
+
+/// The wait before the first retry. It doubles with every further attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest wait between two attempts, unless the server asks for more
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Downloads files, retrying network errors and responses asking to come back later
+///
+/// Failed attempts are retried with exponential backoff and jitter, so many replays
+/// which failed at the same time don't hit the server again at the same time. A
+/// `Retry-After` header of a 429 or 5xx response is honored instead of the backoff.
+#[derive(Debug, Clone)]
+pub struct Downloader {
+    pub client: reqwest::Client,
+    /// How often a download is attempted before its error is returned
+    pub max_attempts: u32,
+    /// Record the responses to, or play them back from, this cassette
+    pub cassette: Option<Arc<Cassette>>,
+}
+
+/// Why an attempt failed
+enum Failure {
+    /// Another attempt may succeed, after waiting the given time if the server said so
+    Transient(color_eyre::Report, Option<Duration>),
+    Permanent(color_eyre::Report),
+}
+
+impl Downloader {
+    /// Download the file at `url`
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Bytes>>` - `None` if the server has no such file
+    pub async fn download(&self, url: &str) -> Result<Option<Bytes>> {
+        self.download_with_headers(url, &|| Ok(HeaderMap::new()))
+            .await
+    }
+
+    /// Download the file at `url`, sending the headers made by `headers` with every
+    /// attempt, like the signature of an object storage request
+    pub async fn download_with_headers(
+        &self,
+        url: &str,
+        headers: &(dyn Fn() -> Result<HeaderMap> + Sync),
+    ) -> Result<Option<Bytes>> {
+        let Some(cassette) = &self.cassette else {
+            return self.download_with_retries(url, headers).await;
+        };
+        match cassette.mode {
+            CassetteMode::Replay => cassette.play(url),
+            CassetteMode::Record => {
+                let data = self.download_with_retries(url, headers).await?;
+                cassette.record(url, data.as_ref())?;
+                Ok(data)
+            }
+        }
+    }
+
+    async fn download_with_retries(
+        &self,
+        url: &str,
+        headers: &(dyn Fn() -> Result<HeaderMap> + Sync),
+    ) -> Result<Option<Bytes>> {
+        let mut attempt = 1;
+        loop {
+            let failure = match self.attempt(url, headers()?).await {
+                Ok(data) => return Ok(data),
+                Err(failure) => failure,
+            };
+            let (err, retry_after) = match failure {
+                Failure::Transient(err, retry_after) if attempt < self.max_attempts => {
+                    (err, retry_after)
+                }
+                Failure::Transient(err, _) | Failure::Permanent(err) => return Err(err),
+            };
+            let wait = retry_after.unwrap_or_else(|| backoff(attempt));
+            warn!(
+                "Downloading {} failed: {}. Retrying in {:?} (attempt {} of {})",
+                url, err, wait, attempt, self.max_attempts
+            );
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+
+    /// Download the file at `url` as text
+    pub async fn download_text(&self, url: &str) -> Result<Option<String>> {
+        self.download(url)
+            .await?
+            .map(|data| Ok(String::from_utf8(data.to_vec())?))
+            .transpose()
+    }
+
+    async fn attempt(
+        &self,
+        url: &str,
+        headers: HeaderMap,
+    ) -> std::result::Result<Option<Bytes>, Failure> {
+        #[cfg(feature = "fault-injection")]
+        crate::faults::network(url).map_err(|err| Failure::Transient(err, None))?;
+        let response = self
+            .client
+            .get(url)
+            .headers(headers)
+            .send()
+            .await
+            .map_err(|err| Failure::Transient(err.into(), None))?;
+        let status = response.status();
+        if status == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            return Err(Failure::Transient(
+                eyre!("The server answered {}", status),
+                retry_after(&response),
+            ));
+        }
+        let response = response
+            .error_for_status()
+            .map_err(|err| Failure::Permanent(err.into()))?;
+        // A connection dropped in the middle of the body is retried as well
+        let data = response
+            .bytes()
+            .await
+            .map_err(|err| Failure::Transient(err.into(), None))?;
+        Ok(Some(data))
+    }
+}
+
+/// The wait before the given retry: exponential, capped and with up to half of it
+/// randomized
+fn backoff(attempt: u32) -> Duration {
+    let backoff = INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(MAX_BACKOFF);
+    let jitter = RandomState::new().build_hasher().finish() % 1000;
+    backoff / 2 + backoff / 2 * jitter as u32 / 1000
+}
+
+/// The wait the server asks for, as seconds or as an HTTP date
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let until = OffsetDateTime::parse(value, &Rfc2822).ok()?;
+    Some(
+        (until - OffsetDateTime::now_utc())
+            .try_into()
+            .unwrap_or_default(),
+    )
+}
@@ -0,0 +1,154 @@
+//! Records unexpected contents of the parsed files for a later look
+//!
+//! Every report gets a snippet file in the error folder, named after the time and the
+//! sequence it is from, and an entry in the `summary.json` next to them.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::warn;
+
+/// The machine-readable list of all reports in the error folder
+pub const SUMMARY_FILE: &str = "summary.json";
+
+/// The error report of this process, if any
+static ERROR_REPORT: OnceLock<ErrorReport> = OnceLock::new();
+
+/// Record the errors of this process in `directory`
+///
+/// The report is set once for the whole process rather than passed along, as the
+/// parsers run deep down in the replay.
+pub fn report_errors_to(directory: impl Into<PathBuf>) -> Result<()> {
+    ERROR_REPORT
+        .set(ErrorReport::new(directory))
+        .map_err(|_| eyre!("The error report is already set"))
+}
+
+/// What went wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    /// The parser came across contents it doesn't know, and carried on without them
+    UnexpectedEvent,
+    /// An element could not be parsed and was skipped in lenient mode
+    SkippedElement,
+}
+
+impl ErrorKind {
+    fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::UnexpectedEvent => "unexpected-event",
+            ErrorKind::SkippedElement => "skipped-element",
+        }
+    }
+}
+
+/// An entry of the summary
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorEntry {
+    pub time: String,
+    pub kind: ErrorKind,
+    /// The sequence of the replication file, if the contents are from one
+    pub sequence: Option<String>,
+    pub message: String,
+    /// The name of the snippet file in the error folder
+    pub snippet: Option<String>,
+}
+
+/// Report unexpected contents of a file
+///
+/// Nothing is recorded if the process has no error report. Failing to write the report
+/// is logged, as it must not stop the replay.
+pub fn report_error(kind: ErrorKind, sequence: Option<&str>, message: &str, snippet: &[u8]) {
+    let Some(report) = ERROR_REPORT.get() else {
+        return;
+    };
+    if let Err(err) = report.record(kind, sequence, message, snippet) {
+        warn!(
+            "Unable to write the error report to {}: {}",
+            report.directory.display(),
+            err
+        );
+    }
+}
+
+/// The error folder, usually `<cache>/errors`
+pub struct ErrorReport {
+    directory: PathBuf,
+    /// Held while the summary is rewritten
+    summary: Mutex<()>,
+}
+
+impl ErrorReport {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        ErrorReport {
+            directory: directory.into(),
+            summary: Mutex::new(()),
+        }
+    }
+
+    /// Read the entries of the summary
+    pub fn entries(directory: &Path) -> Result<Vec<ErrorEntry>> {
+        match std::fs::read(directory.join(SUMMARY_FILE)) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Write the snippet and add it to the summary
+    pub fn record(
+        &self,
+        kind: ErrorKind,
+        sequence: Option<&str>,
+        message: &str,
+        snippet: &[u8],
+    ) -> Result<ErrorEntry> {
+        let _summary = self
+            .summary
+            .lock()
+            .map_err(|_| eyre!("The error report is poisoned"))?;
+        std::fs::create_dir_all(&self.directory)?;
+
+        let now = OffsetDateTime::now_utc();
+        let mut entries = Self::entries(&self.directory)?;
+        // The position in the summary keeps the names of reports within the same
+        // microsecond apart
+        let snippet_name = (!snippet.is_empty()).then(|| {
+            format!(
+                "{:04}{:02}{:02}T{:02}{:02}{:02}.{:06}Z-{}-{}-{}.xml",
+                now.year(),
+                u8::from(now.month()),
+                now.day(),
+                now.hour(),
+                now.minute(),
+                now.second(),
+                now.microsecond(),
+                sequence.unwrap_or("none").replace('/', ""),
+                kind.name(),
+                entries.len()
+            )
+        });
+        if let Some(snippet_name) = &snippet_name {
+            std::fs::write(self.directory.join(snippet_name), snippet)?;
+        }
+        let entry = ErrorEntry {
+            time: now.format(&Rfc3339)?,
+            kind,
+            sequence: sequence.map(|sequence| sequence.to_string()),
+            message: message.to_string(),
+            snippet: snippet_name,
+        };
+        entries.push(entry.clone());
+        let summary_path = self.directory.join(SUMMARY_FILE);
+        let temporary_path = summary_path.with_extension("json.tmp");
+        std::fs::write(&temporary_path, serde_json::to_vec_pretty(&entries)?)?;
+        std::fs::rename(temporary_path, summary_path)?;
+        Ok(entry)
+    }
+}
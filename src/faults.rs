@@ -0,0 +1,502 @@
+//! Faults injected on purpose, to check that the replay recovers from them
+//!
+//! Only built with the `fault-injection` feature, which must never be enabled for a
+//! mirror in production. The faults are configured with the `OSM_GIT_FAULTS`
+//! environment variable as a comma separated list like
+//! `network=0.2,corrupt-gzip=0.1,git-lock=0.05,crash=tagged:3,seed=42`:
+//!
+//! * `network=<probability>` fails download attempts like a dropped connection
+//! * `corrupt-gzip=<probability>` truncates downloaded replication files before they are
+//!   written to the cache
+//! * `git-lock=<probability>` fails ref updates like another process holding the lock
+//! * `crash=<stage>[:<n>]` aborts the process the `n`th time, by default the first, a
+//!   replication file reaches a stage: `staged`, `tagged`, `notes` or `published`
+//! * `seed=<n>` makes the random faults repeat across runs
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+};
+
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Result};
+use tracing::{error, warn};
+
+/// The environment variable configuring the faults
+pub const FAULTS_VAR: &str = "OSM_GIT_FAULTS";
+
+/// A point between the steps of applying a replication file, where a crash leaves the
+/// git repo for the next run to recover
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// The changesets are committed to the staging ref
+    Staged,
+    /// The sequence is tagged
+    Tagged,
+    /// The notes are written
+    Notes,
+    /// The branch is fast-forwarded, the state is not written yet
+    Published,
+}
+
+impl FromStr for Stage {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "staged" => Ok(Stage::Staged),
+            "tagged" => Ok(Stage::Tagged),
+            "notes" => Ok(Stage::Notes),
+            "published" => Ok(Stage::Published),
+            _ => Err(eyre!(
+                "Unknown stage {:?}, expected staged, tagged, notes or published",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stage::Staged => "staged",
+            Stage::Tagged => "tagged",
+            Stage::Notes => "notes",
+            Stage::Published => "published",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The faults to inject, parsed from [`FAULTS_VAR`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FaultPlan {
+    pub network: f64,
+    pub corrupt_gzip: f64,
+    pub git_lock: f64,
+    /// The stage to crash at and the how manieth time
+    pub crash: Option<(Stage, u32)>,
+    pub seed: Option<u64>,
+}
+
+impl FromStr for FaultPlan {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut plan = FaultPlan::default();
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (name, value) = entry
+                .split_once('=')
+                .ok_or_else(|| eyre!("Expected <fault>=<value> instead of {:?}", entry))?;
+            let probability = || -> Result<f64> {
+                let probability = value.parse::<f64>()?;
+                if !(0.0..=1.0).contains(&probability) {
+                    return Err(eyre!("The probability of {} must be within 0 and 1", name));
+                }
+                Ok(probability)
+            };
+            match name {
+                "network" => plan.network = probability()?,
+                "corrupt-gzip" => plan.corrupt_gzip = probability()?,
+                "git-lock" => plan.git_lock = probability()?,
+                "crash" => {
+                    let (stage, occurrence) = value.split_once(':').unwrap_or((value, "1"));
+                    plan.crash = Some((stage.parse()?, occurrence.parse()?));
+                }
+                "seed" => plan.seed = Some(value.parse()?),
+                _ => return Err(eyre!("Unknown fault {:?}", name)),
+            }
+        }
+        Ok(plan)
+    }
+}
+
+struct Injector {
+    plan: FaultPlan,
+    /// The state of the xorshift generator deciding on the random faults
+    random: u64,
+    /// How often each stage was reached
+    reached: Vec<(Stage, u32)>,
+}
+
+impl Injector {
+    /// Decide on a fault with the given probability
+    fn roll(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        ((self.random >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+fn injector() -> &'static Mutex<Injector> {
+    static INJECTOR: OnceLock<Mutex<Injector>> = OnceLock::new();
+    INJECTOR.get_or_init(|| {
+        let plan = match std::env::var(FAULTS_VAR) {
+            Ok(value) => value.parse().unwrap_or_else(|err| {
+                error!("Ignoring {}: {}", FAULTS_VAR, err);
+                FaultPlan::default()
+            }),
+            Err(_) => FaultPlan::default(),
+        };
+        if plan != FaultPlan::default() {
+            warn!("Injecting faults: {:?}", plan);
+        }
+        let seed = plan.seed.unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_nanos() as u64)
+                .unwrap_or_default()
+        });
+        // Scramble the seed with a splitmix64 step, as xorshift takes a while to get going
+        // from small seeds, and never leaves zero
+        let mut random = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        random = (random ^ (random >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        random = (random ^ (random >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        Mutex::new(Injector {
+            plan,
+            random: (random ^ (random >> 31)) | 1,
+            reached: Vec::new(),
+        })
+    })
+}
+
+/// Fail a download attempt of `url` like a dropped connection
+pub fn network(url: &str) -> Result<()> {
+    let mut injector = injector().lock().unwrap();
+    let probability = injector.plan.network;
+    if injector.roll(probability) {
+        warn!("Injecting a network failure downloading {}", url);
+        return Err(eyre!("Injected network failure"));
+    }
+    Ok(())
+}
+
+/// Truncate a downloaded file of `url`, so it is no longer a valid gzip stream
+pub fn corrupt_gzip(url: &str, data: Bytes) -> Bytes {
+    let mut injector = injector().lock().unwrap();
+    let probability = injector.plan.corrupt_gzip;
+    if injector.roll(probability) && data.len() > 1 {
+        warn!("Injecting a corrupt gzip stream for {}", url);
+        return data.slice(..data.len() / 2);
+    }
+    data
+}
+
+/// Fail an update of `reference` like another process holding its lock
+pub fn git_lock(reference: &str) -> Result<(), git2::Error> {
+    let mut injector = injector().lock().unwrap();
+    let probability = injector.plan.git_lock;
+    if injector.roll(probability) {
+        warn!("Injecting lock contention on {}", reference);
+        return Err(git2::Error::new(
+            git2::ErrorCode::Locked,
+            git2::ErrorClass::Reference,
+            format!(
+                "failed to lock file '{}.lock' for writing: injected",
+                reference
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Abort the process if it is configured to crash at this stage
+pub fn stage_reached(stage: Stage) {
+    let mut injector = injector().lock().unwrap();
+    let Some((crash_stage, occurrence)) = injector.plan.crash else {
+        return;
+    };
+    let reached = match injector
+        .reached
+        .iter_mut()
+        .find(|(other, _)| *other == stage)
+    {
+        Some((_, reached)) => {
+            *reached += 1;
+            *reached
+        }
+        None => {
+            injector.reached.push((stage, 1));
+            1
+        }
+    };
+    if crash_stage == stage && reached == occurrence {
+        error!("Injecting a crash after the {} stage", stage);
+        // Release the lock first, so the tests can carry on after the panic
+        drop(injector);
+        crash();
+    }
+}
+
+#[cfg(not(test))]
+fn crash() {
+    std::process::abort();
+}
+
+/// Unwind instead of aborting, so a test can reopen the git repo like a new process
+#[cfg(test)]
+fn crash() {
+    panic!("Injected crash");
+}
+
+/// Replace the faults read from [`FAULTS_VAR`], starting the stage counts over
+#[cfg(test)]
+fn set_plan(plan: FaultPlan) {
+    let mut injector = injector().lock().unwrap();
+    injector.plan = plan;
+    injector.reached.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, VecDeque},
+        io::Write,
+        panic::{catch_unwind, AssertUnwindSafe},
+    };
+
+    use flate2::{write::GzEncoder, Compression};
+    use git2::Repository;
+
+    use super::*;
+    use crate::{
+        git::{notes::CHANGESET_NOTES_REF, read_state, recover_staging, testing::TestRepository},
+        osm::changesets::{Changeset, LifecycleEvent},
+        replay::testing::sink,
+    };
+
+    /// The faults are global, so the tests injecting them take turns
+    static FAULTS: Mutex<()> = Mutex::new(());
+
+    fn node(id: u64, version: u32, changeset: u64, value: &str) -> String {
+        format!(
+            r#"<node id="{}" version="{}" timestamp="2012-09-12T0{}:00:00Z" uid="5" user="alice" changeset="{}" lat="51.5" lon="-0.1"><tag k="amenity" v="{}"/></node>"#,
+            id, version, version, changeset, value
+        )
+    }
+
+    /// Three replication files with two changesets each
+    fn replication_files() -> Vec<(String, Vec<u8>)> {
+        (1..=3u64)
+            .map(|sequence| {
+                let osc = format!(
+                    r#"<?xml version='1.0' encoding='UTF-8'?>
+<osmChange version="0.6" generator="test">
+  <create>{}</create>
+  <modify>{}</modify>
+</osmChange>
+"#,
+                    node(sequence * 10, 1, sequence * 2, "cafe"),
+                    node(
+                        1,
+                        sequence as u32 + 1,
+                        sequence * 2 + 1,
+                        &sequence.to_string()
+                    ),
+                );
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+                encoder.write_all(osc.as_bytes()).unwrap();
+                (
+                    format!("000/000/{:03}", sequence),
+                    encoder.finish().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    /// The changesets of the replication files, cached like responses of the OSM API
+    fn changesets(repository: &TestRepository) -> String {
+        let location = repository.path().join("test-changesets");
+        std::fs::create_dir_all(location.join("api")).unwrap();
+        for id in 2..=7 {
+            std::fs::write(
+                location.join("api").join(format!("{}.osm", id)),
+                format!(
+                    r#"<osm><changeset id="{}" created_at="2012-09-12T00:00:00Z" closed_at="2012-09-12T0{}:30:00Z" open="false" user="alice" uid="5"><tag k="comment" v="Changeset {}"/></changeset></osm>"#,
+                    id, id, id
+                ),
+            )
+            .unwrap();
+        }
+        location.to_str().unwrap().to_string()
+    }
+
+    /// Changesets opened and closed in the changeset stream, one batch per sync before a
+    /// replication file is applied
+    fn changeset_stream() -> VecDeque<Vec<(LifecycleEvent, Changeset)>> {
+        (1..=3u64)
+            .map(|sync| {
+                let changeset = Changeset {
+                    id: 100 + sync,
+                    created_at: format!("2012-09-12T0{}:10:00Z", sync),
+                    closed_at: Some(format!("2012-09-12T0{}:20:00Z", sync)),
+                    open: false,
+                    user: Some("bob".to_string()),
+                    uid: Some(6),
+                    min_lat: None,
+                    max_lat: None,
+                    min_lon: None,
+                    max_lon: None,
+                    tags: HashMap::new(),
+                };
+                vec![
+                    (LifecycleEvent::Opened, changeset.clone()),
+                    (LifecycleEvent::Closed, changeset),
+                ]
+            })
+            .collect()
+    }
+
+    /// Apply the replication files after the last applied one, like a run of the replay
+    ///
+    /// Every file gets the next batch of the changeset stream, like the sync before it.
+    fn replay(
+        repository: &TestRepository,
+        changesets: &str,
+        stream: &mut VecDeque<Vec<(LifecycleEvent, Changeset)>>,
+    ) {
+        let repository = repository.reopen();
+        recover_staging(&repository).unwrap();
+        let applied = read_state(&repository).unwrap().unwrap_or_default();
+        let sink = sink(repository, changesets);
+        for (sequence, data) in replication_files() {
+            if sequence <= applied {
+                continue;
+            }
+            let lifecycle = stream.pop_front().unwrap_or_default();
+            sink.apply_replication_file(&data, &sequence, &lifecycle, &mut |_| {})
+                .unwrap();
+        }
+    }
+
+    /// The refs of the git repo with what they point to, and the tree of the notes
+    fn history(repository: &Repository) -> Vec<String> {
+        repository
+            .references()
+            .unwrap()
+            .map(|reference| {
+                let reference = reference.unwrap();
+                let name = reference.name().unwrap().to_string();
+                let target = if name == CHANGESET_NOTES_REF {
+                    reference.peel_to_tree().unwrap().id()
+                } else {
+                    reference.target().unwrap()
+                };
+                format!("{} {}", name, target)
+            })
+            .collect()
+    }
+
+    /// Crash the second time a replication file reaches `stage` and run the replay again
+    ///
+    /// With `lifecycle` the changesets of the changeset stream are committed too.
+    fn recovers_from_a_crash_at(stage: Stage, lifecycle: bool) {
+        let _faults = FAULTS.lock().unwrap_or_else(|err| err.into_inner());
+        let stream = || match lifecycle {
+            true => changeset_stream(),
+            false => VecDeque::new(),
+        };
+        set_plan(FaultPlan::default());
+        let expected = TestRepository::new("faults-expected", false);
+        replay(&expected, &changesets(&expected), &mut stream());
+
+        let repository = TestRepository::new("faults", false);
+        let changesets = changesets(&repository);
+        let mut stream = stream();
+        set_plan(FaultPlan {
+            crash: Some((stage, 2)),
+            ..FaultPlan::default()
+        });
+        let crashed = catch_unwind(AssertUnwindSafe(|| {
+            replay(&repository, &changesets, &mut stream)
+        }));
+        set_plan(FaultPlan::default());
+        assert!(crashed.is_err(), "no crash at the {} stage", stage);
+        assert_ne!(history(&repository), history(&expected));
+
+        replay(&repository, &changesets, &mut stream);
+        if lifecycle {
+            // The resumed file gets the changesets synced after the crash, so its tag points
+            // to the last of their commits instead of its own
+            let without_tag_targets = |history: Vec<String>| {
+                history
+                    .into_iter()
+                    .map(
+                        |reference| match reference.starts_with("refs/tags/sequence/") {
+                            true => reference.split(' ').next().unwrap().to_string(),
+                            false => reference,
+                        },
+                    )
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(
+                without_tag_targets(history(&repository)),
+                without_tag_targets(history(&expected))
+            );
+        } else {
+            assert_eq!(history(&repository), history(&expected));
+        }
+        assert!(!repository.has_changes());
+    }
+
+    #[test]
+    fn recovers_from_a_crash_after_staging() {
+        recovers_from_a_crash_at(Stage::Staged, false);
+    }
+
+    #[test]
+    fn recovers_from_a_crash_after_tagging() {
+        recovers_from_a_crash_at(Stage::Tagged, false);
+    }
+
+    #[test]
+    fn recovers_from_a_crash_after_writing_the_notes() {
+        recovers_from_a_crash_at(Stage::Notes, false);
+    }
+
+    #[test]
+    fn recovers_from_a_crash_after_publishing() {
+        recovers_from_a_crash_at(Stage::Published, false);
+    }
+
+    #[test]
+    fn recovers_from_a_crash_after_staging_changeset_events() {
+        recovers_from_a_crash_at(Stage::Staged, true);
+    }
+
+    #[test]
+    fn recovers_from_a_crash_after_publishing_changeset_events() {
+        recovers_from_a_crash_at(Stage::Published, true);
+    }
+
+    #[test]
+    fn parses_fault_plans() {
+        assert_eq!(
+            "network=0.2, crash=tagged:3,seed=42"
+                .parse::<FaultPlan>()
+                .unwrap(),
+            FaultPlan {
+                network: 0.2,
+                crash: Some((Stage::Tagged, 3)),
+                seed: Some(42),
+                ..FaultPlan::default()
+            }
+        );
+        assert_eq!(
+            "crash=published".parse::<FaultPlan>().unwrap().crash,
+            Some((Stage::Published, 1))
+        );
+        assert!("network=2".parse::<FaultPlan>().is_err());
+        assert!("crash=parsed".parse::<FaultPlan>().is_err());
+        assert!("flood=0.1".parse::<FaultPlan>().is_err());
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::Result;
+use git2::{Oid, Repository, Signature, Tree};
+
+use super::{cursor::ReplayCursor, STAGING_REF};
+use crate::osm::{layout::Layout, osm_data::OSMObject, state_store::StateStore};
+
+/// The folder in the git dir holding serialized commits which wait for earlier ones
+const COMMIT_BUFFER_FOLDER: &str = "osm-git-commit-buffer";
+
+/// Everything the conversion of a replication file needs from the git repo
+///
+/// The conversion reads and writes objects, checks which objects HEAD has and stages
+/// commits through this trait, so it can run without a repo on disk.
+pub trait GitBackend {
+    /// Whether HEAD had a file at `path` before the replication file is applied
+    fn in_head(&self, path: &Path) -> bool;
+    /// The current version of the object stored at `path`
+    fn read_object(&self, path: &Path) -> Result<Option<OSMObject>>;
+    fn write_object(&self, path: &Path, object: &OSMObject) -> Result<()>;
+    fn remove_object(&self, path: &Path) -> Result<()>;
+    /// Commit the files of a group of changesets onto the staging ref
+    ///
+    /// Files mapped to `None` are removed.
+    fn commit_files(
+        &self,
+        files: BTreeMap<PathBuf, Option<Vec<u8>>>,
+        message: &str,
+        author: &Signature,
+        committer: &Signature,
+    ) -> Result<Oid>;
+    /// Record a staged commit in the cursor of the replication file
+    fn record_staged(
+        &self,
+        cursor: &mut ReplayCursor,
+        changesets: Vec<u64>,
+        commit: Oid,
+    ) -> Result<()>;
+    /// Called once all commits of a replication file are staged, with the files they
+    /// wrote
+    fn finish_commits(&self, layout: &Layout, written_files: &[PathBuf]) -> Result<()>;
+    /// The folder serialized commits are moved to while they wait for earlier ones
+    fn spill_dir(&self) -> PathBuf;
+}
+
+/// A git repo on disk, keeping the objects in a [`StateStore`]
+pub struct RepositoryBackend<'r> {
+    repository: &'r Repository,
+    state_store: &'r dyn StateStore,
+    /// The tree of HEAD before the file is applied
+    head: Option<Tree<'r>>,
+}
+
+impl<'r> RepositoryBackend<'r> {
+    pub fn new(repository: &'r Repository, state_store: &'r dyn StateStore) -> Result<Self> {
+        let head = match repository.head() {
+            Ok(head) => Some(head.peel_to_tree()?),
+            Err(_) => None,
+        };
+        Ok(RepositoryBackend {
+            repository,
+            state_store,
+            head,
+        })
+    }
+}
+
+impl GitBackend for RepositoryBackend<'_> {
+    fn in_head(&self, path: &Path) -> bool {
+        self.head
+            .as_ref()
+            .is_some_and(|tree| tree.get_path(path).is_ok())
+    }
+
+    fn read_object(&self, path: &Path) -> Result<Option<OSMObject>> {
+        self.state_store.read_object(self.repository, path)
+    }
+
+    fn write_object(&self, path: &Path, object: &OSMObject) -> Result<()> {
+        self.state_store.write_object(self.repository, path, object)
+    }
+
+    fn remove_object(&self, path: &Path) -> Result<()> {
+        self.state_store.remove_object(self.repository, path)
+    }
+
+    fn commit_files(
+        &self,
+        files: BTreeMap<PathBuf, Option<Vec<u8>>>,
+        message: &str,
+        author: &Signature,
+        committer: &Signature,
+    ) -> Result<Oid> {
+        self.state_store
+            .commit_files(self.repository, files, message, author, committer)
+    }
+
+    fn record_staged(
+        &self,
+        cursor: &mut ReplayCursor,
+        changesets: Vec<u64>,
+        commit: Oid,
+    ) -> Result<()> {
+        cursor.record(self.repository, changesets, commit)
+    }
+
+    /// Writes out what the state store kept in memory and checks the shard budget
+    fn finish_commits(&self, layout: &Layout, written_files: &[PathBuf]) -> Result<()> {
+        self.state_store.finish_commits(self.repository)?;
+        match self.repository.workdir() {
+            Some(workdir) => layout.check_budget(workdir, written_files),
+            None => {
+                let staged_tree = self
+                    .repository
+                    .find_reference(STAGING_REF)?
+                    .peel_to_tree()?;
+                layout.check_tree_budget(self.repository, &staged_tree, written_files)
+            }
+        }
+    }
+
+    fn spill_dir(&self) -> PathBuf {
+        self.repository.path().join(COMMIT_BUFFER_FOLDER)
+    }
+}
+
+#[cfg(test)]
+pub use memory::{MemoryBackend, MemoryCommit};
+
+#[cfg(test)]
+mod memory {
+    use std::{
+        cell::RefCell,
+        collections::{BTreeMap, BTreeSet},
+        path::{Path, PathBuf},
+    };
+
+    use color_eyre::eyre::Result;
+    use git2::{Oid, Signature};
+
+    use super::GitBackend;
+    use crate::{
+        git::cursor::{ReplayCursor, StagedCommit},
+        osm::{id_mapping::IdMapper, layout::Layout, osm_data::OSMObject},
+    };
+
+    /// A commit of a [`MemoryBackend`]
+    #[derive(Debug, Clone)]
+    pub struct MemoryCommit {
+        pub id: Oid,
+        pub message: String,
+        pub author: String,
+        /// All files of the commit
+        pub tree: BTreeMap<PathBuf, Vec<u8>>,
+    }
+
+    /// Keeps objects and commits in memory, for tests of the conversion
+    #[derive(Default)]
+    pub struct MemoryBackend {
+        objects: RefCell<BTreeMap<PathBuf, OSMObject>>,
+        head: BTreeSet<PathBuf>,
+        pub commits: RefCell<Vec<MemoryCommit>>,
+    }
+
+    impl MemoryBackend {
+        /// Add an object to HEAD and the stored objects
+        pub fn with_object(
+            mut self,
+            layout: &Layout,
+            id_mapper: &dyn IdMapper,
+            object: OSMObject,
+        ) -> Self {
+            let path = layout.object_path(&object, id_mapper);
+            self.head.insert(path.clone());
+            self.objects.get_mut().insert(path, object);
+            self
+        }
+
+        /// The stored version of the object at `path`
+        pub fn object(&self, path: &Path) -> Option<OSMObject> {
+            self.objects.borrow().get(path).cloned()
+        }
+    }
+
+    impl GitBackend for MemoryBackend {
+        fn in_head(&self, path: &Path) -> bool {
+            self.head.contains(path)
+        }
+
+        fn read_object(&self, path: &Path) -> Result<Option<OSMObject>> {
+            Ok(self.object(path))
+        }
+
+        fn write_object(&self, path: &Path, object: &OSMObject) -> Result<()> {
+            self.objects
+                .borrow_mut()
+                .insert(path.to_path_buf(), object.clone());
+            Ok(())
+        }
+
+        fn remove_object(&self, path: &Path) -> Result<()> {
+            self.objects.borrow_mut().remove(path);
+            Ok(())
+        }
+
+        fn commit_files(
+            &self,
+            files: BTreeMap<PathBuf, Option<Vec<u8>>>,
+            message: &str,
+            author: &Signature,
+            _committer: &Signature,
+        ) -> Result<Oid> {
+            let mut commits = self.commits.borrow_mut();
+            let mut tree = commits
+                .last()
+                .map(|commit| commit.tree.clone())
+                .unwrap_or_default();
+            for (path, contents) in files {
+                match contents {
+                    Some(contents) => tree.insert(path, contents),
+                    None => tree.remove(&path),
+                };
+            }
+            let mut id = [0; 20];
+            id[12..].copy_from_slice(&(commits.len() as u64 + 1).to_be_bytes());
+            let id = Oid::from_bytes(&id)?;
+            commits.push(MemoryCommit {
+                id,
+                message: message.to_string(),
+                author: author.name().unwrap_or_default().to_string(),
+                tree,
+            });
+            Ok(id)
+        }
+
+        fn record_staged(
+            &self,
+            cursor: &mut ReplayCursor,
+            changesets: Vec<u64>,
+            commit: Oid,
+        ) -> Result<()> {
+            cursor.staged.push(StagedCommit {
+                changesets,
+                commit: commit.to_string(),
+            });
+            Ok(())
+        }
+
+        fn finish_commits(&self, _layout: &Layout, _written_files: &[PathBuf]) -> Result<()> {
+            Ok(())
+        }
+
+        fn spill_dir(&self) -> PathBuf {
+            std::env::temp_dir().join(format!("osm-git-commit-buffer-{}", std::process::id()))
+        }
+    }
+}
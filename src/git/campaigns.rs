@@ -0,0 +1,223 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use color_eyre::eyre::{eyre, Result};
+use git2::{Oid, Reference, Repository, Signature};
+use tracing::debug;
+
+use super::notes::AppliedChangeset;
+use crate::osm::changesets::Changeset;
+
+/// The prefix of the refs pointing to the latest commit of each campaign
+pub const CAMPAIGN_REF_PREFIX: &str = "refs/campaigns/";
+
+/// The notes namespace listing the members of the campaigns whose ref points to a commit
+pub const CAMPAIGN_NOTES_REF: &str = "refs/notes/campaigns";
+
+/// The campaigns a changeset belongs to
+///
+/// Campaigns are detected from the `hashtags` tag set by editors for organized editing,
+/// like `#hotosm-project-12345;#missingmaps`, and from hashtags in the comment. The names
+/// are lowercased without the `#`, so `#MissingMaps` and `#missingmaps` are one campaign.
+pub fn detect_campaigns(changeset: &Changeset) -> BTreeSet<String> {
+    let mut campaigns = BTreeSet::new();
+    if let Some(hashtags) = changeset.tags.get("hashtags") {
+        for hashtag in hashtags.split(';') {
+            campaigns.extend(campaign_name(hashtag.trim().trim_start_matches('#')));
+        }
+    }
+    if let Some(comment) = changeset.tags.get("comment") {
+        for word in comment.split_whitespace() {
+            let Some(hashtag) = word.strip_prefix('#') else {
+                continue;
+            };
+            // Hashtags end at the first character which can't be part of them, like
+            // punctuation after the tag
+            let end = hashtag
+                .find(|c: char| !is_hashtag_char(c))
+                .unwrap_or(hashtag.len());
+            campaigns.extend(campaign_name(&hashtag[..end]));
+        }
+    }
+    campaigns
+}
+
+fn is_hashtag_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_'
+}
+
+/// Normalize a hashtag into a campaign name usable in a ref name
+fn campaign_name(hashtag: &str) -> Option<String> {
+    let name = hashtag
+        .chars()
+        .map(|c| if is_hashtag_char(c) { c } else { '-' })
+        .collect::<String>()
+        .to_lowercase();
+    let name = name.trim_matches('-');
+    (!name.is_empty() && Reference::is_valid_name(&campaign_ref(name))).then(|| name.to_string())
+}
+
+fn campaign_ref(campaign: &str) -> String {
+    format!("{}{}", CAMPAIGN_REF_PREFIX, campaign)
+}
+
+/// The names of all campaigns with a ref
+pub fn campaigns(repository: &Repository) -> Result<Vec<String>> {
+    let mut campaigns = Vec::new();
+    for reference in repository.references_glob(&format!("{}*", CAMPAIGN_REF_PREFIX))? {
+        if let Some(name) = reference?.name() {
+            campaigns.extend(name.strip_prefix(CAMPAIGN_REF_PREFIX).map(str::to_string));
+        }
+    }
+    campaigns.sort();
+    Ok(campaigns)
+}
+
+/// The `(changeset id, commit)` pairs of a campaign in the order they were applied
+///
+/// They are read from the note of the commit the ref of the campaign points to.
+pub fn campaign_members(repository: &Repository, campaign: &str) -> Result<Vec<(u64, Oid)>> {
+    let Ok(reference) = repository.find_reference(&campaign_ref(campaign)) else {
+        return Ok(Vec::new());
+    };
+    let head = reference.peel_to_commit()?.id();
+    Ok(read_member_lists(repository, head)?
+        .remove(campaign)
+        .unwrap_or_default())
+}
+
+/// Point the refs of the campaigns of the applied changesets to their latest commit and
+/// update their member lists
+///
+/// The member list of a campaign moves along with its ref, so older commits don't keep
+/// outdated lists. A changeset which is applied again replaces its earlier entry, which
+/// makes re-applying an interrupted sequence safe. Changesets without metadata have no
+/// tags to detect campaigns from and are skipped.
+///
+/// # Returns
+///
+/// * `Result<usize>` - The number of campaigns which were updated
+pub fn record_campaigns(
+    repository: &Repository,
+    committer: &Signature,
+    applied_changesets: &[AppliedChangeset],
+) -> Result<usize> {
+    let mut new_members: BTreeMap<String, Vec<(u64, Oid)>> = BTreeMap::new();
+    for applied in applied_changesets.iter().filter(|a| !a.metadata_missing) {
+        for campaign in detect_campaigns(&applied.changeset) {
+            new_members
+                .entry(campaign)
+                .or_default()
+                .push((applied.changeset.id, applied.commit));
+        }
+    }
+
+    for (campaign, new_members) in &new_members {
+        let ref_name = campaign_ref(campaign);
+        let previous_head = match repository.find_reference(&ref_name) {
+            Ok(reference) => Some(reference.peel_to_commit()?.id()),
+            Err(_) => None,
+        };
+        let mut members = campaign_members(repository, campaign)?;
+        for &(changeset_id, commit) in new_members {
+            members.retain(|(member, _)| *member != changeset_id);
+            members.push((changeset_id, commit));
+        }
+        let head = new_members
+            .last()
+            .map(|(_, commit)| *commit)
+            .expect("Campaigns are only added with members");
+
+        if let Some(previous_head) = previous_head.filter(|previous| *previous != head) {
+            let mut lists = read_member_lists(repository, previous_head)?;
+            lists.remove(campaign);
+            write_member_lists(repository, committer, previous_head, &lists)?;
+        }
+        let mut lists = read_member_lists(repository, head)?;
+        lists.insert(campaign.clone(), members);
+        write_member_lists(repository, committer, head, &lists)?;
+        repository.reference(
+            &ref_name,
+            head,
+            true,
+            &format!("osm-git: campaign {}", campaign),
+        )?;
+        debug!(
+            "Campaign {} has {} new changesets up to {}",
+            campaign,
+            new_members.len(),
+            head
+        );
+    }
+    Ok(new_members.len())
+}
+
+/// Read the member lists of the campaigns whose ref points to `commit`
+///
+/// The note has one section per campaign, a `Campaign: <name>` line followed by one
+/// `<changeset id> <commit id>` line per member.
+fn read_member_lists(
+    repository: &Repository,
+    commit: Oid,
+) -> Result<BTreeMap<String, Vec<(u64, Oid)>>> {
+    let Ok(note) = repository.find_note(Some(CAMPAIGN_NOTES_REF), commit) else {
+        return Ok(BTreeMap::new());
+    };
+    let mut lists = BTreeMap::new();
+    for section in note.message().unwrap_or("").split("\n\n") {
+        let mut lines = section.lines();
+        let Some(campaign) = lines
+            .next()
+            .and_then(|line| line.strip_prefix("Campaign: "))
+        else {
+            continue;
+        };
+        let members = lines
+            .map(|line| {
+                let (changeset_id, member) = line.split_once(' ').ok_or_else(|| {
+                    eyre!("Invalid line {:?} in the campaign note of {}", line, commit)
+                })?;
+                Ok((changeset_id.parse()?, Oid::from_str(member)?))
+            })
+            .collect::<Result<Vec<(u64, Oid)>>>()?;
+        lists.insert(campaign.to_string(), members);
+    }
+    Ok(lists)
+}
+
+/// Replace the campaign note of `commit`, removing it if there are no lists left
+fn write_member_lists(
+    repository: &Repository,
+    committer: &Signature,
+    commit: Oid,
+    lists: &BTreeMap<String, Vec<(u64, Oid)>>,
+) -> Result<()> {
+    if lists.is_empty() {
+        if repository
+            .find_note(Some(CAMPAIGN_NOTES_REF), commit)
+            .is_ok()
+        {
+            repository.note_delete(commit, Some(CAMPAIGN_NOTES_REF), committer, committer)?;
+        }
+        return Ok(());
+    }
+    let note = lists
+        .iter()
+        .map(|(campaign, members)| {
+            let mut section = format!("Campaign: {}", campaign);
+            for (changeset_id, member) in members {
+                section.push_str(&format!("\n{} {}", changeset_id, member));
+            }
+            section
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n");
+    repository.note(
+        committer,
+        committer,
+        Some(CAMPAIGN_NOTES_REF),
+        commit,
+        &note,
+        true,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,196 @@
+use std::{
+    collections::BTreeSet,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use color_eyre::eyre::{eyre, Result};
+use tracing::info;
+
+use crate::osm::changesets::BBox;
+
+/// The trailers of every changeset in its commit message, and of its bounding box if it
+/// has one
+const CHANGESET_ID_TRAILER: &str = "Changeset-Id: ";
+const BBOX_TRAILER: &str = "Changeset-BBox: ";
+
+/// Files a partial clone always checks out, as they describe the git repo and its layout
+const ALWAYS_CHECKED_OUT: &[&str] = &["/README.md", "/meta/"];
+
+/// What a partial clone fetches
+pub struct CloneOptions<'a> {
+    /// The git repo to clone
+    pub url: &'a str,
+    pub directory: &'a Path,
+    /// Only check out the objects changed by changesets within this area
+    pub area: Option<BBox>,
+    /// Only fetch the history after this date, in any format `git clone --shallow-since`
+    /// understands
+    pub since: Option<&'a str>,
+}
+
+/// Clone an archive without downloading all of it
+///
+/// The clone is a partial clone without blobs, which git fetches on demand once they are
+/// checked out. With a `since` date it is also shallow, so older commits are not
+/// fetched either. With an `area` only the files changed by commits whose changesets
+/// touch the area are checked out. They are found from the `Changeset-BBox` trailers of
+/// the commits, so only the trees of the history are needed to pick them. Changesets
+/// without a bounding box may be anywhere, so their files are checked out as well. Objects which
+/// were not changed in the fetched history, like the untouched nodes of a changed way,
+/// are not checked out.
+///
+/// libgit2 supports neither partial nor shallow clones, so this runs `git`.
+///
+/// # Returns
+///
+/// * `Result<Option<usize>>` - The number of files checked out for the area
+pub fn partial_clone(options: &CloneOptions) -> Result<Option<usize>> {
+    let directory = options
+        .directory
+        .to_str()
+        .ok_or_else(|| eyre!("The directory {:?} is not UTF-8", options.directory))?;
+    let mut clone_args = vec!["clone", "--filter=blob:none", "--no-checkout"];
+    let shallow_since = options
+        .since
+        .map(|since| format!("--shallow-since={}", since));
+    clone_args.extend(shallow_since.as_deref());
+    clone_args.extend([options.url, directory]);
+    git(None, &clone_args, None)?;
+
+    let Some(area) = &options.area else {
+        git(Some(options.directory), &["checkout"], None)?;
+        return Ok(None);
+    };
+
+    let paths = paths_changed_in_area(options.directory, area)?;
+    info!(
+        "{} files were changed by changesets in the area {}",
+        paths.len(),
+        area
+    );
+    let mut patterns = ALWAYS_CHECKED_OUT
+        .iter()
+        .map(|pattern| pattern.to_string())
+        .collect::<Vec<String>>();
+    patterns.extend(paths.iter().map(|path| format!("/{}", path)));
+    git(
+        Some(options.directory),
+        &["sparse-checkout", "set", "--no-cone", "--stdin"],
+        Some(&patterns.join("\n")),
+    )?;
+    git(Some(options.directory), &["checkout"], None)?;
+    Ok(Some(paths.len()))
+}
+
+/// The files changed by the commits of changesets whose bounding box intersects `area`
+fn paths_changed_in_area(directory: &Path, area: &BBox) -> Result<BTreeSet<String>> {
+    // Every commit is printed as `\0<message>\0` followed by the changed files. Renames
+    // are not detected, as that would fetch the blobs
+    let log = git(
+        Some(directory),
+        &[
+            "log",
+            "--no-renames",
+            "--name-only",
+            "--format=%x00%B%x00",
+            "HEAD",
+        ],
+        None,
+    )?;
+    let mut paths = BTreeSet::new();
+    let mut fields = log.split('\0').skip(1);
+    while let (Some(message), Some(files)) = (fields.next(), fields.next()) {
+        if commit_in_area(message, area) {
+            paths.extend(
+                files
+                    .lines()
+                    .filter(|file| !file.is_empty())
+                    .map(str::to_string),
+            );
+        }
+    }
+    Ok(paths)
+}
+
+/// Whether the changesets of a commit message touch `area`
+///
+/// A changeset without a bounding box is global, so its commit is in every area.
+fn commit_in_area(message: &str, area: &BBox) -> bool {
+    let changesets = message
+        .lines()
+        .filter(|line| line.starts_with(CHANGESET_ID_TRAILER))
+        .count();
+    let bboxes = message
+        .lines()
+        .filter_map(|line| line.strip_prefix(BBOX_TRAILER))
+        .filter_map(|bbox| bbox.parse::<BBox>().ok())
+        .collect::<Vec<BBox>>();
+    bboxes.len() < changesets || bboxes.iter().any(|bbox| bbox.intersects(area))
+}
+
+/// Run git with the given arguments and return what it printed
+fn git(directory: Option<&Path>, args: &[&str], input: Option<&str>) -> Result<String> {
+    let mut command = Command::new("git");
+    if let Some(directory) = directory {
+        command.arg("-C").arg(directory);
+    }
+    let mut child = command
+        .args(args)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| eyre!("Unable to run git: {}", err))?;
+    if let Some(input) = input {
+        child.stdin.take().unwrap().write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AREA: BBox = BBox {
+        min_lat: 51.0,
+        min_lon: -1.0,
+        max_lat: 52.0,
+        max_lon: 0.0,
+    };
+
+    #[test]
+    fn commits_of_changesets_in_the_area_are_checked_out() {
+        let inside = "Add a cafe\n\nChangeset-Id: 1\nChangeset-BBox: -0.2,51.4,-0.1,51.5\n";
+        let outside = "Add a cafe\n\nChangeset-Id: 2\nChangeset-BBox: 13.3,52.4,13.4,52.5\n";
+        assert!(commit_in_area(inside, &AREA));
+        assert!(!commit_in_area(outside, &AREA));
+    }
+
+    #[test]
+    fn commits_of_changesets_without_a_bbox_are_in_every_area() {
+        let global = "Fix the comment\n\nChangeset-Id: 3\n";
+        let squashed =
+            "Two edits\n\nChangeset-Id: 2\nChangeset-BBox: 13.3,52.4,13.4,52.5\nChangeset-Id: 3\n";
+        assert!(commit_in_area(global, &AREA));
+        assert!(commit_in_area(squashed, &AREA));
+        // Gap and file commits have no changesets at all
+        assert!(!commit_in_area(
+            "Replication file 000/000/001: 2 changesets\n",
+            &AREA
+        ));
+    }
+}
@@ -0,0 +1,101 @@
+use std::path::PathBuf;
+
+use color_eyre::eyre::Result;
+use git2::{Oid, Repository};
+use serde::{Deserialize, Serialize};
+
+/// The file in the git directory which stores the cursor of the replication file being applied
+const CURSOR_FILE: &str = "osm-git-cursor.yaml";
+
+/// A commit staged for a replication file which is not published yet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StagedCommit {
+    pub changesets: Vec<u64>,
+    pub commit: String,
+}
+
+/// How far the application of a replication file got
+///
+/// The cursor is updated after every staged commit. If the process is interrupted, the
+/// next run keeps the staged commits and only applies the changesets which are missing,
+/// instead of starting the replication file from scratch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayCursor {
+    pub sequence: String,
+    pub staged: Vec<StagedCommit>,
+}
+
+impl ReplayCursor {
+    pub fn new(sequence: &str) -> Self {
+        ReplayCursor {
+            sequence: sequence.to_string(),
+            staged: Vec::new(),
+        }
+    }
+
+    fn path(repository: &Repository) -> PathBuf {
+        repository.path().join(CURSOR_FILE)
+    }
+
+    /// Load the cursor left by an interrupted run
+    pub fn load(repository: &Repository) -> Result<Option<Self>> {
+        let cursor_path = ReplayCursor::path(repository);
+        if !cursor_path.exists() {
+            return Ok(None);
+        }
+        let cursor_file = std::fs::File::open(cursor_path)?;
+        Ok(Some(serde_yaml::from_reader(cursor_file)?))
+    }
+
+    /// Persist the cursor
+    ///
+    /// The cursor is written to a temporary file first, so an interruption never leaves a
+    /// partially written cursor behind.
+    pub fn save(&self, repository: &Repository) -> Result<()> {
+        let cursor_path = ReplayCursor::path(repository);
+        let temporary_path = cursor_path.with_extension("yaml.tmp");
+        let cursor_file = std::fs::File::create(&temporary_path)?;
+        serde_yaml::to_writer(&cursor_file, self)?;
+        cursor_file.sync_all()?;
+        std::fs::rename(temporary_path, cursor_path)?;
+        Ok(())
+    }
+
+    /// Remove the cursor once the replication file is published
+    pub fn clear(repository: &Repository) -> Result<()> {
+        let cursor_path = ReplayCursor::path(repository);
+        if cursor_path.exists() {
+            std::fs::remove_file(cursor_path)?;
+        }
+        Ok(())
+    }
+
+    /// The last staged commit
+    pub fn last_commit(&self) -> Option<Oid> {
+        self.staged
+            .last()
+            .and_then(|staged| Oid::from_str(&staged.commit).ok())
+    }
+
+    /// The commit a changeset was already staged in
+    pub fn staged_commit(&self, changeset_id: u64) -> Option<Oid> {
+        self.staged
+            .iter()
+            .find(|staged| staged.changesets.contains(&changeset_id))
+            .and_then(|staged| Oid::from_str(&staged.commit).ok())
+    }
+
+    /// Record a staged commit and persist the cursor
+    pub fn record(
+        &mut self,
+        repository: &Repository,
+        changesets: Vec<u64>,
+        commit: Oid,
+    ) -> Result<()> {
+        self.staged.push(StagedCommit {
+            changesets,
+            commit: commit.to_string(),
+        });
+        self.save(repository)
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::BTreeMap;
+
+use color_eyre::eyre::{eyre, Result};
+use git2::{Commit, Oid, Repository, Sort, Tree};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use super::{
+    notes::{applied_sequences, read_sequence_tag},
+    recorded_gaps,
+};
+use crate::osm::{
+    id_mapping::IdMapper,
+    layout::{Layout, ShardBudget},
+    osm_data::OSMObject,
+};
+
+/// What happened to a replication sequence
+#[derive(Debug, Clone)]
+pub enum LogEntry {
+    /// The sequence was applied with the given `(changeset id, commit)` pairs
+    Applied {
+        sequence: String,
+        changesets: Vec<(u64, Oid)>,
+    },
+    /// The sequence was missing upstream and recorded as a gap commit
+    Gap { sequence: String, commit: Oid },
+}
+
+impl LogEntry {
+    pub fn sequence(&self) -> &str {
+        match self {
+            LogEntry::Applied { sequence, .. } => sequence,
+            LogEntry::Gap { sequence, .. } => sequence,
+        }
+    }
+}
+
+/// The history of the replication sequences applied to the git repo, in order
+pub fn replication_log(repository: &Repository) -> Result<Vec<LogEntry>> {
+    let mut log = Vec::new();
+    for sequence in applied_sequences(repository)? {
+        let changesets = read_sequence_tag(repository, &sequence)?.unwrap_or_default();
+        log.push(LogEntry::Applied {
+            sequence,
+            changesets,
+        });
+    }
+    for (sequence, commit) in recorded_gaps(repository)? {
+        log.push(LogEntry::Gap { sequence, commit });
+    }
+    log.sort_by(|a, b| a.sequence().cmp(b.sequence()));
+    Ok(log)
+}
+
+/// A change of a tag between two versions of an object
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagChange {
+    Added {
+        key: String,
+        value: String,
+    },
+    Modified {
+        key: String,
+        old_value: String,
+        new_value: String,
+    },
+    Removed {
+        key: String,
+        value: String,
+    },
+}
+
+/// What a commit did to an object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectChange {
+    Created,
+    Modified,
+    Deleted,
+}
+
+/// A commit which changed an object
+#[derive(Debug, Clone)]
+pub struct ObjectVersion {
+    pub commit: Oid,
+    /// The changesets of the commit, more than one for squashed commits
+    pub changesets: Vec<u64>,
+    /// The mapper who made the version, from the object or the commit trailers
+    pub user: Option<String>,
+    /// The time of the version, from the object or the author time of the commit
+    pub timestamp: String,
+    pub change: ObjectChange,
+    pub tag_changes: Vec<TagChange>,
+}
+
+/// The versions of an object in the history of HEAD, newest first
+///
+/// Like `git log --follow`, the history continues where the file of the object moved,
+/// as the path is taken from the layout of every commit, so re-sharding doesn't cut it
+/// off. Only the first parents of merges are followed.
+pub fn object_history(
+    repository: &Repository,
+    type_name: &str,
+    id: u64,
+    id_mapper: &dyn IdMapper,
+    budget: ShardBudget,
+) -> Result<Vec<ObjectVersion>> {
+    Ok(
+        object_versions(repository, type_name, id, id_mapper, budget)?
+            .into_iter()
+            .map(|(version, _)| version)
+            .collect(),
+    )
+}
+
+/// The versions of an object like [`object_history`], with the object of every version,
+/// `None` for deletions
+fn object_versions(
+    repository: &Repository,
+    type_name: &str,
+    id: u64,
+    id_mapper: &dyn IdMapper,
+    budget: ShardBudget,
+) -> Result<Vec<(ObjectVersion, Option<OSMObject>)>> {
+    let mut revwalk = repository.revwalk()?;
+    revwalk.push_head()?;
+    revwalk.simplify_first_parent()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL)?;
+
+    let read_object = |tree: &Tree| -> Result<Option<OSMObject>> {
+        let layout = Layout::load_from_tree(tree, repository, budget)?;
+        let Ok(entry) = tree.get_path(&layout.path(type_name, id, id_mapper)) else {
+            return Ok(None);
+        };
+        let blob = entry.to_object(repository)?.peel_to_blob()?;
+        Ok(Some(layout.serializer().deserialize(blob.content())?))
+    };
+
+    let mut versions = Vec::new();
+    for oid in revwalk {
+        let commit = repository.find_commit(oid?)?;
+        let object = read_object(&commit.tree()?)?;
+        let previous = match commit.parents().next() {
+            Some(parent) => read_object(&parent.tree()?)?,
+            None => None,
+        };
+        if object == previous {
+            continue;
+        }
+        let change = match (&previous, &object) {
+            (None, _) => ObjectChange::Created,
+            (_, None) => ObjectChange::Deleted,
+            _ => ObjectChange::Modified,
+        };
+        let no_tags = BTreeMap::new();
+        let tag_changes = tag_changes(
+            previous.as_ref().map_or(&no_tags, OSMObject::tags),
+            object.as_ref().map_or(&no_tags, OSMObject::tags),
+        );
+        let version = object_version(&commit, object.as_ref(), change, tag_changes)?;
+        versions.push((version, object));
+    }
+    Ok(versions)
+}
+
+fn object_version(
+    commit: &Commit,
+    object: Option<&OSMObject>,
+    change: ObjectChange,
+    tag_changes: Vec<TagChange>,
+) -> Result<ObjectVersion> {
+    let mut changesets = Vec::new();
+    let mut trailer_user = None;
+    for (key, value) in git2::message_trailers_strs(commit.message().unwrap_or_default())?.iter() {
+        match key {
+            "Changeset-Id" => changesets.push(value.parse()?),
+            "Changeset-User" if trailer_user.is_none() => trailer_user = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    let user = object
+        .and_then(|object| object.mapper().0)
+        .map(str::to_string)
+        .or(trailer_user);
+    let timestamp = match object.and_then(OSMObject::timestamp) {
+        Some(timestamp) => timestamp.to_string(),
+        None => OffsetDateTime::from_unix_timestamp(commit.author().when().seconds())?
+            .format(&Rfc3339)?,
+    };
+    Ok(ObjectVersion {
+        commit: commit.id(),
+        changesets,
+        user,
+        timestamp,
+        change,
+        tag_changes,
+    })
+}
+
+/// The changes of the tags from `old` to `new`, ordered by key
+pub fn tag_changes(
+    old: &BTreeMap<String, String>,
+    new: &BTreeMap<String, String>,
+) -> Vec<TagChange> {
+    let mut changes = Vec::new();
+    for (key, old_value) in old {
+        match new.get(key) {
+            None => changes.push(TagChange::Removed {
+                key: key.clone(),
+                value: old_value.clone(),
+            }),
+            Some(new_value) if new_value != old_value => changes.push(TagChange::Modified {
+                key: key.clone(),
+                old_value: old_value.clone(),
+                new_value: new_value.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+    for (key, value) in new {
+        if !old.contains_key(key) {
+            changes.push(TagChange::Added {
+                key: key.clone(),
+                value: value.clone(),
+            });
+        }
+    }
+    changes.sort_by(|a, b| tag_change_key(a).cmp(tag_change_key(b)));
+    changes
+}
+
+fn tag_change_key(change: &TagChange) -> &str {
+    match change {
+        TagChange::Added { key, .. }
+        | TagChange::Modified { key, .. }
+        | TagChange::Removed { key, .. } => key,
+    }
+}
+
+/// The versions which last changed the parts of an object, like `git blame` for the lines
+/// of its file
+pub struct ObjectBlame {
+    /// The versions of the object, newest first
+    pub versions: Vec<ObjectVersion>,
+    /// The tags of the object with the index of the version which last changed them
+    pub tags: Vec<(String, String, usize)>,
+    /// The nodes of a way or the members of a relation in their order, like `node 123`
+    /// or `way 5 as outer`, with the index of the version which added them
+    pub references: Vec<(String, usize)>,
+}
+
+/// Find the versions which last changed every tag and every reference of an object
+///
+/// Tags are attributed to the version which set their current value. References are
+/// attributed like the lines of a file in `git blame`: the lists of consecutive versions
+/// are diffed, so inserting a node into a way only attributes the new node to the
+/// version. Like [`object_history`], the blame follows the object through layout
+/// changes and works with all object formats. A recreated object starts over.
+pub fn blame_object(
+    repository: &Repository,
+    type_name: &str,
+    id: u64,
+    id_mapper: &dyn IdMapper,
+    budget: ShardBudget,
+) -> Result<ObjectBlame> {
+    let versions = object_versions(repository, type_name, id, id_mapper, budget)?;
+    if !matches!(versions.first(), Some((_, Some(_)))) {
+        return Err(eyre!("{} {} is not in the git repo at HEAD", type_name, id));
+    }
+
+    let mut tags: BTreeMap<String, (String, usize)> = BTreeMap::new();
+    let mut references: Vec<(String, usize)> = Vec::new();
+    for (index, (_, object)) in versions.iter().enumerate().rev() {
+        let Some(object) = object else {
+            tags.clear();
+            references.clear();
+            continue;
+        };
+        tags = object
+            .tags()
+            .iter()
+            .map(|(key, value)| {
+                let version = match tags.get(key) {
+                    Some((old_value, version)) if old_value == value => *version,
+                    _ => index,
+                };
+                (key.clone(), (value.clone(), version))
+            })
+            .collect();
+        references = carry_over(&references, reference_labels(object), index);
+    }
+
+    Ok(ObjectBlame {
+        versions: versions.into_iter().map(|(version, _)| version).collect(),
+        tags: tags
+            .into_iter()
+            .map(|(key, (value, version))| (key, value, version))
+            .collect(),
+        references,
+    })
+}
+
+fn reference_labels(object: &OSMObject) -> Vec<String> {
+    match object {
+        OSMObject::Node(_) => Vec::new(),
+        OSMObject::Way(way) => way
+            .nodes
+            .iter()
+            .map(|node| format!("node {}", node))
+            .collect(),
+        OSMObject::Relation(relation) => relation
+            .member
+            .iter()
+            .map(|member| match member.role.as_deref() {
+                Some(role) if !role.is_empty() => {
+                    format!("{} {} as {}", member.r#type, member.ref_id, role)
+                }
+                _ => format!("{} {}", member.r#type, member.ref_id),
+            })
+            .collect(),
+    }
+}
+
+/// Attribute the references of a new version: references kept from the old version by
+/// the longest common subsequence keep their version, the others get `version`
+fn carry_over(old: &[(String, usize)], new: Vec<String>, version: usize) -> Vec<(String, usize)> {
+    // Only the part between the common prefix and suffix needs the quadratic diff
+    let prefix = old
+        .iter()
+        .zip(&new)
+        .take_while(|((old, _), new)| old == *new)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|((old, _), new)| old == *new)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    // lengths[i][j] is the length of the longest common subsequence of old_middle[i..]
+    // and new_middle[j..]
+    let columns = new_middle.len() + 1;
+    let mut lengths = vec![0u32; (old_middle.len() + 1) * columns];
+    for i in (0..old_middle.len()).rev() {
+        for j in (0..new_middle.len()).rev() {
+            lengths[i * columns + j] = if old_middle[i].0 == new_middle[j] {
+                lengths[(i + 1) * columns + j + 1] + 1
+            } else {
+                lengths[(i + 1) * columns + j].max(lengths[i * columns + j + 1])
+            };
+        }
+    }
+    let mut middle = Vec::with_capacity(new_middle.len());
+    let (mut i, mut j) = (0, 0);
+    while j < new_middle.len() {
+        if i < old_middle.len() && old_middle[i].0 == new_middle[j] {
+            middle.push((new_middle[j].clone(), old_middle[i].1));
+            i += 1;
+            j += 1;
+        } else if i < old_middle.len()
+            && lengths[(i + 1) * columns + j] >= lengths[i * columns + j + 1]
+        {
+            i += 1;
+        } else {
+            middle.push((new_middle[j].clone(), version));
+            j += 1;
+        }
+    }
+
+    old[..prefix]
+        .iter()
+        .cloned()
+        .chain(middle)
+        .chain(old[old.len() - suffix..].iter().cloned())
+        .collect()
+}
@@ -0,0 +1,134 @@
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
+use git2::{Signature, Time};
+use serde::Serialize;
+
+/// The name of the bot committing the changesets unless another one is configured
+pub const DEFAULT_COMMITTER_NAME: &str = "osm-git-replay";
+/// The email of the bot committing the changesets unless another one is configured
+pub const DEFAULT_COMMITTER_EMAIL: &str = "osm-git-replay@localhost";
+/// The domain of the emails of the mappers unless another one is configured
+pub const DEFAULT_AUTHOR_EMAIL_DOMAIN: &str = "users.openstreetmap.org";
+
+/// The name of the author of anonymous changesets unless another one is configured
+pub const DEFAULT_ANONYMOUS_NAME: &str = "Anonymous";
+
+/// The email a mapper authors commits with
+///
+/// It is made from the uid rather than the user name, so it stays the same when the
+/// mapper is renamed. The `.mailmap` of the git repo maps it to the latest name.
+pub fn mapper_email(uid: u64, email_domain: &str) -> String {
+    format!("{}@{}", uid, email_domain)
+}
+
+/// The names and emails the commits of mappers are authored with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthorIdentity {
+    /// The domain of the emails made from the uids of the mappers
+    pub email_domain: String,
+    /// The author of changesets without a user, like the anonymous edits of the early
+    /// years of OSM
+    pub anonymous_name: String,
+    pub anonymous_email: String,
+}
+
+impl Default for AuthorIdentity {
+    fn default() -> Self {
+        AuthorIdentity::new(DEFAULT_AUTHOR_EMAIL_DOMAIN, DEFAULT_ANONYMOUS_NAME, None)
+    }
+}
+
+impl AuthorIdentity {
+    /// The anonymous email defaults to `anonymous@<email domain>`
+    pub fn new(email_domain: &str, anonymous_name: &str, anonymous_email: Option<&str>) -> Self {
+        AuthorIdentity {
+            email_domain: email_domain.to_string(),
+            anonymous_name: anonymous_name.to_string(),
+            anonymous_email: anonymous_email
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("anonymous@{}", email_domain)),
+        }
+    }
+
+    /// The name and email of the author of a changeset
+    ///
+    /// Changesets lacking the user or the uid fall back to the anonymous name or email.
+    pub fn of(&self, user: Option<&str>, uid: Option<u64>) -> (String, String) {
+        (
+            user.unwrap_or(&self.anonymous_name).to_string(),
+            uid.map(|uid| mapper_email(uid, &self.email_domain))
+                .unwrap_or_else(|| self.anonymous_email.clone()),
+        )
+    }
+}
+
+/// The name, email and time osm-git commits with
+///
+/// Unlike a [`Signature`] it can be sent to another thread, like the one a replication
+/// file is applied on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Committer {
+    name: String,
+    email: String,
+    time: Time,
+}
+
+impl Committer {
+    pub fn new(signature: &Signature) -> Result<Self> {
+        Ok(Committer {
+            name: signature
+                .name()
+                .ok_or_else(|| eyre!("The committer name is not UTF-8"))?
+                .to_string(),
+            email: signature
+                .email()
+                .ok_or_else(|| eyre!("The committer email is not UTF-8"))?
+                .to_string(),
+            time: signature.when(),
+        })
+    }
+
+    pub fn signature(&self) -> Result<Signature<'static>> {
+        Ok(Signature::new(&self.name, &self.email, &self.time)?)
+    }
+}
+
+/// Who the commits of changesets are attributed to
+///
+/// Forges with contributor license agreements or DCO checks look at the authors of the
+/// commits, so an organization mirroring OSM may not want mappers as authors. The mapper
+/// is recorded in the `Changeset-User` and `Changeset-Uid` trailers either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentityPolicy {
+    /// The mapper of the changeset is the author, the bot is the committer
+    #[default]
+    Mapper,
+    /// The bot is author and committer. The mapper is only recorded in the trailers
+    Bot,
+}
+
+impl IdentityPolicy {
+    /// The author of a commit, given the signature of the mapper
+    ///
+    /// The author time stays the time of the changeset with either policy, so dates like
+    /// `osm-git export --at` keep working.
+    pub fn author(
+        self,
+        mapper: Signature<'static>,
+        committer: &Signature,
+    ) -> Result<Signature<'static>> {
+        match self {
+            IdentityPolicy::Mapper => Ok(mapper),
+            IdentityPolicy::Bot => Ok(Signature::new(
+                committer
+                    .name()
+                    .ok_or_else(|| eyre!("The committer name is not UTF-8"))?,
+                committer
+                    .email()
+                    .ok_or_else(|| eyre!("The committer email is not UTF-8"))?,
+                &mapper.when(),
+            )?),
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use color_eyre::eyre::Result;
+use git2::{Oid, Repository, Signature, Sort};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use super::{commit_meta_file, identity::mapper_email, in_head_history};
+
+/// The mailmap file at the root of the git repo
+pub const MAILMAP_FILE: &str = ".mailmap";
+
+/// The domain of the emails commits were authored with before they were made from the
+/// uid, as `<user name>@osm`
+const LEGACY_EMAIL_DOMAIN: &str = "osm";
+
+/// The names a mapper committed with
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MapperNames {
+    latest: String,
+    /// The mapper committed with another name before
+    renamed: bool,
+    /// The `<user name>@osm` emails of commits made before the emails came from the uid
+    legacy_emails: BTreeSet<String>,
+}
+
+/// The names of all mappers in the history up to a commit
+///
+/// It is kept in the cache, so updating the mailmap only reads the commits made since.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MailmapIndex {
+    /// The commit the index is up to
+    commit: Option<String>,
+    mappers: BTreeMap<u64, MapperNames>,
+}
+
+impl MailmapIndex {
+    /// Read the index from `path`, or start an empty one
+    pub fn load(path: &str) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        if let Some(parent) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+
+    /// Record the names of the mappers in the commits made since the index was updated
+    ///
+    /// The index starts over if the commit it is up to is no longer in the history.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<usize>` - The number of commits read
+    pub fn update(&mut self, repository: &Repository) -> Result<usize> {
+        let mut walk = repository.revwalk()?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        walk.push_head()?;
+        match self.commit.as_deref().map(Oid::from_str).transpose()? {
+            Some(commit) if in_head_history(repository, commit)? => walk.hide(commit)?,
+            Some(commit) => {
+                debug!(
+                    "{} is no longer in the history, reading all commits",
+                    commit
+                );
+                self.mappers.clear();
+            }
+            None => {}
+        }
+
+        let mut read = 0;
+        for commit in walk {
+            let commit = repository.find_commit(commit?)?;
+            self.record(&commit.author(), commit.message().unwrap_or_default())?;
+            read += 1;
+        }
+        self.commit = Some(repository.refname_to_id("HEAD")?.to_string());
+        Ok(read)
+    }
+
+    /// Record the mappers in the `Changeset-User` and `Changeset-Uid` trailers of a commit
+    fn record(&mut self, author: &Signature, message: &str) -> Result<()> {
+        // Squashed commits list the trailers of every changeset, starting with its
+        // `Changeset-Id`. Anonymous changesets have neither a user nor a uid
+        let mut mappers: Vec<(Option<&str>, Option<u64>)> = Vec::new();
+        let trailers = git2::message_trailers_strs(message)?;
+        for (key, value) in trailers.iter() {
+            match (key, mappers.last_mut()) {
+                ("Changeset-Id", _) => mappers.push((None, None)),
+                ("Changeset-User", Some(mapper)) => mapper.0 = Some(value),
+                ("Changeset-Uid", Some(mapper)) => mapper.1 = value.parse().ok(),
+                _ => {}
+            }
+        }
+        for (user, uid) in mappers {
+            let (Some(user), Some(uid)) = (user, uid) else {
+                continue;
+            };
+            let mapper = self.mappers.entry(uid).or_default();
+            if !mapper.latest.is_empty() && mapper.latest != user {
+                mapper.renamed = true;
+            }
+            mapper.latest = user.to_string();
+            let legacy_email = format!("{}@{}", user, LEGACY_EMAIL_DOMAIN);
+            if author.email() == Some(legacy_email.as_str()) {
+                mapper.legacy_emails.insert(legacy_email);
+            }
+        }
+        Ok(())
+    }
+
+    /// Generate the mailmap, mapping the emails of renamed mappers to their latest name
+    /// and the legacy emails to the email made from the uid
+    pub fn mailmap(&self, email_domain: &str) -> String {
+        let mut mailmap = String::new();
+        for (uid, mapper) in &self.mappers {
+            let email = mapper_email(*uid, email_domain);
+            if mapper.renamed {
+                mailmap.push_str(&format!("{} <{}>\n", mapper.latest, email));
+            }
+            for legacy_email in &mapper.legacy_emails {
+                mailmap.push_str(&format!(
+                    "{} <{}> <{}>\n",
+                    mapper.latest, email, legacy_email
+                ));
+            }
+        }
+        mailmap
+    }
+}
+
+/// Update the `.mailmap` of the git repo with the mappers committed since the last update
+///
+/// The mailmap is committed on top of HEAD if it changed.
+///
+/// # Returns
+///
+/// * `Result<Option<Oid>>` - The commit of the new mailmap, if it changed
+pub fn update_mailmap(
+    repository: &Repository,
+    committer: &Signature,
+    email_domain: &str,
+    index_path: &str,
+) -> Result<Option<Oid>> {
+    let mut index = MailmapIndex::load(index_path)?;
+    let read = index.update(repository)?;
+    let mailmap = index.mailmap(email_domain);
+
+    let current = repository
+        .head()?
+        .peel_to_tree()?
+        .get_path(std::path::Path::new(MAILMAP_FILE))
+        .ok()
+        .map(|entry| entry.to_object(repository)?.peel_to_blob())
+        .transpose()?;
+    let commit = if current.as_ref().map(|blob| blob.content()) == Some(mailmap.as_bytes())
+        || (current.is_none() && mailmap.is_empty())
+    {
+        None
+    } else {
+        let commit = commit_meta_file(
+            repository,
+            MAILMAP_FILE,
+            mailmap.into_bytes(),
+            "Update the mailmap",
+            committer,
+        )?;
+        // The index is up to the mailmap commit, which has no changesets
+        index.commit = Some(commit.to_string());
+        Some(commit)
+    };
+    index.save(index_path)?;
+    info!(
+        "Read {} commits for the mailmap of {} mappers",
+        read,
+        index.mappers.len()
+    );
+    Ok(commit)
+}
@@ -0,0 +1,303 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{eyre, Result};
+use git2::{
+    build::{CheckoutBuilder, TreeUpdateBuilder},
+    Delta, Oid, Repository, Signature, Sort,
+};
+use tracing::{info, warn};
+
+use crate::osm::layout::LAYOUT_FILE;
+
+use super::{
+    create_commit,
+    notes::{
+        applied_sequences, read_sequence_tag, sequence_tag_target, tag_sequence_commit,
+        write_note_batch,
+    },
+    read_state, recorded_gaps, tag_gap, write_state,
+};
+
+/// The refs the branches of the archives are fetched to while merging
+const MERGE_REF_PREFIX: &str = "refs/osm/merge/";
+
+/// The outcome of `osm-git merge-archives`
+#[derive(Debug, Default)]
+pub struct MergeSummary {
+    /// The number of commits in the merged history
+    pub commits: usize,
+    /// Commits which only added files the merged history already had
+    pub deduplicated_commits: usize,
+    /// Object files which were changed by more than one archive
+    ///
+    /// Archives with disjoint spatial filters still share the objects crossing the border
+    /// of their filters. The change which was made last wins.
+    pub overlapping_objects: usize,
+}
+
+/// A commit of one of the archives, waiting to be replayed onto the merged history
+struct PendingCommit {
+    archive: usize,
+    commit: Oid,
+    time: i64,
+}
+
+/// Combine archives with disjoint spatial filters into one new git repo
+///
+/// The first-parent histories of the archives are interleaved by commit time. Every
+/// commit is re-applied on top of the merged history as the changes it made to its
+/// parent, keeping its author, committer and message. Files which are not object files
+/// (the README and the files in `meta/`) are only added once. The sequence tags, gap tags,
+/// notes and the replication state are carried over to the rewritten commits.
+///
+/// All archives have to use the same layout, as object files would end up at different
+/// paths otherwise.
+pub fn merge_archives(
+    repository: &Repository,
+    archive_paths: &[String],
+    tagger: &Signature,
+    notes_ref: &str,
+) -> Result<MergeSummary> {
+    let archives = archive_paths
+        .iter()
+        .map(Repository::open)
+        .collect::<std::result::Result<Vec<Repository>, _>>()?;
+
+    let mut queues = Vec::new();
+    for (index, (archive, path)) in archives.iter().zip(archive_paths).enumerate() {
+        queues.push(fetch_archive(repository, archive, path, index)?);
+    }
+
+    let mut summary = MergeSummary::default();
+    let mut rewritten: HashMap<Oid, Oid> = HashMap::new();
+    // The position of every rewritten commit in the merged history
+    let mut positions: HashMap<Oid, usize> = HashMap::new();
+    // The archive which changed an object file last
+    let mut changed_by: HashMap<PathBuf, usize> = HashMap::new();
+    let mut head: Option<Oid> = None;
+
+    while let Some(next) = next_commit(&mut queues) {
+        let commit = repository.find_commit(next.commit)?;
+        let tree = commit.tree()?;
+        let parent_tree = match commit.parent(0) {
+            Ok(parent) => Some(parent.tree()?),
+            Err(_) => None,
+        };
+        let merged_tree = match head {
+            Some(head) => repository.find_commit(head)?.tree()?,
+            None => repository.find_tree(repository.treebuilder(None)?.write()?)?,
+        };
+
+        let diff = repository.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+        let mut update = TreeUpdateBuilder::new();
+        let mut changes = 0;
+        for delta in diff.deltas() {
+            let path = delta
+                .new_file()
+                .path()
+                .or_else(|| delta.old_file().path())
+                .ok_or_else(|| eyre!("Change without a path in commit {}", commit.id()))?
+                .to_path_buf();
+            let existing = merged_tree.get_path(&path).ok().map(|entry| entry.id());
+
+            if delta.status() == Delta::Deleted {
+                if existing.is_some() {
+                    update.remove(&path);
+                    changes += 1;
+                }
+                continue;
+            }
+
+            let blob = delta.new_file().id();
+            if existing == Some(blob) {
+                continue;
+            }
+            if is_shared_file(&path) {
+                if let Some(existing) = existing {
+                    if path == Path::new(LAYOUT_FILE) {
+                        return Err(eyre!(
+                            "The archives use different layouts. Reshard {} to the layout of the other archives first",
+                            archive_paths[next.archive]
+                        ));
+                    }
+                    if delta.status() == Delta::Added {
+                        warn!(
+                            "{} differs between the archives. Keeping the version {} of the first archive",
+                            path.display(),
+                            existing
+                        );
+                        continue;
+                    }
+                }
+            } else if let Some(previous) = changed_by.insert(path.clone(), next.archive) {
+                if previous != next.archive {
+                    summary.overlapping_objects += 1;
+                }
+            }
+            update.upsert(&path, blob, delta.new_file().mode());
+            changes += 1;
+        }
+
+        // Commits which only added files the merged history already has are dropped, but
+        // tags and notes pointing to them move to the current head
+        if changes == 0 && diff.deltas().len() > 0 {
+            if let Some(head) = head {
+                summary.deduplicated_commits += 1;
+                rewritten.insert(commit.id(), head);
+                continue;
+            }
+        }
+
+        let new_tree = repository.find_tree(update.create_updated(repository, &merged_tree)?)?;
+        let parents = match head {
+            Some(head) => vec![repository.find_commit(head)?],
+            None => Vec::new(),
+        };
+        let new_commit = create_commit(
+            repository,
+            None,
+            &commit.author(),
+            &commit.committer(),
+            commit.message().unwrap_or(""),
+            &new_tree,
+            &parents.iter().collect::<Vec<_>>(),
+        )?;
+        rewritten.insert(commit.id(), new_commit);
+        positions.insert(new_commit, positions.len());
+        head = Some(new_commit);
+    }
+    summary.commits = positions.len();
+
+    let head = head.ok_or_else(|| eyre!("The archives have no commits"))?;
+    let branch = repository
+        .find_reference("HEAD")?
+        .symbolic_target()
+        .ok_or_else(|| eyre!("HEAD is detached"))?
+        .to_string();
+    repository.reference(&branch, head, true, "osm-git: merge archives")?;
+    repository.checkout_head(Some(CheckoutBuilder::new().force()))?;
+
+    let rewrite = |commit: Oid| {
+        rewritten
+            .get(&commit)
+            .copied()
+            .ok_or_else(|| eyre!("Commit {} is not part of the merged history", commit))
+    };
+
+    // Sequences which were applied in several archives get one tag listing the changesets
+    // of all of them, pointing to the last of their commits
+    let mut sequences: BTreeMap<String, (Oid, Vec<(u64, Oid)>)> = BTreeMap::new();
+    for archive in &archives {
+        for sequence in applied_sequences(archive)? {
+            let target = rewrite(sequence_tag_target(archive, &sequence)?)?;
+            let applied = read_sequence_tag(archive, &sequence)?.unwrap_or_default();
+            let entry = sequences
+                .entry(sequence)
+                .or_insert_with(|| (target, Vec::new()));
+            if positions[&target] > positions[&entry.0] {
+                entry.0 = target;
+            }
+            for (changeset_id, commit) in applied {
+                entry.1.push((changeset_id, rewrite(commit)?));
+            }
+        }
+        for (sequence, commit) in recorded_gaps(archive)? {
+            tag_gap(repository, &sequence, rewrite(commit)?)?;
+        }
+    }
+    for (sequence, (target, mut applied)) in sequences {
+        // Commits which were deduplicated are listed by every archive which made them
+        applied.sort_by_key(|(changeset_id, commit)| (positions[commit], *changeset_id));
+        applied.dedup();
+        tag_sequence_commit(repository, tagger, target, &sequence, &applied)?;
+    }
+
+    let mut notes = Vec::new();
+    for archive in &archives {
+        for note in archive.notes(Some(notes_ref)).into_iter().flatten() {
+            let (_, annotated) = note?;
+            let Some(new_commit) = rewritten.get(&annotated) else {
+                continue;
+            };
+            let note = archive.find_note(Some(notes_ref), annotated)?;
+            notes.push((*new_commit, note.message().unwrap_or("").to_string()));
+        }
+    }
+    write_note_batch(repository, tagger, notes_ref, &notes)?;
+
+    // The merged archive is only complete up to the sequence every archive reached
+    let states = archives
+        .iter()
+        .map(read_state)
+        .collect::<Result<Vec<Option<String>>>>()?;
+    if let Some(state) = states.iter().flatten().min() {
+        if states.iter().any(|other| other.as_ref() != Some(state)) {
+            warn!(
+                "The archives were replayed to different sequences. Resuming after {}",
+                state
+            );
+        }
+        write_state(repository, state)?;
+    }
+
+    for reference in repository.references_glob(&format!("{}*", MERGE_REF_PREFIX))? {
+        reference?.delete()?;
+    }
+    Ok(summary)
+}
+
+/// Fetch the current branch of an archive and list its first-parent history, oldest first
+fn fetch_archive(
+    repository: &Repository,
+    archive: &Repository,
+    path: &str,
+    index: usize,
+) -> Result<Vec<PendingCommit>> {
+    let branch = archive
+        .find_reference("HEAD")?
+        .symbolic_target()
+        .ok_or_else(|| eyre!("HEAD of {} is detached", path))?
+        .to_string();
+    let merge_ref = format!("{}{}", MERGE_REF_PREFIX, index);
+    let url = std::fs::canonicalize(path)?;
+    info!("Fetching {} of {}", branch, path);
+    repository.remote_anonymous(&url.to_string_lossy())?.fetch(
+        &[format!("+{}:{}", branch, merge_ref)],
+        None,
+        None,
+    )?;
+
+    let mut revwalk = repository.revwalk()?;
+    revwalk.push_ref(&merge_ref)?;
+    revwalk.simplify_first_parent()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    revwalk
+        .map(|commit| {
+            let commit = repository.find_commit(commit?)?;
+            Ok(PendingCommit {
+                archive: index,
+                commit: commit.id(),
+                time: commit.time().seconds(),
+            })
+        })
+        .collect()
+}
+
+/// Take the oldest of the next commits of all archives
+///
+/// Each archive keeps its own order, even if its commit times are not monotonic.
+fn next_commit(queues: &mut [Vec<PendingCommit>]) -> Option<PendingCommit> {
+    let queue = queues
+        .iter_mut()
+        .filter(|queue| !queue.is_empty())
+        .min_by_key(|queue| queue[0].time)?;
+    Some(queue.remove(0))
+}
+
+/// Files which are not object files and are shared by all archives
+fn is_shared_file(path: &Path) -> bool {
+    path.starts_with("meta") || path.components().count() == 1
+}
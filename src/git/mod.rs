@@ -1,9 +1,44 @@
-use std::{io::Write, path::Path};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+    path::{Path, PathBuf},
+};
 
-use color_eyre::eyre::Result;
-use git2::{Oid, Repository, Signature};
+use color_eyre::eyre::{eyre, Result};
+use git2::{
+    build::{CheckoutBuilder, TreeUpdateBuilder},
+    Commit, FileMode, ObjectType, Oid, Repository, Signature, Tree,
+};
 use tracing::{info, warn};
 
+use crate::osm::{
+    changesets::{Changeset, LifecycleEvent},
+    layout::{Layout, LayoutMetadata, LAYOUT_FILE},
+    squash::{append_trailers, changeset_trailers},
+};
+
+use self::{
+    cursor::ReplayCursor,
+    identity::{AuthorIdentity, IdentityPolicy},
+    signing::{commit_signing_key, GIT_SIGNATURE_NAMESPACE},
+};
+
+pub mod backend;
+pub mod campaigns;
+pub mod clone;
+pub mod cursor;
+pub mod history;
+pub mod identity;
+pub mod mailmap;
+pub mod merge;
+pub mod notes;
+pub mod provenance;
+pub mod push;
+pub mod revert;
+pub mod signing;
+#[cfg(test)]
+pub mod testing;
+
 /// Initialize the git repository
 ///
 /// If the git repository already exists, open it. Otherwise, create it.
@@ -14,7 +49,9 @@ use tracing::{info, warn};
 ///
 /// * `git_repo_path` - The path to the git repository
 /// * `data_url` - The URL to the OSM data server
-/// * `changeset_url` - The URL to the OSM changeset server
+/// * `author` - The author of the initial commit
+/// * `layout` - The layout of the object files in a newly created repository
+/// * `bare` - Create a bare repository whose commits are built without a working directory
 ///
 /// # Returns
 ///
@@ -23,45 +60,86 @@ pub fn init_git_repository(
     git_repo_path: &str,
     data_url: &str,
     author: &Signature,
+    layout: &LayoutMetadata,
+    bare: bool,
 ) -> Result<Repository> {
     // Check if the git repo already exists
     if std::path::Path::new(git_repo_path).exists() {
         info!("Git repository already exists at {}", git_repo_path);
         // Open the git repo
         let repository = Repository::open(git_repo_path)?;
-
+        if repository.head().is_ok() {
+            return Ok(repository);
+        }
+        // A run which died while creating the git repo left it without the first commit
+        warn!(
+            "The git repository at {} has no commits. Finishing its initialization",
+            git_repo_path
+        );
+        create_initial_commit(&repository, data_url, author, layout)?;
         return Ok(repository);
     }
 
     info!("Initializing git repository at {}", git_repo_path);
 
     // Create the git repo if it doesn't exist
-    let repository = Repository::init(git_repo_path)?;
+    let repository = if bare {
+        Repository::init_bare(git_repo_path)?
+    } else {
+        Repository::init(git_repo_path)?
+    };
+    create_initial_commit(&repository, data_url, author, layout)?;
+    Ok(repository)
+}
+
+/// Commit the README.md and the layout of the object files to a new git repo
+fn create_initial_commit(
+    repository: &Repository,
+    data_url: &str,
+    author: &Signature,
+    layout: &LayoutMetadata,
+) -> Result<()> {
+    if repository.is_bare() {
+        let files = BTreeMap::from([
+            (
+                PathBuf::from("README.md"),
+                Some(readme_contents(data_url).into_bytes()),
+            ),
+            (
+                PathBuf::from(LAYOUT_FILE),
+                Some(serde_yaml::to_string(layout)?.into_bytes()),
+            ),
+        ]);
+        commit_blobs(
+            repository,
+            "HEAD",
+            files,
+            "Create the README.md",
+            author,
+            author,
+        )?;
+        return Ok(());
+    }
 
-    generate_readme_from_template(&repository, data_url)?;
+    generate_readme_from_template(repository, data_url)?;
+    Layout::write_metadata(repository, layout)?;
 
     // Commit the README.md file
     commit(
-        &repository,
-        vec!["README.md".to_string()],
+        repository,
+        "HEAD",
+        vec!["README.md".to_string(), LAYOUT_FILE.to_string()],
         vec![],
         "Create the README.md",
         author,
         author,
     )?;
-    Ok(repository)
+    Ok(())
 }
 
 /// Generate the README.md file from the template and write it to the git repo
 pub fn generate_readme_from_template(repository: &Repository, data_url: &str) -> Result<()> {
-    let template_file = include_str!("../../templates/README.md");
-
-    // Replace the template variables with the actual values
-    let template_file = template_file.replace("$server_url", data_url);
-
-    // Get the version of this binary
-    let version = env!("CARGO_PKG_VERSION");
-    let template_file = template_file.replace("$version", version);
+    let template_file = readme_contents(data_url);
 
     // Write the README.md file in the git repo (parent of .git directory)
     let path = repository
@@ -84,9 +162,24 @@ pub fn generate_readme_from_template(repository: &Repository, data_url: &str) ->
     Ok(())
 }
 
+/// The README.md of a new git repo
+fn readme_contents(data_url: &str) -> String {
+    let template_file = include_str!("../../templates/README.md");
+
+    // Replace the template variables with the actual values
+    let template_file = template_file.replace("$server_url", data_url);
+
+    // Get the version of this binary
+    let version = env!("CARGO_PKG_VERSION");
+    template_file.replace("$version", version)
+}
+
 /// Helper for creating a git commit
+///
+/// The commit is created on top of `update_ref` which is moved to the new commit.
 pub fn commit(
     repository: &Repository,
+    update_ref: &str,
     added_or_changed_files: Vec<String>,
     removed_files: Vec<String>,
     message: &str,
@@ -102,8 +195,8 @@ pub fn commit(
             } else {
                 Path::new(&file)
             };
-            // TODO: I am tired to actually debug this so we just do a sanity check if the file exists
-            if file_path.exists() {
+            // Relative paths are relative to the repository and not to the working directory of the process
+            if repository.path().parent().unwrap().join(path).exists() {
                 index.add_path(path)?;
             } else {
                 warn!(
@@ -128,14 +221,803 @@ pub fn commit(
         index.write_tree()?
     };
     let tree = repository.find_tree(tree_id)?;
-    let head_id = repository.refname_to_id("HEAD");
+    #[cfg(feature = "fault-injection")]
+    crate::faults::git_lock(update_ref)?;
+    let head_id = repository.refname_to_id(update_ref);
     if let Ok(head_id) = head_id {
         let parent = repository.find_commit(head_id)?;
 
-        let oid = repository.commit(Some("HEAD"), author, committer, message, &tree, &[&parent])?;
-        Ok(oid)
+        create_commit(
+            repository,
+            Some(update_ref),
+            author,
+            committer,
+            message,
+            &tree,
+            &[&parent],
+        )
     } else {
-        let oid = repository.commit(Some("HEAD"), author, committer, message, &tree, &[])?;
-        Ok(oid)
+        create_commit(
+            repository,
+            Some(update_ref),
+            author,
+            committer,
+            message,
+            &tree,
+            &[],
+        )
+    }
+}
+
+/// Create a commit, signed if a commit signing key is set
+///
+/// Like [`Repository::commit`], `update_ref` is moved to the new commit if it is given.
+/// It must not exist yet or point to the first parent.
+pub fn create_commit(
+    repository: &Repository,
+    update_ref: Option<&str>,
+    author: &Signature,
+    committer: &Signature,
+    message: &str,
+    tree: &Tree,
+    parents: &[&Commit],
+) -> Result<Oid> {
+    let Some(signing_key) = commit_signing_key() else {
+        return Ok(repository.commit(update_ref, author, committer, message, tree, parents)?);
+    };
+    let buffer = repository.commit_create_buffer(author, committer, message, tree, parents)?;
+    let content = buffer
+        .as_str()
+        .ok_or_else(|| eyre!("The commit to sign is not valid UTF-8"))?;
+    let signature = signing_key.sign(content.as_bytes(), GIT_SIGNATURE_NAMESPACE)?;
+    let oid = repository.commit_signed(content, &signature, None)?;
+
+    if let Some(update_ref) = update_ref {
+        // Move the branch HEAD points to instead of detaching HEAD
+        let name = match repository.find_reference(update_ref) {
+            Ok(reference) => reference
+                .symbolic_target()
+                .unwrap_or(update_ref)
+                .to_string(),
+            Err(_) => update_ref.to_string(),
+        };
+        let current = repository.refname_to_id(&name).ok();
+        if current.is_some() && current != parents.first().map(|parent| parent.id()) {
+            return Err(eyre!(
+                "Unable to commit to {} as it no longer points to the parent",
+                name
+            ));
+        }
+        repository.reference(
+            &name,
+            oid,
+            true,
+            &format!("commit: {}", message.lines().next().unwrap_or_default()),
+        )?;
+    }
+    Ok(oid)
+}
+
+/// Create an annotated tag of `target`, signed if a commit signing key is set
+///
+/// An existing tag of the same name is replaced.
+pub fn create_tag(
+    repository: &Repository,
+    name: &str,
+    target: &Commit,
+    tagger: &Signature,
+    message: &str,
+) -> Result<Oid> {
+    let Some(signing_key) = commit_signing_key() else {
+        return Ok(repository.tag(name, target.as_object(), tagger, message, true)?);
+    };
+    // libgit2 can't sign tags, so the tag object is written as git would write it
+    let offset = tagger.when().offset_minutes();
+    let mut content = format!(
+        "object {}\ntype commit\ntag {}\ntagger {} <{}> {} {}{:02}{:02}\n\n{}",
+        target.id(),
+        name,
+        String::from_utf8_lossy(tagger.name_bytes()),
+        String::from_utf8_lossy(tagger.email_bytes()),
+        tagger.when().seconds(),
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 60,
+        offset.abs() % 60,
+        message
+    );
+    if !content.ends_with('\n') {
+        content.push('\n');
+    }
+    // The signature of a tag goes after its message
+    let signature = signing_key.sign(content.as_bytes(), GIT_SIGNATURE_NAMESPACE)?;
+    content.push_str(&signature);
+    let oid = repository
+        .odb()?
+        .write(ObjectType::Tag, content.as_bytes())?;
+    repository.reference(
+        &format!("refs/tags/{}", name),
+        oid,
+        true,
+        &format!("osm-git: tag {}", name),
+    )?;
+    Ok(oid)
+}
+
+/// Create a git commit from file contents without a working directory or index
+///
+/// The tree of the commit `update_ref` points to is updated in memory: files mapped to
+/// `Some` content are written as blobs and files mapped to `None` are removed. The ref
+/// is moved to the new commit.
+pub fn commit_blobs(
+    repository: &Repository,
+    update_ref: &str,
+    files: BTreeMap<PathBuf, Option<Vec<u8>>>,
+    message: &str,
+    author: &Signature,
+    committer: &Signature,
+) -> Result<Oid> {
+    let parent = match repository.refname_to_id(update_ref) {
+        Ok(parent_id) => Some(repository.find_commit(parent_id)?),
+        Err(_) => None,
+    };
+    let baseline = match &parent {
+        Some(parent) => parent.tree()?,
+        None => repository.find_tree(repository.treebuilder(None)?.write()?)?,
+    };
+
+    let mut update = TreeUpdateBuilder::new();
+    for (path, contents) in files {
+        match contents {
+            Some(contents) => {
+                let blob = repository.blob(&contents)?;
+                update.upsert(&path, blob, FileMode::Blob);
+            }
+            // We check if it was tracked before. If not we don't need to remove it
+            None if baseline.get_path(&path).is_ok() => {
+                update.remove(&path);
+            }
+            None => {}
+        }
+    }
+    let tree = repository.find_tree(update.create_updated(repository, &baseline)?)?;
+    let parents = parent.iter().collect::<Vec<_>>();
+    #[cfg(feature = "fault-injection")]
+    crate::faults::git_lock(update_ref)?;
+    create_commit(
+        repository,
+        Some(update_ref),
+        author,
+        committer,
+        message,
+        &tree,
+        &parents,
+    )
+}
+
+/// Commit a new version of a metadata file like `meta/idmap` on top of HEAD
+///
+/// In a repo with a working directory the file is written there too, so the index and
+/// working directory stay in line with HEAD.
+pub fn commit_meta_file(
+    repository: &Repository,
+    path: &str,
+    contents: Vec<u8>,
+    message: &str,
+    committer: &Signature,
+) -> Result<Oid> {
+    commit_meta_files(
+        repository,
+        BTreeMap::from([(path.to_string(), contents)]),
+        message,
+        committer,
+    )
+}
+
+/// Commit new versions of several metadata files on top of HEAD, like
+/// [`commit_meta_file`]
+pub fn commit_meta_files(
+    repository: &Repository,
+    files: BTreeMap<String, Vec<u8>>,
+    message: &str,
+    committer: &Signature,
+) -> Result<Oid> {
+    let Some(workdir) = repository.workdir() else {
+        let files = files
+            .into_iter()
+            .map(|(path, contents)| (PathBuf::from(path), Some(contents)))
+            .collect();
+        return commit_blobs(repository, "HEAD", files, message, committer, committer);
+    };
+    for (path, contents) in &files {
+        let file_path = workdir.join(path);
+        std::fs::create_dir_all(file_path.parent().unwrap())?;
+        std::fs::write(&file_path, contents)?;
+    }
+    commit(
+        repository,
+        "HEAD",
+        files.into_keys().collect(),
+        vec![],
+        message,
+        committer,
+        committer,
+    )
+}
+
+/// Commit object files on top of HEAD, through the working directory if the repo has one
+///
+/// Files mapped to `None` are removed.
+pub fn commit_head_files(
+    repository: &Repository,
+    files: BTreeMap<PathBuf, Option<Vec<u8>>>,
+    message: &str,
+    committer: &Signature,
+) -> Result<Oid> {
+    let Some(workdir) = repository.workdir().map(Path::to_path_buf) else {
+        return commit_blobs(repository, "HEAD", files, message, committer, committer);
+    };
+    let mut added_or_changed_files = Vec::new();
+    let mut removed_files = Vec::new();
+    for (path, contents) in files {
+        let file_path = workdir.join(&path);
+        match contents {
+            Some(contents) => {
+                std::fs::create_dir_all(file_path.parent().unwrap())?;
+                std::fs::write(&file_path, contents)?;
+                added_or_changed_files.push(path.to_string_lossy().to_string());
+            }
+            None => {
+                if file_path.exists() {
+                    std::fs::remove_file(&file_path)?;
+                }
+                removed_files.push(path.to_string_lossy().to_string());
+            }
+        }
+    }
+    commit(
+        repository,
+        "HEAD",
+        added_or_changed_files,
+        removed_files,
+        message,
+        committer,
+        committer,
+    )
+}
+
+/// The prefix of the tags marking replication sequences which are missing upstream
+const GAP_TAG_PREFIX: &str = "gap/";
+
+/// Record a replication sequence which is missing upstream
+///
+/// An empty commit labeled with the missing sequence is created and tagged as
+/// `gap/<sequence>`, so consumers of the repository can detect incomplete coverage.
+pub fn commit_gap(repository: &Repository, committer: &Signature, sequence: &str) -> Result<Oid> {
+    if let Ok(reference) =
+        repository.find_reference(&format!("refs/tags/{}{}", GAP_TAG_PREFIX, sequence))
+    {
+        let gap_commit = reference.peel_to_commit()?.id();
+        if in_head_history(repository, gap_commit)? {
+            info!("The gap of sequence {} was recorded before", sequence);
+            return Ok(gap_commit);
+        }
+    }
+    warn!("Recording replication gap for sequence {}", sequence);
+    let message = format!(
+        "Replication gap: sequence {} is missing upstream\n\nNo data was applied for this sequence.",
+        sequence
+    );
+    let oid = commit(
+        repository,
+        "HEAD",
+        vec![],
+        vec![],
+        &message,
+        committer,
+        committer,
+    )?;
+    tag_gap(repository, sequence, oid)?;
+    Ok(oid)
+}
+
+/// The trailer of the empty commits recording a changeset being opened or closed
+pub const CHANGESET_EVENT_TRAILER: &str = "Changeset-Event";
+
+/// Record a changeset being opened or closed as an empty commit on `update_ref`
+///
+/// The commit is authored by the user of the changeset, or the committer depending on the
+/// identity policy, at the time of the event and has
+/// the trailers of the changeset, so the lifecycle of a changeset is in the history even
+/// if none of its edits are.
+pub fn commit_changeset_event(
+    repository: &Repository,
+    update_ref: &str,
+    committer: &Signature,
+    identity_policy: IdentityPolicy,
+    authors: &AuthorIdentity,
+    changeset: &Changeset,
+    event: LifecycleEvent,
+) -> Result<Oid> {
+    let (subject, author) = match event {
+        LifecycleEvent::Opened => (
+            format!("Opened changeset {}", changeset.id),
+            changeset.opened_signature(authors)?,
+        ),
+        LifecycleEvent::Closed => (
+            format!("Closed changeset {}", changeset.id),
+            changeset.author_signature(authors)?,
+        ),
+    };
+    let author = identity_policy.author(author, committer)?;
+    let mut trailers = changeset_trailers(changeset);
+    trailers.push((CHANGESET_EVENT_TRAILER, event.name().to_string()));
+    let message = append_trailers(
+        subject,
+        &trailers
+            .iter()
+            .map(|(key, value)| (*key, value.as_str()))
+            .collect::<Vec<(&str, &str)>>(),
+    );
+    commit_blobs(
+        repository,
+        update_ref,
+        BTreeMap::new(),
+        &message,
+        &author,
+        committer,
+    )
+}
+
+/// Whether `commit` is HEAD or one of its ancestors
+pub fn in_head_history(repository: &Repository, commit: Oid) -> Result<bool> {
+    let head = repository.head()?.peel_to_commit()?.id();
+    Ok(head == commit || repository.graph_descendant_of(head, commit)?)
+}
+
+/// Tag `commit` as the gap commit of a replication sequence
+pub fn tag_gap(repository: &Repository, sequence: &str, commit: Oid) -> Result<()> {
+    let gap_commit = repository.find_commit(commit)?;
+    repository.tag_lightweight(
+        &format!("{}{}", GAP_TAG_PREFIX, sequence),
+        gap_commit.as_object(),
+        true,
+    )?;
+    Ok(())
+}
+
+/// The replication sequences recorded as gaps together with their gap commits
+pub fn recorded_gaps(repository: &Repository) -> Result<Vec<(String, Oid)>> {
+    let tag_names = repository.tag_names(Some(&format!("{}*", GAP_TAG_PREFIX)))?;
+    let mut gaps = Vec::new();
+    for tag_name in tag_names.iter().flatten() {
+        let commit = repository
+            .find_reference(&format!("refs/tags/{}", tag_name))?
+            .peel_to_commit()?;
+        if let Some(sequence) = tag_name.strip_prefix(GAP_TAG_PREFIX) {
+            gaps.push((sequence.to_string(), commit.id()));
+        }
+    }
+    Ok(gaps)
+}
+
+/// The ref pointing to a blob with the last applied replication sequence
+pub const STATE_REF: &str = "refs/osm/state";
+
+/// The ref pointing to the commit of the last fully applied replication sequence
+///
+/// It only moves once the commits, notes and state of a sequence are all written, so
+/// readers like the HTTP endpoints never see a sequence half-way through being applied.
+pub const CONSISTENT_REF: &str = "refs/osm/consistent";
+
+/// Record the last applied replication sequence
+///
+/// The published commit of the sequence becomes the [`CONSISTENT_REF`].
+pub fn write_state(repository: &Repository, sequence: &str) -> Result<()> {
+    let blob = repository.blob(format!("{}\n", sequence).as_bytes())?;
+    repository.reference(
+        STATE_REF,
+        blob,
+        true,
+        &format!("osm-git: applied sequence {}", sequence),
+    )?;
+    let head = repository.head()?.peel_to_commit()?;
+    repository.reference(
+        CONSISTENT_REF,
+        head.id(),
+        true,
+        &format!("osm-git: sequence {} is consistent", sequence),
+    )?;
+    Ok(())
+}
+
+/// The commit readers should see while a replay may be writing to the git repo
+///
+/// This is the [`CONSISTENT_REF`], or HEAD in git repos which were not replayed into.
+pub fn consistent_commit<'r>(repository: &'r Repository) -> Result<Commit<'r>> {
+    match repository.find_reference(CONSISTENT_REF) {
+        Ok(reference) => Ok(reference.peel_to_commit()?),
+        Err(_) => Ok(repository.head()?.peel_to_commit()?),
+    }
+}
+
+/// Read the last applied replication sequence
+///
+/// # Returns
+///
+/// * `Result<Option<String>>` - `None` if no sequence was applied yet
+pub fn read_state(repository: &Repository) -> Result<Option<String>> {
+    let Ok(state) = repository.find_reference(STATE_REF) else {
+        return Ok(None);
+    };
+    let blob = state.peel_to_blob()?;
+    let sequence = std::str::from_utf8(blob.content())?.trim().to_string();
+    Ok(Some(sequence))
+}
+
+/// The ref the commits of a replication file are staged on before they are published
+pub const STAGING_REF: &str = "refs/osm/staging";
+
+/// Start staging the commits of a replication file on top of the current HEAD
+///
+/// If the staged commits of an interrupted run of the same replication file are still
+/// there, they are kept and the returned cursor tells which changesets are already staged.
+pub fn begin_staging(repository: &Repository, sequence: &str) -> Result<ReplayCursor> {
+    if let Some(cursor) = ReplayCursor::load(repository)? {
+        if cursor.sequence == sequence && repository.find_reference(STAGING_REF).is_ok() {
+            info!(
+                "Resuming sequence {} after {} staged commits",
+                sequence,
+                cursor.staged.len()
+            );
+            return Ok(cursor);
+        }
+        discard_staging(repository)?;
+    }
+
+    let head = repository.head()?.peel_to_commit()?;
+    repository.reference(STAGING_REF, head.id(), true, "osm-git: begin staging")?;
+    let cursor = ReplayCursor::new(sequence);
+    cursor.save(repository)?;
+    Ok(cursor)
+}
+
+/// Fast-forward the current branch to the staged commits
+///
+/// This is the only point at which the published branch changes, so it either contains
+/// all commits of a replication file or none of them.
+pub fn publish_staging(repository: &Repository) -> Result<()> {
+    let mut staging = repository.find_reference(STAGING_REF)?;
+    let staged_id = staging
+        .target()
+        .ok_or_else(|| eyre!("{} is not a direct reference", STAGING_REF))?;
+    let head_id = repository.head()?.peel_to_commit()?.id();
+    if head_id != staged_id && !repository.graph_descendant_of(staged_id, head_id)? {
+        return Err(eyre!(
+            "Staged commit {} is not a fast-forward of HEAD {}",
+            staged_id,
+            head_id
+        ));
+    }
+
+    let branch = repository
+        .find_reference("HEAD")?
+        .symbolic_target()
+        .ok_or_else(|| eyre!("HEAD is detached"))?
+        .to_string();
+    // Readers fall back to HEAD without a consistent ref, so pin them to the current HEAD
+    // before the branch moves ahead of the notes and state
+    if repository.find_reference(CONSISTENT_REF).is_err() {
+        repository.reference(
+            CONSISTENT_REF,
+            head_id,
+            false,
+            "osm-git: consistent before the first replayed sequence",
+        )?;
+    }
+    #[cfg(feature = "fault-injection")]
+    crate::faults::git_lock(&branch)?;
+    repository.reference(&branch, staged_id, true, "osm-git: publish staged commits")?;
+    staging.delete()?;
+    ReplayCursor::clear(repository)?;
+    Ok(())
+}
+
+/// Bring the working directory back in line with the staged commits of an interrupted run
+///
+/// If the cursor matches the staged commits, the index and working directory are reset to
+/// the last staged commit, so the replication file can be resumed where it stopped.
+/// Otherwise the staged commits are discarded and the replication file is applied again
+/// from scratch.
+pub fn recover_staging(repository: &Repository) -> Result<()> {
+    let Ok(staging) = repository.find_reference(STAGING_REF) else {
+        return ReplayCursor::clear(repository);
+    };
+
+    let cursor = ReplayCursor::load(repository)?;
+    let staged_id = staging.target();
+    match cursor {
+        Some(cursor) if cursor.last_commit().is_some() && cursor.last_commit() == staged_id => {
+            warn!(
+                "Found {} staged commits of an interrupted run of sequence {}. Resuming from them",
+                cursor.staged.len(),
+                cursor.sequence
+            );
+            // Bare repos have no working directory or index to bring in line
+            if repository.is_bare() {
+                return Ok(());
+            }
+            let staged = staging.peel_to_commit()?;
+            let staged_paths = staged_paths(repository, &staged)?;
+            reset_worktree(repository, &staged, &staged_paths)
+        }
+        _ => {
+            warn!("Found staged commits of an interrupted run. Discarding them");
+            discard_staging(repository)
+        }
+    }
+}
+
+/// Throw away the staged commits and reset the index and working directory to the
+/// published HEAD
+fn discard_staging(repository: &Repository) -> Result<()> {
+    let mut staged_files = Vec::new();
+    if let Ok(mut staging) = repository.find_reference(STAGING_REF) {
+        staged_files = staged_paths(repository, &staging.peel_to_commit()?)?;
+        staging.delete()?;
+    }
+    ReplayCursor::clear(repository)?;
+    if repository.is_bare() {
+        return Ok(());
+    }
+
+    let head = repository.head()?.peel_to_commit()?;
+    reset_worktree(repository, &head, &staged_files)
+}
+
+/// The files the staged commits up to `staged` changed compared to HEAD
+fn staged_paths(repository: &Repository, staged: &Commit) -> Result<Vec<PathBuf>> {
+    let head_tree = repository.head()?.peel_to_tree()?;
+    let diff = repository.diff_tree_to_tree(Some(&head_tree), Some(&staged.tree()?), None)?;
+    Ok(diff
+        .deltas()
+        .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+        .flatten()
+        .map(Path::to_path_buf)
+        .collect::<BTreeSet<PathBuf>>()
+        .into_iter()
+        .collect())
+}
+
+/// Bring the index and working directory in line with `target`
+///
+/// Tracked files are reset to `target`. Untracked files are only removed at `paths`, the
+/// files of the staged commits, so files the replay didn't write are left alone.
+fn reset_worktree(repository: &Repository, target: &Commit, paths: &[PathBuf]) -> Result<()> {
+    repository.checkout_tree(target.as_object(), Some(CheckoutBuilder::new().force()))?;
+    if !paths.is_empty() {
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force().remove_untracked(true);
+        // The paths of objects have no wildcards of pathspecs
+        for path in paths {
+            checkout.path(path);
+        }
+        repository.checkout_tree(target.as_object(), Some(&mut checkout))?;
+    }
+    let mut index = repository.index()?;
+    index.read_tree(&target.tree()?)?;
+    index.write()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::{signature, TestRepository};
+
+    /// Stage a commit of a replication file, like the replay does before publishing it
+    fn stage(repository: &Repository, sequence: &str) -> Oid {
+        let mut cursor = begin_staging(repository, sequence).unwrap();
+        let files = BTreeMap::from([(
+            PathBuf::from(format!("nodes/000/{}.yaml", sequence.replace('/', ""))),
+            Some(format!("sequence: {}\n", sequence).into_bytes()),
+        )]);
+        let commit = commit_blobs(
+            repository,
+            STAGING_REF,
+            files,
+            sequence,
+            &signature(),
+            &signature(),
+        )
+        .unwrap();
+        cursor.record(repository, vec![1], commit).unwrap();
+        commit
+    }
+
+    /// What a reader sees: the consistent commit, which must never be ahead of the branch
+    fn read(repository: &Repository) -> Oid {
+        // The consistent ref is read before the branch, so a writer moving both in between
+        // can only make the branch newer
+        let consistent = consistent_commit(repository).unwrap().id();
+        let head = repository.head().unwrap().peel_to_commit().unwrap().id();
+        assert!(
+            consistent == head || repository.graph_descendant_of(head, consistent).unwrap(),
+            "{} is ahead of the published branch {}",
+            consistent,
+            head
+        );
+        consistent
+    }
+
+    #[test]
+    fn readers_only_see_fully_applied_sequences() {
+        let repository = TestRepository::new("consistent", true);
+        let initial = read(&repository);
+
+        let first = stage(&repository, "000/000/001");
+        // Staged commits are not visible
+        assert_eq!(read(&repository), initial);
+        publish_staging(&repository).unwrap();
+        // Published, but the notes and state are not written yet
+        assert_eq!(read(&repository), initial);
+        write_state(&repository, "000/000/001").unwrap();
+        assert_eq!(read(&repository), first);
+        assert_eq!(
+            read_state(&repository).unwrap().as_deref(),
+            Some("000/000/001")
+        );
+    }
+
+    #[test]
+    fn interrupted_runs_never_move_the_consistent_ref_ahead() {
+        let repository = TestRepository::new("consistent-interrupted", true);
+        stage(&repository, "000/000/001");
+        publish_staging(&repository).unwrap();
+        write_state(&repository, "000/000/001").unwrap();
+        let first = read(&repository);
+
+        // Interrupted while staging: the next run recovers the staged commits
+        let second = stage(&repository, "000/000/002");
+        recover_staging(&repository).unwrap();
+        assert_eq!(read(&repository), first);
+        assert_eq!(repository.refname_to_id(STAGING_REF).unwrap(), second);
+
+        // Interrupted between publishing and writing the state: the branch is ahead, the
+        // readers still see the last consistent sequence
+        publish_staging(&repository).unwrap();
+        recover_staging(&repository).unwrap();
+        assert_eq!(read(&repository), first);
+        assert_eq!(
+            read_state(&repository).unwrap().as_deref(),
+            Some("000/000/001")
+        );
+
+        // The next run applies the sequence again, which writes the state
+        stage(&repository, "000/000/002");
+        publish_staging(&repository).unwrap();
+        write_state(&repository, "000/000/002").unwrap();
+        let third = read(&repository);
+        assert_ne!(third, first);
+        assert_eq!(
+            third,
+            repository.head().unwrap().peel_to_commit().unwrap().id()
+        );
+    }
+
+    #[test]
+    fn concurrent_readers_never_see_the_consistent_ref_ahead() {
+        let repository = TestRepository::new("consistent-concurrent", true);
+        let path = repository.path().to_path_buf();
+        let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let reader = {
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let repository = Repository::open(path).unwrap();
+                let mut reads = 0;
+                while !done.load(std::sync::atomic::Ordering::Relaxed) || reads == 0 {
+                    read(&repository);
+                    reads += 1;
+                }
+                reads
+            })
+        };
+        for sequence in 1..=20 {
+            let sequence = format!("000/000/{:03}", sequence);
+            stage(&repository, &sequence);
+            publish_staging(&repository).unwrap();
+            write_state(&repository, &sequence).unwrap();
+        }
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        assert!(reader.join().unwrap() > 0);
+        assert_eq!(
+            read(&repository),
+            repository.head().unwrap().peel_to_commit().unwrap().id()
+        );
+    }
+
+    /// A worktree repo with files of the user and an interrupted run, which staged a new
+    /// object, wrote it to the working directory and changed a tracked file
+    fn interrupted_worktree() -> (TestRepository, Oid) {
+        let repository = TestRepository::new("interrupted", false);
+        let workdir = repository.workdir().unwrap().to_path_buf();
+        std::fs::write(workdir.join("my-notes.txt"), "mine").unwrap();
+        std::fs::create_dir_all(workdir.join("nodes/000")).unwrap();
+        std::fs::write(workdir.join("nodes/000/mine.txt"), "mine").unwrap();
+
+        let staged = stage(&repository, "000/000/001");
+        std::fs::write(
+            workdir.join("nodes/000/000000001.yaml"),
+            "sequence: 000/000/001\n",
+        )
+        .unwrap();
+        std::fs::write(workdir.join("README.md"), "changed by the interrupted run").unwrap();
+        (repository, staged)
+    }
+
+    fn exists(repository: &Repository, path: &str) -> bool {
+        repository.workdir().unwrap().join(path).exists()
+    }
+
+    /// The files git reports as untracked or changed
+    fn changes(repository: &Repository) -> Vec<String> {
+        let mut options = git2::StatusOptions::new();
+        options.include_untracked(true).recurse_untracked_dirs(true);
+        repository
+            .statuses(Some(&mut options))
+            .unwrap()
+            .iter()
+            .map(|entry| entry.path().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn discarding_staged_commits_keeps_untracked_files() {
+        let (repository, _) = interrupted_worktree();
+        discard_staging(&repository).unwrap();
+
+        assert!(repository.find_reference(STAGING_REF).is_err());
+        assert!(!exists(&repository, "nodes/000/000000001.yaml"));
+        assert_eq!(changes(&repository), ["my-notes.txt", "nodes/000/mine.txt"]);
+    }
+
+    #[test]
+    fn recovering_staged_commits_keeps_untracked_files() {
+        let (repository, staged) = interrupted_worktree();
+        recover_staging(&repository).unwrap();
+
+        assert_eq!(repository.refname_to_id(STAGING_REF).unwrap(), staged);
+        assert!(exists(&repository, "nodes/000/000000001.yaml"));
+        let index = repository.index().unwrap();
+        assert!(index
+            .get_path(Path::new("nodes/000/000000001.yaml"), 0)
+            .is_some());
+        // Compared to HEAD, the staged object is new
+        assert_eq!(
+            changes(&repository),
+            [
+                "my-notes.txt",
+                "nodes/000/000000001.yaml",
+                "nodes/000/mine.txt"
+            ]
+        );
+        assert_eq!(
+            std::fs::read_to_string(repository.workdir().unwrap().join("README.md")).unwrap(),
+            std::str::from_utf8(
+                repository
+                    .head()
+                    .unwrap()
+                    .peel_to_tree()
+                    .unwrap()
+                    .get_path(Path::new("README.md"))
+                    .unwrap()
+                    .to_object(&repository)
+                    .unwrap()
+                    .peel_to_blob()
+                    .unwrap()
+                    .content()
+            )
+            .unwrap()
+        );
     }
 }
@@ -0,0 +1,586 @@
+use std::collections::BTreeMap;
+
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
+use git2::{build::TreeUpdateBuilder, FileMode, Oid, Repository, Signature, Tree};
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use super::{create_tag, in_head_history};
+use crate::osm::changesets::{load_changesets, BBox, Changeset};
+
+/// The notes ref the changeset metadata is written to by default
+///
+/// A namespace of its own keeps the notes apart from the ones people write with
+/// `git notes`, which go to `refs/notes/commits`.
+pub const CHANGESET_NOTES_REF: &str = "refs/notes/osm-changesets";
+
+/// The notes ref of git repos replayed before the notes ref was configurable
+const DEFAULT_NOTES_REF: &str = "refs/notes/commits";
+
+/// The prefix of the tags marking the last commit of a replication sequence
+const SEQUENCE_TAG_PREFIX: &str = "sequence/";
+
+/// A changeset which was applied to the repository as a commit
+#[derive(Debug, Clone)]
+pub struct AppliedChangeset {
+    pub changeset: Changeset,
+    pub commit: Oid,
+    /// The changeset was not found in the dump, so it was committed with placeholder
+    /// metadata and has no note
+    pub metadata_missing: bool,
+}
+
+/// Generate the note text for a changeset
+///
+/// The note contains the legacy changeset id and bounding box followed by all changeset
+/// tags as "Key: Value" lines.
+pub fn changeset_note(changeset: &Changeset) -> String {
+    let mut tags = changeset
+        .tags
+        .iter()
+        .filter(|(key, _)| !key.trim().is_empty())
+        .map(|(key, value)| format!("{}: {}", key, value))
+        .collect::<Vec<String>>();
+    // Keep the note stable between runs so rewriting it is a no-op
+    tags.sort();
+
+    let header = format!(
+        "Legacy Changeset ID: {}\nBounding Box: {}",
+        changeset.id,
+        changeset.spatial_scope()
+    );
+    if tags.is_empty() {
+        header
+    } else {
+        format!("{}\n{}", header, tags.join("\n"))
+    }
+}
+
+/// The format of the changeset notes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NoteFormat {
+    /// "Key: Value" lines meant to be read by humans
+    #[default]
+    Plain,
+    /// A YAML list with the full metadata of the changesets in the commit
+    Yaml,
+}
+
+/// The metadata of a changeset as stored in YAML notes
+#[derive(Debug, Serialize)]
+struct ChangesetNote<'a> {
+    id: u64,
+    user: Option<&'a str>,
+    uid: Option<u64>,
+    created_at: &'a str,
+    closed_at: Option<&'a str>,
+    open: bool,
+    /// `None` for changesets without a bounding box
+    bbox: Option<BBox>,
+    tags: BTreeMap<&'a str, &'a str>,
+}
+
+impl<'a> From<&'a Changeset> for ChangesetNote<'a> {
+    fn from(changeset: &'a Changeset) -> Self {
+        ChangesetNote {
+            id: changeset.id,
+            user: changeset.user.as_deref(),
+            uid: changeset.uid,
+            created_at: &changeset.created_at,
+            closed_at: changeset.closed_at.as_deref(),
+            open: changeset.open,
+            bbox: changeset.bbox(),
+            tags: changeset
+                .tags
+                .iter()
+                .filter(|(key, _)| !key.trim().is_empty())
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect(),
+        }
+    }
+}
+
+impl NoteFormat {
+    /// Generate the note for the changesets applied in one commit
+    pub fn note(self, changesets: &[&Changeset]) -> Result<String> {
+        match self {
+            NoteFormat::Plain => Ok(changesets
+                .iter()
+                .map(|changeset| changeset_note(changeset))
+                .collect::<Vec<String>>()
+                .join("\n\n")),
+            NoteFormat::Yaml => Ok(serde_yaml::to_string(
+                &changesets
+                    .iter()
+                    .map(|changeset| ChangesetNote::from(*changeset))
+                    .collect::<Vec<ChangesetNote>>(),
+            )?),
+        }
+    }
+}
+
+/// Write the notes for a batch of applied changesets
+///
+/// Changesets which were squashed into the same commit share one note with one section
+/// (or list entry for YAML notes) per changeset. All notes of the batch are written with
+/// a single commit to the notes ref.
+///
+/// Writing is idempotent: notes which already exist with the expected content are left
+/// alone and outdated ones are replaced. This makes it safe to re-run after a crash.
+///
+/// # Returns
+///
+/// * `Result<usize>` - The number of notes which were written
+pub fn write_notes(
+    repository: &Repository,
+    committer: &Signature,
+    applied_changesets: &[AppliedChangeset],
+    note_format: NoteFormat,
+    notes_ref: &str,
+) -> Result<usize> {
+    let mut changesets_per_commit: Vec<(Oid, Vec<&Changeset>)> = Vec::new();
+    for applied in applied_changesets {
+        match changesets_per_commit.last_mut() {
+            Some((commit, changesets)) if *commit == applied.commit => {
+                changesets.push(&applied.changeset)
+            }
+            _ => changesets_per_commit.push((applied.commit, vec![&applied.changeset])),
+        }
+    }
+
+    let mut notes = Vec::new();
+    for (commit, changesets) in changesets_per_commit {
+        let note = note_format.note(&changesets)?;
+        if let Ok(existing) = repository.find_note(Some(notes_ref), commit) {
+            if existing.message() == Some(note.as_str()) {
+                debug!("Note for commit {} is up to date", commit);
+                continue;
+            }
+        }
+        notes.push((commit, note));
+    }
+    write_note_batch(repository, committer, notes_ref, &notes)?;
+    Ok(notes.len())
+}
+
+/// The path of the note of `commit` in a notes tree
+///
+/// Git moves notes into fan-out directories like `ab/cdef…` once there are many of
+/// them, so an existing note is replaced where it is. New notes go to the top level.
+fn note_path(tree: Option<&Tree>, commit: Oid) -> String {
+    let hex = commit.to_string();
+    let candidates = [
+        format!("{}/{}/{}", &hex[..2], &hex[2..4], &hex[4..]),
+        format!("{}/{}", &hex[..2], &hex[2..]),
+    ];
+    tree.and_then(|tree| {
+        candidates
+            .into_iter()
+            .find(|path| tree.get_path(std::path::Path::new(path)).is_ok())
+    })
+    .unwrap_or(hex)
+}
+
+/// Add or replace the notes of several commits with one commit to the notes ref
+pub fn write_note_batch(
+    repository: &Repository,
+    committer: &Signature,
+    notes_ref: &str,
+    notes: &[(Oid, String)],
+) -> Result<()> {
+    if notes.is_empty() {
+        return Ok(());
+    }
+    let parent = match repository.find_reference(notes_ref) {
+        Ok(reference) => Some(reference.peel_to_commit()?),
+        Err(_) => None,
+    };
+    let parent_tree = parent.as_ref().map(|parent| parent.tree()).transpose()?;
+    let base_tree = match &parent_tree {
+        Some(tree) => tree.clone(),
+        None => repository.find_tree(repository.treebuilder(None)?.write()?)?,
+    };
+
+    let mut update = TreeUpdateBuilder::new();
+    for (commit, note) in notes {
+        let blob = repository.blob(note.as_bytes())?;
+        update.upsert(
+            note_path(parent_tree.as_ref(), *commit),
+            blob,
+            FileMode::Blob,
+        );
+    }
+    let tree = repository.find_tree(update.create_updated(repository, &base_tree)?)?;
+    let message = format!("Notes added by osm-git for {} commits", notes.len());
+    let parents = parent.iter().collect::<Vec<_>>();
+    #[cfg(feature = "fault-injection")]
+    crate::faults::git_lock(notes_ref)?;
+    repository.commit(
+        Some(notes_ref),
+        committer,
+        committer,
+        &message,
+        &tree,
+        &parents,
+    )?;
+    Ok(())
+}
+
+/// Show the changeset notes in `git log` of the git repo
+///
+/// Git repos replayed before the notes ref was configurable have their notes in the
+/// default namespace, which is left for the user to move.
+pub fn prepare_notes_ref(repository: &Repository, notes_ref: &str) -> Result<()> {
+    if notes_ref != DEFAULT_NOTES_REF
+        && repository.find_reference(notes_ref).is_err()
+        && repository.find_reference(DEFAULT_NOTES_REF).is_ok()
+    {
+        warn!(
+            "The git repo has notes in {} but none in {}. Move them with `git update-ref {} {}` if they are changeset notes, or pass --notes-ref {}",
+            DEFAULT_NOTES_REF, notes_ref, notes_ref, DEFAULT_NOTES_REF, DEFAULT_NOTES_REF
+        );
+    }
+    let mut config = repository.config()?;
+    let mut shown = false;
+    config
+        .multivar("notes.displayRef", None)?
+        .for_each(|entry| shown |= entry.value() == Some(notes_ref))?;
+    if !shown {
+        // A pattern matching no value adds the ref to the list
+        config.set_multivar("notes.displayRef", "^$", notes_ref)?;
+    }
+    Ok(())
+}
+
+/// Name of the tag marking the given replication sequence
+pub fn sequence_tag_name(sequence: &str) -> String {
+    format!("{}{}", SEQUENCE_TAG_PREFIX, sequence)
+}
+
+/// Tag the commit `reference` points to with the replication sequence and the changesets
+/// applied in it
+///
+/// The tag message lists one `<changeset id> <commit id>` pair per line. It is the source
+/// of truth for rebuilding notes later on.
+pub fn tag_sequence(
+    repository: &Repository,
+    tagger: &Signature,
+    reference: &str,
+    sequence: &str,
+    applied_changesets: &[AppliedChangeset],
+) -> Result<()> {
+    let head = repository.find_reference(reference)?.peel_to_commit()?;
+    let applied = applied_changesets
+        .iter()
+        .map(|applied| (applied.changeset.id, applied.commit))
+        .collect::<Vec<(u64, Oid)>>();
+    tag_sequence_commit(repository, tagger, head.id(), sequence, &applied)
+}
+
+/// Tag `commit` with the replication sequence and the `(changeset id, commit)` pairs
+/// applied in it
+pub fn tag_sequence_commit(
+    repository: &Repository,
+    tagger: &Signature,
+    commit: Oid,
+    sequence: &str,
+    applied: &[(u64, Oid)],
+) -> Result<()> {
+    let commit = repository.find_commit(commit)?;
+
+    let mut message = format!("Replication sequence {}\n\n", sequence);
+    for (changeset_id, changeset_commit) in applied {
+        message.push_str(&format!("{} {}\n", changeset_id, changeset_commit));
+    }
+
+    create_tag(
+        repository,
+        &sequence_tag_name(sequence),
+        &commit,
+        tagger,
+        &message,
+    )?;
+    Ok(())
+}
+
+/// The commit the tag of a replication sequence points to
+pub fn sequence_tag_target(repository: &Repository, sequence: &str) -> Result<Oid> {
+    let commit = repository
+        .find_reference(&format!("refs/tags/{}", sequence_tag_name(sequence)))?
+        .peel_to_commit()?;
+    Ok(commit.id())
+}
+
+/// Whether a replication sequence was applied and published to HEAD
+///
+/// A sequence tagged on staged commits of an interrupted run is not in the history yet.
+pub fn sequence_in_history(repository: &Repository, sequence: &str) -> Result<bool> {
+    let Ok(reference) =
+        repository.find_reference(&format!("refs/tags/{}", sequence_tag_name(sequence)))
+    else {
+        return Ok(false);
+    };
+    in_head_history(repository, reference.peel_to_commit()?.id())
+}
+
+/// All replication sequences which were applied, in order
+pub fn applied_sequences(repository: &Repository) -> Result<Vec<String>> {
+    let tag_names = repository.tag_names(Some(&format!("{}*", SEQUENCE_TAG_PREFIX)))?;
+    let mut sequences = tag_names
+        .iter()
+        .flatten()
+        .filter_map(|tag_name| tag_name.strip_prefix(SEQUENCE_TAG_PREFIX))
+        .map(str::to_string)
+        .collect::<Vec<String>>();
+    sequences.sort();
+    Ok(sequences)
+}
+
+/// Read the `(changeset id, commit)` pairs recorded in all sequence tags
+pub fn read_sequence_tags(repository: &Repository) -> Result<Vec<(u64, Oid)>> {
+    let mut applied = Vec::new();
+    let tag_names = repository.tag_names(Some(&format!("{}*", SEQUENCE_TAG_PREFIX)))?;
+    for tag_name in tag_names.iter().flatten() {
+        applied.extend(parse_sequence_tag(repository, tag_name)?);
+    }
+    info!(
+        "Found {} applied changesets in sequence tags",
+        applied.len()
+    );
+    Ok(applied)
+}
+
+/// Read the `(changeset id, commit)` pairs recorded for a replication sequence
+///
+/// # Returns
+///
+/// * `Result<Option<Vec<(u64, Oid)>>>` - `None` if the sequence was not applied yet
+pub fn read_sequence_tag(
+    repository: &Repository,
+    sequence: &str,
+) -> Result<Option<Vec<(u64, Oid)>>> {
+    let tag_name = sequence_tag_name(sequence);
+    if repository
+        .find_reference(&format!("refs/tags/{}", tag_name))
+        .is_err()
+    {
+        return Ok(None);
+    }
+    Ok(Some(parse_sequence_tag(repository, &tag_name)?))
+}
+
+fn parse_sequence_tag(repository: &Repository, tag_name: &str) -> Result<Vec<(u64, Oid)>> {
+    let reference = repository.find_reference(&format!("refs/tags/{}", tag_name))?;
+    let tag = reference.peel_to_tag()?;
+    let message = tag.message().unwrap_or("");
+    // Skip the title and the empty line, and stop at the signature of a signed tag
+    message
+        .lines()
+        .skip(2)
+        .take_while(|line| !line.starts_with("-----BEGIN "))
+        .map(|line| {
+            let (changeset_id, commit) = line
+                .split_once(' ')
+                .ok_or_else(|| eyre!("Invalid line {:?} in tag {}", line, tag_name))?;
+            Ok((changeset_id.parse()?, Oid::from_str(commit)?))
+        })
+        .collect()
+}
+
+/// Regenerate the notes of all changesets recorded in the sequence tags
+///
+/// The changeset metadata is read from the latest changeset dump. Notes which are
+/// already up to date are not touched.
+pub fn rebuild_notes(
+    repository: &Repository,
+    committer: &Signature,
+    changesets_location: &str,
+    note_format: NoteFormat,
+    notes_ref: &str,
+) -> Result<()> {
+    let recorded = read_sequence_tags(repository)?;
+    let changeset_ids = recorded.iter().map(|(id, _)| *id).collect::<Vec<u64>>();
+    let changesets = load_changesets(changesets_location, &changeset_ids)?;
+
+    let applied_changesets = recorded
+        .into_iter()
+        .filter_map(|(changeset_id, commit)| {
+            let changeset = changesets.iter().find(|c| c.id == changeset_id);
+            if changeset.is_none() {
+                warn!("Unable to find changeset {:?}", changeset_id);
+            }
+            changeset.map(|changeset| AppliedChangeset {
+                changeset: changeset.clone(),
+                commit,
+                metadata_missing: false,
+            })
+        })
+        .collect::<Vec<AppliedChangeset>>();
+
+    let written = write_notes(
+        repository,
+        committer,
+        &applied_changesets,
+        note_format,
+        notes_ref,
+    )?;
+    info!(
+        "Rebuilt notes: {} written, {} already up to date",
+        written,
+        applied_changesets.len() - written
+    );
+    Ok(())
+}
+
+/// The ref pointing to a blob listing the applied changesets which lack metadata
+pub const MISSING_METADATA_REF: &str = "refs/osm/missing-metadata";
+
+/// Read the `(changeset id, commit)` pairs of applied changesets which were not found in
+/// the changeset dump
+pub fn read_missing_metadata(repository: &Repository) -> Result<Vec<(u64, Oid)>> {
+    let Ok(reference) = repository.find_reference(MISSING_METADATA_REF) else {
+        return Ok(Vec::new());
+    };
+    let blob = reference.peel_to_blob()?;
+    std::str::from_utf8(blob.content())?
+        .lines()
+        .map(|line| {
+            let (changeset_id, commit) = line
+                .split_once(' ')
+                .ok_or_else(|| eyre!("Invalid line {:?} in {}", line, MISSING_METADATA_REF))?;
+            Ok((changeset_id.parse()?, Oid::from_str(commit)?))
+        })
+        .collect()
+}
+
+/// Replace the list of applied changesets which lack metadata
+///
+/// The ref is removed once the list is empty.
+fn write_missing_metadata(repository: &Repository, missing: &[(u64, Oid)]) -> Result<()> {
+    if missing.is_empty() {
+        if let Ok(mut reference) = repository.find_reference(MISSING_METADATA_REF) {
+            reference.delete()?;
+        }
+        return Ok(());
+    }
+    let mut contents = String::new();
+    for (changeset_id, commit) in missing {
+        contents.push_str(&format!("{} {}\n", changeset_id, commit));
+    }
+    let blob = repository.blob(contents.as_bytes())?;
+    repository.reference(
+        MISSING_METADATA_REF,
+        blob,
+        true,
+        &format!("osm-git: {} changesets lack metadata", missing.len()),
+    )?;
+    Ok(())
+}
+
+/// Add the applied changesets which were committed without metadata to the list
+///
+/// Changesets which are listed already are not added again, so re-applying a sequence
+/// is a no-op.
+pub fn record_missing_metadata(
+    repository: &Repository,
+    applied_changesets: &[AppliedChangeset],
+) -> Result<()> {
+    let mut missing = read_missing_metadata(repository)?;
+    let before = missing.len();
+    for applied in applied_changesets.iter().filter(|a| a.metadata_missing) {
+        let entry = (applied.changeset.id, applied.commit);
+        if !missing.contains(&entry) {
+            missing.push(entry);
+        }
+    }
+    if missing.len() == before {
+        return Ok(());
+    }
+    write_missing_metadata(repository, &missing)
+}
+
+/// Write the notes of applied changesets which lacked metadata when they were committed
+///
+/// The metadata is read from the latest changeset dump. Only notes are added, the
+/// commits are not rewritten. Changesets which are still missing stay on the list for a
+/// later run.
+///
+/// # Returns
+///
+/// * `Result<(usize, usize)>` - The number of annotated and still missing changesets
+pub fn annotate_missing(
+    repository: &Repository,
+    committer: &Signature,
+    changesets_location: &str,
+    note_format: NoteFormat,
+    notes_ref: &str,
+) -> Result<(usize, usize)> {
+    let missing = read_missing_metadata(repository)?;
+    if missing.is_empty() {
+        return Ok((0, 0));
+    }
+    let changeset_ids = missing.iter().map(|(id, _)| *id).collect::<Vec<u64>>();
+    let changesets = load_changesets(changesets_location, &changeset_ids)?;
+
+    let mut annotated = Vec::new();
+    let mut still_missing = Vec::new();
+    for (changeset_id, commit) in missing {
+        match changesets.iter().find(|c| c.id == changeset_id) {
+            Some(changeset) => annotated.push(AppliedChangeset {
+                changeset: changeset.clone(),
+                commit,
+                metadata_missing: false,
+            }),
+            None => still_missing.push((changeset_id, commit)),
+        }
+    }
+
+    write_notes(repository, committer, &annotated, note_format, notes_ref)?;
+    write_missing_metadata(repository, &still_missing)?;
+    Ok((annotated.len(), still_missing.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use time::OffsetDateTime;
+
+    use super::*;
+
+    fn changeset(min_lat: Option<f64>) -> Changeset {
+        Changeset {
+            min_lat,
+            max_lat: Some(52.0),
+            min_lon: Some(-1.0),
+            max_lon: Some(0.0),
+            tags: HashMap::from([("comment".to_string(), "Add a cafe".to_string())]),
+            ..Changeset::placeholder(1, OffsetDateTime::UNIX_EPOCH).unwrap()
+        }
+    }
+
+    #[test]
+    fn plain_notes_report_changesets_without_a_bbox_as_global() {
+        assert_eq!(
+            changeset_note(&changeset(None)),
+            "Legacy Changeset ID: 1\nBounding Box: global\ncomment: Add a cafe"
+        );
+        assert_eq!(
+            changeset_note(&changeset(Some(51.0))),
+            "Legacy Changeset ID: 1\nBounding Box: -1,51,0,52\ncomment: Add a cafe"
+        );
+    }
+
+    #[test]
+    fn yaml_notes_have_no_bbox_for_changesets_without_one() {
+        let global = changeset(None);
+        let note = NoteFormat::Yaml.note(&[&global]).unwrap();
+        assert!(note.contains("bbox: null"), "{}", note);
+
+        let area = changeset(Some(51.0));
+        let note = NoteFormat::Yaml.note(&[&area]).unwrap();
+        assert!(note.contains("min_lat: 51.0"), "{}", note);
+    }
+}
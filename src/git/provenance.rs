@@ -0,0 +1,201 @@
+use std::{collections::BTreeMap, io::Read, path::Path};
+
+use color_eyre::eyre::Result;
+use git2::{Oid, Repository, Signature};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use super::{commit_meta_files, signing::SigningKey};
+
+/// The folder of the git repo with the manifests of the runs
+pub const PROVENANCE_FOLDER: &str = "meta/provenance";
+/// The version of the manifest format, raised on incompatible changes
+const MANIFEST_VERSION: u32 = 1;
+/// The purpose SSH signatures of manifests are made for, to pass to `ssh-keygen -Y verify`
+pub const SIGNATURE_NAMESPACE: &str = "osm-git-provenance";
+
+/// What an input of a run is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputKind {
+    /// A replication file, as downloaded from the replication server or read from disk
+    ReplicationFile,
+    /// A changeset dump the metadata of the changesets was read from
+    ChangesetDump,
+}
+
+/// A file a run read, identified by its SHA-256 hash
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProvenanceInput {
+    pub kind: InputKind,
+    /// The sequence of a replication file or the file name of a dump
+    pub name: String,
+    pub sha256: String,
+}
+
+/// The record of one run, listing what it read and which commits it produced
+///
+/// Manifests are chained through the hash of the previous manifest, so a third party
+/// can replay the inputs with the same version and configuration and check that every
+/// commit of a published archive comes out the same.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProvenanceManifest {
+    pub manifest_version: u32,
+    pub tool: String,
+    pub tool_version: String,
+    pub started_at: String,
+    pub finished_at: String,
+    /// The SHA-256 hash of the effective configuration in the format of the config file
+    pub config_sha256: String,
+    pub inputs: Vec<ProvenanceInput>,
+    /// HEAD before the run, `None` for a new git repo
+    pub from_commit: Option<String>,
+    /// HEAD after the run. The commits of the run are `from_commit..to_commit`
+    pub to_commit: String,
+    /// The SHA-256 hash of the manifest of the previous run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_manifest_sha256: Option<String>,
+}
+
+/// The SHA-256 hash of `data` in hex
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// The SHA-256 hash of a file in hex, read in chunks as dumps are large
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Collects the inputs of a run for its manifest
+pub struct ProvenanceRecorder {
+    started_at: OffsetDateTime,
+    config_sha256: String,
+    from_commit: Option<Oid>,
+    inputs: Vec<ProvenanceInput>,
+}
+
+impl ProvenanceRecorder {
+    /// Start recording a run with the given effective configuration
+    pub fn start(repository: &Repository, config: &[u8]) -> Result<Self> {
+        Ok(ProvenanceRecorder {
+            started_at: OffsetDateTime::now_utc().replace_nanosecond(0)?,
+            config_sha256: sha256_hex(config),
+            from_commit: repository.refname_to_id("HEAD").ok(),
+            inputs: Vec::new(),
+        })
+    }
+
+    pub fn record_input(&mut self, kind: InputKind, name: &str, sha256: &str) {
+        self.inputs.push(ProvenanceInput {
+            kind,
+            name: name.to_string(),
+            sha256: sha256.to_string(),
+        });
+    }
+
+    /// Record a file read by the run, hashing its contents
+    pub fn record_file(&mut self, kind: InputKind, path: &Path) -> Result<()> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let sha256 = sha256_file(path)?;
+        self.record_input(kind, &name, &sha256);
+        Ok(())
+    }
+
+    /// Commit the manifest of the run to `meta/provenance/`, with a detached signature
+    /// next to it if a key is given
+    ///
+    /// Runs which didn't create any commits leave no manifest.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<String>>` - The path of the manifest, if one was written
+    pub fn finish(
+        self,
+        repository: &Repository,
+        committer: &Signature,
+        signing_key: Option<&SigningKey>,
+    ) -> Result<Option<String>> {
+        let to_commit = repository.refname_to_id("HEAD")?;
+        if Some(to_commit) == self.from_commit {
+            return Ok(None);
+        }
+        let manifest = ProvenanceManifest {
+            manifest_version: MANIFEST_VERSION,
+            tool: env!("CARGO_PKG_NAME").to_string(),
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            started_at: self.started_at.format(&Rfc3339)?,
+            finished_at: OffsetDateTime::now_utc()
+                .replace_nanosecond(0)?
+                .format(&Rfc3339)?,
+            config_sha256: self.config_sha256,
+            inputs: self.inputs,
+            from_commit: self.from_commit.map(|commit| commit.to_string()),
+            to_commit: to_commit.to_string(),
+            previous_manifest_sha256: latest_manifest(repository)?
+                .map(|contents| sha256_hex(&contents)),
+        };
+        let mut contents = serde_json::to_vec_pretty(&manifest)?;
+        contents.push(b'\n');
+
+        // Timestamps sort by name, with the colons replaced as Windows can't store them
+        let path = format!(
+            "{}/{}.json",
+            PROVENANCE_FOLDER,
+            manifest.started_at.replace(':', "-")
+        );
+        let mut files = BTreeMap::new();
+        if let Some(signing_key) = signing_key {
+            let signature = signing_key.sign(&contents, SIGNATURE_NAMESPACE)?;
+            files.insert(format!("{}.sig", path), signature.into_bytes());
+        }
+        files.insert(path.clone(), contents);
+        commit_meta_files(
+            repository,
+            files,
+            &format!(
+                "Record the provenance of {}..{}",
+                manifest.from_commit.as_deref().unwrap_or_default(),
+                manifest.to_commit
+            ),
+            committer,
+        )?;
+        Ok(Some(path))
+    }
+}
+
+/// The contents of the newest manifest at HEAD
+fn latest_manifest(repository: &Repository) -> Result<Option<Vec<u8>>> {
+    let tree = repository.head()?.peel_to_tree()?;
+    let Ok(folder) = tree.get_path(Path::new(PROVENANCE_FOLDER)) else {
+        return Ok(None);
+    };
+    let folder = folder.to_object(repository)?.peel_to_tree()?;
+    let latest = folder
+        .iter()
+        .filter(|entry| entry.name().is_some_and(|name| name.ends_with(".json")))
+        .max_by(|a, b| a.name_bytes().cmp(b.name_bytes()));
+    latest
+        .map(|entry| {
+            Ok(entry
+                .to_object(repository)?
+                .peel_to_blob()?
+                .content()
+                .to_vec())
+        })
+        .transpose()
+}
@@ -0,0 +1,181 @@
+use std::{fmt::Display, str::FromStr};
+
+use color_eyre::eyre::{eyre, Result};
+use git2::{Cred, CredentialType, Oid, PushOptions, RemoteCallbacks, Repository};
+use tracing::info;
+
+use crate::replay::ReplayEvent;
+
+/// The refs of the notes pushed with the branch
+const NOTES_REFS: &str = "refs/notes/*";
+
+/// How often the replay pushes to the remote
+///
+/// Given as a number of replication files like `5`, or as a number of commits with a
+/// `commits` suffix like `100commits`. Pushes only happen once a replication file is
+/// published, so a count of commits is reached at the end of the file which crosses it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushCadence {
+    Files(u32),
+    Commits(u32),
+}
+
+impl Default for PushCadence {
+    fn default() -> Self {
+        PushCadence::Files(1)
+    }
+}
+
+impl FromStr for PushCadence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (count, unit) = s
+            .find(|c: char| !c.is_ascii_digit())
+            .map(|index| s.split_at(index))
+            .unwrap_or((s, ""));
+        let count = count
+            .parse::<u32>()
+            .ok()
+            .filter(|count| *count > 0)
+            .ok_or_else(|| format!("Expected a positive number of files or commits in {:?}", s))?;
+        match unit.trim() {
+            "" | "files" => Ok(PushCadence::Files(count)),
+            "commits" => Ok(PushCadence::Commits(count)),
+            unit => Err(format!(
+                "Unknown unit {:?}, expected files or commits",
+                unit
+            )),
+        }
+    }
+}
+
+impl Display for PushCadence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PushCadence::Files(count) => write!(f, "{}files", count),
+            PushCadence::Commits(count) => write!(f, "{}commits", count),
+        }
+    }
+}
+
+/// Pushes the branch and the notes to a remote as the replay publishes commits
+pub struct Pusher {
+    /// A URL or the name of a remote configured in the git repo
+    remote: String,
+    cadence: PushCadence,
+    /// Replication files published since the last push
+    pending_files: u32,
+    /// Commits created since the last push
+    pending_commits: u32,
+    /// The commit of the last push
+    pushed: Option<Oid>,
+}
+
+impl Pusher {
+    pub fn new(remote: &str, cadence: PushCadence) -> Self {
+        Pusher {
+            remote: remote.to_string(),
+            cadence,
+            pending_files: 0,
+            pending_commits: 0,
+            pushed: None,
+        }
+    }
+
+    /// Count the commits of an event of the replay
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Whether a push is due
+    pub fn record(&mut self, event: &ReplayEvent) -> bool {
+        match event {
+            ReplayEvent::CommitCreated { .. }
+            | ReplayEvent::GapRecorded { .. }
+            | ReplayEvent::ChangesetEventRecorded { .. } => {
+                self.pending_commits += 1;
+                false
+            }
+            ReplayEvent::SequenceApplied { .. } => {
+                self.pending_files += 1;
+                match self.cadence {
+                    PushCadence::Files(count) => self.pending_files >= count,
+                    PushCadence::Commits(count) => self.pending_commits >= count,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Check if HEAD moved since the last push of this run, or nothing was pushed yet
+    pub fn is_behind(&self, repository: &Repository) -> Result<bool> {
+        Ok(Some(repository.refname_to_id("HEAD")?) != self.pushed)
+    }
+
+    /// Push the branch and the notes, and start counting again
+    pub fn push(&mut self, repository: &Repository) -> Result<()> {
+        let head = repository.refname_to_id("HEAD")?;
+        push_to_remote(repository, &self.remote)?;
+        self.pending_files = 0;
+        self.pending_commits = 0;
+        self.pushed = Some(head);
+        Ok(())
+    }
+}
+
+/// Push the current branch and all notes refs to a remote
+///
+/// The refs are only fast-forwarded, so a remote with diverging history is left alone.
+/// Credentials come from the SSH agent for SSH remotes and from the configured git
+/// credential helper for HTTPS remotes.
+pub fn push_to_remote(repository: &Repository, remote: &str) -> Result<()> {
+    let branch = repository
+        .find_reference("HEAD")?
+        .symbolic_target()
+        .ok_or_else(|| eyre!("HEAD is detached"))?
+        .to_string();
+    let mut remote = match repository.find_remote(remote) {
+        Ok(remote) => remote,
+        Err(_) => repository.remote_anonymous(remote)?,
+    };
+
+    let config = repository.config()?;
+    let mut rejected = Vec::new();
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(|url, username, allowed| {
+        if allowed.contains(CredentialType::SSH_KEY) {
+            Cred::ssh_key_from_agent(username.unwrap_or("git"))
+        } else if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            Cred::credential_helper(&config, url, username)
+        } else {
+            Cred::default()
+        }
+    });
+    callbacks.push_update_reference(|reference, status| {
+        if let Some(status) = status {
+            rejected.push(format!("{} ({})", reference, status));
+        }
+        Ok(())
+    });
+    // libgit2 doesn't expand globs in push refspecs, so the notes refs are listed one by
+    // one
+    let mut refspecs = vec![format!("{}:{}", branch, branch)];
+    for reference in repository.references_glob(NOTES_REFS)?.names() {
+        let reference = reference?;
+        refspecs.push(format!("{}:{}", reference, reference));
+    }
+    let mut options = PushOptions::new();
+    options.remote_callbacks(callbacks);
+    remote.push(&refspecs, Some(&mut options))?;
+    drop(options);
+    if !rejected.is_empty() {
+        return Err(eyre!("The remote rejected {}", rejected.join(", ")));
+    }
+    info!(
+        "Pushed {} and the notes to {}",
+        branch,
+        remote.url().unwrap_or_default()
+    );
+    Ok(())
+}
@@ -0,0 +1,318 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use color_eyre::eyre::{eyre, Result};
+use git2::{Commit, Delta, DiffFile, Oid, Repository, Signature, Tree};
+use tracing::warn;
+
+use super::{commit_head_files, notes::read_sequence_tags};
+use crate::osm::{
+    id_mapping::IdMapper,
+    layout::{Layout, ShardBudget},
+    osm_data::OSMObject,
+    squash::append_trailers,
+};
+
+/// The trailer naming the changeset a revert commit reverts
+const REVERTS_CHANGESET_TRAILER: &str = "Reverts-Changeset";
+
+/// What reverting a changeset changed
+#[derive(Debug, Default)]
+pub struct RevertSummary {
+    /// The revert commit, `None` if nothing was left to revert
+    pub commit: Option<Oid>,
+    /// Objects modified by the changeset which got their previous version back
+    pub restored: usize,
+    /// Objects created by the changeset which were deleted
+    pub deleted: usize,
+    /// Objects deleted by the changeset which were brought back
+    pub undeleted: usize,
+    /// Objects changed again after the changeset, as type and id, which were left alone
+    pub conflicts: Vec<(&'static str, u64)>,
+}
+
+/// The versions of an object before and after a change
+pub(crate) struct ObjectChange {
+    pub before: Option<OSMObject>,
+    pub after: Option<OSMObject>,
+}
+
+/// The objects changed between two trees, keyed by type and id
+///
+/// Objects are compared by type and id rather than by path, and each tree is read with
+/// its own layout, so moved files don't show up as changes.
+pub(crate) fn object_changes(
+    repository: &Repository,
+    old_tree: Option<&Tree>,
+    new_tree: &Tree,
+    id_mapper: &dyn IdMapper,
+    budget: ShardBudget,
+) -> Result<BTreeMap<(&'static str, u64), ObjectChange>> {
+    let new_layout = Layout::load_from_tree(new_tree, repository, budget)?;
+    let old_layout = match old_tree {
+        Some(old_tree) => Layout::load_from_tree(old_tree, repository, budget)?,
+        None => new_layout.clone(),
+    };
+    let diff = repository.diff_tree_to_tree(old_tree, Some(new_tree), None)?;
+    let mut changes: BTreeMap<(&'static str, u64), ObjectChange> = BTreeMap::new();
+    for delta in diff.deltas() {
+        if delta.status() != Delta::Added {
+            if let Some(before) =
+                diff_object(repository, &old_layout, &delta.old_file(), id_mapper)?
+            {
+                let key = (before.type_name(), before.id());
+                changes
+                    .entry(key)
+                    .or_insert(ObjectChange {
+                        before: None,
+                        after: None,
+                    })
+                    .before = Some(before);
+            }
+        }
+        if delta.status() != Delta::Deleted {
+            if let Some(after) = diff_object(repository, &new_layout, &delta.new_file(), id_mapper)?
+            {
+                let key = (after.type_name(), after.id());
+                changes
+                    .entry(key)
+                    .or_insert(ObjectChange {
+                        before: None,
+                        after: None,
+                    })
+                    .after = Some(after);
+            }
+        }
+    }
+    changes.retain(|_, change| change.before != change.after);
+    Ok(changes)
+}
+
+/// The commits of a changeset in the first-parent history of HEAD, oldest first
+///
+/// They are looked up in the sequence tags, and in the `Changeset-Id` trailers of the
+/// history for changesets which were not applied from replication files. A changeset
+/// open across several replication files has a commit in each.
+fn changeset_commits<'r>(repository: &'r Repository, changeset_id: u64) -> Result<Vec<Commit<'r>>> {
+    let head = repository.head()?.peel_to_commit()?;
+    let mut commit_ids = BTreeSet::new();
+    for (id, commit_id) in read_sequence_tags(repository)? {
+        if id == changeset_id
+            && (commit_id == head.id() || repository.graph_descendant_of(head.id(), commit_id)?)
+        {
+            commit_ids.insert(commit_id);
+        }
+    }
+    let mut commits = commit_ids
+        .into_iter()
+        .map(|commit_id| repository.find_commit(commit_id))
+        .collect::<Result<Vec<Commit>, git2::Error>>()?;
+    if commits.is_empty() {
+        let mut revwalk = repository.revwalk()?;
+        revwalk.push(head.id())?;
+        revwalk.simplify_first_parent()?;
+        for commit_id in revwalk {
+            let commit = repository.find_commit(commit_id?)?;
+            if commit_changesets(&commit)?.contains(&changeset_id) {
+                commits.push(commit);
+            }
+        }
+    }
+    commits.sort_by_key(|commit| commit.time().seconds());
+    Ok(commits)
+}
+
+/// The changesets a commit applied, from its `Changeset-Id` trailers
+fn commit_changesets(commit: &Commit) -> Result<BTreeSet<u64>> {
+    let mut changesets = BTreeSet::new();
+    for (key, value) in git2::message_trailers_strs(commit.message().unwrap_or_default())?.iter() {
+        if key == "Changeset-Id" {
+            changesets.insert(value.parse()?);
+        }
+    }
+    Ok(changesets)
+}
+
+/// Read the object of a file of a diff, `None` if the file is not an object file
+fn diff_object(
+    repository: &Repository,
+    layout: &Layout,
+    file: &DiffFile,
+    id_mapper: &dyn IdMapper,
+) -> Result<Option<OSMObject>> {
+    let Some(path) = file.path() else {
+        return Ok(None);
+    };
+    if path.starts_with("meta")
+        || path.extension().and_then(|ext| ext.to_str()) != Some(layout.serializer().extension())
+    {
+        return Ok(None);
+    }
+    let Some(id) = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| id_mapper.id_from_stem(stem))
+    else {
+        return Ok(None);
+    };
+    let mut object = layout
+        .serializer()
+        .deserialize(repository.find_blob(file.id())?.content())?;
+    object.set_id(id);
+    Ok(Some(object))
+}
+
+/// The object of the given type and id in a tree
+fn tree_object(
+    repository: &Repository,
+    tree: &Tree,
+    layout: &Layout,
+    type_name: &str,
+    id: u64,
+    id_mapper: &dyn IdMapper,
+) -> Result<Option<OSMObject>> {
+    let Ok(entry) = tree.get_path(&layout.path(type_name, id, id_mapper)) else {
+        return Ok(None);
+    };
+    let mut object = layout
+        .serializer()
+        .deserialize(entry.to_object(repository)?.peel_to_blob()?.content())?;
+    if object.type_name() != type_name {
+        return Ok(None);
+    }
+    object.set_id(id);
+    Ok(Some(object))
+}
+
+/// Revert a changeset with a new commit on HEAD
+///
+/// Objects the changeset modified get their previous version back, objects it created
+/// are deleted and objects it deleted are restored, like the revert tools of OSM do.
+/// Objects which were changed again after the changeset are conflicts: nothing is
+/// committed, unless `skip_conflicts` is set, which reverts the other objects and
+/// leaves them as they are.
+///
+/// A changeset which was squashed into a commit with others can't be told apart from
+/// them, as the object files don't record their changeset, so it can't be reverted.
+pub fn revert_changeset(
+    repository: &Repository,
+    changeset_id: u64,
+    id_mapper: &dyn IdMapper,
+    budget: ShardBudget,
+    committer: &Signature,
+    skip_conflicts: bool,
+) -> Result<RevertSummary> {
+    let commits = changeset_commits(repository, changeset_id)?;
+    if commits.is_empty() {
+        return Err(eyre!(
+            "Changeset {} is not in the history of HEAD",
+            changeset_id
+        ));
+    }
+
+    let mut changes: BTreeMap<(&'static str, u64), ObjectChange> = BTreeMap::new();
+    for commit in &commits {
+        let squashed = commit_changesets(commit)?;
+        if squashed.len() > 1 {
+            return Err(eyre!(
+                "Changeset {} was squashed with changesets {:?} into commit {}. Their changes can't be told apart",
+                changeset_id,
+                squashed.iter().filter(|id| **id != changeset_id).collect::<Vec<&u64>>(),
+                commit.id()
+            ));
+        }
+        let parent_tree = match commit.parents().next() {
+            Some(parent) => Some(parent.tree()?),
+            None => None,
+        };
+        let commit_changes = object_changes(
+            repository,
+            parent_tree.as_ref(),
+            &commit.tree()?,
+            id_mapper,
+            budget,
+        )?;
+        for (key, change) in commit_changes {
+            changes
+                .entry(key)
+                .and_modify(|earlier| earlier.after = change.after.clone())
+                .or_insert(change);
+        }
+    }
+
+    let head = repository.head()?.peel_to_commit()?;
+    let head_tree = head.tree()?;
+    let head_layout = Layout::load_from_tree(&head_tree, repository, budget)?;
+    let mut summary = RevertSummary::default();
+    let mut files = BTreeMap::new();
+    for ((type_name, id), change) in changes {
+        if change.before == change.after {
+            continue;
+        }
+        let current = tree_object(
+            repository,
+            &head_tree,
+            &head_layout,
+            type_name,
+            id,
+            id_mapper,
+        )?;
+        if current != change.after {
+            summary.conflicts.push((type_name, id));
+            continue;
+        }
+        let path = head_layout.path(type_name, id, id_mapper);
+        match (&change.before, &change.after) {
+            (Some(before), Some(_)) => {
+                files.insert(path, Some(head_layout.serializer().serialize(before)?));
+                summary.restored += 1;
+            }
+            (Some(before), None) => {
+                files.insert(path, Some(head_layout.serializer().serialize(before)?));
+                summary.undeleted += 1;
+            }
+            (None, Some(_)) => {
+                files.insert(path, None);
+                summary.deleted += 1;
+            }
+            (None, None) => {}
+        }
+    }
+
+    if !summary.conflicts.is_empty() {
+        let conflicts = summary
+            .conflicts
+            .iter()
+            .map(|(type_name, id)| format!("{} {}", type_name, id))
+            .collect::<Vec<String>>()
+            .join(", ");
+        if !skip_conflicts {
+            return Err(eyre!(
+                "Changeset {} can't be reverted, as these objects were changed after it: {}",
+                changeset_id,
+                conflicts
+            ));
+        }
+        warn!(
+            "Leaving the objects changed after the changeset as they are: {}",
+            conflicts
+        );
+    }
+    if files.is_empty() {
+        return Ok(summary);
+    }
+
+    let reverted_commits = commits
+        .iter()
+        .map(|commit| commit.id().to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+    let message = append_trailers(
+        format!(
+            "Revert changeset {}\n\nThis reverts commit {}.",
+            changeset_id, reverted_commits
+        ),
+        &[(REVERTS_CHANGESET_TRAILER, &changeset_id.to_string())],
+    );
+    summary.commit = Some(commit_head_files(repository, files, &message, committer)?);
+    Ok(summary)
+}
@@ -0,0 +1,123 @@
+use std::{
+    fmt::Display,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use color_eyre::eyre::{eyre, Result};
+
+/// The namespace git uses for SSH signatures of commits and tags
+pub const GIT_SIGNATURE_NAMESPACE: &str = "git";
+
+/// The key signing the commits and annotated tags of this process, if any
+static COMMIT_SIGNING_KEY: OnceLock<SigningKey> = OnceLock::new();
+
+/// Sign all commits and annotated tags created from now on with `key`
+///
+/// The key is set once for the whole process rather than passed along, as commits are
+/// created in many places.
+pub fn sign_commits_with(key: SigningKey) -> Result<()> {
+    COMMIT_SIGNING_KEY
+        .set(key)
+        .map_err(|_| eyre!("The commit signing key is already set"))
+}
+
+/// The key commits and annotated tags are signed with
+pub fn commit_signing_key() -> Option<&'static SigningKey> {
+    COMMIT_SIGNING_KEY.get()
+}
+
+/// A key signing what osm-git writes, with the tools git uses for signing
+///
+/// Given as `ssh:<path of the private key>` for `ssh-keygen -Y sign`, or as
+/// `gpg:<key id>` for `gpg --detach-sign`. A value without a prefix is taken as an SSH
+/// key if such a file exists and as a GPG key id otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SigningKey {
+    Gpg(String),
+    Ssh(PathBuf),
+}
+
+impl FromStr for SigningKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(key_id) = s.strip_prefix("gpg:") {
+            return Ok(SigningKey::Gpg(key_id.to_string()));
+        }
+        if let Some(path) = s.strip_prefix("ssh:") {
+            return Ok(SigningKey::Ssh(path.into()));
+        }
+        if s.is_empty() {
+            return Err("The signing key is empty".to_string());
+        }
+        if std::path::Path::new(s).is_file() {
+            Ok(SigningKey::Ssh(s.into()))
+        } else {
+            Ok(SigningKey::Gpg(s.to_string()))
+        }
+    }
+}
+
+impl Display for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SigningKey::Gpg(key_id) => write!(f, "gpg:{}", key_id),
+            SigningKey::Ssh(path) => write!(f, "ssh:{}", path.display()),
+        }
+    }
+}
+
+impl SigningKey {
+    /// Create an ASCII armored detached signature of `data`
+    ///
+    /// `namespace` is the purpose SSH signatures are made for, like `git` for commits, so
+    /// a signature can't be passed off for another purpose. GPG signatures have none.
+    pub fn sign(&self, data: &[u8], namespace: &str) -> Result<String> {
+        let mut command = match self {
+            SigningKey::Gpg(key_id) => {
+                let mut command = Command::new("gpg");
+                command.args([
+                    "--batch",
+                    "--detach-sign",
+                    "--armor",
+                    "--local-user",
+                    key_id,
+                ]);
+                command
+            }
+            SigningKey::Ssh(path) => {
+                let mut command = Command::new("ssh-keygen");
+                command
+                    .args(["-Y", "sign", "-n", namespace, "-f"])
+                    .arg(path);
+                command
+            }
+        };
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| eyre!("Unable to run the signing tool for {}: {}", self, err))?;
+        // Write from another thread, as the tool may fill its output before reading all
+        let mut stdin = child.stdin.take().unwrap();
+        let data = data.to_vec();
+        let writer = std::thread::spawn(move || stdin.write_all(&data));
+        let output = child.wait_with_output()?;
+        writer
+            .join()
+            .map_err(|_| eyre!("Unable to pass the data to the signing tool"))??;
+        if !output.status.success() {
+            return Err(eyre!(
+                "Signing with {} failed: {}",
+                self,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+}
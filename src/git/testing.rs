@@ -0,0 +1,81 @@
+use std::{
+    ops::Deref,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use git2::{Repository, Signature, Time};
+
+use super::init_git_repository;
+use crate::osm::{layout::LayoutMetadata, osm_data::ObjectFormat};
+
+/// Tells apart the git repos of tests running in parallel
+static REPOSITORY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A git repo in the temporary directory, removed again when dropped
+pub struct TestRepository {
+    repository: Repository,
+    path: PathBuf,
+}
+
+impl TestRepository {
+    /// A git repo with the initial commit of osm-git, storing YAML files one directory
+    /// level deep
+    pub fn new(name: &str, bare: bool) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "osm-git-test-{}-{}-{}",
+            name,
+            std::process::id(),
+            REPOSITORY_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        if path.exists() {
+            std::fs::remove_dir_all(&path).unwrap();
+        }
+        let repository = init_git_repository(
+            path.to_str().unwrap(),
+            "https://planet.openstreetmap.org/replication/minute",
+            &signature(),
+            &LayoutMetadata {
+                fan_out_depth: 1,
+                object_format: ObjectFormat::Yaml,
+            },
+            bare,
+        )
+        .unwrap();
+        TestRepository { repository, path }
+    }
+
+    /// Open the git repo again, like a new process would
+    pub fn reopen(&self) -> Repository {
+        Repository::open(&self.path).unwrap()
+    }
+
+    /// Whether the working directory and index have changes which are not committed
+    pub fn has_changes(&self) -> bool {
+        !self.repository.is_bare() && !self.repository.statuses(None).unwrap().is_empty()
+    }
+}
+
+impl Deref for TestRepository {
+    type Target = Repository;
+
+    fn deref(&self) -> &Repository {
+        &self.repository
+    }
+}
+
+impl Drop for TestRepository {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// The committer of the commits of tests
+pub fn signature() -> Signature<'static> {
+    Signature::new(
+        "osm-git-replay",
+        "replay@localhost",
+        &Time::new(1347408000, 0),
+    )
+    .unwrap()
+}
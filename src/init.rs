@@ -0,0 +1,127 @@
+use std::{
+    ffi::CString,
+    io::{BufRead, Write},
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{eyre, Result};
+
+/// Below this many free bytes, `osm-git init` warns that the git repo or cache may run
+/// out of space. Planet-wide histories need a lot more, regional ones a lot less
+pub const RECOMMENDED_FREE_SPACE: u64 = 20 * 1024 * 1024 * 1024;
+
+/// Asks the questions of `osm-git init` on the terminal
+///
+/// When not interactive, every question is answered with its default, which comes from
+/// the options and the config file. The answers are validated the same way in both modes,
+/// but only an interactive prompt asks again after an invalid answer.
+pub struct Prompt {
+    interactive: bool,
+}
+
+impl Prompt {
+    pub fn new(interactive: bool) -> Self {
+        Prompt { interactive }
+    }
+
+    /// Print the heading of a group of questions
+    pub fn section(&self, title: &str) {
+        if self.interactive {
+            println!("\n{}", title);
+        }
+    }
+
+    /// Ask a question until `parse` accepts the answer. An empty answer takes the default
+    pub fn ask<T>(
+        &self,
+        question: &str,
+        default: &str,
+        parse: impl Fn(&str) -> Result<T>,
+    ) -> Result<T> {
+        if !self.interactive {
+            return parse(default).map_err(|err| eyre!("{}: {}", question, err));
+        }
+        let stdin = std::io::stdin();
+        loop {
+            print!("{} [{}]: ", question, default);
+            std::io::stdout().flush()?;
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                return Err(eyre!("The setup was aborted"));
+            }
+            let answer = match line.trim() {
+                "" => default,
+                answer => answer,
+            };
+            match parse(answer) {
+                Ok(value) => return Ok(value),
+                Err(err) => println!("{}", err),
+            }
+        }
+    }
+
+    pub fn ask_string(&self, question: &str, default: &str) -> Result<String> {
+        self.ask(question, default, |answer| Ok(answer.to_string()))
+    }
+
+    /// Ask for a value which may be left unset by answering `none`
+    pub fn ask_optional(
+        &self,
+        question: &str,
+        default: Option<&str>,
+        validate: impl Fn(&str) -> Result<()>,
+    ) -> Result<Option<String>> {
+        self.ask(question, default.unwrap_or("none"), |answer| {
+            if answer == "none" {
+                return Ok(None);
+            }
+            validate(answer)?;
+            Ok(Some(answer.to_string()))
+        })
+    }
+
+    pub fn confirm(&self, question: &str, default: bool) -> Result<bool> {
+        self.ask(
+            question,
+            if default { "yes" } else { "no" },
+            |answer| match answer.to_lowercase().as_str() {
+                "y" | "yes" | "true" => Ok(true),
+                "n" | "no" | "false" => Ok(false),
+                _ => Err(eyre!("Answer yes or no")),
+            },
+        )
+    }
+
+    pub fn is_interactive(&self) -> bool {
+        self.interactive
+    }
+}
+
+/// The number of bytes available to unprivileged users on the file system of `path`
+///
+/// Paths which don't exist yet are checked at their nearest existing parent directory.
+pub fn free_space(path: &str) -> Result<u64> {
+    let path = std::env::current_dir()?.join(path);
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("/"));
+    let c_path = CString::new(existing.as_os_str().as_bytes())?;
+    let mut stats = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `c_path` is a valid C string and `stats` is only read after statvfs filled it
+    if unsafe { libc::statvfs(c_path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return Err(eyre!(
+            "Can't get the free space of {}: {}",
+            existing.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    // SAFETY: statvfs succeeded
+    let stats = unsafe { stats.assume_init() };
+    // The types of the fields differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
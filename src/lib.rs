@@ -0,0 +1,44 @@
+//! Replays the OpenStreetMap replication files into a git repo, one commit per changeset
+//!
+//! The `osm-git` binary is a command line interface on top of this library. Other programs
+//! can embed the conversion with a [`Replayer`], which fetches the files of a
+//! [`ReplicationSource`] as configured by [`ReplayOptions`] and commits them with a
+//! [`GitSink`]. The modules give access to the parts the replay is made of, like the
+//! parsing of replication files in [`osm::osm_data`] or the reading of the resulting git
+//! repo in [`osm::snapshot`].
+
+#[cfg(feature = "http")]
+pub mod cassette;
+#[cfg(feature = "http")]
+pub mod control;
+#[cfg(feature = "notifications")]
+pub mod digest;
+#[cfg(feature = "http")]
+pub mod download;
+pub mod error_report;
+#[cfg(feature = "fault-injection")]
+pub mod faults;
+pub mod git;
+mod ordered;
+pub mod osm;
+#[cfg(feature = "http")]
+pub mod polite;
+pub mod profile;
+pub mod replay;
+pub mod replication;
+#[cfg(feature = "serve")]
+pub mod server;
+#[cfg(feature = "http")]
+pub mod source;
+#[cfg(feature = "http")]
+pub mod tuning;
+pub mod verify;
+pub mod watch;
+#[cfg(feature = "editing")]
+pub mod workdir;
+
+pub use replay::{GitSink, ReplayEvent};
+#[cfg(feature = "http")]
+pub use replay::{ReplayOptions, Replayer};
+#[cfg(feature = "http")]
+pub use source::ReplicationSource;
@@ -1,204 +1,2622 @@
-use std::{fs::File, time::Duration};
+use std::ffi::OsString;
+#[cfg(feature = "serve")]
+use std::net::SocketAddr;
+#[cfg(any(feature = "http", feature = "serve"))]
+use std::sync::Arc;
+#[cfg(feature = "http")]
+use std::time::Duration;
+#[cfg(feature = "export")]
+use std::{fs::File, io::BufWriter};
 
-use clap::Parser;
-use color_eyre::eyre::Result;
-use git2::Signature;
-use memmap2::Mmap;
-use tracing::{info, warn};
+#[cfg(feature = "http")]
+use clap::{Args, ValueEnum};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap_complete::Shell;
+use color_eyre::eyre::{eyre, Result};
+use git2::{Repository, Signature};
+use serde::Serialize;
+use serde_yaml::Value;
+#[cfg(feature = "http")]
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+#[cfg(feature = "http")]
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+#[cfg(feature = "http")]
+use tokio_stream::StreamExt;
+#[cfg(feature = "http")]
+use tracing::debug;
+use tracing::{error, info, warn};
 
-use crate::{git::init_git_repository, osm::osm_data::convert_objects_to_git};
+#[cfg(feature = "notifications")]
+use osm_git::digest::{Digest, DigestConfig, DigestPeriod};
+#[cfg(any(feature = "http", feature = "pbf"))]
+use osm_git::git::identity::AuthorIdentity;
+#[cfg(feature = "export")]
+use osm_git::osm::export::{
+    write_osc_diff, write_osh_xml, write_osm_xml, DiffFormat, ExportFormat,
+};
+#[cfg(feature = "tiles")]
+use osm_git::osm::tiles::write_tileset;
+#[cfg(feature = "dumps")]
+use osm_git::osm::{changeset_index::ChangesetIndex, changesets::check_changeset_dump};
+#[cfg(feature = "serve")]
+use osm_git::server::{serve, ServerState};
+#[cfg(feature = "editing")]
+use osm_git::workdir::{watch_workdir, EditOutcome, EditValidator};
+#[cfg(feature = "http")]
+use osm_git::{
+    cassette::{Cassette, CassetteMode},
+    control::{
+        bind_control_socket, send_command, serve_control_socket, ControlCommand, ReplayControl,
+        RuntimeSettings,
+    },
+    download::Downloader,
+    git::{
+        identity::Committer,
+        notes::{prepare_notes_ref, read_missing_metadata},
+        provenance::{InputKind, ProvenanceRecorder},
+        push::{push_to_remote, PushCadence, Pusher},
+    },
+    osm::{
+        admin_areas::AdminAreas,
+        api_quota::DEFAULT_DAILY_QUOTA,
+        area_filter::AreaFilter,
+        changesets::{find_latest_changeset_dump, parse_timestamp, ChangesetApi},
+        mapper_filter::MapperFilter,
+        parse_error::ParseMode,
+        squash::Granularity,
+        tag_filter::TagFilter,
+        upload::{plan_upload, record_upload, OsmApi, UPLOAD_MARKER_REF},
+    },
+    polite::{
+        validate_user_agent, OffPeakWindow, DEFAULT_OFF_PEAK, DEFAULT_USER_AGENT, MIN_WAIT_TIME,
+    },
+    replay::{
+        bench::FollowBenchmark,
+        osc_dir::{import_local_source, LocalSource},
+        GitSink, ReplayEvent, ReplayOptions, Replayer,
+    },
+    replication::{Interval, IntervalMode, SequenceNumber},
+    source::{open_source, FailoverSource, ReplicationSource, S3Options, SourceOptions},
+    tuning::TuningBounds,
+    watch::{WatchConfig, WatchReporter, Watchlist},
+};
+use osm_git::{
+    error_report::report_errors_to,
+    git::{
+        campaigns::{campaign_members, campaigns},
+        clone::{partial_clone, CloneOptions},
+        history::{
+            blame_object, object_history, replication_log, LogEntry, ObjectChange, TagChange,
+        },
+        identity::{
+            IdentityPolicy, DEFAULT_ANONYMOUS_NAME, DEFAULT_AUTHOR_EMAIL_DOMAIN,
+            DEFAULT_COMMITTER_EMAIL, DEFAULT_COMMITTER_NAME,
+        },
+        mailmap::update_mailmap,
+        merge::merge_archives,
+        notes::{annotate_missing, rebuild_notes, NoteFormat, CHANGESET_NOTES_REF},
+        recover_staging,
+        revert::revert_changeset,
+        signing::{sign_commits_with, SigningKey},
+    },
+    osm::{
+        changesets::BBox,
+        id_mapping::{
+            record_id_mapping, IdMapper, IdentityMapping, IndirectMapping, PrivateOverlayMapping,
+        },
+        layout::{reshard, ShardBudget},
+        migration::{convert_object_format, migrate},
+        osm_data::ObjectFormat,
+        snapshot::{resolve_commit, Snapshot},
+    },
+    profile::Profile,
+    verify::verify_repository,
+};
+#[cfg(any(feature = "http", feature = "pbf"))]
+use osm_git::{
+    git::init_git_repository,
+    osm::{
+        layout::{Layout, LayoutMetadata},
+        state_store::StateStoreKind,
+    },
+};
+#[cfg(feature = "pbf")]
+use osm_git::{
+    git::read_state,
+    osm::pbf::{bootstrap_from_pbf, import_history, BootstrapSettings, PbfReader},
+};
 
-mod git;
-mod osm;
+use crate::config::{apply_config_file, serialize_display};
+#[cfg(feature = "http")]
+use crate::{
+    config::config_args,
+    init::{free_space, Prompt, RECOMMENDED_FREE_SPACE},
+    service::{notify, ServiceDefinition, ServiceManager},
+};
 
-#[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+mod config;
+#[cfg(feature = "http")]
+mod init;
+#[cfg(feature = "http")]
+mod service;
+
+#[derive(Parser, Serialize)]
+#[command(author, version, about, long_about = None, args_override_self = true)]
 struct Cli {
+    /// A YAML file with default values for the options
+    /// Explicitly passed flags override the values from the file
+    #[arg(long, global = true)]
+    #[serde(skip)]
+    config: Option<String>,
+    /// Print the effective configuration as YAML and exit
+    #[arg(long, global = true)]
+    #[serde(skip)]
+    dump_config: bool,
     /// Path to the git repo to replay changesets to
-    #[arg(short, long, default_value = "./osm-git-repo")]
+    #[arg(short, long, global = true, default_value = "./osm-git-repo")]
     git_repo_path: String,
-    /// The server to get day replication files from
+    /// Where to write cache files
+    #[arg(long, global = true, default_value = "./cache")]
+    cache_path: String,
+    /// Map private (negative) object ids above this offset
+    /// This allows layering private data onto OSM data without id collisions
+    #[arg(long, global = true)]
+    private_id_offset: Option<u64>,
+    /// The file name prefix used for objects with private ids
+    #[arg(long, global = true, default_value = "private-")]
+    private_id_prefix: String,
+    /// A Unix socket a running replay listens on for `osm-git ctl` commands
+    #[arg(long, global = true)]
+    control_socket: Option<String>,
+    /// The preset of settings to use. Explicitly passed flags override the preset
+    #[arg(long, global = true, value_enum, default_value_t = Profile::Archive)]
+    profile: Profile,
+    /// The format of the changeset notes
+    #[arg(long, global = true, value_enum)]
+    note_format: Option<NoteFormat>,
+    /// The notes ref the changeset metadata is written to and read from
+    #[arg(long, global = true, default_value = CHANGESET_NOTES_REF)]
+    notes_ref: String,
+    /// Who the commits of changesets are attributed to. With `bot` the committer is also
+    /// the author and the mapper is only recorded in the commit trailers
+    #[arg(long, global = true, value_enum, default_value_t = IdentityPolicy::Mapper)]
+    identity_policy: IdentityPolicy,
+    /// The domain of the emails mappers author commits with, as `<uid>@<domain>`
+    #[arg(long, global = true, default_value = DEFAULT_AUTHOR_EMAIL_DOMAIN)]
+    author_email_domain: String,
+    /// The name anonymous changesets are authored by
+    #[arg(long, global = true, default_value = DEFAULT_ANONYMOUS_NAME)]
+    anonymous_name: String,
+    /// The email anonymous changesets are authored with. Defaults to
+    /// anonymous@<author email domain>
+    #[arg(long, global = true)]
+    anonymous_email: Option<String>,
+    /// The name the commits are committed by, like the bot account of an organization
+    #[arg(long, global = true, default_value = DEFAULT_COMMITTER_NAME)]
+    committer_name: String,
+    /// The email the commits are committed by
+    #[arg(long, global = true, default_value = DEFAULT_COMMITTER_EMAIL)]
+    committer_email: String,
+    /// Sign the commits and sequence tags written by osm-git with this key, given as
+    /// ssh:<private key file> or gpg:<key id>, so users of the mirror can check them with
+    /// `git verify-commit` and `git verify-tag`
+    #[arg(long, global = true)]
+    #[serde(serialize_with = "serialize_display")]
+    signing_key: Option<SigningKey>,
+    /// The number of directory levels used to shard object files in a new git repo
+    #[arg(long, global = true, default_value = "2")]
+    fan_out_depth: u8,
+    /// The format objects are stored in, YAML by default. It is recorded when the git repo
+    /// is created and existing git repos must be used with the same format
+    #[arg(long, global = true, value_enum)]
+    object_format: Option<ObjectFormat>,
+    /// Warn when a directory in the git repo has more entries than this
+    #[arg(long, global = true, default_value = "1000")]
+    max_shard_entries: usize,
+    /// Warn when an object file is bigger than this many bytes
+    #[arg(long, global = true, default_value = "1048576")]
+    max_object_size: u64,
+    #[command(subcommand)]
+    #[serde(skip)]
+    command: Commands,
+}
+
+/// Options of the replay
+#[cfg(feature = "http")]
+#[derive(Args, Clone, Serialize)]
+struct ReplayArgs {
+    /// The server to get day replication files from. Also takes a local copy of a
+    /// replication server as a path or file:// URL, a copy in an S3 compatible object
+    /// storage as s3://<bucket>/<prefix>, or the daily feed of a Geofabrik extract as
+    /// geofabrik:<region>, like geofabrik:europe/germany. Can be given several times for mirrors of the
+    /// same feeds: once downloads from a server keep failing, the replay switches to the
+    /// next one
     #[arg(
         short,
         long,
         default_value = "https://planet.openstreetmap.org/replication/day"
     )]
-    replication_server: String,
-    /// Where to write cache files
-    #[arg(long, default_value = "./cache")]
-    cache_path: String,
+    replication_server: Vec<String>,
+    /// The URL of the object storage of an s3:// --replication-server. Defaults to AWS S3.
+    /// Requests are signed with the AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY of the
+    /// environment, if set
+    #[arg(long)]
+    s3_endpoint: Option<String>,
+    /// The region of the object storage of an s3:// --replication-server
+    #[arg(long, default_value = "us-east-1")]
+    s3_region: String,
+    /// The cookie file of a geofabrik: --replication-server, as written by Geofabrik's
+    /// oauth_cookie_client.py. The public extracts have no changeset ids, so the feeds are
+    /// read from the server for logged in OSM users
+    #[arg(long)]
+    geofabrik_cookie: Option<String>,
+    /// Which replication feed to replay. A server URL ending in /day, /hour or /minute is
+    /// switched to the feed of this interval
+    #[arg(long, value_enum, default_value_t = IntervalMode::Day)]
+    replication_interval: IntervalMode,
+    /// Replay local osc files instead of the replication server: dir:<path> for a
+    /// directory of .osc or .osc.gz files, like `import-osc`, or - for a single file on
+    /// stdin. With --no-changeset-api the replay runs offline
+    #[arg(long, conflicts_with_all = ["start_data", "start_date", "follow"])]
+    #[serde(serialize_with = "serialize_display")]
+    source: Option<LocalSource>,
     /// If the git repo should be removed and recreated
     #[arg(short, long)]
     clean: bool,
-    /// Where to start downloading data from
-    #[arg(long, default_value = "000/000/000")]
-    start_data: String,
+    /// Where to start downloading data from, like 005/123/456
+    /// Defaults to the sequence after the last one applied to the git repo
+    #[arg(long)]
+    #[serde(serialize_with = "serialize_display")]
+    start_data: Option<SequenceNumber>,
+    /// Start at the first sequence with changes after this time, like 2023-01-01T00:00:00Z
+    /// or 2023-01-01, instead of giving --start-data. The sequence is looked up in the
+    /// state files of the replication server
+    #[arg(long, conflicts_with = "start_data")]
+    start_date: Option<String>,
+    /// Stop after this sequence, like 005/123/456, for a reproducible snapshot. With
+    /// --replication-interval auto it is a sequence of the feed replayed at the end
+    #[arg(long)]
+    #[serde(serialize_with = "serialize_display")]
+    end_sequence: Option<SequenceNumber>,
+    /// Stop before the first sequence with changes after this time, like
+    /// 2023-02-01T00:00:00Z or 2023-02-01
+    #[arg(long)]
+    end_date: Option<String>,
     /// The time to wait between downloading data
     /// This is to avoid causing a lot of load on the OSM servers
     #[arg(long, default_value = "500")]
     wait_time: u64,
+    /// If the changeset metadata should be written as git notes
+    #[arg(long)]
+    notes: Option<bool>,
+    /// Keep running after catching up and apply new replication files as they are published
+    #[arg(long)]
+    follow: bool,
+    /// How many seconds to wait between checking the server for new replication files
+    /// in follow mode
+    #[arg(long, default_value = "60")]
+    poll_interval: u64,
+    /// The least number of upcoming replication files downloaded in the background while
+    /// the current one is applied
+    #[arg(long, default_value = "1")]
+    min_prefetch: usize,
+    /// The most upcoming replication files downloaded in the background. The number is
+    /// tuned in between depending on whether downloading or applying is slower
+    #[arg(long, default_value = "4")]
+    max_prefetch: usize,
+    /// Maintain a ref per campaign, like refs/campaigns/hotosm-project-12345, pointing to
+    /// its latest commit. Campaigns are detected from the hashtags of the changesets and
+    /// their members are listed in notes under refs/notes/campaigns
+    #[arg(long)]
+    campaign_refs: bool,
+    /// Update the .mailmap at the end of the run, like `osm-git mailmap`
+    #[arg(long)]
+    update_mailmap: bool,
+    /// Squash changesets of the same user made within this many minutes
+    /// in an overlapping area into one commit
+    #[arg(long)]
+    squash_window: Option<i64>,
+    /// Make a commit per changeset, or a single commit per replication file listing its
+    /// changesets in Changeset-Id trailers, for a much smaller planet mirror. Defaults to
+    /// the granularity of the profile
+    #[arg(long, value_enum, conflicts_with = "squash_window")]
+    granularity: Option<Granularity>,
+    /// Add a Tag-Keys trailer to the commits summarizing the tag keys of the changed
+    /// objects. Defaults to the profile, which only leaves them out for a mirror
+    #[arg(long)]
+    tag_key_summaries: Option<bool>,
+    /// Only keep objects within this bounding box, given as min_lon,min_lat,max_lon,max_lat.
+    /// Ways and relations are kept if they have a node or member within it and changesets
+    /// without any kept objects are dropped, for a small regional mirror
+    #[arg(long, conflicts_with = "poly")]
+    #[serde(serialize_with = "serialize_display")]
+    bbox: Option<BBox>,
+    /// Only keep objects within the region of this Osmosis polygon file (.poly), like
+    /// --bbox
+    #[arg(long)]
+    poly: Option<String>,
+    /// Only keep objects matching this osmium-style tag filter expression, like
+    /// "w/highway=cycleway" or "n/amenity=drinking_water,toilets", and the objects of
+    /// the same file they reference. Can be given several times to keep objects matching
+    /// any of them
+    #[arg(long)]
+    filter: Vec<String>,
+    /// Only keep edits of this user, to review the work of a mapper or organization. Can
+    /// be given several times, together with --uid
+    #[arg(long)]
+    user: Vec<String>,
+    /// Only keep edits of the user with this id, like --user
+    #[arg(long)]
+    uid: Vec<u64>,
+    /// Drop changesets tagged as automated edits or imports with bot=yes or import=yes
+    #[arg(long)]
+    exclude_bots: bool,
+    /// A GeoJSON file with named boundaries. The names of the boundaries a changeset
+    /// intersects are added as `Admin-Area` trailers to its commit
+    #[arg(long)]
+    admin_areas: Option<String>,
+    /// A YAML file with rules for objects to watch. Changes of matching objects are
+    /// written to a report file and optionally sent to a webhook or Matrix room
+    #[arg(long)]
+    watchlist: Option<String>,
+    /// Keep the metadata of recent changesets from this changeset replication stream in a
+    /// rolling store, like https://planet.openstreetmap.org/replication/changesets. New
+    /// changesets then don't need a newer changeset dump
+    #[arg(long)]
+    changeset_stream: Option<String>,
+    /// How many days changesets are kept in the rolling store after they were closed.
+    /// The first sync of the changeset stream goes back this far
+    #[arg(long, default_value = "2")]
+    changeset_retention_days: u64,
+    /// Record changesets being opened and closed in the changeset stream as empty commits,
+    /// so the history has the lifecycle of changesets whose edits are not in the git repo
+    #[arg(long, requires = "changeset_stream")]
+    changeset_lifecycle: bool,
+    /// The OSM API to download changesets from which are missing from the changeset dump
+    /// and stream. Defaults to the API of the profile, the production API of
+    /// openstreetmap.org unless the sandbox profile is used
+    #[arg(long)]
+    changeset_api: Option<String>,
+    /// Don't download missing changesets from the OSM API. They are committed without
+    /// metadata instead
+    #[arg(long)]
+    no_changeset_api: bool,
+    /// The most changesets downloaded from the OSM API per day. The requests are spread
+    /// over the day, so catching up after a long downtime doesn't burst thousands of
+    /// them. Changesets over the quota are committed without metadata, which
+    /// `annotate-missing` adds once a newer changeset dump has them
+    #[arg(long, default_value_t = DEFAULT_DAILY_QUOTA)]
+    changeset_api_quota: u32,
+    /// How many threads serialize the objects of a replication file. The commits and
+    /// their order are the same for any number
+    #[arg(long, default_value = "1")]
+    serialize_workers: usize,
+    /// How many MiB of serialized objects may wait for earlier commits in memory. More
+    /// are moved to disk until it is their turn
+    #[arg(long, default_value = "256")]
+    commit_buffer_mb: usize,
+    /// Keep the cached replication files below this many MiB by removing the least
+    /// recently used ones. Files which are not applied yet are never removed
+    #[arg(long, value_name = "MiB")]
+    max_cache_size: Option<u64>,
+    /// Follow the usage policies of the OSM servers: downloads use a single connection
+    /// without prefetching, wait at least a second between files and only happen in the
+    /// off-peak window. A --user-agent with contact information is required
+    #[arg(long)]
+    polite: bool,
+    /// How often a download is attempted before the replay stops with its error. Network
+    /// errors and 429 or 5xx responses are retried with exponential backoff, or after the
+    /// time a Retry-After header asks for
+    #[arg(long, default_value = "5")]
+    max_attempts: u32,
+    /// Record all responses of the servers to this directory, or play them back from it
+    /// without any requests, depending on --cassette-mode
+    #[arg(long)]
+    cassette: Option<String>,
+    /// Whether the --cassette records responses or plays them back
+    #[arg(long, value_enum, default_value_t = CassetteMode::Replay, requires = "cassette")]
+    cassette_mode: CassetteMode,
+    /// The user agent to send, which should say how to contact the operator like
+    /// "my-mirror/1.0 (admin@example.com)"
+    #[arg(long)]
+    user_agent: Option<String>,
+    /// Only download replication files in this daily window in UTC, like 00:00-06:00.
+    /// Defaults to 00:00-06:00 with --polite
+    #[arg(long)]
+    off_peak: Option<String>,
+    /// Where the current versions of the objects are kept while replication files are
+    /// applied. Defaults to the state store of the profile
+    #[arg(long, value_enum)]
+    state_store: Option<StateStoreKind>,
+    /// Create the git repo as a bare repo. Commits are built in memory without writing
+    /// the objects to a working directory, which is a lot faster for large diffs.
+    /// Only used when the git repo is created. Defaults to the profile, `--bare false`
+    /// creates a working directory with any profile
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    bare: Option<bool>,
+    /// Commit a manifest of every run to meta/provenance/, listing the hashes of the
+    /// replication files and the changeset dump it read, the hash of the configuration,
+    /// the version of osm-git and the commits it created
+    #[arg(long)]
+    provenance: bool,
+    /// Sign the provenance manifests with this key, given as ssh:<private key file> or
+    /// gpg:<key id>. The detached signature is committed next to the manifest
+    #[arg(long, requires = "provenance")]
+    #[serde(serialize_with = "serialize_display")]
+    provenance_signing_key: Option<SigningKey>,
+    /// Push the branch and the notes to this remote as the replay goes, given as a URL or
+    /// the name of a remote of the git repo
+    #[arg(long)]
+    push_remote: Option<String>,
+    /// How often to push: every N replication files like 5, or every N commits like
+    /// 100commits. Defaults to every replication file
+    #[arg(long, requires = "push_remote")]
+    #[serde(serialize_with = "serialize_display")]
+    push_every: Option<PushCadence>,
+    /// Stop the replay at an element of a replication file which can't be parsed. This is
+    /// the default, and takes precedence over --lenient
+    #[arg(long)]
+    strict: bool,
+    /// Skip elements of replication files which can't be parsed instead of stopping. They
+    /// are logged and recorded in the errors folder of the cache
+    #[arg(long)]
+    lenient: bool,
+}
+
+#[cfg(feature = "http")]
+impl ReplayArgs {
+    /// The source of the --replication-server, failing over between them if there are
+    /// several
+    fn replication_source(&self, downloader: &Downloader) -> Result<Arc<dyn ReplicationSource>> {
+        let options = SourceOptions {
+            s3: S3Options {
+                endpoint: self.s3_endpoint.clone(),
+                region: self.s3_region.clone(),
+            },
+            geofabrik_cookie: self.geofabrik_cookie.as_ref().map(std::path::PathBuf::from),
+        };
+        let mut mirrors = self
+            .replication_server
+            .iter()
+            .map(|server| open_source(server, downloader, &options))
+            .collect::<Result<Vec<_>>>()?;
+        if mirrors.len() == 1 {
+            return Ok(mirrors.remove(0));
+        }
+        Ok(Arc::new(FailoverSource::new(mirrors)?))
+    }
+
+    /// The first --replication-server
+    fn primary_replication_server(&self) -> &str {
+        self.replication_server
+            .first()
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+
+    fn parse_mode(&self) -> ParseMode {
+        if self.lenient && !self.strict {
+            ParseMode::Lenient
+        } else {
+            ParseMode::Strict
+        }
+    }
+
+    fn cassette(&self) -> Option<Arc<Cassette>> {
+        self.cassette.as_ref().map(|directory| {
+            info!(
+                "Using the cassette {} in {:?} mode",
+                directory, self.cassette_mode
+            );
+            Arc::new(Cassette {
+                directory: directory.into(),
+                mode: self.cassette_mode,
+            })
+        })
+    }
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Set up a replay: ask for the source, area, layout, identity and schedule, check the
+    /// replication server and the free disk space, write a config file and create the git
+    /// repo
+    #[cfg(feature = "http")]
+    Init {
+        /// Don't ask, take the answers from the options and the config file
+        #[arg(long)]
+        non_interactive: bool,
+        /// The config file to write
+        #[arg(long, default_value = "osm-git.yaml")]
+        output: String,
+        #[command(flatten)]
+        replay: ReplayArgs,
+    },
+
+    /// Replay the replication files to the git repo
+    #[cfg(feature = "http")]
+    Replay(ReplayArgs),
+
+    /// Follow the server and measure how long new sequences take from their publication
+    /// upstream until they are committed, then report the percentiles
+    #[cfg(feature = "http")]
+    BenchFollow {
+        /// The number of sequences to measure before stopping
+        #[arg(long, default_value = "60")]
+        samples: usize,
+        /// Fail if the 95th percentile of the latency exceeds this many seconds
+        #[arg(long)]
+        target: Option<f64>,
+        #[command(flatten)]
+        replay: ReplayArgs,
+    },
+
+    /// Replay a directory of .osc or .osc.gz files with arbitrary names, like a collection
+    /// of historical diffs. The files are ordered by the timestamps of their objects and
+    /// object versions which were in an earlier file are dropped. Takes the options of the
+    /// replay for committing
+    #[cfg(feature = "http")]
+    ImportOsc {
+        /// The directory with the osc files
+        directory: String,
+        #[command(flatten)]
+        replay: ReplayArgs,
+    },
+
+    /// Control a running replay through its --control-socket
+    #[cfg(feature = "http")]
+    Ctl {
+        #[command(subcommand)]
+        command: ControlCommand,
+    },
+
+    /// Serve read-only HTTP endpoints like object geometries and the OSM API 0.6 object
+    /// reads for the git repo
+    #[cfg(feature = "serve")]
+    Serve {
+        /// The address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+    },
+
+    /// Export the objects at a commit as an OSM XML file
+    #[cfg(feature = "export")]
+    Export {
+        /// A revision or ISO 8601 date. Defaults to HEAD
+        #[arg(long)]
+        at: Option<String>,
+        /// The file to write to instead of printing the export
+        #[arg(short, long)]
+        output: Option<String>,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Osm)]
+        format: ExportFormat,
+        /// A revision or ISO 8601 date. Only versions committed after it are written to a
+        /// history export. Defaults to the start of the history
+        #[arg(long)]
+        since: Option<String>,
+    },
+
+    /// Write the changes of the objects between two commits as an osmChange file, for
+    /// any OSM tool to apply
+    #[cfg(feature = "export")]
+    Diff {
+        /// A revision or ISO 8601 date to diff from
+        from: String,
+        /// A revision or ISO 8601 date to diff to. Defaults to HEAD
+        to: Option<String>,
+        #[arg(long, value_enum, default_value_t = DiffFormat::Osc)]
+        format: DiffFormat,
+        /// The file to write to instead of printing the diff
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+
+    /// Export the objects at a commit as a vector tileset to preview them on a map
+    #[cfg(feature = "tiles")]
+    Tiles {
+        /// A revision or ISO 8601 date. Defaults to HEAD
+        #[arg(long)]
+        at: Option<String>,
+        /// The tileset to write, as MBTiles or PMTiles depending on the extension
+        #[arg(short, long)]
+        output: String,
+        #[arg(long, default_value_t = 0)]
+        min_zoom: u8,
+        #[arg(long, default_value_t = 14)]
+        max_zoom: u8,
+    },
+
+    /// Show which changeset and mapper last changed every tag, node and member of an
+    /// object, like `blame way 123`
+    Blame {
+        /// The type of the object: node, way or relation
+        #[arg(value_parser = ["node", "way", "relation"])]
+        object_type: String,
+        /// The upstream id of the object. Negative placeholder ids are allowed
+        #[arg(allow_negative_numbers = true)]
+        id: i64,
+    },
+
+    /// Check the git repo for inconsistencies
+    Verify {
+        /// A revision or ISO 8601 date to check the objects at. Defaults to HEAD
+        #[arg(long)]
+        at: Option<String>,
+    },
+
+    /// Watch the working directory for manual edits of object files, and stage the edits
+    /// which match the schema and only reference existing objects. Runs until stopped
+    #[cfg(feature = "editing")]
+    WatchWorkdir,
+
+    /// Show the replication sequences applied to the git repo, or the history of one
+    /// object with its changesets and tag changes, like `log node 123`
+    Log {
+        /// The type of the object: node, way or relation
+        #[arg(value_parser = ["node", "way", "relation"], requires = "id")]
+        object_type: Option<String>,
+        /// The upstream id of the object. Negative placeholder ids are allowed
+        #[arg(allow_negative_numbers = true)]
+        id: Option<i64>,
+    },
+
+    /// Clone an archive partially: without blobs, only the history since a date and only
+    /// the objects changed by changesets within an area
+    Clone {
+        /// Only check out objects changed by changesets intersecting this bounding box,
+        /// given as min_lon,min_lat,max_lon,max_lat
+        #[arg(long)]
+        area: Option<BBox>,
+        /// Only fetch the commits after this date, like 2023-01-01
+        #[arg(long)]
+        since: Option<String>,
+        /// The URL of the archive
+        url: String,
+        /// The directory to clone into
+        directory: String,
+    },
+
+    /// Import a planet file or extract in the PBF format as the initial commits of a new
+    /// git repo. History files like history-latest.osm.pbf are imported with one commit per
+    /// changeset. If the file names its replication feed and sequence, a replay of that
+    /// feed continues right after it
+    #[cfg(feature = "pbf")]
+    Bootstrap {
+        /// The PBF file to import, like a Geofabrik extract
+        #[arg(long)]
+        pbf: String,
+        /// How many objects go into one commit. History files get a commit per changeset
+        #[arg(long, default_value = "100000")]
+        objects_per_commit: usize,
+        /// Where the current versions of the objects are kept. Defaults to the state store
+        /// of the profile
+        #[arg(long, value_enum)]
+        state_store: Option<StateStoreKind>,
+        /// Create the git repo as a bare repo. Defaults to the profile
+        #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+        bare: Option<bool>,
+    },
+
+    /// List the campaigns recorded with --campaign-refs, or the changesets of one campaign
+    Campaigns {
+        /// The campaign to list the changesets of, like hotosm-project-12345
+        campaign: Option<String>,
+    },
+
+    /// Validate a changeset dump, report its coverage and refresh its index
+    #[cfg(feature = "dumps")]
+    CheckDump {
+        /// The changeset dump (`changesets-*.osm.zst`) to check
+        file: String,
+    },
+    /// Print a service definition which runs the replay with the current options
+    #[cfg(feature = "http")]
+    InstallService {
+        /// The service manager to generate the definition for
+        #[arg(long, value_enum, default_value_t = ServiceManager::Systemd)]
+        manager: ServiceManager,
+        /// Write the definition to this file instead of printing it
+        #[arg(long)]
+        output: Option<String>,
+        #[command(flatten)]
+        replay: ReplayArgs,
+    },
+    /// Build or refresh the index of the latest changeset dump for fast lookups
+    #[cfg(feature = "dumps")]
+    IndexChangesets,
+    /// Print shell completions
+    Completions {
+        /// The shell to generate the completions for
+        shell: Shell,
+    },
+    /// Manage the changeset notes of the git repo
+    Notes {
+        #[command(subcommand)]
+        command: NotesCommands,
+    },
+
+    /// Add notes to changesets which were committed without metadata, once a newer
+    /// changeset dump includes them. The commits are not rewritten
+    AnnotateMissing,
+
+    /// Update the .mailmap of the git repo, which maps the emails of renamed mappers to
+    /// their latest name and the `<user>@osm` emails of older commits to the emails made
+    /// from the uid
+    Mailmap,
+
+    /// Record that an upstream id refers to an object stored under another id, for
+    /// example after upstream renumbered it. The mapping is kept in `meta/idmap`
+    Renumber {
+        /// The type of the object: node, way or relation
+        #[arg(value_parser = ["node", "way", "relation"])]
+        object_type: String,
+        /// The id used upstream from now on. Negative placeholder ids are allowed
+        #[arg(allow_negative_numbers = true)]
+        upstream_id: i64,
+        /// The id the object is stored under in the git repo
+        id: u64,
+    },
+
+    /// Move all object files to a layout with a different fan-out depth
+    Reshard {
+        /// The new number of directory levels used to shard object files
+        depth: u8,
+    },
+
+    /// Revert a changeset with a new commit, restoring the previous versions of the objects
+    /// it modified or deleted and deleting the ones it created
+    RevertChangeset {
+        /// The id of the changeset
+        id: u64,
+        /// Revert the other objects when some were changed again after the changeset,
+        /// instead of committing nothing
+        #[arg(long)]
+        skip_conflicts: bool,
+    },
+
+    /// Upload the local commits since the last upload to the OSM API as a changeset and
+    /// record the ids the API gave the created objects
+    #[cfg(feature = "http")]
+    Upload {
+        /// The commit after which the local edits start. Defaults to the commit recording
+        /// the last upload
+        #[arg(long)]
+        base: Option<String>,
+        /// The comment of the changeset. Defaults to the summaries of the commits
+        #[arg(long)]
+        comment: Option<String>,
+        /// The OSM API to upload to. Defaults to the API of the profile
+        #[arg(long)]
+        api: Option<String>,
+        /// A file with the OAuth2 access token. Defaults to the OSM_OAUTH2_TOKEN
+        /// environment variable
+        #[arg(long)]
+        oauth2_token_file: Option<String>,
+        /// Merge the edits of objects changed upstream since the base commit when the
+        /// changes don't overlap, instead of stopping at the conflict
+        #[arg(long)]
+        merge_tags: bool,
+        /// Print the osmChange upload instead of uploading it, without checking for
+        /// conflicts
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Upgrade all object files written by older versions of osm-git to the current file
+    /// version, as a single commit
+    Migrate {
+        /// Convert the object files to this format instead, like the compact one
+        #[arg(long, value_enum)]
+        to_format: Option<ObjectFormat>,
+    },
+
+    /// Combine archives with disjoint spatial filters into a new git repo at the git repo path
+    MergeArchives {
+        /// The git repos of the archives to merge
+        #[arg(required = true, num_args = 2..)]
+        archives: Vec<String>,
+    },
+
+    /// Summarize the commits of the last day or week in the configured areas, and write
+    /// the summary to a directory or send it by email. Meant to be run from cron
+    #[cfg(feature = "notifications")]
+    Digest {
+        /// The YAML file configuring the areas and where the digest goes
+        digest_config: String,
+        /// The time span the digest covers, ending now
+        #[arg(long, value_enum, default_value = "daily")]
+        period: DigestPeriod,
+    },
+}
+
+#[derive(Subcommand)]
+enum NotesCommands {
+    /// Regenerate the notes of all changesets recorded in the sequence tags
+    Rebuild,
+}
+
+impl Cli {
+    /// The options of the replay the subcommand runs or sets up
+    #[cfg(feature = "http")]
+    fn replay_args(&self) -> Option<&ReplayArgs> {
+        match &self.command {
+            Commands::Replay(replay) => Some(replay),
+            Commands::BenchFollow { replay, .. } => Some(replay),
+            Commands::InstallService { replay, .. } => Some(replay),
+            Commands::ImportOsc { replay, .. } => Some(replay),
+            _ => None,
+        }
+    }
+
+    /// The effective configuration in the format of the config file
+    fn effective_config(&self) -> Result<Value> {
+        #[allow(unused_mut)]
+        let mut config = serde_yaml::to_value(self)?;
+        #[cfg(feature = "http")]
+        if let (Some(config), Some(replay)) = (config.as_mapping_mut(), self.replay_args()) {
+            if let Value::Mapping(replay) = serde_yaml::to_value(replay)? {
+                config.extend(replay);
+            }
+        }
+        Ok(config)
+    }
+
+    /// The signature of the commits, notes and tags written by osm-git
+    fn committer(&self) -> Result<Signature<'static>> {
+        Ok(Signature::now(&self.committer_name, &self.committer_email)?)
+    }
+
+    /// The layout metadata of a new git repo
+    #[cfg(any(feature = "http", feature = "pbf"))]
+    fn layout_metadata(&self) -> LayoutMetadata {
+        LayoutMetadata {
+            fan_out_depth: self.fan_out_depth,
+            object_format: self.object_format.unwrap_or_default(),
+        }
+    }
+
+    fn id_mapper(&self) -> Box<dyn IdMapper> {
+        match self.private_id_offset {
+            Some(offset) => {
+                info!(
+                    "Mapping private ids above {} with prefix {}",
+                    offset, self.private_id_prefix
+                );
+                Box::new(PrivateOverlayMapping {
+                    offset,
+                    prefix: self.private_id_prefix.clone(),
+                })
+            }
+            None => Box::new(IdentityMapping),
+        }
+    }
+
+    fn shard_budget(&self) -> ShardBudget {
+        ShardBudget {
+            max_entries: self.max_shard_entries,
+            max_file_size: self.max_object_size,
+        }
+    }
+
+    fn changeset_location(&self) -> String {
+        format!("{}/changesets/torrents", self.cache_path)
+    }
+
+    #[cfg(any(feature = "http", feature = "pbf"))]
+    fn authors(&self) -> AuthorIdentity {
+        AuthorIdentity::new(
+            &self.author_email_domain,
+            &self.anonymous_name,
+            self.anonymous_email.as_deref(),
+        )
+    }
+
+    fn mailmap_index_path(&self) -> String {
+        format!("{}/mailmap.json", self.cache_path)
+    }
+
+    fn error_report_path(&self) -> String {
+        format!("{}/errors", self.cache_path)
+    }
+}
+
+/// Parse the command line and merge it with the config file if one is given
+fn parse_cli() -> Result<Cli> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let cli = Cli::parse_from(&args);
+    let Some(config_path) = &cli.config else {
+        return Ok(cli);
+    };
+
+    let command = apply_config_file(Cli::command(), config_path)?;
+    Ok(Cli::from_arg_matches(&command.get_matches_from(args))?)
+}
+
+/// Parse the command line and the config file again while the replay runs
+///
+/// Unlike [`parse_cli`], invalid options are returned as errors instead of exiting, so a
+/// broken config file doesn't stop the replay.
+#[cfg(feature = "http")]
+fn reparse_cli() -> Result<Cli> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let cli = Cli::try_parse_from(&args)?;
+    let Some(config_path) = &cli.config else {
+        return Ok(cli);
+    };
+
+    let command = apply_config_file(Cli::command(), config_path)?;
+    Ok(Cli::from_arg_matches(&command.try_get_matches_from(args)?)?)
+}
+
+/// Log the progress of a replay
+#[cfg(feature = "http")]
+fn log_event(event: &ReplayEvent) {
+    match event {
+        ReplayEvent::DownloadStarted {
+            url,
+            timestamp: Some(timestamp),
+            ..
+        } => info!(
+            "Downloading data file until {} from {}",
+            timestamp.format(&Rfc3339).unwrap_or_default(),
+            url
+        ),
+        ReplayEvent::DownloadStarted { url, .. } => info!("Downloading data file from {}", url),
+        ReplayEvent::CacheHit { path, .. } => info!("Using cached data file at {}", path),
+        ReplayEvent::CacheEvicted {
+            files,
+            bytes,
+            cache_size,
+        } => debug!(
+            "Evicted {} cached data files of {} MiB, the cache has {} MiB left",
+            files,
+            bytes / 1024 / 1024,
+            cache_size / 1024 / 1024
+        ),
+        ReplayEvent::CacheCorrupt { path, error, .. } => warn!(
+            "The cached data file at {} is corrupt ({}), downloading it again",
+            path, error
+        ),
+        ReplayEvent::SequenceMissing { sequence } => {
+            warn!("data file for sequence {} not found", sequence)
+        }
+        ReplayEvent::CaughtUp {
+            next_sequence,
+            latest_sequence,
+        } => info!(
+            "Caught up with the newest sequence {} of the server. Next is {}",
+            latest_sequence, next_sequence
+        ),
+        ReplayEvent::EndReached { next_sequence } => {
+            info!("Reached the end of the replay before {}", next_sequence)
+        }
+        ReplayEvent::GapRecorded { sequence, commit } => {
+            warn!("Recorded gap for sequence {} as {}", sequence, commit)
+        }
+        ReplayEvent::ChangesetEventRecorded {
+            changeset_id,
+            event,
+            commit,
+        } => debug!(
+            "Recorded {} of changeset {} as {}",
+            event, changeset_id, commit
+        ),
+        ReplayEvent::AlreadyApplied {
+            sequence,
+            changesets,
+        } => info!(
+            "Sequence {} was applied before. Skipping {} recorded changesets",
+            sequence, changesets
+        ),
+        ReplayEvent::ElementParsed { .. } => {}
+        ReplayEvent::ElementSkipped {
+            sequence,
+            object_type,
+            id,
+            error,
+        } => warn!(
+            "Skipped a {} {} of sequence {}: {}",
+            object_type,
+            id.as_deref().unwrap_or("without id"),
+            sequence,
+            error
+        ),
+        ReplayEvent::CommitCreated {
+            changeset_ids,
+            commit,
+        } => debug!("Committed changesets {:?} as {}", changeset_ids, commit),
+        ReplayEvent::MetadataMissing {
+            sequence,
+            changeset_ids,
+        } => warn!(
+            "Sequence {} has {} changesets without metadata: {:?}",
+            sequence,
+            changeset_ids.len(),
+            changeset_ids
+        ),
+        ReplayEvent::WatchMatched(watch_match) => info!("Watched object changed: {}", watch_match),
+        ReplayEvent::ChangesetStreamSynced {
+            sequence,
+            changesets,
+            pruned,
+        } => info!(
+            "Synced changeset stream up to {} ({} changesets stored, {} pruned)",
+            sequence, changesets, pruned
+        ),
+        ReplayEvent::CampaignsUpdated {
+            sequence,
+            campaigns,
+        } => info!(
+            "Updated {} campaign refs in sequence {}",
+            campaigns, sequence
+        ),
+        ReplayEvent::SequenceApplied {
+            sequence,
+            changesets,
+            notes_written,
+            ..
+        } => info!(
+            "Applied sequence {} with {} changesets ({} notes written)",
+            sequence, changesets, notes_written
+        ),
+        ReplayEvent::PrefetchDepthChanged {
+            prefetch_depth,
+            reason,
+        } => info!("Downloading {} files ahead as {}", prefetch_depth, reason),
+        ReplayEvent::IntervalSwitched {
+            from,
+            to,
+            next_sequence,
+        } => info!(
+            "Switching from the {} feed to the {} feed at {}",
+            from, to, next_sequence
+        ),
+        ReplayEvent::SettingsReloaded => info!("Using the reloaded settings"),
+        ReplayEvent::Paused => {
+            info!("Paused. Continue with `osm-git ctl resume` or `osm-git ctl step`")
+        }
+        ReplayEvent::Resumed => info!("Resumed"),
+        ReplayEvent::OffPeakWait { resumes_at } => info!(
+            "Waiting for the off-peak window. Downloads resume at {}",
+            resumes_at.format(&Rfc3339).unwrap_or_default()
+        ),
+        ReplayEvent::Finished { last_sequence } => {
+            info!("Downloaded data until {}", last_sequence)
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
     tracing_subscriber::fmt::init();
-    let cli = Cli::parse();
+    let cli = parse_cli()?;
+
+    if cli.dump_config {
+        print!("{}", serde_yaml::to_string(&cli.effective_config()?)?);
+        return Ok(());
+    }
+    if let Some(signing_key) = &cli.signing_key {
+        sign_commits_with(signing_key.clone())?;
+    }
+    report_errors_to(cli.error_report_path())?;
+
+    match &cli.command {
+        #[cfg(feature = "http")]
+        Commands::Replay(replay) => match &replay.source {
+            Some(source) => import_osc(&cli, source, replay).await,
+            None => replay_to_git(&cli, replay.clone(), None).await,
+        },
+        #[cfg(feature = "http")]
+        Commands::BenchFollow {
+            samples,
+            target,
+            replay,
+        } => {
+            let mut replay = replay.clone();
+            replay.follow = true;
+            let mut benchmark = FollowBenchmark::new(*samples);
+            replay_to_git(&cli, replay, Some(&mut benchmark)).await?;
+            let report = benchmark.report();
+            print!("{}", serde_yaml::to_string(&report)?);
+            match (target, report.commit) {
+                (Some(target), Some(commit)) if commit.p95 > *target => Err(eyre!(
+                    "The 95th percentile of the latency is {:.1}s, above the target of {}s",
+                    commit.p95,
+                    target
+                )),
+                (Some(_), None) => Err(eyre!("No sequence was measured")),
+                _ => Ok(()),
+            }
+        }
+        #[cfg(feature = "http")]
+        Commands::ImportOsc { directory, replay } => {
+            import_osc(&cli, &LocalSource::Directory(directory.into()), replay).await
+        }
+        #[cfg(feature = "http")]
+        Commands::Init {
+            non_interactive,
+            output,
+            replay,
+        } => init(&cli, &Prompt::new(!non_interactive), output, replay.clone()).await,
+        #[cfg(feature = "http")]
+        Commands::Ctl { command } => {
+            let socket_path = cli
+                .control_socket
+                .as_deref()
+                .ok_or_else(|| eyre!("Pass the --control-socket of the replay to control"))?;
+            print!("{}", send_command(socket_path, *command).await?);
+            Ok(())
+        }
+        #[cfg(feature = "serve")]
+        Commands::Serve { listen } => {
+            serve(
+                *listen,
+                ServerState {
+                    git_repo_path: cli.git_repo_path.clone(),
+                    id_mapper: Arc::from(cli.id_mapper()),
+                    budget: cli.shard_budget(),
+                },
+            )
+            .await
+        }
+        #[cfg(feature = "export")]
+        Commands::Export {
+            at,
+            output,
+            format,
+            since,
+        } => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            let id_mapper = cli.id_mapper();
+            let commit = resolve_commit(&repository, at.as_deref())?;
+            let mut writer: Box<dyn std::io::Write> = match output {
+                Some(output) => Box::new(BufWriter::new(File::create(output)?)),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            match format {
+                ExportFormat::Osm => {
+                    let snapshot = Snapshot::new(
+                        &repository,
+                        &commit,
+                        id_mapper.as_ref(),
+                        cli.shard_budget(),
+                    )?;
+                    let exported = write_osm_xml(&snapshot, &mut writer)?;
+                    info!("Exported {} objects at commit {}", exported, commit.id());
+                }
+                ExportFormat::Osh => {
+                    let since = since
+                        .as_deref()
+                        .map(|since| resolve_commit(&repository, Some(since)))
+                        .transpose()?;
+                    let exported = write_osh_xml(
+                        &repository,
+                        since.as_ref(),
+                        &commit,
+                        id_mapper.as_ref(),
+                        &mut writer,
+                    )?;
+                    info!(
+                        "Exported {} object versions up to commit {}",
+                        exported,
+                        commit.id()
+                    );
+                }
+            }
+            writer.flush()?;
+            Ok(())
+        }
+        #[cfg(feature = "export")]
+        Commands::Diff {
+            from,
+            to,
+            format: DiffFormat::Osc,
+            output,
+        } => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            let id_mapper = cli.id_mapper();
+            let from = resolve_commit(&repository, Some(from))?;
+            let to = resolve_commit(&repository, to.as_deref())?;
+            let mut writer: Box<dyn std::io::Write> = match output {
+                Some(output) => Box::new(BufWriter::new(File::create(output)?)),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            let summary = write_osc_diff(&repository, &from, &to, id_mapper.as_ref(), &mut writer)?;
+            writer.flush()?;
+            info!(
+                "Wrote {} created, {} modified and {} deleted objects between {} and {}",
+                summary.created,
+                summary.modified,
+                summary.deleted,
+                from.id(),
+                to.id()
+            );
+            Ok(())
+        }
+        #[cfg(feature = "tiles")]
+        Commands::Tiles {
+            at,
+            output,
+            min_zoom,
+            max_zoom,
+        } => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            let id_mapper = cli.id_mapper();
+            let commit = resolve_commit(&repository, at.as_deref())?;
+            let snapshot =
+                Snapshot::new(&repository, &commit, id_mapper.as_ref(), cli.shard_budget())?;
+            let summary = write_tileset(
+                &snapshot,
+                std::path::Path::new(output),
+                *min_zoom,
+                *max_zoom,
+            )?;
+            info!(
+                "Wrote {} features in {} tiles at commit {} to {}",
+                summary.features,
+                summary.tiles,
+                commit.id(),
+                output
+            );
+            Ok(())
+        }
+        Commands::Blame { object_type, id } => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            let id_mapper = IndirectMapping::load(&repository, cli.id_mapper())?;
+            let archive_id = id_mapper.map_id(object_type, *id)?;
+            let blame = blame_object(
+                &repository,
+                object_type,
+                archive_id,
+                id_mapper.as_ref(),
+                cli.shard_budget(),
+            )?;
+            let attribution = |index: usize| {
+                let version = &blame.versions[index];
+                format!(
+                    "{} {:>10} {:<20} {}",
+                    &version.commit.to_string()[..10],
+                    version
+                        .changesets
+                        .iter()
+                        .map(u64::to_string)
+                        .collect::<Vec<String>>()
+                        .join(","),
+                    version.user.as_deref().unwrap_or("anonymous"),
+                    version.timestamp
+                )
+            };
+            for (key, value, index) in &blame.tags {
+                println!("{}  {}={}", attribution(*index), key, value);
+            }
+            for (reference, index) in &blame.references {
+                println!("{}  {}", attribution(*index), reference);
+            }
+            Ok(())
+        }
+        Commands::Verify { at } => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            let id_mapper = cli.id_mapper();
+            let commit = resolve_commit(&repository, at.as_deref())?;
+            let snapshot =
+                Snapshot::new(&repository, &commit, id_mapper.as_ref(), cli.shard_budget())?;
+            let report = verify_repository(&repository, &snapshot)?;
+            info!(
+                "Checked {} objects with {} references to objects outside of the git repo",
+                report.objects, report.dangling_references
+            );
+            if report.non_canonical_files > 0 {
+                warn!(
+                    "{} object files are not in the canonical form and will be rewritten the next time their object changes",
+                    report.non_canonical_files
+                );
+            }
+            for problem in &report.problems {
+                error!("{}", problem);
+            }
+            if !report.problems.is_empty() {
+                return Err(eyre!(
+                    "Found {} problems in the git repo",
+                    report.problems.len()
+                ));
+            }
+            Ok(())
+        }
+        #[cfg(feature = "editing")]
+        Commands::WatchWorkdir => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            let id_mapper = cli.id_mapper();
+            let validator =
+                EditValidator::new(&repository, id_mapper.as_ref(), cli.shard_budget())?;
+            info!("Watching {} for edits", cli.git_repo_path);
+            watch_workdir(&validator, &mut |path, outcome| match outcome {
+                EditOutcome::Staged => info!("Staged {}", path.display()),
+                EditOutcome::Removed => info!("Staged the removal of {}", path.display()),
+                EditOutcome::Rejected(problems) => {
+                    warn!("Not staging {}: {}", path.display(), problems.join(". "))
+                }
+                EditOutcome::Ignored => (),
+            })
+        }
+        Commands::Log {
+            object_type: Some(object_type),
+            id: Some(id),
+        } => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            let id_mapper = IndirectMapping::load(&repository, cli.id_mapper())?;
+            let archive_id = id_mapper.map_id(object_type, *id)?;
+            let history = object_history(
+                &repository,
+                object_type,
+                archive_id,
+                id_mapper.as_ref(),
+                cli.shard_budget(),
+            )?;
+            if history.is_empty() {
+                return Err(eyre!("{} {} is not in the git repo", object_type, id));
+            }
+            for version in history {
+                let changesets = version
+                    .changesets
+                    .iter()
+                    .map(u64::to_string)
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                println!(
+                    "{} {} in changeset {} by {} at {}",
+                    &version.commit.to_string()[..10],
+                    match version.change {
+                        ObjectChange::Created => "created",
+                        ObjectChange::Modified => "modified",
+                        ObjectChange::Deleted => "deleted",
+                    },
+                    if changesets.is_empty() {
+                        "-"
+                    } else {
+                        &changesets
+                    },
+                    version.user.as_deref().unwrap_or("anonymous"),
+                    version.timestamp
+                );
+                for change in version.tag_changes {
+                    match change {
+                        TagChange::Added { key, value } => println!("    + {}={}", key, value),
+                        TagChange::Modified {
+                            key,
+                            old_value,
+                            new_value,
+                        } => println!("    ~ {}={} -> {}", key, old_value, new_value),
+                        TagChange::Removed { key, value } => println!("    - {}={}", key, value),
+                    }
+                }
+            }
+            Ok(())
+        }
+        Commands::Log { .. } => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            for entry in replication_log(&repository)? {
+                match entry {
+                    LogEntry::Applied {
+                        sequence,
+                        changesets,
+                    } => match changesets.last() {
+                        Some((_, commit)) => println!(
+                            "{} applied {} changesets up to {}",
+                            sequence,
+                            changesets.len(),
+                            commit
+                        ),
+                        None => println!("{} applied without changesets", sequence),
+                    },
+                    LogEntry::Gap { sequence, commit } => {
+                        println!("{} missing upstream, recorded as gap {}", sequence, commit)
+                    }
+                }
+            }
+            Ok(())
+        }
+        Commands::Clone {
+            area,
+            since,
+            url,
+            directory,
+        } => {
+            let checked_out = partial_clone(&CloneOptions {
+                url,
+                directory: std::path::Path::new(directory),
+                area: *area,
+                since: since.as_deref(),
+            })?;
+            match checked_out {
+                Some(files) => info!(
+                    "Cloned {} into {} with {} files of the area checked out",
+                    url, directory, files
+                ),
+                None => info!("Cloned {} into {}", url, directory),
+            }
+            Ok(())
+        }
+        #[cfg(feature = "pbf")]
+        Commands::Bootstrap {
+            pbf,
+            objects_per_commit,
+            state_store,
+            bare,
+        } => {
+            let profile_settings = cli.profile.settings();
+            bootstrap(
+                &cli,
+                pbf,
+                *objects_per_commit,
+                state_store.unwrap_or(profile_settings.state_store),
+                bare.unwrap_or(profile_settings.bare),
+            )
+        }
+        Commands::Campaigns { campaign } => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            match campaign {
+                Some(campaign) => {
+                    for (changeset_id, commit) in campaign_members(&repository, campaign)? {
+                        println!("{} {}", changeset_id, commit);
+                    }
+                }
+                None => {
+                    for campaign in campaigns(&repository)? {
+                        let members = campaign_members(&repository, &campaign)?;
+                        match members.last() {
+                            Some((_, commit)) => println!(
+                                "{} has {} changesets up to {}",
+                                campaign,
+                                members.len(),
+                                commit
+                            ),
+                            None => println!("{} has no member list", campaign),
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+        #[cfg(feature = "dumps")]
+        Commands::CheckDump { file } => {
+            info!("Checking changeset dump {}", file);
+            let index = check_changeset_dump(file)?;
+            info!(
+                "Changeset dump covers {} changesets ({} open) with ids {} to {} from {} to {}",
+                index.changesets,
+                index.open_changesets,
+                index.first_id,
+                index.last_id,
+                index.earliest_created_at,
+                index.latest_timestamp
+            );
+            index.write(file)
+        }
+        #[cfg(feature = "http")]
+        Commands::InstallService {
+            manager,
+            output,
+            replay,
+        } => {
+            let mut config = serde_yaml::to_value(&cli)?;
+            let mut replay_config = serde_yaml::to_value(replay)?;
+            // A restarted service must neither wipe the git repo nor start over, it resumes
+            // after the last applied sequence instead
+            if let Some(replay_config) = replay_config.as_mapping_mut() {
+                replay_config.remove("clean");
+                replay_config.remove("start_data");
+                replay_config.remove("start_date");
+            }
+            if let Some(config) = config.as_mapping_mut() {
+                // The options of the config file are passed explicitly
+                config.remove("config");
+            }
+            let mut args = config_args(&Cli::command(), config)?;
+            args.push(OsString::from("replay"));
+            args.extend(config_args(
+                Cli::command()
+                    .find_subcommand("replay")
+                    .expect("The replay subcommand exists"),
+                replay_config,
+            )?);
+            let definition = manager.definition(&ServiceDefinition {
+                executable: &std::env::current_exe()?,
+                args: &args,
+                working_directory: &std::env::current_dir()?,
+                // The replay pings every 10 seconds. The margin covers the pushes and
+                // other work the replay waits for in between
+                watchdog_secs: 600,
+            });
+            match output {
+                Some(output) => {
+                    std::fs::write(output, definition)?;
+                    info!(
+                        "Wrote the service definition to {}. Install it as {}",
+                        output,
+                        manager.file_name()
+                    );
+                }
+                None => print!("{}", definition),
+            }
+            Ok(())
+        }
+        #[cfg(feature = "dumps")]
+        Commands::IndexChangesets => {
+            ChangesetIndex::build(&cli.changeset_location())?;
+            Ok(())
+        }
+        Commands::Completions { shell } => {
+            clap_complete::generate(
+                *shell,
+                &mut Cli::command(),
+                "osm-git",
+                &mut std::io::stdout(),
+            );
+            Ok(())
+        }
+        Commands::Notes {
+            command: NotesCommands::Rebuild,
+        } => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            let author = cli.committer()?;
+            let note_format = cli
+                .note_format
+                .unwrap_or(cli.profile.settings().note_format);
+            rebuild_notes(
+                &repository,
+                &author,
+                &cli.changeset_location(),
+                note_format,
+                &cli.notes_ref,
+            )
+        }
+        Commands::Mailmap => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            match update_mailmap(
+                &repository,
+                &cli.committer()?,
+                &cli.author_email_domain,
+                &cli.mailmap_index_path(),
+            )? {
+                Some(commit) => info!("Committed the updated mailmap as {}", commit),
+                None => info!("The mailmap is up to date"),
+            }
+            Ok(())
+        }
+        Commands::AnnotateMissing => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            let author = cli.committer()?;
+            let note_format = cli
+                .note_format
+                .unwrap_or(cli.profile.settings().note_format);
+            let (annotated, still_missing) = annotate_missing(
+                &repository,
+                &author,
+                &cli.changeset_location(),
+                note_format,
+                &cli.notes_ref,
+            )?;
+            println!(
+                "Annotated {} changesets, {} still lack metadata",
+                annotated, still_missing
+            );
+            Ok(())
+        }
+        Commands::Renumber {
+            object_type,
+            upstream_id,
+            id,
+        } => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            recover_staging(&repository)?;
+            let author = cli.committer()?;
+            record_id_mapping(&repository, &author, object_type, *upstream_id, *id)
+        }
+        Commands::Reshard { depth } => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            recover_staging(&repository)?;
+            let author = cli.committer()?;
+            reshard(
+                &repository,
+                &author,
+                cli.id_mapper().as_ref(),
+                cli.shard_budget(),
+                *depth,
+            )
+        }
+        Commands::RevertChangeset { id, skip_conflicts } => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            recover_staging(&repository)?;
+            let id_mapper = IndirectMapping::load(&repository, cli.id_mapper())?;
+            let summary = revert_changeset(
+                &repository,
+                *id,
+                id_mapper.as_ref(),
+                cli.shard_budget(),
+                &cli.committer()?,
+                *skip_conflicts,
+            )?;
+            match summary.commit {
+                Some(commit) => info!(
+                    "Reverted changeset {} as {}: {} objects restored, {} deleted and {} undeleted",
+                    id, commit, summary.restored, summary.deleted, summary.undeleted
+                ),
+                None => info!("Changeset {} left nothing to revert", id),
+            }
+            Ok(())
+        }
+        #[cfg(feature = "http")]
+        Commands::Upload {
+            base,
+            comment,
+            api,
+            oauth2_token_file,
+            merge_tags,
+            dry_run,
+        } => {
+            upload(
+                &cli,
+                base.as_deref(),
+                comment.as_deref(),
+                api.as_deref(),
+                oauth2_token_file.as_deref(),
+                *merge_tags,
+                *dry_run,
+            )
+            .await
+        }
+        Commands::Migrate { to_format } => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            recover_staging(&repository)?;
+            let author = cli.committer()?;
+            match to_format {
+                Some(object_format) => {
+                    convert_object_format(
+                        &repository,
+                        &author,
+                        cli.id_mapper().as_ref(),
+                        cli.shard_budget(),
+                        *object_format,
+                    )?;
+                }
+                None => {
+                    migrate(&repository, &author, cli.shard_budget())?;
+                }
+            }
+            Ok(())
+        }
+        Commands::MergeArchives { archives } => {
+            if std::path::Path::new(&cli.git_repo_path).exists() {
+                return Err(eyre!(
+                    "{} already exists. Archives can only be merged into a new git repo",
+                    cli.git_repo_path
+                ));
+            }
+            let repository = Repository::init(&cli.git_repo_path)?;
+            let author = cli.committer()?;
+            let summary = merge_archives(&repository, archives, &author, &cli.notes_ref)?;
+            info!(
+                "Merged {} archives into {} commits ({} duplicate commits dropped, {} objects changed in more than one archive)",
+                archives.len(),
+                summary.commits,
+                summary.deduplicated_commits,
+                summary.overlapping_objects
+            );
+            Ok(())
+        }
+        #[cfg(feature = "notifications")]
+        Commands::Digest {
+            digest_config,
+            period,
+        } => {
+            let repository = Repository::open(&cli.git_repo_path)?;
+            let config = DigestConfig::load(digest_config)?;
+            let digest = Digest::collect(&repository, &config, *period)?;
+            digest.deliver(&config).await
+        }
+    }
+}
+
+/// Push the published commits of a replay, recording the push in the benchmark
+///
+/// A failed push is retried at the next push, as the replay must keep going while the
+/// remote is unreachable.
+#[cfg(feature = "http")]
+async fn push_replay(cli: &Cli, pusher: &mut Pusher, benchmark: &mut Option<&mut FollowBenchmark>) {
+    let pushed = tokio::task::block_in_place(|| {
+        let repository = Repository::open(&cli.git_repo_path)?;
+        pusher.push(&repository)
+    });
+    match pushed {
+        Ok(()) => {
+            if let Some(benchmark) = benchmark {
+                benchmark.record_push(OffsetDateTime::now_utc());
+            }
+        }
+        Err(err) => warn!("Pushing failed, retrying with the next push: {}", err),
+    }
+}
+
+/// Upload the local edits after the upload marker, or `base`, as a changeset
+#[cfg(feature = "http")]
+async fn upload(
+    cli: &Cli,
+    base: Option<&str>,
+    comment: Option<&str>,
+    api: Option<&str>,
+    oauth2_token_file: Option<&str>,
+    merge_tags: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let repository = Repository::open(&cli.git_repo_path)?;
+    recover_staging(&repository)?;
+    let id_mapper = IndirectMapping::load(&repository, cli.id_mapper())?;
+    let base = match base {
+        Some(base) => resolve_commit(&repository, Some(base))?,
+        None => repository
+            .find_reference(UPLOAD_MARKER_REF)
+            .and_then(|reference| reference.peel_to_commit())
+            .map_err(|_| {
+                eyre!(
+                    "Nothing was uploaded yet. Pass the commit the local edits start after with --base"
+                )
+            })?,
+    };
+    let mut plan = plan_upload(&repository, &base, id_mapper.as_ref(), cli.shard_budget())?;
+    if plan.is_empty() {
+        info!("No edits after {} to upload", base.id());
+        return Ok(());
+    }
+    if dry_run {
+        print!("{}", plan.osmchange(0)?);
+        return Ok(());
+    }
+    let comment = comment
+        .map(|comment| comment.to_string())
+        .unwrap_or_else(|| plan.default_comment());
+    if comment.trim().is_empty() {
+        return Err(eyre!(
+            "The changeset needs a comment, pass one with --comment"
+        ));
+    }
+    let token = match oauth2_token_file {
+        Some(path) => std::fs::read_to_string(path)?,
+        None => std::env::var("OSM_OAUTH2_TOKEN").map_err(|_| {
+            eyre!("Pass an OAuth2 access token in OSM_OAUTH2_TOKEN or with --oauth2-token-file")
+        })?,
+    };
+    let api = OsmApi::new(
+        api.unwrap_or(cli.profile.settings().changeset_api),
+        &token,
+        DEFAULT_USER_AGENT,
+    )?;
+
+    let conflicts = plan
+        .check_conflicts(&api, id_mapper.as_ref(), merge_tags)
+        .await?;
+    if !conflicts.is_empty() {
+        for conflict in &conflicts {
+            warn!("{}", conflict);
+        }
+        return Err(eyre!(
+            "{} objects were changed upstream since the base commit. Replay the upstream changes and rebase the edits{}",
+            conflicts.len(),
+            if merge_tags { "" } else { ", or try --merge-tags" }
+        ));
+    }
+
+    let changeset_id = api.open_changeset(&comment).await?;
+    info!(
+        "Opened changeset {} for {} edited objects",
+        changeset_id,
+        plan.len()
+    );
+    let results = api
+        .upload(changeset_id, plan.osmchange(changeset_id)?)
+        .await;
+    // Close the changeset even if the upload failed, as open changesets block the mapper
+    api.close_changeset(changeset_id).await?;
+    let results = results?;
+    record_upload(
+        &repository,
+        &plan,
+        changeset_id,
+        &results,
+        &cli.committer()?,
+        id_mapper.as_ref(),
+        cli.shard_budget(),
+    )?;
+    info!(
+        "Uploaded {} objects in changeset {}",
+        results.len(),
+        changeset_id
+    );
+    Ok(())
+}
+
+/// Ask for the settings of a replay, check them and write them to a config file, then
+/// create the git repo
+#[cfg(feature = "http")]
+async fn init(cli: &Cli, prompt: &Prompt, output: &str, mut replay: ReplayArgs) -> Result<()> {
+    if std::path::Path::new(output).exists()
+        && !prompt.confirm(&format!("{} exists. Overwrite it?", output), false)?
+    {
+        return Err(eyre!("Not overwriting the config file {}", output));
+    }
+
+    prompt.section("Source");
+    let primary = prompt.ask(
+        "Replication server",
+        replay.primary_replication_server(),
+        |answer| {
+            if !answer.starts_with("https://") && !answer.starts_with("http://") {
+                return Err(eyre!("The replication server must be an http(s) URL"));
+            }
+            Ok(answer.trim_end_matches('/').to_string())
+        },
+    )?;
+    // Mirrors given with further --replication-server options are kept
+    replay.replication_server = std::iter::once(primary)
+        .chain(replay.replication_server.iter().skip(1).cloned())
+        .collect();
+    let default_interval = replay
+        .replication_interval
+        .to_possible_value()
+        .expect("Interval modes aren't skipped");
+    replay.replication_interval = prompt.ask(
+        "Replication interval (day, hour, minute or auto)",
+        default_interval.get_name(),
+        |answer| IntervalMode::from_str(answer, true).map_err(|err| eyre!(err)),
+    )?;
+
+    prompt.section("Area");
+    replay.admin_areas = prompt.ask_optional(
+        "GeoJSON file with named boundaries to tag commits with",
+        replay.admin_areas.as_deref(),
+        |path| {
+            AdminAreas::load(path)
+                .map(|_| ())
+                .map_err(|err| eyre!("Can't load {}: {}", path, err))
+        },
+    )?;
+
+    prompt.section("Layout");
+    let git_repo_path = prompt.ask_string("Git repo path", &cli.git_repo_path)?;
+    let cache_path = prompt.ask_string("Cache path", &cli.cache_path)?;
+    let fan_out_depth = prompt.ask(
+        "Directory levels to shard object files",
+        &cli.fan_out_depth.to_string(),
+        |answer| Ok(answer.parse::<u8>()?),
+    )?;
+    let object_format = prompt.ask(
+        "Format of the object files (yaml, json, toml, cbor or compact)",
+        &cli.object_format.unwrap_or_default().to_string(),
+        |answer| ObjectFormat::from_str(answer, true).map_err(|err| eyre!(err)),
+    )?;
+    let bare = prompt.confirm(
+        "Create a bare repo without a working directory",
+        replay.bare.unwrap_or(cli.profile.settings().bare),
+    )?;
+    replay.bare = Some(bare);
+
+    prompt.section("Identity");
+    replay.polite = prompt.confirm(
+        "Follow the usage policies of the OSM servers (--polite)",
+        replay.polite,
+    )?;
+    let polite = replay.polite;
+    let user_agent = prompt.ask(
+        "User agent with contact information, like \"my-mirror/1.0 (admin@example.com)\"",
+        replay.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT),
+        |answer| {
+            if polite {
+                validate_user_agent(answer)?;
+            }
+            Ok(answer.to_string())
+        },
+    )?;
+    replay.user_agent = (user_agent != DEFAULT_USER_AGENT).then(|| user_agent.clone());
+    let committer_name =
+        prompt.ask_string("Name the commits are committed by", &cli.committer_name)?;
+    let committer_email =
+        prompt.ask_string("Email the commits are committed by", &cli.committer_email)?;
+    let default_policy = cli
+        .identity_policy
+        .to_possible_value()
+        .expect("Identity policies aren't skipped");
+    let identity_policy = prompt.ask(
+        "Commit author (mapper, or bot to only name mappers in the trailers)",
+        default_policy.get_name(),
+        |answer| IdentityPolicy::from_str(answer, true).map_err(|err| eyre!(err)),
+    )?;
+
+    prompt.section("Schedule");
+    replay.follow = prompt.confirm(
+        "Keep running and apply new replication files as they are published",
+        replay.follow,
+    )?;
+    if replay.follow {
+        replay.poll_interval = prompt.ask(
+            "Seconds between checks for new replication files",
+            &replay.poll_interval.to_string(),
+            |answer| Ok(answer.parse::<u64>()?),
+        )?;
+    }
+    let default_off_peak = replay
+        .off_peak
+        .as_deref()
+        .or(replay.polite.then_some(DEFAULT_OFF_PEAK));
+    replay.off_peak = prompt.ask_optional(
+        "Daily download window in UTC, like 00:00-06:00",
+        default_off_peak,
+        |window| OffPeakWindow::parse(window).map(|_| ()),
+    )?;
+
+    prompt.section("Checks");
+    let downloader = Downloader {
+        client: reqwest::Client::builder()
+            .user_agent(&user_agent)
+            .gzip(true)
+            .timeout(Duration::from_secs(60))
+            .build()?,
+        max_attempts: replay.max_attempts.max(1),
+        cassette: replay.cassette(),
+    };
+    let interval = replay.replication_interval.fixed().unwrap_or(Interval::Day);
+    let source = replay.replication_source(&downloader)?;
+    let feed_url = interval.feed_url(source.root());
+    let latest = source
+        .latest_state(interval)
+        .await
+        .map_err(|err| eyre!("Can't reach the replication server {}: {}", feed_url, err))?;
+    info!(
+        "The replication server {} is reachable, its newest sequence is {} from {}",
+        feed_url,
+        latest.sequence_number,
+        latest.timestamp.format(&Rfc3339)?
+    );
+    for path in [&git_repo_path, &cache_path] {
+        let free = free_space(path)?;
+        info!("{} has {} MiB of free space", path, free / 1024 / 1024);
+        if free < RECOMMENDED_FREE_SPACE {
+            let question = format!(
+                "{} has less than the recommended {} GiB of free space. Continue anyway",
+                path,
+                RECOMMENDED_FREE_SPACE / 1024 / 1024 / 1024
+            );
+            if prompt.is_interactive() && !prompt.confirm(&question, false)? {
+                return Err(eyre!("The setup was aborted"));
+            }
+            warn!("{}", question.trim_end_matches(". Continue anyway"));
+        }
+    }
+
+    let mut config = serde_yaml::to_value(cli)?;
+    let mut replay_config = serde_yaml::to_value(&replay)?;
+    // A replay started from the config file resumes after the last applied sequence
+    if let Some(replay_config) = replay_config.as_mapping_mut() {
+        replay_config.remove("clean");
+        replay_config.remove("start_data");
+        replay_config.remove("start_date");
+    }
+    if let (Some(config), Value::Mapping(replay_config)) = (config.as_mapping_mut(), replay_config)
+    {
+        config.insert("git_repo_path".into(), git_repo_path.clone().into());
+        config.insert("cache_path".into(), cache_path.clone().into());
+        config.insert("fan_out_depth".into(), fan_out_depth.into());
+        config.insert("object_format".into(), serde_yaml::to_value(object_format)?);
+        config.insert("committer_name".into(), committer_name.clone().into());
+        config.insert("committer_email".into(), committer_email.clone().into());
+        config.insert(
+            "identity_policy".into(),
+            serde_yaml::to_value(identity_policy)?,
+        );
+        config.extend(replay_config);
+    }
+    std::fs::write(output, serde_yaml::to_string(&config)?)?;
+    info!("Wrote the config file {}", output);
+
+    std::fs::create_dir_all(&cache_path)?;
+    init_git_repository(
+        &git_repo_path,
+        replay.primary_replication_server(),
+        &Signature::now(&committer_name, &committer_email)?,
+        &LayoutMetadata {
+            fan_out_depth,
+            object_format,
+        },
+        bare,
+    )?;
+    info!(
+        "Initialized the git repo at {}. Start the replay with `osm-git --config {} replay`",
+        git_repo_path, output
+    );
+    Ok(())
+}
+
+/// Import a PBF file into a git repo which has no replicated data yet
+#[cfg(feature = "pbf")]
+fn bootstrap(
+    cli: &Cli,
+    pbf: &str,
+    objects_per_commit: usize,
+    state_store: StateStoreKind,
+    bare: bool,
+) -> Result<()> {
+    let file = std::io::BufReader::new(std::fs::File::open(pbf)?);
+    let mut reader = PbfReader::new(file)?;
+    let header = reader.header().clone();
+    info!(
+        "Bootstrapping from {} written by {}",
+        pbf,
+        header
+            .writing_program
+            .as_deref()
+            .unwrap_or("an unknown program")
+    );
 
+    let author = cli.committer()?;
+    let repository = init_git_repository(
+        &cli.git_repo_path,
+        header
+            .replication_base_url
+            .as_deref()
+            .unwrap_or("https://planet.openstreetmap.org/replication/day"),
+        &author,
+        &cli.layout_metadata(),
+        bare,
+    )?;
+    recover_staging(&repository)?;
+    if let Some(sequence) = read_state(&repository)? {
+        return Err(eyre!(
+            "The git repo already has data up to sequence {}. Only new git repos can be bootstrapped",
+            sequence
+        ));
+    }
+
+    let layout = Layout::load(&repository, cli.shard_budget())?;
+    layout.check_object_format(cli.object_format)?;
+    let id_mapper = IndirectMapping::load(&repository, cli.id_mapper())?;
+    let state_store = state_store.open(&repository, layout.object_format)?;
+    let import = if header.historical {
+        info!("The PBF file has the full history. Committing every changeset");
+        import_history
+    } else {
+        bootstrap_from_pbf
+    };
+    let summary = import(
+        &repository,
+        &author,
+        &mut reader,
+        &BootstrapSettings {
+            source_name: std::path::Path::new(pbf)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or(pbf),
+            id_mapper: id_mapper.as_ref(),
+            layout: &layout,
+            state_store: state_store.as_ref(),
+            changesets_location: &cli.changeset_location(),
+            objects_per_commit: objects_per_commit.max(1),
+            identity_policy: cli.identity_policy,
+            authors: &cli.authors(),
+        },
+    )?;
+    info!(
+        "Imported {} objects in {} commits",
+        summary.objects, summary.commits
+    );
+    match summary.sequence {
+        Some(sequence) => info!(
+            "A replay of {} continues after sequence {}",
+            header.replication_base_url.unwrap_or_default(),
+            sequence
+        ),
+        None => {
+            warn!("The PBF file names no replication sequence. Pass --start-data to the replay")
+        }
+    }
+    Ok(())
+}
+
+/// Replay the replication files to the git repo until the stream ends
+#[cfg(feature = "http")]
+async fn replay_to_git(
+    cli: &Cli,
+    mut replay: ReplayArgs,
+    mut benchmark: Option<&mut FollowBenchmark>,
+) -> Result<()> {
     info!(
         "Starting to replay osm changesets to git repo at {}",
         cli.git_repo_path
     );
 
-    let client = reqwest::Client::builder()
-        .user_agent("osm-git-replay/0.1.0")
+    let user_agent = replay
+        .user_agent
+        .clone()
+        .unwrap_or_else(|| DEFAULT_USER_AGENT.to_string());
+    let mut client = reqwest::Client::builder()
+        .user_agent(&user_agent)
         .gzip(true)
-        .timeout(Duration::from_secs(60))
-        .build()?;
+        .timeout(Duration::from_secs(60));
+    if replay.polite {
+        validate_user_agent(&user_agent)?;
+        client = client.pool_max_idle_per_host(1);
+        apply_polite_limits(&mut replay);
+        info!(
+            "Replaying politely as {:?} with one connection, downloading in the off-peak window {}",
+            user_agent,
+            replay.off_peak.as_deref().unwrap_or_default()
+        );
+    }
+    let client = client.build()?;
+    let downloader = Downloader {
+        client: client.clone(),
+        max_attempts: replay.max_attempts.max(1),
+        cassette: replay.cassette(),
+    };
+    let (settings, mut watch_reporter) = runtime_settings(&replay, &client)?;
 
-    if cli.clean {
+    if replay.clean {
         info!("Cleaning git repo at {}", cli.git_repo_path);
         if std::path::Path::new(&cli.git_repo_path).exists() {
             std::fs::remove_dir_all(&cli.git_repo_path)?;
         }
     }
 
-    let author = Signature::now("osm-git-replay", "osm-git-replay@localhost")?;
+    let RuntimeSettings {
+        wait_time,
+        follow,
+        tuning,
+        off_peak,
+        watchlist,
+    } = settings;
+    let sink = git_sink(cli, &replay, &downloader, watchlist)?;
+    let mut provenance = start_provenance(cli, &replay, &sink)?;
 
-    let repository = init_git_repository(&cli.git_repo_path, &cli.replication_server, &author)?;
-    info!("Git repository initialized");
+    let control = Arc::new(ReplayControl::default());
+    let source = ReplayOptions {
+        source: replay.replication_source(&downloader)?,
+        downloader,
+        interval: replay.replication_interval,
+        tuning,
+        cache_path: cli.cache_path.clone(),
+        max_cache_size: replay.max_cache_size.map(|size| size * 1024 * 1024),
+        start_data: replay.start_data,
+        start_date: replay
+            .start_date
+            .as_deref()
+            .map(parse_timestamp)
+            .transpose()?,
+        end_sequence: replay.end_sequence,
+        end_date: replay
+            .end_date
+            .as_deref()
+            .map(parse_timestamp)
+            .transpose()?,
+        wait_time,
+        follow,
+        changeset_stream: replay.changeset_stream.clone(),
+        changeset_retention: Duration::from_secs(replay.changeset_retention_days * 24 * 60 * 60),
+        changeset_lifecycle: replay.changeset_lifecycle,
+        off_peak,
+        control: control.clone(),
+    };
 
-    // Data download metadata
-    // TODO: We should probably detect where to resume from
-    let mut data_position_top = cli.start_data[0..3].parse::<u16>()?;
-    let mut data_position_middle = cli.start_data[4..7].parse::<u16>()?;
-    let mut data_position_bottom = cli.start_data[8..11].parse::<u16>()?;
+    // SIGHUP and the control socket reload the settings without restarting the replay
+    let (reload_sender, mut reload_requests) = mpsc::channel(1);
+    if let Some(socket_path) = &cli.control_socket {
+        let listener = bind_control_socket(socket_path).await?;
+        let control = control.clone();
+        let reload_sender = reload_sender.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_control_socket(listener, control, reload_sender).await {
+                error!("The control socket stopped: {}", err);
+            }
+        });
+    }
+    let mut hangups = signal(SignalKind::hangup())?;
+    // SIGINT and SIGTERM let the replay finish the replication file it is applying
+    let mut interrupts = signal(SignalKind::interrupt())?;
+    let mut terminations = signal(SignalKind::terminate())?;
 
-    // Parse the changesets and convert them to git objects
+    let mut pusher = replay
+        .push_remote
+        .as_deref()
+        .map(|remote| Pusher::new(remote, replay.push_every.unwrap_or_default()));
+    let events = Replayer::new(sink, source).into_stream();
+    tokio::pin!(events);
+    notify("READY=1")?;
+    // The service manager is pinged independently of the events, as a replication file
+    // may be applied for longer than the watchdog timeout without reporting anything. A
+    // paused replay is halted on purpose and must not be restarted either
+    let mut watchdog_ticks = tokio::time::interval(Duration::from_secs(10));
     loop {
-        // Check for cache and use it if it exists
-        let cache_file_path = format!(
-            "{}/replication/{:03}/{:03}/{:03}.osm.gz",
-            cli.cache_path, data_position_top, data_position_middle, data_position_bottom
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    break;
+                };
+                let event = event?;
+                log_event(&event);
+                if let Some(benchmark) = &mut benchmark {
+                    benchmark.record(&event, OffsetDateTime::now_utc());
+                }
+                if let Some(pusher) = &mut pusher {
+                    if pusher.record(&event) {
+                        push_replay(cli, pusher, &mut benchmark).await;
+                    }
+                }
+                if let Some(benchmark) = &benchmark {
+                    if benchmark.is_complete() && !control.shutdown_requested() {
+                        info!("Measured {} sequences, finishing the benchmark", benchmark.samples());
+                        control.request_shutdown();
+                    }
+                }
+                match &event {
+                    ReplayEvent::WatchMatched(watch_match) => {
+                        if let Some(watch_reporter) = &watch_reporter {
+                            watch_reporter.report(watch_match).await?;
+                        }
+                    }
+                    ReplayEvent::CommitCreated { .. } => {
+                        control.update_status(|status| status.commits_created += 1)
+                    }
+                    ReplayEvent::SequenceApplied { sequence, sha256, .. } => {
+                        control.update_status(|status| {
+                            status.last_sequence = Some(sequence.clone());
+                            status.sequences_applied += 1;
+                        });
+                        if let Some(provenance) = &mut provenance {
+                            provenance.record_input(InputKind::ReplicationFile, sequence, sha256);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ = hangups.recv() => reload_replay_settings(&replay, &client, &control, &mut watch_reporter),
+            Some(()) = reload_requests.recv() => {
+                reload_replay_settings(&replay, &client, &control, &mut watch_reporter)
+            }
+            _ = watchdog_ticks.tick() => notify("WATCHDOG=1")?,
+            _ = interrupts.recv() => request_shutdown(&control)?,
+            _ = terminations.recv() => request_shutdown(&control)?,
+        }
+    }
+    drop(reload_sender);
+    if let Some(socket_path) = &cli.control_socket {
+        std::fs::remove_file(socket_path)?;
+    }
+    if control.shutdown_requested() {
+        info!("Stopped cleanly. The next replay resumes after the last applied sequence");
+    }
+    if replay.update_mailmap {
+        update_replay_mailmap(cli)?;
+    }
+    finish_provenance(cli, &replay, provenance)?;
+    // Publish what the run committed since the last push, including the manifest
+    if let Some(pusher) = &mut pusher {
+        if pusher.is_behind(&Repository::open(&cli.git_repo_path)?)? {
+            push_replay(cli, pusher, &mut benchmark).await;
+        }
+    }
+
+    let missing = read_missing_metadata(&Repository::open(&cli.git_repo_path)?)?;
+    if !missing.is_empty() {
+        warn!(
+            "{} applied changesets lack metadata: {:?}. Run `osm-git annotate-missing` once a newer changeset dump is available",
+            missing.len(),
+            missing.iter().map(|(id, _)| *id).collect::<Vec<u64>>()
         );
+    }
 
-        if std::path::Path::new(&cache_file_path).exists() {
-            info!("Using cached data file at {}", cache_file_path);
-            let file = File::open(&cache_file_path)?;
-            let data = unsafe { Mmap::map(&file)? };
-            let changeset_location = format!("{}/changesets/torrents", cli.cache_path);
-            convert_objects_to_git(&repository, &author, &data, &changeset_location)?;
-            info!("Data file parsed");
+    Ok(())
+}
 
-            // Increment the data position
-            if data_position_top == 999
-                && data_position_middle == 999
-                && data_position_bottom == 999
-            {
-                // Uhhhhhh?!
-                break;
-            }
+/// Apply local osc files with the commit options of the replay
+#[cfg(feature = "http")]
+async fn import_osc(cli: &Cli, source: &LocalSource, replay: &ReplayArgs) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .user_agent(replay.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT))
+        .gzip(true)
+        .timeout(Duration::from_secs(60))
+        .build()?;
+    let downloader = Downloader {
+        client: client.clone(),
+        max_attempts: replay.max_attempts.max(1),
+        cassette: replay.cassette(),
+    };
+    let (settings, watch_reporter) = runtime_settings(replay, &client)?;
+    let sink = git_sink(cli, replay, &downloader, settings.watchlist)?;
+    let mut provenance = start_provenance(cli, replay, &sink)?;
 
-            if data_position_middle == 999 && data_position_bottom == 999 {
-                data_position_middle = 0;
-                data_position_bottom = 0;
-                data_position_top += 1;
+    let mut watch_matches = Vec::new();
+    let summary = import_local_source(&sink, source, &mut |event| {
+        log_event(&event);
+        match event {
+            ReplayEvent::WatchMatched(watch_match) => watch_matches.push(watch_match),
+            ReplayEvent::SequenceApplied {
+                sequence, sha256, ..
+            } => {
+                if let Some(provenance) = &mut provenance {
+                    provenance.record_input(InputKind::ReplicationFile, &sequence, &sha256);
+                }
             }
+            _ => {}
+        }
+    })?;
+    if replay.update_mailmap {
+        update_replay_mailmap(cli)?;
+    }
+    finish_provenance(cli, replay, provenance)?;
+    if let Some(remote) = &replay.push_remote {
+        push_to_remote(&Repository::open(&cli.git_repo_path)?, remote)?;
+    }
+    if let Some(watch_reporter) = &watch_reporter {
+        for watch_match in &watch_matches {
+            watch_reporter.report(watch_match).await?;
+        }
+    }
+    info!(
+        "Imported {} osc files, dropping {} object versions which were in earlier files",
+        summary.files, summary.duplicates
+    );
+    Ok(())
+}
 
-            if data_position_bottom == 999 {
-                data_position_bottom = 0;
-                data_position_middle += 1;
-            }
-        } else {
-            {
-                // Download minute replication files and find the changesets that were modified in that minute
-                let data_url = format!(
-                    "{}/{:03}/{:03}/{:03}.osc.gz",
-                    cli.replication_server,
-                    data_position_top,
-                    data_position_middle,
-                    data_position_bottom
-                );
-                info!("Downloading data file from {}", data_url);
-                let data_response: reqwest::Response = client.get(&data_url).send().await?;
-
-                if data_response.status() == reqwest::StatusCode::NOT_FOUND {
-                    warn!("data file not found at {}", data_url);
-                    // Increment the data position
-                    if data_position_top == 999
-                        && data_position_middle == 999
-                        && data_position_bottom == 999
-                    {
-                        // Uhhhhhh?!
-                        break;
-                    }
+/// Start recording the provenance manifest of a run, if enabled
+#[cfg(feature = "http")]
+fn start_provenance(
+    cli: &Cli,
+    replay: &ReplayArgs,
+    sink: &GitSink,
+) -> Result<Option<ProvenanceRecorder>> {
+    if !replay.provenance {
+        return Ok(None);
+    }
+    let config = serde_yaml::to_string(&cli.effective_config()?)?;
+    Ok(Some(ProvenanceRecorder::start(
+        &sink.repository,
+        config.as_bytes(),
+    )?))
+}
 
-                    if data_position_middle == 999 && data_position_bottom == 999 {
-                        data_position_middle = 0;
-                        data_position_bottom = 0;
-                        data_position_top += 1;
-                    }
+/// Update the mailmap with the mappers of the commits of the run
+#[cfg(feature = "http")]
+fn update_replay_mailmap(cli: &Cli) -> Result<()> {
+    let repository = Repository::open(&cli.git_repo_path)?;
+    if let Some(commit) = update_mailmap(
+        &repository,
+        &cli.committer()?,
+        &cli.author_email_domain,
+        &cli.mailmap_index_path(),
+    )? {
+        info!("Committed the updated mailmap as {}", commit);
+    }
+    Ok(())
+}
 
-                    if data_position_bottom == 999 {
-                        data_position_bottom = 0;
-                        data_position_middle += 1;
-                    }
+/// Commit the provenance manifest of a run, with the changeset dump it read
+#[cfg(feature = "http")]
+fn finish_provenance(
+    cli: &Cli,
+    replay: &ReplayArgs,
+    provenance: Option<ProvenanceRecorder>,
+) -> Result<()> {
+    let Some(mut provenance) = provenance else {
+        return Ok(());
+    };
+    let changeset_dump = find_latest_changeset_dump(&cli.changeset_location()).unwrap_or_default();
+    if !changeset_dump.is_empty() {
+        provenance.record_file(
+            InputKind::ChangesetDump,
+            std::path::Path::new(&changeset_dump),
+        )?;
+    }
+    let repository = Repository::open(&cli.git_repo_path)?;
+    match provenance.finish(
+        &repository,
+        &cli.committer()?,
+        replay.provenance_signing_key.as_ref(),
+    )? {
+        Some(path) => info!("Recorded the provenance of the run in {}", path),
+        None => info!("The run created no commits, so no provenance manifest was recorded"),
+    }
+    Ok(())
+}
 
-                    if data_position_bottom < 999 {
-                        data_position_bottom += 1;
-                    }
+/// Create or open the git repo and set up how the replay commits to it
+#[cfg(feature = "http")]
+fn git_sink(
+    cli: &Cli,
+    replay: &ReplayArgs,
+    downloader: &Downloader,
+    watchlist: Option<Watchlist>,
+) -> Result<GitSink> {
+    let author = cli.committer()?;
 
-                    continue;
-                }
+    let profile_settings = cli.profile.settings();
+    let write_changeset_notes = replay.notes.unwrap_or(profile_settings.write_notes);
+    let note_format = cli.note_format.unwrap_or(profile_settings.note_format);
+    let bare = replay.bare.unwrap_or(profile_settings.bare);
+    let state_store = replay.state_store.unwrap_or(profile_settings.state_store);
+    // A squash window asks for commits of changesets, whatever the profile
+    let granularity = match (replay.granularity, replay.squash_window) {
+        (Some(granularity), _) => granularity,
+        (None, Some(_)) => Granularity::Changeset,
+        (None, None) => profile_settings.granularity,
+    };
+    info!(
+        "Using the {:?} profile (notes: {}, note format: {:?}, bare: {}, state store: {:?}, granularity: {:?})",
+        cli.profile, write_changeset_notes, note_format, bare, state_store, granularity
+    );
 
-                let data = data_response.bytes().await?;
-                info!("Caching Data file to disk");
-                std::fs::create_dir_all(std::path::Path::new(&cache_file_path).parent().unwrap())?;
-                std::fs::write(&cache_file_path, &data)?;
-                info!("Data file downloaded");
-            };
+    let repository = init_git_repository(
+        &cli.git_repo_path,
+        replay.primary_replication_server(),
+        &author,
+        &cli.layout_metadata(),
+        bare,
+    )?;
+    info!("Git repository initialized");
+    recover_staging(&repository)?;
+    if write_changeset_notes {
+        prepare_notes_ref(&repository, &cli.notes_ref)?;
+    }
 
-            let file = File::open(cache_file_path)?;
-            let data = unsafe { Mmap::map(&file)? };
+    let layout = Layout::load(&repository, cli.shard_budget())?;
+    layout.check_object_format(cli.object_format)?;
+    let id_mapper = IndirectMapping::load(&repository, cli.id_mapper())?;
+    let state_store = state_store.open(&repository, layout.object_format)?;
+    Ok(GitSink {
+        repository,
+        author: Committer::new(&author)?,
+        identity_policy: cli.identity_policy,
+        authors: cli.authors(),
+        changeset_location: cli.changeset_location(),
+        id_mapper,
+        layout,
+        state_store,
+        write_changeset_notes,
+        note_format,
+        notes_ref: cli.notes_ref.clone(),
+        campaign_refs: replay.campaign_refs,
+        squash_window: replay.squash_window.map(|minutes| minutes * 60),
+        granularity,
+        tag_key_summaries: replay
+            .tag_key_summaries
+            .unwrap_or(profile_settings.tag_key_summaries),
+        area: match (&replay.bbox, &replay.poly) {
+            (Some(bbox), _) => Some(AreaFilter::from_bbox(*bbox)),
+            (None, Some(poly)) => Some(AreaFilter::load_poly(poly)?),
+            (None, None) => None,
+        },
+        tag_filter: (!replay.filter.is_empty())
+            .then(|| TagFilter::parse(&replay.filter))
+            .transpose()?,
+        mappers: MapperFilter::new(&replay.user, &replay.uid, replay.exclude_bots),
+        admin_areas: replay
+            .admin_areas
+            .as_deref()
+            .map(AdminAreas::load)
+            .transpose()?,
+        watchlist,
+        #[cfg(feature = "http")]
+        changeset_api: (!replay.no_changeset_api).then(|| ChangesetApi {
+            downloader: downloader.clone(),
+            url: replay
+                .changeset_api
+                .clone()
+                .unwrap_or_else(|| profile_settings.changeset_api.to_string()),
+            daily_quota: replay.changeset_api_quota,
+        }),
+        serialize_workers: replay.serialize_workers,
+        commit_buffer_size: replay.commit_buffer_mb * 1024 * 1024,
+        parse_mode: replay.parse_mode(),
+    })
+}
 
-            let changeset_location = format!("{}/changesets/torrents", cli.cache_path);
-            convert_objects_to_git(&repository, &author, &data, &changeset_location)?;
+/// Ask the replay to stop after the current replication file
+///
+/// A second signal exits right away. The next replay recovers the staged commits of the
+/// interrupted replication file.
+#[cfg(feature = "http")]
+fn request_shutdown(control: &ReplayControl) -> Result<()> {
+    if control.shutdown_requested() {
+        warn!("Exiting without finishing the current replication file");
+        std::process::exit(130);
+    }
+    info!("Finishing the current replication file before exiting. Signal again to exit right away");
+    notify("STOPPING=1")?;
+    control.request_shutdown();
+    Ok(())
+}
 
-            // Increment the data position
-            if data_position_top == 999
-                && data_position_middle == 999
-                && data_position_bottom == 999
-            {
-                // Uhhhhhh?!
-                break;
-            }
+/// Enforce the limits of `--polite` on the replay options
+#[cfg(feature = "http")]
+fn apply_polite_limits(replay: &mut ReplayArgs) {
+    replay.min_prefetch = 0;
+    replay.max_prefetch = 0;
+    replay.wait_time = replay.wait_time.max(MIN_WAIT_TIME.as_millis() as u64);
+    replay.off_peak = replay
+        .off_peak
+        .take()
+        .or_else(|| Some(DEFAULT_OFF_PEAK.to_string()));
+}
 
-            if data_position_middle == 999 && data_position_bottom == 999 {
-                data_position_middle = 0;
-                data_position_bottom = 0;
-                data_position_top += 1;
-            }
+/// The settings of the replay options which can be changed while the replay runs
+#[cfg(feature = "http")]
+fn runtime_settings(
+    replay: &ReplayArgs,
+    #[cfg_attr(not(feature = "notifications"), allow(unused_variables))] client: &reqwest::Client,
+) -> Result<(RuntimeSettings, Option<WatchReporter>)> {
+    let (watchlist, watch_reporter) = match &replay.watchlist {
+        Some(path) => {
+            let config = WatchConfig::load(path)?;
+            #[cfg(feature = "notifications")]
+            let (watchlist, watch_reporter) = config.split(client.clone());
+            #[cfg(not(feature = "notifications"))]
+            let (watchlist, watch_reporter) = config.split();
+            (Some(watchlist), Some(watch_reporter))
+        }
+        None => (None, None),
+    };
+    let settings = RuntimeSettings {
+        wait_time: Duration::from_millis(replay.wait_time),
+        follow: replay
+            .follow
+            .then(|| Duration::from_secs(replay.poll_interval)),
+        tuning: TuningBounds {
+            min_prefetch: replay.min_prefetch,
+            max_prefetch: replay.max_prefetch.max(replay.min_prefetch),
+        },
+        off_peak: replay
+            .off_peak
+            .as_deref()
+            .map(OffPeakWindow::parse)
+            .transpose()?,
+        watchlist,
+    };
+    Ok((settings, watch_reporter))
+}
 
-            if data_position_bottom == 999 {
-                data_position_bottom = 0;
-                data_position_middle += 1;
+/// Re-read the config file and hand the new settings to the running replay
+///
+/// Only the settings in [`RuntimeSettings`] change. Other options like the user agent
+/// or the git repo need a restart. A config which can't be loaded is logged and the
+/// replay continues with the previous settings.
+#[cfg(feature = "http")]
+fn reload_replay_settings(
+    running: &ReplayArgs,
+    client: &reqwest::Client,
+    control: &ReplayControl,
+    watch_reporter: &mut Option<WatchReporter>,
+) {
+    info!("Reloading the settings");
+    let reloaded = reparse_cli().and_then(|cli| match cli.command {
+        Commands::Replay(mut replay) => {
+            if replay.polite != running.polite || replay.user_agent != running.user_agent {
+                warn!("Changing --polite or --user-agent needs a restart of the replay");
             }
-
-            if data_position_bottom < 999 {
-                data_position_bottom += 1;
+            if running.polite {
+                apply_polite_limits(&mut replay);
             }
-
-            // Wait a few seconds before downloading the next data file
-            tokio::time::sleep(Duration::from_millis(cli.wait_time)).await;
+            runtime_settings(&replay, client)
         }
+        _ => Err(eyre!("The command line is no replay")),
+    });
+    match reloaded {
+        Ok((settings, reporter)) => {
+            control.reload(settings);
+            *watch_reporter = reporter;
+        }
+        Err(err) => error!(
+            "Unable to reload the settings, keeping the previous ones: {}",
+            err
+        ),
     }
-
-    info!(
-        "Downloaded data until {} {} {}",
-        data_position_top,
-        data_position_middle,
-        data_position_bottom - 1
-    );
-
-    Ok(())
 }
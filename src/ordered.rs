@@ -0,0 +1,105 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+};
+
+use color_eyre::eyre::Result;
+
+/// An item which can be moved out of memory while it waits in an [`OrderedBuffer`]
+pub trait Spill: Sized {
+    /// The approximate number of bytes the item occupies in memory
+    fn size(&self) -> usize;
+    fn spill(&self, writer: &mut dyn Write) -> Result<()>;
+    fn unspill(reader: &mut dyn Read) -> Result<Self>;
+}
+
+enum Slot<T> {
+    Memory(T),
+    Disk(PathBuf),
+}
+
+/// Hands out items in the order of their index, whatever order they arrive in
+///
+/// Workers finish items in any order, but commits have to be created in the order of the
+/// changesets, so the history doesn't depend on the number of workers or how they were
+/// scheduled. Items which arrive before their turn wait in the buffer. Once the waiting
+/// items take more than `memory_limit` bytes, further items are written to files in
+/// `spill_dir` and read back when it is their turn. A single slow item then doesn't let
+/// the buffer grow without bound.
+pub struct OrderedBuffer<T> {
+    /// The index of the item handed out next
+    next: usize,
+    waiting: BTreeMap<usize, Slot<T>>,
+    memory_used: usize,
+    memory_limit: usize,
+    spill_dir: PathBuf,
+    spilled: usize,
+}
+
+impl<T: Spill> OrderedBuffer<T> {
+    pub fn new(spill_dir: PathBuf, memory_limit: usize) -> Self {
+        OrderedBuffer {
+            next: 0,
+            waiting: BTreeMap::new(),
+            memory_used: 0,
+            memory_limit,
+            spill_dir,
+            spilled: 0,
+        }
+    }
+
+    /// Add the item with the given index. Every index is pushed once
+    pub fn push(&mut self, index: usize, item: T) -> Result<()> {
+        let size = item.size();
+        // The item in line is handed out right away, so spilling it would be wasted
+        let slot = if index != self.next && self.memory_used + size > self.memory_limit {
+            std::fs::create_dir_all(&self.spill_dir)?;
+            let path = self.spill_dir.join(index.to_string());
+            let mut writer = BufWriter::new(File::create(&path)?);
+            item.spill(&mut writer)?;
+            writer.flush()?;
+            self.spilled += 1;
+            Slot::Disk(path)
+        } else {
+            self.memory_used += size;
+            Slot::Memory(item)
+        };
+        self.waiting.insert(index, slot);
+        Ok(())
+    }
+
+    /// Take the next item in line, if it arrived
+    pub fn pop(&mut self) -> Result<Option<T>> {
+        let Some(slot) = self.waiting.remove(&self.next) else {
+            return Ok(None);
+        };
+        self.next += 1;
+        match slot {
+            Slot::Memory(item) => {
+                self.memory_used -= item.size();
+                Ok(Some(item))
+            }
+            Slot::Disk(path) => {
+                let item = T::unspill(&mut BufReader::new(File::open(&path)?))?;
+                std::fs::remove_file(path)?;
+                Ok(Some(item))
+            }
+        }
+    }
+
+    /// How many items were written to disk
+    pub fn spilled(&self) -> usize {
+        self.spilled
+    }
+}
+
+impl<T> Drop for OrderedBuffer<T> {
+    fn drop(&mut self) {
+        // Items left behind by an error are of no use to the next run
+        if self.spilled > 0 {
+            let _ = std::fs::remove_dir_all(&self.spill_dir);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::{BTreeSet, HashMap};
+
+use color_eyre::eyre::{eyre, Result};
+use serde::Deserialize;
+use serde_yaml::Value;
+use tracing::info;
+
+use super::{
+    changesets::{BBox, Changeset},
+    geometry::{ring_contains, Position},
+};
+
+/// The trailer key listing the admin areas a commit touches
+pub const ADMIN_AREA_TRAILER: &str = "Admin-Area";
+
+#[derive(Debug, Deserialize)]
+struct FeatureCollection {
+    features: Vec<Feature>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Feature {
+    #[serde(default)]
+    properties: HashMap<String, Value>,
+    geometry: BoundaryGeometry,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "coordinates")]
+enum BoundaryGeometry {
+    Polygon(Vec<Vec<Position>>),
+    MultiPolygon(Vec<Vec<Vec<Position>>>),
+}
+
+/// A named administrative area like a country or region
+#[derive(Debug)]
+struct AdminArea {
+    name: String,
+    bbox: BBox,
+    /// The outer rings of the polygons of the area
+    rings: Vec<Vec<Position>>,
+}
+
+/// Administrative areas to spatially join changesets with
+#[derive(Debug)]
+pub struct AdminAreas {
+    areas: Vec<AdminArea>,
+}
+
+impl AdminAreas {
+    /// Load the areas from a GeoJSON feature collection
+    ///
+    /// Every feature needs a `name` property and a `Polygon` or `MultiPolygon` geometry.
+    pub fn load(path: &str) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        // GeoJSON is valid YAML
+        let collection: FeatureCollection = serde_yaml::from_reader(file)?;
+
+        let mut areas = Vec::new();
+        for (index, feature) in collection.features.into_iter().enumerate() {
+            let name = feature
+                .properties
+                .get("name")
+                .and_then(Value::as_str)
+                .ok_or_else(|| eyre!("Feature {} in {} has no name", index, path))?
+                .to_string();
+            let rings: Vec<Vec<Position>> = match feature.geometry {
+                BoundaryGeometry::Polygon(polygon) => polygon.into_iter().take(1).collect(),
+                BoundaryGeometry::MultiPolygon(polygons) => polygons
+                    .into_iter()
+                    .filter_map(|polygon| polygon.into_iter().next())
+                    .collect(),
+            };
+            let positions = rings.iter().flatten();
+            let bbox = BBox {
+                min_lat: positions.clone().map(|p| p[1]).fold(f64::MAX, f64::min),
+                min_lon: positions.clone().map(|p| p[0]).fold(f64::MAX, f64::min),
+                max_lat: positions.clone().map(|p| p[1]).fold(f64::MIN, f64::max),
+                max_lon: positions.map(|p| p[0]).fold(f64::MIN, f64::max),
+            };
+            areas.push(AdminArea { name, bbox, rings });
+        }
+        info!("Loaded {} admin areas from {}", areas.len(), path);
+        Ok(AdminAreas { areas })
+    }
+
+    /// The names of the areas the bounding boxes of the changesets intersect, sorted
+    ///
+    /// Changesets without a bounding box are not in any area. Holes of the areas are
+    /// ignored, so a bounding box inside an enclave still counts for the surrounding area.
+    pub fn intersecting(&self, changesets: &[&Changeset]) -> Vec<&str> {
+        let mut names = BTreeSet::new();
+        for bbox in changesets.iter().filter_map(|changeset| changeset.bbox()) {
+            for area in &self.areas {
+                if area.bbox.intersects(&bbox)
+                    && area.rings.iter().any(|ring| ring_intersects(ring, &bbox))
+                {
+                    names.insert(area.name.as_str());
+                }
+            }
+        }
+        names.into_iter().collect()
+    }
+}
+
+/// Check if a ring and a bounding box share any point
+fn ring_intersects(ring: &[Position], bbox: &BBox) -> bool {
+    let inside_bbox = |[lon, lat]: Position| {
+        bbox.min_lon <= lon && lon <= bbox.max_lon && bbox.min_lat <= lat && lat <= bbox.max_lat
+    };
+    let corners = [
+        [bbox.min_lon, bbox.min_lat],
+        [bbox.max_lon, bbox.min_lat],
+        [bbox.max_lon, bbox.max_lat],
+        [bbox.min_lon, bbox.max_lat],
+    ];
+    if ring.iter().any(|position| inside_bbox(*position))
+        || corners.iter().any(|corner| ring_contains(ring, *corner))
+    {
+        return true;
+    }
+
+    // An edge of the ring can still cross the bounding box without a vertex inside it
+    let bbox_edges = corners.iter().zip(corners.iter().cycle().skip(1));
+    ring.windows(2).any(|edge| {
+        bbox_edges
+            .clone()
+            .any(|(a, b)| segments_intersect(edge[0], edge[1], *a, *b))
+    })
+}
+
+fn segments_intersect(a: Position, b: Position, c: Position, d: Position) -> bool {
+    let orientation = |p: Position, q: Position, r: Position| {
+        ((q[0] - p[0]) * (r[1] - p[1]) - (q[1] - p[1]) * (r[0] - p[0])).signum()
+    };
+    orientation(a, b, c) != orientation(a, b, d) && orientation(c, d, a) != orientation(c, d, b)
+}
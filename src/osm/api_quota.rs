@@ -0,0 +1,143 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// The file in the API cache folder recording the requests made and the backoff
+const QUOTA_FILE: &str = "quota.yaml";
+
+/// The default number of changesets downloaded from the OSM API per day
+pub const DEFAULT_DAILY_QUOTA: u32 = 1000;
+
+/// The share of the daily quota which may be used at once after a pause, in hours of
+/// requests
+const BURST_HOURS: f64 = 1.0;
+
+/// The backoff after the first failed request, in seconds. It doubles with every failure
+const INITIAL_BACKOFF: i64 = 60;
+/// The longest backoff, in seconds
+const MAX_BACKOFF: i64 = 6 * 60 * 60;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct QuotaState {
+    /// The requests which may be made right away
+    tokens: f64,
+    /// The unix time the tokens were last refilled
+    refilled_at: i64,
+    /// Failed requests since the last successful one
+    #[serde(default)]
+    failures: u32,
+    /// The unix time until which no requests are made, after failures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backoff_until: Option<i64>,
+}
+
+/// A daily quota of requests to the OSM API, kept across runs
+///
+/// The quota refills continuously over the day, and at most an hour worth of requests can
+/// be saved up. A replay catching up after a long downtime, when the changeset dump is
+/// far behind, so only makes a few requests per replication file instead of thousands at
+/// once. After failed requests no requests are made for a backoff which doubles with
+/// every failure. The state is stored next to the cached API responses, so restarting
+/// the replay doesn't reset it.
+#[derive(Debug)]
+pub struct ApiQuota {
+    path: PathBuf,
+    daily_requests: u32,
+    state: QuotaState,
+}
+
+impl ApiQuota {
+    /// Load the state of the quota from the API cache folder
+    ///
+    /// Without a recorded state, the saved up requests start full.
+    pub fn load(api_cache_folder: &Path, daily_requests: u32, now: i64) -> Result<Self> {
+        let path = api_cache_folder.join(QUOTA_FILE);
+        let mut quota = ApiQuota {
+            path,
+            daily_requests,
+            state: QuotaState::default(),
+        };
+        quota.state = match std::fs::read(&quota.path) {
+            Ok(contents) => match serde_yaml::from_slice(&contents) {
+                Ok(state) => state,
+                Err(err) => {
+                    warn!(
+                        "Unable to read {}: {}. Starting with a fresh quota",
+                        quota.path.display(),
+                        err
+                    );
+                    quota.fresh_state(now)
+                }
+            },
+            Err(_) => quota.fresh_state(now),
+        };
+        Ok(quota)
+    }
+
+    fn fresh_state(&self, now: i64) -> QuotaState {
+        QuotaState {
+            tokens: self.burst(),
+            refilled_at: now,
+            ..Default::default()
+        }
+    }
+
+    /// The most requests which can be saved up
+    fn burst(&self) -> f64 {
+        (self.daily_requests as f64 * BURST_HOURS / 24.0).max(1.0)
+    }
+
+    /// The number of requests which may be made now
+    pub fn available(&mut self, now: i64) -> usize {
+        let elapsed = (now - self.state.refilled_at).max(0) as f64;
+        self.state.tokens = (self.state.tokens
+            + elapsed * self.daily_requests as f64 / (24.0 * 60.0 * 60.0))
+            .min(self.burst());
+        self.state.refilled_at = now;
+        if self.backing_off(now) {
+            return 0;
+        }
+        self.state.tokens.floor() as usize
+    }
+
+    /// Check if no requests are made because of failed requests
+    pub fn backing_off(&self, now: i64) -> bool {
+        self.state
+            .backoff_until
+            .is_some_and(|backoff_until| now < backoff_until)
+    }
+
+    /// Record that a request is made
+    pub fn spend(&mut self) {
+        self.state.tokens = (self.state.tokens - 1.0).max(0.0);
+    }
+
+    pub fn record_success(&mut self) {
+        self.state.failures = 0;
+        self.state.backoff_until = None;
+    }
+
+    /// Record a failed request, which stops all requests for the backoff
+    ///
+    /// # Returns
+    ///
+    /// * `i64` - The backoff in seconds
+    pub fn record_failure(&mut self, now: i64) -> i64 {
+        self.state.failures += 1;
+        let backoff = INITIAL_BACKOFF
+            .saturating_mul(1 << (self.state.failures - 1).min(16))
+            .min(MAX_BACKOFF);
+        self.state.backoff_until = Some(now + backoff);
+        backoff
+    }
+
+    pub fn save(&self) -> Result<()> {
+        std::fs::create_dir_all(self.path.parent().unwrap())?;
+        let temporary_path = self.path.with_extension("yaml.tmp");
+        std::fs::write(&temporary_path, serde_yaml::to_string(&self.state)?)?;
+        std::fs::rename(&temporary_path, &self.path)?;
+        Ok(())
+    }
+}
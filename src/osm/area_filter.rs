@@ -0,0 +1,112 @@
+use color_eyre::eyre::{eyre, Result};
+
+use super::{
+    changesets::BBox,
+    geometry::{ring_contains, Position},
+};
+
+/// The region a regional mirror keeps objects of
+#[derive(Debug, Clone)]
+pub struct AreaFilter {
+    /// The bounding box of the region, to skip the polygon test for most points
+    bbox: BBox,
+    /// The rings of a polygon file, `true` for the rings which cut holes
+    rings: Vec<(Vec<Position>, bool)>,
+}
+
+impl AreaFilter {
+    pub fn from_bbox(bbox: BBox) -> Self {
+        AreaFilter {
+            bbox,
+            rings: Vec::new(),
+        }
+    }
+
+    /// Load the region from an Osmosis polygon file (`.poly`)
+    ///
+    /// The file starts with a name, followed by sections of `lon lat` lines which end with
+    /// `END`. Sections whose name starts with `!` cut holes into the region. The file ends
+    /// with another `END`.
+    pub fn load_poly(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut lines = contents.lines().map(str::trim).filter(|l| !l.is_empty());
+        lines
+            .next()
+            .ok_or_else(|| eyre!("The polygon file {} is empty", path))?;
+
+        let mut rings = Vec::new();
+        loop {
+            let section = lines
+                .next()
+                .ok_or_else(|| eyre!("The polygon file {} has no final END", path))?;
+            if section == "END" {
+                break;
+            }
+            let mut ring = Vec::new();
+            for line in lines.by_ref() {
+                if line == "END" {
+                    break;
+                }
+                let mut coordinates = line.split_whitespace().map(str::parse::<f64>);
+                match (coordinates.next(), coordinates.next()) {
+                    (Some(Ok(lon)), Some(Ok(lat))) => ring.push([lon, lat]),
+                    _ => {
+                        return Err(eyre!(
+                            "Invalid coordinates {:?} in the polygon file {}",
+                            line,
+                            path
+                        ))
+                    }
+                }
+            }
+            if ring.len() < 3 {
+                return Err(eyre!(
+                    "The section {} of the polygon file {} has fewer than 3 points",
+                    section,
+                    path
+                ));
+            }
+            rings.push((ring, section.starts_with('!')));
+        }
+
+        let outer_points = rings
+            .iter()
+            .filter(|(_, hole)| !hole)
+            .flat_map(|(ring, _)| ring);
+        let mut bbox: Option<BBox> = None;
+        for [lon, lat] in outer_points {
+            let bbox = bbox.get_or_insert(BBox {
+                min_lat: *lat,
+                min_lon: *lon,
+                max_lat: *lat,
+                max_lon: *lon,
+            });
+            bbox.min_lat = bbox.min_lat.min(*lat);
+            bbox.min_lon = bbox.min_lon.min(*lon);
+            bbox.max_lat = bbox.max_lat.max(*lat);
+            bbox.max_lon = bbox.max_lon.max(*lon);
+        }
+        Ok(AreaFilter {
+            bbox: bbox.ok_or_else(|| eyre!("The polygon file {} has no outer ring", path))?,
+            rings,
+        })
+    }
+
+    /// Check if a point lies within the region
+    pub fn contains(&self, lon: f64, lat: f64) -> bool {
+        let in_bbox = self.bbox.min_lon <= lon
+            && lon <= self.bbox.max_lon
+            && self.bbox.min_lat <= lat
+            && lat <= self.bbox.max_lat;
+        if !in_bbox || self.rings.is_empty() {
+            return in_bbox;
+        }
+        let within = |holes: bool| {
+            self.rings
+                .iter()
+                .filter(|(_, hole)| *hole == holes)
+                .any(|(ring, _)| ring_contains(ring, [lon, lat]))
+        };
+        within(false) && !within(true)
+    }
+}
@@ -0,0 +1,209 @@
+#[cfg(feature = "dumps")]
+use std::fs::File;
+use std::{collections::HashMap, path::Path};
+
+#[cfg(feature = "dumps")]
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::Result;
+#[cfg(any(feature = "dumps", feature = "http"))]
+use rusqlite::Statement;
+use rusqlite::{params, Connection, OptionalExtension};
+use tracing::info;
+
+use super::changesets::{find_latest_changeset_dump, Changeset};
+#[cfg(feature = "dumps")]
+use super::changesets::{for_each_changeset, uncompress_changeset_file};
+
+/// The file in the changeset folder which stores the index
+const INDEX_FILE: &str = "changesets.sqlite";
+
+/// A SQLite index of the changeset metadata keyed by changeset id
+///
+/// Scanning the changeset dump for every replication file reads gigabytes of compressed
+/// XML. The index is built once from the latest dump with `osm-git index-changesets` and
+/// answers lookups without touching the dump again. It records which dump it was built
+/// from, so a newer dump is not shadowed by an outdated index.
+pub struct ChangesetIndex {
+    connection: Connection,
+}
+
+impl ChangesetIndex {
+    fn path(changesets_location: &str) -> String {
+        Path::new(changesets_location)
+            .join(INDEX_FILE)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Open the index if it was built from the latest changeset dump
+    pub fn open(changesets_location: &str) -> Result<Option<Self>> {
+        let index_path = ChangesetIndex::path(changesets_location);
+        if !Path::new(&index_path).exists() {
+            return Ok(None);
+        }
+        let index = ChangesetIndex {
+            connection: Connection::open(index_path)?,
+        };
+
+        let latest_dump = find_latest_changeset_dump(changesets_location)?;
+        if index.source()?.as_deref() != Some(dump_name(&latest_dump)) {
+            info!(
+                "The changeset index is outdated. Run `osm-git index-changesets` to refresh it from {}",
+                latest_dump
+            );
+            return Ok(None);
+        }
+        Ok(Some(index))
+    }
+
+    /// Build the index from the latest changeset dump, replacing an existing index
+    #[cfg(feature = "dumps")]
+    pub fn build(changesets_location: &str) -> Result<Self> {
+        let changeset_path = find_latest_changeset_dump(changesets_location)?;
+        if changeset_path.is_empty() {
+            return Err(eyre!("No changeset dump found in {}", changesets_location));
+        }
+        info!("Indexing changesets from {}", changeset_path);
+
+        // Build into a temporary file, so readers never see a half built index
+        let index_path = ChangesetIndex::path(changesets_location);
+        let temporary_path = format!("{}.tmp", index_path);
+        if Path::new(&temporary_path).exists() {
+            std::fs::remove_file(&temporary_path)?;
+        }
+
+        let mut connection = Connection::open(&temporary_path)?;
+        connection.execute_batch("CREATE TABLE source (dump TEXT NOT NULL);")?;
+        create_changesets_table(&connection)?;
+
+        let transaction = connection.transaction()?;
+        let mut indexed = 0;
+        {
+            let mut insert = transaction.prepare(INSERT_CHANGESET)?;
+            let mut changeset_data = uncompress_changeset_file(File::open(&changeset_path)?);
+            for_each_changeset(&mut changeset_data, &mut |changeset| {
+                insert_changeset(&mut insert, &changeset)?;
+                indexed += 1;
+                if indexed % 1_000_000 == 0 {
+                    info!("Indexed {} changesets", indexed);
+                }
+                Ok(())
+            })?;
+            transaction.execute(
+                "INSERT INTO source VALUES (?1)",
+                params![dump_name(&changeset_path)],
+            )?;
+        }
+        transaction.commit()?;
+        connection.close().map_err(|(_, err)| err)?;
+
+        std::fs::rename(&temporary_path, &index_path)?;
+        info!("Indexed {} changesets", indexed);
+        Ok(ChangesetIndex {
+            connection: Connection::open(index_path)?,
+        })
+    }
+
+    /// The file name of the dump the index was built from
+    fn source(&self) -> Result<Option<String>> {
+        Ok(self
+            .connection
+            .query_row("SELECT dump FROM source", [], |row| row.get(0))
+            .optional()?)
+    }
+
+    /// Look up the given changesets. Changesets which are not in the index are left out.
+    pub fn get(&self, changeset_list: &[u64]) -> Result<Vec<Changeset>> {
+        select_changesets(&self.connection, changeset_list)
+    }
+}
+
+/// Replaces a changeset in the `changesets` table
+#[cfg(any(feature = "dumps", feature = "http"))]
+pub(super) const INSERT_CHANGESET: &str =
+    "INSERT OR REPLACE INTO changesets VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)";
+
+/// Create the table changesets are stored in
+pub(super) fn create_changesets_table(connection: &Connection) -> Result<()> {
+    connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS changesets (
+            id INTEGER PRIMARY KEY,
+            created_at TEXT NOT NULL,
+            closed_at TEXT,
+            open INTEGER NOT NULL,
+            user TEXT NOT NULL,
+            uid INTEGER NOT NULL,
+            min_lat REAL,
+            max_lat REAL,
+            min_lon REAL,
+            max_lon REAL,
+            tags TEXT NOT NULL
+        );",
+    )?;
+    Ok(())
+}
+
+/// Store a changeset with a statement prepared from [`INSERT_CHANGESET`]
+#[cfg(any(feature = "dumps", feature = "http"))]
+pub(super) fn insert_changeset(insert: &mut Statement, changeset: &Changeset) -> Result<()> {
+    insert.execute(params![
+        changeset.id as i64,
+        changeset.created_at,
+        changeset.closed_at,
+        changeset.open,
+        // Anonymous changesets are stored with an empty user and uid 0, which no mapper has
+        changeset.user.as_deref().unwrap_or_default(),
+        changeset.uid.unwrap_or_default() as i64,
+        changeset.min_lat,
+        changeset.max_lat,
+        changeset.min_lon,
+        changeset.max_lon,
+        serde_yaml::to_string(&changeset.tags)?,
+    ])?;
+    Ok(())
+}
+
+/// Look up the given changesets in the `changesets` table. Changesets which are not
+/// stored are left out.
+pub(super) fn select_changesets(
+    connection: &Connection,
+    changeset_list: &[u64],
+) -> Result<Vec<Changeset>> {
+    let mut select = connection.prepare_cached("SELECT * FROM changesets WHERE id = ?1")?;
+    let mut changesets = Vec::new();
+    for id in changeset_list {
+        let changeset = select
+            .query_row(params![*id as i64], |row| {
+                Ok((
+                    Changeset {
+                        id: row.get::<_, i64>(0)? as u64,
+                        created_at: row.get(1)?,
+                        closed_at: row.get(2)?,
+                        open: row.get(3)?,
+                        user: Some(row.get::<_, String>(4)?).filter(|user| !user.is_empty()),
+                        uid: Some(row.get::<_, i64>(5)? as u64).filter(|uid| *uid != 0),
+                        min_lat: row.get(6)?,
+                        max_lat: row.get(7)?,
+                        min_lon: row.get(8)?,
+                        max_lon: row.get(9)?,
+                        tags: HashMap::new(),
+                    },
+                    row.get::<_, String>(10)?,
+                ))
+            })
+            .optional()?;
+        if let Some((mut changeset, tags)) = changeset {
+            changeset.tags = serde_yaml::from_str(&tags)?;
+            changesets.push(changeset);
+        }
+    }
+    Ok(changesets)
+}
+
+/// The file name of a changeset dump
+fn dump_name(changeset_path: &str) -> &str {
+    Path::new(changeset_path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(changeset_path)
+}
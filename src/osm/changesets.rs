@@ -1,27 +1,183 @@
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result};
+#[cfg(feature = "http")]
+use flate2::bufread::GzDecoder;
+use git2::{Signature, Time};
 use quick_xml::{
     events::{BytesStart, Event},
     name::QName,
     Reader,
 };
+use rusqlite::Connection;
+#[cfg(feature = "http")]
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+#[cfg(any(feature = "http", feature = "dumps"))]
+use std::io::BufReader;
+#[cfg(feature = "http")]
+use std::time::Duration;
 use std::{
     borrow::Cow,
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     convert::Infallible,
     fs::File,
-    io::{BufReader, Write},
+    io::BufRead,
+    path::{Path, PathBuf},
 };
-use tracing::{debug, error, info, warn};
+#[cfg(feature = "http")]
+use time::format_description::well_known::Rfc3339;
+use time::{format_description::well_known::Iso8601, OffsetDateTime};
+#[cfg(any(feature = "http", feature = "dumps"))]
+use tracing::info;
+use tracing::{debug, warn};
+#[cfg(feature = "dumps")]
 use zstd::stream::Decoder;
 
+#[cfg(feature = "http")]
+use super::api_quota::ApiQuota;
+use super::changeset_index::{create_changesets_table, select_changesets, ChangesetIndex};
+#[cfg(feature = "http")]
+use super::changeset_index::{insert_changeset, INSERT_CHANGESET};
+use super::parse_error::{required_attribute, skip_malformed};
+#[cfg(feature = "http")]
+use crate::download::Downloader;
+use crate::{
+    error_report::{report_error, ErrorKind},
+    git::identity::AuthorIdentity,
+    replication::SequenceNumber,
+};
+
+/// A geographic bounding box in WGS84 coordinates
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BBox {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+impl BBox {
+    /// Check if two bounding boxes overlap. Touching edges count as overlap.
+    pub fn intersects(&self, other: &BBox) -> bool {
+        self.min_lat <= other.max_lat
+            && other.min_lat <= self.max_lat
+            && self.min_lon <= other.max_lon
+            && other.min_lon <= self.max_lon
+    }
+}
+
+impl std::str::FromStr for BBox {
+    type Err = color_eyre::Report;
+
+    /// Parse a bounding box in the order it is displayed in, `min_lon,min_lat,max_lon,max_lat`
+    fn from_str(bbox: &str) -> Result<Self> {
+        let coordinates = bbox
+            .split(',')
+            .map(|coordinate| coordinate.trim().parse::<f64>())
+            .collect::<std::result::Result<Vec<f64>, _>>()
+            .map_err(|err| eyre!("Invalid bounding box {:?}: {}", bbox, err))?;
+        let [min_lon, min_lat, max_lon, max_lat] = coordinates[..] else {
+            return Err(eyre!(
+                "Invalid bounding box {:?}. Use min_lon,min_lat,max_lon,max_lat",
+                bbox
+            ));
+        };
+        if min_lon > max_lon || min_lat > max_lat {
+            return Err(eyre!("The bounding box {:?} is empty", bbox));
+        }
+        Ok(BBox {
+            min_lat,
+            min_lon,
+            max_lat,
+            max_lon,
+        })
+    }
+}
+
+impl std::fmt::Display for BBox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{},{},{},{}",
+            self.min_lon, self.min_lat, self.max_lon, self.max_lat
+        )
+    }
+}
+
+/// The area a changeset applies to
+///
+/// Changesets without a bounding box (empty changesets or changesets only changing
+/// their own tags) have no location. They are treated as `Global`:
+///
+/// * Notes report them as global and commits leave out the `Changeset-BBox` trailer
+///   instead of inventing coordinates.
+/// * Features which follow an area (squashing, clones of an area) include them, as they
+///   can't be ruled out.
+/// * Features which need actual coordinates (the admin areas of a changeset) skip them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpatialScope {
+    Area(BBox),
+    Global,
+}
+
+impl SpatialScope {
+    /// Check if two scopes overlap. A global scope overlaps with everything.
+    pub fn overlaps(&self, other: &SpatialScope) -> bool {
+        match (self, other) {
+            (SpatialScope::Area(bbox), SpatialScope::Area(other_bbox)) => {
+                bbox.intersects(other_bbox)
+            }
+            _ => true,
+        }
+    }
+}
+
+impl std::fmt::Display for SpatialScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpatialScope::Area(bbox) => write!(f, "{}", bbox),
+            SpatialScope::Global => write!(f, "global"),
+        }
+    }
+}
+
+/// Parse a timestamp of OSM data
+///
+/// Besides ISO 8601 like `2012-09-12T00:00:00Z`, old data and some tools write
+/// timestamps like `2012-09-12 00:00:00 UTC` or without a time zone. Timestamps without a
+/// time zone are taken as UTC like all OSM timestamps, and a date without a time as its
+/// start.
+pub fn parse_timestamp(timestamp: &str) -> Result<OffsetDateTime> {
+    let timestamp = timestamp.trim();
+    if let Ok(time) = OffsetDateTime::parse(timestamp, &Iso8601::DEFAULT) {
+        return Ok(time);
+    }
+    let mut normalized = timestamp
+        .strip_suffix(" UTC")
+        .unwrap_or(timestamp)
+        .replacen(' ', "T", 1);
+    if !normalized.contains('T') {
+        normalized.push_str("T00:00:00");
+    }
+    let has_time_zone = normalized.ends_with('Z')
+        || normalized
+            .split_once('T')
+            .is_some_and(|(_, time)| time.contains(['+', '-']));
+    if !has_time_zone {
+        normalized.push('Z');
+    }
+    OffsetDateTime::parse(&normalized, &Iso8601::DEFAULT)
+        .map_err(|err| eyre!("Unable to parse the timestamp {:?}: {}", timestamp, err))
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Changeset {
     pub id: u64,
     pub created_at: String,
     pub closed_at: Option<String>,
     pub open: bool,
-    pub user: String,
-    pub uid: u64,
+    /// `None` for anonymous changesets
+    pub user: Option<String>,
+    pub uid: Option<u64>,
     pub min_lat: Option<f64>,
     pub max_lat: Option<f64>,
     pub min_lon: Option<f64>,
@@ -30,12 +186,129 @@ pub struct Changeset {
 }
 
 impl Changeset {
-    fn new_from_element(
-        reader: &mut Reader<BufReader<Decoder<'_, BufReader<File>>>>,
+    /// The bounding box of the changeset
+    ///
+    /// A bounding box is only returned if all four coordinates are present. Partial
+    /// bounding boxes are treated as missing.
+    pub fn bbox(&self) -> Option<BBox> {
+        Some(BBox {
+            min_lat: self.min_lat?,
+            min_lon: self.min_lon?,
+            max_lat: self.max_lat?,
+            max_lon: self.max_lon?,
+        })
+    }
+
+    /// The area the changeset applies to. See [`SpatialScope`] for the semantics.
+    pub fn spatial_scope(&self) -> SpatialScope {
+        match self.bbox() {
+            Some(bbox) => SpatialScope::Area(bbox),
+            None => SpatialScope::Global,
+        }
+    }
+
+    /// Stand-in metadata for a changeset which is neither in the dump nor the index
+    ///
+    /// The changeset is attributed to the anonymous author at `time`, so its objects can
+    /// still be committed. The real metadata can be added as a note later on.
+    pub fn placeholder(id: u64, time: OffsetDateTime) -> Result<Self> {
+        Ok(Changeset {
+            id,
+            created_at: time.format(&Iso8601::DEFAULT)?,
+            closed_at: None,
+            open: false,
+            user: None,
+            uid: None,
+            min_lat: None,
+            max_lat: None,
+            min_lon: None,
+            max_lon: None,
+            tags: HashMap::new(),
+        })
+    }
+
+    /// The time the changeset was closed or created if it is still open
+    ///
+    /// Old data has changesets recorded by servers with clocks which were off, so the
+    /// timestamps are not trusted blindly:
+    ///
+    /// * A `closed_at` before `created_at` is ignored and the changeset is timed at its
+    ///   creation, so it isn't placed before the time it was opened.
+    /// * A `closed_at` which can't be parsed is ignored as well.
+    /// * Without a usable `created_at` nor `closed_at` this fails. The replay then times
+    ///   the changeset at the newest timestamp of its objects instead, see
+    ///   [`Changeset::retime`].
+    ///
+    /// The timestamps of the objects are data of their own and kept as they are, even if
+    /// they lie outside of the time the changeset was open.
+    pub fn timestamp(&self) -> Result<OffsetDateTime> {
+        let created_at = parse_timestamp(&self.created_at);
+        let closed_at = self.closed_at.as_deref().map(parse_timestamp);
+        match (created_at, closed_at) {
+            (Ok(created_at), Some(Ok(closed_at))) if closed_at < created_at => {
+                debug!(
+                    "Changeset {} was closed at {} before it was created at {}. Using the creation time",
+                    self.id, closed_at, created_at
+                );
+                Ok(created_at)
+            }
+            (_, Some(Ok(closed_at))) => Ok(closed_at),
+            (Ok(created_at), _) => Ok(created_at),
+            (Err(err), _) => Err(eyre!(
+                "Changeset {} has no usable timestamp: {}",
+                self.id,
+                err
+            )),
+        }
+    }
+
+    /// Time a changeset without a usable timestamp at `time`
+    ///
+    /// The unusable timestamps are replaced, so the notes show the time the changeset was
+    /// committed at.
+    pub fn retime(&mut self, time: OffsetDateTime) -> Result<()> {
+        warn!(
+            "Changeset {} has no usable timestamp (created at {:?}, closed at {:?}). Timing it at {}",
+            self.id, self.created_at, self.closed_at, time
+        );
+        self.created_at = time.format(&Iso8601::DEFAULT)?;
+        self.closed_at = None;
+        Ok(())
+    }
+
+    /// The git signature of the changeset author at the time the changeset was closed
+    pub fn author_signature(&self, authors: &AuthorIdentity) -> Result<Signature<'static>> {
+        self.signature_at(self.timestamp()?, authors)
+    }
+
+    /// The git signature of the changeset author at the time the changeset was opened
+    pub fn opened_signature(&self, authors: &AuthorIdentity) -> Result<Signature<'static>> {
+        self.signature_at(
+            parse_timestamp(&self.created_at).or_else(|_| self.timestamp())?,
+            authors,
+        )
+    }
+
+    fn signature_at(
+        &self,
+        time: OffsetDateTime,
+        authors: &AuthorIdentity,
+    ) -> Result<Signature<'static>> {
+        let (name, email) = authors.of(self.user.as_deref(), self.uid);
+        // Convert to git time (seconds since epoch) with offset 0 (UTC)
+        Ok(Signature::new(
+            &name,
+            &email,
+            &Time::new(time.unix_timestamp(), 0),
+        )?)
+    }
+
+    fn new_from_element<R: BufRead>(
+        reader: &mut Reader<R>,
         element: &BytesStart,
-        changeset_list: &[u64],
+        wanted: &dyn Fn(u64) -> bool,
     ) -> Result<Option<Self>> {
-        let changeset_attributes: HashMap<String, String> = element
+        let changeset_attributes: BTreeMap<String, String> = element
             .attributes()
             .filter_map(|attr_result| attr_result.ok())
             .map(|attr| {
@@ -68,37 +341,47 @@ impl Changeset {
 
         //debug!("changeset_attributes: {:?}", changeset_attributes);
 
-        let id = changeset_attributes.get("id").unwrap().parse().unwrap();
-        if !changeset_list.contains(&id) {
+        let id = match required_attribute::<u64>(&changeset_attributes, "changeset", "id") {
+            Ok(id) => id,
+            Err(err) => return skip_malformed(reader, element, err),
+        };
+        if !wanted(id) {
             return Ok(None);
         }
 
+        let closed_at = changeset_attributes.get("closed_at").map(|s| s.to_string());
+        // Missing timestamps are left empty, the changeset is timed by its objects then
         let mut changeset = Changeset {
-            id: changeset_attributes.get("id").unwrap().parse().unwrap(),
-            created_at: changeset_attributes.get("created_at").unwrap().to_string(),
-            closed_at: changeset_attributes.get("closed_at").map(|s| s.to_string()),
-            open: changeset_attributes.get("open").unwrap().parse().unwrap(),
+            id,
+            created_at: changeset_attributes
+                .get("created_at")
+                .cloned()
+                .unwrap_or_default(),
+            open: changeset_attributes
+                .get("open")
+                .and_then(|open| open.parse().ok())
+                .unwrap_or(closed_at.is_none()),
+            closed_at,
+            // Anonymous changesets have neither a user nor a uid
             user: changeset_attributes
                 .get("user")
-                .map(|s| s.to_string())
-                .unwrap_or_else(|| "Unknown".to_string()),
+                .filter(|user| !user.is_empty())
+                .cloned(),
             uid: changeset_attributes
                 .get("uid")
-                .unwrap_or(&"0".to_string())
-                .parse()
-                .unwrap(),
+                .and_then(|uid| uid.parse().ok()),
             min_lat: changeset_attributes
                 .get("min_lat")
-                .map(|s| s.parse().unwrap()),
+                .and_then(|s| s.parse().ok()),
             max_lat: changeset_attributes
                 .get("max_lat")
-                .map(|s| s.parse().unwrap()),
+                .and_then(|s| s.parse().ok()),
             min_lon: changeset_attributes
                 .get("min_lon")
-                .map(|s| s.parse().unwrap()),
+                .and_then(|s| s.parse().ok()),
             max_lon: changeset_attributes
                 .get("max_lon")
-                .map(|s| s.parse().unwrap()),
+                .and_then(|s| s.parse().ok()),
             tags: HashMap::new(),
         };
 
@@ -119,7 +402,7 @@ impl Changeset {
                         let mut key = Cow::Borrowed("");
                         let mut value = Cow::Borrowed("");
 
-                        for attr_result in element.attributes() {
+                        for attr_result in e.attributes() {
                             let a = attr_result?;
                             match a.key.as_ref() {
                                 b"k" => key = a.decode_and_unescape_value(reader)?,
@@ -145,11 +428,12 @@ impl Changeset {
                         }
                     }
                     warn!("Unexpected event in changeset: {:?}", event);
-                    // Write the data to file for debugging
-
-                    let mut file = std::fs::File::create("debug.xml")?;
-                    file.write_all(&new_buf)?;
-                    file.sync_all()?;
+                    report_error(
+                        ErrorKind::UnexpectedEvent,
+                        None,
+                        &format!("Unexpected event in changeset: {:?}", event),
+                        &new_buf,
+                    );
                 }
             }
             new_buf = Vec::new();
@@ -159,6 +443,7 @@ impl Changeset {
     }
 }
 
+#[cfg(feature = "dumps")]
 pub fn uncompress_changeset_file<'a>(
     file: File,
 ) -> Reader<BufReader<Decoder<'a, BufReader<File>>>> {
@@ -168,6 +453,7 @@ pub fn uncompress_changeset_file<'a>(
     Reader::from_reader(reader)
 }
 
+#[cfg(feature = "dumps")]
 pub fn parse_changeset(
     changeset_data: &mut Reader<BufReader<Decoder<'_, BufReader<File>>>>,
     changeset_list: &[u64],
@@ -201,19 +487,17 @@ pub fn parse_changeset(
             Event::Start(element) => {
                 if let b"changeset" = element.name().as_ref() {
                     // TODO: What do we do in case of an error?
-                    let changeset =
-                        Changeset::new_from_element(changeset_data, &element, changeset_list);
+                    let changeset = Changeset::new_from_element(changeset_data, &element, &|id| {
+                        changeset_hashset.contains(&id)
+                    });
 
                     match changeset {
                         Ok(Some(changeset)) => {
                             changesets.push(changeset);
                         }
-                        Err(err) => {
-                            error!(
-                                "unable to read changeset element {:?}, utf8 error {:?}",
-                                &element, err
-                            );
-                        }
+                        // Changesets which can't be parsed are committed without
+                        // metadata, like changesets missing from the dump
+                        Err(err) => warn!("Skipping a changeset: {:#}", err),
                         _ => {}
                     }
                 }
@@ -226,3 +510,793 @@ pub fn parse_changeset(
     }
     Ok(changesets)
 }
+
+/// Call `on_changeset` for every changeset in a changeset dump or replication file
+pub fn for_each_changeset<R: BufRead>(
+    changeset_data: &mut Reader<R>,
+    on_changeset: &mut dyn FnMut(Changeset) -> Result<()>,
+) -> Result<()> {
+    changeset_data.expand_empty_elements(true);
+
+    let mut buf = Vec::new();
+    loop {
+        match changeset_data.read_event_into(&mut buf)? {
+            Event::Start(element) if element.name().as_ref() == b"changeset" => {
+                match Changeset::new_from_element(changeset_data, &element, &|_| true) {
+                    Ok(Some(changeset)) => on_changeset(changeset)?,
+                    Err(err) => warn!("Skipping a changeset: {:#}", err),
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(())
+}
+
+/// Find the latest changeset dump in the given folder
+///
+/// The latest dump is the one with the highest number in the file name after
+/// "changesets-" and before ".osm.zst".
+pub fn find_latest_changeset_dump(changesets_location: &str) -> Result<String> {
+    let changeset_files = std::fs::read_dir(changesets_location)?;
+    let mut last_highest_id = 0;
+    let mut changeset_path = String::new();
+    for changeset_file in changeset_files {
+        let changeset_file = changeset_file?;
+        let changeset_file_path = changeset_file.path();
+        let changeset_file_name = changeset_file_path.file_name().unwrap().to_str().unwrap();
+        let changeset_file_name = changeset_file_name.trim_end_matches(".osm.zst");
+        let changeset_file_name = changeset_file_name.trim_start_matches("changesets-");
+        let changeset_file_name = changeset_file_name.parse::<u64>();
+        if let Ok(changeset_file_name) = changeset_file_name {
+            if changeset_file_name > last_highest_id {
+                last_highest_id = changeset_file_name;
+                changeset_path = changeset_file_path.to_str().unwrap().to_string();
+            }
+        }
+    }
+    Ok(changeset_path)
+}
+
+/// Load the metadata of the given changesets
+///
+/// Changesets are looked up in the store of the changeset replication stream first, as
+/// it has the newest state of recent changesets, then in the responses cached from the
+/// OSM API. The remaining ones are read from the latest changeset dump, using the
+/// changeset index instead of scanning the dump if it is up to date.
+pub fn load_changesets(
+    changesets_location: &str,
+    changeset_list: &[u64],
+) -> Result<Vec<Changeset>> {
+    let stream = ChangesetStream::open(changesets_location)?;
+    let mut changesets = match &stream {
+        Some(stream) => stream.get(changeset_list)?,
+        None => Vec::new(),
+    };
+    let found = changesets
+        .iter()
+        .map(|changeset| changeset.id)
+        .collect::<BTreeSet<u64>>();
+    let changeset_list = changeset_list
+        .iter()
+        .copied()
+        .filter(|id| !found.contains(id))
+        .collect::<Vec<u64>>();
+    if changeset_list.is_empty() {
+        return Ok(changesets);
+    }
+
+    let cached = read_api_cache(changesets_location, &changeset_list)?;
+    let changeset_list = changeset_list
+        .into_iter()
+        .filter(|id| !cached.iter().any(|changeset| changeset.id == *id))
+        .collect::<Vec<u64>>();
+    changesets.extend(cached);
+    if changeset_list.is_empty() {
+        return Ok(changesets);
+    }
+
+    let changeset_path = find_latest_changeset_dump(changesets_location)?;
+    // Without a dump, changesets older than the stream store have no metadata
+    if changeset_path.is_empty() && stream.is_some() {
+        return Ok(changesets);
+    }
+    if let Some(index) = DumpIndex::load(&changeset_path)? {
+        let uncovered = changeset_list
+            .iter()
+            .filter(|id| **id > index.last_id)
+            .count();
+        if uncovered > 0 {
+            warn!(
+                "{} changesets are newer than the last changeset {} in {}. Download a newer changeset dump",
+                uncovered, index.last_id, changeset_path
+            );
+        }
+    }
+    if let Some(index) = ChangesetIndex::open(changesets_location)? {
+        changesets.extend(index.get(&changeset_list)?);
+        return Ok(changesets);
+    }
+
+    #[cfg(feature = "dumps")]
+    {
+        let changeset_file = File::open(changeset_path)?;
+        let mut uncompressed_data = uncompress_changeset_file(changeset_file);
+
+        changesets.extend(parse_changeset(&mut uncompressed_data, &changeset_list)?);
+    }
+    #[cfg(not(feature = "dumps"))]
+    warn!(
+        "Reading {} needs the dumps feature. {} changesets are left without metadata",
+        changeset_path,
+        changeset_list.len()
+    );
+    Ok(changesets)
+}
+
+/// The folder in the changeset folder caching the changesets downloaded from the OSM API
+const API_CACHE_FOLDER: &str = "api";
+
+fn api_cache_path(changesets_location: &str, changeset_id: u64) -> PathBuf {
+    Path::new(changesets_location)
+        .join(API_CACHE_FOLDER)
+        .join(format!("{}.osm", changeset_id))
+}
+
+/// Read the changesets which were downloaded from the OSM API before
+fn read_api_cache(changesets_location: &str, changeset_list: &[u64]) -> Result<Vec<Changeset>> {
+    let mut changesets = Vec::new();
+    for changeset_id in changeset_list {
+        let path = api_cache_path(changesets_location, *changeset_id);
+        if path.exists() {
+            changesets.extend(parse_changeset_xml(&std::fs::read(path)?)?);
+        }
+    }
+    Ok(changesets)
+}
+
+/// Parse the changesets of an uncompressed OSM XML document
+fn parse_changeset_xml(data: &[u8]) -> Result<Vec<Changeset>> {
+    let mut changesets = Vec::new();
+    for_each_changeset(&mut Reader::from_reader(data), &mut |changeset| {
+        changesets.push(changeset);
+        Ok(())
+    })?;
+    Ok(changesets)
+}
+
+/// Downloads the metadata of changesets which are missing locally from the OSM API
+#[cfg(feature = "http")]
+pub struct ChangesetApi {
+    pub downloader: Downloader,
+    /// The base URL of the API like `https://api.openstreetmap.org`
+    pub url: String,
+    /// The most changesets downloaded per day, see [`ApiQuota`]
+    pub daily_quota: u32,
+}
+
+#[cfg(feature = "http")]
+impl ChangesetApi {
+    /// Download the given changesets
+    ///
+    /// The responses are cached in the changeset folder, so [`load_changesets`] finds
+    /// them from then on. Failing requests are only logged and their changesets are left
+    /// out, so the replay continues without their metadata. The same goes for changesets
+    /// over the daily quota, and for all changesets during the backoff after a failed
+    /// request.
+    ///
+    /// The conversion of replication files is blocking, so this blocks the current
+    /// worker thread of the multi-threaded runtime until the downloads are done.
+    pub fn fetch(
+        &self,
+        changesets_location: &str,
+        changeset_list: &[u64],
+    ) -> Result<Vec<Changeset>> {
+        tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current()
+                .block_on(self.fetch_async(changesets_location, changeset_list))
+        })
+    }
+
+    async fn fetch_async(
+        &self,
+        changesets_location: &str,
+        changeset_list: &[u64],
+    ) -> Result<Vec<Changeset>> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let mut quota = ApiQuota::load(
+            &Path::new(changesets_location).join(API_CACHE_FOLDER),
+            self.daily_quota,
+            now,
+        )?;
+        let available = quota.available(now);
+        if quota.backing_off(now) {
+            warn!(
+                "Not downloading {} missing changesets from the OSM API after failed requests. They are committed without metadata",
+                changeset_list.len()
+            );
+        } else if available < changeset_list.len() {
+            warn!(
+                "The quota of {} API requests per day allows downloading {} of {} missing changesets now. The others are committed without metadata",
+                self.daily_quota,
+                available,
+                changeset_list.len()
+            );
+        }
+
+        let mut changesets = Vec::new();
+        for changeset_id in changeset_list.iter().take(available) {
+            let url = format!(
+                "{}/api/0.6/changeset/{}",
+                self.url.trim_end_matches('/'),
+                changeset_id
+            );
+            info!("Downloading changeset {} from {}", changeset_id, url);
+            quota.spend();
+            let data = match self.downloader.download(&url).await {
+                Ok(Some(data)) => data,
+                Ok(None) => {
+                    warn!("The OSM API has no changeset {}", changeset_id);
+                    quota.record_success();
+                    continue;
+                }
+                Err(err) => {
+                    let backoff = quota.record_failure(OffsetDateTime::now_utc().unix_timestamp());
+                    warn!(
+                        "Unable to download changeset {}: {}. Not using the OSM API for {} seconds",
+                        changeset_id, err, backoff
+                    );
+                    break;
+                }
+            };
+            quota.record_success();
+
+            let cache_path = api_cache_path(changesets_location, *changeset_id);
+            std::fs::create_dir_all(cache_path.parent().unwrap())?;
+            let temporary_path = cache_path.with_extension("osm.tmp");
+            std::fs::write(&temporary_path, &data)?;
+            std::fs::rename(&temporary_path, &cache_path)?;
+            changesets.extend(parse_changeset_xml(&data)?);
+        }
+        quota.save()?;
+        Ok(changesets)
+    }
+}
+
+/// The file in the changeset folder which stores the changesets of the replication stream
+const STREAM_STORE_FILE: &str = "changesets-stream.sqlite";
+
+/// The progress of syncing the changeset replication stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamSync {
+    /// The newest sequence of the stream which is in the store
+    pub sequence: SequenceNumber,
+    /// The number of changesets which were added or updated
+    pub changesets: usize,
+    /// The number of changesets which were dropped from the store
+    pub pruned: usize,
+    /// Changesets which were opened or closed since the last sync, in the order of the
+    /// stream. Empty for the first sync, which only fills the store
+    pub lifecycle: Vec<(LifecycleEvent, Changeset)>,
+}
+
+/// A changeset being opened or closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleEvent {
+    Opened,
+    Closed,
+}
+
+impl LifecycleEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            LifecycleEvent::Opened => "open",
+            LifecycleEvent::Closed => "close",
+        }
+    }
+}
+
+/// A rolling store of the changesets published in the changeset replication stream
+///
+/// The stream at `https://planet.openstreetmap.org/replication/changesets` publishes the
+/// changesets which were opened, changed or closed every minute. Keeping the recent ones
+/// in a SQLite file next to the changeset dumps gives the metadata of freshly applied
+/// changesets without downloading and scanning a newer multi-GB dump. Changesets which
+/// were closed longer ago than the retention are pruned, so the store stays small.
+pub struct ChangesetStream {
+    connection: Connection,
+}
+
+impl ChangesetStream {
+    fn path(changesets_location: &str) -> String {
+        Path::new(changesets_location)
+            .join(STREAM_STORE_FILE)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Open the store if the stream was synced before
+    pub fn open(changesets_location: &str) -> Result<Option<Self>> {
+        let store_path = ChangesetStream::path(changesets_location);
+        if !Path::new(&store_path).exists() {
+            return Ok(None);
+        }
+        Ok(Some(ChangesetStream {
+            connection: Connection::open(store_path)?,
+        }))
+    }
+
+    /// Open the store, creating it if the stream was not synced before
+    pub fn open_or_create(changesets_location: &str) -> Result<Self> {
+        std::fs::create_dir_all(changesets_location)?;
+        let connection = Connection::open(ChangesetStream::path(changesets_location))?;
+        connection
+            .execute_batch("CREATE TABLE IF NOT EXISTS state (sequence INTEGER NOT NULL);")?;
+        create_changesets_table(&connection)?;
+        Ok(ChangesetStream { connection })
+    }
+
+    /// The newest sequence of the stream which is in the store
+    #[cfg(feature = "http")]
+    fn sequence(&self) -> Result<Option<SequenceNumber>> {
+        Ok(self
+            .connection
+            .query_row("SELECT sequence FROM state", [], |row| row.get(0))
+            .optional()?
+            .map(SequenceNumber::new))
+    }
+
+    /// Look up the given changesets. Changesets which are not in the store are left out.
+    pub fn get(&self, changeset_list: &[u64]) -> Result<Vec<Changeset>> {
+        select_changesets(&self.connection, changeset_list)
+    }
+
+    /// Download the sequences of the stream published since the last sync
+    ///
+    /// The first sync starts `retention` before the newest sequence, as the stream
+    /// publishes one sequence per minute. Every sequence is stored in its own
+    /// transaction, so an interrupted sync continues where it stopped.
+    #[cfg(feature = "http")]
+    pub async fn sync(
+        &mut self,
+        downloader: &Downloader,
+        stream_url: &str,
+        retention: Duration,
+    ) -> Result<StreamSync> {
+        let latest = fetch_stream_sequence(downloader, stream_url).await?;
+        let next = match self.sequence()? {
+            Some(sequence) => sequence.next(),
+            None => latest.saturating_sub(retention.as_secs() / 60),
+        };
+        if next <= latest {
+            info!("Syncing changeset stream sequences {} to {}", next, latest);
+        }
+
+        // Changesets closed before the retention were pruned. When the stream publishes
+        // them again, for example for a new discussion comment, they are not new
+        let track_lifecycle = self.sequence()?.is_some();
+        let retained_since = self.retention_cutoff(retention)?;
+
+        let mut changesets = 0;
+        let mut lifecycle = Vec::new();
+        for sequence in next.through(latest) {
+            let url = format!("{}/{}.osm.gz", stream_url, sequence);
+            let data = downloader.download(&url).await?;
+            if data.is_none() {
+                warn!("Changeset stream file {} not found", url);
+            }
+
+            let transaction = self.connection.transaction()?;
+            {
+                let mut insert = transaction.prepare_cached(INSERT_CHANGESET)?;
+                let mut select_open =
+                    transaction.prepare_cached("SELECT open FROM changesets WHERE id = ?1")?;
+                if let Some(data) = data {
+                    let mut reader = Reader::from_reader(BufReader::new(GzDecoder::new(&data[..])));
+                    for_each_changeset(&mut reader, &mut |changeset| {
+                        if track_lifecycle {
+                            let stored_open: Option<bool> = select_open
+                                .query_row(params![changeset.id], |row| row.get(0))
+                                .optional()?;
+                            let pruned = stored_open.is_none()
+                                && retained_since.as_ref().is_some_and(|retained_since| {
+                                    changeset
+                                        .closed_at
+                                        .as_ref()
+                                        .is_some_and(|closed_at| closed_at < retained_since)
+                                });
+                            if stored_open.is_none() && !pruned {
+                                lifecycle.push((LifecycleEvent::Opened, changeset.clone()));
+                            }
+                            if !changeset.open
+                                && (stored_open == Some(true) || stored_open.is_none() && !pruned)
+                            {
+                                lifecycle.push((LifecycleEvent::Closed, changeset.clone()));
+                            }
+                        }
+                        insert_changeset(&mut insert, &changeset)?;
+                        changesets += 1;
+                        Ok(())
+                    })?;
+                }
+                transaction.execute("DELETE FROM state", [])?;
+                transaction.execute("INSERT INTO state VALUES (?1)", params![sequence.get()])?;
+            }
+            transaction.commit()?;
+            if (sequence.get() - next.get() + 1) % 100 == 0 {
+                info!("Synced changeset stream up to {}", sequence);
+            }
+        }
+
+        let pruned = match self.retention_cutoff(retention)? {
+            Some(cutoff) => self.connection.execute(
+                "DELETE FROM changesets WHERE open = 0 AND closed_at < ?1",
+                params![cutoff],
+            )?,
+            None => 0,
+        };
+        Ok(StreamSync {
+            sequence: latest,
+            changesets,
+            pruned,
+            lifecycle,
+        })
+    }
+
+    /// Changesets closed before this time are pruned from the store
+    ///
+    /// The retention is counted from the newest changeset, so a store which lags behind
+    /// the stream doesn't lose changesets the replay still needs.
+    #[cfg(feature = "http")]
+    fn retention_cutoff(&self, retention: Duration) -> Result<Option<String>> {
+        let newest: Option<String> =
+            self.connection
+                .query_row("SELECT MAX(closed_at) FROM changesets", [], |row| {
+                    row.get(0)
+                })?;
+        newest
+            .map(|newest| {
+                Ok(
+                    (OffsetDateTime::parse(&newest, &Iso8601::DEFAULT)? - retention)
+                        .format(&Rfc3339)?,
+                )
+            })
+            .transpose()
+    }
+}
+
+/// Download the newest sequence of the changeset replication stream
+///
+/// Unlike the object feeds the stream has a YAML state file:
+///
+/// ```text
+/// ---
+/// last_run: 2023-06-01 12:34:02.047131000 +00:00
+/// sequence: 5573871
+/// ```
+#[cfg(feature = "http")]
+async fn fetch_stream_sequence(
+    downloader: &Downloader,
+    stream_url: &str,
+) -> Result<SequenceNumber> {
+    let state_url = format!("{}/state.yaml", stream_url);
+    let contents = downloader
+        .download_text(&state_url)
+        .await?
+        .ok_or_else(|| eyre!("The changeset stream has no state file {}", state_url))?;
+    let state: serde_yaml::Mapping = serde_yaml::from_str(&contents)?;
+    state
+        .get("sequence")
+        .and_then(|sequence| sequence.as_u64())
+        .map(SequenceNumber::new)
+        .ok_or_else(|| eyre!("Invalid state file {}: no sequence", state_url))
+}
+
+/// The coverage of a changeset dump as recorded next to the dump by `osm-git check-dump`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DumpIndex {
+    /// The number of changesets in the dump
+    pub changesets: u64,
+    /// The number of changesets which were still open when the dump was made
+    pub open_changesets: u64,
+    pub first_id: u64,
+    pub last_id: u64,
+    /// The creation time of the oldest changeset
+    pub earliest_created_at: String,
+    /// The latest time a changeset was closed or created at
+    pub latest_timestamp: String,
+}
+
+impl DumpIndex {
+    /// The path of the index file belonging to a changeset dump
+    fn path(changeset_path: &str) -> String {
+        format!("{}.index.yaml", changeset_path)
+    }
+
+    /// Load the index of a changeset dump if it was built before
+    pub fn load(changeset_path: &str) -> Result<Option<Self>> {
+        let index_path = DumpIndex::path(changeset_path);
+        if !Path::new(&index_path).exists() {
+            return Ok(None);
+        }
+        let index_file = File::open(index_path)?;
+        Ok(Some(serde_yaml::from_reader(index_file)?))
+    }
+
+    /// Write the index next to the changeset dump
+    #[cfg(feature = "dumps")]
+    pub fn write(&self, changeset_path: &str) -> Result<()> {
+        let index_file = File::create(DumpIndex::path(changeset_path))?;
+        serde_yaml::to_writer(index_file, self)?;
+        Ok(())
+    }
+}
+
+/// Validate the structure of a changeset dump and compute its coverage
+///
+/// The whole dump is read, so a truncated download fails with a decompression or XML
+/// error. Every changeset needs an id and a parseable creation time and the ids have to
+/// be strictly increasing.
+#[cfg(feature = "dumps")]
+pub fn check_changeset_dump(changeset_path: &str) -> Result<DumpIndex> {
+    let changeset_file = File::open(changeset_path)?;
+    let mut reader = uncompress_changeset_file(changeset_file);
+    reader.expand_empty_elements(true);
+
+    let mut index: Option<DumpIndex> = None;
+    let mut osm_closed = false;
+    let mut buf = Vec::new();
+    loop {
+        let position = reader.buffer_position();
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(element) if element.name().as_ref() == b"changeset" => {
+                let mut id = None;
+                let mut created_at = None;
+                let mut closed_at = None;
+                let mut open = false;
+                for attr_result in element.attributes() {
+                    let attr = attr_result?;
+                    let value = attr.decode_and_unescape_value(&reader)?.to_string();
+                    match attr.key.as_ref() {
+                        b"id" => id = Some(value.parse::<u64>()?),
+                        b"created_at" => created_at = Some(value),
+                        b"closed_at" => closed_at = Some(value),
+                        b"open" => open = value == "true",
+                        _ => (),
+                    }
+                }
+
+                let id = id.ok_or_else(|| eyre!("Changeset without id at byte {}", position))?;
+                let created_at =
+                    created_at.ok_or_else(|| eyre!("Changeset {} has no creation time", id))?;
+                parse_timestamp(&created_at).map_err(|err| {
+                    eyre!("Changeset {} has an invalid creation time: {}", id, err)
+                })?;
+                let timestamp = closed_at.unwrap_or_else(|| created_at.clone());
+
+                match index.as_mut() {
+                    None => {
+                        index = Some(DumpIndex {
+                            changesets: 1,
+                            open_changesets: open as u64,
+                            first_id: id,
+                            last_id: id,
+                            earliest_created_at: created_at,
+                            latest_timestamp: timestamp,
+                        })
+                    }
+                    Some(index) => {
+                        if id <= index.last_id {
+                            return Err(eyre!(
+                                "Changeset {} follows changeset {}. The dump is not sorted by id",
+                                id,
+                                index.last_id
+                            ));
+                        }
+                        index.changesets += 1;
+                        index.open_changesets += open as u64;
+                        index.last_id = id;
+                        if created_at < index.earliest_created_at {
+                            index.earliest_created_at = created_at;
+                        }
+                        if timestamp > index.latest_timestamp {
+                            index.latest_timestamp = timestamp;
+                        }
+                    }
+                }
+            }
+            Event::End(element) if element.name().as_ref() == b"osm" => osm_closed = true,
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    if !osm_closed {
+        return Err(eyre!(
+            "The changeset dump ends without closing the osm element. The file is probably truncated"
+        ));
+    }
+    index.ok_or_else(|| eyre!("The changeset dump contains no changesets"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2012-09-12T00:00:00Z
+    const MIDNIGHT: i64 = 1347408000;
+
+    fn changeset(created_at: &str, closed_at: Option<&str>) -> Changeset {
+        Changeset {
+            closed_at: closed_at.map(str::to_string),
+            created_at: created_at.to_string(),
+            ..Changeset::placeholder(1, OffsetDateTime::UNIX_EPOCH).unwrap()
+        }
+    }
+
+    fn with_bbox(min_lat: Option<f64>) -> Changeset {
+        Changeset {
+            min_lat,
+            max_lat: Some(52.0),
+            min_lon: Some(-1.0),
+            max_lon: Some(0.0),
+            ..changeset("2012-09-12T00:00:00Z", None)
+        }
+    }
+
+    #[test]
+    fn changesets_without_a_full_bbox_are_global() {
+        let area = with_bbox(Some(51.0));
+        assert_eq!(
+            area.spatial_scope(),
+            SpatialScope::Area(BBox {
+                min_lat: 51.0,
+                min_lon: -1.0,
+                max_lat: 52.0,
+                max_lon: 0.0,
+            })
+        );
+        assert_eq!(area.spatial_scope().to_string(), "-1,51,0,52");
+
+        let partial = with_bbox(None);
+        assert_eq!(partial.bbox(), None);
+        assert_eq!(partial.spatial_scope(), SpatialScope::Global);
+        assert_eq!(partial.spatial_scope().to_string(), "global");
+    }
+
+    #[test]
+    fn global_scopes_overlap_every_area() {
+        let london = with_bbox(Some(51.0)).spatial_scope();
+        let berlin = SpatialScope::Area("13.3,52.4,13.4,52.5".parse().unwrap());
+        assert!(london.overlaps(&london));
+        assert!(!london.overlaps(&berlin));
+        assert!(SpatialScope::Global.overlaps(&berlin));
+        assert!(berlin.overlaps(&SpatialScope::Global));
+        assert!(SpatialScope::Global.overlaps(&SpatialScope::Global));
+    }
+
+    #[test]
+    fn changesets_with_unusable_coordinates_are_global() {
+        let xml = br#"<osm>
+  <changeset id="1" created_at="2012-09-12T00:00:00Z" closed_at="2012-09-12T00:10:00Z" open="false" user="alice" uid="5"/>
+  <changeset id="2" created_at="2012-09-12T00:00:00Z" closed_at="2012-09-12T00:10:00Z" open="false" user="alice" uid="5" min_lat="" min_lon="-1" max_lat="52" max_lon="0"/>
+  <changeset id="3" created_at="2012-09-12T00:00:00Z" closed_at="2012-09-12T00:10:00Z" open="false" user="alice" uid="5" min_lat="51" min_lon="-1" max_lat="52" max_lon="0"/>
+</osm>"#;
+        let scopes = parse_changeset_xml(xml)
+            .unwrap()
+            .iter()
+            .map(|changeset| changeset.spatial_scope().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(scopes, ["global", "global", "-1,51,0,52"]);
+    }
+
+    #[test]
+    fn parses_the_timestamps_of_osm_data() {
+        let cases = [
+            ("2012-09-12T00:00:00Z", MIDNIGHT),
+            ("2012-09-12T00:00:00.750Z", MIDNIGHT),
+            ("2012-09-12T00:00:00", MIDNIGHT),
+            ("2012-09-12 00:00:00", MIDNIGHT),
+            ("2012-09-12 00:00:00 UTC", MIDNIGHT),
+            ("2012-09-12", MIDNIGHT),
+            ("  2012-09-12T00:00:00Z\n", MIDNIGHT),
+            ("2012-09-12T02:00:00+02:00", MIDNIGHT),
+            ("2012-09-11T19:00:00-05:00", MIDNIGHT),
+            ("2012-09-12 05:30:00+05:30", MIDNIGHT),
+            ("2012-09-12T12:34:56Z", MIDNIGHT + 45296),
+        ];
+        for (timestamp, expected) in cases {
+            let parsed = parse_timestamp(timestamp)
+                .unwrap_or_else(|err| panic!("{:?} was rejected: {}", timestamp, err));
+            assert_eq!(parsed.unix_timestamp(), expected, "{:?}", timestamp);
+        }
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_timestamp() {
+        let cases = [
+            "",
+            "yesterday",
+            "12/09/2012",
+            "2012-13-01T00:00:00Z",
+            "2012-02-30",
+            "2012-09-12T25:00:00Z",
+            "2012-09-12T00:00:00 CET",
+            "2012-09-12T00:00:00+02:60",
+        ];
+        for timestamp in cases {
+            assert!(
+                parse_timestamp(timestamp).is_err(),
+                "{:?} was accepted",
+                timestamp
+            );
+        }
+    }
+
+    #[test]
+    fn times_changesets_at_their_usable_timestamps() {
+        let cases = [
+            // Closed after it was created
+            (
+                "2012-09-12T00:00:00Z",
+                Some("2012-09-12T01:00:00Z"),
+                Some(MIDNIGHT + 3600),
+            ),
+            // Still open
+            ("2012-09-12T00:00:00Z", None, Some(MIDNIGHT)),
+            // Closed before it was created, by a server with a clock which was off
+            (
+                "2012-09-12T01:00:00Z",
+                Some("2012-09-12T00:00:00Z"),
+                Some(MIDNIGHT + 3600),
+            ),
+            // The offsets are taken into account when comparing: 23:00Z is before 00:00Z
+            (
+                "2012-09-12T02:00:00+02:00",
+                Some("2012-09-12T01:00:00+02:00"),
+                Some(MIDNIGHT),
+            ),
+            (
+                "2012-09-12T00:00:00Z",
+                Some("2012-09-12T03:00:00+02:00"),
+                Some(MIDNIGHT + 3600),
+            ),
+            // An unusable closed_at is ignored
+            ("2012-09-12T00:00:00Z", Some("never"), Some(MIDNIGHT)),
+            // An unusable created_at doesn't matter once the changeset is closed
+            ("garbage", Some("2012-09-12T00:00:00Z"), Some(MIDNIGHT)),
+            ("garbage", None, None),
+            ("garbage", Some("never"), None),
+        ];
+        for (created_at, closed_at, expected) in cases {
+            let timestamp = changeset(created_at, closed_at).timestamp();
+            assert_eq!(
+                timestamp.ok().map(|time| time.unix_timestamp()),
+                expected,
+                "created at {:?}, closed at {:?}",
+                created_at,
+                closed_at
+            );
+        }
+    }
+
+    #[test]
+    fn retimes_changesets_without_a_usable_timestamp() {
+        let mut changeset = changeset("garbage", Some("never"));
+        let time = OffsetDateTime::from_unix_timestamp(MIDNIGHT).unwrap();
+        changeset.retime(time).unwrap();
+
+        assert_eq!(changeset.timestamp().unwrap(), time);
+        assert_eq!(changeset.closed_at, None);
+        assert_eq!(parse_timestamp(&changeset.created_at).unwrap(), time);
+        let signature = changeset
+            .author_signature(&AuthorIdentity::default())
+            .unwrap();
+        assert_eq!(signature.when().seconds(), MIDNIGHT);
+        assert_eq!(signature.when().offset_minutes(), 0);
+    }
+}
@@ -0,0 +1,593 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    path::Path,
+};
+
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
+use git2::{Commit, Delta, DiffFile, Oid, Repository, Sort, Tree};
+use quick_xml::escape::escape;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::git::notes::read_sequence_tags;
+
+#[cfg(feature = "serve")]
+use super::layout::{Layout, ShardBudget};
+use super::{
+    id_mapping::IdMapper,
+    layout::{LayoutMetadata, LAYOUT_FILE},
+    osm_data::{OSMObject, Serializer},
+    snapshot::Snapshot,
+};
+
+/// The file format of an export
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// The objects at one commit as OSM XML
+    Osm,
+    /// Every version of the objects in the history as OSM XML with history
+    Osh,
+}
+
+/// Write all objects of a snapshot as an OSM XML file
+///
+/// Nodes are written first, then ways and relations, each sorted by id, as tools like
+/// osmium expect.
+///
+/// # Returns
+///
+/// * `Result<usize>` - The number of exported objects
+pub fn write_osm_xml(snapshot: &Snapshot, writer: &mut dyn Write) -> Result<usize> {
+    let mut objects = snapshot
+        .objects()?
+        .into_iter()
+        .map(|(_, object)| object)
+        .collect::<Result<Vec<OSMObject>>>()?;
+    objects.sort_by_key(|object| {
+        let type_order = match object {
+            OSMObject::Node(_) => 0,
+            OSMObject::Way(_) => 1,
+            OSMObject::Relation(_) => 2,
+        };
+        (type_order, object.id())
+    });
+
+    write_header(writer)?;
+    for object in &objects {
+        write_object(writer, object, "")?;
+    }
+    writeln!(writer, "</osm>")?;
+    Ok(objects.len())
+}
+
+/// The file format of a diff between two commits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DiffFormat {
+    /// osmChange XML, as used by the replication files
+    Osc,
+}
+
+/// The number of objects of a diff per kind of change
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffSummary {
+    pub created: usize,
+    pub modified: usize,
+    pub deleted: usize,
+}
+
+/// Write the changes of the objects between two commits as an osmChange file (`.osc`)
+///
+/// The objects of the files changed between the trees are compared by type and id
+/// rather than by path, so resharding the git repo in between doesn't show up as
+/// changes. Created and modified objects are written in their version at `to`. Deleted
+/// objects get the version after their last one, attributed to the author of `to`.
+///
+/// Creations and modifications are sorted nodes first, deletions relations first, so
+/// the file can be applied in order without dangling references.
+///
+/// # Returns
+///
+/// * `Result<DiffSummary>` - The number of created, modified and deleted objects
+pub fn write_osc_diff(
+    repository: &Repository,
+    from: &Commit,
+    to: &Commit,
+    id_mapper: &dyn IdMapper,
+    writer: &mut dyn Write,
+) -> Result<DiffSummary> {
+    let old_tree = from.tree()?;
+    let new_tree = to.tree()?;
+    let diff = repository.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+    let old_serializer = tree_serializer(repository, &old_tree)?;
+    let new_serializer = tree_serializer(repository, &new_tree)?;
+    let mut old_objects = BTreeMap::new();
+    let mut new_objects = BTreeMap::new();
+    for delta in diff.deltas() {
+        if delta.status() != Delta::Added {
+            read_changed_object(
+                repository,
+                old_serializer,
+                &delta.old_file(),
+                id_mapper,
+                &mut old_objects,
+            )?;
+        }
+        if delta.status() != Delta::Deleted {
+            read_changed_object(
+                repository,
+                new_serializer,
+                &delta.new_file(),
+                id_mapper,
+                &mut new_objects,
+            )?;
+        }
+    }
+
+    let mut created = Vec::new();
+    let mut modified = Vec::new();
+    for (key, object) in &new_objects {
+        match old_objects.get(key) {
+            None => created.push(object),
+            Some(old_object) if old_object != object => modified.push(object),
+            Some(_) => {}
+        }
+    }
+    let (deleted_at, deleted_by) = deletion_metadata(to)?;
+    let deleted = old_objects
+        .iter()
+        .rev()
+        .filter(|(key, _)| !new_objects.contains_key(key))
+        .map(|(_, object)| deleted_version(object.clone(), deleted_at.clone(), deleted_by.clone()))
+        .collect::<Vec<OSMObject>>();
+
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        writer,
+        r#"<osmChange version="0.6" generator="osm-git {}">"#,
+        env!("CARGO_PKG_VERSION")
+    )?;
+    let deleted_versions = deleted.iter().collect::<Vec<&OSMObject>>();
+    for (action, objects) in [
+        ("create", &created),
+        ("modify", &modified),
+        ("delete", &deleted_versions),
+    ] {
+        if objects.is_empty() {
+            continue;
+        }
+        writeln!(writer, "<{}>", action)?;
+        for object in objects {
+            write_object(writer, object, "")?;
+        }
+        writeln!(writer, "</{}>", action)?;
+    }
+    writeln!(writer, "</osmChange>")?;
+    Ok(DiffSummary {
+        created: created.len(),
+        modified: modified.len(),
+        deleted: deleted.len(),
+    })
+}
+
+/// Read the object of a changed file into `objects`, keyed by type and id
+///
+/// Files which are not objects, like the metadata of the git repo, are skipped.
+fn read_changed_object(
+    repository: &Repository,
+    serializer: &dyn Serializer,
+    file: &DiffFile,
+    id_mapper: &dyn IdMapper,
+    objects: &mut BTreeMap<(u8, u64), OSMObject>,
+) -> Result<()> {
+    let Some(path) = file.path() else {
+        return Ok(());
+    };
+    if path.starts_with("meta")
+        || path.extension().and_then(|ext| ext.to_str()) != Some(serializer.extension())
+    {
+        return Ok(());
+    }
+    let id = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| id_mapper.id_from_stem(stem))
+        .ok_or_else(|| eyre!("{} is not named after an object id", path.display()))?;
+    let blob = repository.find_blob(file.id())?;
+    let mut object = serializer.deserialize(blob.content())?;
+    object.set_id(id);
+    let type_order = match object {
+        OSMObject::Node(_) => 0,
+        OSMObject::Way(_) => 1,
+        OSMObject::Relation(_) => 2,
+    };
+    objects.insert((type_order, id), object);
+    Ok(())
+}
+
+/// The serializer of the object files of a tree
+fn tree_serializer(repository: &Repository, tree: &Tree) -> Result<&'static dyn Serializer> {
+    Ok(LayoutMetadata::load_from_tree(tree, repository)?
+        .map(|metadata| metadata.object_format)
+        .unwrap_or_default()
+        .serializer())
+}
+
+/// One version of an object in the history
+pub struct ObjectVersion {
+    pub object: OSMObject,
+    /// `None` if the commit of the version was made for several changesets
+    pub changeset: Option<u64>,
+}
+
+/// Write every version of the objects in the first-parent history up to `until` as an
+/// OSM XML file with history (`.osh`)
+///
+/// Each commit adding or changing an object file is a visible version of the object and
+/// each commit removing one is a deleted version. Versions committed before `since`
+/// are left out. Commits changing the layout of the object files only move them and
+/// don't create versions.
+///
+/// The changeset of a version is taken from the sequence tags. Commits of squashed
+/// changesets can't be attributed to a single changeset, so their versions have none.
+///
+/// # Returns
+///
+/// * `Result<usize>` - The number of exported versions
+pub fn write_osh_xml(
+    repository: &Repository,
+    since: Option<&Commit>,
+    until: &Commit,
+    id_mapper: &dyn IdMapper,
+    writer: &mut dyn Write,
+) -> Result<usize> {
+    let changesets_per_commit = changesets_per_commit(repository)?;
+    // The format is chosen when the git repo is created, so it is the same for all commits
+    let serializer = LayoutMetadata::load_from_tree(&until.tree()?, repository)?
+        .map(|metadata| metadata.object_format)
+        .unwrap_or_default()
+        .serializer();
+
+    let mut revwalk = repository.revwalk()?;
+    revwalk.push(until.id())?;
+    if let Some(since) = since {
+        revwalk.hide(since.id())?;
+    }
+    revwalk.simplify_first_parent()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+
+    let mut versions = Vec::new();
+    for oid in revwalk {
+        let commit = repository.find_commit(oid?)?;
+        let tree = commit.tree()?;
+        let parent_tree = match commit.parents().next() {
+            Some(parent) => Some(parent.tree()?),
+            None => None,
+        };
+        let diff = repository.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+        if diff
+            .deltas()
+            .any(|delta| delta.new_file().path() == Some(Path::new(LAYOUT_FILE)))
+        {
+            continue;
+        }
+
+        let changeset = single_changeset(&changesets_per_commit, &commit);
+        let (deleted_at, deleted_by) = deletion_metadata(&commit)?;
+        for delta in diff.deltas() {
+            let file = match delta.status() {
+                Delta::Deleted => delta.old_file(),
+                _ => delta.new_file(),
+            };
+            let Some(path) = file.path() else {
+                continue;
+            };
+            // The metadata of the git repo is not an object
+            if path.starts_with("meta")
+                || path.extension().and_then(|ext| ext.to_str()) != Some(serializer.extension())
+            {
+                continue;
+            }
+            let id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| id_mapper.id_from_stem(stem))
+                .ok_or_else(|| eyre!("{} is not named after an object id", path.display()))?;
+            let blob = repository.find_blob(file.id())?;
+            let mut object = serializer.deserialize(blob.content())?;
+            object.set_id(id);
+            if delta.status() == Delta::Deleted {
+                object = deleted_version(object, deleted_at.clone(), deleted_by.clone());
+            }
+            versions.push(ObjectVersion { object, changeset });
+        }
+    }
+
+    // History files are sorted by type, id and version. The sort is stable, so the
+    // versions of an object stay in the order they were committed in
+    versions.sort_by_key(|version| {
+        let type_order = match version.object {
+            OSMObject::Node(_) => 0,
+            OSMObject::Way(_) => 1,
+            OSMObject::Relation(_) => 2,
+        };
+        (type_order, version.object.id())
+    });
+
+    write_header(writer)?;
+    for version in &versions {
+        write_version(writer, version)?;
+    }
+    writeln!(writer, "</osm>")?;
+    Ok(versions.len())
+}
+
+/// Every version of one object in the first-parent history up to `until`
+///
+/// A version is recorded whenever the blob of the object file changes. The file is
+/// looked up with the layout of each commit, so resharding doesn't create versions.
+#[cfg(feature = "serve")]
+pub fn object_history(
+    repository: &Repository,
+    until: &Commit,
+    type_name: &str,
+    id: u64,
+    id_mapper: &dyn IdMapper,
+    budget: ShardBudget,
+) -> Result<Vec<ObjectVersion>> {
+    let changesets_per_commit = changesets_per_commit(repository)?;
+
+    let mut revwalk = repository.revwalk()?;
+    revwalk.push(until.id())?;
+    revwalk.simplify_first_parent()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+
+    let mut versions = Vec::new();
+    let mut previous: Option<(Oid, OSMObject)> = None;
+    for oid in revwalk {
+        let commit = repository.find_commit(oid?)?;
+        let tree = commit.tree()?;
+        let layout = Layout::load_from_tree(&tree, repository, budget)?;
+        let blob_id = tree
+            .get_path(&layout.path(type_name, id, id_mapper))
+            .ok()
+            .map(|entry| entry.id());
+        if blob_id == previous.as_ref().map(|(blob_id, _)| *blob_id) {
+            continue;
+        }
+
+        let mut current = None;
+        if let Some(blob_id) = blob_id {
+            let blob = repository.find_blob(blob_id)?;
+            let mut object = layout.serializer().deserialize(blob.content())?;
+            // Objects of all types share one directory in the legacy flat layout
+            if object.type_name() == type_name {
+                object.set_id(id);
+                current = Some((blob_id, object));
+            }
+        }
+
+        let changeset = single_changeset(&changesets_per_commit, &commit);
+        match (&current, previous) {
+            (Some((_, object)), _) => versions.push(ObjectVersion {
+                object: object.clone(),
+                changeset,
+            }),
+            (None, Some((_, object))) => {
+                let (deleted_at, deleted_by) = deletion_metadata(&commit)?;
+                versions.push(ObjectVersion {
+                    object: deleted_version(object, deleted_at, deleted_by),
+                    changeset,
+                });
+            }
+            (None, None) => {}
+        }
+        previous = current;
+    }
+    Ok(versions)
+}
+
+/// The changesets applied by each commit, according to the sequence tags
+fn changesets_per_commit(repository: &Repository) -> Result<HashMap<Oid, Vec<u64>>> {
+    let mut changesets_per_commit: HashMap<Oid, Vec<u64>> = HashMap::new();
+    for (changeset_id, commit) in read_sequence_tags(repository)? {
+        changesets_per_commit
+            .entry(commit)
+            .or_default()
+            .push(changeset_id);
+    }
+    Ok(changesets_per_commit)
+}
+
+/// The changeset a commit was made for, unless it squashed several
+fn single_changeset(
+    changesets_per_commit: &HashMap<Oid, Vec<u64>>,
+    commit: &Commit,
+) -> Option<u64> {
+    match changesets_per_commit.get(&commit.id()).map(Vec::as_slice) {
+        Some([changeset_id]) => Some(*changeset_id),
+        _ => None,
+    }
+}
+
+/// The timestamp and user of deleted versions, which are attributed to the author of
+/// the deleting commit
+fn deletion_metadata(commit: &Commit) -> Result<(String, Option<String>)> {
+    let deleted_at =
+        OffsetDateTime::from_unix_timestamp(commit.author().when().seconds())?.format(&Rfc3339)?;
+    Ok((deleted_at, commit.author().name().map(str::to_string)))
+}
+
+/// Write the XML declaration and the opening `<osm>` element
+pub fn write_header(writer: &mut dyn Write) -> Result<()> {
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        writer,
+        r#"<osm version="0.6" generator="osm-git {}">"#,
+        env!("CARGO_PKG_VERSION")
+    )?;
+    Ok(())
+}
+
+/// Write a version with its `changeset` and `visible` attributes
+pub fn write_version(writer: &mut dyn Write, version: &ObjectVersion) -> Result<()> {
+    let visible = match &version.object {
+        OSMObject::Node(node) => node.visible,
+        OSMObject::Way(way) => way.visible,
+        OSMObject::Relation(relation) => relation.visible,
+    } != Some(false);
+    let mut attributes = String::new();
+    if let Some(changeset) = version.changeset {
+        attributes.push_str(&format!(r#" changeset="{}""#, changeset));
+    }
+    attributes.push_str(&format!(r#" visible="{}""#, visible));
+    write_object(writer, &version.object, &attributes)
+}
+
+/// The version deleting an object, following its last version
+fn deleted_version(object: OSMObject, timestamp: String, user: Option<String>) -> OSMObject {
+    let next_version = |version: &Option<String>| {
+        version
+            .as_ref()
+            .and_then(|version| version.parse::<u64>().ok())
+            .map(|version| (version + 1).to_string())
+    };
+    match object {
+        OSMObject::Node(mut node) => {
+            node.legacy_object_version = next_version(&node.legacy_object_version);
+            node.timestamp = Some(timestamp);
+            node.uid = None;
+            node.user = user;
+            node.visible = Some(false);
+            node.tags.clear();
+            OSMObject::Node(node)
+        }
+        OSMObject::Way(mut way) => {
+            way.legacy_object_version = next_version(&way.legacy_object_version);
+            way.timestamp = Some(timestamp);
+            way.uid = None;
+            way.user = user;
+            way.visible = Some(false);
+            way.tags.clear();
+            way.nodes.clear();
+            OSMObject::Way(way)
+        }
+        OSMObject::Relation(mut relation) => {
+            relation.legacy_object_version = next_version(&relation.legacy_object_version);
+            relation.timestamp = Some(timestamp);
+            relation.uid = None;
+            relation.user = user;
+            relation.visible = Some(false);
+            relation.tags.clear();
+            relation.member.clear();
+            OSMObject::Relation(relation)
+        }
+    }
+}
+
+/// Write one object as an OSM XML element
+///
+/// `extra_attributes` are added after the metadata attributes. Deleted nodes are
+/// written without a position.
+fn write_object(writer: &mut dyn Write, object: &OSMObject, extra_attributes: &str) -> Result<()> {
+    match object {
+        OSMObject::Node(node) => {
+            write!(
+                writer,
+                r#"  <node id="{}"{}{}{}"#,
+                node.id,
+                version_attribute(&node.legacy_object_version),
+                metadata_attributes(&node.timestamp, node.uid, &node.user),
+                extra_attributes
+            )?;
+            if node.visible != Some(false) {
+                write!(writer, r#" lat="{}" lon="{}""#, node.lat, node.lon)?;
+            }
+            if node.tags.is_empty() {
+                writeln!(writer, "/>")?;
+                return Ok(());
+            }
+            writeln!(writer, ">")?;
+            write_tags(writer, node.tags.iter())?;
+            writeln!(writer, "  </node>")?;
+        }
+        OSMObject::Way(way) => {
+            writeln!(
+                writer,
+                r#"  <way id="{}"{}{}{}>"#,
+                way.id,
+                version_attribute(&way.legacy_object_version),
+                metadata_attributes(&way.timestamp, way.uid, &way.user),
+                extra_attributes
+            )?;
+            for node in &way.nodes {
+                writeln!(writer, r#"    <nd ref="{}"/>"#, node)?;
+            }
+            write_tags(writer, way.tags.iter())?;
+            writeln!(writer, "  </way>")?;
+        }
+        OSMObject::Relation(relation) => {
+            writeln!(
+                writer,
+                r#"  <relation id="{}"{}{}{}>"#,
+                relation.id,
+                version_attribute(&relation.legacy_object_version),
+                metadata_attributes(&relation.timestamp, relation.uid, &relation.user),
+                extra_attributes
+            )?;
+            for member in &relation.member {
+                writeln!(
+                    writer,
+                    r#"    <member type="{}" ref="{}" role="{}"/>"#,
+                    escape(&member.r#type),
+                    member.ref_id,
+                    escape(member.role.as_deref().unwrap_or(""))
+                )?;
+            }
+            write_tags(writer, relation.tags.iter())?;
+            writeln!(writer, "  </relation>")?;
+        }
+    }
+    Ok(())
+}
+
+fn version_attribute(version: &Option<String>) -> String {
+    version
+        .as_ref()
+        .map(|version| format!(r#" version="{}""#, escape(version)))
+        .unwrap_or_default()
+}
+
+/// The `timestamp`, `uid` and `user` attributes of the metadata which is known
+fn metadata_attributes(
+    timestamp: &Option<String>,
+    uid: Option<u64>,
+    user: &Option<String>,
+) -> String {
+    let mut attributes = String::new();
+    if let Some(timestamp) = timestamp {
+        attributes.push_str(&format!(r#" timestamp="{}""#, escape(timestamp)));
+    }
+    if let Some(uid) = uid {
+        attributes.push_str(&format!(r#" uid="{}""#, uid));
+    }
+    if let Some(user) = user {
+        attributes.push_str(&format!(r#" user="{}""#, escape(user)));
+    }
+    attributes
+}
+
+fn write_tags<'a>(
+    writer: &mut dyn Write,
+    tags: impl Iterator<Item = (&'a String, &'a String)>,
+) -> Result<()> {
+    for (key, value) in tags {
+        writeln!(
+            writer,
+            r#"    <tag k="{}" v="{}"/>"#,
+            escape(key),
+            escape(value)
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,153 @@
+#[cfg(any(feature = "serve", feature = "tiles"))]
+use color_eyre::eyre::{eyre, Result};
+#[cfg(feature = "serve")]
+use serde::Serialize;
+
+#[cfg(feature = "serve")]
+use super::{osm_data::OSMObject, snapshot::Snapshot};
+
+/// A `[lon, lat]` coordinate pair as used by GeoJSON
+pub type Position = [f64; 2];
+
+/// The geometry of an object, serialized as a GeoJSON geometry object
+#[cfg(feature = "serve")]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", content = "coordinates")]
+pub enum Geometry {
+    Point(Position),
+    LineString(Vec<Position>),
+    /// The member ways of relations which don't describe an area
+    MultiLineString(Vec<Vec<Position>>),
+    /// Polygons of an outer ring followed by its inner rings
+    MultiPolygon(Vec<Vec<Vec<Position>>>),
+}
+
+#[cfg(feature = "serve")]
+impl<'r> Snapshot<'r> {
+    /// Assemble the geometry of an object from the coordinates of its nodes
+    ///
+    /// Relations tagged as `multipolygon` or `boundary` become a `MultiPolygon`, all
+    /// other relations a `MultiLineString` of their member ways.
+    pub fn geometry(&self, type_name: &str, id: u64) -> Result<Option<Geometry>> {
+        let Some(object) = self.object(type_name, id)? else {
+            return Ok(None);
+        };
+        let geometry = match object {
+            OSMObject::Node(node) => Geometry::Point([node.lon, node.lat]),
+            OSMObject::Way(way) => Geometry::LineString(self.positions(&way.nodes)?),
+            OSMObject::Relation(relation) => {
+                let is_area = matches!(
+                    relation.tags.get("type").map(String::as_str),
+                    Some("multipolygon") | Some("boundary")
+                );
+                let mut outer_ways = Vec::new();
+                let mut inner_ways = Vec::new();
+                for member in relation.member.iter().filter(|m| m.r#type == "way") {
+                    let Some(OSMObject::Way(way)) = self.object("way", member.ref_id)? else {
+                        return Err(eyre!(
+                            "Way {} of relation {} does not exist",
+                            member.ref_id,
+                            id
+                        ));
+                    };
+                    match member.role.as_deref() {
+                        Some("inner") if is_area => inner_ways.push(way.nodes),
+                        _ => outer_ways.push(way.nodes),
+                    }
+                }
+
+                if is_area {
+                    self.multipolygon(id, outer_ways, inner_ways)?
+                } else {
+                    Geometry::MultiLineString(
+                        outer_ways
+                            .iter()
+                            .map(|nodes| self.positions(nodes))
+                            .collect::<Result<_>>()?,
+                    )
+                }
+            }
+        };
+        Ok(Some(geometry))
+    }
+
+    fn positions(&self, nodes: &[u64]) -> Result<Vec<Position>> {
+        nodes
+            .iter()
+            .map(|node_id| match self.object("node", *node_id)? {
+                Some(OSMObject::Node(node)) => Ok([node.lon, node.lat]),
+                _ => Err(eyre!("Node {} does not exist", node_id)),
+            })
+            .collect()
+    }
+
+    fn multipolygon(
+        &self,
+        relation_id: u64,
+        outer_ways: Vec<Vec<u64>>,
+        inner_ways: Vec<Vec<u64>>,
+    ) -> Result<Geometry> {
+        let mut polygons = assemble_rings(relation_id, outer_ways)?
+            .iter()
+            .map(|ring| Ok(vec![self.positions(ring)?]))
+            .collect::<Result<Vec<Vec<Vec<Position>>>>>()?;
+
+        for inner_ring in assemble_rings(relation_id, inner_ways)? {
+            let inner_ring = self.positions(&inner_ring)?;
+            let polygon = polygons
+                .iter_mut()
+                .find(|polygon| ring_contains(&polygon[0], inner_ring[0]))
+                .ok_or_else(|| {
+                    eyre!(
+                        "Inner ring of relation {} is not inside any outer ring",
+                        relation_id
+                    )
+                })?;
+            polygon.push(inner_ring);
+        }
+        Ok(Geometry::MultiPolygon(polygons))
+    }
+}
+
+/// Join ways into closed rings of node ids
+///
+/// Ways are joined at shared end nodes and reversed where needed.
+#[cfg(any(feature = "serve", feature = "tiles"))]
+pub(crate) fn assemble_rings(relation_id: u64, mut ways: Vec<Vec<u64>>) -> Result<Vec<Vec<u64>>> {
+    ways.retain(|way| !way.is_empty());
+    let mut rings = Vec::new();
+    while let Some(mut ring) = ways.pop() {
+        while ring.len() < 2 || ring.first() != ring.last() {
+            let end = *ring.last().unwrap();
+            let Some(index) = ways
+                .iter()
+                .position(|way| way.first() == Some(&end) || way.last() == Some(&end))
+            else {
+                return Err(eyre!(
+                    "Relation {} has an unclosed ring ending at node {}",
+                    relation_id,
+                    end
+                ));
+            };
+            let mut way = ways.remove(index);
+            if way.first() != Some(&end) {
+                way.reverse();
+            }
+            ring.extend(way.into_iter().skip(1));
+        }
+        rings.push(ring);
+    }
+    Ok(rings)
+}
+
+/// Check if a point lies inside a ring using the even-odd rule
+pub fn ring_contains(ring: &[Position], point: Position) -> bool {
+    let [x, y] = point;
+    let mut inside = false;
+    for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+        if (a[1] > y) != (b[1] > y) && x < (b[0] - a[0]) * (y - a[1]) / (b[1] - a[1]) + a[0] {
+            inside = !inside;
+        }
+    }
+    inside
+}
@@ -0,0 +1,235 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use color_eyre::eyre::{eyre, Result};
+use git2::{Repository, Signature};
+use tracing::info;
+
+use crate::git::commit_meta_file;
+
+/// The file in the git repo mapping upstream ids to the ids objects are stored under
+pub const ID_MAP_FILE: &str = "meta/idmap";
+
+/// Maps raw object ids from the input data into the id space of the archive.
+///
+/// Public OSM data only ever uses positive ids. Private data layered on top of it
+/// (local edits, internal databases, ...) conventionally uses negative ids, which would
+/// otherwise collide with or be rejected next to the public ones. The mapper is applied
+/// to object ids as well as to all references (way nodes, relation members) so both
+/// stay consistent inside the archive.
+pub trait IdMapper: Send + Sync {
+    /// Map a raw id of an object of the given type (`node`, `way` or `relation`) as found
+    /// in the input data to the id stored in the archive
+    fn map_id(&self, type_name: &str, raw_id: i64) -> Result<u64>;
+
+    /// The file stem (file name without extension) used for an archive id
+    fn file_stem(&self, id: u64) -> String;
+
+    /// The archive id stored in a file with the given stem
+    fn id_from_stem(&self, stem: &str) -> Option<u64>;
+}
+
+/// The default mapper which only accepts public (positive) ids and keeps them as is
+#[derive(Debug, Clone, Default)]
+pub struct IdentityMapping;
+
+impl IdMapper for IdentityMapping {
+    fn map_id(&self, _type_name: &str, raw_id: i64) -> Result<u64> {
+        u64::try_from(raw_id).map_err(|_| {
+            eyre!(
+                "Found private id {} but no private id offset is configured",
+                raw_id
+            )
+        })
+    }
+
+    fn file_stem(&self, id: u64) -> String {
+        id.to_string()
+    }
+
+    fn id_from_stem(&self, stem: &str) -> Option<u64> {
+        stem.parse().ok()
+    }
+}
+
+/// A mapper which moves private (negative) ids above a fixed offset
+///
+/// Private ids are stored as `offset + |raw_id|` and their files are prefixed with
+/// `prefix`, so `-5` with the prefix `private-` ends up in `private-5.yaml`.
+#[derive(Debug, Clone)]
+pub struct PrivateOverlayMapping {
+    pub offset: u64,
+    pub prefix: String,
+}
+
+impl PrivateOverlayMapping {
+    fn is_private(&self, id: u64) -> bool {
+        id >= self.offset
+    }
+}
+
+impl IdMapper for PrivateOverlayMapping {
+    fn map_id(&self, _type_name: &str, raw_id: i64) -> Result<u64> {
+        if raw_id >= 0 {
+            let id = raw_id as u64;
+            if self.is_private(id) {
+                return Err(eyre!(
+                    "Public id {} collides with the private id range starting at {}",
+                    id,
+                    self.offset
+                ));
+            }
+            return Ok(id);
+        }
+
+        self.offset
+            .checked_add(raw_id.unsigned_abs())
+            .ok_or_else(|| eyre!("Private id {} does not fit into the id space", raw_id))
+    }
+
+    fn file_stem(&self, id: u64) -> String {
+        if self.is_private(id) {
+            format!("{}{}", self.prefix, id - self.offset)
+        } else {
+            id.to_string()
+        }
+    }
+
+    fn id_from_stem(&self, stem: &str) -> Option<u64> {
+        match stem.strip_prefix(&self.prefix) {
+            Some(private_id) => self.offset.checked_add(private_id.parse().ok()?),
+            None => stem.parse().ok(),
+        }
+    }
+}
+
+/// Stable archive ids for upstream ids which don't map to themselves
+///
+/// `meta/idmap` lists one `<type> <upstream id> <archive id>` line per object, like
+/// `node 123 456` for a node renumbered upstream to 123 whose history is stored as node
+/// 456. Negative placeholder ids from local edits can be pinned to an archive id the
+/// same way. All other ids are mapped by the wrapped mapper, so private overlays keep
+/// working on top of it.
+pub struct IndirectMapping {
+    base: Box<dyn IdMapper>,
+    entries: BTreeMap<(String, i64), u64>,
+    /// The archive ids entries map to, which can't be used by other upstream ids
+    targets: BTreeSet<(String, u64)>,
+}
+
+impl IndirectMapping {
+    /// Wrap a mapper with the id map of the HEAD commit of the git repo
+    ///
+    /// The mapper is returned as is if the git repo has no id map.
+    pub fn load(repository: &Repository, base: Box<dyn IdMapper>) -> Result<Box<dyn IdMapper>> {
+        let entries = read_id_map(repository)?;
+        if entries.is_empty() {
+            return Ok(base);
+        }
+        info!(
+            "Mapping {} upstream ids through {}",
+            entries.len(),
+            ID_MAP_FILE
+        );
+        let targets = entries
+            .iter()
+            .map(|((type_name, _), id)| (type_name.clone(), *id))
+            .collect();
+        Ok(Box::new(IndirectMapping {
+            base,
+            entries,
+            targets,
+        }))
+    }
+}
+
+impl IdMapper for IndirectMapping {
+    fn map_id(&self, type_name: &str, raw_id: i64) -> Result<u64> {
+        if let Some(id) = self.entries.get(&(type_name.to_string(), raw_id)) {
+            return Ok(*id);
+        }
+        let id = self.base.map_id(type_name, raw_id)?;
+        if self.targets.contains(&(type_name.to_string(), id)) {
+            return Err(eyre!(
+                "Upstream {} {} collides with an id mapped in {}",
+                type_name,
+                raw_id,
+                ID_MAP_FILE
+            ));
+        }
+        Ok(id)
+    }
+
+    fn file_stem(&self, id: u64) -> String {
+        self.base.file_stem(id)
+    }
+
+    fn id_from_stem(&self, stem: &str) -> Option<u64> {
+        self.base.id_from_stem(stem)
+    }
+}
+
+/// Read the id map of the HEAD commit
+fn read_id_map(repository: &Repository) -> Result<BTreeMap<(String, i64), u64>> {
+    let tree = repository.head()?.peel_to_tree()?;
+    let Ok(entry) = tree.get_path(Path::new(ID_MAP_FILE)) else {
+        return Ok(BTreeMap::new());
+    };
+    let blob = repository.find_blob(entry.id())?;
+    let mut entries = BTreeMap::new();
+    for line in std::str::from_utf8(blob.content())?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let [type_name, upstream_id, id] = line.split_whitespace().collect::<Vec<&str>>()[..]
+        else {
+            return Err(eyre!("Invalid line {:?} in {}", line, ID_MAP_FILE));
+        };
+        entries.insert((type_name.to_string(), upstream_id.parse()?), id.parse()?);
+    }
+    Ok(entries)
+}
+
+/// Record that an upstream id refers to the object stored under `id`
+///
+/// The id map is updated in a commit of its own, so the history shows when the mapping
+/// started to apply.
+pub fn record_id_mapping(
+    repository: &Repository,
+    committer: &Signature,
+    type_name: &str,
+    upstream_id: i64,
+    id: u64,
+) -> Result<()> {
+    if !matches!(type_name, "node" | "way" | "relation") {
+        return Err(eyre!("{} is not an object type", type_name));
+    }
+    let mut entries = read_id_map(repository)?;
+    if let Some(((_, other), _)) = entries.iter().find(|((other_type, other), other_id)| {
+        other_type == type_name && **other_id == id && *other != upstream_id
+    }) {
+        return Err(eyre!(
+            "{} {} is already mapped from upstream id {}",
+            type_name,
+            id,
+            other
+        ));
+    }
+    entries.insert((type_name.to_string(), upstream_id), id);
+
+    let mut contents = String::from("# <type> <upstream id> <archive id>\n");
+    for ((type_name, upstream_id), id) in &entries {
+        contents.push_str(&format!("{} {} {}\n", type_name, upstream_id, id));
+    }
+    commit_meta_file(
+        repository,
+        ID_MAP_FILE,
+        contents.into_bytes(),
+        &format!("Map upstream {} {} to {}", type_name, upstream_id, id),
+        committer,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,560 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{eyre, Result};
+use git2::{ObjectType, Repository, Signature, Tree, TreeWalkMode, TreeWalkResult};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::git::{commit, commit_blobs};
+
+use super::{
+    id_mapping::IdMapper,
+    osm_data::{OSMObject, ObjectFormat, Serializer},
+};
+
+/// The file in the git repo which records the layout of the object files
+pub const LAYOUT_FILE: &str = "meta/layout.yaml";
+
+/// The number of ids sharing a leaf directory
+pub const IDS_PER_LEAF: u64 = 1000;
+
+/// The layout of the object files as recorded in the git repo
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayoutMetadata {
+    /// The number of directory levels between the type directory and the object files
+    pub fan_out_depth: u8,
+    /// Repos created before the format was recorded store YAML files
+    #[serde(default)]
+    pub object_format: ObjectFormat,
+}
+
+impl LayoutMetadata {
+    /// The metadata recorded in the tree of a commit, `None` for the legacy flat layout
+    pub fn load_from_tree(tree: &Tree, repository: &Repository) -> Result<Option<Self>> {
+        let Ok(entry) = tree.get_path(Path::new(LAYOUT_FILE)) else {
+            return Ok(None);
+        };
+        let blob = entry.to_object(repository)?.peel_to_blob()?;
+        Ok(Some(serde_yaml::from_slice(blob.content())?))
+    }
+}
+
+/// Thresholds above which a shard is considered too big for forges to handle well
+#[derive(Debug, Clone, Copy)]
+pub struct ShardBudget {
+    pub max_entries: usize,
+    pub max_file_size: u64,
+}
+
+/// Decides where object files are stored in the git repo
+///
+/// Objects are stored as `<type>s/<shard>/.../<id>.<format>`. The ids are grouped into leaf
+/// directories of [`IDS_PER_LEAF`] ids and `fan_out_depth` levels of directories with up
+/// to 1000 entries each. The top level takes whatever is left, so a deeper fan-out keeps
+/// it smaller.
+///
+/// Repositories created before the layout was recorded store all objects flat in the
+/// root of the repository.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    /// `None` for the legacy flat layout
+    pub fan_out_depth: Option<u8>,
+    pub object_format: ObjectFormat,
+    pub budget: ShardBudget,
+}
+
+impl Layout {
+    /// Load the layout recorded in the git repo
+    ///
+    /// Bare repos have no working directory, so the layout is read from the HEAD commit.
+    pub fn load(repository: &Repository, budget: ShardBudget) -> Result<Self> {
+        if repository.is_bare() {
+            let tree = repository.head()?.peel_to_tree()?;
+            return Layout::load_from_tree(&tree, repository, budget);
+        }
+        let layout_file_path = repository_folder(repository)?.join(LAYOUT_FILE);
+        if !layout_file_path.exists() {
+            info!("No layout recorded in the git repo. Using the legacy flat layout");
+            return Ok(Layout {
+                fan_out_depth: None,
+                object_format: ObjectFormat::Yaml,
+                budget,
+            });
+        }
+
+        let layout_file = std::fs::File::open(layout_file_path)?;
+        let metadata: LayoutMetadata = serde_yaml::from_reader(layout_file)?;
+        Ok(Layout {
+            fan_out_depth: Some(metadata.fan_out_depth),
+            object_format: metadata.object_format,
+            budget,
+        })
+    }
+
+    /// Write the layout metadata to the git repo
+    ///
+    /// # Returns
+    ///
+    /// * `Result<PathBuf>` - The path of the written file
+    pub fn write_metadata(repository: &Repository, metadata: &LayoutMetadata) -> Result<PathBuf> {
+        let layout_file_path = repository_folder(repository)?.join(LAYOUT_FILE);
+        std::fs::create_dir_all(layout_file_path.parent().unwrap())?;
+        let layout_file = std::fs::File::create(&layout_file_path)?;
+        serde_yaml::to_writer(layout_file, metadata)?;
+        Ok(layout_file_path)
+    }
+
+    /// Load the layout recorded in the tree of a commit
+    ///
+    /// The layout can change over the history of the git repo, so reading objects from an
+    /// older commit has to use the layout of that commit.
+    pub fn load_from_tree(
+        tree: &Tree,
+        repository: &Repository,
+        budget: ShardBudget,
+    ) -> Result<Self> {
+        let Some(metadata) = LayoutMetadata::load_from_tree(tree, repository)? else {
+            return Ok(Layout {
+                fan_out_depth: None,
+                object_format: ObjectFormat::Yaml,
+                budget,
+            });
+        };
+        Ok(Layout {
+            fan_out_depth: Some(metadata.fan_out_depth),
+            object_format: metadata.object_format,
+            budget,
+        })
+    }
+
+    /// Fail if a format other than the recorded one is requested
+    ///
+    /// Objects of an existing repo are only ever stored in one format, so the format of
+    /// a new repo can't be changed by later runs.
+    pub fn check_object_format(&self, requested: Option<ObjectFormat>) -> Result<()> {
+        match requested {
+            Some(requested) if requested != self.object_format => Err(eyre!(
+                "The git repo stores objects as {}, not as {}",
+                self.object_format,
+                requested
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Turns the objects into the contents of their files and back
+    pub fn serializer(&self) -> &'static dyn Serializer {
+        self.object_format.serializer()
+    }
+
+    /// The path of the object file relative to the root of the git repo
+    pub fn object_path(&self, object: &OSMObject, id_mapper: &dyn IdMapper) -> PathBuf {
+        self.path(object.type_name(), object.id(), id_mapper)
+    }
+
+    /// The path of the file of an object with the given type and id
+    pub fn path(&self, type_name: &str, id: u64, id_mapper: &dyn IdMapper) -> PathBuf {
+        let file_name = format!(
+            "{}.{}",
+            id_mapper.file_stem(id),
+            self.serializer().extension()
+        );
+        let Some(fan_out_depth) = self.fan_out_depth else {
+            return PathBuf::from(file_name);
+        };
+
+        let mut path = PathBuf::from(format!("{}s", type_name));
+        let mut shards = Vec::new();
+        let mut bucket = id / IDS_PER_LEAF;
+        for _ in 1..fan_out_depth {
+            shards.push(bucket % 1000);
+            bucket /= 1000;
+        }
+        if fan_out_depth > 0 {
+            shards.push(bucket);
+        }
+        for shard in shards.iter().rev() {
+            path.push(format!("{:03}", shard));
+        }
+        path.join(file_name)
+    }
+
+    /// Warn about directories and files which exceed the shard budget
+    pub fn check_budget(&self, repository_folder: &Path, written_files: &[PathBuf]) -> Result<()> {
+        let mut directories = BTreeSet::new();
+        for file in written_files {
+            let file_path = repository_folder.join(file);
+            if let Ok(metadata) = std::fs::metadata(&file_path) {
+                self.check_file_size(file, metadata.len());
+            }
+            if let Some(directory) = file_path.parent() {
+                directories.insert(directory.to_path_buf());
+            }
+        }
+
+        for directory in directories {
+            let Ok(entries) = std::fs::read_dir(&directory) else {
+                continue;
+            };
+            self.check_entry_count(&directory, entries.count());
+        }
+        Ok(())
+    }
+
+    /// Warn about directories and files of a tree which exceed the shard budget
+    ///
+    /// This is the counterpart of [`Layout::check_budget`] for bare repos.
+    pub fn check_tree_budget(
+        &self,
+        repository: &Repository,
+        tree: &Tree,
+        written_files: &[PathBuf],
+    ) -> Result<()> {
+        let mut directories = BTreeSet::new();
+        for file in written_files {
+            if let Ok(entry) = tree.get_path(file) {
+                let blob = repository.find_blob(entry.id())?;
+                self.check_file_size(file, blob.size() as u64);
+            }
+            if let Some(directory) = file.parent() {
+                directories.insert(directory.to_path_buf());
+            }
+        }
+
+        for directory in directories {
+            let entry_count = if directory.as_os_str().is_empty() {
+                tree.len()
+            } else {
+                let Ok(entry) = tree.get_path(&directory) else {
+                    continue;
+                };
+                repository.find_tree(entry.id())?.len()
+            };
+            self.check_entry_count(&directory, entry_count);
+        }
+        Ok(())
+    }
+
+    fn check_file_size(&self, file: &Path, size: u64) {
+        if size > self.budget.max_file_size {
+            warn!(
+                "Object file {} is {} bytes which exceeds the budget of {} bytes",
+                file.display(),
+                size,
+                self.budget.max_file_size
+            );
+        }
+    }
+
+    fn check_entry_count(&self, directory: &Path, entry_count: usize) {
+        if entry_count > self.budget.max_entries {
+            warn!(
+                "Directory {} has {} entries which exceeds the budget of {} entries. Consider running `osm-git reshard` with a deeper fan-out",
+                directory.display(),
+                entry_count,
+                self.budget.max_entries
+            );
+        }
+    }
+}
+
+/// The working directory of the git repo
+fn repository_folder(repository: &Repository) -> Result<PathBuf> {
+    repository
+        .workdir()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| eyre!("The git repository has no working directory"))
+}
+
+/// Move all object files to a layout with a different fan-out depth
+///
+/// The move is recorded as a single migration commit together with the updated layout
+/// metadata, so the history shows exactly when the layout changed. Bare repos have the
+/// files moved in the tree of HEAD.
+pub fn reshard(
+    repository: &Repository,
+    committer: &Signature,
+    id_mapper: &dyn IdMapper,
+    budget: ShardBudget,
+    fan_out_depth: u8,
+) -> Result<()> {
+    let old_layout = Layout::load(repository, budget)?;
+    if old_layout.fan_out_depth == Some(fan_out_depth) {
+        info!("Layout already uses a fan-out depth of {}", fan_out_depth);
+        return Ok(());
+    }
+    let new_layout = Layout {
+        fan_out_depth: Some(fan_out_depth),
+        object_format: old_layout.object_format,
+        budget,
+    };
+    let metadata = LayoutMetadata {
+        fan_out_depth,
+        object_format: new_layout.object_format,
+    };
+    let message = format!(
+        "Re-shard object layout to a fan-out depth of {}",
+        fan_out_depth
+    );
+
+    let moved_files = if repository.is_bare() {
+        reshard_tree(
+            repository,
+            committer,
+            id_mapper,
+            (&old_layout, &new_layout),
+            &metadata,
+            &message,
+        )?
+    } else {
+        reshard_working_directory(
+            repository,
+            committer,
+            id_mapper,
+            (&old_layout, &new_layout),
+            &metadata,
+            &message,
+        )?
+    };
+    info!(
+        "Moved {} object files to a fan-out depth of {}",
+        moved_files, fan_out_depth
+    );
+    Ok(())
+}
+
+/// The id of the object stored at `path`, `None` for files which are no object files
+fn object_file_id(layout: &Layout, id_mapper: &dyn IdMapper, path: &Path) -> Option<u64> {
+    if path.extension().and_then(|e| e.to_str()) != Some(layout.serializer().extension())
+        || path.starts_with("meta")
+    {
+        return None;
+    }
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| id_mapper.id_from_stem(stem))
+}
+
+/// Where the object file at `old_path` goes in the new layout
+///
+/// # Returns
+///
+/// * `Result<Option<PathBuf>>` - `None` if the file stays where it is
+fn resharded_path(
+    (old_layout, new_layout): (&Layout, &Layout),
+    id_mapper: &dyn IdMapper,
+    id: u64,
+    old_path: &Path,
+    contents: &[u8],
+) -> Result<Option<PathBuf>> {
+    let mut object = old_layout.serializer().deserialize(contents)?;
+    object.set_id(id);
+    let new_path = new_layout.object_path(&object, id_mapper);
+    Ok((new_path != old_path).then_some(new_path))
+}
+
+/// Move the object files in the working directory and commit the move
+///
+/// # Returns
+///
+/// * `Result<usize>` - The number of moved files
+fn reshard_working_directory(
+    repository: &Repository,
+    committer: &Signature,
+    id_mapper: &dyn IdMapper,
+    layouts: (&Layout, &Layout),
+    metadata: &LayoutMetadata,
+    message: &str,
+) -> Result<usize> {
+    let repository_folder = repository_folder(repository)?;
+    let mut added_files = Vec::new();
+    let mut removed_files = Vec::new();
+
+    let index = repository.index()?;
+    for entry in index.iter() {
+        let old_path = PathBuf::from(String::from_utf8_lossy(&entry.path).to_string());
+        let Some(id) = object_file_id(layouts.0, id_mapper, &old_path) else {
+            continue;
+        };
+        let contents = std::fs::read(repository_folder.join(&old_path))?;
+        let Some(new_path) = resharded_path(layouts, id_mapper, id, &old_path, &contents)? else {
+            continue;
+        };
+        std::fs::create_dir_all(repository_folder.join(&new_path).parent().unwrap())?;
+        std::fs::rename(
+            repository_folder.join(&old_path),
+            repository_folder.join(&new_path),
+        )?;
+        added_files.push(
+            repository_folder
+                .join(new_path)
+                .to_string_lossy()
+                .to_string(),
+        );
+        removed_files.push(
+            repository_folder
+                .join(old_path)
+                .to_string_lossy()
+                .to_string(),
+        );
+    }
+    let moved_files = removed_files.len();
+
+    let layout_file = Layout::write_metadata(repository, metadata)?;
+    added_files.push(layout_file.to_string_lossy().to_string());
+    commit(
+        repository,
+        "HEAD",
+        added_files,
+        removed_files,
+        message,
+        committer,
+        committer,
+    )?;
+    Ok(moved_files)
+}
+
+/// Move the object files in the tree of HEAD and commit the move, for bare repos
+///
+/// # Returns
+///
+/// * `Result<usize>` - The number of moved files
+fn reshard_tree(
+    repository: &Repository,
+    committer: &Signature,
+    id_mapper: &dyn IdMapper,
+    layouts: (&Layout, &Layout),
+    metadata: &LayoutMetadata,
+    message: &str,
+) -> Result<usize> {
+    let head_tree = repository.head()?.peel_to_tree()?;
+    let mut blobs = Vec::new();
+    head_tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if let (Some(ObjectType::Blob), Some(name)) = (entry.kind(), entry.name()) {
+            blobs.push((PathBuf::from(format!("{}{}", root, name)), entry.id()));
+        }
+        TreeWalkResult::Ok
+    })?;
+
+    let mut files = BTreeMap::new();
+    let mut moved_files = 0;
+    for (old_path, blob_id) in blobs {
+        let Some(id) = object_file_id(layouts.0, id_mapper, &old_path) else {
+            continue;
+        };
+        let contents = repository.find_blob(blob_id)?.content().to_vec();
+        let Some(new_path) = resharded_path(layouts, id_mapper, id, &old_path, &contents)? else {
+            continue;
+        };
+        files.insert(new_path, Some(contents));
+        files.entry(old_path).or_insert(None);
+        moved_files += 1;
+    }
+
+    files.insert(
+        PathBuf::from(LAYOUT_FILE),
+        Some(serde_yaml::to_string(metadata)?.into_bytes()),
+    );
+    commit_blobs(repository, "HEAD", files, message, committer, committer)?;
+    Ok(moved_files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        git::{
+            commit_head_files,
+            testing::{signature, TestRepository},
+        },
+        osm::{id_mapping::IdentityMapping, osm_data::Node},
+    };
+
+    const BUDGET: ShardBudget = ShardBudget {
+        max_entries: 10_000,
+        max_file_size: 1 << 20,
+    };
+
+    fn node(id: u64) -> OSMObject {
+        OSMObject::Node(Node {
+            id,
+            changeset: 2,
+            file_generator: None,
+            file_version: "1".to_string(),
+            legacy_object_version: None,
+            timestamp: Some("2012-09-12T00:00:00Z".to_string()),
+            uid: Some(5),
+            user: Some("alice".to_string()),
+            visible: None,
+            lat: 51.5,
+            lon: -0.1,
+            tags: Default::default(),
+        })
+    }
+
+    /// Commit two nodes and reshard them to a fan-out depth of 2
+    fn reshards(bare: bool) {
+        let repository = TestRepository::new("reshard", bare);
+        let layout = Layout::load(&repository, BUDGET).unwrap();
+        let objects = [node(1), node(1_234_567)];
+        commit_head_files(
+            &repository,
+            objects
+                .iter()
+                .map(|object| {
+                    (
+                        layout.object_path(object, &IdentityMapping),
+                        Some(layout.serializer().serialize(object).unwrap()),
+                    )
+                })
+                .collect(),
+            "Add nodes",
+            &signature(),
+        )
+        .unwrap();
+
+        reshard(&repository, &signature(), &IdentityMapping, BUDGET, 2).unwrap();
+
+        let resharded = Layout::load(&repository, BUDGET).unwrap();
+        assert_eq!(resharded.fan_out_depth, Some(2));
+        let head = repository.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(
+            head.message(),
+            Some("Re-shard object layout to a fan-out depth of 2")
+        );
+        let tree = head.tree().unwrap();
+        for object in &objects {
+            let old_path = layout.object_path(object, &IdentityMapping);
+            let new_path = resharded.object_path(object, &IdentityMapping);
+            assert_ne!(old_path, new_path);
+            assert!(tree.get_path(&old_path).is_err());
+            let blob = repository
+                .find_blob(tree.get_path(&new_path).unwrap().id())
+                .unwrap();
+            assert_eq!(
+                blob.content(),
+                layout.serializer().serialize(object).unwrap()
+            );
+        }
+        assert_eq!(
+            tree.get_path(Path::new("nodes/001/234/1234567.yaml"))
+                .unwrap()
+                .name(),
+            Some("1234567.yaml")
+        );
+        assert!(!repository.has_changes());
+    }
+
+    #[test]
+    fn reshards_repos_with_a_working_directory() {
+        reshards(false);
+    }
+
+    #[test]
+    fn reshards_bare_repos() {
+        reshards(true);
+    }
+}
@@ -0,0 +1,55 @@
+use std::collections::HashSet;
+
+use super::changesets::Changeset;
+
+/// The changeset tags marking automated edits and imports, which are expected to be set
+/// to `yes` by the Automated Edits and Import guidelines
+const AUTOMATED_EDIT_TAGS: &[&str] = &["bot", "import"];
+
+/// Which mappers' edits a mirror keeps
+#[derive(Debug, Clone, Default)]
+pub struct MapperFilter {
+    users: HashSet<String>,
+    uids: HashSet<u64>,
+    exclude_bots: bool,
+}
+
+impl MapperFilter {
+    /// # Returns
+    ///
+    /// * `Option<Self>` - `None` if the filter would keep all edits
+    pub fn new(users: &[String], uids: &[u64], exclude_bots: bool) -> Option<Self> {
+        if users.is_empty() && uids.is_empty() && !exclude_bots {
+            return None;
+        }
+        Some(MapperFilter {
+            users: users.iter().cloned().collect(),
+            uids: uids.iter().copied().collect(),
+            exclude_bots,
+        })
+    }
+
+    /// Changesets tagged as automated edits or imports are dropped
+    pub fn excludes_bots(&self) -> bool {
+        self.exclude_bots
+    }
+
+    /// Check if an edit of this mapper is kept
+    ///
+    /// Without users or uids, edits of all mappers are kept. Otherwise the edit must be
+    /// made by one of the users or uids.
+    pub fn matches(&self, user: Option<&str>, uid: Option<u64>) -> bool {
+        if self.users.is_empty() && self.uids.is_empty() {
+            return true;
+        }
+        user.is_some_and(|user| self.users.contains(user))
+            || uid.is_some_and(|uid| self.uids.contains(&uid))
+    }
+}
+
+/// Check if a changeset is tagged as an automated edit or import, like `bot=yes`
+pub fn is_automated(changeset: &Changeset) -> bool {
+    AUTOMATED_EDIT_TAGS
+        .iter()
+        .any(|key| changeset.tags.get(*key).is_some_and(|value| value == "yes"))
+}
@@ -0,0 +1,255 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Result};
+use git2::{Repository, Signature};
+use serde_yaml::{Mapping, Value};
+use tracing::info;
+
+use crate::git::commit;
+
+use super::{
+    id_mapping::IdMapper,
+    layout::{Layout, LayoutMetadata, ShardBudget},
+    osm_data::{OSMObject, ObjectFormat, Serializer, FILE_VERSION},
+};
+
+/// A step upgrading object files from one file version to the next
+struct Migration {
+    from: &'static str,
+    to: &'static str,
+    /// Rewrite the fields of an object file of version `from` to the ones of version `to`
+    upgrade: fn(&mut Mapping) -> Result<()>,
+}
+
+/// The upgrades between file versions, oldest first
+///
+/// Every change to the schema of the object files bumps [`FILE_VERSION`] and adds a step
+/// from the previous version here, so git repos written by any older version can still
+/// be upgraded with `osm-git migrate`.
+const MIGRATIONS: &[Migration] = &[];
+
+/// What a migration changed
+#[derive(Debug, Default)]
+pub struct MigrationSummary {
+    pub files: usize,
+    /// Files of an older file version
+    pub upgraded: usize,
+    /// Files of the current file version which were not in their canonical form
+    pub rewritten: usize,
+}
+
+/// The numeric parts of a file version like `0.1.0`, to order versions
+fn version_parts(version: &str) -> Result<Vec<u64>> {
+    version
+        .split('.')
+        .map(|part| {
+            part.parse::<u64>()
+                .map_err(|_| eyre!("{} is not a valid file version", version))
+        })
+        .collect()
+}
+
+/// Upgrade the contents of an object file to the current file version
+///
+/// # Returns
+///
+/// * `Result<(Vec<u8>, String)>` - The upgraded contents and the file version the file had
+fn upgrade_object(contents: &[u8], serializer: &dyn Serializer) -> Result<(Vec<u8>, String)> {
+    let Value::Mapping(mut fields) = serializer.deserialize_value(contents)? else {
+        return Err(eyre!("The file is not a map of fields"));
+    };
+    let original_version = fields
+        .get("file_version")
+        .and_then(Value::as_str)
+        .ok_or_else(|| eyre!("The file has no file_version"))?
+        .to_string();
+    if version_parts(&original_version)? > version_parts(FILE_VERSION)? {
+        return Err(eyre!(
+            "The file has file version {} which is newer than the version {} this osm-git writes. Upgrade osm-git instead",
+            original_version,
+            FILE_VERSION
+        ));
+    }
+
+    let mut version = original_version.clone();
+    while version != FILE_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == version)
+            .ok_or_else(|| eyre!("There is no migration from file version {}", version))?;
+        (migration.upgrade)(&mut fields)?;
+        version = migration.to.to_string();
+        fields.insert("file_version".into(), version.clone().into());
+    }
+
+    let object: OSMObject = serde_yaml::from_value(Value::Mapping(fields))?;
+    Ok((serializer.serialize(&object)?, original_version))
+}
+
+/// Upgrade all object files to the current file version
+///
+/// Files are upgraded step by step through the versions in between, and written in the
+/// canonical form of the current version, so files written by hand or by older versions
+/// of osm-git are rewritten too. All changes are recorded as a single commit, so the
+/// history shows exactly when the schema changed. Nothing is committed if all files are
+/// up to date.
+pub fn migrate(
+    repository: &Repository,
+    committer: &Signature,
+    budget: ShardBudget,
+) -> Result<MigrationSummary> {
+    let layout = Layout::load(repository, budget)?;
+    let serializer = layout.serializer();
+    let repository_folder = repository
+        .workdir()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| eyre!("The git repository has no working directory"))?;
+
+    let mut summary = MigrationSummary::default();
+    let mut changed_files = Vec::new();
+    let index = repository.index()?;
+    for entry in index.iter() {
+        let path = PathBuf::from(String::from_utf8_lossy(&entry.path).to_string());
+        if path.extension().and_then(|e| e.to_str()) != Some(serializer.extension())
+            || path.starts_with("meta")
+        {
+            continue;
+        }
+        summary.files += 1;
+
+        let file = repository_folder.join(&path);
+        let contents = std::fs::read(&file)?;
+        let (upgraded, version) = upgrade_object(&contents, serializer)
+            .map_err(|err| eyre!("Unable to migrate {}: {}", path.display(), err))?;
+        if upgraded == contents {
+            continue;
+        }
+        if version == FILE_VERSION {
+            summary.rewritten += 1;
+        } else {
+            summary.upgraded += 1;
+        }
+        std::fs::write(&file, upgraded)?;
+        changed_files.push(file.to_string_lossy().to_string());
+    }
+
+    if changed_files.is_empty() {
+        info!(
+            "All {} object files are up to date with file version {}",
+            summary.files, FILE_VERSION
+        );
+        return Ok(summary);
+    }
+    info!(
+        "Upgraded {} and rewrote {} of {} object files",
+        summary.upgraded, summary.rewritten, summary.files
+    );
+    commit(
+        repository,
+        "HEAD",
+        changed_files,
+        vec![],
+        &format!("Migrate object files to file version {}", FILE_VERSION),
+        committer,
+        committer,
+    )?;
+    Ok(summary)
+}
+
+/// Convert all object files to another format
+///
+/// Each file is upgraded to the current file version, written in the new format and its
+/// old file removed. The new format is recorded in the layout metadata in the same
+/// commit, so every commit has its files in the format its layout names. Repos in the
+/// legacy flat layout have no metadata to record the format in and have to be resharded
+/// first.
+///
+/// # Returns
+///
+/// * `Result<usize>` - The number of converted files
+pub fn convert_object_format(
+    repository: &Repository,
+    committer: &Signature,
+    id_mapper: &dyn IdMapper,
+    budget: ShardBudget,
+    object_format: ObjectFormat,
+) -> Result<usize> {
+    let old_layout = Layout::load(repository, budget)?;
+    if old_layout.object_format == object_format {
+        info!("The object files are stored as {} already", object_format);
+        return Ok(0);
+    }
+    let Some(fan_out_depth) = old_layout.fan_out_depth else {
+        return Err(eyre!(
+            "The git repo uses the legacy flat layout. Run `osm-git reshard` before converting the object files"
+        ));
+    };
+    let new_layout = Layout {
+        object_format,
+        ..old_layout.clone()
+    };
+    let repository_folder = repository
+        .workdir()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| eyre!("The git repository has no working directory"))?;
+
+    let mut added_files = Vec::new();
+    let mut removed_files = Vec::new();
+    let index = repository.index()?;
+    for entry in index.iter() {
+        let old_path = PathBuf::from(String::from_utf8_lossy(&entry.path).to_string());
+        if old_path.extension().and_then(|e| e.to_str())
+            != Some(old_layout.serializer().extension())
+            || old_path.starts_with("meta")
+        {
+            continue;
+        }
+        let Some(id) = old_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| id_mapper.id_from_stem(stem))
+        else {
+            continue;
+        };
+
+        let old_file = repository_folder.join(&old_path);
+        let (upgraded, _) = upgrade_object(&std::fs::read(&old_file)?, old_layout.serializer())
+            .map_err(|err| eyre!("Unable to convert {}: {}", old_path.display(), err))?;
+        let mut object = old_layout.serializer().deserialize(&upgraded)?;
+        object.set_id(id);
+
+        let new_file = repository_folder.join(new_layout.object_path(&object, id_mapper));
+        std::fs::write(&new_file, new_layout.serializer().serialize(&object)?)?;
+        std::fs::remove_file(&old_file)?;
+        added_files.push(new_file.to_string_lossy().to_string());
+        removed_files.push(old_file.to_string_lossy().to_string());
+    }
+
+    let layout_file = Layout::write_metadata(
+        repository,
+        &LayoutMetadata {
+            fan_out_depth,
+            object_format,
+        },
+    )?;
+    added_files.push(layout_file.to_string_lossy().to_string());
+
+    let converted = removed_files.len();
+    info!(
+        "Converted {} object files from {} to {}",
+        converted, old_layout.object_format, object_format
+    );
+    commit(
+        repository,
+        "HEAD",
+        added_files,
+        removed_files,
+        &format!(
+            "Convert object files from {} to {}",
+            old_layout.object_format, object_format
+        ),
+        committer,
+        committer,
+    )?;
+    Ok(converted)
+}
@@ -1,2 +1,26 @@
+pub mod admin_areas;
+#[cfg(feature = "http")]
+pub mod api_quota;
+pub mod area_filter;
+pub mod changeset_index;
 pub mod changesets;
+#[cfg(feature = "export")]
+pub mod export;
+pub mod geometry;
+pub mod id_mapping;
+pub mod layout;
+pub mod mapper_filter;
+pub mod migration;
 pub mod osm_data;
+pub mod parse_error;
+#[cfg(feature = "pbf")]
+pub mod pbf;
+pub mod selection;
+pub mod snapshot;
+pub mod squash;
+pub mod state_store;
+pub mod tag_filter;
+#[cfg(feature = "tiles")]
+pub mod tiles;
+#[cfg(feature = "http")]
+pub mod upload;
@@ -1,6 +1,7 @@
-use color_eyre::eyre::Result;
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
 use flate2::bufread::GzDecoder;
-use git2::{Repository, Signature, Time};
+use git2::{Oid, Signature};
 use quick_xml::{
     events::{BytesStart, Event},
     name::QName,
@@ -9,19 +10,72 @@ use quick_xml::{
 use serde::{Deserialize, Serialize};
 use std::{
     borrow::Cow,
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     convert::Infallible,
-    fs::{File, OpenOptions},
-    io::{Read, Write},
+    io::{BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    thread::Scope,
 };
-use time::{format_description::well_known::Iso8601, OffsetDateTime};
+use time::OffsetDateTime;
 use tracing::{debug, error, info, warn};
 
-use crate::git::commit;
+use crate::{
+    error_report::{report_error, ErrorKind},
+    git::{
+        backend::GitBackend,
+        cursor::ReplayCursor,
+        identity::{AuthorIdentity, IdentityPolicy},
+        notes::AppliedChangeset,
+    },
+    ordered::{OrderedBuffer, Spill},
+    replay::ReplayEvent,
+    watch::Watchlist,
+};
+
+#[cfg(feature = "http")]
+use super::changesets::ChangesetApi;
+use super::{
+    admin_areas::{AdminAreas, ADMIN_AREA_TRAILER},
+    area_filter::AreaFilter,
+    changesets::{load_changesets, parse_timestamp, Changeset},
+    id_mapping::IdMapper,
+    layout::Layout,
+    mapper_filter::{is_automated, MapperFilter},
+    parse_error::{required_attribute, skip_malformed, OsmParseError, ParseMode},
+    selection::ObjectSelection,
+    squash::{
+        append_trailers, changeset_trailers, commit_message, file_commit_message,
+        squash_changesets, tag_key_counts, Granularity, TAG_KEYS_TRAILER,
+    },
+    tag_filter::TagFilter,
+};
+
+pub(crate) const FILE_VERSION: &str = "0.1.0";
 
-use super::changesets::{parse_changeset, uncompress_changeset_file, Changeset};
+/// The number of decimals of coordinates, the precision the OSM database stores them with
+const COORDINATE_DECIMALS: i32 = 7;
 
-const FILE_VERSION: &str = "0.1.0";
+/// Round a coordinate to the precision of the OSM database
+///
+/// Coordinates computed from the nanodegrees of PBF files, like `51.500000000000004`, end
+/// up with the same value as the ones parsed from OSM XML, so both give the same file.
+fn round_coordinate(coordinate: f64) -> f64 {
+    let scale = 10f64.powi(COORDINATE_DECIMALS);
+    // Adding zero turns -0.0 into 0.0
+    (coordinate * scale).round() / scale + 0.0
+}
+
+fn serialize_coordinate<S: serde::Serializer>(
+    coordinate: &f64,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_f64(round_coordinate(*coordinate))
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Node {
@@ -35,13 +89,30 @@ pub struct Node {
     pub file_version: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub legacy_object_version: Option<String>,
+    /// The time the version of the object was created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// `false` if the version deleted the object
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visible: Option<bool>,
+    #[serde(serialize_with = "serialize_coordinate")]
     pub lat: f64,
+    #[serde(serialize_with = "serialize_coordinate")]
     pub lon: f64,
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub tags: BTreeMap<String, String>,
 }
 impl Node {
-    fn new_from_element(reader: &mut Reader<&[u8]>, element: &BytesStart) -> Result<Self> {
+    fn new_from_element<R: BufRead>(
+        reader: &mut Reader<R>,
+        element: &BytesStart,
+        id_mapper: &dyn IdMapper,
+        sequence: Option<&str>,
+    ) -> Result<Self> {
         let attributes: BTreeMap<String, String> = element
             .attributes()
             .filter_map(|attr_result| attr_result.ok())
@@ -75,29 +146,30 @@ impl Node {
             })
             .collect();
 
+        let required = || {
+            Ok((
+                required_attribute::<i64>(&attributes, "node", "id")?,
+                required_attribute::<u64>(&attributes, "node", "changeset")?,
+                required_attribute::<f64>(&attributes, "node", "lat")?,
+                required_attribute::<f64>(&attributes, "node", "lon")?,
+            ))
+        };
+        let (id, changeset, lat, lon) = match required() {
+            Ok(required) => required,
+            Err(err) => return skip_malformed(reader, element, err),
+        };
+
         let mut node = Node {
-            id: attributes
-                .get("id")
-                .unwrap()
-                .parse::<u64>()
-                .expect("Unable to parse node id"),
-            changeset: attributes
-                .get("changeset")
-                .unwrap()
-                .parse::<u64>()
-                .expect("Unable to parse node changeset"),
+            id: id_mapper.map_id("node", id)?,
+            changeset,
             file_generator: attributes.get("generator").map(|s| s.to_string()),
             legacy_object_version: attributes.get("version").map(|s| s.to_string()),
-            lat: attributes
-                .get("lat")
-                .unwrap()
-                .parse::<f64>()
-                .expect("Unable to parse node lat"),
-            lon: attributes
-                .get("lon")
-                .unwrap()
-                .parse::<f64>()
-                .expect("Unable to parse node lon"),
+            timestamp: attributes.get("timestamp").map(|s| s.to_string()),
+            uid: attributes.get("uid").and_then(|s| s.parse().ok()),
+            user: attributes.get("user").map(|s| s.to_string()),
+            visible: attributes.get("visible").and_then(|s| s.parse().ok()),
+            lat,
+            lon,
             tags: BTreeMap::new(),
             file_version: FILE_VERSION.to_string(),
         };
@@ -131,7 +203,7 @@ impl Node {
                 } else {
                     warn!("Unexpected tag: {:?}", name);
                 }
-                reader.read_to_end(name)?;
+                reader.read_to_end_into(name, &mut Vec::new())?;
             } else {
                 if let Event::Text(ref text) = event {
                     if text.borrow().starts_with(b"\n") {
@@ -143,11 +215,12 @@ impl Node {
                     }
                 }
                 warn!("Unexpected event in node: {:?}", event);
-                // Write the data to file for debugging
-
-                let mut file = std::fs::File::create("debug.xml")?;
-                file.write_all(&buf)?;
-                file.sync_all()?;
+                report_error(
+                    ErrorKind::UnexpectedEvent,
+                    sequence,
+                    &format!("Unexpected event in node: {:?}", event),
+                    &buf,
+                );
             }
             buf = Vec::new();
         }
@@ -168,6 +241,16 @@ pub struct Way {
     pub file_version: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub legacy_object_version: Option<String>,
+    /// The time the version of the object was created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// `false` if the version deleted the object
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visible: Option<bool>,
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub tags: BTreeMap<String, String>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
@@ -175,7 +258,12 @@ pub struct Way {
 }
 
 impl Way {
-    fn new_from_element(reader: &mut Reader<&[u8]>, element: &BytesStart) -> Result<Self> {
+    fn new_from_element<R: BufRead>(
+        reader: &mut Reader<R>,
+        element: &BytesStart,
+        id_mapper: &dyn IdMapper,
+        sequence: Option<&str>,
+    ) -> Result<Self> {
         let attributes: BTreeMap<String, String> = element
             .attributes()
             .filter_map(|attr_result| attr_result.ok())
@@ -209,24 +297,33 @@ impl Way {
             })
             .collect();
 
+        let required = || {
+            Ok((
+                required_attribute::<i64>(&attributes, "way", "id")?,
+                required_attribute::<u64>(&attributes, "way", "changeset")?,
+            ))
+        };
+        let (id, changeset) = match required() {
+            Ok(required) => required,
+            Err(err) => return skip_malformed(reader, element, err),
+        };
+
         let mut way = Way {
-            id: attributes
-                .get("id")
-                .unwrap()
-                .parse::<u64>()
-                .expect("Unable to parse way id"),
-            changeset: attributes
-                .get("changeset")
-                .unwrap()
-                .parse::<u64>()
-                .expect("Unable to parse way changeset"),
+            id: id_mapper.map_id("way", id)?,
+            changeset,
             file_generator: attributes.get("generator").map(|s| s.to_string()),
             legacy_object_version: attributes.get("version").map(|s| s.to_string()),
+            timestamp: attributes.get("timestamp").map(|s| s.to_string()),
+            uid: attributes.get("uid").and_then(|s| s.parse().ok()),
+            user: attributes.get("user").map(|s| s.to_string()),
+            visible: attributes.get("visible").and_then(|s| s.parse().ok()),
             tags: BTreeMap::new(),
             nodes: Vec::new(),
             file_version: FILE_VERSION.to_string(),
         };
 
+        // An invalid ref is reported once the rest of the element is read
+        let mut invalid_reference = None;
         let mut buf = Vec::new();
         loop {
             let event = reader.read_event_into(&mut buf)?;
@@ -263,16 +360,20 @@ impl Way {
                         }
                     }
 
-                    way.nodes.push(
-                        ref_id
-                            .to_string()
-                            .parse::<u64>()
-                            .expect("Unable to parse way node ref"),
-                    );
+                    match ref_id.parse::<i64>() {
+                        Ok(ref_id) => way.nodes.push(id_mapper.map_id("node", ref_id)?),
+                        Err(_) => {
+                            invalid_reference.get_or_insert(OsmParseError::InvalidReference {
+                                element: "way",
+                                id: Some(id.to_string()),
+                                value: ref_id.to_string(),
+                            });
+                        }
+                    }
                 } else {
                     warn!("Unexpected tag: {:?}", name);
                 }
-                reader.read_to_end(name)?;
+                reader.read_to_end_into(name, &mut Vec::new())?;
             } else {
                 if let Event::Text(ref text) = event {
                     if text.borrow().starts_with(b"\n") {
@@ -283,16 +384,20 @@ impl Way {
                         continue;
                     }
                 }
-                warn!("Unexpected event way: {:?}", event);
-                // Write the data to file for debugging
-
-                let mut file = std::fs::File::create("debug.xml")?;
-                file.write_all(&buf)?;
-                file.sync_all()?;
+                warn!("Unexpected event in way: {:?}", event);
+                report_error(
+                    ErrorKind::UnexpectedEvent,
+                    sequence,
+                    &format!("Unexpected event in way: {:?}", event),
+                    &buf,
+                );
             }
             buf = Vec::new();
         }
 
+        if let Some(err) = invalid_reference {
+            return Err(err.into());
+        }
         Ok(way)
     }
 }
@@ -319,6 +424,16 @@ pub struct Relation {
     pub file_version: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub legacy_object_version: Option<String>,
+    /// The time the version of the object was created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// `false` if the version deleted the object
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visible: Option<bool>,
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub tags: BTreeMap<String, String>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
@@ -326,7 +441,12 @@ pub struct Relation {
 }
 
 impl Relation {
-    fn new_from_element(reader: &mut Reader<&[u8]>, element: &BytesStart) -> Result<Self> {
+    fn new_from_element<R: BufRead>(
+        reader: &mut Reader<R>,
+        element: &BytesStart,
+        id_mapper: &dyn IdMapper,
+        sequence: Option<&str>,
+    ) -> Result<Self> {
         let attributes: BTreeMap<String, String> = element
             .attributes()
             .filter_map(|attr_result| attr_result.ok())
@@ -360,24 +480,33 @@ impl Relation {
             })
             .collect();
 
+        let required = || {
+            Ok((
+                required_attribute::<i64>(&attributes, "relation", "id")?,
+                required_attribute::<u64>(&attributes, "relation", "changeset")?,
+            ))
+        };
+        let (id, changeset) = match required() {
+            Ok(required) => required,
+            Err(err) => return skip_malformed(reader, element, err),
+        };
+
         let mut relation = Relation {
-            id: attributes
-                .get("id")
-                .unwrap()
-                .parse::<u64>()
-                .expect("Unable to parse way id"),
-            changeset: attributes
-                .get("changeset")
-                .unwrap()
-                .parse::<u64>()
-                .expect("Unable to parse way changeset"),
+            id: id_mapper.map_id("relation", id)?,
+            changeset,
             file_generator: attributes.get("generator").map(|s| s.to_string()),
             legacy_object_version: attributes.get("version").map(|s| s.to_string()),
+            timestamp: attributes.get("timestamp").map(|s| s.to_string()),
+            uid: attributes.get("uid").and_then(|s| s.parse().ok()),
+            user: attributes.get("user").map(|s| s.to_string()),
+            visible: attributes.get("visible").and_then(|s| s.parse().ok()),
             tags: BTreeMap::new(),
             member: Vec::new(),
             file_version: FILE_VERSION.to_string(),
         };
 
+        // An invalid ref is reported once the rest of the element is read
+        let mut invalid_reference = None;
         let mut buf = Vec::new();
         loop {
             let event = reader.read_event_into(&mut buf)?;
@@ -425,18 +554,24 @@ impl Relation {
                         Some(role.to_string())
                     };
 
-                    relation.member.push(RelationMember {
-                        r#type: r#type.to_string(),
-                        ref_id: ref_id
-                            .to_string()
-                            .parse::<u64>()
-                            .expect("Unable to parse relation member ref"),
-                        role: normalized_role,
-                    });
+                    match ref_id.parse::<i64>() {
+                        Ok(ref_id) => relation.member.push(RelationMember {
+                            r#type: r#type.to_string(),
+                            ref_id: id_mapper.map_id(&r#type, ref_id)?,
+                            role: normalized_role,
+                        }),
+                        Err(_) => {
+                            invalid_reference.get_or_insert(OsmParseError::InvalidReference {
+                                element: "relation",
+                                id: Some(id.to_string()),
+                                value: ref_id.to_string(),
+                            });
+                        }
+                    }
                 } else {
                     warn!("Unexpected tag: {:?}", name);
                 }
-                reader.read_to_end(name)?;
+                reader.read_to_end_into(name, &mut Vec::new())?;
             } else {
                 if let Event::Text(ref text) = event {
                     if text.borrow().starts_with(b"\n") {
@@ -447,16 +582,20 @@ impl Relation {
                         continue;
                     }
                 }
-                warn!("Unexpected event in Relation: {:?}", event);
-                // Write the data to file for debugging
-
-                let mut file = std::fs::File::create("debug.xml")?;
-                file.write_all(&buf)?;
-                file.sync_all()?;
+                warn!("Unexpected event in relation: {:?}", event);
+                report_error(
+                    ErrorKind::UnexpectedEvent,
+                    sequence,
+                    &format!("Unexpected event in relation: {:?}", event),
+                    &buf,
+                );
             }
             buf = Vec::new();
         }
 
+        if let Some(err) = invalid_reference {
+            return Err(err.into());
+        }
         Ok(relation)
     }
 }
@@ -477,36 +616,813 @@ impl OSMObject {
             OSMObject::Relation(relation) => relation.id,
         }
     }
+
+    /// Set the id of the object. The id is not part of the serialized object.
+    pub fn set_id(&mut self, id: u64) {
+        match self {
+            OSMObject::Node(node) => node.id = id,
+            OSMObject::Way(way) => way.id = id,
+            OSMObject::Relation(relation) => relation.id = id,
+        }
+    }
+
+    /// The changeset the version of the object was created in
+    pub fn changeset(&self) -> u64 {
+        match self {
+            OSMObject::Node(node) => node.changeset,
+            OSMObject::Way(way) => way.changeset,
+            OSMObject::Relation(relation) => relation.changeset,
+        }
+    }
+
+    /// The name and uid of the mapper who created the version of the object
+    pub fn mapper(&self) -> (Option<&str>, Option<u64>) {
+        match self {
+            OSMObject::Node(node) => (node.user.as_deref(), node.uid),
+            OSMObject::Way(way) => (way.user.as_deref(), way.uid),
+            OSMObject::Relation(relation) => (relation.user.as_deref(), relation.uid),
+        }
+    }
+
+    /// The time the version of the object was created
+    pub fn timestamp(&self) -> Option<&str> {
+        match self {
+            OSMObject::Node(node) => node.timestamp.as_deref(),
+            OSMObject::Way(way) => way.timestamp.as_deref(),
+            OSMObject::Relation(relation) => relation.timestamp.as_deref(),
+        }
+    }
+
+    pub fn tags(&self) -> &BTreeMap<String, String> {
+        match self {
+            OSMObject::Node(node) => &node.tags,
+            OSMObject::Way(way) => &way.tags,
+            OSMObject::Relation(relation) => &relation.tags,
+        }
+    }
+
+    /// Apply the version of a `<modify>` to the stored object
+    ///
+    /// The coordinates, tags, nodes, members and mapper of the version replace the stored
+    /// ones. The generator, version, timestamp and visibility are optional in OSM XML, so
+    /// the stored ones are kept where the modify leaves them out. The mapper is always
+    /// replaced, as anonymous edits have none. Without a stored object of the same type
+    /// the version is taken as it is, as if it was created.
+    pub fn modified(stored: Option<OSMObject>, version: OSMObject) -> OSMObject {
+        match (stored, version) {
+            (Some(OSMObject::Node(mut stored)), OSMObject::Node(node)) => {
+                stored.id = node.id;
+                stored.changeset = node.changeset;
+                stored.file_generator = node.file_generator.or(stored.file_generator);
+                stored.file_version = node.file_version;
+                stored.legacy_object_version =
+                    node.legacy_object_version.or(stored.legacy_object_version);
+                stored.timestamp = node.timestamp.or(stored.timestamp);
+                stored.uid = node.uid;
+                stored.user = node.user;
+                stored.visible = node.visible.or(stored.visible);
+                stored.lat = node.lat;
+                stored.lon = node.lon;
+                stored.tags = node.tags;
+                OSMObject::Node(stored)
+            }
+            (Some(OSMObject::Way(mut stored)), OSMObject::Way(way)) => {
+                stored.id = way.id;
+                stored.changeset = way.changeset;
+                stored.file_generator = way.file_generator.or(stored.file_generator);
+                stored.file_version = way.file_version;
+                stored.legacy_object_version =
+                    way.legacy_object_version.or(stored.legacy_object_version);
+                stored.timestamp = way.timestamp.or(stored.timestamp);
+                stored.uid = way.uid;
+                stored.user = way.user;
+                stored.visible = way.visible.or(stored.visible);
+                stored.tags = way.tags;
+                stored.nodes = way.nodes;
+                OSMObject::Way(stored)
+            }
+            (Some(OSMObject::Relation(mut stored)), OSMObject::Relation(relation)) => {
+                stored.id = relation.id;
+                stored.changeset = relation.changeset;
+                stored.file_generator = relation.file_generator.or(stored.file_generator);
+                stored.file_version = relation.file_version;
+                stored.legacy_object_version = relation
+                    .legacy_object_version
+                    .or(stored.legacy_object_version);
+                stored.timestamp = relation.timestamp.or(stored.timestamp);
+                stored.uid = relation.uid;
+                stored.user = relation.user;
+                stored.visible = relation.visible.or(stored.visible);
+                stored.tags = relation.tags;
+                stored.member = relation.member;
+                OSMObject::Relation(stored)
+            }
+            (_, version) => version,
+        }
+    }
+
+    /// The OSM name of the object type
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            OSMObject::Node(_) => "node",
+            OSMObject::Way(_) => "way",
+            OSMObject::Relation(_) => "relation",
+        }
+    }
 }
 
-pub fn convert_objects_to_git(
-    repository: &Repository,
-    committer: &Signature,
-    data: &[u8],
-    changesets_location: &str,
+/// Turns objects into the contents of their files and back
+///
+/// The id of an object is not part of its file, it is derived from the file name.
+///
+/// Serializing has to be canonical, so writing an unchanged object never shows up as a
+/// diff: the same object always gives the same bytes, with the fields in a fixed order,
+/// tags and keys sorted, coordinates rounded to the precision of the OSM database and
+/// text formats using `\n` line endings and ending with one.
+pub trait Serializer: Send + Sync {
+    /// The extension of the object files, without the dot
+    fn extension(&self) -> &'static str;
+    fn serialize(&self, object: &OSMObject) -> Result<Vec<u8>>;
+    fn deserialize(&self, contents: &[u8]) -> Result<OSMObject>;
+
+    /// Parse the contents of a file without a schema, for files of older file versions
+    /// which may not deserialize into the current objects
+    fn deserialize_value(&self, contents: &[u8]) -> Result<serde_yaml::Value>;
+
+    /// Check if the contents of a file are what serializing its object gives
+    ///
+    /// Files written by older versions or by hand may not be, and get rewritten in the
+    /// canonical form the next time their object changes.
+    fn is_canonical(&self, contents: &[u8]) -> Result<bool> {
+        Ok(self.serialize(&self.deserialize(contents)?)? == contents)
+    }
+}
+
+/// The formats objects can be stored in
+///
+/// The format is recorded in the layout metadata of the git repo when it is created. It
+/// is only changed by `osm-git migrate --to-format`, which converts all files in one
+/// commit, as the files of both formats would mix otherwise.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectFormat {
+    #[default]
+    Yaml,
+    Json,
+    Toml,
+    /// Compact binary files, which are smaller but can't be diffed as text
+    Cbor,
+    /// One `key=value` line per field and tag, the smallest text format
+    Compact,
+}
+
+impl ObjectFormat {
+    pub fn serializer(self) -> &'static dyn Serializer {
+        match self {
+            ObjectFormat::Yaml => &YamlSerializer,
+            ObjectFormat::Json => &JsonSerializer,
+            ObjectFormat::Toml => &TomlSerializer,
+            ObjectFormat::Cbor => &CborSerializer,
+            ObjectFormat::Compact => &CompactSerializer,
+        }
+    }
+}
+
+impl std::fmt::Display for ObjectFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.to_possible_value() {
+            Some(value) => f.write_str(value.get_name()),
+            None => f.write_str(self.serializer().extension()),
+        }
+    }
+}
+
+struct YamlSerializer;
+
+impl Serializer for YamlSerializer {
+    fn extension(&self) -> &'static str {
+        "yaml"
+    }
+
+    fn serialize(&self, object: &OSMObject) -> Result<Vec<u8>> {
+        Ok(serde_yaml::to_string(object)?.into_bytes())
+    }
+
+    fn deserialize(&self, contents: &[u8]) -> Result<OSMObject> {
+        Ok(serde_yaml::from_slice(contents)?)
+    }
+
+    fn deserialize_value(&self, contents: &[u8]) -> Result<serde_yaml::Value> {
+        Ok(serde_yaml::from_slice(contents)?)
+    }
+}
+
+/// Pretty printed, so diffs stay line based like the ones of YAML files
+struct JsonSerializer;
+
+impl Serializer for JsonSerializer {
+    fn extension(&self) -> &'static str {
+        "json"
+    }
+
+    fn serialize(&self, object: &OSMObject) -> Result<Vec<u8>> {
+        let mut contents = serde_json::to_vec_pretty(object)?;
+        contents.push(b'\n');
+        Ok(contents)
+    }
+
+    fn deserialize(&self, contents: &[u8]) -> Result<OSMObject> {
+        Ok(serde_json::from_slice(contents)?)
+    }
+
+    fn deserialize_value(&self, contents: &[u8]) -> Result<serde_yaml::Value> {
+        Ok(serde_json::from_slice(contents)?)
+    }
+}
+
+struct TomlSerializer;
+
+impl Serializer for TomlSerializer {
+    fn extension(&self) -> &'static str {
+        "toml"
+    }
+
+    fn serialize(&self, object: &OSMObject) -> Result<Vec<u8>> {
+        Ok(toml::to_string(object)?.into_bytes())
+    }
+
+    fn deserialize(&self, contents: &[u8]) -> Result<OSMObject> {
+        Ok(toml::from_str(std::str::from_utf8(contents)?)?)
+    }
+
+    fn deserialize_value(&self, contents: &[u8]) -> Result<serde_yaml::Value> {
+        Ok(toml::from_str(std::str::from_utf8(contents)?)?)
+    }
+}
+
+struct CborSerializer;
+
+impl Serializer for CborSerializer {
+    fn extension(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn serialize(&self, object: &OSMObject) -> Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        ciborium::ser::into_writer(object, &mut contents)?;
+        Ok(contents)
+    }
+
+    fn deserialize(&self, contents: &[u8]) -> Result<OSMObject> {
+        Ok(ciborium::de::from_reader(contents)?)
+    }
+
+    fn deserialize_value(&self, contents: &[u8]) -> Result<serde_yaml::Value> {
+        Ok(ciborium::de::from_reader(contents)?)
+    }
+}
+
+/// One canonical `key=value` line per field, tag and relation member
+///
+/// The fields of the object are prefixed with `@` and the longer ones abbreviated, to
+/// keep them apart from the tags, which follow sorted by key. The nodes of a way are
+/// one `@nd=<id> <id> ...` line and relation members `@member=<type> <id> <role>` lines,
+/// in their order. Backslashes and line breaks are
+/// escaped with a backslash, as are `=` in keys and a leading `@` of keys. Without the
+/// quoting and indentation of YAML, blobs and packs are a lot smaller at planet scale,
+/// and every changed tag is still a one line diff.
+struct CompactSerializer;
+
+/// The fields of the compact format which are numbers, all others are strings
+const COMPACT_NUMBER_FIELDS: &[&str] = &["uid", "lat", "lon"];
+/// The abbreviations of the fields in the compact format
+const COMPACT_FIELD_NAMES: &[(&str, &str)] = &[
+    ("file_generator", "gen"),
+    ("file_version", "fv"),
+    ("legacy_object_version", "v"),
+    ("timestamp", "t"),
+];
+
+impl Serializer for CompactSerializer {
+    fn extension(&self) -> &'static str {
+        "txt"
+    }
+
+    fn serialize(&self, object: &OSMObject) -> Result<Vec<u8>> {
+        let serde_yaml::Value::Mapping(fields) = serde_yaml::to_value(object)? else {
+            return Err(eyre!("The object is not a map of fields"));
+        };
+        let mut contents = String::new();
+        let mut references = String::new();
+        let mut tags = String::new();
+        for (key, value) in fields {
+            let key = key.as_str().unwrap_or_default();
+            match (key, value) {
+                ("type", value) => push_compact_line(
+                    &mut contents,
+                    "@type",
+                    &value.as_str().unwrap_or_default().to_lowercase(),
+                ),
+                ("tags", serde_yaml::Value::Mapping(object_tags)) => {
+                    for (tag_key, tag_value) in object_tags {
+                        push_compact_line(
+                            &mut tags,
+                            &escape_compact_key(tag_key.as_str().unwrap_or_default()),
+                            tag_value.as_str().unwrap_or_default(),
+                        );
+                    }
+                }
+                ("nodes", serde_yaml::Value::Sequence(nodes)) => {
+                    let nodes = nodes
+                        .iter()
+                        .map(compact_scalar)
+                        .collect::<Vec<String>>()
+                        .join(" ");
+                    push_compact_line(&mut references, "@nd", &nodes);
+                }
+                ("member", serde_yaml::Value::Sequence(members)) => {
+                    for member in members {
+                        let mut line = format!(
+                            "{} {}",
+                            compact_scalar(&member["type"]),
+                            compact_scalar(&member["ref"])
+                        );
+                        if let Some(role) = member.get("role") {
+                            line.push(' ');
+                            line.push_str(&compact_scalar(role));
+                        }
+                        push_compact_line(&mut references, "@member", &line);
+                    }
+                }
+                (key, value) => {
+                    let key = COMPACT_FIELD_NAMES
+                        .iter()
+                        .find(|(name, _)| *name == key)
+                        .map_or(key, |(_, abbreviation)| abbreviation);
+                    push_compact_line(&mut contents, &format!("@{}", key), &compact_scalar(&value))
+                }
+            }
+        }
+        contents.push_str(&references);
+        contents.push_str(&tags);
+        Ok(contents.into_bytes())
+    }
+
+    fn deserialize(&self, contents: &[u8]) -> Result<OSMObject> {
+        Ok(serde_yaml::from_value(self.deserialize_value(contents)?)?)
+    }
+
+    fn deserialize_value(&self, contents: &[u8]) -> Result<serde_yaml::Value> {
+        let mut fields = serde_yaml::Mapping::new();
+        let mut nodes = Vec::new();
+        let mut members = Vec::new();
+        let mut tags = serde_yaml::Mapping::new();
+        for line in std::str::from_utf8(contents)?.lines() {
+            let (key, value) = split_compact_line(line)?;
+            let Some(field) = key.strip_prefix('@').filter(|_| !line.starts_with('\\')) else {
+                tags.insert(key.into(), value.into());
+                continue;
+            };
+            match field {
+                "type" => {
+                    let mut type_name = value.chars();
+                    let type_name = type_name
+                        .next()
+                        .map(|first| first.to_uppercase().chain(type_name).collect::<String>())
+                        .unwrap_or_default();
+                    fields.insert("type".into(), type_name.into());
+                }
+                "nd" => {
+                    for node in value.split(' ') {
+                        nodes.push(serde_yaml::Value::Number(node.parse::<u64>()?.into()));
+                    }
+                }
+                "member" => {
+                    let mut parts = value.splitn(3, ' ');
+                    let mut member = serde_yaml::Mapping::new();
+                    member.insert("type".into(), parts.next().unwrap_or_default().into());
+                    let ref_id = parts
+                        .next()
+                        .ok_or_else(|| eyre!("The member {:?} has no id", value))?
+                        .parse::<u64>()?;
+                    member.insert("ref".into(), serde_yaml::Value::Number(ref_id.into()));
+                    if let Some(role) = parts.next() {
+                        member.insert("role".into(), role.into());
+                    }
+                    members.push(serde_yaml::Value::Mapping(member));
+                }
+                "visible" => {
+                    fields.insert(field.into(), value.parse::<bool>()?.into());
+                }
+                field if COMPACT_NUMBER_FIELDS.contains(&field) => {
+                    let number = match value.parse::<u64>() {
+                        Ok(number) => serde_yaml::Number::from(number),
+                        Err(_) => serde_yaml::Number::from(value.parse::<f64>()?),
+                    };
+                    fields.insert(field.into(), serde_yaml::Value::Number(number));
+                }
+                field => {
+                    let field = COMPACT_FIELD_NAMES
+                        .iter()
+                        .find(|(_, abbreviation)| *abbreviation == field)
+                        .map_or(field, |(name, _)| name);
+                    fields.insert(field.into(), value.into());
+                }
+            }
+        }
+        if !tags.is_empty() {
+            fields.insert("tags".into(), serde_yaml::Value::Mapping(tags));
+        }
+        if !nodes.is_empty() {
+            fields.insert("nodes".into(), serde_yaml::Value::Sequence(nodes));
+        }
+        if !members.is_empty() {
+            fields.insert("member".into(), serde_yaml::Value::Sequence(members));
+        }
+        Ok(serde_yaml::Value::Mapping(fields))
+    }
+}
+
+/// A scalar of a serialized object as the text of a compact line
+fn compact_scalar(value: &serde_yaml::Value) -> String {
+    match value {
+        serde_yaml::Value::String(value) => value.clone(),
+        serde_yaml::Value::Number(number) => number.to_string(),
+        serde_yaml::Value::Bool(value) => value.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Append a line with an escaped key and the value escaped in turn
+fn push_compact_line(contents: &mut String, escaped_key: &str, value: &str) {
+    contents.push_str(escaped_key);
+    contents.push('=');
+    for c in value.chars() {
+        match c {
+            '\\' => contents.push_str("\\\\"),
+            '\n' => contents.push_str("\\n"),
+            '\r' => contents.push_str("\\r"),
+            c => contents.push(c),
+        }
+    }
+    contents.push('\n');
+}
+
+/// Escape a tag key, so it can't be mistaken for a field or end before its `=`
+fn escape_compact_key(key: &str) -> String {
+    let mut escaped = String::new();
+    for (index, c) in key.chars().enumerate() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '=' => escaped.push_str("\\="),
+            '@' if index == 0 => escaped.push_str("\\@"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Split a compact line into its unescaped key and value
+fn split_compact_line(line: &str) -> Result<(String, String)> {
+    let mut key = String::new();
+    let mut value = String::new();
+    let mut in_value = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        let target = if in_value { &mut value } else { &mut key };
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => target.push('\n'),
+                Some('r') => target.push('\r'),
+                Some(escaped) => target.push(escaped),
+                None => return Err(eyre!("The line {:?} ends with a backslash", line)),
+            },
+            '=' if !in_value => in_value = true,
+            c => target.push(c),
+        }
+    }
+    if !in_value {
+        return Err(eyre!("The line {:?} has no =", line));
+    }
+    Ok((key, value))
+}
+
+/// Settings for converting a replication file to git commits
+pub struct ConversionSettings<'a> {
+    /// The folder containing the changeset dumps
+    pub changesets_location: &'a str,
+    pub id_mapper: &'a dyn IdMapper,
+    pub layout: &'a Layout,
+    /// Who the commits are attributed to
+    pub identity_policy: IdentityPolicy,
+    /// The names and emails of the mappers
+    pub authors: &'a AuthorIdentity,
+    /// Squash changesets of the same user within this many seconds into one commit
+    pub squash_window: Option<i64>,
+    /// Whether a commit is made per changeset or per replication file
+    pub granularity: Granularity,
+    /// Add `Tag-Keys` trailers summarizing the tags of the changed objects
+    pub tag_key_summaries: bool,
+    /// Only keep the objects within this region
+    pub area: Option<&'a AreaFilter>,
+    /// Only keep the objects matching this filter and the objects they reference
+    pub tag_filter: Option<&'a TagFilter>,
+    /// Only keep the edits of these mappers
+    pub mappers: Option<&'a MapperFilter>,
+    /// Add the admin areas the changesets touch as trailers to the commit messages
+    pub admin_areas: Option<&'a AdminAreas>,
+    /// Report changes of watched objects
+    pub watchlist: Option<&'a Watchlist>,
+    /// Download changesets missing from the changeset dump from the OSM API
+    #[cfg(feature = "http")]
+    pub changeset_api: Option<&'a ChangesetApi>,
+    /// How many threads serialize objects. Commits are still created in the order of
+    /// the changesets
+    pub serialize_workers: usize,
+    /// How many bytes of serialized objects may wait in memory for earlier commits
+    /// before they are moved to disk
+    pub commit_buffer_size: usize,
+    /// Whether elements which can't be parsed stop the conversion or are skipped
+    pub parse_mode: ParseMode,
+}
+
+/// Handle an element of a replication file which could not be parsed
+///
+/// In strict mode the conversion stops. In lenient mode the element is skipped and
+/// recorded in the error report.
+fn unparsable_element(
+    err: color_eyre::Report,
+    object_type: &'static str,
+    element: &BytesStart,
+    settings: &ConversionSettings,
+    cursor: &ReplayCursor,
+    on_event: &mut dyn FnMut(ReplayEvent),
 ) -> Result<()> {
-    // If the file is empty we skip it
-    if data.is_empty() {
-        return Ok(());
+    if settings.parse_mode == ParseMode::Strict {
+        return Err(err.wrap_err(format!(
+            "Unable to parse a {} of sequence {}. Pass --lenient to skip it",
+            object_type, cursor.sequence
+        )));
     }
+    let id = match err.downcast_ref::<OsmParseError>() {
+        Some(parse_error) => parse_error.id().map(|id| id.to_string()),
+        None => element
+            .try_get_attribute("id")
+            .ok()
+            .flatten()
+            .map(|id| String::from_utf8_lossy(&id.value).into_owned()),
+    };
+    let error = format!("{:#}", err);
+    report_error(
+        ErrorKind::SkippedElement,
+        Some(&cursor.sequence),
+        &error,
+        &[b"<".as_slice(), &element[..], b">"].concat(),
+    );
+    on_event(ReplayEvent::ElementSkipped {
+        sequence: cursor.sequence.clone(),
+        object_type,
+        id,
+        error,
+    });
+    Ok(())
+}
 
-    // Decompress the changeset file
-    let mut data_reader = GzDecoder::new(data);
-    let mut file_data = String::new();
-    if let Err(e) = data_reader.read_to_string(&mut file_data) {
-        error!("Unable to decompress data file: {:?}. Moving on", e);
-        return Ok(());
+/// The changesets the objects of a replication file were created in
+fn changeset_ids(compressed_data: &[u8]) -> Result<Vec<u64>> {
+    let mut data = Reader::from_reader(BufReader::new(GzDecoder::new(compressed_data)));
+    let mut changesets = BTreeSet::new();
+    let mut buf = Vec::new();
+    loop {
+        match data.read_event_into(&mut buf)? {
+            Event::Start(ref element) | Event::Empty(ref element)
+                if matches!(element.name().as_ref(), b"node" | b"way" | b"relation") =>
+            {
+                if let Some(changeset) = element
+                    .try_get_attribute("changeset")?
+                    .and_then(|attribute| std::str::from_utf8(&attribute.value).ok()?.parse().ok())
+                {
+                    changesets.insert(changeset);
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
     }
-    debug!("Data file decompressed. Size: {}", file_data.len());
+    Ok(changesets.into_iter().collect())
+}
+
+/// Read the objects of an OSM XML document, like the answers of the OSM API
+pub fn read_osm_objects(document: &[u8], id_mapper: &dyn IdMapper) -> Result<Vec<OSMObject>> {
+    let mut data = Reader::from_reader(document);
+    data.expand_empty_elements(true);
+    let mut objects = Vec::new();
+    let mut buf = Vec::new();
+    loop {
+        match data.read_event_into(&mut buf)? {
+            Event::Start(ref element) if element.name() == QName(b"node") => {
+                objects.push(OSMObject::Node(Node::new_from_element(
+                    &mut data, element, id_mapper, None,
+                )?));
+            }
+            Event::Start(ref element) if element.name() == QName(b"way") => {
+                objects.push(OSMObject::Way(Way::new_from_element(
+                    &mut data, element, id_mapper, None,
+                )?));
+            }
+            Event::Start(ref element) if element.name() == QName(b"relation") => {
+                objects.push(OSMObject::Relation(Relation::new_from_element(
+                    &mut data, element, id_mapper, None,
+                )?));
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(objects)
+}
+
+/// The objects of a replication file referenced by the ways and relations matching a tag
+/// filter, including the nodes of ways referenced by matching relations
+///
+/// The file is parsed once more up front, as ways and relations come after the nodes
+/// they reference.
+fn referenced_by_matches(
+    compressed_data: &[u8],
+    tag_filter: &TagFilter,
+    id_mapper: &dyn IdMapper,
+) -> Result<HashSet<(&'static str, u64)>> {
+    let mut data = Reader::from_reader(BufReader::new(GzDecoder::new(compressed_data)));
+    data.expand_empty_elements(true);
+
+    let mut way_nodes = HashMap::new();
+    let mut referenced = HashSet::new();
+    let mut buf = Vec::new();
+    loop {
+        match data.read_event_into(&mut buf)? {
+            Event::Start(ref element) if element.name() == QName(b"way") => {
+                // Malformed elements are handled when the file is converted
+                if let Ok(way) = Way::new_from_element(&mut data, element, id_mapper, None) {
+                    if tag_filter.matches_tags("way", &way.tags) {
+                        referenced.extend(way.nodes.iter().map(|node| ("node", *node)));
+                    }
+                    way_nodes.insert(way.id, way.nodes);
+                }
+            }
+            Event::Start(ref element) if element.name() == QName(b"relation") => {
+                if let Ok(relation) =
+                    Relation::new_from_element(&mut data, element, id_mapper, None)
+                {
+                    if tag_filter.matches_tags("relation", &relation.tags) {
+                        for member in &relation.member {
+                            let type_name = match member.r#type.as_str() {
+                                "node" => "node",
+                                "way" => "way",
+                                _ => "relation",
+                            };
+                            referenced.insert((type_name, member.ref_id));
+                        }
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    let member_ways = referenced
+        .iter()
+        .filter(|(type_name, _)| *type_name == "way")
+        .filter_map(|(_, id)| way_nodes.get(id))
+        .flatten()
+        .map(|node| ("node", *node))
+        .collect::<Vec<(&'static str, u64)>>();
+    referenced.extend(member_ways);
+    Ok(referenced)
+}
+
+/// The trailer naming objects which were created or modified and deleted again by the
+/// changesets of a commit. Their last version is in the parent commit
+const TRANSIENT_OBJECT_TRAILER: &str = "Transient-Object";
+
+/// The objects of a replication file by the changesets which changed them
+pub struct ParsedFile {
+    pub created_or_modified: BTreeMap<u64, Vec<OSMObject>>,
+    pub deleted: BTreeMap<u64, Vec<OSMObject>>,
+    /// The type, id and changeset of the created objects, to tell creations from
+    /// modifications
+    pub created: BTreeSet<(&'static str, u64, u64)>,
+    /// The changesets loaded while selecting the objects
+    preloaded_changesets: Option<Vec<Changeset>>,
+}
+
+impl ParsedFile {
+    /// The ids of the changesets with objects in the file, sorted by id
+    ///
+    /// They are committed in the order of their time, see [`changeset_groups`].
+    pub fn changeset_ids(&self) -> Vec<u64> {
+        self.created_or_modified
+            .keys()
+            .chain(self.deleted.keys())
+            .copied()
+            .collect::<BTreeSet<u64>>()
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Convert a replication file into commits on the staging ref
+///
+/// The file is parsed, the metadata of its changesets is loaded and the changesets are
+/// committed in groups.
+pub fn convert_objects_to_git(
+    backend: &dyn GitBackend,
+    committer: &Signature,
+    compressed_data: &[u8],
+    settings: &ConversionSettings,
+    cursor: &mut ReplayCursor,
+    on_event: &mut dyn FnMut(ReplayEvent),
+) -> Result<Vec<AppliedChangeset>> {
+    let Some(mut parsed) =
+        parse_replication_file(backend, compressed_data, settings, cursor, on_event)?
+    else {
+        return Ok(Vec::new());
+    };
+
+    let changeset_list = parsed.changeset_ids();
+    let changesets = match parsed.preloaded_changesets.take() {
+        Some(changesets) => changesets
+            .into_iter()
+            .filter(|changeset| changeset_list.binary_search(&changeset.id).is_ok())
+            .collect(),
+        None => load_changesets(settings.changesets_location, &changeset_list)?,
+    };
+    #[cfg(feature = "http")]
+    let changesets = {
+        let mut changesets = changesets;
+        if let Some(changeset_api) = settings.changeset_api {
+            let missing = changeset_list
+                .iter()
+                .copied()
+                .filter(|changeset_id| !changesets.iter().any(|c| c.id == *changeset_id))
+                .collect::<Vec<u64>>();
+            if !missing.is_empty() {
+                changesets.extend(changeset_api.fetch(settings.changesets_location, &missing)?);
+            }
+        }
+        changesets
+    };
+
+    commit_changesets(
+        backend, committer, &parsed, changesets, settings, cursor, on_event,
+    )
+}
+
+/// Parse a replication file, writing its objects to the state store of the backend
+///
+/// # Returns
+///
+/// * `Result<Option<ParsedFile>>` - `None` if the file is empty or can't be decompressed
+pub fn parse_replication_file(
+    backend: &dyn GitBackend,
+    compressed_data: &[u8],
+    settings: &ConversionSettings,
+    cursor: &ReplayCursor,
+    on_event: &mut dyn FnMut(ReplayEvent),
+) -> Result<Option<ParsedFile>> {
+    let ConversionSettings {
+        changesets_location,
+        id_mapper,
+        layout,
+        ..
+    } = *settings;
 
     // If the file is empty we skip it
-    if file_data.is_empty() {
-        return Ok(());
+    if compressed_data.is_empty() {
+        return Ok(None);
+    }
+
+    // The file is parsed while it is decompressed, so it is never held in memory as a
+    // whole
+    let mut decompressed = BufReader::new(GzDecoder::new(compressed_data));
+    match decompressed.fill_buf() {
+        Err(e) => {
+            error!("Unable to decompress data file: {:?}. Moving on", e);
+            return Ok(None);
+        }
+        // If the file is empty we skip it
+        Ok([]) => return Ok(None),
+        Ok(_) => {}
     }
 
     info!("Parsing data file");
 
-    let mut data = Reader::from_str(&file_data);
+    let mut data = Reader::from_reader(decompressed);
 
     // == Handling empty elements ==
     // To simply our processing code
@@ -515,10 +1431,46 @@ pub fn convert_objects_to_git(
     //   <Text/>
     data.expand_empty_elements(true);
 
+    // Objects outside of the region, theme or mappers of a mirror are dropped, and with
+    // them changesets which only changed objects outside of it
+    let mut preloaded_changesets = None;
+    let mut selection = None;
+    if settings.area.is_some() || settings.tag_filter.is_some() || settings.mappers.is_some() {
+        let mut object_selection = ObjectSelection::new(backend, layout, id_mapper);
+        if let Some(area) = settings.area {
+            object_selection = object_selection.with_area(area);
+        }
+        if let Some(tag_filter) = settings.tag_filter {
+            let referenced = referenced_by_matches(compressed_data, tag_filter, id_mapper)?;
+            object_selection = object_selection.with_tags(tag_filter, referenced);
+        }
+        if let Some(mappers) = settings.mappers {
+            // Automated edits are only known from the changeset tags, so the changesets
+            // are loaded before the objects. Changesets missing from the dump are kept
+            let automated_changesets = if mappers.excludes_bots() {
+                let changesets =
+                    load_changesets(changesets_location, &changeset_ids(compressed_data)?)?;
+                let automated = changesets
+                    .iter()
+                    .filter(|changeset| is_automated(changeset))
+                    .map(|changeset| changeset.id)
+                    .collect();
+                preloaded_changesets = Some(changesets);
+                automated
+            } else {
+                HashSet::new()
+            };
+            object_selection = object_selection.with_mappers(mappers, automated_changesets);
+        }
+        selection = Some(object_selection);
+    }
+
     let mut buf = Vec::new();
     let mut skip_buf = Vec::new();
     let mut created_or_modified_objects_for_changeset = BTreeMap::new();
     let mut deleted_objects_for_changeset = BTreeMap::new();
+    // Objects created by each changeset, to tell creations from modifications
+    let mut created_objects_set = BTreeSet::new();
 
     loop {
         let event: Event = data.read_event_into(&mut buf)?;
@@ -541,43 +1493,46 @@ pub fn convert_objects_to_git(
                         if let Event::Start(ref e) = event {
                             let name = e.name();
                             if name == QName(b"node") {
-                                let node = Node::new_from_element(&mut data, e);
-                                match node {
+                                match Node::new_from_element(
+                                    &mut data,
+                                    e,
+                                    id_mapper,
+                                    Some(&cursor.sequence),
+                                ) {
                                     Ok(node) => created_objects.push(OSMObject::Node(node)),
-                                    Err(err) => {
-                                        error!(
-                                            "unable to read node element {:?}, utf8 error {:?}",
-                                            &e, err
-                                        );
-                                    }
+                                    Err(err) => unparsable_element(
+                                        err, "node", e, settings, cursor, on_event,
+                                    )?,
                                 }
                             } else if name == QName(b"way") {
-                                let way = Way::new_from_element(&mut data, e);
-                                match way {
+                                match Way::new_from_element(
+                                    &mut data,
+                                    e,
+                                    id_mapper,
+                                    Some(&cursor.sequence),
+                                ) {
                                     Ok(way) => created_objects.push(OSMObject::Way(way)),
-                                    Err(err) => {
-                                        error!(
-                                            "unable to read way element {:?}, utf8 error {:?}",
-                                            &e, err
-                                        );
-                                    }
+                                    Err(err) => unparsable_element(
+                                        err, "way", e, settings, cursor, on_event,
+                                    )?,
                                 }
                             } else if name == QName(b"relation") {
-                                let relation = Relation::new_from_element(&mut data, e);
-                                match relation {
+                                match Relation::new_from_element(
+                                    &mut data,
+                                    e,
+                                    id_mapper,
+                                    Some(&cursor.sequence),
+                                ) {
                                     Ok(relation) => {
                                         created_objects.push(OSMObject::Relation(relation))
                                     }
-                                    Err(err) => {
-                                        error!(
-                                            "unable to read relation element {:?}, utf8 error {:?}",
-                                            &e, err
-                                        );
-                                    }
+                                    Err(err) => unparsable_element(
+                                        err, "relation", e, settings, cursor, on_event,
+                                    )?,
                                 }
                             } else {
                                 warn!("Unexpected tag: {:?}", name);
-                                data.read_to_end(name)?;
+                                data.read_to_end_into(name, &mut Vec::new())?;
                             }
                         } else {
                             if let Event::Text(ref text) = event {
@@ -586,33 +1541,28 @@ pub fn convert_objects_to_git(
                                 }
                             }
                             warn!("Unexpected event in create: {:?}", event);
-                            // Write the data to file for debugging
-
-                            let mut file = std::fs::File::create("debug.xml")?;
-                            file.write_all(file_data.as_bytes())?;
-                            file.sync_all()?;
+                            report_error(
+                                ErrorKind::UnexpectedEvent,
+                                Some(&cursor.sequence),
+                                &format!("Unexpected event in create: {:?}", event),
+                                &skip_buf,
+                            );
                         }
                         skip_buf = Vec::new();
                     }
 
                     // write the objects to the git repo as yaml files
-                    let repository_folder = repository.path().parent().unwrap();
-                    // TODO: We should chunk the world and split it into folders... Otherwise good luck
                     for object in created_objects {
-                        let object_file_name = match object {
-                            OSMObject::Node(ref node) => format!("{}.yaml", node.id),
-                            OSMObject::Way(ref way) => format!("{}.yaml", way.id),
-                            OSMObject::Relation(ref relation) => format!("{}.yaml", relation.id),
-                        };
-                        let object_file_path = repository_folder.join(object_file_name);
-
-                        // We need to create the file
-                        let object_file = OpenOptions::new()
-                            .read(true)
-                            .write(true)
-                            .create(true)
-                            .open(&object_file_path)?;
-                        serde_yaml::to_writer(object_file, &object)?;
+                        on_event(ReplayEvent::ElementParsed {
+                            object_type: object.type_name(),
+                            id: object.id(),
+                        });
+                        if let Some(selection) = &mut selection {
+                            if !selection.keep(&object, false) {
+                                continue;
+                            }
+                        }
+                        backend.write_object(&layout.object_path(&object, id_mapper), &object)?;
 
                         // Add the object to the list of created objects for the changeset based on the changeset id
                         let changeset = match object {
@@ -620,6 +1570,7 @@ pub fn convert_objects_to_git(
                             OSMObject::Way(ref way) => way.changeset,
                             OSMObject::Relation(ref relation) => relation.changeset,
                         };
+                        created_objects_set.insert((object.type_name(), object.id(), changeset));
                         created_or_modified_objects_for_changeset
                             .entry(changeset)
                             .or_insert_with(Vec::new)
@@ -643,43 +1594,46 @@ pub fn convert_objects_to_git(
                         if let Event::Start(ref e) = event {
                             let name = e.name();
                             if name == QName(b"node") {
-                                let node = Node::new_from_element(&mut data, e);
-                                match node {
+                                match Node::new_from_element(
+                                    &mut data,
+                                    e,
+                                    id_mapper,
+                                    Some(&cursor.sequence),
+                                ) {
                                     Ok(node) => deleted_objects.push(OSMObject::Node(node)),
-                                    Err(err) => {
-                                        error!(
-                                            "unable to read node element {:?}, utf8 error {:?}",
-                                            &e, err
-                                        );
-                                    }
+                                    Err(err) => unparsable_element(
+                                        err, "node", e, settings, cursor, on_event,
+                                    )?,
                                 }
                             } else if name == QName(b"way") {
-                                let way = Way::new_from_element(&mut data, e);
-                                match way {
+                                match Way::new_from_element(
+                                    &mut data,
+                                    e,
+                                    id_mapper,
+                                    Some(&cursor.sequence),
+                                ) {
                                     Ok(way) => deleted_objects.push(OSMObject::Way(way)),
-                                    Err(err) => {
-                                        error!(
-                                            "unable to read way element {:?}, utf8 error {:?}",
-                                            &e, err
-                                        );
-                                    }
+                                    Err(err) => unparsable_element(
+                                        err, "way", e, settings, cursor, on_event,
+                                    )?,
                                 }
                             } else if name == QName(b"relation") {
-                                let relation = Relation::new_from_element(&mut data, e);
-                                match relation {
+                                match Relation::new_from_element(
+                                    &mut data,
+                                    e,
+                                    id_mapper,
+                                    Some(&cursor.sequence),
+                                ) {
                                     Ok(relation) => {
                                         deleted_objects.push(OSMObject::Relation(relation))
                                     }
-                                    Err(err) => {
-                                        error!(
-                                            "unable to read relation element {:?}, utf8 error {:?}",
-                                            &e, err
-                                        );
-                                    }
+                                    Err(err) => unparsable_element(
+                                        err, "relation", e, settings, cursor, on_event,
+                                    )?,
                                 }
                             } else {
                                 warn!("Unexpected tag: {:?}", name);
-                                data.read_to_end(name)?;
+                                data.read_to_end_into(name, &mut Vec::new())?;
                             }
                         } else {
                             if let Event::Text(ref text) = event {
@@ -687,90 +1641,48 @@ pub fn convert_objects_to_git(
                                     continue;
                                 }
                             }
-                            warn!("Unexpected event in create: {:?}", event);
-                            // Write the data to file for debugging
-
-                            let mut file = std::fs::File::create("debug.xml")?;
-                            file.write_all(file_data.as_bytes())?;
-                            file.sync_all()?;
+                            warn!("Unexpected event in modify: {:?}", event);
+                            report_error(
+                                ErrorKind::UnexpectedEvent,
+                                Some(&cursor.sequence),
+                                &format!("Unexpected event in modify: {:?}", event),
+                                &skip_buf,
+                            );
                         }
                         skip_buf = Vec::new();
                     }
 
                     // write the objects to the git repo as yaml files
-                    let repository_folder = repository.path().parent().unwrap();
                     for object in deleted_objects {
-                        let object_file_name = match object {
-                            OSMObject::Node(ref node) => format!("{}.yaml", node.id),
-                            OSMObject::Way(ref way) => format!("{}.yaml", way.id),
-                            OSMObject::Relation(ref relation) => format!("{}.yaml", relation.id),
-                        };
-                        let object_file_path = repository_folder.join(object_file_name);
-                        // Change the file according to the changeset
-
-                        // If we got the file we open it otherwise we create a new object
-                        if !object_file_path.exists() {
-                            // We need to create the file
-                            let object_file = OpenOptions::new()
-                                .read(true)
-                                .write(true)
-                                .create(true)
-                                .open(&object_file_path)?;
-                            serde_yaml::to_writer(object_file, &object)?;
-                        }
-                        let mut object_file =
-                            OpenOptions::new().read(true).open(&object_file_path)?;
-
-                        let mut file_object: OSMObject = serde_yaml::from_reader(&mut object_file)?;
-
-                        match object {
-                            OSMObject::Node(ref node) => {
-                                if let OSMObject::Node(ref mut file_node) = file_object {
-                                    file_node.changeset = node.changeset;
-                                    file_node.file_generator = node.file_generator.clone();
-                                    file_node.file_version = node.file_version.clone();
-                                    file_node.legacy_object_version =
-                                        node.legacy_object_version.clone();
-                                    file_node.lat = node.lat;
-                                    file_node.lon = node.lon;
-                                    file_node.tags = node.tags.clone();
-                                }
-                            }
-                            OSMObject::Way(ref way) => {
-                                if let OSMObject::Way(ref mut file_way) = file_object {
-                                    file_way.changeset = way.changeset;
-                                    file_way.file_generator = way.file_generator.clone();
-                                    file_way.file_version = way.file_version.clone();
-                                    file_way.legacy_object_version =
-                                        way.legacy_object_version.clone();
-                                    file_way.tags = way.tags.clone();
-                                    file_way.nodes = way.nodes.clone();
-                                }
-                            }
-                            OSMObject::Relation(ref relation) => {
-                                if let OSMObject::Relation(ref mut file_relation) = file_object {
-                                    file_relation.changeset = relation.changeset;
-                                    file_relation.file_generator = relation.file_generator.clone();
-                                    file_relation.file_version = relation.file_version.clone();
-                                    file_relation.legacy_object_version =
-                                        relation.legacy_object_version.clone();
-                                    file_relation.tags = relation.tags.clone();
-                                    file_relation.member = relation.member.clone();
-                                }
+                        on_event(ReplayEvent::ElementParsed {
+                            object_type: object.type_name(),
+                            id: object.id(),
+                        });
+                        if let Some(selection) = &mut selection {
+                            if !selection.keep(&object, false) {
+                                continue;
                             }
                         }
-                        let object_file = OpenOptions::new()
-                            .read(true)
-                            .write(true)
-                            .truncate(true)
-                            .open(object_file_path)?;
-                        serde_yaml::to_writer(object_file, &object)?;
+                        let object_path = layout.object_path(&object, id_mapper);
+                        // The stored object is read once and the modified one written once
+                        let stored = backend.read_object(&object_path)?;
                         // Add the object to the list of created objects for the changeset based on the changeset id
                         let changeset = match object {
                             OSMObject::Node(ref node) => node.changeset,
                             OSMObject::Way(ref way) => way.changeset,
                             OSMObject::Relation(ref relation) => relation.changeset,
                         };
+                        // Without a stored version, like for an object moving into the
+                        // area of a mirror, the object is created
+                        if stored.is_none() {
+                            created_objects_set.insert((
+                                object.type_name(),
+                                object.id(),
+                                changeset,
+                            ));
+                        }
+                        let object = OSMObject::modified(stored, object);
+                        backend.write_object(&object_path, &object)?;
 
                         created_or_modified_objects_for_changeset
                             .entry(changeset)
@@ -795,43 +1707,46 @@ pub fn convert_objects_to_git(
                         if let Event::Start(ref e) = event {
                             let name = e.name();
                             if name == QName(b"node") {
-                                let node = Node::new_from_element(&mut data, e);
-                                match node {
+                                match Node::new_from_element(
+                                    &mut data,
+                                    e,
+                                    id_mapper,
+                                    Some(&cursor.sequence),
+                                ) {
                                     Ok(node) => deleted_objects.push(OSMObject::Node(node)),
-                                    Err(err) => {
-                                        error!(
-                                            "unable to read node element {:?}, utf8 error {:?}",
-                                            &e, err
-                                        );
-                                    }
+                                    Err(err) => unparsable_element(
+                                        err, "node", e, settings, cursor, on_event,
+                                    )?,
                                 }
                             } else if name == QName(b"way") {
-                                let way = Way::new_from_element(&mut data, e);
-                                match way {
+                                match Way::new_from_element(
+                                    &mut data,
+                                    e,
+                                    id_mapper,
+                                    Some(&cursor.sequence),
+                                ) {
                                     Ok(way) => deleted_objects.push(OSMObject::Way(way)),
-                                    Err(err) => {
-                                        error!(
-                                            "unable to read way element {:?}, utf8 error {:?}",
-                                            &e, err
-                                        );
-                                    }
+                                    Err(err) => unparsable_element(
+                                        err, "way", e, settings, cursor, on_event,
+                                    )?,
                                 }
                             } else if name == QName(b"relation") {
-                                let relation = Relation::new_from_element(&mut data, e);
-                                match relation {
+                                match Relation::new_from_element(
+                                    &mut data,
+                                    e,
+                                    id_mapper,
+                                    Some(&cursor.sequence),
+                                ) {
                                     Ok(relation) => {
                                         deleted_objects.push(OSMObject::Relation(relation))
                                     }
-                                    Err(err) => {
-                                        error!(
-                                            "unable to read relation element {:?}, utf8 error {:?}",
-                                            &e, err
-                                        );
-                                    }
+                                    Err(err) => unparsable_element(
+                                        err, "relation", e, settings, cursor, on_event,
+                                    )?,
                                 }
                             } else {
                                 warn!("Unexpected tag: {:?}", name);
-                                data.read_to_end(name)?;
+                                data.read_to_end_into(name, &mut Vec::new())?;
                             }
                         } else {
                             if let Event::Text(ref text) = event {
@@ -839,30 +1754,29 @@ pub fn convert_objects_to_git(
                                     continue;
                                 }
                             }
-                            warn!("Unexpected event in create: {:?}", event);
-                            // Write the data to file for debugging
-
-                            let mut file = std::fs::File::create("debug.xml")?;
-                            file.write_all(file_data.as_bytes())?;
-                            file.sync_all()?;
+                            warn!("Unexpected event in delete: {:?}", event);
+                            report_error(
+                                ErrorKind::UnexpectedEvent,
+                                Some(&cursor.sequence),
+                                &format!("Unexpected event in delete: {:?}", event),
+                                &skip_buf,
+                            );
                         }
                         skip_buf = Vec::new();
                     }
 
                     // write the objects to the git repo as yaml files
-                    let repository_folder = repository.path().parent().unwrap();
                     for object in deleted_objects {
-                        let object_file_name = match object {
-                            OSMObject::Node(ref node) => format!("{}.yaml", node.id),
-                            OSMObject::Way(ref way) => format!("{}.yaml", way.id),
-                            OSMObject::Relation(ref relation) => format!("{}.yaml", relation.id),
-                        };
-                        let object_file_path = repository_folder.join(object_file_name);
-
-                        // Delete the file if it exists
-                        if object_file_path.exists() {
-                            std::fs::remove_file(object_file_path)?;
+                        on_event(ReplayEvent::ElementParsed {
+                            object_type: object.type_name(),
+                            id: object.id(),
+                        });
+                        if let Some(selection) = &mut selection {
+                            if !selection.keep(&object, true) {
+                                continue;
+                            }
                         }
+                        backend.remove_object(&layout.object_path(&object, id_mapper))?;
 
                         // Add the object to the list of created objects for the changeset based on the changeset id
                         let changeset = match object {
@@ -884,154 +1798,483 @@ pub fn convert_objects_to_git(
         buf = Vec::new();
     }
 
-    // For all the objects changed apply the changesets as commits
-    // Get changeset list from BTreeMaps
-    let changeset_list: Vec<u64> = created_or_modified_objects_for_changeset
-        .keys()
-        .chain(deleted_objects_for_changeset.keys())
-        .copied()
-        .collect();
-
-    // Find latest changeset file (highest number in filename after "changesets-" and before ".osm.zst")
-    let changeset_files = std::fs::read_dir(changesets_location)?;
-    let mut last_highest_id = 0;
-    let mut changeset_path = String::new();
-    for changeset_file in changeset_files {
-        // Delete all objects by id that are in deleted_objects_for_changeset from created_or_modified_objects_for_changeset
-        let deleted_ids: Vec<u64> = deleted_objects_for_changeset
-            .values()
-            .flatten()
-            .map(|object| object.id())
-            .collect();
-        created_or_modified_objects_for_changeset
-            .iter_mut()
-            .for_each(|(_, objects)| {
-                objects.retain(|object| !deleted_ids.contains(&object.id()));
-            });
+    Ok(Some(ParsedFile {
+        created_or_modified: created_or_modified_objects_for_changeset,
+        deleted: deleted_objects_for_changeset,
+        created: created_objects_set,
+        preloaded_changesets,
+    }))
+}
 
-        let changeset_file = changeset_file?;
-        let changeset_file_path = changeset_file.path();
-        let changeset_file_name = changeset_file_path.file_name().unwrap().to_str().unwrap();
-        let changeset_file_name = changeset_file_name.trim_end_matches(".osm.zst");
-        let changeset_file_name = changeset_file_name.trim_start_matches("changesets-");
-        let changeset_file_name = changeset_file_name.parse::<u64>();
-        if let Ok(changeset_file_name) = changeset_file_name {
-            if changeset_file_name > last_highest_id {
-                last_highest_id = changeset_file_name;
-                changeset_path = changeset_file_path.to_str().unwrap().to_string();
-            }
+/// Commit the changesets of a parsed replication file onto the staging ref
+///
+/// Changesets staged before an interruption, as recorded in the cursor, are not
+/// committed again.
+pub fn commit_changesets(
+    backend: &dyn GitBackend,
+    committer: &Signature,
+    parsed: &ParsedFile,
+    mut changesets: Vec<Changeset>,
+    settings: &ConversionSettings,
+    cursor: &mut ReplayCursor,
+    on_event: &mut dyn FnMut(ReplayEvent),
+) -> Result<Vec<AppliedChangeset>> {
+    let ConversionSettings {
+        id_mapper, layout, ..
+    } = *settings;
+    let created_or_modified_objects_for_changeset = &parsed.created_or_modified;
+    let deleted_objects_for_changeset = &parsed.deleted;
+    let changeset_list = parsed.changeset_ids();
+
+    info!("Generating commits for changesets");
+
+    // Changesets without a usable time are timed at their newest object, or at the time of
+    // the replay if their objects have no usable time either
+    let committed_at = OffsetDateTime::from_unix_timestamp(committer.when().seconds())?;
+    let object_time = |changeset_id: u64| {
+        created_or_modified_objects_for_changeset
+            .get(&changeset_id)
+            .into_iter()
+            .chain(deleted_objects_for_changeset.get(&changeset_id))
+            .flatten()
+            .filter_map(|object| parse_timestamp(object.timestamp()?).ok())
+            .max()
+            .unwrap_or(committed_at)
+    };
+    for changeset in &mut changesets {
+        if changeset.timestamp().is_err() {
+            changeset.retime(object_time(changeset.id))?;
         }
     }
 
-    let changeset_file = File::open(changeset_path)?;
-    let mut uncompressed_data = uncompress_changeset_file(changeset_file);
+    // Changesets missing from the dump are still committed, attributed to an unknown user
+    let placeholders = changeset_list
+        .iter()
+        .filter(|changeset_id| !changesets.iter().any(|c| c.id == **changeset_id))
+        .map(|changeset_id| {
+            warn!(
+                "Unable to find changeset {:?}, committing it without metadata",
+                changeset_id
+            );
+            Changeset::placeholder(*changeset_id, object_time(*changeset_id))
+        })
+        .collect::<Result<Vec<Changeset>>>()?;
+
+    let changeset_groups = changeset_groups(&changeset_list, &changesets, &placeholders, settings)?;
+
+    // Changesets staged before an interruption are not committed again
+    let staged_commits = changeset_groups
+        .iter()
+        .map(|changeset_group| {
+            changeset_group
+                .iter()
+                .map(|changeset| cursor.staged_commit(changeset.id))
+                .collect::<Option<Vec<Oid>>>()
+        })
+        .collect::<Vec<_>>();
+    let plans = changeset_groups
+        .iter()
+        .zip(&staged_commits)
+        .filter(|(_, staged)| staged.is_none())
+        .map(|(changeset_group, _)| {
+            plan_files(
+                changeset_group,
+                created_or_modified_objects_for_changeset,
+                deleted_objects_for_changeset,
+                layout,
+                id_mapper,
+            )
+        })
+        .collect::<Vec<FilePlan>>();
+
+    let mut applied_changesets = Vec::new();
+    std::thread::scope(|scope| -> Result<()> {
+        let serialized = serialize_in_background(
+            scope,
+            &plans,
+            settings.layout.serializer(),
+            settings.serialize_workers,
+        );
+        let mut buffer = OrderedBuffer::new(backend.spill_dir(), settings.commit_buffer_size);
+        let mut plans = plans.iter();
+
+        for (changeset_group, staged_commits) in changeset_groups.into_iter().zip(staged_commits) {
+            if let Some(staged_commits) = staged_commits {
+                debug!(
+                    "Changesets {:?} are already staged",
+                    changeset_group.iter().map(|c| c.id).collect::<Vec<u64>>()
+                );
+                for (changeset, commit) in changeset_group.into_iter().zip(staged_commits) {
+                    applied_changesets.push(AppliedChangeset {
+                        changeset: changeset.clone(),
+                        commit,
+                        metadata_missing: placeholders.contains(changeset),
+                    });
+                }
+                continue;
+            }
 
-    let changesets = parse_changeset(&mut uncompressed_data, &changeset_list)?;
+            // The commit is authored at the time of the last changeset of the group. The
+            // commit of a whole file has no single mapper, so it is the bot's
+            let identity_policy = match settings.granularity {
+                Granularity::Changeset => settings.identity_policy,
+                Granularity::File => IdentityPolicy::Bot,
+            };
+            let author = identity_policy.author(
+                changeset_group
+                    .last()
+                    .unwrap()
+                    .author_signature(settings.authors)?,
+                committer,
+            )?;
+            // Changesets without metadata, and the changesets of a whole file, only get
+            // their id as a trailer
+            let mut trailers = Vec::new();
+            for changeset in &changeset_group {
+                if placeholders.contains(changeset) || settings.granularity == Granularity::File {
+                    trailers.push(("Changeset-Id", changeset.id.to_string()));
+                } else {
+                    trailers.extend(changeset_trailers(changeset));
+                }
+            }
+            if settings.tag_key_summaries {
+                let changed_objects = changeset_group.iter().flat_map(|changeset| {
+                    let created_or_modified = created_or_modified_objects_for_changeset
+                        .get(&changeset.id)
+                        .into_iter()
+                        .flatten();
+                    let deleted = deleted_objects_for_changeset
+                        .get(&changeset.id)
+                        .into_iter()
+                        .flatten();
+                    created_or_modified.chain(deleted)
+                });
+                if let Some(counts) = tag_key_counts(changed_objects) {
+                    trailers.push((TAG_KEYS_TRAILER, counts));
+                }
+            }
+            if let Some(admin_areas) = settings.admin_areas {
+                trailers.extend(
+                    admin_areas
+                        .intersecting(&changeset_group)
+                        .into_iter()
+                        .map(|name| (ADMIN_AREA_TRAILER, name.to_string())),
+                );
+            }
+            let message = append_trailers(
+                match settings.granularity {
+                    Granularity::Changeset => commit_message(&changeset_group),
+                    Granularity::File => file_commit_message(&cursor.sequence, &changeset_group),
+                },
+                &trailers
+                    .iter()
+                    .map(|(key, value)| (*key, value.as_str()))
+                    .collect::<Vec<(&str, &str)>>(),
+            );
+
+            let transient_objects = &plans.next().unwrap().transient_objects;
+            let files = loop {
+                if let Some(files) = buffer.pop()? {
+                    break files;
+                }
+                let (index, files) = serialized.recv()?;
+                buffer.push(index, files?)?;
+            };
 
-    info!("Generating commits for changesets");
+            let oid = if transient_objects.is_empty() {
+                backend.commit_files(files.0, &message, &author, committer)?
+            } else {
+                debug!(
+                    "Changesets {:?} create and delete {} objects",
+                    changeset_group.iter().map(|c| c.id).collect::<Vec<u64>>(),
+                    transient_objects.len()
+                );
+                backend.commit_files(files.0, &message, &author, committer)?;
+                let trailers = transient_objects
+                    .values()
+                    .map(|object| format!("{}/{}", object.type_name(), object.id()))
+                    .collect::<Vec<String>>();
+                let message = append_trailers(
+                    message.clone(),
+                    &trailers
+                        .iter()
+                        .map(|object| (TRANSIENT_OBJECT_TRAILER, object.as_str()))
+                        .collect::<Vec<(&str, &str)>>(),
+                );
+                let deletions = transient_objects
+                    .keys()
+                    .map(|path| (path.clone(), None))
+                    .collect();
+                backend.commit_files(deletions, &message, &author, committer)?
+            };
 
-    for changeset_id in changeset_list {
-        // Find the changeset within the files of the cache
-        let changeset = find_changesets_in_cache(&changesets, changeset_id)?;
+            let changeset_ids = changeset_group
+                .iter()
+                .map(|changeset| changeset.id)
+                .collect::<Vec<u64>>();
+            backend.record_staged(cursor, changeset_ids.clone(), oid)?;
+            on_event(ReplayEvent::CommitCreated {
+                changeset_ids,
+                commit: oid,
+            });
+            if let Some(watchlist) = settings.watchlist {
+                for changeset in &changeset_group {
+                    for object in created_or_modified_objects_for_changeset
+                        .get(&changeset.id)
+                        .unwrap_or(&Vec::new())
+                    {
+                        let action = if parsed.created.contains(&(
+                            object.type_name(),
+                            object.id(),
+                            changeset.id,
+                        )) {
+                            "create"
+                        } else {
+                            "modify"
+                        };
+                        for watch_match in watchlist.matches(object, action, changeset.id, oid) {
+                            on_event(ReplayEvent::WatchMatched(watch_match));
+                        }
+                    }
+                    for object in deleted_objects_for_changeset
+                        .get(&changeset.id)
+                        .unwrap_or(&Vec::new())
+                    {
+                        for watch_match in watchlist.matches(object, "delete", changeset.id, oid) {
+                            on_event(ReplayEvent::WatchMatched(watch_match));
+                        }
+                    }
+                }
+            }
+            for changeset in changeset_group {
+                applied_changesets.push(AppliedChangeset {
+                    changeset: changeset.clone(),
+                    commit: oid,
+                    metadata_missing: placeholders.contains(changeset),
+                });
+            }
+        }
+        if buffer.spilled() > 0 {
+            debug!(
+                "{} commits waited on disk for earlier ones to be serialized",
+                buffer.spilled()
+            );
+        }
+        Ok(())
+    })?;
+    let written_files = created_or_modified_objects_for_changeset
+        .values()
+        .flatten()
+        .map(|object| layout.object_path(object, id_mapper))
+        .collect::<Vec<_>>();
+    backend.finish_commits(layout, &written_files)?;
+
+    Ok(applied_changesets)
+}
 
-        if changeset.is_none() {
-            warn!("Unable to find changeset {:?}", changeset_id);
+/// Split the changesets of a replication file into the groups committed together
+///
+/// The changesets are committed in the order of their time, which their commits are
+/// authored at: the time they were closed, or the newest timestamp of their objects if
+/// they have no usable time. Changesets of the same time are ordered by their ids.
+///
+/// Placeholders of changesets without metadata always get a commit of their own, so only
+/// the changesets between them are squashed. With [`Granularity::File`] all changesets
+/// are one group.
+pub fn changeset_groups<'c>(
+    changeset_list: &[u64],
+    changesets: &'c [Changeset],
+    placeholders: &'c [Changeset],
+    settings: &ConversionSettings,
+) -> Result<Vec<Vec<&'c Changeset>>> {
+    let mut ordered_changesets = changeset_list
+        .iter()
+        .map(
+            |changeset_id| match find_changesets_in_cache(changesets, *changeset_id)? {
+                Some(changeset) => Ok((changeset.timestamp()?, changeset, false)),
+                None => {
+                    let placeholder = placeholders
+                        .iter()
+                        .find(|c| c.id == *changeset_id)
+                        .ok_or_else(|| eyre!("Changeset {} has no placeholder", changeset_id))?;
+                    Ok((placeholder.timestamp()?, placeholder, true))
+                }
+            },
+        )
+        .collect::<Result<Vec<(OffsetDateTime, &Changeset, bool)>>>()?;
+    ordered_changesets.sort_by_key(|(time, changeset, _)| (*time, changeset.id));
+
+    let mut changeset_groups = Vec::new();
+    let mut found_changesets = Vec::new();
+    for (_, changeset, is_placeholder) in ordered_changesets {
+        if !is_placeholder {
+            found_changesets.push(changeset);
             continue;
         }
+        changeset_groups.extend(group_changesets(
+            std::mem::take(&mut found_changesets),
+            settings.squash_window,
+        )?);
+        changeset_groups.push(vec![changeset]);
+    }
+    changeset_groups.extend(group_changesets(found_changesets, settings.squash_window)?);
+    if settings.granularity == Granularity::File && changeset_groups.len() > 1 {
+        changeset_groups = vec![changeset_groups.into_iter().flatten().collect()];
+    }
 
-        if let Some(changeset) = changeset {
-            // Get comment tag if it exists and trim it
-            let comment = changeset
-                .tags
-                .get("comment")
-                .map(|s| s.trim())
-                .unwrap_or("");
-
-            // Parse changeset time (ISO 8601) to git time (seconds since epoch) with offset 0 (UTC) using `time`
-            let changeset_time = changeset
-                .closed_at
-                .clone()
-                .unwrap_or(changeset.created_at.clone());
-            let commit_time =
-                OffsetDateTime::parse(changeset_time.as_str(), &Iso8601::DEFAULT)?.unix_timestamp();
-
-            let author = git2::Signature::new(
-                &changeset.user,
-                &format!("{}@osm", changeset.user),
-                &Time::new(commit_time, 0),
-            )
-            .expect("Unable to create author signature");
+    Ok(changeset_groups)
+}
 
-            let repository_folder = repository.path().parent().unwrap();
+/// The objects a commit writes, with `None` for the files it removes
+struct FilePlan<'a> {
+    files: BTreeMap<PathBuf, Option<&'a OSMObject>>,
+    /// Objects which are written and deleted again within the group. They are kept for
+    /// an intermediate commit, so their creation is not lost
+    transient_objects: BTreeMap<PathBuf, &'a OSMObject>,
+}
 
-            let added_or_changed_files = created_or_modified_objects_for_changeset
-                .get(&changeset.id)
-                .unwrap_or(&Vec::new())
-                .iter()
-                .map(|object| match object {
-                    OSMObject::Node(ref node) => {
-                        repository_folder.join(format!("{}.yaml", node.id))
-                    }
-                    OSMObject::Way(ref way) => repository_folder.join(format!("{}.yaml", way.id)),
-                    OSMObject::Relation(ref relation) => {
-                        repository_folder.join(format!("{}.yaml", relation.id))
-                    }
-                })
-                .map(|path| path.to_string_lossy().to_string())
-                .collect::<Vec<String>>();
+/// Collect the files of a group of changesets. Later versions of an object within the
+/// group replace earlier ones
+fn plan_files<'a>(
+    changeset_group: &[&Changeset],
+    created_or_modified: &'a BTreeMap<u64, Vec<OSMObject>>,
+    deleted: &'a BTreeMap<u64, Vec<OSMObject>>,
+    layout: &Layout,
+    id_mapper: &dyn IdMapper,
+) -> FilePlan<'a> {
+    let mut files = BTreeMap::new();
+    let mut transient_objects = BTreeMap::new();
+    for changeset in changeset_group {
+        for object in created_or_modified.get(&changeset.id).into_iter().flatten() {
+            let path = layout.object_path(object, id_mapper);
+            transient_objects.remove(&path);
+            files.insert(path, Some(object));
+        }
+        for object in deleted.get(&changeset.id).into_iter().flatten() {
+            let path = layout.object_path(object, id_mapper);
+            match files.get(&path) {
+                Some(Some(_)) => {
+                    transient_objects.insert(path, object);
+                }
+                _ => {
+                    files.insert(path, None);
+                }
+            }
+        }
+    }
+    FilePlan {
+        files,
+        transient_objects,
+    }
+}
 
-            let removed_files = deleted_objects_for_changeset
-                .get(&changeset.id)
-                .unwrap_or(&Vec::new())
-                .iter()
-                .map(|object| match object {
-                    OSMObject::Node(ref node) => {
-                        repository_folder.join(format!("{}.yaml", node.id))
-                    }
-                    OSMObject::Way(ref way) => repository_folder.join(format!("{}.yaml", way.id)),
-                    OSMObject::Relation(ref relation) => {
-                        repository_folder.join(format!("{}.yaml", relation.id))
-                    }
-                })
-                .map(|path| path.to_string_lossy().to_string())
-                .collect::<Vec<String>>();
-
-            let oid = commit(
-                repository,
-                added_or_changed_files,
-                removed_files,
-                comment,
-                &author,
-                committer,
-            )?;
+/// The file contents of a commit, with `None` for the files it removes
+struct SerializedFiles(BTreeMap<PathBuf, Option<Vec<u8>>>);
 
-            // Convert tags to "Key: Value" strings separated by newlines for the note
-            let note = changeset
-                .tags
-                .iter()
-                .filter_map(|(key, value)| {
-                    if key.trim().is_empty() {
-                        None
-                    } else {
-                        Some((key, value))
-                    }
-                })
-                .map(|(key, value)| format!("{}: {}", key, value))
-                .collect::<Vec<String>>()
-                .join("\n");
+impl Spill for SerializedFiles {
+    fn size(&self) -> usize {
+        self.0
+            .iter()
+            .map(|(path, contents)| path.as_os_str().len() + contents.as_ref().map_or(0, Vec::len))
+            .sum()
+    }
 
-            // Add the id of the changeset to the note
-            let note = if note.is_empty() {
-                format!("Legacy Changeset ID: {}", changeset.id)
-            } else {
-                format!("Legacy Changeset ID: {}\n{}", changeset.id, note)
-            };
+    fn spill(&self, writer: &mut dyn Write) -> Result<()> {
+        writer.write_all(&(self.0.len() as u64).to_le_bytes())?;
+        for (path, contents) in &self.0 {
+            let path = path.to_string_lossy();
+            writer.write_all(&(path.len() as u64).to_le_bytes())?;
+            writer.write_all(path.as_bytes())?;
+            match contents {
+                Some(contents) => {
+                    writer.write_all(&[1])?;
+                    writer.write_all(&(contents.len() as u64).to_le_bytes())?;
+                    writer.write_all(contents)?;
+                }
+                None => writer.write_all(&[0])?,
+            }
+        }
+        Ok(())
+    }
 
-            repository.note(&author, committer, None, oid, &note, false)?;
+    fn unspill(reader: &mut dyn Read) -> Result<Self> {
+        fn read_bytes(reader: &mut dyn Read) -> Result<Vec<u8>> {
+            let mut length = [0; 8];
+            reader.read_exact(&mut length)?;
+            let mut bytes = vec![0; u64::from_le_bytes(length) as usize];
+            reader.read_exact(&mut bytes)?;
+            Ok(bytes)
         }
+
+        let mut count = [0; 8];
+        reader.read_exact(&mut count)?;
+        let mut files = BTreeMap::new();
+        for _ in 0..u64::from_le_bytes(count) {
+            let path = PathBuf::from(String::from_utf8(read_bytes(reader)?)?);
+            let mut present = [0];
+            reader.read_exact(&mut present)?;
+            let contents = match present {
+                [0] => None,
+                _ => Some(read_bytes(reader)?),
+            };
+            files.insert(path, contents);
+        }
+        Ok(SerializedFiles(files))
     }
+}
 
-    Ok(())
+/// Serialize the objects of the plans on `workers` threads
+///
+/// Plans are handed out in order, but finish in any order. The results are sent with the
+/// index of their plan, so they can be put back in order with an [`OrderedBuffer`]. The
+/// workers stop once the receiver is dropped.
+fn serialize_in_background<'scope>(
+    scope: &'scope Scope<'scope, '_>,
+    plans: &'scope [FilePlan<'scope>],
+    serializer: &'static dyn Serializer,
+    workers: usize,
+) -> Receiver<(usize, Result<SerializedFiles>)> {
+    let next_plan = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::sync_channel(workers.max(1) * 2);
+    for _ in 0..workers.max(1) {
+        let next_plan = next_plan.clone();
+        let sender = sender.clone();
+        scope.spawn(move || loop {
+            let index = next_plan.fetch_add(1, Ordering::Relaxed);
+            let Some(plan) = plans.get(index) else {
+                break;
+            };
+            let files = plan
+                .files
+                .iter()
+                .map(|(path, object)| {
+                    let contents = object
+                        .map(|object| serializer.serialize(object))
+                        .transpose()?;
+                    Ok((path.clone(), contents))
+                })
+                .collect::<Result<BTreeMap<_, _>>>()
+                .map(SerializedFiles);
+            if sender.send((index, files)).is_err() {
+                break;
+            }
+        });
+    }
+    receiver
+}
+
+/// Split changesets into the groups committed together, squashing them if a window is set
+fn group_changesets(
+    changesets: Vec<&Changeset>,
+    squash_window: Option<i64>,
+) -> Result<Vec<Vec<&Changeset>>> {
+    match squash_window {
+        Some(squash_window) => squash_changesets(changesets, squash_window),
+        None => Ok(changesets
+            .into_iter()
+            .map(|changeset| vec![changeset])
+            .collect()),
+    }
 }
 
 /// Scans the files in the cache folder and returns the requested changeset
@@ -1052,3 +2295,636 @@ fn find_changesets_in_cache(
 
     Ok(changeset)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+    use crate::{
+        git::{backend::MemoryBackend, testing::signature},
+        osm::{id_mapping::IdentityMapping, layout::ShardBudget},
+    };
+
+    fn layout() -> Layout {
+        Layout {
+            fan_out_depth: Some(1),
+            object_format: ObjectFormat::Yaml,
+            budget: ShardBudget {
+                max_entries: 10_000,
+                max_file_size: 1 << 20,
+            },
+        }
+    }
+
+    fn settings<'a>(layout: &'a Layout, authors: &'a AuthorIdentity) -> ConversionSettings<'a> {
+        ConversionSettings {
+            changesets_location: "",
+            id_mapper: &IdentityMapping,
+            layout,
+            identity_policy: IdentityPolicy::Mapper,
+            authors,
+            squash_window: None,
+            granularity: Granularity::Changeset,
+            tag_key_summaries: true,
+            area: None,
+            tag_filter: None,
+            mappers: None,
+            admin_areas: None,
+            watchlist: None,
+            #[cfg(feature = "http")]
+            changeset_api: None,
+            serialize_workers: 1,
+            commit_buffer_size: 1 << 20,
+            parse_mode: ParseMode::Strict,
+        }
+    }
+
+    fn gzip(osc: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(osc.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn node(id: u64, changeset: u64, tags: &[(&str, &str)]) -> OSMObject {
+        OSMObject::Node(Node {
+            id,
+            changeset,
+            file_generator: None,
+            file_version: "1".to_string(),
+            legacy_object_version: None,
+            timestamp: Some("2012-09-12T00:00:00Z".to_string()),
+            uid: Some(5),
+            user: Some("alice".to_string()),
+            visible: None,
+            lat: 51.5,
+            lon: -0.1,
+            tags: tags
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        })
+    }
+
+    fn changeset(id: u64, uid: u64, closed_at: &str) -> Changeset {
+        Changeset {
+            id,
+            created_at: closed_at.to_string(),
+            closed_at: Some(closed_at.to_string()),
+            open: false,
+            user: Some(format!("user{}", uid)),
+            uid: Some(uid),
+            min_lat: Some(51.0),
+            max_lat: Some(52.0),
+            min_lon: Some(-1.0),
+            max_lon: Some(0.0),
+            tags: HashMap::from([("comment".to_string(), format!("Changeset {}", id))]),
+        }
+    }
+
+    fn ids(groups: &[Vec<&Changeset>]) -> Vec<Vec<u64>> {
+        groups
+            .iter()
+            .map(|group| group.iter().map(|changeset| changeset.id).collect())
+            .collect()
+    }
+
+    const OSC: &str = r#"<?xml version='1.0' encoding='UTF-8'?>
+<osmChange version="0.6" generator="test">
+  <create>
+    <node id="2" version="1" timestamp="2012-09-12T00:00:00Z" uid="5" user="alice" changeset="100" lat="51.6" lon="-0.2">
+      <tag k="amenity" v="cafe"/>
+    </node>
+  </create>
+  <modify>
+    <node id="1" version="2" timestamp="2012-09-12T01:00:00Z" uid="6" user="bob" changeset="101" lat="51.55" lon="-0.1">
+      <tag k="amenity" v="pub"/>
+    </node>
+  </modify>
+  <delete>
+    <node id="3" version="2" timestamp="2012-09-12T02:00:00Z" uid="6" user="bob" changeset="102" lat="51.7" lon="-0.3"/>
+  </delete>
+</osmChange>
+"#;
+
+    #[test]
+    fn parses_creations_modifications_and_deletions() {
+        let layout = layout();
+        let authors = AuthorIdentity::default();
+        let settings = settings(&layout, &authors);
+        let backend = MemoryBackend::default()
+            .with_object(
+                &layout,
+                &IdentityMapping,
+                node(1, 90, &[("amenity", "cafe")]),
+            )
+            .with_object(&layout, &IdentityMapping, node(3, 90, &[]));
+
+        let parsed = parse_replication_file(
+            &backend,
+            &gzip(OSC),
+            &settings,
+            &ReplayCursor::new("000/000/001"),
+            &mut |_| {},
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(parsed.changeset_ids(), vec![100, 101, 102]);
+        assert_eq!(parsed.created_or_modified[&100][0].id(), 2);
+        assert_eq!(parsed.created_or_modified[&101][0].id(), 1);
+        assert_eq!(parsed.deleted[&102][0].id(), 3);
+        assert!(parsed.created.contains(&("node", 2, 100)));
+        assert!(!parsed.created.contains(&("node", 1, 101)));
+
+        let path = |id| layout.path("node", id, &IdentityMapping);
+        assert!(backend.object(&path(2)).is_some());
+        assert_eq!(
+            backend.object(&path(1)).unwrap().tags().get("amenity"),
+            Some(&"pub".to_string())
+        );
+        assert!(backend.object(&path(3)).is_none());
+    }
+
+    #[test]
+    fn modify_without_a_stored_object_is_a_create() {
+        let layout = layout();
+        let authors = AuthorIdentity::default();
+        let settings = settings(&layout, &authors);
+        let backend = MemoryBackend::default();
+
+        let parsed = parse_replication_file(
+            &backend,
+            &gzip(OSC),
+            &settings,
+            &ReplayCursor::new("000/000/001"),
+            &mut |_| {},
+        )
+        .unwrap()
+        .unwrap();
+
+        assert!(parsed.created.contains(&("node", 1, 101)));
+        let stored = backend
+            .object(&layout.path("node", 1, &IdentityMapping))
+            .unwrap();
+        assert_eq!(stored, parsed.created_or_modified[&101][0]);
+        assert_eq!(stored.mapper(), (Some("bob"), Some(6)));
+    }
+
+    #[test]
+    fn modify_keeps_the_fields_the_version_leaves_out() {
+        let layout = layout();
+        let authors = AuthorIdentity::default();
+        let settings = settings(&layout, &authors);
+        let mut stored = node(1, 90, &[("amenity", "cafe"), ("name", "Corner")]);
+        if let OSMObject::Node(node) = &mut stored {
+            node.file_generator = Some("osmium/1.16".to_string());
+            node.visible = Some(true);
+        }
+        let backend = MemoryBackend::default().with_object(&layout, &IdentityMapping, stored);
+
+        parse_replication_file(
+            &backend,
+            &gzip(OSC),
+            &settings,
+            &ReplayCursor::new("000/000/001"),
+            &mut |_| {},
+        )
+        .unwrap()
+        .unwrap();
+
+        let OSMObject::Node(modified) = backend
+            .object(&layout.path("node", 1, &IdentityMapping))
+            .unwrap()
+        else {
+            panic!("The node is no longer a node");
+        };
+        assert_eq!(modified.file_generator.as_deref(), Some("osmium/1.16"));
+        assert_eq!(modified.visible, Some(true));
+        assert_eq!(modified.changeset, 101);
+        assert_eq!(modified.legacy_object_version.as_deref(), Some("2"));
+        assert_eq!(modified.timestamp.as_deref(), Some("2012-09-12T01:00:00Z"));
+        assert_eq!(modified.user.as_deref(), Some("bob"));
+        assert_eq!(modified.lat, 51.55);
+        // The tags are replaced as a whole, removed tags don't survive
+        assert_eq!(
+            modified.tags,
+            BTreeMap::from([("amenity".to_string(), "pub".to_string())])
+        );
+    }
+
+    #[test]
+    fn anonymous_modify_replaces_the_mapper() {
+        let stored = node(1, 90, &[]);
+        let mut version = node(1, 91, &[]);
+        if let OSMObject::Node(node) = &mut version {
+            node.uid = None;
+            node.user = None;
+        }
+
+        let modified = OSMObject::modified(Some(stored), version);
+        assert_eq!(modified.mapper(), (None, None));
+        assert_eq!(modified.changeset(), 91);
+    }
+
+    #[test]
+    fn skips_empty_and_undecodable_files() {
+        let layout = layout();
+        let authors = AuthorIdentity::default();
+        let settings = settings(&layout, &authors);
+        let backend = MemoryBackend::default();
+        let cursor = ReplayCursor::new("000/000/001");
+
+        for data in [&b""[..], b"not gzip"] {
+            let parsed =
+                parse_replication_file(&backend, data, &settings, &cursor, &mut |_| {}).unwrap();
+            assert!(parsed.is_none());
+        }
+    }
+
+    #[test]
+    fn commits_every_changeset_of_a_file() {
+        let layout = layout();
+        let authors = AuthorIdentity::default();
+        let settings = settings(&layout, &authors);
+        let backend = MemoryBackend::default()
+            .with_object(
+                &layout,
+                &IdentityMapping,
+                node(1, 90, &[("amenity", "cafe")]),
+            )
+            .with_object(&layout, &IdentityMapping, node(3, 90, &[]));
+        let committer = signature();
+        let mut cursor = ReplayCursor::new("000/000/001");
+        let changesets = vec![
+            changeset(100, 5, "2012-09-12T00:10:00Z"),
+            changeset(101, 6, "2012-09-12T01:10:00Z"),
+        ];
+
+        let applied = convert_with_changesets(
+            &backend,
+            &committer,
+            OSC,
+            changesets,
+            &settings,
+            &mut cursor,
+        );
+
+        // The missing changeset 102 is committed as a placeholder
+        assert_eq!(
+            applied
+                .iter()
+                .map(|applied| (applied.changeset.id, applied.metadata_missing))
+                .collect::<Vec<_>>(),
+            vec![(100, false), (101, false), (102, true)]
+        );
+        let commits = backend.commits.borrow();
+        assert_eq!(commits.len(), 3);
+        assert!(commits[0].message.starts_with("Changeset 100"));
+        assert_eq!(commits[1].author, "user6");
+        let path = |id| layout.path("node", id, &IdentityMapping);
+        assert!(commits[0].tree.contains_key(&path(2)));
+        assert!(!commits[2].tree.contains_key(&path(3)));
+        assert_eq!(
+            cursor
+                .staged
+                .iter()
+                .map(|staged| staged.changesets.clone())
+                .collect::<Vec<_>>(),
+            vec![vec![100], vec![101], vec![102]]
+        );
+    }
+
+    #[test]
+    fn changesets_without_a_usable_time_are_timed_at_their_newest_object() {
+        let layout = layout();
+        let authors = AuthorIdentity::default();
+        let settings = settings(&layout, &authors);
+        let backend = MemoryBackend::default()
+            .with_object(&layout, &IdentityMapping, node(1, 90, &[]))
+            .with_object(&layout, &IdentityMapping, node(3, 90, &[]));
+        let mut garbled = changeset(100, 5, "2012-09-12T00:10:00Z");
+        garbled.created_at = "0000-00-00 00:00:00".to_string();
+        garbled.closed_at = None;
+        let changesets = vec![garbled, changeset(101, 6, "2012-09-12T01:10:00Z")];
+
+        let applied = convert_with_changesets(
+            &backend,
+            &signature(),
+            OSC,
+            changesets,
+            &settings,
+            &mut ReplayCursor::new("000/000/001"),
+        );
+
+        let times = applied
+            .iter()
+            .map(|applied| {
+                (
+                    applied.changeset.id,
+                    applied.changeset.timestamp().unwrap().unix_timestamp(),
+                )
+            })
+            .collect::<Vec<_>>();
+        // The placeholder of 102 is timed at its deletion, 101 keeps its own time
+        assert_eq!(
+            times,
+            vec![
+                (100, 1347408000),
+                (101, 1347408000 + 4200),
+                (102, 1347408000 + 7200)
+            ]
+        );
+    }
+
+    fn convert_with_changesets(
+        backend: &MemoryBackend,
+        committer: &Signature,
+        osc: &str,
+        changesets: Vec<Changeset>,
+        settings: &ConversionSettings,
+        cursor: &mut ReplayCursor,
+    ) -> Vec<AppliedChangeset> {
+        let parsed = parse_replication_file(backend, &gzip(osc), settings, cursor, &mut |_| {})
+            .unwrap()
+            .unwrap();
+        commit_changesets(
+            backend,
+            committer,
+            &parsed,
+            changesets,
+            settings,
+            cursor,
+            &mut |_| {},
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn groups_each_changeset_by_default() {
+        let layout = layout();
+        let authors = AuthorIdentity::default();
+        let settings = settings(&layout, &authors);
+        let changesets = vec![
+            changeset(1, 5, "2012-09-12T00:00:00Z"),
+            changeset(2, 5, "2012-09-12T00:01:00Z"),
+        ];
+
+        let groups = changeset_groups(&[1, 2], &changesets, &[], &settings).unwrap();
+        assert_eq!(ids(&groups), vec![vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn squashes_changesets_of_a_mapper_within_the_window() {
+        let layout = layout();
+        let authors = AuthorIdentity::default();
+        let mut settings = settings(&layout, &authors);
+        settings.squash_window = Some(300);
+        let changesets = vec![
+            changeset(1, 5, "2012-09-12T00:00:00Z"),
+            changeset(2, 5, "2012-09-12T00:04:00Z"),
+            changeset(3, 6, "2012-09-12T00:05:00Z"),
+            changeset(4, 6, "2012-09-12T01:00:00Z"),
+        ];
+
+        let groups = changeset_groups(&[1, 2, 3, 4], &changesets, &[], &settings).unwrap();
+        assert_eq!(ids(&groups), vec![vec![1, 2], vec![3], vec![4]]);
+    }
+
+    #[test]
+    fn commits_changesets_in_the_order_of_their_time() {
+        let layout = layout();
+        let authors = AuthorIdentity::default();
+        let settings = settings(&layout, &authors);
+        let changesets = vec![
+            changeset(1, 5, "2012-09-12T00:03:00Z"),
+            changeset(2, 5, "2012-09-12T00:01:00Z"),
+            changeset(3, 6, "2012-09-12T00:01:00Z"),
+        ];
+        // Timed at the newest of its objects
+        let placeholders = vec![Changeset::placeholder(
+            4,
+            OffsetDateTime::from_unix_timestamp(1347408120).unwrap(),
+        )
+        .unwrap()];
+
+        let groups =
+            changeset_groups(&[1, 2, 3, 4], &changesets, &placeholders, &settings).unwrap();
+        assert_eq!(ids(&groups), vec![vec![2], vec![3], vec![4], vec![1]]);
+    }
+
+    #[test]
+    fn squashes_changesets_in_the_order_of_their_time() {
+        let layout = layout();
+        let authors = AuthorIdentity::default();
+        let mut settings = settings(&layout, &authors);
+        settings.squash_window = Some(300);
+        // Another mapper closed changeset 3 between the changesets 1 and 2
+        let changesets = vec![
+            changeset(1, 5, "2012-09-12T00:00:00Z"),
+            changeset(2, 5, "2012-09-12T00:04:00Z"),
+            changeset(3, 6, "2012-09-12T00:02:00Z"),
+        ];
+
+        let groups = changeset_groups(&[1, 2, 3], &changesets, &[], &settings).unwrap();
+        assert_eq!(ids(&groups), vec![vec![1], vec![3], vec![2]]);
+    }
+
+    #[test]
+    fn placeholders_are_never_squashed() {
+        let layout = layout();
+        let authors = AuthorIdentity::default();
+        let mut settings = settings(&layout, &authors);
+        settings.squash_window = Some(300);
+        let changesets = vec![
+            changeset(1, 5, "2012-09-12T00:00:00Z"),
+            changeset(3, 5, "2012-09-12T00:02:00Z"),
+        ];
+        let placeholders = vec![Changeset::placeholder(
+            2,
+            OffsetDateTime::from_unix_timestamp(1347408060).unwrap(),
+        )
+        .unwrap()];
+
+        let groups = changeset_groups(&[1, 2, 3], &changesets, &placeholders, &settings).unwrap();
+        assert_eq!(ids(&groups), vec![vec![1], vec![2], vec![3]]);
+    }
+
+    #[test]
+    fn file_granularity_makes_one_group() {
+        let layout = layout();
+        let authors = AuthorIdentity::default();
+        let mut settings = settings(&layout, &authors);
+        settings.granularity = Granularity::File;
+        let changesets = vec![
+            changeset(1, 5, "2012-09-12T00:00:00Z"),
+            changeset(3, 6, "2012-09-12T00:02:00Z"),
+        ];
+        let placeholders = vec![Changeset::placeholder(
+            2,
+            OffsetDateTime::from_unix_timestamp(1347408060).unwrap(),
+        )
+        .unwrap()];
+
+        let groups = changeset_groups(&[1, 2, 3], &changesets, &placeholders, &settings).unwrap();
+        assert_eq!(ids(&groups), vec![vec![1, 2, 3]]);
+    }
+
+    /// Objects with values which are easy to get wrong: tags which look like other YAML
+    /// types, line breaks, escapes, unicode and coordinates with float noise
+    fn tricky_objects() -> Vec<OSMObject> {
+        let tags = [
+            ("name", "Caf\u{e9} \"Zur Linde\" \u{1f333}"),
+            ("note", "first line\nsecond line\r\nthird line"),
+            ("description", "  leading and trailing spaces  "),
+            ("opening_hours", "Mo-Fr 08:00-18:00; Sa 10:00-14:00"),
+            ("fixme", "yes"),
+            ("ele", "0012"),
+            ("layer", "-1"),
+            ("source", "null"),
+            ("wikipedia", "de:K\u{f6}ln"),
+            ("path", "C:\\temp\\"),
+            ("@odd", "a=b"),
+            ("key=with=equals", "~"),
+            ("empty", ""),
+        ];
+        let mut node = node(1, 100, &tags);
+        if let OSMObject::Node(node) = &mut node {
+            // Like the coordinates computed from the nanodegrees of PBF files
+            node.lat = 51.5 + 1e-12;
+            node.lon = -0.0;
+            node.legacy_object_version = Some("3".to_string());
+            node.visible = Some(true);
+        }
+        let way = OSMObject::Way(Way {
+            id: 2,
+            changeset: 100,
+            file_generator: Some("osmium/1.16".to_string()),
+            file_version: FILE_VERSION.to_string(),
+            legacy_object_version: Some("1".to_string()),
+            timestamp: Some("2012-09-12T00:00:00Z".to_string()),
+            uid: None,
+            user: None,
+            visible: None,
+            tags: BTreeMap::from([("highway".to_string(), "residential".to_string())]),
+            nodes: vec![1, 3, 1],
+        });
+        let relation = OSMObject::Relation(Relation {
+            id: 3,
+            changeset: 100,
+            file_generator: None,
+            file_version: FILE_VERSION.to_string(),
+            legacy_object_version: Some("7".to_string()),
+            timestamp: Some("2012-09-12T00:00:00Z".to_string()),
+            uid: Some(5),
+            user: Some("alice".to_string()),
+            visible: Some(false),
+            tags: BTreeMap::new(),
+            member: vec![
+                RelationMember {
+                    r#type: "way".to_string(),
+                    ref_id: 2,
+                    role: Some("outer".to_string()),
+                },
+                RelationMember {
+                    r#type: "node".to_string(),
+                    ref_id: 1,
+                    role: Some(String::new()),
+                },
+                RelationMember {
+                    r#type: "relation".to_string(),
+                    ref_id: 4,
+                    role: None,
+                },
+            ],
+        });
+        vec![node, way, relation, node_without_tags()]
+    }
+
+    fn node_without_tags() -> OSMObject {
+        node(4, 100, &[])
+    }
+
+    #[test]
+    fn every_format_round_trips_byte_for_byte() {
+        for format in ObjectFormat::value_variants() {
+            let serializer = format.serializer();
+            for object in tricky_objects() {
+                let contents = serializer.serialize(&object).unwrap();
+                let read = serializer.deserialize(&contents).unwrap();
+                assert_eq!(
+                    serializer.serialize(&read).unwrap(),
+                    contents,
+                    "{} changes when {} is serialized again",
+                    format,
+                    object.type_name()
+                );
+                assert!(serializer.is_canonical(&contents).unwrap());
+                // The id and changeset are not part of the file
+                assert_eq!(read.tags(), object.tags());
+            }
+        }
+    }
+
+    #[test]
+    fn coordinates_are_rounded_to_the_database_precision() {
+        for format in ObjectFormat::value_variants() {
+            let serializer = format.serializer();
+            let object = &tricky_objects()[0];
+            let OSMObject::Node(read) = serializer
+                .deserialize(&serializer.serialize(object).unwrap())
+                .unwrap()
+            else {
+                panic!("{} turned a node into another type", format);
+            };
+            assert_eq!((read.lat, read.lon), (51.5, 0.0), "{}", format);
+            assert!(read.lon.is_sign_positive(), "{}", format);
+        }
+    }
+
+    #[test]
+    fn text_formats_use_unix_line_endings() {
+        for format in ObjectFormat::value_variants() {
+            if *format == ObjectFormat::Cbor {
+                continue;
+            }
+            let serializer = format.serializer();
+            for object in tricky_objects() {
+                let contents = serializer.serialize(&object).unwrap();
+                // Line breaks within tags are escaped, so no carriage return is left
+                assert!(!contents.contains(&b'\r'), "{} has a \\r", format);
+                assert!(contents.ends_with(b"\n"), "{} misses the last \\n", format);
+                assert!(
+                    !contents.ends_with(b"\n\n"),
+                    "{} ends with an empty line",
+                    format
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn files_with_windows_line_endings_are_not_canonical() {
+        for format in ObjectFormat::value_variants() {
+            if *format == ObjectFormat::Cbor {
+                continue;
+            }
+            let serializer = format.serializer();
+            for object in tricky_objects() {
+                let contents = serializer.serialize(&object).unwrap();
+                let windows = String::from_utf8(contents.clone())
+                    .unwrap()
+                    .replace('\n', "\r\n")
+                    .into_bytes();
+                assert!(!serializer.is_canonical(&windows).unwrap(), "{}", format);
+                // Rewriting the file normalizes it
+                let rewritten = serializer
+                    .serialize(&serializer.deserialize(&windows).unwrap())
+                    .unwrap();
+                assert_eq!(rewritten, contents, "{}", format);
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::{collections::BTreeMap, fmt::Display, io::BufRead, str::FromStr};
+
+use color_eyre::eyre::Result;
+use quick_xml::{events::BytesStart, Reader};
+
+/// A malformed element of an OSM XML file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OsmParseError {
+    /// A required attribute is missing
+    MissingAttribute {
+        element: &'static str,
+        id: Option<String>,
+        attribute: &'static str,
+    },
+    /// An attribute doesn't hold a value of the expected type
+    InvalidAttribute {
+        element: &'static str,
+        id: Option<String>,
+        attribute: &'static str,
+        value: String,
+    },
+    /// A node of a way or a member of a relation has an invalid ref
+    InvalidReference {
+        element: &'static str,
+        id: Option<String>,
+        value: String,
+    },
+}
+
+impl OsmParseError {
+    /// The type of the malformed element, like `node`
+    pub fn element(&self) -> &'static str {
+        match self {
+            OsmParseError::MissingAttribute { element, .. }
+            | OsmParseError::InvalidAttribute { element, .. }
+            | OsmParseError::InvalidReference { element, .. } => element,
+        }
+    }
+
+    /// The id of the malformed element, as given in the file
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            OsmParseError::MissingAttribute { id, .. }
+            | OsmParseError::InvalidAttribute { id, .. }
+            | OsmParseError::InvalidReference { id, .. } => id.as_deref(),
+        }
+    }
+}
+
+impl Display for OsmParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.element())?;
+        if let Some(id) = self.id() {
+            write!(f, " {}", id)?;
+        }
+        match self {
+            OsmParseError::MissingAttribute { attribute, .. } => {
+                write!(f, " has no {} attribute", attribute)
+            }
+            OsmParseError::InvalidAttribute {
+                attribute, value, ..
+            } => write!(f, " has an invalid {} {:?}", attribute, value),
+            OsmParseError::InvalidReference { value, .. } => {
+                write!(f, " references an invalid id {:?}", value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for OsmParseError {}
+
+/// Parse a required attribute of an element
+pub(crate) fn required_attribute<T: FromStr>(
+    attributes: &BTreeMap<String, String>,
+    element: &'static str,
+    attribute: &'static str,
+) -> Result<T, OsmParseError> {
+    let id = || attributes.get("id").cloned();
+    let value = attributes
+        .get(attribute)
+        .ok_or_else(|| OsmParseError::MissingAttribute {
+            element,
+            id: id(),
+            attribute,
+        })?;
+    value.parse().map_err(|_| OsmParseError::InvalidAttribute {
+        element,
+        id: id(),
+        attribute,
+        value: value.to_string(),
+    })
+}
+
+/// Skip the rest of a malformed element, so the reader continues after it
+pub(crate) fn skip_malformed<R: BufRead, T>(
+    reader: &mut Reader<R>,
+    element: &BytesStart,
+    error: OsmParseError,
+) -> Result<T> {
+    reader.read_to_end_into(element.name(), &mut Vec::new())?;
+    Err(error.into())
+}
+
+/// What happens to elements of a replication file which can't be parsed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Stop the replay, so the git repo never misses an object
+    #[default]
+    Strict,
+    /// Skip the element and record it in the error report
+    Lenient,
+}
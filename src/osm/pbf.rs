@@ -0,0 +1,1131 @@
+use std::{
+    collections::BTreeMap,
+    io::{ErrorKind, Read},
+    path::PathBuf,
+};
+
+use color_eyre::eyre::{eyre, Result};
+use flate2::read::ZlibDecoder;
+use git2::{Repository, Signature};
+use prost::Message;
+use rusqlite::Connection;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::info;
+
+use super::{
+    changesets::{load_changesets, Changeset},
+    id_mapping::IdMapper,
+    layout::Layout,
+    osm_data::{Node, OSMObject, Relation, RelationMember, Way, FILE_VERSION},
+    squash::{
+        append_trailers, changeset_trailers, commit_message, tag_key_counts, TAG_KEYS_TRAILER,
+    },
+    state_store::StateStore,
+};
+use crate::{
+    git::{
+        identity::{AuthorIdentity, IdentityPolicy},
+        publish_staging, write_state, STAGING_REF,
+    },
+    replication::{feed_base, Interval, SequenceNumber},
+};
+
+/// The largest blob header the format allows
+const MAX_BLOB_HEADER_SIZE: u32 = 64 * 1024;
+
+/// The largest blob the format allows
+const MAX_BLOB_SIZE: i32 = 32 * 1024 * 1024;
+
+/// The features of the file format which are understood by the reader
+const SUPPORTED_FEATURES: &[&str] = &["OsmSchema-V0.6", "DenseNodes", HISTORY_FEATURE];
+
+/// The feature of files with every version of the objects, like `history-latest.osm.pbf`
+const HISTORY_FEATURE: &str = "HistoricalInformation";
+
+/// The file in the git dir the versions of a history file are sorted by changeset in
+const HISTORY_IMPORT_FILE: &str = "osm-git-history-import.sqlite";
+
+/// How many changesets are looked up in the changeset dump at once
+const CHANGESETS_PER_LOOKUP: usize = 1000;
+
+/// The messages of the OSM PBF format, as defined in `fileformat.proto` and
+/// `osmformat.proto` of the format
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct BlobHeader {
+        #[prost(string, required, tag = "1")]
+        pub r#type: String,
+        #[prost(int32, required, tag = "3")]
+        pub datasize: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Blob {
+        #[prost(bytes = "vec", optional, tag = "1")]
+        pub raw: Option<Vec<u8>>,
+        #[prost(int32, optional, tag = "2")]
+        pub raw_size: Option<i32>,
+        #[prost(bytes = "vec", optional, tag = "3")]
+        pub zlib_data: Option<Vec<u8>>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HeaderBlock {
+        #[prost(string, repeated, tag = "4")]
+        pub required_features: Vec<String>,
+        #[prost(string, optional, tag = "16")]
+        pub writingprogram: Option<String>,
+        #[prost(int64, optional, tag = "32")]
+        pub osmosis_replication_timestamp: Option<i64>,
+        #[prost(int64, optional, tag = "33")]
+        pub osmosis_replication_sequence_number: Option<i64>,
+        #[prost(string, optional, tag = "34")]
+        pub osmosis_replication_base_url: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PrimitiveBlock {
+        #[prost(message, required, tag = "1")]
+        pub stringtable: StringTable,
+        #[prost(message, repeated, tag = "2")]
+        pub primitivegroup: Vec<PrimitiveGroup>,
+        #[prost(int32, optional, tag = "17", default = "100")]
+        pub granularity: Option<i32>,
+        #[prost(int64, optional, tag = "19", default = "0")]
+        pub lat_offset: Option<i64>,
+        #[prost(int64, optional, tag = "20", default = "0")]
+        pub lon_offset: Option<i64>,
+        #[prost(int32, optional, tag = "18", default = "1000")]
+        pub date_granularity: Option<i32>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StringTable {
+        #[prost(bytes = "vec", repeated, tag = "1")]
+        pub s: Vec<Vec<u8>>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PrimitiveGroup {
+        #[prost(message, repeated, tag = "1")]
+        pub nodes: Vec<Node>,
+        #[prost(message, optional, tag = "2")]
+        pub dense: Option<DenseNodes>,
+        #[prost(message, repeated, tag = "3")]
+        pub ways: Vec<Way>,
+        #[prost(message, repeated, tag = "4")]
+        pub relations: Vec<Relation>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Info {
+        #[prost(int32, optional, tag = "1", default = "-1")]
+        pub version: Option<i32>,
+        #[prost(int64, optional, tag = "2")]
+        pub timestamp: Option<i64>,
+        #[prost(int64, optional, tag = "3")]
+        pub changeset: Option<i64>,
+        #[prost(int32, optional, tag = "4")]
+        pub uid: Option<i32>,
+        #[prost(uint32, optional, tag = "5")]
+        pub user_sid: Option<u32>,
+        #[prost(bool, optional, tag = "6")]
+        pub visible: Option<bool>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DenseInfo {
+        #[prost(int32, repeated, tag = "1")]
+        pub version: Vec<i32>,
+        #[prost(sint64, repeated, tag = "2")]
+        pub timestamp: Vec<i64>,
+        #[prost(sint64, repeated, tag = "3")]
+        pub changeset: Vec<i64>,
+        #[prost(sint32, repeated, tag = "4")]
+        pub uid: Vec<i32>,
+        #[prost(sint32, repeated, tag = "5")]
+        pub user_sid: Vec<i32>,
+        #[prost(bool, repeated, tag = "6")]
+        pub visible: Vec<bool>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Node {
+        #[prost(sint64, required, tag = "1")]
+        pub id: i64,
+        #[prost(uint32, repeated, tag = "2")]
+        pub keys: Vec<u32>,
+        #[prost(uint32, repeated, tag = "3")]
+        pub vals: Vec<u32>,
+        #[prost(message, optional, tag = "4")]
+        pub info: Option<Info>,
+        #[prost(sint64, required, tag = "8")]
+        pub lat: i64,
+        #[prost(sint64, required, tag = "9")]
+        pub lon: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DenseNodes {
+        #[prost(sint64, repeated, tag = "1")]
+        pub id: Vec<i64>,
+        #[prost(message, optional, tag = "5")]
+        pub denseinfo: Option<DenseInfo>,
+        #[prost(sint64, repeated, tag = "8")]
+        pub lat: Vec<i64>,
+        #[prost(sint64, repeated, tag = "9")]
+        pub lon: Vec<i64>,
+        #[prost(int32, repeated, tag = "10")]
+        pub keys_vals: Vec<i32>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Way {
+        #[prost(int64, required, tag = "1")]
+        pub id: i64,
+        #[prost(uint32, repeated, tag = "2")]
+        pub keys: Vec<u32>,
+        #[prost(uint32, repeated, tag = "3")]
+        pub vals: Vec<u32>,
+        #[prost(message, optional, tag = "4")]
+        pub info: Option<Info>,
+        #[prost(sint64, repeated, tag = "8")]
+        pub refs: Vec<i64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Relation {
+        #[prost(int64, required, tag = "1")]
+        pub id: i64,
+        #[prost(uint32, repeated, tag = "2")]
+        pub keys: Vec<u32>,
+        #[prost(uint32, repeated, tag = "3")]
+        pub vals: Vec<u32>,
+        #[prost(message, optional, tag = "4")]
+        pub info: Option<Info>,
+        #[prost(int32, repeated, tag = "8")]
+        pub roles_sid: Vec<i32>,
+        #[prost(sint64, repeated, tag = "9")]
+        pub memids: Vec<i64>,
+        #[prost(int32, repeated, tag = "10")]
+        pub types: Vec<i32>,
+    }
+}
+
+/// What the header of a PBF file says about its contents
+#[derive(Debug, Clone)]
+pub struct PbfHeader {
+    pub writing_program: Option<String>,
+    /// The file has every version of the objects instead of only the current ones
+    pub historical: bool,
+    /// The replication feed the file can be updated from
+    pub replication_base_url: Option<String>,
+    /// The last sequence of the replication feed included in the file
+    pub replication_sequence: Option<SequenceNumber>,
+    pub replication_timestamp: Option<OffsetDateTime>,
+}
+
+/// Reads the objects of an OSM PBF file, like a planet file or a Geofabrik extract
+///
+/// The file is read one block of up to 8000 objects at a time, so files of any size can
+/// be read. Blocks compressed with zlib or stored uncompressed are supported, which is
+/// what planet files and extracts use.
+pub struct PbfReader<R: Read> {
+    reader: R,
+    header: PbfHeader,
+}
+
+impl<R: Read> PbfReader<R> {
+    /// Read the header of the file
+    pub fn new(mut reader: R) -> Result<Self> {
+        let (blob_type, data) =
+            read_blob(&mut reader)?.ok_or_else(|| eyre!("The PBF file is empty"))?;
+        if blob_type != "OSMHeader" {
+            return Err(eyre!(
+                "The PBF file starts with a {} block instead of its header",
+                blob_type
+            ));
+        }
+        let header = proto::HeaderBlock::decode(data.as_slice())?;
+        if let Some(feature) = header
+            .required_features
+            .iter()
+            .find(|feature| !SUPPORTED_FEATURES.contains(&feature.as_str()))
+        {
+            return Err(eyre!(
+                "The PBF file needs the unsupported feature {}",
+                feature
+            ));
+        }
+        Ok(PbfReader {
+            reader,
+            header: PbfHeader {
+                historical: header
+                    .required_features
+                    .iter()
+                    .any(|feature| feature == HISTORY_FEATURE),
+                writing_program: header.writingprogram,
+                replication_base_url: header.osmosis_replication_base_url,
+                replication_sequence: header
+                    .osmosis_replication_sequence_number
+                    .and_then(|sequence| u64::try_from(sequence).ok())
+                    .map(SequenceNumber::new),
+                replication_timestamp: header
+                    .osmosis_replication_timestamp
+                    .and_then(|timestamp| OffsetDateTime::from_unix_timestamp(timestamp).ok()),
+            },
+        })
+    }
+
+    pub fn header(&self) -> &PbfHeader {
+        &self.header
+    }
+
+    /// Read the objects of the next block
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Vec<OSMObject>>>` - `None` at the end of the file
+    pub fn next_block(&mut self, id_mapper: &dyn IdMapper) -> Result<Option<Vec<OSMObject>>> {
+        loop {
+            let Some((blob_type, data)) = read_blob(&mut self.reader)? else {
+                return Ok(None);
+            };
+            // Blocks of unknown types may be skipped according to the format
+            if blob_type != "OSMData" {
+                continue;
+            }
+            let block = proto::PrimitiveBlock::decode(data.as_slice())?;
+            return Ok(Some(decode_block(&block, id_mapper)?));
+        }
+    }
+}
+
+/// Read the next blob and decompress its data
+///
+/// # Returns
+///
+/// * `Result<Option<(String, Vec<u8>)>>` - The type and data of the blob, `None` at the
+///   end of the file
+fn read_blob(reader: &mut impl Read) -> Result<Option<(String, Vec<u8>)>> {
+    let mut header_size = [0; 4];
+    match reader.read_exact(&mut header_size) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let header_size = u32::from_be_bytes(header_size);
+    if header_size > MAX_BLOB_HEADER_SIZE {
+        return Err(eyre!("Invalid blob header size {}", header_size));
+    }
+    let mut header = vec![0; header_size as usize];
+    reader.read_exact(&mut header)?;
+    let header = proto::BlobHeader::decode(header.as_slice())?;
+    if !(0..=MAX_BLOB_SIZE).contains(&header.datasize) {
+        return Err(eyre!("Invalid blob size {}", header.datasize));
+    }
+    let mut blob = vec![0; header.datasize as usize];
+    reader.read_exact(&mut blob)?;
+    let blob = proto::Blob::decode(blob.as_slice())?;
+
+    let data = match (blob.raw, blob.zlib_data) {
+        (Some(raw), _) => raw,
+        (None, Some(zlib_data)) => {
+            let mut data = Vec::with_capacity(blob.raw_size.unwrap_or_default().max(0) as usize);
+            ZlibDecoder::new(zlib_data.as_slice()).read_to_end(&mut data)?;
+            data
+        }
+        (None, None) => {
+            return Err(eyre!(
+                "The {} block uses an unsupported compression",
+                header.r#type
+            ))
+        }
+    };
+    Ok(Some((header.r#type, data)))
+}
+
+/// The metadata every object version has
+struct Metadata {
+    changeset: u64,
+    version: Option<String>,
+    timestamp: Option<String>,
+    uid: Option<u64>,
+    user: Option<String>,
+    visible: Option<bool>,
+}
+
+/// Decodes the values of a block which are relative to its string table, offsets and
+/// granularities
+struct BlockContext<'a> {
+    strings: Vec<String>,
+    block: &'a proto::PrimitiveBlock,
+}
+
+impl BlockContext<'_> {
+    fn string(&self, index: usize) -> Result<&str> {
+        self.strings
+            .get(index)
+            .map(String::as_str)
+            .ok_or_else(|| eyre!("The string table has no entry {}", index))
+    }
+
+    fn tags(&self, keys: &[u32], values: &[u32]) -> Result<BTreeMap<String, String>> {
+        keys.iter()
+            .zip(values)
+            .map(|(&key, &value)| {
+                Ok((
+                    self.string(key as usize)?.to_string(),
+                    self.string(value as usize)?.to_string(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Coordinates are stored in nanodegrees, which is divided only once so they are the
+    /// same values as when parsed from OSM XML
+    fn latitude(&self, lat: i64) -> f64 {
+        let granularity = self.block.granularity.unwrap_or(100) as i64;
+        (self.block.lat_offset.unwrap_or_default() + granularity * lat) as f64 / 1e9
+    }
+
+    fn longitude(&self, lon: i64) -> f64 {
+        let granularity = self.block.granularity.unwrap_or(100) as i64;
+        (self.block.lon_offset.unwrap_or_default() + granularity * lon) as f64 / 1e9
+    }
+
+    fn timestamp(&self, timestamp: i64) -> Option<String> {
+        let milliseconds = timestamp * self.block.date_granularity.unwrap_or(1000) as i64;
+        OffsetDateTime::from_unix_timestamp(milliseconds / 1000)
+            .ok()?
+            .format(&Rfc3339)
+            .ok()
+    }
+
+    fn metadata(
+        &self,
+        version: i32,
+        timestamp: Option<i64>,
+        changeset: i64,
+        uid: i32,
+        user_sid: usize,
+        visible: Option<bool>,
+    ) -> Result<Metadata> {
+        // Anonymous edits from before 2007 have neither a user nor a uid
+        let user = self.string(user_sid)?;
+        let has_user = !user.is_empty();
+        Ok(Metadata {
+            changeset: u64::try_from(changeset).unwrap_or_default(),
+            version: (version >= 0).then(|| version.to_string()),
+            timestamp: timestamp.and_then(|timestamp| self.timestamp(timestamp)),
+            uid: has_user.then(|| u64::try_from(uid).ok()).flatten(),
+            user: has_user.then(|| user.to_string()),
+            visible,
+        })
+    }
+
+    fn info(&self, info: Option<&proto::Info>) -> Result<Metadata> {
+        let info = info.cloned().unwrap_or_default();
+        self.metadata(
+            info.version(),
+            info.timestamp,
+            info.changeset(),
+            info.uid(),
+            info.user_sid() as usize,
+            info.visible,
+        )
+    }
+}
+
+fn decode_block(block: &proto::PrimitiveBlock, id_mapper: &dyn IdMapper) -> Result<Vec<OSMObject>> {
+    let context = BlockContext {
+        strings: block
+            .stringtable
+            .s
+            .iter()
+            .map(|s| String::from_utf8_lossy(s).to_string())
+            .collect(),
+        block,
+    };
+    let mut objects = Vec::new();
+    for group in &block.primitivegroup {
+        for node in &group.nodes {
+            let metadata = context.info(node.info.as_ref())?;
+            objects.push(OSMObject::Node(Node {
+                id: id_mapper.map_id("node", node.id)?,
+                changeset: metadata.changeset,
+                file_generator: None,
+                file_version: FILE_VERSION.to_string(),
+                legacy_object_version: metadata.version,
+                timestamp: metadata.timestamp,
+                uid: metadata.uid,
+                user: metadata.user,
+                visible: metadata.visible,
+                lat: context.latitude(node.lat),
+                lon: context.longitude(node.lon),
+                tags: context.tags(&node.keys, &node.vals)?,
+            }));
+        }
+        if let Some(dense) = &group.dense {
+            decode_dense_nodes(&context, dense, id_mapper, &mut objects)?;
+        }
+        for way in &group.ways {
+            let metadata = context.info(way.info.as_ref())?;
+            let mut node_id = 0;
+            let nodes = way
+                .refs
+                .iter()
+                .map(|delta| {
+                    node_id += delta;
+                    id_mapper.map_id("node", node_id)
+                })
+                .collect::<Result<Vec<u64>>>()?;
+            objects.push(OSMObject::Way(Way {
+                id: id_mapper.map_id("way", way.id)?,
+                changeset: metadata.changeset,
+                file_generator: None,
+                file_version: FILE_VERSION.to_string(),
+                legacy_object_version: metadata.version,
+                timestamp: metadata.timestamp,
+                uid: metadata.uid,
+                user: metadata.user,
+                visible: metadata.visible,
+                tags: context.tags(&way.keys, &way.vals)?,
+                nodes,
+            }));
+        }
+        for relation in &group.relations {
+            let metadata = context.info(relation.info.as_ref())?;
+            let mut member_id = 0;
+            let member = relation
+                .memids
+                .iter()
+                .zip(&relation.roles_sid)
+                .zip(&relation.types)
+                .map(|((delta, &role), &member_type)| {
+                    member_id += delta;
+                    let member_type = match member_type {
+                        0 => "node",
+                        1 => "way",
+                        2 => "relation",
+                        _ => return Err(eyre!("Unknown member type {}", member_type)),
+                    };
+                    let role = context.string(role as usize)?;
+                    Ok(RelationMember {
+                        r#type: member_type.to_string(),
+                        ref_id: id_mapper.map_id(member_type, member_id)?,
+                        role: (!role.is_empty()).then(|| role.to_string()),
+                    })
+                })
+                .collect::<Result<Vec<RelationMember>>>()?;
+            objects.push(OSMObject::Relation(Relation {
+                id: id_mapper.map_id("relation", relation.id)?,
+                changeset: metadata.changeset,
+                file_generator: None,
+                file_version: FILE_VERSION.to_string(),
+                legacy_object_version: metadata.version,
+                timestamp: metadata.timestamp,
+                uid: metadata.uid,
+                user: metadata.user,
+                visible: metadata.visible,
+                tags: context.tags(&relation.keys, &relation.vals)?,
+                member,
+            }));
+        }
+    }
+    Ok(objects)
+}
+
+/// Decode densely packed nodes, whose ids, coordinates and metadata are delta coded and
+/// whose tags are one list of key and value indexes with a `0` after the tags of each node
+fn decode_dense_nodes(
+    context: &BlockContext,
+    dense: &proto::DenseNodes,
+    id_mapper: &dyn IdMapper,
+    objects: &mut Vec<OSMObject>,
+) -> Result<()> {
+    let info = dense.denseinfo.clone().unwrap_or_default();
+    let mut keys_vals = dense.keys_vals.iter();
+    let (mut id, mut lat, mut lon) = (0, 0, 0);
+    let (mut timestamp, mut changeset, mut uid, mut user_sid) = (0, 0, 0, 0);
+    for index in 0..dense.id.len() {
+        id += dense.id[index];
+        lat += dense.lat.get(index).copied().unwrap_or_default();
+        lon += dense.lon.get(index).copied().unwrap_or_default();
+        timestamp += info.timestamp.get(index).copied().unwrap_or_default();
+        changeset += info.changeset.get(index).copied().unwrap_or_default();
+        uid += info.uid.get(index).copied().unwrap_or_default();
+        user_sid += info.user_sid.get(index).copied().unwrap_or_default();
+
+        let mut tags = BTreeMap::new();
+        while let Some(&key) = keys_vals.next() {
+            if key == 0 {
+                break;
+            }
+            let value = keys_vals
+                .next()
+                .ok_or_else(|| eyre!("The tags of node {} have a key without a value", id))?;
+            tags.insert(
+                context.string(key as usize)?.to_string(),
+                context.string(*value as usize)?.to_string(),
+            );
+        }
+
+        let metadata = context.metadata(
+            info.version.get(index).copied().unwrap_or(-1),
+            (!info.timestamp.is_empty()).then_some(timestamp),
+            changeset,
+            uid,
+            usize::try_from(user_sid).unwrap_or_default(),
+            info.visible.get(index).copied(),
+        )?;
+        objects.push(OSMObject::Node(Node {
+            id: id_mapper.map_id("node", id)?,
+            changeset: metadata.changeset,
+            file_generator: None,
+            file_version: FILE_VERSION.to_string(),
+            legacy_object_version: metadata.version,
+            timestamp: metadata.timestamp,
+            uid: metadata.uid,
+            user: metadata.user,
+            visible: metadata.visible,
+            lat: context.latitude(lat),
+            lon: context.longitude(lon),
+            tags,
+        }));
+    }
+    Ok(())
+}
+
+/// Settings for bootstrapping a git repo from a PBF file
+pub struct BootstrapSettings<'a> {
+    /// The name of the file, used in the commit messages
+    pub source_name: &'a str,
+    pub id_mapper: &'a dyn IdMapper,
+    pub layout: &'a Layout,
+    pub state_store: &'a dyn StateStore,
+    /// The folder containing the changeset dumps, used for the commits of history files
+    pub changesets_location: &'a str,
+    /// Who the commits of history files are attributed to
+    pub identity_policy: IdentityPolicy,
+    /// The names and emails of the mappers in the commits of history files
+    pub authors: &'a AuthorIdentity,
+    /// How many objects go into one commit. Big files are split into several commits,
+    /// so no commit has to hold all objects of the file in memory
+    pub objects_per_commit: usize,
+}
+
+/// What a bootstrap imported
+pub struct BootstrapSummary {
+    /// The number of objects, or of object versions for history files
+    pub objects: usize,
+    pub commits: usize,
+    /// The replication sequence a replay continues after, if the file names one
+    pub sequence: Option<String>,
+}
+
+/// Import the objects of a PBF file as the initial commits of a git repo
+///
+/// The commits are staged and only published once the whole file is imported, so an
+/// interrupted bootstrap leaves the git repo as it was. If the header of the file names
+/// its replication feed and sequence, it is recorded as the applied sequence, so a replay
+/// of that feed continues right after the file instead of at sequence 000/000/000.
+pub fn bootstrap_from_pbf<R: Read>(
+    repository: &Repository,
+    committer: &Signature,
+    reader: &mut PbfReader<R>,
+    settings: &BootstrapSettings,
+) -> Result<BootstrapSummary> {
+    let head = repository.head()?.peel_to_commit()?;
+    repository.reference(STAGING_REF, head.id(), true, "osm-git: begin bootstrap")?;
+
+    let mut summary = BootstrapSummary {
+        objects: 0,
+        commits: 0,
+        sequence: None,
+    };
+    let mut files = BTreeMap::new();
+    let mut first_object = 1;
+    let mut commit_batch = |files: &mut BTreeMap<PathBuf, Option<Vec<u8>>>,
+                            summary: &mut BootstrapSummary|
+     -> Result<()> {
+        if files.is_empty() {
+            return Ok(());
+        }
+        let message = format!(
+            "Bootstrap from {}\n\nObjects {} to {} of the file",
+            settings.source_name, first_object, summary.objects
+        );
+        settings.state_store.write_files(repository, files)?;
+        settings.state_store.commit_files(
+            repository,
+            std::mem::take(files),
+            &message,
+            committer,
+            committer,
+        )?;
+        settings.state_store.finish_commits(repository)?;
+        first_object = summary.objects + 1;
+        summary.commits += 1;
+        info!("Committed {} objects", summary.objects);
+        Ok(())
+    };
+
+    while let Some(objects) = reader.next_block(settings.id_mapper)? {
+        for object in objects {
+            let path = settings.layout.object_path(&object, settings.id_mapper);
+            files.insert(path, Some(settings.layout.serializer().serialize(&object)?));
+            summary.objects += 1;
+            if files.len() >= settings.objects_per_commit {
+                commit_batch(&mut files, &mut summary)?;
+            }
+        }
+    }
+    commit_batch(&mut files, &mut summary)?;
+    publish_staging(repository)?;
+    summary.sequence = record_replication_state(repository, reader.header())?;
+    Ok(summary)
+}
+
+/// The newest version of an object within a changeset, as read from a history file
+struct ChangesetVersion {
+    path: PathBuf,
+    /// `None` if the version deleted the object
+    contents: Option<Vec<u8>>,
+    user: Option<String>,
+    uid: Option<u64>,
+    timestamp: Option<String>,
+}
+
+/// Import every version of a history file, like `history-latest.osm.pbf`, as one commit
+/// per changeset
+///
+/// History files are sorted by object, so the versions are first sorted by changeset in
+/// a SQLite database in the git dir, which is removed afterwards. The changesets are then
+/// committed in the order of their ids with the metadata of the changeset dump, like
+/// replicated changesets. Changesets missing from the dump are attributed to the user of
+/// their versions. When a changeset changed an object more than once, its last version
+/// is committed. Like a bootstrap, the commits are only published once all of them are
+/// created.
+pub fn import_history<R: Read>(
+    repository: &Repository,
+    committer: &Signature,
+    reader: &mut PbfReader<R>,
+    settings: &BootstrapSettings,
+) -> Result<BootstrapSummary> {
+    let database_path = repository.path().join(HISTORY_IMPORT_FILE);
+    // The database of an interrupted import is incomplete
+    if database_path.exists() {
+        std::fs::remove_file(&database_path)?;
+    }
+    let connection = Connection::open(&database_path)?;
+    connection.execute_batch(
+        "PRAGMA journal_mode = OFF;
+        PRAGMA synchronous = OFF;
+        CREATE TABLE versions (
+            changeset INTEGER NOT NULL,
+            path TEXT NOT NULL,
+            version INTEGER NOT NULL,
+            contents BLOB,
+            user TEXT,
+            uid INTEGER,
+            timestamp TEXT,
+            PRIMARY KEY (changeset, path)
+        );",
+    )?;
+
+    let mut summary = BootstrapSummary {
+        objects: 0,
+        commits: 0,
+        sequence: None,
+    };
+    while let Some(objects) = reader.next_block(settings.id_mapper)? {
+        let transaction = connection.unchecked_transaction()?;
+        let mut insert = transaction.prepare_cached(
+            "INSERT INTO versions (changeset, path, version, contents, user, uid, timestamp)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            ON CONFLICT (changeset, path) DO UPDATE SET
+                version = excluded.version,
+                contents = excluded.contents,
+                user = excluded.user,
+                uid = excluded.uid,
+                timestamp = excluded.timestamp
+            WHERE excluded.version > versions.version",
+        )?;
+        for mut object in objects {
+            let path = settings.layout.object_path(&object, settings.id_mapper);
+            let (changeset, version, user, uid, timestamp, visible) = match &mut object {
+                OSMObject::Node(Node {
+                    changeset,
+                    legacy_object_version,
+                    user,
+                    uid,
+                    timestamp,
+                    visible,
+                    ..
+                })
+                | OSMObject::Way(Way {
+                    changeset,
+                    legacy_object_version,
+                    user,
+                    uid,
+                    timestamp,
+                    visible,
+                    ..
+                })
+                | OSMObject::Relation(Relation {
+                    changeset,
+                    legacy_object_version,
+                    user,
+                    uid,
+                    timestamp,
+                    visible,
+                    ..
+                }) => (
+                    *changeset,
+                    legacy_object_version
+                        .as_deref()
+                        .and_then(|version| version.parse::<i64>().ok())
+                        .unwrap_or_default(),
+                    user.clone(),
+                    *uid,
+                    timestamp.clone(),
+                    // Replicated objects don't record their visibility either
+                    visible.take().unwrap_or(true),
+                ),
+            };
+            let contents = visible
+                .then(|| settings.layout.serializer().serialize(&object))
+                .transpose()?;
+            insert.execute((
+                changeset as i64,
+                path.to_string_lossy(),
+                version,
+                contents,
+                user,
+                uid.map(|uid| uid as i64),
+                timestamp,
+            ))?;
+            summary.objects += 1;
+        }
+        drop(insert);
+        transaction.commit()?;
+    }
+    info!(
+        "Read {} versions. Committing them by changeset",
+        summary.objects
+    );
+
+    let head = repository.head()?.peel_to_commit()?;
+    repository.reference(
+        STAGING_REF,
+        head.id(),
+        true,
+        "osm-git: begin history import",
+    )?;
+    let mut statement = connection.prepare(
+        "SELECT changeset, path, contents, user, uid, timestamp FROM versions
+        ORDER BY changeset, path",
+    )?;
+    let mut rows = statement.query([])?;
+    let mut batch: Vec<(u64, Vec<ChangesetVersion>)> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let changeset = row.get::<_, i64>(0)? as u64;
+        let version = ChangesetVersion {
+            path: PathBuf::from(row.get::<_, String>(1)?),
+            contents: row.get(2)?,
+            user: row.get(3)?,
+            uid: row.get::<_, Option<i64>>(4)?.map(|uid| uid as u64),
+            timestamp: row.get(5)?,
+        };
+        match batch.last_mut() {
+            Some((last, versions)) if *last == changeset => versions.push(version),
+            _ => {
+                if batch.len() >= CHANGESETS_PER_LOOKUP {
+                    summary.commits += commit_changesets(repository, committer, settings, &batch)?;
+                    info!("Committed {} changesets", summary.commits);
+                    batch.clear();
+                }
+                batch.push((changeset, vec![version]));
+            }
+        }
+    }
+    summary.commits += commit_changesets(repository, committer, settings, &batch)?;
+    drop(rows);
+    drop(statement);
+    drop(connection);
+    std::fs::remove_file(&database_path)?;
+    publish_staging(repository)?;
+    summary.sequence = record_replication_state(repository, reader.header())?;
+    Ok(summary)
+}
+
+/// Commit the versions of a batch of changesets, one commit per changeset
+///
+/// # Returns
+///
+/// * `Result<usize>` - The number of commits created
+fn commit_changesets(
+    repository: &Repository,
+    committer: &Signature,
+    settings: &BootstrapSettings,
+    batch: &[(u64, Vec<ChangesetVersion>)],
+) -> Result<usize> {
+    let changeset_ids = batch.iter().map(|(id, _)| *id).collect::<Vec<u64>>();
+    let changesets = load_changesets(settings.changesets_location, &changeset_ids)?;
+    for (changeset_id, versions) in batch {
+        let changeset = match changesets.iter().find(|c| c.id == *changeset_id) {
+            Some(changeset) => changeset.clone(),
+            None => changeset_from_versions(*changeset_id, versions, committer)?,
+        };
+        let objects = versions
+            .iter()
+            .filter_map(|version| version.contents.as_deref())
+            .map(|contents| settings.layout.serializer().deserialize(contents))
+            .collect::<Result<Vec<OSMObject>>>()?;
+        let mut trailers = changeset_trailers(&changeset);
+        if let Some(counts) = tag_key_counts(&objects) {
+            trailers.push((TAG_KEYS_TRAILER, counts));
+        }
+        let message = append_trailers(
+            commit_message(&[&changeset]),
+            &trailers
+                .iter()
+                .map(|(key, value)| (*key, value.as_str()))
+                .collect::<Vec<(&str, &str)>>(),
+        );
+        let files = versions
+            .iter()
+            .map(|version| (version.path.clone(), version.contents.clone()))
+            .collect();
+        settings.state_store.write_files(repository, &files)?;
+        settings.state_store.commit_files(
+            repository,
+            files,
+            &message,
+            &settings
+                .identity_policy
+                .author(changeset.author_signature(settings.authors)?, committer)?,
+            committer,
+        )?;
+    }
+    settings.state_store.finish_commits(repository)?;
+    Ok(batch.len())
+}
+
+/// A changeset missing from the changeset dump, attributed to the user of its versions
+/// at the time of its last version
+fn changeset_from_versions(
+    id: u64,
+    versions: &[ChangesetVersion],
+    committer: &Signature,
+) -> Result<Changeset> {
+    let last_version = versions
+        .iter()
+        .filter(|version| version.timestamp.is_some())
+        .max_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    let time = match last_version.and_then(|version| version.timestamp.as_deref()) {
+        Some(timestamp) => OffsetDateTime::parse(timestamp, &Rfc3339)?,
+        None => OffsetDateTime::from_unix_timestamp(committer.when().seconds())?,
+    };
+    let mut changeset = Changeset::placeholder(id, time)?;
+    if let Some(user) = last_version.and_then(|version| version.user.clone()) {
+        changeset.user = Some(user);
+        changeset.uid = last_version.and_then(|version| version.uid);
+    }
+    Ok(changeset)
+}
+
+/// Record the replication sequence named in the header as the applied sequence
+///
+/// # Returns
+///
+/// * `Result<Option<String>>` - The recorded sequence, `None` if the header names none
+fn record_replication_state(repository: &Repository, header: &PbfHeader) -> Result<Option<String>> {
+    let (Some(base_url), Some(sequence)) =
+        (&header.replication_base_url, header.replication_sequence)
+    else {
+        return Ok(None);
+    };
+    // Feeds outside of the planet server, like the ones of Geofabrik, are daily
+    let interval = feed_base(base_url)
+        .and_then(|base| base_url.trim_end_matches('/').strip_prefix(base))
+        .and_then(|interval| match interval.trim_start_matches('/') {
+            "hour" => Some(Interval::Hour),
+            "minute" => Some(Interval::Minute),
+            _ => None,
+        })
+        .unwrap_or(Interval::Day);
+    let sequence = interval.sequence_name(sequence);
+    write_state(repository, &sequence)?;
+    Ok(Some(sequence))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osm::id_mapping::IdentityMapping;
+
+    /// Written by `tests/fixtures/small_osm_pbf.py`, which encodes the messages by hand
+    const SMALL_PBF: &[u8] = include_bytes!("../../tests/fixtures/small.osm.pbf");
+
+    fn objects(data: &[u8]) -> Result<Vec<OSMObject>> {
+        let mut reader = PbfReader::new(data)?;
+        let mut objects = Vec::new();
+        while let Some(block) = reader.next_block(&IdentityMapping)? {
+            objects.extend(block);
+        }
+        Ok(objects)
+    }
+
+    fn tags(tags: &[(&str, &str)]) -> BTreeMap<String, String> {
+        tags.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn node(
+        id: u64,
+        changeset: u64,
+        version: &str,
+        timestamp: &str,
+        mapper: Option<(u64, &str)>,
+        lat: f64,
+        lon: f64,
+        node_tags: &[(&str, &str)],
+    ) -> OSMObject {
+        OSMObject::Node(Node {
+            id,
+            changeset,
+            file_generator: None,
+            file_version: FILE_VERSION.to_string(),
+            legacy_object_version: Some(version.to_string()),
+            timestamp: Some(timestamp.to_string()),
+            uid: mapper.map(|(uid, _)| uid),
+            user: mapper.map(|(_, user)| user.to_string()),
+            visible: None,
+            lat,
+            lon,
+            tags: tags(node_tags),
+        })
+    }
+
+    #[test]
+    fn reads_the_header() {
+        let reader = PbfReader::new(SMALL_PBF).unwrap();
+        let header = reader.header();
+        assert_eq!(header.writing_program.as_deref(), Some("osm-git fixture"));
+        assert!(!header.historical);
+        assert_eq!(
+            header.replication_base_url.as_deref(),
+            Some("https://planet.openstreetmap.org/replication/minute")
+        );
+        assert_eq!(header.replication_sequence, Some(SequenceNumber::new(1234)));
+        assert_eq!(
+            header
+                .replication_timestamp
+                .map(OffsetDateTime::unix_timestamp),
+            Some(1347408000)
+        );
+    }
+
+    #[test]
+    fn decodes_dense_nodes_ways_and_relations() {
+        let alice = Some((5, "alice"));
+        let bob = Some((6, "bob"));
+        let expected = vec![
+            node(
+                1,
+                10,
+                "1",
+                "2012-09-12T00:00:00Z",
+                alice,
+                51.5,
+                -0.1,
+                &[("amenity", "cafe"), ("name", "Café")],
+            ),
+            node(
+                2,
+                11,
+                "2",
+                "2012-09-12T00:01:00Z",
+                alice,
+                51.5000001,
+                -0.1000002,
+                &[],
+            ),
+            node(
+                3,
+                11,
+                "1",
+                "2012-09-12T00:02:00Z",
+                None,
+                51.4999999,
+                -0.0999999,
+                &[],
+            ),
+            OSMObject::Way(Way {
+                id: 10,
+                changeset: 12,
+                file_generator: None,
+                file_version: FILE_VERSION.to_string(),
+                legacy_object_version: Some("1".to_string()),
+                timestamp: Some("2012-09-12T00:02:00Z".to_string()),
+                uid: Some(5),
+                user: Some("alice".to_string()),
+                visible: None,
+                tags: tags(&[("highway", "residential")]),
+                nodes: vec![1, 2, 3],
+            }),
+            // From the uncompressed block with a granularity of 1000 and an offset
+            node(
+                4,
+                13,
+                "2",
+                "2012-09-12T00:03:00Z",
+                bob,
+                51.5,
+                -0.1,
+                &[("amenity", "cafe")],
+            ),
+            OSMObject::Relation(Relation {
+                id: 20,
+                changeset: 13,
+                file_generator: None,
+                file_version: FILE_VERSION.to_string(),
+                legacy_object_version: Some("3".to_string()),
+                timestamp: Some("2012-09-12T00:04:00Z".to_string()),
+                uid: Some(6),
+                user: Some("bob".to_string()),
+                visible: None,
+                tags: tags(&[("type", "multipolygon")]),
+                member: vec![
+                    RelationMember {
+                        r#type: "way".to_string(),
+                        ref_id: 10,
+                        role: Some("outer".to_string()),
+                    },
+                    RelationMember {
+                        r#type: "node".to_string(),
+                        ref_id: 1,
+                        role: None,
+                    },
+                ],
+            }),
+        ];
+        assert_eq!(objects(SMALL_PBF).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_broken_files() {
+        assert!(PbfReader::new(&[][..]).is_err());
+        // A file cut off within a block
+        assert!(objects(&SMALL_PBF[..SMALL_PBF.len() / 2]).is_err());
+        // A file starting with a data block instead of the header
+        let header_length = 4 + u32::from_be_bytes(SMALL_PBF[..4].try_into().unwrap()) as usize;
+        let header = proto::BlobHeader::decode(&SMALL_PBF[4..header_length]).unwrap();
+        let data_block = &SMALL_PBF[header_length + header.datasize as usize..];
+        assert!(PbfReader::new(data_block).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use std::{collections::HashSet, path::PathBuf};
+
+use super::{
+    area_filter::AreaFilter, id_mapping::IdMapper, layout::Layout, mapper_filter::MapperFilter,
+    osm_data::OSMObject, tag_filter::TagFilter,
+};
+use crate::git::backend::GitBackend;
+
+/// Decides which objects of a replication file a mirror of a region or theme keeps
+///
+/// With an area, nodes are kept if they lie within it, ways if they have a kept node and
+/// relations if they have a kept member. With a tag filter, objects are kept if they
+/// match it or are referenced by matching objects of the same file, so new ways come
+/// with their new nodes. Objects which are in the mirror already are always kept, so
+/// their modifications and deletions are applied even when they move out of the region
+/// or lose their tags. Like a simple extract, ways crossing the border of the region only
+/// have their nodes within it, and objects referenced by matching objects but not changed
+/// in the file are missing.
+///
+/// A mapper filter is different: edits of other mappers are dropped even for objects in
+/// the mirror, so the history only has the work of the wanted mappers.
+pub struct ObjectSelection<'a> {
+    area: Option<&'a AreaFilter>,
+    tags: Option<&'a TagFilter>,
+    /// The objects of the file referenced by objects matching the tag filter
+    referenced: HashSet<(&'static str, u64)>,
+    mappers: Option<&'a MapperFilter>,
+    /// The changesets of the file tagged as automated edits, dropped with `exclude_bots`
+    automated_changesets: HashSet<u64>,
+    layout: &'a Layout,
+    id_mapper: &'a dyn IdMapper,
+    /// Looks up the objects in HEAD before the file is applied
+    backend: &'a dyn GitBackend,
+    /// The objects of the file kept so far
+    kept: HashSet<PathBuf>,
+}
+
+impl<'a> ObjectSelection<'a> {
+    /// A selection keeping all objects until filters are added
+    pub fn new(
+        backend: &'a dyn GitBackend,
+        layout: &'a Layout,
+        id_mapper: &'a dyn IdMapper,
+    ) -> Self {
+        ObjectSelection {
+            area: None,
+            tags: None,
+            referenced: HashSet::new(),
+            mappers: None,
+            automated_changesets: HashSet::new(),
+            layout,
+            id_mapper,
+            backend,
+            kept: HashSet::new(),
+        }
+    }
+
+    pub fn with_area(mut self, area: &'a AreaFilter) -> Self {
+        self.area = Some(area);
+        self
+    }
+
+    /// Only keep objects matching `tags`, or referenced by matching objects of the file
+    pub fn with_tags(
+        mut self,
+        tags: &'a TagFilter,
+        referenced: HashSet<(&'static str, u64)>,
+    ) -> Self {
+        self.tags = Some(tags);
+        self.referenced = referenced;
+        self
+    }
+
+    /// Only keep edits of the mappers of `mappers`, outside of `automated_changesets`
+    pub fn with_mappers(
+        mut self,
+        mappers: &'a MapperFilter,
+        automated_changesets: HashSet<u64>,
+    ) -> Self {
+        self.mappers = Some(mappers);
+        self.automated_changesets = automated_changesets;
+        self
+    }
+
+    fn in_mirror(&self, path: &PathBuf) -> bool {
+        self.kept.contains(path) || self.backend.in_head(path)
+    }
+
+    /// Check if an object of the file is kept
+    ///
+    /// Deletions are only kept for objects in the mirror, as there is nothing to delete
+    /// otherwise.
+    pub fn keep(&mut self, object: &OSMObject, deleted: bool) -> bool {
+        if !self.by_wanted_mapper(object) {
+            return false;
+        }
+        let path = self.layout.object_path(object, self.id_mapper);
+        if self.in_mirror(&path) {
+            self.kept.insert(path);
+            return true;
+        }
+        if deleted {
+            return false;
+        }
+        let keep = self.matches_tags(object) && self.in_area(object);
+        if keep {
+            self.kept.insert(path);
+        }
+        keep
+    }
+
+    fn by_wanted_mapper(&self, object: &OSMObject) -> bool {
+        let Some(mappers) = self.mappers else {
+            return true;
+        };
+        let (user, uid) = object.mapper();
+        mappers.matches(user, uid) && !self.automated_changesets.contains(&object.changeset())
+    }
+
+    fn matches_tags(&self, object: &OSMObject) -> bool {
+        let Some(tags) = self.tags else {
+            return true;
+        };
+        tags.matches(object) || self.referenced.contains(&(object.type_name(), object.id()))
+    }
+
+    fn in_area(&self, object: &OSMObject) -> bool {
+        let Some(area) = self.area else {
+            return true;
+        };
+        match object {
+            OSMObject::Node(node) => area.contains(node.lon, node.lat),
+            OSMObject::Way(way) => way
+                .nodes
+                .iter()
+                .any(|node| self.in_mirror(&self.layout.path("node", *node, self.id_mapper))),
+            OSMObject::Relation(relation) => relation.member.iter().any(|member| {
+                self.in_mirror(
+                    &self
+                        .layout
+                        .path(&member.r#type, member.ref_id, self.id_mapper),
+                )
+            }),
+        }
+    }
+}
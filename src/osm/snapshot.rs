@@ -0,0 +1,227 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Result};
+use git2::{Commit, ObjectType, Repository, Sort, Tree, TreeWalkMode, TreeWalkResult};
+use time::{format_description::well_known::Iso8601, Date, OffsetDateTime};
+
+use super::{
+    id_mapping::IdMapper,
+    layout::{Layout, ShardBudget},
+    osm_data::OSMObject,
+};
+
+/// Find the commit a revision or point in time refers to
+///
+/// `at` is either anything `git rev-parse` understands (commit ids, branches, tags like
+/// `sequence/000/000/001`) or an ISO 8601 timestamp or date. For a timestamp, the last
+/// commit of a changeset closed at or before that time is used. A date refers to the
+/// start of that day in UTC.
+pub fn resolve_commit<'r>(repository: &'r Repository, at: Option<&str>) -> Result<Commit<'r>> {
+    resolve_commit_from(repository, repository.head()?.peel_to_commit()?, at)
+}
+
+/// Like [`resolve_commit`], but dates are looked up in the history of `tip` instead of
+/// HEAD, which is also the commit used without `at`
+pub fn resolve_commit_from<'r>(
+    repository: &'r Repository,
+    tip: Commit<'r>,
+    at: Option<&str>,
+) -> Result<Commit<'r>> {
+    let Some(at) = at else {
+        return Ok(tip);
+    };
+    if let Ok(object) = repository.revparse_single(at) {
+        return Ok(object.peel_to_commit()?);
+    }
+
+    let timestamp = match OffsetDateTime::parse(at, &Iso8601::DEFAULT) {
+        Ok(timestamp) => timestamp,
+        Err(_) => Date::parse(at, &Iso8601::DEFAULT)
+            .map_err(|_| eyre!("{} is neither a revision nor a date", at))?
+            .midnight()
+            .assume_utc(),
+    }
+    .unix_timestamp();
+
+    let mut revwalk = repository.revwalk()?;
+    revwalk.push(tip.id())?;
+    revwalk.set_sorting(Sort::TIME)?;
+    for oid in revwalk {
+        let commit = repository.find_commit(oid?)?;
+        if commit.author().when().seconds() <= timestamp {
+            return Ok(commit);
+        }
+    }
+    Err(eyre!("The git repo has no commits before {}", at))
+}
+
+/// Read access to the objects in the tree of a commit
+pub struct Snapshot<'r> {
+    repository: &'r Repository,
+    tree: Tree<'r>,
+    layout: Layout,
+    id_mapper: &'r dyn IdMapper,
+}
+
+impl<'r> Snapshot<'r> {
+    pub fn new(
+        repository: &'r Repository,
+        commit: &Commit<'r>,
+        id_mapper: &'r dyn IdMapper,
+        budget: ShardBudget,
+    ) -> Result<Self> {
+        let tree = commit.tree()?;
+        let layout = Layout::load_from_tree(&tree, repository, budget)?;
+        Ok(Snapshot {
+            repository,
+            tree,
+            layout,
+            id_mapper,
+        })
+    }
+
+    /// Read an object. Returns `None` if it does not exist at this commit.
+    #[cfg(feature = "serve")]
+    pub fn object(&self, type_name: &str, id: u64) -> Result<Option<OSMObject>> {
+        let path = self.layout.path(type_name, id, self.id_mapper);
+        let Ok(entry) = self.tree.get_path(Path::new(&path)) else {
+            return Ok(None);
+        };
+        let blob = entry.to_object(self.repository)?.peel_to_blob()?;
+        let mut object = self.layout.serializer().deserialize(blob.content())?;
+        // Objects of all types share one directory in the legacy flat layout
+        if object.type_name() != type_name {
+            return Ok(None);
+        }
+        object.set_id(id);
+        Ok(Some(object))
+    }
+
+    /// The layout of the object files at this commit
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn id_mapper(&self) -> &dyn IdMapper {
+        self.id_mapper
+    }
+
+    /// Read all objects stored at this commit
+    ///
+    /// Files which can't be read are returned as errors next to their path, so callers
+    /// can decide whether to skip them or give up.
+    pub fn objects(&self) -> Result<Vec<(PathBuf, Result<OSMObject>)>> {
+        Ok(self
+            .object_files()?
+            .into_iter()
+            .map(|(path, blob_id)| {
+                let object = self.read_blob(&path, blob_id);
+                (path, object)
+            })
+            .collect())
+    }
+
+    /// The object files whose contents differ from what serializing their object gives
+    ///
+    /// Files which can't be read are left out, [`Snapshot::objects`] reports them.
+    pub fn non_canonical_files(&self) -> Result<Vec<PathBuf>> {
+        let serializer = self.layout.serializer();
+        let mut files = Vec::new();
+        for (path, blob_id) in self.object_files()? {
+            let blob = self.repository.find_blob(blob_id)?;
+            if !serializer.is_canonical(blob.content()).unwrap_or(true) {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    /// The paths and blobs of all object files at this commit
+    fn object_files(&self) -> Result<Vec<(PathBuf, git2::Oid)>> {
+        let extension = format!(".{}", self.layout.serializer().extension());
+        let mut paths = Vec::new();
+        self.tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+            let Some(name) = entry.name() else {
+                return TreeWalkResult::Ok;
+            };
+            // The metadata of the git repo is not an object
+            if root.is_empty() && name == "meta" {
+                return TreeWalkResult::Skip;
+            }
+            if entry.kind() == Some(ObjectType::Blob) && name.ends_with(&extension) {
+                paths.push((PathBuf::from(format!("{}{}", root, name)), entry.id()));
+            }
+            TreeWalkResult::Ok
+        })?;
+        Ok(paths)
+    }
+
+    fn read_blob(&self, path: &Path, blob_id: git2::Oid) -> Result<OSMObject> {
+        let id = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| self.id_mapper.id_from_stem(stem))
+            .ok_or_else(|| eyre!("{} is not named after an object id", path.display()))?;
+        let blob = self.repository.find_blob(blob_id)?;
+        let mut object = self.layout.serializer().deserialize(blob.content())?;
+        object.set_id(id);
+        Ok(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        git::{
+            commit_blobs,
+            testing::{signature, TestRepository},
+        },
+        osm::id_mapping::IdentityMapping,
+    };
+
+    #[test]
+    fn files_with_windows_line_endings_are_reported() {
+        let repository = TestRepository::new("non-canonical", true);
+        let canonical = "type: Node\nfile_version: '1'\nlat: 51.5\nlon: -0.1\n";
+        let files = BTreeMap::from([
+            (
+                PathBuf::from("nodes/000/1.yaml"),
+                Some(canonical.as_bytes().to_vec()),
+            ),
+            (
+                PathBuf::from("nodes/000/2.yaml"),
+                Some(canonical.replace('\n', "\r\n").into_bytes()),
+            ),
+            (
+                PathBuf::from("nodes/000/3.yaml"),
+                Some(canonical.trim_end().as_bytes().to_vec()),
+            ),
+        ]);
+        commit_blobs(
+            &repository,
+            "HEAD",
+            files,
+            "Objects",
+            &signature(),
+            &signature(),
+        )
+        .unwrap();
+
+        let commit = repository.head().unwrap().peel_to_commit().unwrap();
+        let budget = ShardBudget {
+            max_entries: 10_000,
+            max_file_size: 1 << 20,
+        };
+        let snapshot = Snapshot::new(&repository, &commit, &IdentityMapping, budget).unwrap();
+        assert_eq!(
+            snapshot.non_canonical_files().unwrap(),
+            vec![
+                PathBuf::from("nodes/000/2.yaml"),
+                PathBuf::from("nodes/000/3.yaml")
+            ]
+        );
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+
+use clap::ValueEnum;
+use color_eyre::eyre::Result;
+use serde::Serialize;
+use tracing::debug;
+
+use super::{changesets::Changeset, osm_data::OSMObject};
+
+/// The trailer counting the changed objects of a commit per tag key
+pub const TAG_KEYS_TRAILER: &str = "Tag-Keys";
+
+/// How many tag keys the `Tag-Keys` trailer lists at most
+const MAX_TAG_KEYS: usize = 10;
+
+/// What a commit is made of
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    /// A commit per changeset, or per group of squashed changesets
+    #[default]
+    Changeset,
+    /// A commit per replication file with all of its changesets, which are only listed in
+    /// `Changeset-Id` trailers. A planet mirror gets far fewer commits this way
+    File,
+}
+
+/// Group rapid-fire changesets of the same user into one group per commit
+///
+/// A changeset joins the previous group if it was made by the same user, within
+/// `window` seconds of the last changeset of the group and its area overlaps with it.
+/// The changesets are expected in the order they are committed in.
+pub fn squash_changesets(changesets: Vec<&Changeset>, window: i64) -> Result<Vec<Vec<&Changeset>>> {
+    let mut groups: Vec<Vec<&Changeset>> = Vec::new();
+    for changeset in changesets {
+        if let Some(group) = groups.last_mut() {
+            let last = group.last().unwrap();
+            let elapsed = (changeset.timestamp()? - last.timestamp()?).whole_seconds();
+            // Anonymous changesets may be made by anyone, so they are never squashed
+            if last.uid.is_some()
+                && last.uid == changeset.uid
+                && elapsed.abs() <= window
+                && last.spatial_scope().overlaps(&changeset.spatial_scope())
+            {
+                debug!("Squashing changeset {} into {}", changeset.id, last.id);
+                group.push(changeset);
+                continue;
+            }
+        }
+        groups.push(vec![changeset]);
+    }
+    Ok(groups)
+}
+
+/// The commit message for a group of changesets
+///
+/// A single changeset uses its comment. Squashed changesets list all distinct comments
+/// followed by the ids of all changesets in the group. Without any comment the
+/// changesets are named by their ids, so the message always has a subject line.
+pub fn commit_message(changesets: &[&Changeset]) -> String {
+    let comment = |changeset: &Changeset| {
+        changeset
+            .tags
+            .get("comment")
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default()
+    };
+
+    if let [changeset] = changesets {
+        let changeset_comment = comment(changeset);
+        if changeset_comment.is_empty() {
+            return format!("Changeset {}", changeset.id);
+        }
+        return changeset_comment;
+    }
+
+    let mut comments: Vec<String> = Vec::new();
+    for changeset in changesets {
+        let changeset_comment = comment(changeset);
+        if !changeset_comment.is_empty() && !comments.contains(&changeset_comment) {
+            comments.push(changeset_comment);
+        }
+    }
+    let ids = changesets
+        .iter()
+        .map(|changeset| changeset.id.to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    if comments.is_empty() {
+        return format!("Squashed changesets: {}", ids);
+    }
+    format!("{}\n\nSquashed changesets: {}", comments.join("\n"), ids)
+}
+
+/// The commit message for all changesets of a replication file
+pub fn file_commit_message(sequence: &str, changesets: &[&Changeset]) -> String {
+    match changesets.len() {
+        1 => format!("Replication file {}: 1 changeset", sequence),
+        count => format!("Replication file {}: {} changesets", sequence, count),
+    }
+}
+
+/// The trailers with the machine-readable metadata of a changeset
+///
+/// `Changeset-User` and `Changeset-Uid` are left out for anonymous changesets,
+/// `Changeset-BBox` for changesets without a bounding box and `Created-By` for
+/// changesets without a `created_by` tag.
+pub fn changeset_trailers(changeset: &Changeset) -> Vec<(&'static str, String)> {
+    let mut trailers = vec![("Changeset-Id", changeset.id.to_string())];
+    if let Some(user) = &changeset.user {
+        trailers.push(("Changeset-User", user.clone()));
+    }
+    if let Some(uid) = changeset.uid {
+        trailers.push(("Changeset-Uid", uid.to_string()));
+    }
+    if let Some(bbox) = changeset.bbox() {
+        trailers.push(("Changeset-BBox", bbox.to_string()));
+    }
+    if let Some(created_by) = changeset.tags.get("created_by") {
+        trailers.push(("Created-By", created_by.trim().to_string()));
+    }
+    trailers
+}
+
+/// The most common tag keys of the objects changed by a commit with the number of
+/// objects having them, like `building: 120, highway: 30`
+///
+/// The keys are counted on the versions in the replication file, so deletions, which
+/// usually carry no tags, count for little. The counts let `git log --grep "building:"`
+/// find thematic activity without diffing the trees.
+///
+/// # Returns
+///
+/// * `Option<String>` - `None` if none of the objects has tags
+pub fn tag_key_counts<'a>(objects: impl IntoIterator<Item = &'a OSMObject>) -> Option<String> {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for object in objects {
+        for key in object.tags().keys() {
+            *counts.entry(key).or_default() += 1;
+        }
+    }
+    let mut counts = counts.into_iter().collect::<Vec<(&str, usize)>>();
+    // The most common keys first, keys with the same count in alphabetical order
+    counts
+        .sort_by(|(a_key, a_count), (b_key, b_count)| b_count.cmp(a_count).then(a_key.cmp(b_key)));
+    let counts = counts
+        .iter()
+        .take(MAX_TAG_KEYS)
+        .map(|(key, count)| format!("{}: {}", key, count))
+        .collect::<Vec<String>>();
+    (!counts.is_empty()).then(|| counts.join(", "))
+}
+
+/// Append git trailers (`Key: value` lines) as the last paragraph of a commit message
+pub fn append_trailers(message: String, trailers: &[(&str, &str)]) -> String {
+    if trailers.is_empty() {
+        return message;
+    }
+    let trailers = trailers
+        .iter()
+        .map(|(key, value)| format!("{}: {}", key, value))
+        .collect::<Vec<String>>()
+        .join("\n");
+    if message.is_empty() {
+        trailers
+    } else {
+        format!("{}\n\n{}", message, trailers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::OffsetDateTime;
+
+    use super::*;
+
+    fn changeset(id: u64, bbox: Option<[f64; 4]>) -> Changeset {
+        let [min_lon, min_lat, max_lon, max_lat] = bbox.map_or([None; 4], |bbox| bbox.map(Some));
+        Changeset {
+            uid: Some(5),
+            user: Some("alice".to_string()),
+            closed_at: Some(format!("2012-09-12T00:0{}:00Z", id)),
+            min_lat,
+            min_lon,
+            max_lat,
+            max_lon,
+            ..Changeset::placeholder(id, OffsetDateTime::UNIX_EPOCH).unwrap()
+        }
+    }
+
+    fn ids(groups: &[Vec<&Changeset>]) -> Vec<Vec<u64>> {
+        groups
+            .iter()
+            .map(|group| group.iter().map(|changeset| changeset.id).collect())
+            .collect()
+    }
+
+    #[test]
+    fn changesets_without_a_bbox_are_squashed_with_any_area() {
+        let london = changeset(1, Some([-1.0, 51.0, 0.0, 52.0]));
+        let global = changeset(2, None);
+        let berlin = changeset(3, Some([13.3, 52.4, 13.4, 52.5]));
+        let groups = squash_changesets(vec![&london, &global, &berlin], 600).unwrap();
+        assert_eq!(ids(&groups), [vec![1, 2, 3]]);
+
+        let groups = squash_changesets(vec![&london, &berlin], 600).unwrap();
+        assert_eq!(ids(&groups), [vec![1], vec![3]]);
+    }
+
+    #[test]
+    fn changesets_without_a_bbox_have_no_bbox_trailer() {
+        let trailers = changeset_trailers(&changeset(2, None));
+        assert_eq!(
+            trailers,
+            [
+                ("Changeset-Id", "2".to_string()),
+                ("Changeset-User", "alice".to_string()),
+                ("Changeset-Uid", "5".to_string()),
+            ]
+        );
+        let trailers = changeset_trailers(&changeset(1, Some([-1.0, 51.0, 0.0, 52.0])));
+        assert!(trailers.contains(&("Changeset-BBox", "-1,51,0,52".to_string())));
+    }
+}
@@ -0,0 +1,450 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
+use git2::{Oid, Repository, Signature};
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+
+use super::osm_data::{OSMObject, ObjectFormat, Serializer};
+use crate::git::{commit_blobs, STAGING_REF};
+
+/// The file in the git dir holding the objects of the SQLite store
+const SQLITE_STORE_FILE: &str = "osm-git-objects.sqlite";
+
+/// Keeps the current versions of the objects while replication files are applied
+///
+/// The parser writes every object it reads to the store and looks up the current version
+/// of modified objects there. The files of each commit are then handed to the store to
+/// be committed on the staging ref. Implementing this trait is all a new backend needs,
+/// the parsing code stays the same.
+pub trait StateStore: Send {
+    /// The current version of the object stored at `path`, if the store has one
+    fn read_object(&self, repository: &Repository, path: &Path) -> Result<Option<OSMObject>>;
+    fn write_object(&self, repository: &Repository, path: &Path, object: &OSMObject) -> Result<()>;
+    fn remove_object(&self, repository: &Repository, path: &Path) -> Result<()>;
+    /// Store files which are already serialized, for objects which don't go through
+    /// [`StateStore::write_object`]
+    ///
+    /// Files mapped to `None` are removed.
+    fn write_files(
+        &self,
+        repository: &Repository,
+        files: &BTreeMap<PathBuf, Option<Vec<u8>>>,
+    ) -> Result<()>;
+    /// Commit the files of a group of changesets onto the staging ref
+    ///
+    /// The files are expected to be stored already. Files mapped to `None` are removed.
+    fn commit_files(
+        &self,
+        repository: &Repository,
+        files: BTreeMap<PathBuf, Option<Vec<u8>>>,
+        message: &str,
+        author: &Signature,
+        committer: &Signature,
+    ) -> Result<Oid>;
+    /// Called once all commits of a replication file are staged, to write out what the
+    /// store kept in memory between them and to update files which were stored in another
+    /// order than they were committed in
+    fn finish_commits(&self, _repository: &Repository) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The available [`StateStore`]s
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StateStoreKind {
+    /// The working directory of the git repo, or nothing besides the commits in a bare repo
+    #[default]
+    Git,
+    /// Experimental: a SQLite database in the git dir. Needs a bare git repo
+    Sqlite,
+}
+
+impl StateStoreKind {
+    /// Open the store for a git repo storing objects in `object_format`
+    pub fn open(
+        self,
+        repository: &Repository,
+        object_format: ObjectFormat,
+    ) -> Result<Box<dyn StateStore>> {
+        let serializer = object_format.serializer();
+        match self {
+            StateStoreKind::Git => Ok(Box::new(GitStore {
+                serializer,
+                changed_paths: Mutex::new(BTreeSet::new()),
+            })),
+            StateStoreKind::Sqlite => Ok(Box::new(SqliteStore::open(repository, serializer)?)),
+        }
+    }
+}
+
+/// Keeps the objects in the working directory of the git repo
+///
+/// Objects are read from and written to their files. The trees of the commits are built
+/// in memory from the tree of the previous commit, like in a bare repo, which has nothing
+/// to look objects up in, so the store only commits the files as blobs there. The index
+/// is only read and written once per replication file, for the paths its commits changed.
+pub struct GitStore {
+    serializer: &'static dyn Serializer,
+    /// The paths changed since the index was last written
+    changed_paths: Mutex<BTreeSet<PathBuf>>,
+}
+
+impl StateStore for GitStore {
+    fn read_object(&self, repository: &Repository, path: &Path) -> Result<Option<OSMObject>> {
+        let Some(workdir) = repository.workdir() else {
+            return Ok(None);
+        };
+        let object_file_path = workdir.join(path);
+        if !object_file_path.exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read(&object_file_path)?;
+        Ok(Some(self.serializer.deserialize(&contents)?))
+    }
+
+    fn write_object(&self, repository: &Repository, path: &Path, object: &OSMObject) -> Result<()> {
+        let Some(workdir) = repository.workdir() else {
+            return Ok(());
+        };
+        let object_file_path = workdir.join(path);
+        std::fs::create_dir_all(object_file_path.parent().unwrap())?;
+        let mut object_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&object_file_path)?;
+        object_file.write_all(&self.serializer.serialize(object)?)?;
+        Ok(())
+    }
+
+    fn remove_object(&self, repository: &Repository, path: &Path) -> Result<()> {
+        let Some(workdir) = repository.workdir() else {
+            return Ok(());
+        };
+        let object_file_path = workdir.join(path);
+        if object_file_path.exists() {
+            std::fs::remove_file(object_file_path)?;
+        }
+        Ok(())
+    }
+
+    fn write_files(
+        &self,
+        repository: &Repository,
+        files: &BTreeMap<PathBuf, Option<Vec<u8>>>,
+    ) -> Result<()> {
+        let Some(workdir) = repository.workdir() else {
+            return Ok(());
+        };
+        for (path, contents) in files {
+            write_file(&workdir.join(path), contents.as_deref())?;
+        }
+        Ok(())
+    }
+
+    fn commit_files(
+        &self,
+        repository: &Repository,
+        files: BTreeMap<PathBuf, Option<Vec<u8>>>,
+        message: &str,
+        author: &Signature,
+        committer: &Signature,
+    ) -> Result<Oid> {
+        if repository.workdir().is_some() {
+            self.changed_paths
+                .lock()
+                .unwrap()
+                .extend(files.keys().cloned());
+        }
+        commit_blobs(repository, STAGING_REF, files, message, author, committer)
+    }
+
+    /// The changed paths are added to the index from the working directory, so it keeps
+    /// their file stats and `git status` doesn't hash them again. Files whose last stored
+    /// version is not the last committed one, as the changesets were committed in another
+    /// order than the replication file has them in, are checked out from the staged tree.
+    fn finish_commits(&self, repository: &Repository) -> Result<()> {
+        let Some(workdir) = repository.workdir() else {
+            return Ok(());
+        };
+        let changed_paths = std::mem::take(&mut *self.changed_paths.lock().unwrap());
+        if changed_paths.is_empty() {
+            return Ok(());
+        }
+        let staged_tree = repository.find_reference(STAGING_REF)?.peel_to_tree()?;
+        let mut index = repository.index()?;
+        for path in changed_paths {
+            let file_path = workdir.join(&path);
+            match staged_tree.get_path(&path) {
+                Ok(entry) => {
+                    if file_path.exists() {
+                        index.add_path(&path)?;
+                    }
+                    if index.get_path(&path, 0).map(|entry| entry.id) != Some(entry.id()) {
+                        let blob = repository.find_blob(entry.id())?;
+                        write_file(&file_path, Some(blob.content()))?;
+                        index.add_path(&path)?;
+                    }
+                }
+                Err(_) => {
+                    write_file(&file_path, None)?;
+                    if index.get_path(&path, 0).is_some() {
+                        index.remove_path(&path)?;
+                    }
+                }
+            }
+        }
+        index.write()?;
+        Ok(())
+    }
+}
+
+/// Write a file of the working directory, or remove it for `None`
+fn write_file(file_path: &Path, contents: Option<&[u8]>) -> Result<()> {
+    match contents {
+        Some(contents) => {
+            std::fs::create_dir_all(file_path.parent().unwrap())?;
+            std::fs::write(file_path, contents)?;
+        }
+        None => {
+            if file_path.exists() {
+                std::fs::remove_file(file_path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Experimental: keeps the objects in a SQLite database next to a bare git repo
+///
+/// Looking up an object is a query on the primary key instead of a path lookup in a
+/// working directory with millions of files. Commits are still built with the tree
+/// builder like in any bare repo. The database only knows the objects written since it
+/// was created, so it is meant to be used from the start of a replay.
+pub struct SqliteStore {
+    connection: Connection,
+    serializer: &'static dyn Serializer,
+    /// The paths committed since the last replication file
+    changed_paths: Mutex<BTreeSet<PathBuf>>,
+}
+
+impl SqliteStore {
+    pub fn open(repository: &Repository, serializer: &'static dyn Serializer) -> Result<Self> {
+        if !repository.is_bare() {
+            return Err(eyre!(
+                "The SQLite state store needs a bare git repo. Create it with --bare"
+            ));
+        }
+        let connection = Connection::open(repository.path().join(SQLITE_STORE_FILE))?;
+        connection.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = NORMAL;
+            CREATE TABLE IF NOT EXISTS objects (
+                path TEXT PRIMARY KEY,
+                contents BLOB NOT NULL
+            );",
+        )?;
+        Ok(SqliteStore {
+            connection,
+            serializer,
+            changed_paths: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    fn upsert(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO objects (path, contents) VALUES (?1, ?2)",
+            (path.to_string_lossy(), contents),
+        )?;
+        Ok(())
+    }
+}
+
+impl StateStore for SqliteStore {
+    fn read_object(&self, _repository: &Repository, path: &Path) -> Result<Option<OSMObject>> {
+        let contents: Option<Vec<u8>> = self
+            .connection
+            .query_row(
+                "SELECT contents FROM objects WHERE path = ?1",
+                [path.to_string_lossy()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        contents
+            .map(|contents| self.serializer.deserialize(&contents))
+            .transpose()
+    }
+
+    fn write_object(
+        &self,
+        _repository: &Repository,
+        path: &Path,
+        object: &OSMObject,
+    ) -> Result<()> {
+        self.upsert(path, &self.serializer.serialize(object)?)
+    }
+
+    fn remove_object(&self, _repository: &Repository, path: &Path) -> Result<()> {
+        self.connection.execute(
+            "DELETE FROM objects WHERE path = ?1",
+            [path.to_string_lossy()],
+        )?;
+        Ok(())
+    }
+
+    /// The files are stored in one transaction
+    fn write_files(
+        &self,
+        repository: &Repository,
+        files: &BTreeMap<PathBuf, Option<Vec<u8>>>,
+    ) -> Result<()> {
+        let transaction = self.connection.unchecked_transaction()?;
+        for (path, contents) in files {
+            match contents {
+                Some(contents) => self.upsert(path, contents)?,
+                None => self.remove_object(repository, path)?,
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn commit_files(
+        &self,
+        repository: &Repository,
+        files: BTreeMap<PathBuf, Option<Vec<u8>>>,
+        message: &str,
+        author: &Signature,
+        committer: &Signature,
+    ) -> Result<Oid> {
+        self.changed_paths
+            .lock()
+            .unwrap()
+            .extend(files.keys().cloned());
+        commit_blobs(repository, STAGING_REF, files, message, author, committer)
+    }
+
+    /// Objects whose last stored version is not the last committed one, as the
+    /// changesets were committed in another order than the replication file has them in,
+    /// are stored again from the staged tree
+    fn finish_commits(&self, repository: &Repository) -> Result<()> {
+        let changed_paths = std::mem::take(&mut *self.changed_paths.lock().unwrap());
+        if changed_paths.is_empty() {
+            return Ok(());
+        }
+        let staged_tree = repository.find_reference(STAGING_REF)?.peel_to_tree()?;
+        let transaction = self.connection.unchecked_transaction()?;
+        for path in changed_paths {
+            let stored: Option<Vec<u8>> = self
+                .connection
+                .query_row(
+                    "SELECT contents FROM objects WHERE path = ?1",
+                    [path.to_string_lossy()],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            match staged_tree.get_path(&path) {
+                Ok(entry) => {
+                    let blob = repository.find_blob(entry.id())?;
+                    if stored.as_deref() != Some(blob.content()) {
+                        self.upsert(&path, blob.content())?;
+                    }
+                }
+                Err(_) => {
+                    if stored.is_some() {
+                        self.remove_object(repository, &path)?;
+                    }
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::{signature, TestRepository};
+
+    #[test]
+    fn git_store_writes_files_once_and_follows_the_commit_order() {
+        let repository = TestRepository::new("git-store", false);
+        let workdir = repository.workdir().unwrap().to_path_buf();
+        let store = StateStoreKind::Git
+            .open(&repository, ObjectFormat::Yaml)
+            .unwrap();
+        let signature = signature();
+        let path = PathBuf::from("nodes/1.yaml");
+        let file =
+            |contents: &str| BTreeMap::from([(path.clone(), Some(contents.as_bytes().to_vec()))]);
+
+        // The replication file had the later version last, but the changesets are committed
+        // the other way round
+        std::fs::create_dir_all(workdir.join("nodes")).unwrap();
+        std::fs::write(workdir.join(&path), "later\n").unwrap();
+        store
+            .commit_files(
+                &repository,
+                file("later\n"),
+                "later",
+                &signature,
+                &signature,
+            )
+            .unwrap();
+        store
+            .commit_files(
+                &repository,
+                file("earlier\n"),
+                "earlier",
+                &signature,
+                &signature,
+            )
+            .unwrap();
+        // Committing doesn't write the working directory again
+        assert_eq!(
+            std::fs::read_to_string(workdir.join(&path)).unwrap(),
+            "later\n"
+        );
+
+        store.finish_commits(&repository).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(workdir.join(&path)).unwrap(),
+            "earlier\n"
+        );
+        let staged_tree = repository
+            .find_reference(STAGING_REF)
+            .unwrap()
+            .peel_to_tree()
+            .unwrap();
+        let index = repository.index().unwrap();
+        assert_eq!(
+            index.get_path(&path, 0).unwrap().id,
+            staged_tree.get_path(&path).unwrap().id()
+        );
+
+        // Removed files are removed from the working directory and the index
+        store
+            .commit_files(
+                &repository,
+                BTreeMap::from([(path.clone(), None)]),
+                "delete",
+                &signature,
+                &signature,
+            )
+            .unwrap();
+        store.finish_commits(&repository).unwrap();
+        assert!(!workdir.join(&path).exists());
+        assert!(repository.index().unwrap().get_path(&path, 0).is_none());
+    }
+}
@@ -0,0 +1,152 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use color_eyre::eyre::{eyre, Report, Result};
+
+use super::osm_data::OSMObject;
+
+/// Tag filter expressions like the ones of `osmium tags-filter`
+///
+/// An object matches the filter if it matches any of the expressions.
+#[derive(Debug, Clone)]
+pub struct TagFilter {
+    expressions: Vec<TagExpression>,
+}
+
+impl TagFilter {
+    pub fn parse(expressions: &[String]) -> Result<Self> {
+        Ok(TagFilter {
+            expressions: expressions
+                .iter()
+                .map(|expression| expression.parse())
+                .collect::<Result<Vec<TagExpression>>>()?,
+        })
+    }
+
+    /// Check if the tags of an object match any of the expressions
+    pub fn matches(&self, object: &OSMObject) -> bool {
+        self.matches_tags(object.type_name(), object.tags())
+    }
+
+    /// Check if an object of the type `node`, `way` or `relation` with these tags matches
+    pub fn matches_tags(&self, type_name: &str, tags: &BTreeMap<String, String>) -> bool {
+        self.expressions
+            .iter()
+            .any(|expression| expression.matches(type_name, tags))
+    }
+}
+
+/// One expression, `[TYPES/]KEY[=VALUES]` or `[TYPES/]KEY!=VALUES`
+///
+/// `TYPES` is any combination of `n`, `w` and `r` and defaults to all of them. `VALUES`
+/// is a comma separated list. Keys and values can start or end with `*` to match any
+/// prefix or suffix, so `highway=*` is the same as `highway` and `name:*` matches all
+/// name tags.
+#[derive(Debug, Clone)]
+struct TagExpression {
+    nodes: bool,
+    ways: bool,
+    relations: bool,
+    key: Pattern,
+    /// The values the tag must have, or with `!=` must not have
+    values: Option<(Vec<Pattern>, bool)>,
+}
+
+impl TagExpression {
+    fn matches(&self, type_name: &str, tags: &BTreeMap<String, String>) -> bool {
+        let type_matches = match type_name {
+            "node" => self.nodes,
+            "way" => self.ways,
+            _ => self.relations,
+        };
+        type_matches
+            && tags.iter().any(|(key, value)| {
+                self.key.matches(key)
+                    && match &self.values {
+                        None => true,
+                        Some((values, negated)) => {
+                            values.iter().any(|pattern| pattern.matches(value)) != *negated
+                        }
+                    }
+            })
+    }
+}
+
+impl FromStr for TagExpression {
+    type Err = Report;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        let (types, tag) = match expression.split_once('/') {
+            Some((types, tag)) if !types.is_empty() && types.chars().all(|c| "nwr".contains(c)) => {
+                (types, tag)
+            }
+            _ => ("nwr", expression),
+        };
+        let (key, values) = match tag.split_once('=') {
+            Some((key, values)) => match key.strip_suffix('!') {
+                Some(key) => (key, Some((values, true))),
+                None => (key, Some((values, false))),
+            },
+            None => (tag, None),
+        };
+        if key.is_empty() {
+            return Err(eyre!("The filter expression {:?} has no key", expression));
+        }
+        let values = values
+            .map(|(values, negated)| -> Result<(Vec<Pattern>, bool)> {
+                let values = values
+                    .split(',')
+                    .filter(|value| !value.is_empty())
+                    .map(Pattern::new)
+                    .collect::<Vec<Pattern>>();
+                if values.is_empty() {
+                    return Err(eyre!("The filter expression {:?} has no value", expression));
+                }
+                Ok((values, negated))
+            })
+            .transpose()?;
+        Ok(TagExpression {
+            nodes: types.contains('n'),
+            ways: types.contains('w'),
+            relations: types.contains('r'),
+            key: Pattern::new(key),
+            // `key=*` is the same as only the key
+            values: values.filter(|(values, negated)| {
+                *negated || !values.iter().any(|pattern| matches!(pattern, Pattern::Any))
+            }),
+        })
+    }
+}
+
+/// A key or value with an optional `*` at its start or end
+#[derive(Debug, Clone)]
+enum Pattern {
+    Any,
+    Exact(String),
+    Prefix(String),
+    Suffix(String),
+    Contains(String),
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Self {
+        if pattern == "*" {
+            return Pattern::Any;
+        }
+        match (pattern.strip_prefix('*'), pattern.strip_suffix('*')) {
+            (Some(rest), Some(_)) => Pattern::Contains(rest[..rest.len() - 1].to_string()),
+            (Some(suffix), None) => Pattern::Suffix(suffix.to_string()),
+            (None, Some(prefix)) => Pattern::Prefix(prefix.to_string()),
+            (None, None) => Pattern::Exact(pattern.to_string()),
+        }
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        match self {
+            Pattern::Any => true,
+            Pattern::Exact(exact) => text == exact,
+            Pattern::Prefix(prefix) => text.starts_with(prefix.as_str()),
+            Pattern::Suffix(suffix) => text.ends_with(suffix.as_str()),
+            Pattern::Contains(part) => text.contains(part.as_str()),
+        }
+    }
+}
@@ -0,0 +1,1274 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    f64::consts::PI,
+    io::Write,
+    path::Path,
+};
+
+use color_eyre::eyre::{eyre, Result};
+use flate2::{write::GzEncoder, Compression};
+use prost::Message;
+use rusqlite::Connection;
+use tracing::{info, warn};
+
+use super::{
+    geometry::{assemble_rings, ring_contains, Position},
+    osm_data::OSMObject,
+    snapshot::Snapshot,
+};
+
+/// The size of a tile in the coordinates of its geometries
+const EXTENT: u32 = 4096;
+
+/// How far geometries reach beyond the edge of a tile, so renderers don't draw seams
+const BUFFER: f64 = 64.0;
+
+/// The latitude limit of the Web Mercator projection
+const MAX_LATITUDE: f64 = 85.051_128_78;
+
+/// Keys which make a closed way an area
+const AREA_KEYS: &[&str] = &[
+    "amenity", "building", "landuse", "leisure", "natural", "place", "shop", "tourism",
+];
+
+/// The largest root directory of a PMTiles archive, so it fits into the first 16 KiB
+/// together with the header
+const PMTILES_MAX_ROOT_DIRECTORY: usize = 16384 - PMTILES_HEADER_SIZE;
+const PMTILES_HEADER_SIZE: usize = 127;
+
+/// The messages of the Mapbox Vector Tile format, as defined in `vector_tile.proto` of
+/// version 2.1 of the specification
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Tile {
+        #[prost(message, repeated, tag = "3")]
+        pub layers: Vec<Layer>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Value {
+        #[prost(string, optional, tag = "1")]
+        pub string_value: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Feature {
+        #[prost(uint64, optional, tag = "1")]
+        pub id: Option<u64>,
+        #[prost(uint32, repeated, tag = "2")]
+        pub tags: Vec<u32>,
+        #[prost(int32, optional, tag = "3")]
+        pub r#type: Option<i32>,
+        #[prost(uint32, repeated, tag = "4")]
+        pub geometry: Vec<u32>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Layer {
+        #[prost(uint32, required, tag = "15")]
+        pub version: u32,
+        #[prost(string, required, tag = "1")]
+        pub name: String,
+        #[prost(message, repeated, tag = "2")]
+        pub features: Vec<Feature>,
+        #[prost(string, repeated, tag = "3")]
+        pub keys: Vec<String>,
+        #[prost(message, repeated, tag = "4")]
+        pub values: Vec<Value>,
+        #[prost(uint32, optional, tag = "5")]
+        pub extent: Option<u32>,
+    }
+}
+
+/// The file format of a tileset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TilesetFormat {
+    /// A SQLite database as used by most tile servers
+    MbTiles,
+    /// A single file which can be served from static hosting with range requests
+    PmTiles,
+}
+
+impl TilesetFormat {
+    /// The format of a tileset file, by its extension
+    pub fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("mbtiles") => Ok(TilesetFormat::MbTiles),
+            Some("pmtiles") => Ok(TilesetFormat::PmTiles),
+            _ => Err(eyre!(
+                "Unable to tell the format of {}. Use a .mbtiles or .pmtiles file",
+                path.display()
+            )),
+        }
+    }
+}
+
+/// The layers of the tileset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LayerKind {
+    /// Nodes with tags
+    Points,
+    /// Ways which are not areas
+    Lines,
+    /// Closed ways with area tags and multipolygon relations
+    Polygons,
+}
+
+impl LayerKind {
+    const ALL: [LayerKind; 3] = [LayerKind::Points, LayerKind::Lines, LayerKind::Polygons];
+
+    fn name(self) -> &'static str {
+        match self {
+            LayerKind::Points => "points",
+            LayerKind::Lines => "lines",
+            LayerKind::Polygons => "polygons",
+        }
+    }
+
+    /// The geometry type of the features as defined by the specification
+    fn geometry_type(self) -> i32 {
+        match self {
+            LayerKind::Points => 1,
+            LayerKind::Lines => 2,
+            LayerKind::Polygons => 3,
+        }
+    }
+}
+
+/// The geometry of a feature in Web Mercator coordinates between 0 and 1
+enum Shape {
+    Point([f64; 2]),
+    Line(Vec<[f64; 2]>),
+    /// Polygons of an outer ring followed by its inner rings
+    Polygons(Vec<Vec<Vec<[f64; 2]>>>),
+}
+
+struct Feature {
+    id: u64,
+    layer: LayerKind,
+    tags: BTreeMap<String, String>,
+    shape: Shape,
+    /// The smallest and largest x and y of the shape
+    bounds: ([f64; 2], [f64; 2]),
+}
+
+/// What an export of a tileset wrote
+#[derive(Debug, Default)]
+pub struct TilesetSummary {
+    pub features: usize,
+    pub tiles: usize,
+}
+
+/// Write the objects of a snapshot as a basic vector tileset
+///
+/// The tileset has a `points` layer with the nodes which have tags, a `lines` layer with
+/// the ways and a `polygons` layer with the closed ways with area tags like `building`
+/// or `landuse` and the multipolygon relations. All tags are kept. Objects without
+/// tags, other relations and objects referencing objects missing from the snapshot are
+/// left out. This is meant to preview a small region on a map, not to replace a
+/// rendering pipeline: there is no generalization, all features are in all zoom levels.
+pub fn write_tileset(
+    snapshot: &Snapshot,
+    output: &Path,
+    min_zoom: u8,
+    max_zoom: u8,
+) -> Result<TilesetSummary> {
+    let format = TilesetFormat::from_path(output)?;
+    if min_zoom > max_zoom || max_zoom > 24 {
+        return Err(eyre!(
+            "The zoom levels must be between 0 and 24 with the minimum not above the maximum"
+        ));
+    }
+    let features = collect_features(snapshot)?;
+    if features.is_empty() {
+        return Err(eyre!("The snapshot has no objects to draw"));
+    }
+    let bounds = features.iter().fold(
+        ([f64::MAX, f64::MAX], [f64::MIN, f64::MIN]),
+        |(min, max), feature| {
+            (
+                [
+                    min[0].min(feature.bounds.0[0]),
+                    min[1].min(feature.bounds.0[1]),
+                ],
+                [
+                    max[0].max(feature.bounds.1[0]),
+                    max[1].max(feature.bounds.1[1]),
+                ],
+            )
+        },
+    );
+
+    let mut tiles = Vec::new();
+    for zoom in min_zoom..=max_zoom {
+        let zoom_tiles = render_zoom(&features, zoom)?;
+        info!("Rendered {} tiles at zoom {}", zoom_tiles.len(), zoom);
+        tiles.extend(zoom_tiles);
+    }
+
+    let metadata = TilesetMetadata {
+        min_zoom,
+        max_zoom,
+        // West, south, east, north
+        bounds: [
+            unproject(bounds.0)[0],
+            unproject(bounds.1)[1],
+            unproject(bounds.1)[0],
+            unproject(bounds.0)[1],
+        ]
+        .map(|degrees| (degrees * 1e7).round() / 1e7),
+        fields: layer_fields(&features),
+    };
+    if output.exists() {
+        std::fs::remove_file(output)?;
+    }
+    let summary = TilesetSummary {
+        features: features.len(),
+        tiles: tiles.len(),
+    };
+    match format {
+        TilesetFormat::MbTiles => write_mbtiles(output, &metadata, &tiles)?,
+        TilesetFormat::PmTiles => write_pmtiles(output, &metadata, tiles)?,
+    }
+    Ok(summary)
+}
+
+/// Project a `[lon, lat]` position to Web Mercator coordinates between 0 and 1
+fn project(position: Position) -> [f64; 2] {
+    let [lon, lat] = position;
+    let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    [
+        (lon + 180.0) / 360.0,
+        (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0,
+    ]
+}
+
+/// The `[lon, lat]` position of Web Mercator coordinates between 0 and 1
+fn unproject(point: [f64; 2]) -> Position {
+    let [x, y] = point;
+    [
+        x * 360.0 - 180.0,
+        (PI * (1.0 - 2.0 * y)).sinh().atan().to_degrees(),
+    ]
+}
+
+fn is_area(tags: &BTreeMap<String, String>) -> bool {
+    match tags.get("area").map(String::as_str) {
+        Some("yes") => true,
+        Some("no") => false,
+        _ => AREA_KEYS.iter().any(|key| tags.contains_key(*key)),
+    }
+}
+
+fn collect_features(snapshot: &Snapshot) -> Result<Vec<Feature>> {
+    let mut objects = snapshot
+        .objects()?
+        .into_iter()
+        .map(|(_, object)| object)
+        .collect::<Result<Vec<OSMObject>>>()?;
+    objects.sort_by_key(|object| (object.type_name(), object.id()));
+
+    let mut nodes = HashMap::new();
+    let mut ways = HashMap::new();
+    for object in &objects {
+        match object {
+            OSMObject::Node(node) => {
+                nodes.insert(node.id, [node.lon, node.lat]);
+            }
+            OSMObject::Way(way) => {
+                ways.insert(way.id, way.nodes.clone());
+            }
+            OSMObject::Relation(_) => (),
+        }
+    }
+    let positions = |node_ids: &[u64]| -> Option<Vec<[f64; 2]>> {
+        node_ids
+            .iter()
+            .map(|node_id| nodes.get(node_id).copied().map(project))
+            .collect()
+    };
+
+    let mut features = Vec::new();
+    let mut incomplete = 0;
+    for object in objects {
+        if object.tags().is_empty() {
+            continue;
+        }
+        let (layer, shape) = match &object {
+            OSMObject::Node(node) => (
+                LayerKind::Points,
+                Shape::Point(project([node.lon, node.lat])),
+            ),
+            OSMObject::Way(way) => {
+                let Some(line) = positions(&way.nodes) else {
+                    incomplete += 1;
+                    continue;
+                };
+                let closed = way.nodes.len() >= 4 && way.nodes.first() == way.nodes.last();
+                if closed && is_area(&way.tags) {
+                    (LayerKind::Polygons, Shape::Polygons(vec![vec![line]]))
+                } else {
+                    (LayerKind::Lines, Shape::Line(line))
+                }
+            }
+            OSMObject::Relation(relation) => {
+                if relation.tags.get("type").map(String::as_str) != Some("multipolygon") {
+                    continue;
+                }
+                let mut outer_ways = Vec::new();
+                let mut inner_ways = Vec::new();
+                for member in relation.member.iter().filter(|m| m.r#type == "way") {
+                    let Some(way) = ways.get(&member.ref_id) else {
+                        continue;
+                    };
+                    match member.role.as_deref() {
+                        Some("inner") => inner_ways.push(way.clone()),
+                        _ => outer_ways.push(way.clone()),
+                    }
+                }
+                let Some(polygons) = assemble_polygons(relation.id, outer_ways, inner_ways, &nodes)
+                    .and_then(|polygons| {
+                        polygons
+                            .iter()
+                            .map(|polygon| polygon.iter().map(|ring| positions(ring)).collect())
+                            .collect::<Option<Vec<Vec<Vec<[f64; 2]>>>>>()
+                    })
+                else {
+                    incomplete += 1;
+                    continue;
+                };
+                (LayerKind::Polygons, Shape::Polygons(polygons))
+            }
+        };
+        let bounds = shape_bounds(&shape);
+        features.push(Feature {
+            id: object.id(),
+            layer,
+            tags: object.tags().clone(),
+            shape,
+            bounds,
+        });
+    }
+    if incomplete > 0 {
+        warn!(
+            "Left out {} ways and relations with objects missing from the snapshot",
+            incomplete
+        );
+    }
+    Ok(features)
+}
+
+/// Assemble the rings of a multipolygon into polygons of node ids
+///
+/// # Returns
+///
+/// * `Option<Vec<Vec<Vec<u64>>>>` - `None` if the rings can't be closed or an inner ring
+///   is not inside any outer ring
+fn assemble_polygons(
+    relation_id: u64,
+    outer_ways: Vec<Vec<u64>>,
+    inner_ways: Vec<Vec<u64>>,
+    nodes: &HashMap<u64, Position>,
+) -> Option<Vec<Vec<Vec<u64>>>> {
+    let ring_positions = |ring: &[u64]| -> Option<Vec<Position>> {
+        ring.iter()
+            .map(|node_id| nodes.get(node_id).copied())
+            .collect()
+    };
+    let mut polygons = Vec::new();
+    for ring in assemble_rings(relation_id, outer_ways).ok()? {
+        polygons.push((ring_positions(&ring)?, vec![ring]));
+    }
+    for ring in assemble_rings(relation_id, inner_ways).ok()? {
+        let first = *nodes.get(ring.first()?)?;
+        let (_, polygon) = polygons
+            .iter_mut()
+            .find(|(outer, _)| ring_contains(outer, first))?;
+        polygon.push(ring);
+    }
+    Some(polygons.into_iter().map(|(_, polygon)| polygon).collect())
+}
+
+fn shape_bounds(shape: &Shape) -> ([f64; 2], [f64; 2]) {
+    let points: Vec<&[f64; 2]> = match shape {
+        Shape::Point(point) => vec![point],
+        Shape::Line(line) => line.iter().collect(),
+        Shape::Polygons(polygons) => polygons.iter().flatten().flatten().collect(),
+    };
+    points.iter().fold(
+        ([f64::MAX, f64::MAX], [f64::MIN, f64::MIN]),
+        |(min, max), point| {
+            (
+                [min[0].min(point[0]), min[1].min(point[1])],
+                [max[0].max(point[0]), max[1].max(point[1])],
+            )
+        },
+    )
+}
+
+/// The tag keys of the features of every layer
+fn layer_fields(features: &[Feature]) -> BTreeMap<LayerKind, BTreeSet<String>> {
+    let mut fields: BTreeMap<LayerKind, BTreeSet<String>> = LayerKind::ALL
+        .iter()
+        .map(|layer| (*layer, BTreeSet::new()))
+        .collect();
+    for feature in features {
+        fields
+            .entry(feature.layer)
+            .or_default()
+            .extend(feature.tags.keys().cloned());
+    }
+    fields
+}
+
+/// An encoded and compressed tile
+#[derive(Debug)]
+struct TileData {
+    zoom: u8,
+    x: u32,
+    y: u32,
+    data: Vec<u8>,
+}
+
+/// Cut the features into the tiles of a zoom level
+fn render_zoom(features: &[Feature], zoom: u8) -> Result<Vec<TileData>> {
+    let tiles_per_side = 1u32 << zoom;
+    let scale = tiles_per_side as f64;
+    let buffer = BUFFER / EXTENT as f64 / scale;
+    let tile_range = |min: f64, max: f64| {
+        let first = ((min - buffer) * scale).floor().max(0.0) as u32;
+        let last = ((max + buffer) * scale)
+            .floor()
+            .clamp(0.0, (tiles_per_side - 1) as f64) as u32;
+        first..=last
+    };
+
+    let mut tiles: BTreeMap<(u32, u32), TileBuilder> = BTreeMap::new();
+    for feature in features {
+        let (min, max) = feature.bounds;
+        for x in tile_range(min[0], max[0]) {
+            for y in tile_range(min[1], max[1]) {
+                let to_tile = |point: &[f64; 2]| {
+                    [
+                        (point[0] * scale - x as f64) * EXTENT as f64,
+                        (point[1] * scale - y as f64) * EXTENT as f64,
+                    ]
+                };
+                if let Some(geometry) = encode_shape(&feature.shape, &to_tile) {
+                    tiles.entry((x, y)).or_default().add(feature, geometry);
+                }
+            }
+        }
+    }
+    tiles
+        .into_iter()
+        .map(|((x, y), tile)| {
+            Ok(TileData {
+                zoom,
+                x,
+                y,
+                data: tile.finish()?,
+            })
+        })
+        .collect()
+}
+
+/// Clip a shape to the buffered area of a tile and encode it as the commands of the
+/// vector tile format
+///
+/// # Returns
+///
+/// * `Option<Vec<u32>>` - `None` if nothing of the shape is left in the tile
+fn encode_shape(shape: &Shape, to_tile: &dyn Fn(&[f64; 2]) -> [f64; 2]) -> Option<Vec<u32>> {
+    let (min, max) = (-BUFFER, EXTENT as f64 + BUFFER);
+    let mut encoder = GeometryEncoder::default();
+    match shape {
+        Shape::Point(point) => {
+            let point = to_tile(point);
+            if point
+                .iter()
+                .any(|coordinate| !(min..=max).contains(coordinate))
+            {
+                return None;
+            }
+            encoder.move_to(round(point));
+        }
+        Shape::Line(line) => {
+            let line = line.iter().map(to_tile).collect::<Vec<[f64; 2]>>();
+            for part in clip_line(&line, min, max) {
+                let part = quantize(&part);
+                if part.len() >= 2 {
+                    encoder.move_to(part[0]);
+                    encoder.line_to(&part[1..]);
+                }
+            }
+        }
+        Shape::Polygons(polygons) => {
+            for polygon in polygons {
+                for (index, ring) in polygon.iter().enumerate() {
+                    let ring = ring.iter().map(to_tile).collect::<Vec<[f64; 2]>>();
+                    let mut ring = quantize(&clip_ring(&ring, min, max));
+                    if ring.len() > 1 && ring.first() == ring.last() {
+                        ring.pop();
+                    }
+                    let area = ring_area(&ring);
+                    if ring.len() < 3 || area == 0 {
+                        // Without its outer ring, the inner rings of a polygon are dropped
+                        if index == 0 {
+                            break;
+                        }
+                        continue;
+                    }
+                    // Outer rings have a positive area in tile coordinates, inner rings a
+                    // negative one
+                    if (area > 0) != (index == 0) {
+                        ring.reverse();
+                    }
+                    encoder.move_to(ring[0]);
+                    encoder.line_to(&ring[1..]);
+                    encoder.close_path();
+                }
+            }
+        }
+    }
+    (!encoder.commands.is_empty()).then_some(encoder.commands)
+}
+
+fn round(point: [f64; 2]) -> [i32; 2] {
+    [point[0].round() as i32, point[1].round() as i32]
+}
+
+/// Round the points to tile coordinates, dropping points which end up on the previous one
+fn quantize(points: &[[f64; 2]]) -> Vec<[i32; 2]> {
+    let mut quantized: Vec<[i32; 2]> = Vec::with_capacity(points.len());
+    for point in points {
+        let point = round(*point);
+        if quantized.last() != Some(&point) {
+            quantized.push(point);
+        }
+    }
+    quantized
+}
+
+/// Twice the area of a ring by the surveyor's formula
+fn ring_area(ring: &[[i32; 2]]) -> i64 {
+    ring.iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|(a, b)| a[0] as i64 * b[1] as i64 - b[0] as i64 * a[1] as i64)
+        .sum()
+}
+
+/// Clip a line to the square from `min` to `max`, which may split it into several parts
+fn clip_line(line: &[[f64; 2]], min: f64, max: f64) -> Vec<Vec<[f64; 2]>> {
+    let mut parts = Vec::new();
+    let mut part: Vec<[f64; 2]> = Vec::new();
+    for segment in line.windows(2) {
+        match clip_segment(segment[0], segment[1], min, max) {
+            Some((start, end)) => {
+                if part.last() != Some(&start) {
+                    parts.push(std::mem::take(&mut part));
+                    part.push(start);
+                }
+                part.push(end);
+            }
+            None => parts.push(std::mem::take(&mut part)),
+        }
+    }
+    parts.push(part);
+    parts.retain(|part| part.len() >= 2);
+    parts
+}
+
+/// Clip a segment to the square from `min` to `max` with the Liang-Barsky algorithm
+fn clip_segment(a: [f64; 2], b: [f64; 2], min: f64, max: f64) -> Option<([f64; 2], [f64; 2])> {
+    let delta = [b[0] - a[0], b[1] - a[1]];
+    let (mut start, mut end) = (0.0, 1.0);
+    for (p, q) in [
+        (-delta[0], a[0] - min),
+        (delta[0], max - a[0]),
+        (-delta[1], a[1] - min),
+        (delta[1], max - a[1]),
+    ] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+            continue;
+        }
+        let t = q / p;
+        if p < 0.0 {
+            if t > end {
+                return None;
+            }
+            start = f64::max(start, t);
+        } else {
+            if t < start {
+                return None;
+            }
+            end = f64::min(end, t);
+        }
+    }
+    let point = |t: f64| match t {
+        0.0 => a,
+        1.0 => b,
+        t => [a[0] + t * delta[0], a[1] + t * delta[1]],
+    };
+    Some((point(start), point(end)))
+}
+
+/// Clip a ring to the square from `min` to `max` with the Sutherland-Hodgman algorithm
+fn clip_ring(ring: &[[f64; 2]], min: f64, max: f64) -> Vec<[f64; 2]> {
+    let mut output = ring.to_vec();
+    for (axis, bound, keep_above) in [
+        (0, min, true),
+        (0, max, false),
+        (1, min, true),
+        (1, max, false),
+    ] {
+        let input = std::mem::take(&mut output);
+        let Some(mut previous) = input.last().copied() else {
+            break;
+        };
+        let inside = |point: &[f64; 2]| {
+            if keep_above {
+                point[axis] >= bound
+            } else {
+                point[axis] <= bound
+            }
+        };
+        let intersection = |a: [f64; 2], b: [f64; 2]| {
+            let t = (bound - a[axis]) / (b[axis] - a[axis]);
+            [a[0] + t * (b[0] - a[0]), a[1] + t * (b[1] - a[1])]
+        };
+        for point in input {
+            if inside(&point) {
+                if !inside(&previous) {
+                    output.push(intersection(previous, point));
+                }
+                output.push(point);
+            } else if inside(&previous) {
+                output.push(intersection(previous, point));
+            }
+            previous = point;
+        }
+    }
+    output
+}
+
+/// Writes the commands of a geometry, relative to the previous point
+#[derive(Default)]
+struct GeometryEncoder {
+    commands: Vec<u32>,
+    cursor: [i32; 2],
+}
+
+impl GeometryEncoder {
+    fn command(&mut self, id: u32, count: usize) {
+        self.commands.push((id & 0x7) | ((count as u32) << 3));
+    }
+
+    fn point(&mut self, point: [i32; 2]) {
+        for (coordinate, cursor) in point.iter().zip(self.cursor) {
+            let delta = coordinate - cursor;
+            self.commands.push(((delta << 1) ^ (delta >> 31)) as u32);
+        }
+        self.cursor = point;
+    }
+
+    fn move_to(&mut self, point: [i32; 2]) {
+        self.command(1, 1);
+        self.point(point);
+    }
+
+    fn line_to(&mut self, points: &[[i32; 2]]) {
+        self.command(2, points.len());
+        for point in points {
+            self.point(*point);
+        }
+    }
+
+    fn close_path(&mut self) {
+        self.command(7, 1);
+    }
+}
+
+/// The features of one layer of a tile, with their tags as indices into the key and
+/// value tables of the layer
+#[derive(Default)]
+struct LayerBuilder {
+    features: Vec<proto::Feature>,
+    keys: Vec<String>,
+    key_indices: HashMap<String, u32>,
+    values: Vec<String>,
+    value_indices: HashMap<String, u32>,
+}
+
+impl LayerBuilder {
+    fn add(&mut self, feature: &Feature, geometry: Vec<u32>) {
+        let mut tags = Vec::with_capacity(feature.tags.len() * 2);
+        for (key, value) in &feature.tags {
+            tags.push(table_index(&mut self.keys, &mut self.key_indices, key));
+            tags.push(table_index(
+                &mut self.values,
+                &mut self.value_indices,
+                value,
+            ));
+        }
+        self.features.push(proto::Feature {
+            id: Some(feature.id),
+            tags,
+            r#type: Some(feature.layer.geometry_type()),
+            geometry,
+        });
+    }
+
+    fn finish(self, layer: LayerKind) -> proto::Layer {
+        proto::Layer {
+            version: 2,
+            name: layer.name().to_string(),
+            features: self.features,
+            keys: self.keys,
+            values: self
+                .values
+                .into_iter()
+                .map(|value| proto::Value {
+                    string_value: Some(value),
+                })
+                .collect(),
+            extent: Some(EXTENT),
+        }
+    }
+}
+
+fn table_index(table: &mut Vec<String>, indices: &mut HashMap<String, u32>, entry: &str) -> u32 {
+    if let Some(index) = indices.get(entry) {
+        return *index;
+    }
+    let index = table.len() as u32;
+    table.push(entry.to_string());
+    indices.insert(entry.to_string(), index);
+    index
+}
+
+#[derive(Default)]
+struct TileBuilder {
+    layers: BTreeMap<LayerKind, LayerBuilder>,
+}
+
+impl TileBuilder {
+    fn add(&mut self, feature: &Feature, geometry: Vec<u32>) {
+        self.layers
+            .entry(feature.layer)
+            .or_default()
+            .add(feature, geometry);
+    }
+
+    /// The gzipped tile
+    fn finish(self) -> Result<Vec<u8>> {
+        let tile = proto::Tile {
+            layers: self
+                .layers
+                .into_iter()
+                .map(|(layer, builder)| builder.finish(layer))
+                .collect(),
+        };
+        gzip(&tile.encode_to_vec())
+    }
+}
+
+fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// What a tileset describes itself with
+struct TilesetMetadata {
+    min_zoom: u8,
+    max_zoom: u8,
+    /// West, south, east and north
+    bounds: [f64; 4],
+    /// The tag keys of the features of every layer
+    fields: BTreeMap<LayerKind, BTreeSet<String>>,
+}
+
+impl TilesetMetadata {
+    /// The `[lon, lat]` center of the bounds
+    fn center(&self) -> Position {
+        [
+            (self.bounds[0] + self.bounds[2]) / 2.0,
+            (self.bounds[1] + self.bounds[3]) / 2.0,
+        ]
+    }
+
+    /// The TileJSON description of the layers
+    fn vector_layers(&self) -> serde_json::Value {
+        let layers = self
+            .fields
+            .iter()
+            .map(|(layer, fields)| {
+                serde_json::json!({
+                    "id": layer.name(),
+                    "fields": fields
+                        .iter()
+                        .map(|field| (field.clone(), serde_json::json!("String")))
+                        .collect::<serde_json::Map<String, serde_json::Value>>(),
+                    "minzoom": self.min_zoom,
+                    "maxzoom": self.max_zoom,
+                })
+            })
+            .collect::<Vec<serde_json::Value>>();
+        serde_json::json!({ "vector_layers": layers })
+    }
+}
+
+fn write_mbtiles(output: &Path, metadata: &TilesetMetadata, tiles: &[TileData]) -> Result<()> {
+    let mut connection = Connection::open(output)?;
+    connection.execute_batch(
+        "CREATE TABLE metadata (name TEXT, value TEXT);
+        CREATE TABLE tiles (
+            zoom_level INTEGER,
+            tile_column INTEGER,
+            tile_row INTEGER,
+            tile_data BLOB
+        );
+        CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);",
+    )?;
+    let transaction = connection.transaction()?;
+    {
+        let center = metadata.center();
+        let mut insert =
+            transaction.prepare("INSERT INTO metadata (name, value) VALUES (?1, ?2)")?;
+        for (name, value) in [
+            ("name", "osm-git".to_string()),
+            ("format", "pbf".to_string()),
+            ("type", "overlay".to_string()),
+            ("minzoom", metadata.min_zoom.to_string()),
+            ("maxzoom", metadata.max_zoom.to_string()),
+            (
+                "bounds",
+                metadata
+                    .bounds
+                    .iter()
+                    .map(f64::to_string)
+                    .collect::<Vec<String>>()
+                    .join(","),
+            ),
+            (
+                "center",
+                format!("{},{},{}", center[0], center[1], metadata.min_zoom),
+            ),
+            ("json", metadata.vector_layers().to_string()),
+        ] {
+            insert.execute((name, value))?;
+        }
+
+        // MBTiles count the rows from the south like TMS
+        let mut insert = transaction.prepare(
+            "INSERT INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for tile in tiles {
+            let row = (1u32 << tile.zoom) - 1 - tile.y;
+            insert.execute((tile.zoom, tile.x, row, &tile.data))?;
+        }
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+/// An entry of a PMTiles directory
+///
+/// Entries with a run length of 0 point to a leaf directory instead of tile data.
+struct DirectoryEntry {
+    tile_id: u64,
+    offset: u64,
+    length: u32,
+    run_length: u32,
+}
+
+/// Write a version 3 PMTiles archive
+///
+/// Tiles with the same contents are only stored once, and runs of them with consecutive
+/// tile ids share a directory entry.
+fn write_pmtiles(output: &Path, metadata: &TilesetMetadata, tiles: Vec<TileData>) -> Result<()> {
+    let mut tiles = tiles
+        .into_iter()
+        .map(|tile| (tile_id(tile.zoom, tile.x, tile.y), tile.data))
+        .collect::<Vec<(u64, Vec<u8>)>>();
+    tiles.sort_by_key(|(tile_id, _)| *tile_id);
+    let addressed_tiles = tiles.len() as u64;
+
+    let mut tile_data = Vec::new();
+    let mut stored: HashMap<Vec<u8>, (u64, u32)> = HashMap::new();
+    let mut entries: Vec<DirectoryEntry> = Vec::new();
+    for (tile_id, data) in tiles {
+        let (offset, length) = match stored.get(&data) {
+            Some(location) => *location,
+            None => {
+                let location = (tile_data.len() as u64, data.len() as u32);
+                tile_data.extend_from_slice(&data);
+                stored.insert(data, location);
+                location
+            }
+        };
+        match entries.last_mut() {
+            Some(last)
+                if last.tile_id + last.run_length as u64 == tile_id
+                    && last.offset == offset
+                    && last.length == length =>
+            {
+                last.run_length += 1
+            }
+            _ => entries.push(DirectoryEntry {
+                tile_id,
+                offset,
+                length,
+                run_length: 1,
+            }),
+        }
+    }
+
+    let (root_directory, leaf_directories) = build_directories(&entries)?;
+    let metadata_json = gzip(metadata.vector_layers().to_string().as_bytes())?;
+
+    let root_offset = PMTILES_HEADER_SIZE as u64;
+    let metadata_offset = root_offset + root_directory.len() as u64;
+    let leaf_offset = metadata_offset + metadata_json.len() as u64;
+    let tile_data_offset = leaf_offset + leaf_directories.len() as u64;
+    let e7 = |degrees: f64| ((degrees * 1e7).round() as i32).to_le_bytes();
+    let center = metadata.center();
+
+    let mut header = Vec::with_capacity(PMTILES_HEADER_SIZE);
+    header.extend_from_slice(b"PMTiles");
+    header.push(3);
+    for value in [
+        root_offset,
+        root_directory.len() as u64,
+        metadata_offset,
+        metadata_json.len() as u64,
+        leaf_offset,
+        leaf_directories.len() as u64,
+        tile_data_offset,
+        tile_data.len() as u64,
+        addressed_tiles,
+        entries.len() as u64,
+        stored.len() as u64,
+    ] {
+        header.extend_from_slice(&value.to_le_bytes());
+    }
+    // Clustered, gzipped directories and tiles, vector tiles
+    header.extend_from_slice(&[1, 2, 2, 1, metadata.min_zoom, metadata.max_zoom]);
+    for degrees in metadata.bounds {
+        header.extend_from_slice(&e7(degrees));
+    }
+    header.push(metadata.min_zoom);
+    header.extend_from_slice(&e7(center[0]));
+    header.extend_from_slice(&e7(center[1]));
+
+    let mut file = std::io::BufWriter::new(std::fs::File::create(output)?);
+    for part in [
+        &header,
+        &root_directory,
+        &metadata_json,
+        &leaf_directories,
+        &tile_data,
+    ] {
+        file.write_all(part)?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// The position of a tile on the Hilbert curve through all tiles of all zoom levels
+fn tile_id(zoom: u8, x: u32, y: u32) -> u64 {
+    let tiles_before = ((1u64 << (2 * zoom as u64)) - 1) / 3;
+    let side = 1u64 << zoom;
+    let (mut x, mut y) = (x as u64, y as u64);
+    let mut position = 0;
+    let mut s = side / 2;
+    while s > 0 {
+        let rx = u64::from(x & s > 0);
+        let ry = u64::from(y & s > 0);
+        position += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = side - 1 - x;
+                y = side - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    tiles_before + position
+}
+
+/// The root directory and the leaf directories of the entries
+///
+/// The entries are split into leaf directories if the root directory would not fit into
+/// the first 16 KiB of the archive.
+fn build_directories(entries: &[DirectoryEntry]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let root = serialize_directory(entries)?;
+    if root.len() <= PMTILES_MAX_ROOT_DIRECTORY {
+        return Ok((root, Vec::new()));
+    }
+    let mut leaf_size = 4096;
+    loop {
+        let mut leaves = Vec::new();
+        let mut root_entries = Vec::new();
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = serialize_directory(chunk)?;
+            root_entries.push(DirectoryEntry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: leaf.len() as u32,
+                run_length: 0,
+            });
+            leaves.extend(leaf);
+        }
+        let root = serialize_directory(&root_entries)?;
+        if root.len() <= PMTILES_MAX_ROOT_DIRECTORY {
+            return Ok((root, leaves));
+        }
+        leaf_size *= 2;
+    }
+}
+
+/// Serialize and gzip a directory: the number of entries followed by the delta encoded
+/// tile ids, the run lengths, the lengths and the offsets of all entries
+fn serialize_directory(entries: &[DirectoryEntry]) -> Result<Vec<u8>> {
+    let mut directory = Vec::new();
+    write_varint(&mut directory, entries.len() as u64);
+    let mut last_tile_id = 0;
+    for entry in entries {
+        write_varint(&mut directory, entry.tile_id - last_tile_id);
+        last_tile_id = entry.tile_id;
+    }
+    for entry in entries {
+        write_varint(&mut directory, entry.run_length as u64);
+    }
+    for entry in entries {
+        write_varint(&mut directory, entry.length as u64);
+    }
+    for (index, entry) in entries.iter().enumerate() {
+        // 0 marks an entry which directly follows the previous one
+        let follows_previous = index > 0
+            && entry.offset == entries[index - 1].offset + entries[index - 1].length as u64;
+        write_varint(
+            &mut directory,
+            if follows_previous {
+                0
+            } else {
+                entry.offset + 1
+            },
+        );
+    }
+    gzip(&directory)
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    fn encode(shape: &Shape) -> Option<Vec<u32>> {
+        encode_shape(shape, &|point| *point)
+    }
+
+    /// The entries of a serialized directory as tile id, offset, length and run length
+    fn read_directory(directory: &[u8]) -> Vec<(u64, u64, u32, u32)> {
+        let mut data = Vec::new();
+        GzDecoder::new(directory).read_to_end(&mut data).unwrap();
+        let mut bytes = data.into_iter();
+        let mut varint = || {
+            let (mut value, mut shift) = (0u64, 0);
+            loop {
+                let byte = bytes.next().unwrap();
+                value |= u64::from(byte & 0x7f) << shift;
+                if byte < 0x80 {
+                    return value;
+                }
+                shift += 7;
+            }
+        };
+        let count = varint() as usize;
+        let mut tile_id = 0;
+        let tile_ids = (0..count)
+            .map(|_| {
+                tile_id += varint();
+                tile_id
+            })
+            .collect::<Vec<u64>>();
+        let run_lengths = (0..count).map(|_| varint() as u32).collect::<Vec<u32>>();
+        let lengths = (0..count).map(|_| varint() as u32).collect::<Vec<u32>>();
+        let mut entries: Vec<(u64, u64, u32, u32)> = Vec::new();
+        for index in 0..count {
+            let offset = match (varint(), entries.last()) {
+                (0, Some(&(_, offset, length, _))) => offset + length as u64,
+                (offset, _) => offset - 1,
+            };
+            entries.push((tile_ids[index], offset, lengths[index], run_lengths[index]));
+        }
+        entries
+    }
+
+    #[test]
+    fn encodes_the_geometries_of_the_specification() {
+        // The examples of section 4.3.5 of the vector tile specification
+        assert_eq!(encode(&Shape::Point([25.0, 17.0])), Some(vec![9, 50, 34]));
+        assert_eq!(
+            encode(&Shape::Line(vec![[2.0, 2.0], [2.0, 10.0], [10.0, 10.0]])),
+            Some(vec![9, 4, 4, 18, 0, 16, 16, 0])
+        );
+        let ring = vec![[3.0, 6.0], [8.0, 12.0], [20.0, 34.0], [3.0, 6.0]];
+        assert_eq!(
+            encode(&Shape::Polygons(vec![vec![ring.clone()]])),
+            Some(vec![9, 6, 12, 18, 10, 12, 24, 44, 15])
+        );
+        // An inner ring is wound the other way round
+        assert_eq!(
+            encode(&Shape::Polygons(vec![vec![ring.clone(), ring]])),
+            Some(vec![
+                9, 6, 12, 18, 10, 12, 24, 44, 15, 9, 0, 0, 18, 23, 43, 9, 11, 15
+            ])
+        );
+    }
+
+    #[test]
+    fn zigzag_encodes_the_deltas_of_the_points() {
+        let mut encoder = GeometryEncoder::default();
+        encoder.move_to([-1, 1]);
+        encoder.line_to(&[[-3, 1], [4160, 1]]);
+        encoder.close_path();
+        assert_eq!(encoder.commands, vec![9, 1, 2, 18, 3, 0, 8326, 0, 15]);
+    }
+
+    #[test]
+    fn drops_what_is_outside_the_buffer() {
+        assert_eq!(encode(&Shape::Point([-64.0, 0.0])), Some(vec![9, 127, 0]));
+        assert_eq!(encode(&Shape::Point([-65.0, 0.0])), None);
+        assert_eq!(
+            encode(&Shape::Line(vec![[-100.0, -100.0], [-200.0, -100.0]])),
+            None
+        );
+        // A polygon without area is dropped
+        let flat = vec![[1.0, 1.0], [2.0, 2.0], [3.0, 3.0], [1.0, 1.0]];
+        assert_eq!(encode(&Shape::Polygons(vec![vec![flat]])), None);
+    }
+
+    #[test]
+    fn numbers_tiles_along_the_hilbert_curve() {
+        // The tile ids of the test suite of the PMTiles reference implementation
+        let cases = [
+            ((0, 0, 0), 0),
+            ((1, 0, 0), 1),
+            ((1, 0, 1), 2),
+            ((1, 1, 1), 3),
+            ((1, 1, 0), 4),
+            ((2, 0, 0), 5),
+            ((3, 0, 0), 21),
+            ((12, 3423, 1763), 19078479),
+        ];
+        for ((zoom, x, y), expected) in cases {
+            assert_eq!(tile_id(zoom, x, y), expected, "{}/{}/{}", zoom, x, y);
+        }
+    }
+
+    #[test]
+    fn writes_varints() {
+        for (value, expected) in [
+            (0, vec![0]),
+            (127, vec![0x7f]),
+            (128, vec![0x80, 0x01]),
+            (300, vec![0xac, 0x02]),
+            (u64::MAX, [vec![0xff; 9], vec![0x01]].concat()),
+        ] {
+            let mut buffer = Vec::new();
+            write_varint(&mut buffer, value);
+            assert_eq!(buffer, expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn serializes_directories_with_delta_coded_columns() {
+        let entries = [
+            DirectoryEntry {
+                tile_id: 0,
+                offset: 0,
+                length: 10,
+                run_length: 1,
+            },
+            DirectoryEntry {
+                tile_id: 1,
+                offset: 10,
+                length: 20,
+                run_length: 2,
+            },
+            DirectoryEntry {
+                tile_id: 5,
+                offset: 0,
+                length: 10,
+                run_length: 1,
+            },
+        ];
+        let directory = serialize_directory(&entries).unwrap();
+        let mut data = Vec::new();
+        GzDecoder::new(directory.as_slice())
+            .read_to_end(&mut data)
+            .unwrap();
+        // The count, tile id deltas, run lengths, lengths, and offsets plus one, with 0
+        // for an entry directly following the previous one
+        assert_eq!(data, [3, 0, 1, 4, 1, 2, 1, 10, 20, 10, 1, 0, 1]);
+        assert_eq!(
+            read_directory(&directory),
+            [(0, 0, 10, 1), (1, 10, 20, 2), (5, 0, 10, 1)]
+        );
+    }
+
+    #[test]
+    fn splits_large_directories_into_leaves() {
+        // Lengths which don't compress well, so the directory outgrows the root
+        let entries = (0..20_000u64)
+            .scan(0, |offset, tile_id| {
+                let length = (tile_id * 7919 % 65_521) as u32 + 1;
+                let entry = DirectoryEntry {
+                    tile_id: tile_id * 2,
+                    offset: *offset,
+                    length,
+                    run_length: 1,
+                };
+                *offset += length as u64;
+                Some(entry)
+            })
+            .collect::<Vec<DirectoryEntry>>();
+        let (root, leaves) = build_directories(&entries).unwrap();
+        assert!(root.len() <= PMTILES_MAX_ROOT_DIRECTORY);
+        assert!(!leaves.is_empty());
+
+        let mut read_entries = Vec::new();
+        for (tile_id, offset, length, run_length) in read_directory(&root) {
+            // Root entries point to their leaf
+            assert_eq!(run_length, 0);
+            let leaf = read_directory(&leaves[offset as usize..][..length as usize]);
+            assert_eq!(leaf[0].0, tile_id);
+            read_entries.extend(leaf);
+        }
+        assert_eq!(
+            read_entries,
+            entries
+                .iter()
+                .map(|entry| (entry.tile_id, entry.offset, entry.length, entry.run_length))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,789 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    time::Duration,
+};
+
+use color_eyre::eyre::{eyre, Result};
+use git2::{Commit, Oid, Repository, Signature};
+use quick_xml::{escape::escape, events::Event, Reader};
+use reqwest::Method;
+use tracing::info;
+
+use super::{
+    id_mapping::IdMapper,
+    layout::{Layout, ShardBudget},
+    osm_data::{read_osm_objects, OSMObject},
+    squash::append_trailers,
+};
+use crate::git::{commit_head_files, revert::object_changes};
+
+/// The ref marking the last commit whose edits were uploaded to the OSM API
+pub const UPLOAD_MARKER_REF: &str = "refs/osm/uploaded";
+/// The most elements the OSM API accepts in one changeset
+const MAX_CHANGESET_ELEMENTS: usize = 10_000;
+/// The most characters of a changeset tag value
+const MAX_COMMENT_LENGTH: usize = 255;
+/// The trailer naming the changeset the edits of a commit were uploaded in
+const UPLOADED_CHANGESET_TRAILER: &str = "Uploaded-Changeset";
+/// The most objects fetched from the API at once, keeping the URLs short
+const FETCH_BATCH_SIZE: usize = 200;
+
+/// The local edits to upload, from the objects changed between two commits
+///
+/// Objects which are not in the base commit are created. They get negative placeholder
+/// ids in the upload, and the references of other uploaded objects to them are rewritten
+/// to the placeholders, so new ways can be drawn with new nodes.
+pub struct UploadPlan {
+    pub base: Oid,
+    pub head: Oid,
+    /// The summaries of the uploaded commits, oldest first
+    pub summaries: Vec<String>,
+    created: Vec<OSMObject>,
+    modified: Vec<Edit>,
+    /// The last versions of the deleted objects
+    deleted: Vec<(OSMObject, String)>,
+    placeholders: HashMap<(&'static str, u64), i64>,
+}
+
+/// An object edited locally
+struct Edit {
+    /// The object at the base commit
+    base: OSMObject,
+    object: OSMObject,
+    /// The version the edit is uploaded against, the one of the base commit unless it
+    /// was merged with a newer version upstream
+    version: String,
+}
+
+/// The id the OSM API gave an element of an upload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffResult {
+    pub type_name: &'static str,
+    pub old_id: i64,
+    /// `None` for deleted elements
+    pub new_id: Option<u64>,
+    pub new_version: Option<u64>,
+}
+
+/// An edited or deleted object which was changed upstream since the base commit
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub type_name: &'static str,
+    pub id: u64,
+    /// The version the local edit is based on
+    pub base_version: String,
+    /// The current version upstream, `None` if the API doesn't know the object
+    pub live_version: Option<String>,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} (edited from version {}, upstream is at {})",
+            self.type_name,
+            self.id,
+            self.reason,
+            self.base_version,
+            self.live_version.as_deref().unwrap_or("none")
+        )
+    }
+}
+
+fn type_order(type_name: &str) -> u8 {
+    match type_name {
+        "node" => 0,
+        "way" => 1,
+        _ => 2,
+    }
+}
+
+fn static_type_name(type_name: &str) -> Option<&'static str> {
+    match type_name {
+        "node" => Some("node"),
+        "way" => Some("way"),
+        "relation" => Some("relation"),
+        _ => None,
+    }
+}
+
+fn version(object: &OSMObject) -> Option<&String> {
+    match object {
+        OSMObject::Node(node) => node.legacy_object_version.as_ref(),
+        OSMObject::Way(way) => way.legacy_object_version.as_ref(),
+        OSMObject::Relation(relation) => relation.legacy_object_version.as_ref(),
+    }
+}
+
+fn set_version(object: &mut OSMObject, version: u64) {
+    let version = Some(version.to_string());
+    match object {
+        OSMObject::Node(node) => node.legacy_object_version = version,
+        OSMObject::Way(way) => way.legacy_object_version = version,
+        OSMObject::Relation(relation) => relation.legacy_object_version = version,
+    }
+}
+
+fn is_visible(object: &OSMObject) -> bool {
+    let visible = match object {
+        OSMObject::Node(node) => node.visible,
+        OSMObject::Way(way) => way.visible,
+        OSMObject::Relation(relation) => relation.visible,
+    };
+    visible != Some(false)
+}
+
+/// Check if two objects have the same coordinates, nodes or members
+fn same_geometry(a: &OSMObject, b: &OSMObject) -> bool {
+    match (a, b) {
+        (OSMObject::Node(a), OSMObject::Node(b)) => a.lat == b.lat && a.lon == b.lon,
+        (OSMObject::Way(a), OSMObject::Way(b)) => a.nodes == b.nodes,
+        (OSMObject::Relation(a), OSMObject::Relation(b)) => a.member == b.member,
+        _ => false,
+    }
+}
+
+fn copy_geometry(target: &mut OSMObject, source: &OSMObject) {
+    match (target, source) {
+        (OSMObject::Node(target), OSMObject::Node(source)) => {
+            target.lat = source.lat;
+            target.lon = source.lon;
+        }
+        (OSMObject::Way(target), OSMObject::Way(source)) => target.nodes = source.nodes.clone(),
+        (OSMObject::Relation(target), OSMObject::Relation(source)) => {
+            target.member = source.member.clone()
+        }
+        _ => {}
+    }
+}
+
+fn set_tags(object: &mut OSMObject, tags: BTreeMap<String, String>) {
+    match object {
+        OSMObject::Node(node) => node.tags = tags,
+        OSMObject::Way(way) => way.tags = tags,
+        OSMObject::Relation(relation) => relation.tags = tags,
+    }
+}
+
+/// Merge the local and upstream changes of an object since the base version
+///
+/// Tags are merged key by key: a key changed on one side only gets that value, and a key
+/// changed on both sides only merges if both set the same value. The coordinates, nodes
+/// or members may only be changed on one side, as there is no sensible merge of two
+/// edits of a geometry. The merge is the upstream version with the local changes on top.
+///
+/// # Returns
+///
+/// * `Result<OSMObject, String>` - The merged object, or why the changes can't be merged
+fn three_way_merge(
+    base: &OSMObject,
+    local: &OSMObject,
+    live: &OSMObject,
+) -> std::result::Result<OSMObject, String> {
+    let mut merged = live.clone();
+    merged.set_id(local.id());
+    // The API marks current versions as visible, which the files of the git repo leave out
+    match (&mut merged, local) {
+        (OSMObject::Node(merged), OSMObject::Node(local)) => merged.visible = local.visible,
+        (OSMObject::Way(merged), OSMObject::Way(local)) => merged.visible = local.visible,
+        (OSMObject::Relation(merged), OSMObject::Relation(local)) => merged.visible = local.visible,
+        _ => return Err("changed its type upstream".to_string()),
+    }
+    if !same_geometry(local, base) {
+        if !same_geometry(live, base) && !same_geometry(live, local) {
+            return Err("had its geometry changed upstream as well".to_string());
+        }
+        copy_geometry(&mut merged, local);
+    }
+
+    let (base_tags, local_tags, live_tags) = (base.tags(), local.tags(), live.tags());
+    let mut tags = BTreeMap::new();
+    let mut conflicting = Vec::new();
+    let keys = base_tags
+        .keys()
+        .chain(local_tags.keys())
+        .chain(live_tags.keys())
+        .collect::<std::collections::BTreeSet<&String>>();
+    for key in keys {
+        let (base_value, local_value, live_value) =
+            (base_tags.get(key), local_tags.get(key), live_tags.get(key));
+        let value = if local_value == base_value {
+            live_value
+        } else if live_value == base_value || live_value == local_value {
+            local_value
+        } else {
+            conflicting.push(key.as_str());
+            continue;
+        };
+        if let Some(value) = value {
+            tags.insert(key.clone(), value.clone());
+        }
+    }
+    if !conflicting.is_empty() {
+        return Err(format!(
+            "had the tags {} changed upstream as well",
+            conflicting.join(", ")
+        ));
+    }
+    set_tags(&mut merged, tags);
+    Ok(merged)
+}
+
+/// Rewrite the references of a way or relation with `ids`
+fn remap_references(object: &mut OSMObject, ids: &HashMap<(&'static str, u64), u64>) {
+    match object {
+        OSMObject::Node(_) => {}
+        OSMObject::Way(way) => {
+            for node in &mut way.nodes {
+                if let Some(id) = ids.get(&("node", *node)) {
+                    *node = *id;
+                }
+            }
+        }
+        OSMObject::Relation(relation) => {
+            for member in &mut relation.member {
+                let Some(type_name) = static_type_name(&member.r#type) else {
+                    continue;
+                };
+                if let Some(id) = ids.get(&(type_name, member.ref_id)) {
+                    member.ref_id = *id;
+                }
+            }
+        }
+    }
+}
+
+/// Collect the edits of the commits after `base` up to HEAD
+///
+/// Commits applied from upstream carry `Changeset-Id` trailers. Uploading their changes
+/// again would duplicate them, so they can't be part of an upload.
+pub fn plan_upload(
+    repository: &Repository,
+    base: &Commit,
+    id_mapper: &dyn IdMapper,
+    budget: ShardBudget,
+) -> Result<UploadPlan> {
+    let head = repository.head()?.peel_to_commit()?;
+    let mut revwalk = repository.revwalk()?;
+    revwalk.push(head.id())?;
+    revwalk.hide(base.id())?;
+    let mut summaries = Vec::new();
+    for commit_id in revwalk {
+        let commit = repository.find_commit(commit_id?)?;
+        let trailers = git2::message_trailers_strs(commit.message().unwrap_or_default())?;
+        if let Some((_, changeset_id)) = trailers.iter().find(|(key, _)| *key == "Changeset-Id") {
+            return Err(eyre!(
+                "Commit {} applied changeset {} from upstream. Only local edits can be uploaded, pass a --base after it",
+                commit.id(),
+                changeset_id
+            ));
+        }
+        summaries.push(commit.summary().unwrap_or_default().to_string());
+    }
+    summaries.reverse();
+
+    let changes = object_changes(
+        repository,
+        Some(&base.tree()?),
+        &head.tree()?,
+        id_mapper,
+        budget,
+    )?;
+    let mut plan = UploadPlan {
+        base: base.id(),
+        head: head.id(),
+        summaries,
+        created: Vec::new(),
+        modified: Vec::new(),
+        deleted: Vec::new(),
+        placeholders: HashMap::new(),
+    };
+    let edited_version = |object: &OSMObject| {
+        version(object).cloned().ok_or_else(|| {
+            eyre!(
+                "{} {} has no version to upload the edit against",
+                object.type_name(),
+                object.id()
+            )
+        })
+    };
+    for ((type_name, id), change) in changes {
+        match (change.before, change.after) {
+            (None, Some(after)) => {
+                plan.placeholders
+                    .insert((type_name, id), -(plan.created.len() as i64) - 1);
+                plan.created.push(after);
+            }
+            (Some(before), Some(after)) => {
+                let version = edited_version(&before)?;
+                plan.modified.push(Edit {
+                    base: before,
+                    object: after,
+                    version,
+                });
+            }
+            (Some(before), None) => {
+                let version = edited_version(&before)?;
+                plan.deleted.push((before, version));
+            }
+            (None, None) => {}
+        }
+    }
+    if plan.len() > MAX_CHANGESET_ELEMENTS {
+        return Err(eyre!(
+            "The edits change {} objects, more than the {} a changeset can have",
+            plan.len(),
+            MAX_CHANGESET_ELEMENTS
+        ));
+    }
+    plan.created
+        .sort_by_key(|object| (type_order(object.type_name()), object.id()));
+    plan.modified
+        .sort_by_key(|edit| (type_order(edit.object.type_name()), edit.object.id()));
+    plan.deleted.sort_by_key(|(object, _)| {
+        std::cmp::Reverse((type_order(object.type_name()), object.id()))
+    });
+    Ok(plan)
+}
+
+impl UploadPlan {
+    /// The number of uploaded objects
+    pub fn len(&self) -> usize {
+        self.created.len() + self.modified.len() + self.deleted.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The comment of the changeset, from the summaries of the commits
+    pub fn default_comment(&self) -> String {
+        self.summaries
+            .join("; ")
+            .chars()
+            .take(MAX_COMMENT_LENGTH)
+            .collect()
+    }
+
+    /// Compare the edited and deleted objects with their current versions upstream
+    ///
+    /// Objects still at the version of the base commit upstream can be uploaded as they
+    /// are, the API would reject the others. With `merge_tags`, edits of objects changed
+    /// upstream are merged with the upstream version where the changes don't overlap, and
+    /// the merged objects are uploaded and recorded instead.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<Conflict>>` - The objects which were changed upstream and not merged
+    pub async fn check_conflicts(
+        &mut self,
+        api: &OsmApi,
+        id_mapper: &dyn IdMapper,
+        merge_tags: bool,
+    ) -> Result<Vec<Conflict>> {
+        let mut ids: BTreeMap<&'static str, Vec<u64>> = BTreeMap::new();
+        let edited = self
+            .modified
+            .iter()
+            .map(|edit| &edit.object)
+            .chain(self.deleted.iter().map(|(object, _)| object));
+        for object in edited {
+            ids.entry(object.type_name()).or_default().push(object.id());
+        }
+        let mut live = HashMap::new();
+        for (type_name, ids) in ids {
+            for object in api.fetch_objects(type_name, &ids, id_mapper).await? {
+                live.insert((object.type_name(), object.id()), object);
+            }
+        }
+
+        let mut conflicts = Vec::new();
+        for edit in &mut self.modified {
+            let key = (edit.object.type_name(), edit.object.id());
+            let live_object = live.get(&key);
+            let live_version = live_object.and_then(version).cloned();
+            if live_version.as_ref() == Some(&edit.version) {
+                continue;
+            }
+            let merged = match live_object {
+                None => Err("is unknown upstream".to_string()),
+                Some(live_object) if !is_visible(live_object) => {
+                    Err("was deleted upstream".to_string())
+                }
+                Some(_) if !merge_tags => Err("was changed upstream".to_string()),
+                Some(live_object) => three_way_merge(&edit.base, &edit.object, live_object),
+            };
+            match merged {
+                Ok(merged) => {
+                    info!(
+                        "Merged the edit of {} {} with version {} upstream",
+                        key.0,
+                        key.1,
+                        live_version.as_deref().unwrap_or_default()
+                    );
+                    edit.object = merged;
+                    edit.version = live_version.unwrap_or_default();
+                }
+                Err(reason) => conflicts.push(Conflict {
+                    type_name: key.0,
+                    id: key.1,
+                    base_version: edit.version.clone(),
+                    live_version,
+                    reason,
+                }),
+            }
+        }
+        for (object, base_version) in &self.deleted {
+            let live_object = live.get(&(object.type_name(), object.id()));
+            let live_version = live_object.and_then(version).cloned();
+            if live_version.as_ref() == Some(base_version) {
+                continue;
+            }
+            let reason = match live_object {
+                None => "is unknown upstream",
+                Some(live_object) if !is_visible(live_object) => "was already deleted upstream",
+                Some(_) => "was changed upstream",
+            };
+            conflicts.push(Conflict {
+                type_name: object.type_name(),
+                id: object.id(),
+                base_version: base_version.clone(),
+                live_version,
+                reason: reason.to_string(),
+            });
+        }
+        Ok(conflicts)
+    }
+
+    /// The id of a referenced object in the upload
+    fn reference(&self, type_name: &str, id: u64) -> i64 {
+        static_type_name(type_name)
+            .and_then(|type_name| self.placeholders.get(&(type_name, id)))
+            .copied()
+            .unwrap_or(id as i64)
+    }
+
+    /// The edits as an osmChange document for the upload to the changeset
+    ///
+    /// Creations and modifications come nodes first and deletions relations first, so
+    /// the API never sees a reference to an element which doesn't exist yet or anymore.
+    pub fn osmchange(&self, changeset_id: u64) -> Result<String> {
+        let mut document = String::new();
+        writeln!(
+            document,
+            r#"<osmChange version="0.6" generator="osm-git {}">"#,
+            env!("CARGO_PKG_VERSION")
+        )?;
+        if !self.created.is_empty() {
+            writeln!(document, "<create>")?;
+            for object in &self.created {
+                let id = self.reference(object.type_name(), object.id());
+                self.write_element(&mut document, object, id, None, changeset_id)?;
+            }
+            writeln!(document, "</create>")?;
+        }
+        if !self.modified.is_empty() {
+            writeln!(document, "<modify>")?;
+            for edit in &self.modified {
+                self.write_element(
+                    &mut document,
+                    &edit.object,
+                    edit.object.id() as i64,
+                    Some(&edit.version),
+                    changeset_id,
+                )?;
+            }
+            writeln!(document, "</modify>")?;
+        }
+        if !self.deleted.is_empty() {
+            writeln!(document, "<delete>")?;
+            for (object, version) in &self.deleted {
+                writeln!(
+                    document,
+                    r#"  <{} id="{}" version="{}" changeset="{}"/>"#,
+                    object.type_name(),
+                    object.id(),
+                    escape(version),
+                    changeset_id
+                )?;
+            }
+            writeln!(document, "</delete>")?;
+        }
+        writeln!(document, "</osmChange>")?;
+        Ok(document)
+    }
+
+    fn write_element(
+        &self,
+        document: &mut String,
+        object: &OSMObject,
+        id: i64,
+        version: Option<&String>,
+        changeset_id: u64,
+    ) -> Result<()> {
+        write!(document, r#"  <{} id="{}""#, object.type_name(), id)?;
+        if let Some(version) = version {
+            write!(document, r#" version="{}""#, escape(version))?;
+        }
+        write!(document, r#" changeset="{}""#, changeset_id)?;
+        if let OSMObject::Node(node) = object {
+            write!(document, r#" lat="{}" lon="{}""#, node.lat, node.lon)?;
+        }
+        writeln!(document, ">")?;
+        match object {
+            OSMObject::Node(_) => {}
+            OSMObject::Way(way) => {
+                for node in &way.nodes {
+                    writeln!(
+                        document,
+                        r#"    <nd ref="{}"/>"#,
+                        self.reference("node", *node)
+                    )?;
+                }
+            }
+            OSMObject::Relation(relation) => {
+                for member in &relation.member {
+                    writeln!(
+                        document,
+                        r#"    <member type="{}" ref="{}" role="{}"/>"#,
+                        escape(&member.r#type),
+                        self.reference(&member.r#type, member.ref_id),
+                        escape(member.role.as_deref().unwrap_or(""))
+                    )?;
+                }
+            }
+        }
+        for (key, value) in object.tags() {
+            writeln!(
+                document,
+                r#"    <tag k="{}" v="{}"/>"#,
+                escape(key),
+                escape(value)
+            )?;
+        }
+        writeln!(document, "  </{}>", object.type_name())?;
+        Ok(())
+    }
+}
+
+/// Parse the `diffResult` the OSM API answers an upload with
+pub fn parse_diff_result(document: &str) -> Result<Vec<DiffResult>> {
+    let mut reader = Reader::from_str(document);
+    let mut results = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) | Event::Empty(element) => {
+                let Some(type_name) =
+                    static_type_name(std::str::from_utf8(element.name().as_ref())?)
+                else {
+                    continue;
+                };
+                let mut result = DiffResult {
+                    type_name,
+                    old_id: 0,
+                    new_id: None,
+                    new_version: None,
+                };
+                for attribute in element.attributes() {
+                    let attribute = attribute?;
+                    let value = std::str::from_utf8(&attribute.value)?;
+                    match attribute.key.as_ref() {
+                        b"old_id" => result.old_id = value.parse()?,
+                        b"new_id" => result.new_id = Some(value.parse()?),
+                        b"new_version" => result.new_version = Some(value.parse()?),
+                        _ => {}
+                    }
+                }
+                results.push(result);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(results)
+}
+
+/// A client of the editing endpoints of the OSM API 0.6, authenticated with an OAuth2
+/// access token
+pub struct OsmApi {
+    client: reqwest::Client,
+    /// The base URL of the API like `https://api.openstreetmap.org`
+    url: String,
+    token: String,
+}
+
+impl OsmApi {
+    pub fn new(url: &str, token: &str, user_agent: &str) -> Result<Self> {
+        Ok(OsmApi {
+            client: reqwest::Client::builder()
+                .user_agent(user_agent)
+                .timeout(Duration::from_secs(300))
+                .build()?,
+            url: url.trim_end_matches('/').to_string(),
+            token: token.trim().to_string(),
+        })
+    }
+
+    async fn request(&self, method: Method, path: &str, body: String) -> Result<String> {
+        let url = format!("{}/api/0.6/{}", self.url, path);
+        let response = self
+            .client
+            .request(method, &url)
+            .bearer_auth(&self.token)
+            .header("Content-Type", "text/xml; charset=utf-8")
+            .body(body)
+            .send()
+            .await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            return Err(eyre!("{} answered {}: {}", url, status, text.trim()));
+        }
+        Ok(text)
+    }
+
+    /// Fetch the current versions of objects of one type, including deleted ones
+    pub async fn fetch_objects(
+        &self,
+        type_name: &str,
+        ids: &[u64],
+        id_mapper: &dyn IdMapper,
+    ) -> Result<Vec<OSMObject>> {
+        let mut objects = Vec::new();
+        for batch in ids.chunks(FETCH_BATCH_SIZE) {
+            let ids = batch
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<String>>()
+                .join(",");
+            let document = self
+                .request(
+                    Method::GET,
+                    &format!("{}s?{}s={}", type_name, type_name, ids),
+                    String::new(),
+                )
+                .await?;
+            objects.extend(read_osm_objects(document.as_bytes(), id_mapper)?);
+        }
+        Ok(objects)
+    }
+
+    /// Open a changeset with the given comment
+    pub async fn open_changeset(&self, comment: &str) -> Result<u64> {
+        let body = format!(
+            "<osm>\n  <changeset>\n    <tag k=\"created_by\" v=\"osm-git {}\"/>\n    <tag k=\"comment\" v=\"{}\"/>\n  </changeset>\n</osm>\n",
+            env!("CARGO_PKG_VERSION"),
+            escape(comment)
+        );
+        let id = self.request(Method::PUT, "changeset/create", body).await?;
+        id.trim()
+            .parse()
+            .map_err(|_| eyre!("The API answered {:?} instead of a changeset id", id))
+    }
+
+    /// Upload an osmChange document to an open changeset
+    pub async fn upload(&self, changeset_id: u64, osmchange: String) -> Result<Vec<DiffResult>> {
+        let result = self
+            .request(
+                Method::POST,
+                &format!("changeset/{}/upload", changeset_id),
+                osmchange,
+            )
+            .await?;
+        parse_diff_result(&result)
+    }
+
+    pub async fn close_changeset(&self, changeset_id: u64) -> Result<()> {
+        self.request(
+            Method::PUT,
+            &format!("changeset/{}/close", changeset_id),
+            String::new(),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Record the ids and versions the API gave the uploaded objects with a commit on HEAD,
+/// and move the upload marker to it
+///
+/// Created objects move to the files of their new ids and the references to them are
+/// rewritten, so the git repo matches upstream once the changeset is replicated.
+pub fn record_upload(
+    repository: &Repository,
+    plan: &UploadPlan,
+    changeset_id: u64,
+    results: &[DiffResult],
+    committer: &Signature,
+    id_mapper: &dyn IdMapper,
+    budget: ShardBudget,
+) -> Result<Oid> {
+    let results = results
+        .iter()
+        .map(|result| ((result.type_name, result.old_id), result))
+        .collect::<HashMap<(&str, i64), &DiffResult>>();
+    let new_ids = plan
+        .placeholders
+        .iter()
+        .filter_map(|(key, placeholder)| {
+            results
+                .get(&(key.0, *placeholder))
+                .and_then(|result| result.new_id)
+                .map(|new_id| (*key, new_id))
+        })
+        .collect::<HashMap<(&'static str, u64), u64>>();
+
+    let layout = Layout::load(repository, budget)?;
+    let mut files = BTreeMap::new();
+    let mut record = |object: &OSMObject, old_id: i64| -> Result<()> {
+        let result = results.get(&(object.type_name(), old_id)).ok_or_else(|| {
+            eyre!(
+                "The API answered without the new version of {} {}",
+                object.type_name(),
+                old_id
+            )
+        })?;
+        let mut object = object.clone();
+        if let Some(new_id) = result.new_id {
+            object.set_id(new_id);
+        }
+        if let Some(new_version) = result.new_version {
+            set_version(&mut object, new_version);
+        }
+        remap_references(&mut object, &new_ids);
+        files.insert(
+            layout.object_path(&object, id_mapper),
+            Some(layout.serializer().serialize(&object)?),
+        );
+        Ok(())
+    };
+    for object in &plan.created {
+        record(object, plan.reference(object.type_name(), object.id()))?;
+    }
+    for edit in &plan.modified {
+        record(&edit.object, edit.object.id() as i64)?;
+    }
+    for object in &plan.created {
+        let path = layout.object_path(object, id_mapper);
+        files.entry(path).or_insert(None);
+    }
+
+    let message = append_trailers(
+        format!("Record the ids of changeset {}", changeset_id),
+        &[(UPLOADED_CHANGESET_TRAILER, &changeset_id.to_string())],
+    );
+    let commit = commit_head_files(repository, files, &message, committer)?;
+    repository.reference(
+        UPLOAD_MARKER_REF,
+        commit,
+        true,
+        &format!("Uploaded changeset {}", changeset_id),
+    )?;
+    info!(
+        "Recorded the ids of changeset {} as {}",
+        changeset_id, commit
+    );
+    Ok(commit)
+}
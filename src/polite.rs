@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Result};
+use time::{OffsetDateTime, Time};
+
+/// The user agent sent unless another one is configured. It has no contact information,
+/// so `--polite` refuses it
+pub const DEFAULT_USER_AGENT: &str = concat!("osm-git-replay/", env!("CARGO_PKG_VERSION"));
+
+/// The off-peak window of `--polite` unless another one is configured
+pub const DEFAULT_OFF_PEAK: &str = "00:00-06:00";
+
+/// The least time `--polite` waits between downloading replication files
+pub const MIN_WAIT_TIME: Duration = Duration::from_secs(1);
+
+/// Check that a user agent tells the operators of the server whom to contact
+///
+/// The usage policies of the OSM servers ask for an identifying user agent, so the
+/// operators can reach a misbehaving deployment instead of having to block it. An email
+/// address or an http(s) URL counts as contact information.
+pub fn validate_user_agent(user_agent: &str) -> Result<()> {
+    let has_contact = user_agent
+        .split(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | '<' | '>' | ';' | ','))
+        .any(|word| {
+            is_email_address(word) || word.starts_with("https://") || word.starts_with("http://")
+        });
+    if !has_contact {
+        return Err(eyre!(
+            "The user agent {:?} has no contact information. Pass --user-agent with an email address or URL, like \"my-mirror/1.0 (admin@example.com)\"",
+            user_agent
+        ));
+    }
+    Ok(())
+}
+
+fn is_email_address(word: &str) -> bool {
+    let Some((local, domain)) = word.split_once('@') else {
+        return false;
+    };
+    !local.is_empty() && domain.contains('.') && !domain.starts_with('.') && !domain.ends_with('.')
+}
+
+/// A daily time window in UTC like `00:00-06:00` in which downloads are allowed
+///
+/// A window may wrap around midnight, like `22:00-04:00`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OffPeakWindow {
+    start: Time,
+    end: Time,
+}
+
+impl OffPeakWindow {
+    pub fn parse(window: &str) -> Result<Self> {
+        let invalid = || {
+            eyre!(
+                "Invalid off-peak window {:?}. Use HH:MM-HH:MM in UTC",
+                window
+            )
+        };
+        let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+        let parse_time = |time: &str| -> Result<Time> {
+            let (hour, minute) = time.trim().split_once(':').ok_or_else(invalid)?;
+            Ok(Time::from_hms(hour.parse()?, minute.parse()?, 0)?)
+        };
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end {
+            return Err(eyre!("The off-peak window {:?} is empty", window));
+        }
+        Ok(OffPeakWindow { start, end })
+    }
+
+    /// How long to wait at `now` until the window opens. `None` within the window
+    pub fn wait(&self, now: OffsetDateTime) -> Option<Duration> {
+        let now = now.to_offset(time::UtcOffset::UTC);
+        let time = now.time();
+        let within = if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        };
+        if within {
+            return None;
+        }
+
+        let mut opens = now.replace_time(self.start);
+        if opens <= now {
+            opens += time::Duration::DAY;
+        }
+        Some((opens - now).unsigned_abs())
+    }
+}
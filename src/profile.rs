@@ -0,0 +1,141 @@
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{
+    git::notes::NoteFormat,
+    osm::{squash::Granularity, state_store::StateStoreKind},
+};
+
+/// The OSM API of openstreetmap.org
+pub const PRODUCTION_API_URL: &str = "https://api.openstreetmap.org";
+/// The sandbox API of the OSM developers, which can be used for tests without touching the
+/// real data
+pub const SANDBOX_API_URL: &str = "https://master.apis.dev.openstreetmap.org";
+
+/// Preset combinations of settings for common ways to run the replay
+///
+/// Explicitly passed flags always take precedence over the profile.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// A complete, annotated history of the data: a working directory, a commit per
+    /// changeset and changeset notes
+    #[default]
+    Archive,
+    /// A lean copy of the current data which is kept up to date: a bare repo with commits
+    /// built in memory and a commit per replication file
+    Mirror,
+    /// A history optimized for querying statistics about the edits: the objects in a
+    /// SQLite database next to a bare repo, YAML notes and tag key summaries
+    Analytics,
+    /// A test setup downloading missing changesets from the OSM sandbox API instead of
+    /// the production one. Pair it with a --cassette to record the responses once and
+    /// play them back in automated checks
+    Sandbox,
+}
+
+/// The settings a profile defines
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileSettings {
+    /// Write the changeset metadata as git notes
+    pub write_notes: bool,
+    /// The format of the changeset notes
+    pub note_format: NoteFormat,
+    /// The OSM API missing changesets are downloaded from
+    pub changeset_api: &'static str,
+    /// Create the git repo without a working directory
+    pub bare: bool,
+    /// Where the current versions of the objects are kept
+    pub state_store: StateStoreKind,
+    /// Whether a commit is made per changeset or per replication file
+    pub granularity: Granularity,
+    /// Add `Tag-Keys` trailers summarizing the tags of the changed objects
+    pub tag_key_summaries: bool,
+}
+
+impl Profile {
+    pub fn settings(self) -> ProfileSettings {
+        match self {
+            Profile::Archive => ProfileSettings {
+                write_notes: true,
+                note_format: NoteFormat::Plain,
+                changeset_api: PRODUCTION_API_URL,
+                bare: false,
+                state_store: StateStoreKind::Git,
+                granularity: Granularity::Changeset,
+                tag_key_summaries: true,
+            },
+            // The git state store of a bare repo builds the commits with the tree builder
+            Profile::Mirror => ProfileSettings {
+                write_notes: false,
+                note_format: NoteFormat::Plain,
+                changeset_api: PRODUCTION_API_URL,
+                bare: true,
+                state_store: StateStoreKind::Git,
+                granularity: Granularity::File,
+                tag_key_summaries: false,
+            },
+            Profile::Analytics => ProfileSettings {
+                write_notes: true,
+                note_format: NoteFormat::Yaml,
+                changeset_api: PRODUCTION_API_URL,
+                bare: true,
+                state_store: StateStoreKind::Sqlite,
+                granularity: Granularity::Changeset,
+                tag_key_summaries: true,
+            },
+            Profile::Sandbox => ProfileSettings {
+                changeset_api: SANDBOX_API_URL,
+                ..Profile::Archive.settings()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirror_is_bare_with_a_commit_per_file() {
+        let settings = Profile::Mirror.settings();
+        assert!(settings.bare);
+        assert_eq!(settings.state_store, StateStoreKind::Git);
+        assert_eq!(settings.granularity, Granularity::File);
+        assert!(!settings.write_notes);
+        assert!(!settings.tag_key_summaries);
+    }
+
+    #[test]
+    fn archive_has_a_worktree_a_commit_per_changeset_and_notes() {
+        let settings = Profile::Archive.settings();
+        assert!(!settings.bare);
+        assert_eq!(settings.state_store, StateStoreKind::Git);
+        assert_eq!(settings.granularity, Granularity::Changeset);
+        assert!(settings.write_notes);
+        assert_eq!(settings.note_format, NoteFormat::Plain);
+        assert_eq!(settings.changeset_api, PRODUCTION_API_URL);
+    }
+
+    #[test]
+    fn analytics_keeps_the_objects_in_sqlite_with_summaries() {
+        let settings = Profile::Analytics.settings();
+        // The SQLite store needs a bare repo
+        assert!(settings.bare);
+        assert_eq!(settings.state_store, StateStoreKind::Sqlite);
+        assert!(settings.write_notes);
+        assert_eq!(settings.note_format, NoteFormat::Yaml);
+        assert!(settings.tag_key_summaries);
+    }
+
+    #[test]
+    fn sandbox_is_an_archive_of_the_sandbox_api() {
+        assert_eq!(
+            Profile::Sandbox.settings(),
+            ProfileSettings {
+                changeset_api: SANDBOX_API_URL,
+                ..Profile::Archive.settings()
+            }
+        );
+    }
+}
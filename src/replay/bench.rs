@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use super::ReplayEvent;
+
+/// Percentiles of a latency, in seconds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LatencyPercentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl LatencyPercentiles {
+    /// The percentiles of the given latencies with the nearest rank method, `None` without
+    /// any
+    pub fn of(latencies: &[f64]) -> Option<Self> {
+        if latencies.is_empty() {
+            return None;
+        }
+        let mut sorted = latencies.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let percentile = |percent: f64| {
+            let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+            sorted[rank.clamp(1, sorted.len()) - 1]
+        };
+        Some(LatencyPercentiles {
+            p50: percentile(50.0),
+            p90: percentile(90.0),
+            p95: percentile(95.0),
+            p99: percentile(99.0),
+            max: sorted[sorted.len() - 1],
+        })
+    }
+}
+
+/// The latencies measured by a benchmark
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyReport {
+    /// The number of sequences measured
+    pub samples: usize,
+    /// Sequences applied while following which couldn't be measured, as the server had no
+    /// state file for them
+    pub unmeasured: usize,
+    /// From the publication of a sequence to its commits on the branch
+    pub commit: Option<LatencyPercentiles>,
+    /// From the publication of a sequence to the push of its commits, if the replay
+    /// pushes to a remote
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub push: Option<LatencyPercentiles>,
+}
+
+/// Measures how long sequences take from their publication upstream to their commits on
+/// the branch, from the events of a replay which follows the server
+///
+/// A sequence counts as published at the timestamp of its state file, the time up to
+/// which it includes changes. Upstream publishes it shortly after that, so the latencies
+/// include the delay of the server too, as seen by the users of the mirror. Only the
+/// sequences downloaded after the replay caught up are measured, as the backlog of a
+/// catch-up would say nothing about the freshness of the mirror. Replays pushing to a
+/// remote measure the latency until the commits are pushed as well.
+#[derive(Debug)]
+pub struct FollowBenchmark {
+    /// The number of sequences to measure
+    target_samples: usize,
+    caught_up: bool,
+    published: HashMap<String, OffsetDateTime>,
+    commit_latencies: Vec<f64>,
+    /// The publication times of the measured sequences which were not pushed yet
+    unpushed: Vec<OffsetDateTime>,
+    push_latencies: Vec<f64>,
+    unmeasured: usize,
+}
+
+impl FollowBenchmark {
+    pub fn new(target_samples: usize) -> Self {
+        FollowBenchmark {
+            target_samples,
+            caught_up: false,
+            published: HashMap::new(),
+            commit_latencies: Vec::new(),
+            unpushed: Vec::new(),
+            push_latencies: Vec::new(),
+            unmeasured: 0,
+        }
+    }
+
+    /// Record an event of the replay, which happened at `now`
+    pub fn record(&mut self, event: &ReplayEvent, now: OffsetDateTime) {
+        match event {
+            ReplayEvent::CaughtUp { .. } => self.caught_up = true,
+            ReplayEvent::DownloadStarted {
+                sequence,
+                timestamp,
+                ..
+            } if self.caught_up => match timestamp {
+                Some(timestamp) => {
+                    self.published.insert(sequence.clone(), *timestamp);
+                }
+                None => self.unmeasured += 1,
+            },
+            ReplayEvent::SequenceApplied { sequence, .. } => {
+                if let Some(published) = self.published.remove(sequence) {
+                    self.commit_latencies
+                        .push((now - published).as_seconds_f64().max(0.0));
+                    self.unpushed.push(published);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Record that the commits applied so far were pushed at `now`
+    pub fn record_push(&mut self, now: OffsetDateTime) {
+        for published in self.unpushed.drain(..) {
+            self.push_latencies
+                .push((now - published).as_seconds_f64().max(0.0));
+        }
+    }
+
+    /// The number of sequences measured so far
+    pub fn samples(&self) -> usize {
+        self.commit_latencies.len()
+    }
+
+    /// Check if enough sequences were measured to stop the replay
+    pub fn is_complete(&self) -> bool {
+        self.samples() >= self.target_samples
+    }
+
+    pub fn report(&self) -> LatencyReport {
+        LatencyReport {
+            samples: self.samples(),
+            unmeasured: self.unmeasured,
+            commit: LatencyPercentiles::of(&self.commit_latencies),
+            push: LatencyPercentiles::of(&self.push_latencies),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Keeps the cached replication files within a size limit
+//!
+//! The files are evicted least recently used first. The modification time of a file is
+//! its last use, so the order survives restarts of the replay.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use color_eyre::eyre::Result;
+use git2::Repository;
+
+use crate::git::notes::sequence_in_history;
+
+/// The file extension of cached replication files
+const CACHE_FILE_EXTENSION: &str = ".osm.gz";
+
+/// A file in the cache
+#[derive(Debug, Clone, Copy)]
+struct CachedFile {
+    size: u64,
+    used: SystemTime,
+}
+
+/// What an eviction removed
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Eviction {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// The replication files in `<cache>/replication`, by sequence
+///
+/// The size counts the files cached when the replay started and the ones used since.
+/// Files downloaded ahead of time are counted once they are applied.
+pub struct CacheLimit {
+    directory: PathBuf,
+    max_size: u64,
+    files: HashMap<String, CachedFile>,
+    size: u64,
+}
+
+impl CacheLimit {
+    /// Read the files in the replication folder of the cache
+    pub fn load(cache_path: &str, max_size: u64) -> Result<Self> {
+        let mut cache = CacheLimit {
+            directory: Path::new(cache_path).join("replication"),
+            max_size,
+            files: HashMap::new(),
+            size: 0,
+        };
+        let mut folders = vec![cache.directory.clone()];
+        while let Some(folder) = folders.pop() {
+            let entries = match std::fs::read_dir(&folder) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            for entry in entries {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    folders.push(entry.path());
+                    continue;
+                }
+                let path = entry.path();
+                let Some(sequence) = path
+                    .strip_prefix(&cache.directory)?
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(CACHE_FILE_EXTENSION))
+                    .map(str::to_string)
+                else {
+                    continue;
+                };
+                cache.insert(
+                    sequence,
+                    CachedFile {
+                        size: metadata.len(),
+                        used: metadata.modified()?,
+                    },
+                );
+            }
+        }
+        Ok(cache)
+    }
+
+    /// The number of bytes of the tracked files
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn path(&self, sequence: &str) -> PathBuf {
+        self.directory
+            .join(format!("{}{}", sequence, CACHE_FILE_EXTENSION))
+    }
+
+    fn insert(&mut self, sequence: String, file: CachedFile) {
+        if let Some(previous) = self.files.insert(sequence, file) {
+            self.size -= previous.size;
+        }
+        self.size += file.size;
+    }
+
+    /// Record the use of the cached file of a sequence
+    pub fn used(&mut self, sequence: &str) -> Result<()> {
+        let path = self.path(sequence);
+        let used = SystemTime::now();
+        let file = File::options().write(true).open(&path)?;
+        file.set_modified(used)?;
+        let size = file.metadata()?.len();
+        self.insert(sequence.to_string(), CachedFile { size, used });
+        Ok(())
+    }
+
+    /// Remove the least recently used files until the cache fits in its size limit
+    ///
+    /// Only files of sequences in the history of the git repo are removed, so files
+    /// downloaded ahead of time or of a replay which stopped are kept until they are
+    /// applied, even if the cache stays above the limit.
+    pub fn evict(&mut self, repository: &Repository) -> Result<Eviction> {
+        let mut eviction = Eviction::default();
+        if self.size <= self.max_size {
+            return Ok(eviction);
+        }
+        let mut files = self
+            .files
+            .iter()
+            .map(|(sequence, file)| (file.used, sequence.clone()))
+            .collect::<Vec<_>>();
+        files.sort();
+        for (_, sequence) in files {
+            if self.size <= self.max_size {
+                break;
+            }
+            if !sequence_in_history(repository, &sequence)? {
+                continue;
+            }
+            match std::fs::remove_file(self.path(&sequence)) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+            if let Some(file) = self.files.remove(&sequence) {
+                self.size -= file.size;
+                eviction.files += 1;
+                eviction.bytes += file.size;
+            }
+        }
+        Ok(eviction)
+    }
+}
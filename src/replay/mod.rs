@@ -0,0 +1,372 @@
+use color_eyre::eyre::Result;
+use git2::{Oid, Repository, Signature};
+use time::OffsetDateTime;
+
+#[cfg(feature = "http")]
+use crate::osm::changesets::ChangesetApi;
+use crate::{
+    git::{
+        backend::RepositoryBackend,
+        begin_staging,
+        campaigns::record_campaigns,
+        commit_changeset_event,
+        cursor::ReplayCursor,
+        identity::{AuthorIdentity, Committer, IdentityPolicy},
+        notes::{
+            read_sequence_tag, record_missing_metadata, sequence_in_history, tag_sequence,
+            write_notes, NoteFormat,
+        },
+        provenance::sha256_hex,
+        publish_staging, write_state, STAGING_REF,
+    },
+    osm::{
+        admin_areas::AdminAreas,
+        area_filter::AreaFilter,
+        changesets::{Changeset, LifecycleEvent},
+        id_mapping::IdMapper,
+        layout::Layout,
+        mapper_filter::MapperFilter,
+        osm_data::{convert_objects_to_git, ConversionSettings},
+        parse_error::ParseMode,
+        squash::Granularity,
+        state_store::StateStore,
+        tag_filter::TagFilter,
+    },
+    replication::Interval,
+    watch::{WatchMatch, Watchlist},
+};
+
+#[cfg(feature = "http")]
+pub mod bench;
+#[cfg(feature = "http")]
+mod cache;
+pub mod osc_dir;
+#[cfg(feature = "http")]
+mod stream;
+#[cfg(test)]
+pub mod testing;
+
+#[cfg(feature = "http")]
+pub use stream::{ReplayOptions, Replayer};
+
+/// Progress of a replay
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayEvent {
+    /// A replication file is downloaded from the server
+    DownloadStarted {
+        sequence: String,
+        url: String,
+        /// The time up to which the file includes changes, if the server has a state file
+        /// for it
+        timestamp: Option<OffsetDateTime>,
+    },
+    /// A replication file is read from the cache instead of downloading it
+    CacheHit { sequence: String, path: String },
+    /// A cached replication file is not a complete gzip stream. It was removed to download
+    /// it again
+    CacheCorrupt {
+        sequence: String,
+        path: String,
+        error: String,
+    },
+    /// Applied replication files were removed from the cache to keep it within its size
+    /// limit
+    CacheEvicted {
+        files: usize,
+        bytes: u64,
+        /// The size of the cache after the eviction
+        cache_size: u64,
+    },
+    /// A replication file does not exist on the server
+    SequenceMissing { sequence: String },
+    /// The replay caught up with the newest sequence of the server. Unless it follows the
+    /// server, the replay stops
+    CaughtUp {
+        next_sequence: String,
+        latest_sequence: String,
+    },
+    /// A missing sequence was recorded as a gap commit
+    GapRecorded { sequence: String, commit: Oid },
+    /// A changeset being opened or closed was recorded as an empty commit
+    ChangesetEventRecorded {
+        changeset_id: u64,
+        event: &'static str,
+        commit: Oid,
+    },
+    /// A sequence was applied before. Its recorded changesets are not committed again
+    AlreadyApplied { sequence: String, changesets: usize },
+    /// An object was parsed from a replication file
+    ElementParsed { object_type: &'static str, id: u64 },
+    /// An element of a replication file could not be parsed and was skipped in lenient
+    /// mode
+    ElementSkipped {
+        sequence: String,
+        object_type: &'static str,
+        /// The id of the element as given in the file, if it has one
+        id: Option<String>,
+        error: String,
+    },
+    /// A commit was created for one or more changesets
+    CommitCreated {
+        changeset_ids: Vec<u64>,
+        commit: Oid,
+    },
+    /// Changesets of a replication file were not found in the changeset dump. They were
+    /// committed without metadata and are listed for `osm-git annotate-missing`
+    MetadataMissing {
+        sequence: String,
+        changeset_ids: Vec<u64>,
+    },
+    /// A watched object was changed
+    WatchMatched(WatchMatch),
+    /// The rolling changeset store caught up with the changeset replication stream
+    ChangesetStreamSynced {
+        sequence: String,
+        changesets: usize,
+        pruned: usize,
+    },
+    /// The refs of campaigns got new changesets
+    CampaignsUpdated { sequence: String, campaigns: usize },
+    /// All commits of a replication file were published
+    SequenceApplied {
+        sequence: String,
+        changesets: usize,
+        notes_written: usize,
+        /// The SHA-256 hash of the applied data, for the provenance manifest
+        sha256: String,
+    },
+    /// The number of replication files downloaded in the background was tuned
+    PrefetchDepthChanged {
+        prefetch_depth: usize,
+        reason: String,
+    },
+    /// The replay continues with a feed of a lower latency
+    IntervalSwitched {
+        from: Interval,
+        to: Interval,
+        next_sequence: String,
+    },
+    /// The settings were reloaded and are used from the next replication file on
+    SettingsReloaded,
+    /// The replay was paused through the control socket and waits before the next sequence
+    Paused,
+    /// A paused replay continues
+    Resumed,
+    /// The replay waits for the off-peak window before downloading the next file
+    OffPeakWait { resumes_at: OffsetDateTime },
+    /// The replay reached the end sequence or end date it was given and stops before the
+    /// given sequence
+    EndReached { next_sequence: String },
+    /// The replay stopped after the given sequence
+    Finished { last_sequence: String },
+}
+
+/// Applies replication files to the git repo
+pub struct GitSink {
+    pub repository: Repository,
+    /// The bot committing the changesets
+    pub author: Committer,
+    pub identity_policy: IdentityPolicy,
+    /// The names and emails of the mappers
+    pub authors: AuthorIdentity,
+    pub changeset_location: String,
+    pub id_mapper: Box<dyn IdMapper>,
+    pub layout: Layout,
+    pub state_store: Box<dyn StateStore>,
+    pub write_changeset_notes: bool,
+    pub note_format: NoteFormat,
+    /// The notes ref the changeset metadata is written to
+    pub notes_ref: String,
+    /// Maintain a ref per campaign detected from the changeset hashtags
+    pub campaign_refs: bool,
+    pub squash_window: Option<i64>,
+    pub granularity: Granularity,
+    /// Add `Tag-Keys` trailers to the commits
+    pub tag_key_summaries: bool,
+    /// Only keep the objects within this region, for a regional mirror
+    pub area: Option<AreaFilter>,
+    /// Only keep the objects matching this filter, for a thematic mirror
+    pub tag_filter: Option<TagFilter>,
+    /// Only keep the edits of these mappers
+    pub mappers: Option<MapperFilter>,
+    pub admin_areas: Option<AdminAreas>,
+    pub watchlist: Option<Watchlist>,
+    #[cfg(feature = "http")]
+    pub changeset_api: Option<ChangesetApi>,
+    pub serialize_workers: usize,
+    pub commit_buffer_size: usize,
+    /// Whether elements which can't be parsed stop the replay or are skipped
+    pub parse_mode: ParseMode,
+}
+
+impl GitSink {
+    /// Apply a downloaded replication file to the git repo
+    ///
+    /// Commits are created on a staging ref first, then the sequence is tagged and the notes
+    /// are written in one batch. Only then the branch is fast-forwarded to the staged
+    /// commits. If a previous run was interrupted while applying the same file, the
+    /// changesets it already staged are not committed again. The same goes for changesets
+    /// recorded in the tag of an already applied sequence. A sequence which is already in
+    /// the history of HEAD is skipped without parsing it, so re-applying a file is a no-op.
+    /// Note writing is idempotent so it can be repeated with
+    /// `osm-git notes rebuild` if the process dies in between.
+    ///
+    /// The changesets opened or closed in `lifecycle` are recorded as empty commits in
+    /// front of the commits of the file. They are staged like the other commits, so the
+    /// staged commits of an interrupted run stay a fast-forward of HEAD.
+    pub fn apply_replication_file(
+        &self,
+        data: &[u8],
+        sequence: &str,
+        lifecycle: &[(LifecycleEvent, Changeset)],
+        on_event: &mut dyn FnMut(ReplayEvent),
+    ) -> Result<()> {
+        let author = self.author.signature()?;
+        if sequence_in_history(&self.repository, sequence)? {
+            // Nothing is staged for an applied sequence, so the events go onto HEAD
+            self.commit_changeset_events(&author, "HEAD", lifecycle, None, on_event)?;
+            on_event(ReplayEvent::AlreadyApplied {
+                sequence: sequence.to_string(),
+                changesets: read_sequence_tag(&self.repository, sequence)?
+                    .unwrap_or_default()
+                    .len(),
+            });
+            return Ok(());
+        }
+        let mut cursor = begin_staging(&self.repository, sequence)?;
+        if let Some(recorded) = read_sequence_tag(&self.repository, sequence)? {
+            let staged_id = self.repository.refname_to_id(STAGING_REF)?;
+            let mut already_applied = 0;
+            for (changeset_id, commit) in recorded {
+                let reachable = commit == staged_id
+                    || self.repository.graph_descendant_of(staged_id, commit)?;
+                if reachable && cursor.staged_commit(changeset_id).is_none() {
+                    cursor.record(&self.repository, vec![changeset_id], commit)?;
+                    already_applied += 1;
+                }
+            }
+            on_event(ReplayEvent::AlreadyApplied {
+                sequence: sequence.to_string(),
+                changesets: already_applied,
+            });
+        }
+        self.commit_changeset_events(&author, STAGING_REF, lifecycle, Some(&mut cursor), on_event)?;
+        let applied_changesets = convert_objects_to_git(
+            &RepositoryBackend::new(&self.repository, self.state_store.as_ref())?,
+            &author,
+            data,
+            &ConversionSettings {
+                changesets_location: &self.changeset_location,
+                id_mapper: self.id_mapper.as_ref(),
+                layout: &self.layout,
+                identity_policy: self.identity_policy,
+                authors: &self.authors,
+                squash_window: self.squash_window,
+                granularity: self.granularity,
+                tag_key_summaries: self.tag_key_summaries,
+                area: self.area.as_ref(),
+                tag_filter: self.tag_filter.as_ref(),
+                mappers: self.mappers.as_ref(),
+                admin_areas: self.admin_areas.as_ref(),
+                watchlist: self.watchlist.as_ref(),
+                #[cfg(feature = "http")]
+                changeset_api: self.changeset_api.as_ref(),
+                serialize_workers: self.serialize_workers,
+                commit_buffer_size: self.commit_buffer_size,
+                parse_mode: self.parse_mode,
+            },
+            &mut cursor,
+            on_event,
+        )?;
+        #[cfg(feature = "fault-injection")]
+        crate::faults::stage_reached(crate::faults::Stage::Staged);
+        tag_sequence(
+            &self.repository,
+            &author,
+            STAGING_REF,
+            sequence,
+            &applied_changesets,
+        )?;
+        #[cfg(feature = "fault-injection")]
+        crate::faults::stage_reached(crate::faults::Stage::Tagged);
+        let (metadata_missing, with_metadata): (Vec<_>, Vec<_>) = applied_changesets
+            .iter()
+            .cloned()
+            .partition(|applied| applied.metadata_missing);
+        let notes_written = if self.write_changeset_notes {
+            write_notes(
+                &self.repository,
+                &author,
+                &with_metadata,
+                self.note_format,
+                &self.notes_ref,
+            )?
+        } else {
+            0
+        };
+        #[cfg(feature = "fault-injection")]
+        crate::faults::stage_reached(crate::faults::Stage::Notes);
+        if !metadata_missing.is_empty() {
+            record_missing_metadata(&self.repository, &metadata_missing)?;
+            on_event(ReplayEvent::MetadataMissing {
+                sequence: sequence.to_string(),
+                changeset_ids: metadata_missing
+                    .iter()
+                    .map(|applied| applied.changeset.id)
+                    .collect(),
+            });
+        }
+        if self.campaign_refs {
+            let campaigns = record_campaigns(&self.repository, &author, &with_metadata)?;
+            if campaigns > 0 {
+                on_event(ReplayEvent::CampaignsUpdated {
+                    sequence: sequence.to_string(),
+                    campaigns,
+                });
+            }
+        }
+        publish_staging(&self.repository)?;
+        #[cfg(feature = "fault-injection")]
+        crate::faults::stage_reached(crate::faults::Stage::Published);
+        write_state(&self.repository, sequence)?;
+        on_event(ReplayEvent::SequenceApplied {
+            sequence: sequence.to_string(),
+            changesets: applied_changesets.len(),
+            notes_written,
+            sha256: sha256_hex(data),
+        });
+        Ok(())
+    }
+
+    /// Commit the changesets being opened or closed onto `update_ref`, recording the
+    /// commits in the cursor of the staged file
+    fn commit_changeset_events(
+        &self,
+        committer: &Signature,
+        update_ref: &str,
+        lifecycle: &[(LifecycleEvent, Changeset)],
+        mut cursor: Option<&mut ReplayCursor>,
+        on_event: &mut dyn FnMut(ReplayEvent),
+    ) -> Result<()> {
+        for (event, changeset) in lifecycle {
+            let commit = commit_changeset_event(
+                &self.repository,
+                update_ref,
+                committer,
+                self.identity_policy,
+                &self.authors,
+                changeset,
+                *event,
+            )?;
+            if let Some(cursor) = cursor.as_deref_mut() {
+                cursor.record(&self.repository, Vec::new(), commit)?;
+            }
+            on_event(ReplayEvent::ChangesetEventRecorded {
+                changeset_id: changeset.id,
+                event: event.name(),
+                commit,
+            });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader, Cursor, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use color_eyre::eyre::{eyre, Result};
+use flate2::{bufread::GzDecoder, write::GzEncoder, Compression};
+use quick_xml::{
+    events::{BytesStart, Event},
+    Reader, Writer,
+};
+use tracing::{info, warn};
+
+use super::{GitSink, ReplayEvent};
+use crate::git::{provenance::sha256_hex, read_state, write_state, STATE_REF};
+
+/// The prefix of the sequence names of imported files, so their tags don't mix with the
+/// numbered sequences of a replication feed
+const IMPORT_SEQUENCE_PREFIX: &str = "import/";
+
+/// Where a replay reads osc files from instead of a replication server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocalSource {
+    /// `dir:<path>`, a directory of osc files, see [`import_osc_directory`]
+    Directory(PathBuf),
+    /// `-`, a single osc file read from stdin
+    Stdin,
+}
+
+impl FromStr for LocalSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "-" {
+            return Ok(LocalSource::Stdin);
+        }
+        match s.strip_prefix("dir:") {
+            Some(path) if !path.is_empty() => Ok(LocalSource::Directory(PathBuf::from(path))),
+            _ => Err(format!(
+                "Unknown source {:?}, expected dir:<path> or - for stdin",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for LocalSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LocalSource::Directory(path) => write!(f, "dir:{}", path.display()),
+            LocalSource::Stdin => write!(f, "-"),
+        }
+    }
+}
+
+/// An osc file of a directory with the time range of the objects in it
+#[derive(Debug, Clone)]
+pub struct OscFile {
+    pub path: PathBuf,
+    /// The sequence name the file is tagged with, derived from its file name
+    pub sequence: String,
+    /// The oldest and newest timestamps of the objects, in ISO 8601
+    pub first_timestamp: String,
+    pub last_timestamp: String,
+}
+
+/// What an import of an osc directory applied
+#[derive(Debug, Default)]
+pub struct OscImportSummary {
+    pub files: usize,
+    /// Object versions dropped as they were in an earlier file already, or a newer version
+    /// of the object was
+    pub duplicates: usize,
+}
+
+/// The `.osc` and `.osc.gz` files of a directory and its subdirectories, ordered by the
+/// timestamps of their objects
+///
+/// Files without objects are left out. Files are ordered by their oldest object, then
+/// their newest one, then their name. The sequence names of files in subdirectories,
+/// like the `AAA/BBB/CCC.osc.gz` of a copied replication feed, include their path.
+pub fn scan_osc_directory(directory: &Path) -> Result<Vec<OscFile>> {
+    let mut files = Vec::new();
+    let mut folders = vec![directory.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        for entry in std::fs::read_dir(&folder)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                folders.push(path);
+                continue;
+            }
+            let Some(name) = path.strip_prefix(directory)?.to_str() else {
+                continue;
+            };
+            let Some(stem) = name
+                .strip_suffix(".osc.gz")
+                .or_else(|| name.strip_suffix(".osc"))
+            else {
+                continue;
+            };
+            let sequence = format!("{}{}", IMPORT_SEQUENCE_PREFIX, sanitize(stem));
+            match timestamp_range(&path)? {
+                Some((first_timestamp, last_timestamp)) => files.push(OscFile {
+                    path,
+                    sequence,
+                    first_timestamp,
+                    last_timestamp,
+                }),
+                None => warn!("{} has no objects. Skipping it", path.display()),
+            }
+        }
+    }
+    files.sort_by(|a, b| {
+        (&a.first_timestamp, &a.last_timestamp, &a.path).cmp(&(
+            &b.first_timestamp,
+            &b.last_timestamp,
+            &b.path,
+        ))
+    });
+    Ok(files)
+}
+
+/// Replay a directory of osc files with arbitrary names, like the collections of
+/// historical diffs produced by extract tools
+///
+/// The files are applied in the order of [`scan_osc_directory`]. Files of such
+/// collections often overlap, so object versions which were in an earlier file, or which
+/// are older than a version applied before, are dropped. Every file is tagged with a
+/// sequence name derived from its file name, so importing the directory again skips the
+/// files applied before. The last applied sequence of the replication feed is kept, so a
+/// replay continues where it was.
+pub fn import_osc_directory(
+    sink: &GitSink,
+    directory: &Path,
+    on_event: &mut dyn FnMut(ReplayEvent),
+) -> Result<OscImportSummary> {
+    let files = scan_osc_directory(directory)?;
+    info!(
+        "Importing {} osc files from {}",
+        files.len(),
+        directory.display()
+    );
+    keeping_state(sink, || {
+        let mut summary = OscImportSummary::default();
+        let mut versions = HashMap::new();
+        for file in files {
+            info!(
+                "Importing {} with changes from {} to {}",
+                file.path.display(),
+                file.first_timestamp,
+                file.last_timestamp
+            );
+            let (data, duplicates) = drop_applied_versions(
+                open_osc(&file.path)?,
+                &file.path.display().to_string(),
+                &mut versions,
+            )?;
+            summary.duplicates += duplicates;
+            sink.apply_replication_file(&data, &file.sequence, &[], on_event)?;
+            summary.files += 1;
+        }
+        Ok(summary)
+    })
+}
+
+/// Replay a single osc file read from stdin, gzipped or not
+///
+/// The file is tagged with a sequence name derived from its contents, so importing the
+/// same file again skips it.
+pub fn import_osc_stdin(
+    sink: &GitSink,
+    on_event: &mut dyn FnMut(ReplayEvent),
+) -> Result<OscImportSummary> {
+    let mut contents = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut contents)?;
+    if contents.is_empty() {
+        return Err(eyre!("No osc file was given on stdin"));
+    }
+    let sequence = format!(
+        "{}stdin-{}",
+        IMPORT_SEQUENCE_PREFIX,
+        &sha256_hex(&contents)[..16]
+    );
+    info!(
+        "Importing an osc file of {} bytes from stdin as {}",
+        contents.len(),
+        sequence
+    );
+    keeping_state(sink, || {
+        let (data, duplicates) = drop_applied_versions(
+            osc_reader(Box::new(Cursor::new(contents)))?,
+            "stdin",
+            &mut HashMap::new(),
+        )?;
+        sink.apply_replication_file(&data, &sequence, &[], on_event)?;
+        Ok(OscImportSummary {
+            files: 1,
+            duplicates,
+        })
+    })
+}
+
+/// Replay the osc files of a local source
+pub fn import_local_source(
+    sink: &GitSink,
+    source: &LocalSource,
+    on_event: &mut dyn FnMut(ReplayEvent),
+) -> Result<OscImportSummary> {
+    match source {
+        LocalSource::Directory(directory) => import_osc_directory(sink, directory, on_event),
+        LocalSource::Stdin => import_osc_stdin(sink, on_event),
+    }
+}
+
+/// Apply imported files, keeping the last applied sequence of the replication feed, so a
+/// replay continues where it was
+fn keeping_state<T>(sink: &GitSink, import: impl FnOnce() -> Result<T>) -> Result<T> {
+    let last_applied = read_state(&sink.repository)?;
+    let imported = import()?;
+    match last_applied {
+        Some(sequence) => write_state(&sink.repository, &sequence)?,
+        None => {
+            if let Ok(mut state) = sink.repository.find_reference(STATE_REF) {
+                state.delete()?;
+            }
+        }
+    }
+    Ok(imported)
+}
+
+/// Make a file name usable as part of a tag name
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '-',
+        })
+        .collect()
+}
+
+/// Open an osc file, decompressing it if it starts like a gzip file
+fn open_osc(path: &Path) -> Result<Reader<Box<dyn BufRead>>> {
+    osc_reader(Box::new(BufReader::new(File::open(path)?)))
+}
+
+/// Read an osc file, decompressing it if it starts like a gzip file
+fn osc_reader(mut input: Box<dyn BufRead>) -> Result<Reader<Box<dyn BufRead>>> {
+    let reader: Box<dyn BufRead> = if input.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Box::new(BufReader::new(GzDecoder::new(input)))
+    } else {
+        input
+    };
+    Ok(Reader::from_reader(reader))
+}
+
+fn is_object(element: &BytesStart) -> bool {
+    matches!(element.name().as_ref(), b"node" | b"way" | b"relation")
+}
+
+fn attribute(element: &BytesStart, name: &[u8]) -> Option<String> {
+    element
+        .try_get_attribute(name)
+        .ok()
+        .flatten()
+        .and_then(|attribute| String::from_utf8(attribute.value.into_owned()).ok())
+}
+
+/// The oldest and newest timestamps of the objects in an osc file
+fn timestamp_range(path: &Path) -> Result<Option<(String, String)>> {
+    let mut reader = open_osc(path)?;
+    let mut range: Option<(String, String)> = None;
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(ref element) | Event::Empty(ref element) if is_object(element) => {
+                if let Some(timestamp) = attribute(element, b"timestamp") {
+                    range = Some(match range {
+                        None => (timestamp.clone(), timestamp),
+                        Some((first, last)) => (first.min(timestamp.clone()), last.max(timestamp)),
+                    });
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(range)
+}
+
+/// Copy an osc file without the object versions applied before, gzipped like a
+/// replication file. `name` tells which file it is in errors
+///
+/// `versions` has the newest applied version of every object and is updated with the
+/// versions of the file. Objects without a version are always kept.
+///
+/// # Returns
+///
+/// * `Result<(Vec<u8>, usize)>` - The gzipped file and the number of dropped versions
+fn drop_applied_versions(
+    mut reader: Reader<Box<dyn BufRead>>,
+    name: &str,
+    versions: &mut HashMap<(Vec<u8>, u64), u64>,
+) -> Result<(Vec<u8>, usize)> {
+    let mut writer = Writer::new(GzEncoder::new(Vec::new(), Compression::default()));
+    let mut dropped = 0;
+    let mut buf = Vec::new();
+    loop {
+        let event = reader.read_event_into(&mut buf)?;
+        let applied = match &event {
+            Event::Start(element) | Event::Empty(element) if is_object(element) => {
+                let id = attribute(element, b"id").and_then(|id| id.parse::<u64>().ok());
+                let version =
+                    attribute(element, b"version").and_then(|version| version.parse::<u64>().ok());
+                match (id, version) {
+                    (Some(id), Some(version)) => {
+                        let newest = versions
+                            .entry((element.name().as_ref().to_vec(), id))
+                            .or_insert(0);
+                        let applied = version <= *newest;
+                        *newest = version.max(*newest);
+                        applied
+                    }
+                    _ => false,
+                }
+            }
+            _ => false,
+        };
+        match event {
+            Event::Eof => break,
+            Event::Start(element) if applied => {
+                dropped += 1;
+                reader.read_to_end_into(element.name(), &mut Vec::new())?;
+            }
+            Event::Empty(_) if applied => dropped += 1,
+            event => writer
+                .write_event(event)
+                .map_err(|err| eyre!("Unable to copy {}: {}", name, err))?,
+        }
+        buf.clear();
+    }
+    let mut encoder = writer.into_inner();
+    encoder.flush()?;
+    Ok((encoder.finish()?, dropped))
+}
@@ -0,0 +1,800 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    ops::RangeInclusive,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_stream::try_stream;
+use color_eyre::eyre::{eyre, Result};
+use flate2::bufread::GzDecoder;
+use git2::Repository;
+use memmap2::Mmap;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_stream::{Stream, StreamExt};
+use tracing::{info, warn};
+
+use super::{cache::CacheLimit, GitSink, ReplayEvent};
+use crate::{
+    control::ReplayControl,
+    download::Downloader,
+    git::{commit_gap, read_state},
+    osm::changesets::{Changeset, ChangesetStream, LifecycleEvent},
+    polite::OffPeakWindow,
+    replication::{find_sequence_at, Interval, IntervalMode, SequenceNumber, State},
+    source::ReplicationSource,
+    tuning::{ThroughputController, TuningBounds},
+};
+
+/// Where the replication files come from and how they are replayed
+pub struct ReplayOptions {
+    /// Downloads the changeset stream. Its attempts are the attempts of fetching a
+    /// replication file which turns out to be corrupt
+    pub downloader: Downloader,
+    /// The server, directory or bucket to get replication files from
+    pub source: Arc<dyn ReplicationSource>,
+    /// Which feeds of the source to replay
+    pub interval: IntervalMode,
+    /// Where to write cache files
+    pub cache_path: String,
+    /// Evict applied replication files from the cache once it is bigger than this many
+    /// bytes
+    pub max_cache_size: Option<u64>,
+    /// Where to start downloading data from. Defaults to the sequence after the last
+    /// applied one
+    pub start_data: Option<SequenceNumber>,
+    /// Start at the first sequence with changes after this time instead, found from the
+    /// state files of the server
+    pub start_date: Option<OffsetDateTime>,
+    /// Stop after this sequence of the replayed feed
+    pub end_sequence: Option<SequenceNumber>,
+    /// Stop before the first sequence with changes after this time
+    pub end_date: Option<OffsetDateTime>,
+    /// The time to wait between downloading data
+    pub wait_time: Duration,
+    /// Keep polling the server for new replication files at this interval once the replay
+    /// caught up. Without it, the replay stops at the newest sequence.
+    pub follow: Option<Duration>,
+    /// The range the number of replication files downloaded in the background is tuned in
+    pub tuning: TuningBounds,
+    /// The changeset replication stream to keep the rolling changeset store in sync with
+    pub changeset_stream: Option<String>,
+    /// How long changesets are kept in the rolling store after they were closed
+    pub changeset_retention: Duration,
+    /// Record changesets being opened and closed in the stream as empty commits
+    pub changeset_lifecycle: bool,
+    /// Only download replication files within this daily window
+    pub off_peak: Option<OffPeakWindow>,
+    /// Where reloaded settings come from
+    pub control: Arc<ReplayControl>,
+}
+
+/// Switch to the settings reloaded since the last call
+///
+/// # Returns
+///
+/// * `bool` - If the settings were reloaded
+fn apply_reloaded_settings(
+    sink: &mut GitSink,
+    options: &mut ReplayOptions,
+    controller: &mut ThroughputController,
+) -> bool {
+    let Some(settings) = options.control.take_reloaded() else {
+        return false;
+    };
+    options.wait_time = settings.wait_time;
+    options.follow = settings.follow;
+    options.tuning = settings.tuning;
+    options.off_peak = settings.off_peak;
+    controller.set_bounds(settings.tuning);
+    sink.watchlist = settings.watchlist;
+    true
+}
+
+/// How often the replay reports that it waits for the off-peak window
+const OFF_PEAK_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How many events of the replication file being applied are buffered until the stream is
+/// polled again
+const APPLY_EVENT_BUFFER: usize = 1024;
+
+/// How often the changeset replication stream is synced. The stream is published minutely
+const CHANGESET_STREAM_SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Sync the changeset store before a replication file is applied, so the store has the
+/// metadata of the changesets in it
+///
+/// The stream is synced at most once per [`CHANGESET_STREAM_SYNC_INTERVAL`].
+/// With `changeset_lifecycle` the changesets opened or closed since the last sync are
+/// added to `lifecycle`, to be committed with the next replication file.
+async fn sync_changeset_stream(
+    store: Option<&mut ChangesetStream>,
+    options: &ReplayOptions,
+    last_sync: &mut Option<Instant>,
+    lifecycle: &mut Vec<(LifecycleEvent, Changeset)>,
+) -> Result<Vec<ReplayEvent>> {
+    let (Some(store), Some(stream_url)) = (store, &options.changeset_stream) else {
+        return Ok(Vec::new());
+    };
+    if last_sync.is_some_and(|last_sync| last_sync.elapsed() < CHANGESET_STREAM_SYNC_INTERVAL) {
+        return Ok(Vec::new());
+    }
+    let synced = store
+        .sync(&options.downloader, stream_url, options.changeset_retention)
+        .await?;
+    *last_sync = Some(Instant::now());
+    if options.changeset_lifecycle {
+        lifecycle.extend(synced.lifecycle);
+    }
+    Ok(vec![ReplayEvent::ChangesetStreamSynced {
+        sequence: synced.sequence.to_string(),
+        changesets: synced.changesets,
+        pruned: synced.pruned,
+    }])
+}
+
+/// Fetch a replication file into the cache
+///
+/// The file is written to a temporary file first, so an interrupted download is never
+/// mistaken for a cached file.
+///
+/// # Returns
+///
+/// * `Result<bool>` - `false` if the source has no file for the sequence
+async fn download_to_cache(
+    source: Arc<dyn ReplicationSource>,
+    interval: Interval,
+    sequence_number: SequenceNumber,
+    max_attempts: u32,
+    cache_file_path: String,
+) -> Result<bool> {
+    let mut attempt = 1;
+    let data = loop {
+        let Some(data) = source.fetch(interval, sequence_number).await? else {
+            return Ok(false);
+        };
+        let data_url = data_location(source.as_ref(), interval, sequence_number);
+        #[cfg(feature = "fault-injection")]
+        let data = crate::faults::corrupt_gzip(&data_url, data);
+        // A corrupt download never ends up in the cache
+        match verify_gzip(&data) {
+            Ok(()) => break data,
+            Err(err) if attempt < max_attempts => warn!(
+                "The download of {} is corrupt: {}. Downloading it again (attempt {} of {})",
+                data_url, err, attempt, max_attempts
+            ),
+            Err(err) => {
+                return Err(eyre!(
+                    "The download of {} is corrupt after {} attempts: {}",
+                    data_url,
+                    attempt,
+                    err
+                ))
+            }
+        }
+        attempt += 1;
+    };
+    std::fs::create_dir_all(std::path::Path::new(&cache_file_path).parent().unwrap())?;
+    let temporary_path = format!("{}.tmp", cache_file_path);
+    std::fs::write(&temporary_path, &data)?;
+    std::fs::rename(&temporary_path, &cache_file_path)?;
+    Ok(true)
+}
+
+/// Where the replication file of a sequence is
+fn data_location(
+    source: &dyn ReplicationSource,
+    interval: Interval,
+    sequence_number: SequenceNumber,
+) -> String {
+    source.feed_location(interval, &format!("{}.osc.gz", sequence_number))
+}
+
+/// Check that `data` is a complete gzip stream
+///
+/// Decompressing it to the end checks the CRC and length in the gzip trailer, so a
+/// truncated or damaged file is caught before it is applied.
+fn verify_gzip(data: &[u8]) -> std::io::Result<()> {
+    let mut decoder = GzDecoder::new(data);
+    let mut buf = vec![0; 64 * 1024];
+    while decoder.read(&mut buf)? > 0 {}
+    Ok(())
+}
+
+/// Check a cached replication file
+///
+/// # Returns
+///
+/// * `Result<Option<std::io::Error>>` - Why the file is corrupt, if it is cached and corrupt
+fn verify_cache_file(cache_file_path: &str) -> Result<Option<std::io::Error>> {
+    let file = match File::open(cache_file_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let data = unsafe { Mmap::map(&file)? };
+    Ok(verify_gzip(&data).err())
+}
+
+/// Start downloading the given sequences into the cache in the background
+///
+/// Sequences which are cached or already downloading are skipped.
+fn prefetch(
+    prefetching: &mut HashMap<String, JoinHandle<Result<bool>>>,
+    options: &ReplayOptions,
+    interval: Interval,
+    sequence_numbers: RangeInclusive<SequenceNumber>,
+) {
+    let (first, last) = sequence_numbers.into_inner();
+    for sequence_number in first.through(last) {
+        let sequence = interval.sequence_name(sequence_number);
+        let cache_file_path = format!("{}/replication/{}.osm.gz", options.cache_path, sequence);
+        if prefetching.contains_key(&sequence) || std::path::Path::new(&cache_file_path).exists() {
+            continue;
+        }
+        prefetching.insert(
+            sequence,
+            tokio::spawn(download_to_cache(
+                options.source.clone(),
+                interval,
+                sequence_number,
+                options.downloader.max_attempts,
+                cache_file_path,
+            )),
+        );
+    }
+}
+
+/// Check if the replay stops before the given sequence
+///
+/// For an end date the state file of the sequence tells the time of its changes. Without
+/// a state file the sequence is not published yet, or missing and recorded as a gap.
+async fn reached_end(
+    options: &ReplayOptions,
+    interval: Interval,
+    sequence: SequenceNumber,
+) -> Result<bool> {
+    if options
+        .end_sequence
+        .is_some_and(|end_sequence| sequence > end_sequence)
+    {
+        return Ok(true);
+    }
+    let Some(end_date) = options.end_date else {
+        return Ok(false);
+    };
+    let state = State::fetch_sequence(
+        options.source.as_ref(),
+        &options.cache_path,
+        interval,
+        sequence,
+    )
+    .await?;
+    Ok(state.is_some_and(|state| state.timestamp > end_date))
+}
+
+/// Find the first sequence of a feed with changes after `start_date`
+///
+/// The state files of the feed are binary searched. A date before the start of the feed
+/// starts at its first sequence.
+async fn sequence_after(
+    options: &ReplayOptions,
+    interval: Interval,
+    start_date: OffsetDateTime,
+) -> Result<SequenceNumber> {
+    let latest = options.source.latest_state(interval).await?;
+    let start = find_sequence_at(
+        options.source.as_ref(),
+        &options.cache_path,
+        interval,
+        &latest,
+        start_date,
+    )
+    .await?
+    .map(SequenceNumber::next)
+    .unwrap_or_default();
+    info!(
+        "The {} feed continues after {} at sequence {}",
+        interval,
+        start_date.format(&Rfc3339)?,
+        start
+    );
+    Ok(start)
+}
+
+/// Find the feed and sequence to start replaying from
+///
+/// Without an explicit start the replay continues after the last applied sequence.
+/// Starting at or before the last applied sequence is allowed, the sequences in the
+/// history of HEAD are skipped. A git repo keeps following the feed it was last updated
+/// from, unless the feed switches automatically.
+async fn resolve_start_sequence(
+    repository: &Repository,
+    options: &ReplayOptions,
+) -> Result<(Interval, SequenceNumber)> {
+    let interval_mode = options.interval;
+    let last_applied = read_state(repository)?
+        .as_deref()
+        .map(Interval::parse_sequence_name)
+        .transpose()?;
+    let interval = match (interval_mode.fixed(), last_applied) {
+        (Some(interval), Some((last_interval, _))) if interval != last_interval => {
+            return Err(eyre!(
+                "The git repo follows the {} feed. Replay it with --replication-interval {} or auto",
+                last_interval,
+                last_interval
+            ))
+        }
+        (Some(interval), _) => interval,
+        (None, Some((last_interval, _))) => last_interval,
+        (None, None) => Interval::Day,
+    };
+    let start_data = match options.start_date {
+        Some(start_date) => Some(sequence_after(options, interval, start_date).await?),
+        None => options.start_data,
+    };
+
+    match (start_data, last_applied) {
+        (Some(start_data), Some((_, last_applied))) if start_data <= last_applied => {
+            info!(
+                "The git repo is at sequence {}. Sequences from {} which were applied before are skipped",
+                last_applied, start_data
+            );
+            Ok((interval, start_data))
+        }
+        (Some(start_data), _) => Ok((interval, start_data)),
+        (None, Some((_, last_applied))) => {
+            let next = last_applied.next();
+            info!(
+                "Resuming after {} sequence {} at {}",
+                interval, last_applied, next
+            );
+            Ok((interval, next))
+        }
+        (None, None) => Ok((interval, SequenceNumber::default())),
+    }
+}
+
+/// Replays the files of a replication source into a git repo
+///
+/// This is the entry point for embedding the conversion into other programs: configure
+/// where the files come from with a [`ReplayOptions`] and how they are committed with
+/// a [`GitSink`], then run the replay or consume its events as a stream.
+pub struct Replayer {
+    sink: GitSink,
+    options: ReplayOptions,
+}
+
+impl Replayer {
+    pub fn new(sink: GitSink, options: ReplayOptions) -> Self {
+        Replayer { sink, options }
+    }
+
+    /// The progress of the replay as a stream
+    ///
+    /// The replay only makes progress while the stream is polled and ends after the first
+    /// error.
+    pub fn into_stream(self) -> impl Stream<Item = Result<ReplayEvent>> {
+        replay_stream(self.sink, self.options)
+    }
+
+    /// Replay until the source has no more files, or until a shutdown is requested
+    /// through the control of the source
+    pub async fn run(self, mut on_event: impl FnMut(&ReplayEvent)) -> Result<()> {
+        let events = self.into_stream();
+        tokio::pin!(events);
+        while let Some(event) = events.next().await {
+            on_event(&event?);
+        }
+        Ok(())
+    }
+}
+
+/// Replay the replication files to the git repo
+fn replay_stream(
+    mut sink: GitSink,
+    mut options: ReplayOptions,
+) -> impl Stream<Item = Result<ReplayEvent>> {
+    try_stream! {
+        // Data download metadata
+        let (mut interval, start_data) = resolve_start_sequence(&sink.repository, &options).await?;
+        if options.interval == IntervalMode::Auto && !options.source.has_all_feeds() {
+            Err(eyre!(
+                "Switching feeds automatically needs a replication server URL ending in /day, /hour or /minute"
+            ))?;
+        }
+        let mut current = start_data;
+
+        // Sequences which were not found upstream. They are only recorded as gaps once a later
+        // sequence exists, as the latest sequence might just not be published yet.
+        let mut pending_gaps: Vec<String> = Vec::new();
+
+        // The state of the newest sequence of the server
+        let mut latest: Option<State> = None;
+
+        let mut controller = ThroughputController::new(options.tuning);
+        // Downloads of upcoming replication files running in the background
+        let mut prefetching: HashMap<String, JoinHandle<Result<bool>>> = HashMap::new();
+
+        let mut changeset_stream = match options.changeset_stream {
+            Some(_) => Some(ChangesetStream::open_or_create(&sink.changeset_location)?),
+            None => None,
+        };
+        let mut last_stream_sync = None;
+        // Changesets opened or closed in the stream which are not committed yet
+        let mut lifecycle = Vec::new();
+
+        let mut cache_limit = match options.max_cache_size {
+            Some(max_size) => Some(CacheLimit::load(&options.cache_path, max_size)?),
+            None => None,
+        };
+
+        // Parse the changesets and convert them to git objects
+        loop {
+            if apply_reloaded_settings(&mut sink, &mut options, &mut controller) {
+                yield ReplayEvent::SettingsReloaded;
+            }
+            // A shutdown only happens between replication files, so the git repo and the
+            // resume state are consistent
+            if options.control.shutdown_requested() {
+                break;
+            }
+            if options.control.is_paused() {
+                yield ReplayEvent::Paused;
+                options.control.wait_while_paused().await;
+                if !options.control.shutdown_requested() {
+                    yield ReplayEvent::Resumed;
+                }
+                continue;
+            }
+            let sequence = interval.sequence_name(current);
+            if reached_end(&options, interval, current).await? {
+                yield ReplayEvent::EndReached { next_sequence: sequence };
+                break;
+            }
+
+            // Check for cache and use it if it exists
+            let cache_file_path = format!("{}/replication/{}.osm.gz", options.cache_path, sequence);
+
+            // A file which is downloaded in the background ends up in the cache
+            let prefetch_wait = match prefetching.remove(&sequence) {
+                Some(prefetch) => {
+                    let wait_started = Instant::now();
+                    prefetch.await??;
+                    Some(wait_started.elapsed())
+                }
+                None => None,
+            };
+
+            // A cached file may be truncated by a crash or damaged on disk. It is removed, so
+            // it is downloaded again below
+            if let Some(err) = verify_cache_file(&cache_file_path)? {
+                std::fs::remove_file(&cache_file_path)?;
+                yield ReplayEvent::CacheCorrupt {
+                    sequence: sequence.clone(),
+                    path: cache_file_path.clone(),
+                    error: err.to_string(),
+                };
+            }
+
+            let cached = std::path::Path::new(&cache_file_path).exists();
+            // How long the replay waited for the file to download. Only files downloaded
+            // during this run tell anything about the throughput
+            let download_wait = if cached {
+                yield ReplayEvent::CacheHit {
+                    sequence: sequence.clone(),
+                    path: cache_file_path.clone(),
+                };
+                prefetch_wait
+            } else {
+                // Downloads wait for the off-peak window, reporting in between so the
+                // service watchdog sees the replay is alive
+                while let Some(wait) = options
+                    .off_peak
+                    .and_then(|window| window.wait(OffsetDateTime::now_utc()))
+                {
+                    yield ReplayEvent::OffPeakWait {
+                        resumes_at: OffsetDateTime::now_utc() + wait,
+                    };
+                    options.control.sleep(wait.min(OFF_PEAK_CHECK_INTERVAL)).await;
+                    if options.control.shutdown_requested() {
+                        break;
+                    }
+                    if apply_reloaded_settings(&mut sink, &mut options, &mut controller) {
+                        yield ReplayEvent::SettingsReloaded;
+                    }
+                }
+                if options.control.shutdown_requested() {
+                    continue;
+                }
+
+                // Only sequences up to the newest one of the server can be downloaded. The
+                // state is refreshed once the replay reaches the last known newest sequence.
+                let reached_latest = match &latest {
+                    Some(latest) => current > latest.sequence_number,
+                    None => true,
+                };
+                if reached_latest {
+                    let state = options.source.latest_state(interval).await?;
+                    let caught_up = current > state.sequence_number;
+                    let latest_sequence = interval.sequence_name(state.sequence_number);
+                    let latest_timestamp = state.timestamp;
+                    latest = Some(state);
+                    if caught_up {
+                        yield ReplayEvent::CaughtUp {
+                            next_sequence: sequence.clone(),
+                            latest_sequence,
+                        };
+
+                        // Continue with the finer feed after the time the newest sequence of
+                        // this feed ends at
+                        if let (IntervalMode::Auto, Some(finer)) = (options.interval, interval.finer()) {
+                            let finer_latest = options.source.latest_state(finer).await?;
+                            let last_included = find_sequence_at(
+                                options.source.as_ref(),
+                                &options.cache_path,
+                                finer,
+                                &finer_latest,
+                                latest_timestamp,
+                            )
+                            .await?
+                            .ok_or_else(|| eyre!("The {} feed starts after {}", finer, latest_timestamp))?;
+                            let next = last_included.next();
+                            yield ReplayEvent::IntervalSwitched {
+                                from: interval,
+                                to: finer,
+                                next_sequence: finer.sequence_name(next),
+                            };
+                            interval = finer;
+                            latest = Some(finer_latest);
+                            prefetching.clear();
+                            current = next;
+                            continue;
+                        }
+                        match options.follow {
+                            Some(poll_interval) => {
+                                options.control.sleep(poll_interval).await;
+                                continue;
+                            }
+                            None => break,
+                        }
+                    }
+                }
+
+                let sequence_state = State::fetch_sequence(
+                    options.source.as_ref(),
+                    &options.cache_path,
+                    interval,
+                    current,
+                )
+                .await?;
+                // Download minute replication files and find the changesets that were modified in that minute
+                let data_url = data_location(options.source.as_ref(), interval, current);
+                yield ReplayEvent::DownloadStarted {
+                    sequence: sequence.clone(),
+                    url: data_url.clone(),
+                    timestamp: sequence_state.map(|state| state.timestamp),
+                };
+                let wait_started = Instant::now();
+                let found = download_to_cache(
+                    options.source.clone(),
+                    interval,
+                    current,
+                    options.downloader.max_attempts,
+                    cache_file_path.clone(),
+                )
+                .await?;
+                let wait = wait_started.elapsed();
+
+                if !found {
+                    yield ReplayEvent::SequenceMissing { sequence: sequence.clone() };
+                    pending_gaps.push(sequence.clone());
+                    current = current.next();
+                    continue;
+                }
+                Some(wait)
+            };
+
+            let file = File::open(&cache_file_path)?;
+            let data = unsafe { Mmap::map(&file)? };
+            for gap in pending_gaps.drain(..) {
+                let commit = commit_gap(&sink.repository, &sink.author.signature()?, &gap)?;
+                yield ReplayEvent::GapRecorded { sequence: gap, commit };
+            }
+            for event in
+                sync_changeset_stream(changeset_stream.as_mut(), &options, &mut last_stream_sync, &mut lifecycle).await?
+            {
+                yield event;
+            }
+            // Download the next files in the background while this one is applied. At the end
+            // of a stretch of cached files this starts the downloads ahead of time too
+            if let Some(latest) = &latest {
+                prefetch(
+                    &mut prefetching,
+                    &options,
+                    interval,
+                    current.next()..=(current + controller.prefetch_depth() as u64).min(latest.sequence_number),
+                );
+            }
+            let apply_started = Instant::now();
+            // The file is applied on a blocking thread, which hands over its events as they
+            // happen, so the progress of big files is reported while they are applied
+            let (event_sender, mut applied_events) = mpsc::channel(APPLY_EVENT_BUFFER);
+            let applying = {
+                let sequence = sequence.clone();
+                let lifecycle = std::mem::take(&mut lifecycle);
+                tokio::task::spawn_blocking(move || {
+                    let applied = sink.apply_replication_file(&data, &sequence, &lifecycle, &mut |event| {
+                        // The receiver only goes away if the stream was dropped
+                        let _ = event_sender.blocking_send(event);
+                    });
+                    (sink, applied)
+                })
+            };
+            while let Some(event) = applied_events.recv().await {
+                yield event;
+            }
+            let (applied_sink, applied) = applying.await?;
+            sink = applied_sink;
+            applied?;
+            options.control.sequence_applied();
+            if let Some(cache_limit) = &mut cache_limit {
+                cache_limit.used(&sequence)?;
+                let eviction = cache_limit.evict(&sink.repository)?;
+                if eviction.files > 0 {
+                    yield ReplayEvent::CacheEvicted {
+                        files: eviction.files,
+                        bytes: eviction.bytes,
+                        cache_size: cache_limit.size(),
+                    };
+                }
+            }
+            if let Some(wait) = download_wait {
+                if let Some(decision) = controller.record(wait, apply_started.elapsed()) {
+                    yield ReplayEvent::PrefetchDepthChanged {
+                        prefetch_depth: decision.prefetch_depth,
+                        reason: decision.reason,
+                    };
+                }
+            }
+
+            current = current.next();
+
+            // Wait a few seconds before downloading the next data file
+            if !cached {
+                options.control.sleep(options.wait_time).await;
+            }
+        }
+
+        yield ReplayEvent::Finished {
+            last_sequence: interval.sequence_name(current.previous().unwrap_or(current)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+    use crate::{
+        git::{testing::TestRepository, STAGING_REF},
+        replay::testing::sink,
+    };
+
+    /// A replication source with a single day diff, creating `nodes` nodes in one changeset
+    fn source(repository: &TestRepository, nodes: usize) -> String {
+        let location = repository.path().join("test-source");
+        std::fs::create_dir_all(location.join("000/000")).unwrap();
+        let state = "sequenceNumber=1\ntimestamp=2012-09-12T10\\:00\\:00Z\n";
+        std::fs::write(location.join("state.txt"), state).unwrap();
+        std::fs::write(location.join("000/000/001.state.txt"), state).unwrap();
+        let created = (1..=nodes)
+            .map(|id| {
+                format!(
+                    r#"<node id="{}" version="1" timestamp="2012-09-12T09:00:00Z" uid="5" user="alice" changeset="2" lat="51.5" lon="-0.1"/>"#,
+                    id
+                )
+            })
+            .collect::<String>();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        write!(
+            encoder,
+            r#"<?xml version='1.0' encoding='UTF-8'?><osmChange version="0.6"><create>{}</create></osmChange>"#,
+            created
+        )
+        .unwrap();
+        std::fs::write(
+            location.join("000/000/001.osc.gz"),
+            encoder.finish().unwrap(),
+        )
+        .unwrap();
+        location.to_str().unwrap().to_string()
+    }
+
+    /// The changeset of the source, cached like a response of the OSM API
+    fn changesets(repository: &TestRepository) -> String {
+        let location = repository.path().join("test-changesets");
+        std::fs::create_dir_all(location.join("api")).unwrap();
+        std::fs::write(
+            location.join("api/2.osm"),
+            r#"<osm><changeset id="2" created_at="2012-09-12T09:00:00Z" closed_at="2012-09-12T09:30:00Z" open="false" user="alice" uid="5"/></osm>"#,
+        )
+        .unwrap();
+        location.to_str().unwrap().to_string()
+    }
+
+    fn options(repository: &TestRepository, source: &str) -> ReplayOptions {
+        ReplayOptions {
+            downloader: Downloader {
+                client: reqwest::Client::new(),
+                max_attempts: 1,
+                cassette: None,
+            },
+            source: Arc::new(crate::source::DirSource::new(source)),
+            interval: IntervalMode::Day,
+            cache_path: repository
+                .path()
+                .join("test-cache")
+                .to_str()
+                .unwrap()
+                .to_string(),
+            max_cache_size: None,
+            start_data: Some(SequenceNumber::default().next()),
+            start_date: None,
+            end_sequence: Some(SequenceNumber::default().next()),
+            end_date: None,
+            wait_time: Duration::ZERO,
+            follow: None,
+            tuning: TuningBounds {
+                min_prefetch: 1,
+                max_prefetch: 1,
+            },
+            changeset_stream: None,
+            changeset_retention: Duration::ZERO,
+            changeset_lifecycle: false,
+            off_peak: None,
+            control: Arc::new(ReplayControl::default()),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_the_progress_of_a_file_while_applying_it() {
+        let repository = TestRepository::new("stream", false);
+        // More events than are buffered, so the file can't be applied before the stream
+        // takes some of them
+        let source = source(&repository, APPLY_EVENT_BUFFER * 2);
+        let replayer = Replayer::new(
+            sink(repository.reopen(), &changesets(&repository)),
+            options(&repository, &source),
+        );
+        let events = replayer.into_stream();
+        tokio::pin!(events);
+
+        let mut parsed = 0;
+        let mut applied = false;
+        while let Some(event) = events.next().await {
+            match event.unwrap() {
+                ReplayEvent::ElementParsed { .. } => {
+                    if parsed == 0 {
+                        assert!(repository.find_reference(STAGING_REF).is_ok());
+                    }
+                    parsed += 1;
+                }
+                ReplayEvent::SequenceApplied { sequence, .. } => {
+                    assert_eq!(sequence, "000/000/001");
+                    applied = true;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(parsed, APPLY_EVENT_BUFFER * 2);
+        assert!(applied);
+        assert!(repository.find_reference(STAGING_REF).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+use git2::Repository;
+
+use super::GitSink;
+use crate::{
+    git::{
+        identity::{AuthorIdentity, Committer, IdentityPolicy},
+        notes::{NoteFormat, CHANGESET_NOTES_REF},
+        testing::signature,
+    },
+    osm::{
+        id_mapping::IdentityMapping,
+        layout::{Layout, ShardBudget},
+        parse_error::ParseMode,
+        squash::Granularity,
+        state_store::StateStoreKind,
+    },
+};
+
+/// A sink committing one changeset per commit with notes, taking the changesets from
+/// `changesets`
+pub fn sink(repository: Repository, changesets: &str) -> GitSink {
+    let layout = Layout::load(
+        &repository,
+        ShardBudget {
+            max_entries: 10_000,
+            max_file_size: 1 << 20,
+        },
+    )
+    .unwrap();
+    let state_store = StateStoreKind::Git
+        .open(&repository, layout.object_format)
+        .unwrap();
+    GitSink {
+        repository,
+        author: Committer::new(&signature()).unwrap(),
+        identity_policy: IdentityPolicy::Mapper,
+        authors: AuthorIdentity::default(),
+        changeset_location: changesets.to_string(),
+        id_mapper: Box::new(IdentityMapping),
+        layout,
+        state_store,
+        write_changeset_notes: true,
+        note_format: NoteFormat::default(),
+        notes_ref: CHANGESET_NOTES_REF.to_string(),
+        campaign_refs: false,
+        squash_window: None,
+        granularity: Granularity::Changeset,
+        tag_key_summaries: true,
+        area: None,
+        tag_filter: None,
+        mappers: None,
+        admin_areas: None,
+        watchlist: None,
+        #[cfg(feature = "http")]
+        changeset_api: None,
+        serialize_workers: 1,
+        commit_buffer_size: 1 << 20,
+        parse_mode: ParseMode::Strict,
+    }
+}
@@ -0,0 +1,539 @@
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
+use serde::Serialize;
+use time::{format_description::well_known::Iso8601, OffsetDateTime};
+
+#[cfg(feature = "http")]
+use crate::source::{parse_state, ReplicationSource};
+
+/// The state of a replication stream as published in `state.txt` and
+/// `AAA/BBB/CCC.state.txt` files
+///
+/// The files are Java properties files:
+///
+/// ```text
+/// #Wed Sep 12 02:00:03 UTC 2012
+/// sequenceNumber=2
+/// timestamp=2012-09-12T02\:00\:00Z
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+    pub sequence_number: SequenceNumber,
+    /// The time up to which the changes of the sequence are included
+    pub timestamp: OffsetDateTime,
+}
+
+impl State {
+    /// Parse the contents of a state file
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut sequence_number = None;
+        let mut timestamp = None;
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            // Colons are escaped in properties files
+            let value = value.trim().replace("\\:", ":");
+            match key.trim() {
+                "sequenceNumber" => sequence_number = Some(SequenceNumber::new(value.parse()?)),
+                "timestamp" => timestamp = Some(OffsetDateTime::parse(&value, &Iso8601::DEFAULT)?),
+                _ => {}
+            }
+        }
+        Ok(State {
+            sequence_number: sequence_number
+                .ok_or_else(|| eyre!("State file has no sequenceNumber"))?,
+            timestamp: timestamp.ok_or_else(|| eyre!("State file has no timestamp"))?,
+        })
+    }
+
+    /// Get the state of a sequence from the cache or the replication source
+    ///
+    /// Fetched state files are cached next to the replication files.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<State>>` - `None` if the source has no state file for the sequence
+    #[cfg(feature = "http")]
+    pub async fn fetch_sequence(
+        source: &dyn ReplicationSource,
+        cache_path: &str,
+        interval: Interval,
+        sequence_number: SequenceNumber,
+    ) -> Result<Option<Self>> {
+        let cache_file_path = format!(
+            "{}/replication/{}.state.txt",
+            cache_path,
+            interval.sequence_name(sequence_number)
+        );
+        if let Ok(contents) = std::fs::read_to_string(&cache_file_path) {
+            return Ok(Some(State::parse(&contents)?));
+        }
+
+        let path = format!("{}.state.txt", sequence_number);
+        let Some(contents) = source.read_feed_file(interval, &path).await? else {
+            return Ok(None);
+        };
+        let state = parse_state(&source.feed_location(interval, &path), &contents)?;
+        std::fs::create_dir_all(std::path::Path::new(&cache_file_path).parent().unwrap())?;
+        std::fs::write(&cache_file_path, contents)?;
+        Ok(Some(state))
+    }
+}
+
+/// Find the newest sequence of a feed which only contains changes up to `timestamp`
+///
+/// The search starts at the sequence [`SequenceNumber::from_timestamp`] estimates and
+/// looks at state files in growing steps around it, until the sequence is between two of
+/// them. That range is binary searched, so only a few state files are downloaded.
+/// Sequences without a state file are treated as older than `timestamp`.
+///
+/// # Returns
+///
+/// * `Result<Option<SequenceNumber>>` - `None` if every sequence is newer than `timestamp`
+#[cfg(feature = "http")]
+pub async fn find_sequence_at(
+    source: &dyn ReplicationSource,
+    cache_path: &str,
+    interval: Interval,
+    latest: &State,
+    timestamp: OffsetDateTime,
+) -> Result<Option<SequenceNumber>> {
+    if latest.timestamp <= timestamp {
+        return Ok(Some(latest.sequence_number));
+    }
+    let is_older = |sequence: u64| async move {
+        let state =
+            State::fetch_sequence(source, cache_path, interval, SequenceNumber(sequence)).await?;
+        Ok::<_, color_eyre::Report>(state.is_none_or(|state| state.timestamp <= timestamp))
+    };
+
+    // The sequence is in `low..high`. `high` is known to be newer than `timestamp`
+    let mut found = None;
+    let mut low = 0;
+    let mut high = latest.sequence_number.get();
+    if low < high {
+        let estimate = SequenceNumber::from_timestamp(latest, interval, timestamp)
+            .get()
+            .min(high - 1);
+        let mut step = 1;
+        if is_older(estimate).await? {
+            found = Some(estimate);
+            low = estimate + 1;
+            while low < high {
+                let probe = estimate.saturating_add(step).min(high - 1);
+                if !is_older(probe).await? {
+                    high = probe;
+                    break;
+                }
+                found = Some(probe);
+                low = probe + 1;
+                step *= 2;
+            }
+        } else {
+            high = estimate;
+            while low < high {
+                let probe = estimate.saturating_sub(step).max(low);
+                if is_older(probe).await? {
+                    found = Some(probe);
+                    low = probe + 1;
+                    break;
+                }
+                high = probe;
+                step *= 2;
+            }
+        }
+    }
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if is_older(middle).await? {
+            found = Some(middle);
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    Ok(found.map(SequenceNumber))
+}
+
+/// The interval of the replication feed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interval {
+    Day,
+    Hour,
+    Minute,
+}
+
+impl Interval {
+    pub fn name(self) -> &'static str {
+        match self {
+            Interval::Day => "day",
+            Interval::Hour => "hour",
+            Interval::Minute => "minute",
+        }
+    }
+
+    /// The next feed with a lower latency
+    pub fn finer(self) -> Option<Interval> {
+        match self {
+            Interval::Day => Some(Interval::Hour),
+            Interval::Hour => Some(Interval::Minute),
+            Interval::Minute => None,
+        }
+    }
+
+    /// How long a sequence of this feed covers
+    pub fn seconds(self) -> i64 {
+        match self {
+            Interval::Day => 24 * 60 * 60,
+            Interval::Hour => 60 * 60,
+            Interval::Minute => 60,
+        }
+    }
+
+    /// The name of a sequence of this feed as used in tags, the state and the cache
+    ///
+    /// Sequences of the day feed are named by their path only, so git repos created
+    /// before the hour and minute feeds were supported stay valid. The others are
+    /// prefixed with the interval, like `minute/005/123/456`.
+    pub fn sequence_name(self, sequence: SequenceNumber) -> String {
+        match self {
+            Interval::Day => sequence.to_string(),
+            _ => format!("{}/{}", self.name(), sequence),
+        }
+    }
+
+    /// Split the name of a sequence into its feed and number
+    pub fn parse_sequence_name(name: &str) -> Result<(Interval, SequenceNumber)> {
+        let (interval, path) = [Interval::Hour, Interval::Minute]
+            .into_iter()
+            .find_map(|interval| {
+                name.strip_prefix(&format!("{}/", interval.name()))
+                    .map(|path| (interval, path))
+            })
+            .unwrap_or((Interval::Day, name));
+        Ok((interval, path.parse().map_err(|err: String| eyre!(err))?))
+    }
+
+    /// The URL of the feed of this interval
+    ///
+    /// A replication server URL ending in an interval, like the default
+    /// `.../replication/day`, is switched to this interval. Other URLs are used as they are.
+    pub fn feed_url(self, replication_server: &str) -> String {
+        match feed_base(replication_server) {
+            Some(base) => format!("{}/{}", base, self.name()),
+            None => replication_server.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Which replication feeds to replay
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IntervalMode {
+    Day,
+    Hour,
+    Minute,
+    /// Catch up with day diffs, then switch to hour and minute diffs near the newest
+    /// sequence for a lower latency
+    Auto,
+}
+
+impl IntervalMode {
+    /// The feed to replay, `None` if the feed switches automatically
+    pub fn fixed(self) -> Option<Interval> {
+        match self {
+            IntervalMode::Day => Some(Interval::Day),
+            IntervalMode::Hour => Some(Interval::Hour),
+            IntervalMode::Minute => Some(Interval::Minute),
+            IntervalMode::Auto => None,
+        }
+    }
+}
+
+/// The part of a replication server URL before a trailing interval
+pub fn feed_base(replication_server: &str) -> Option<&str> {
+    let replication_server = replication_server.trim_end_matches('/');
+    let (base, interval) = replication_server.rsplit_once('/')?;
+    matches!(interval, "day" | "hour" | "minute").then_some(base)
+}
+
+/// The number of a replication file
+///
+/// Displayed and parsed as the `AAA/BBB/CCC` path of the file on the server and in the
+/// cache, like `005/123/456` for 5123456. Parsing also takes the plain number.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SequenceNumber(u64);
+
+impl SequenceNumber {
+    pub const fn new(number: u64) -> Self {
+        SequenceNumber(number)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+
+    /// The sequence after this one
+    pub const fn next(self) -> Self {
+        SequenceNumber(self.0 + 1)
+    }
+
+    /// The sequence before this one, `None` for the first sequence
+    pub fn previous(self) -> Option<Self> {
+        self.0.checked_sub(1).map(SequenceNumber)
+    }
+
+    /// The sequence `count` sequences before this one, or the first sequence
+    pub const fn saturating_sub(self, count: u64) -> Self {
+        SequenceNumber(self.0.saturating_sub(count))
+    }
+
+    /// The sequences from this one up to `last`, both included
+    pub fn through(self, last: SequenceNumber) -> impl Iterator<Item = SequenceNumber> {
+        (self.0..=last.0).map(SequenceNumber)
+    }
+
+    /// Estimate the sequence of a feed covering `timestamp` from the state of a known
+    /// sequence, as the feeds publish one sequence per interval
+    ///
+    /// Sequences missing from the feed make the estimate off, so it is only a starting
+    /// point to look at the state files from.
+    pub fn from_timestamp(known: &State, interval: Interval, timestamp: OffsetDateTime) -> Self {
+        let seconds = (timestamp - known.timestamp).whole_seconds();
+        let sequences = seconds.div_euclid(interval.seconds());
+        SequenceNumber(known.sequence_number.0.saturating_add_signed(sequences))
+    }
+}
+
+impl std::ops::Add<u64> for SequenceNumber {
+    type Output = SequenceNumber;
+
+    fn add(self, count: u64) -> Self::Output {
+        SequenceNumber(self.0 + count)
+    }
+}
+
+impl std::fmt::Display for SequenceNumber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:03}/{:03}/{:03}",
+            self.0 / 1_000_000,
+            self.0 / 1_000 % 1_000,
+            self.0 % 1_000
+        )
+    }
+}
+
+impl FromStr for SequenceNumber {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid sequence {:?}, expected AAA/BBB/CCC", s);
+        let parts = s
+            .trim()
+            .split('/')
+            .map(|part| part.parse::<u64>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        match parts[..] {
+            [number] => Ok(SequenceNumber(number)),
+            [top, middle, bottom] if middle < 1_000 && bottom < 1_000 => top
+                .checked_mul(1_000_000)
+                .and_then(|top| top.checked_add(middle * 1_000 + bottom))
+                .map(SequenceNumber)
+                .ok_or_else(invalid),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::format_description::well_known::Rfc3339;
+
+    use super::*;
+
+    fn at(timestamp: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(timestamp, &Rfc3339).unwrap()
+    }
+
+    fn sequence(name: &str) -> SequenceNumber {
+        name.parse().unwrap()
+    }
+
+    #[test]
+    fn counts_over_the_boundaries_of_the_directories() {
+        for (number, name, next) in [
+            (999, "000/000/999", "000/001/000"),
+            (1_999, "000/001/999", "000/002/000"),
+            (999_999, "000/999/999", "001/000/000"),
+            (5_123_999, "005/123/999", "005/124/000"),
+            (999_999_999, "999/999/999", "1000/000/000"),
+        ] {
+            let sequence_number = SequenceNumber::new(number);
+            assert_eq!(sequence_number.to_string(), name);
+            assert_eq!(sequence(name), sequence_number);
+            assert_eq!(sequence_number.next().to_string(), next);
+            assert_eq!(sequence(next), sequence_number.next());
+            assert_eq!(sequence(next).previous(), Some(sequence_number));
+        }
+        assert_eq!(SequenceNumber::default().previous(), None);
+    }
+
+    #[test]
+    fn parses_sequences() {
+        assert_eq!(sequence("1000"), SequenceNumber::new(1_000));
+        assert_eq!(sequence(" 001/000/000\n"), SequenceNumber::new(1_000_000));
+        for invalid in ["000/1000/000", "000/000/1000", "000/000", "a/b/c", ""] {
+            assert!(invalid.parse::<SequenceNumber>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn estimates_sequences_from_timestamps() {
+        let known = State {
+            sequence_number: SequenceNumber::new(5_000),
+            timestamp: at("2024-01-10T00:00:00Z"),
+        };
+        for (interval, timestamp, estimate) in [
+            (Interval::Day, at("2024-01-13T01:00:00Z"), 5_003),
+            (Interval::Day, at("2024-01-09T12:00:00Z"), 4_999),
+            (Interval::Hour, at("2024-01-10T02:59:00Z"), 5_002),
+            (Interval::Minute, at("2024-01-10T00:00:00Z"), 5_000),
+            (Interval::Minute, at("2024-01-06T00:00:00Z"), 0),
+        ] {
+            assert_eq!(
+                SequenceNumber::from_timestamp(&known, interval, timestamp),
+                SequenceNumber::new(estimate),
+                "{} {}",
+                interval,
+                timestamp
+            );
+        }
+    }
+
+    #[cfg(feature = "http")]
+    mod search {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use async_trait::async_trait;
+        use bytes::Bytes;
+        use time::Duration;
+
+        use super::*;
+
+        const LATEST: u64 = 3_000;
+
+        /// A minute feed which stopped for 10 hours after sequence 1000
+        struct Feed {
+            reads: AtomicUsize,
+        }
+
+        impl Feed {
+            fn timestamp(sequence: u64) -> OffsetDateTime {
+                let outage = if sequence > 1_000 { 600 } else { 0 };
+                at("2024-01-10T00:00:00Z") + Duration::minutes((sequence + outage) as i64)
+            }
+
+            fn latest() -> State {
+                State {
+                    sequence_number: SequenceNumber::new(LATEST),
+                    timestamp: Feed::timestamp(LATEST),
+                }
+            }
+        }
+
+        #[async_trait]
+        impl ReplicationSource for Feed {
+            fn root(&self) -> &str {
+                "feed"
+            }
+
+            async fn read_file(&self, location: &str) -> Result<Option<Bytes>> {
+                self.reads.fetch_add(1, Ordering::Relaxed);
+                let name = location
+                    .strip_prefix("feed/")
+                    .and_then(|path| path.strip_suffix(".state.txt"))
+                    .unwrap();
+                let sequence_number = sequence(name);
+                Ok(Some(Bytes::from(format!(
+                    "sequenceNumber={}\ntimestamp={}\n",
+                    sequence_number.get(),
+                    Feed::timestamp(sequence_number.get())
+                        .format(&Rfc3339)
+                        .unwrap()
+                        .replace(':', "\\:")
+                ))))
+            }
+        }
+
+        /// Search the feed with a new cache, returning the sequence and the number of
+        /// state files read
+        async fn search(timestamp: OffsetDateTime) -> (Option<u64>, usize) {
+            let cache_path = std::env::temp_dir().join(format!(
+                "osm-git-test-search-{}-{}",
+                std::process::id(),
+                timestamp.unix_timestamp()
+            ));
+            let feed = Feed {
+                reads: AtomicUsize::new(0),
+            };
+            let found = find_sequence_at(
+                &feed,
+                cache_path.to_str().unwrap(),
+                Interval::Minute,
+                &Feed::latest(),
+                timestamp,
+            )
+            .await
+            .unwrap();
+            let _ = std::fs::remove_dir_all(cache_path);
+            (found.map(SequenceNumber::get), feed.reads.into_inner())
+        }
+
+        #[tokio::test]
+        async fn finds_the_sequence_next_to_the_estimate() {
+            let (found, reads) = search(Feed::timestamp(2_000) + Duration::seconds(30)).await;
+            assert_eq!(found, Some(2_000));
+            assert_eq!(reads, 2);
+        }
+
+        #[tokio::test]
+        async fn finds_sequences_the_estimate_is_off_for() {
+            for sequence in [0, 1, 500, 999, 1_000, 1_001, 2_999] {
+                let (found, reads) = search(Feed::timestamp(sequence)).await;
+                assert_eq!(found, Some(sequence));
+                assert!(reads <= 30, "{} state files read for {}", reads, sequence);
+            }
+            // Within the outage, the last sequence before it has all the changes
+            let (found, _) = search(Feed::timestamp(1_000) + Duration::hours(5)).await;
+            assert_eq!(found, Some(1_000));
+        }
+
+        #[tokio::test]
+        async fn finds_no_sequence_before_the_feed() {
+            let (found, _) = search(Feed::timestamp(0) - Duration::minutes(1)).await;
+            assert_eq!(found, None);
+        }
+
+        #[tokio::test]
+        async fn takes_the_latest_sequence_after_it() {
+            let (found, reads) = search(Feed::timestamp(LATEST) + Duration::hours(1)).await;
+            assert_eq!(found, Some(LATEST));
+            assert_eq!(reads, 0);
+        }
+    }
+}
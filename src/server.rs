@@ -0,0 +1,198 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    routing::get,
+    Json, Router,
+};
+use color_eyre::eyre::Result;
+use git2::Repository;
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{
+    git::consistent_commit,
+    osm::{
+        export::{object_history, write_header, write_version, ObjectVersion},
+        geometry::Geometry,
+        id_mapping::IdMapper,
+        layout::ShardBudget,
+        snapshot::{resolve_commit_from, Snapshot},
+    },
+};
+
+/// Everything the HTTP handlers need to read from the git repo
+#[derive(Clone)]
+pub struct ServerState {
+    pub git_repo_path: String,
+    pub id_mapper: Arc<dyn IdMapper>,
+    pub budget: ShardBudget,
+}
+
+#[derive(Debug, Deserialize)]
+struct AtQuery {
+    /// A revision or ISO 8601 date. Defaults to the last fully applied sequence.
+    at: Option<String>,
+}
+
+type HandlerResult<T> = std::result::Result<T, (StatusCode, String)>;
+
+/// An OSM XML response like the ones of the OSM API
+type XmlResponse = ([(header::HeaderName, &'static str); 1], String);
+
+/// Serve read-only HTTP endpoints for the git repo
+///
+/// The endpoints read from the [`CONSISTENT_REF`](crate::git::CONSISTENT_REF) instead of
+/// HEAD, so a replay running next to the server is only visible once a sequence is
+/// complete.
+pub async fn serve(listen: SocketAddr, state: ServerState) -> Result<()> {
+    let app = Router::new()
+        .route("/geometry/:type/:id", get(geometry))
+        .route("/api/0.6/:type/:id", get(api_object))
+        .route("/api/0.6/:type/:id/history", get(api_history))
+        .with_state(state);
+
+    info!("Listening on http://{}", listen);
+    axum::Server::bind(&listen)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+/// `GET /geometry/{node,way,relation}/<id>?at=<revision or date>`
+///
+/// Responds with the GeoJSON geometry of the object at the given commit.
+async fn geometry(
+    State(state): State<ServerState>,
+    Path((type_name, id)): Path<(String, u64)>,
+    Query(query): Query<AtQuery>,
+) -> HandlerResult<Json<Geometry>> {
+    check_type(&type_name)?;
+
+    // git2 is blocking, so the git repo is read on the blocking thread pool
+    let geometry = tokio::task::spawn_blocking(move || -> HandlerResult<Option<Geometry>> {
+        let repository = Repository::open(&state.git_repo_path).map_err(internal_error)?;
+        let tip = consistent_commit(&repository).map_err(internal_error)?;
+        let commit = resolve_commit_from(&repository, tip, query.at.as_deref())
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        let snapshot = Snapshot::new(&repository, &commit, state.id_mapper.as_ref(), state.budget)
+            .map_err(internal_error)?;
+        snapshot
+            .geometry(&type_name, id)
+            .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))
+    })
+    .await
+    .map_err(internal_error)??;
+
+    geometry
+        .map(Json)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Object not found".to_string()))
+}
+
+/// `GET /api/0.6/{node,way,relation}/<id>`
+///
+/// Responds with the current version of the object like the OSM API. Objects which
+/// existed in the history but were deleted are `410 Gone`.
+async fn api_object(
+    State(state): State<ServerState>,
+    Path((type_name, id)): Path<(String, u64)>,
+) -> HandlerResult<XmlResponse> {
+    check_type(&type_name)?;
+
+    let version = tokio::task::spawn_blocking(move || -> HandlerResult<ObjectVersion> {
+        let repository = Repository::open(&state.git_repo_path).map_err(internal_error)?;
+        let head = consistent_commit(&repository).map_err(internal_error)?;
+        let snapshot = Snapshot::new(&repository, &head, state.id_mapper.as_ref(), state.budget)
+            .map_err(internal_error)?;
+        if let Some(object) = snapshot.object(&type_name, id).map_err(internal_error)? {
+            // The changeset is only known from the history, which is not walked for
+            // objects which still exist
+            return Ok(ObjectVersion {
+                object,
+                changeset: None,
+            });
+        }
+
+        let history = object_history(
+            &repository,
+            &head,
+            &type_name,
+            id,
+            state.id_mapper.as_ref(),
+            state.budget,
+        )
+        .map_err(internal_error)?;
+        if history.is_empty() {
+            Err((StatusCode::NOT_FOUND, "Object not found".to_string()))
+        } else {
+            Err((StatusCode::GONE, "Object was deleted".to_string()))
+        }
+    })
+    .await
+    .map_err(internal_error)??;
+
+    osm_xml(std::slice::from_ref(&version))
+}
+
+/// `GET /api/0.6/{node,way,relation}/<id>/history`
+///
+/// Responds with all versions of the object in the first-parent history of the last
+/// fully applied sequence,
+/// including the deleted ones.
+async fn api_history(
+    State(state): State<ServerState>,
+    Path((type_name, id)): Path<(String, u64)>,
+) -> HandlerResult<XmlResponse> {
+    check_type(&type_name)?;
+
+    let history = tokio::task::spawn_blocking(move || -> HandlerResult<Vec<ObjectVersion>> {
+        let repository = Repository::open(&state.git_repo_path).map_err(internal_error)?;
+        let head = consistent_commit(&repository).map_err(internal_error)?;
+        object_history(
+            &repository,
+            &head,
+            &type_name,
+            id,
+            state.id_mapper.as_ref(),
+            state.budget,
+        )
+        .map_err(internal_error)
+    })
+    .await
+    .map_err(internal_error)??;
+
+    if history.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "Object not found".to_string()));
+    }
+    osm_xml(&history)
+}
+
+fn check_type(type_name: &str) -> HandlerResult<()> {
+    if !matches!(type_name, "node" | "way" | "relation") {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Unknown object type {}", type_name),
+        ));
+    }
+    Ok(())
+}
+
+fn osm_xml(versions: &[ObjectVersion]) -> HandlerResult<XmlResponse> {
+    let mut body = Vec::new();
+    write_header(&mut body).map_err(internal_error)?;
+    for version in versions {
+        write_version(&mut body, version).map_err(internal_error)?;
+    }
+    body.extend_from_slice(b"</osm>\n");
+    let body = String::from_utf8(body).map_err(internal_error)?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        body,
+    ))
+}
+
+fn internal_error(err: impl std::fmt::Display) -> (StatusCode, String) {
+    error!("Unable to handle request: {}", err);
+    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+}
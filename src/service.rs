@@ -0,0 +1,158 @@
+use std::{ffi::OsString, path::Path};
+
+use clap::ValueEnum;
+use color_eyre::eyre::Result;
+use tracing::debug;
+
+/// The service managers `osm-git install-service` can generate definitions for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ServiceManager {
+    /// A systemd unit for Linux
+    Systemd,
+    /// A launchd property list for macOS
+    Launchd,
+}
+
+/// What the service runs
+pub struct ServiceDefinition<'a> {
+    pub executable: &'a Path,
+    pub args: &'a [OsString],
+    pub working_directory: &'a Path,
+    /// The time after which the service manager restarts a replay which stopped
+    /// responding
+    pub watchdog_secs: u64,
+}
+
+impl ServiceManager {
+    /// The file name the definition should be installed as
+    pub fn file_name(self) -> &'static str {
+        match self {
+            ServiceManager::Systemd => "osm-git.service",
+            ServiceManager::Launchd => "org.openstreetmap.osm-git.plist",
+        }
+    }
+
+    /// Generate the service definition
+    pub fn definition(self, service: &ServiceDefinition) -> String {
+        match self {
+            ServiceManager::Systemd => systemd_unit(service),
+            ServiceManager::Launchd => launchd_plist(service),
+        }
+    }
+}
+
+fn systemd_unit(service: &ServiceDefinition) -> String {
+    let command = std::iter::once(service.executable.as_os_str())
+        .chain(service.args.iter().map(OsString::as_os_str))
+        .map(|arg| systemd_quote(&arg.to_string_lossy()))
+        .collect::<Vec<String>>()
+        .join(" ");
+    format!(
+        "[Unit]
+Description=Replay OpenStreetMap changesets to a git repository
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+WorkingDirectory={}
+ExecStart={}
+WatchdogSec={}
+Restart=on-failure
+RestartSec=60
+
+[Install]
+WantedBy=multi-user.target
+",
+        service.working_directory.display(),
+        command,
+        service.watchdog_secs
+    )
+}
+
+/// Quote an argument for the command line of a systemd unit
+fn systemd_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && !arg
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | '%' | '$'))
+    {
+        return arg.to_string();
+    }
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{}\"", escaped)
+}
+
+fn launchd_plist(service: &ServiceDefinition) -> String {
+    let arguments = std::iter::once(service.executable.as_os_str())
+        .chain(service.args.iter().map(OsString::as_os_str))
+        .map(|arg| {
+            format!(
+                "        <string>{}</string>\n",
+                xml_escape(&arg.to_string_lossy())
+            )
+        })
+        .collect::<String>();
+    // launchd has no watchdog, so it only restarts the replay when it exits
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>org.openstreetmap.osm-git</string>
+    <key>ProgramArguments</key>
+    <array>
+{}    </array>
+    <key>WorkingDirectory</key>
+    <string>{}</string>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+    <key>ThrottleInterval</key>
+    <integer>60</integer>
+</dict>
+</plist>
+"#,
+        arguments,
+        xml_escape(&service.working_directory.to_string_lossy())
+    )
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Send a state change to the service manager
+///
+/// This implements the `sd_notify` protocol of systemd and does nothing when the process
+/// is not run by systemd.
+#[cfg(unix)]
+pub fn notify(state: &str) -> Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(socket_path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    debug!("Notifying the service manager: {}", state);
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to(state.as_bytes(), socket_path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn notify(state: &str) -> Result<()> {
+    debug!("Not notifying the service manager: {}", state);
+    Ok(())
+}
@@ -0,0 +1,35 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use color_eyre::eyre::Result;
+
+use super::ReplicationSource;
+
+/// A local copy of a replication server, like one kept in sync with rsync
+pub struct DirSource {
+    path: String,
+}
+
+impl DirSource {
+    pub fn new(path: &str) -> Self {
+        let path = path.trim_end_matches('/');
+        DirSource {
+            // The root directory keeps its slash
+            path: if path.is_empty() { "/" } else { path }.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl ReplicationSource for DirSource {
+    fn root(&self) -> &str {
+        &self.path
+    }
+
+    async fn read_file(&self, location: &str) -> Result<Option<Bytes>> {
+        match tokio::fs::read(location).await {
+            Ok(contents) => Ok(Some(Bytes::from(contents))),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
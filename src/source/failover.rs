@@ -0,0 +1,90 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Result};
+use tracing::warn;
+
+use super::ReplicationSource;
+use crate::replication::Interval;
+
+/// Mirrors of the same replication feeds
+///
+/// Files are read from the current mirror, the first one at the start. Once a read fails,
+/// after the retries of its download, the other mirrors are tried in order and the first
+/// one which answers becomes the current mirror. A file a mirror doesn't have is no
+/// failure, so a mirror which lags behind is not switched away from.
+pub struct FailoverSource {
+    mirrors: Vec<Arc<dyn ReplicationSource>>,
+    current: AtomicUsize,
+}
+
+impl FailoverSource {
+    pub fn new(mirrors: Vec<Arc<dyn ReplicationSource>>) -> Result<Self> {
+        if mirrors.is_empty() {
+            return Err(eyre!("At least one replication server is needed"));
+        }
+        Ok(FailoverSource {
+            mirrors,
+            current: AtomicUsize::new(0),
+        })
+    }
+
+    fn current(&self) -> &Arc<dyn ReplicationSource> {
+        &self.mirrors[self.current.load(Ordering::Relaxed)]
+    }
+}
+
+#[async_trait]
+impl ReplicationSource for FailoverSource {
+    fn root(&self) -> &str {
+        self.current().root()
+    }
+
+    /// Read a file of the mirror the location is of
+    async fn read_file(&self, location: &str) -> Result<Option<Bytes>> {
+        let mirror = self
+            .mirrors
+            .iter()
+            .filter(|mirror| location.starts_with(mirror.root()))
+            .max_by_key(|mirror| mirror.root().len())
+            .unwrap_or_else(|| self.current());
+        mirror.read_file(location).await
+    }
+
+    fn has_all_feeds(&self) -> bool {
+        self.mirrors.iter().all(|mirror| mirror.has_all_feeds())
+    }
+
+    async fn read_feed_file(&self, interval: Interval, path: &str) -> Result<Option<Bytes>> {
+        let first = self.current.load(Ordering::Relaxed);
+        let mut last_error = None;
+        for offset in 0..self.mirrors.len() {
+            let index = (first + offset) % self.mirrors.len();
+            let mirror = &self.mirrors[index];
+            match mirror.read_feed_file(interval, path).await {
+                Ok(file) => {
+                    self.current.store(index, Ordering::Relaxed);
+                    return Ok(file);
+                }
+                Err(err) => {
+                    if offset + 1 < self.mirrors.len() {
+                        let next = &self.mirrors[(index + 1) % self.mirrors.len()];
+                        warn!(
+                            "Reading {} from the mirror {} failed: {}. Switching to the mirror {}",
+                            path,
+                            mirror.root(),
+                            err,
+                            next.root()
+                        );
+                    }
+                    last_error = Some(err);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| eyre!("No mirror has {}", path)))
+    }
+}
@@ -0,0 +1,114 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Result};
+use reqwest::header::{HeaderMap, HeaderValue, COOKIE};
+
+use super::ReplicationSource;
+use crate::{download::Downloader, replication::Interval};
+
+/// The Geofabrik server with the metadata of the objects, for logged in OSM users
+///
+/// The extracts of the public download.geofabrik.de have neither changeset ids nor
+/// mappers, which the commits are made of.
+pub const GEOFABRIK_INTERNAL_SERVER: &str = "https://osm-internal.download.geofabrik.de";
+
+/// The daily replication feed of a Geofabrik extract, like `europe/germany`
+///
+/// The feed is at `<region>-updates` and is only published daily, with its own sequence
+/// numbers. Requests are authorized with the cookie `oauth_cookie_client.py` of Geofabrik
+/// writes. The cookie file is read for every request, so it can be renewed while the
+/// replay runs.
+pub struct GeofabrikSource {
+    url: String,
+    cookie_file: PathBuf,
+    downloader: Downloader,
+}
+
+impl GeofabrikSource {
+    pub fn new(region: &str, cookie_file: Option<PathBuf>, downloader: Downloader) -> Result<Self> {
+        let region = region.trim_matches('/').trim_end_matches("-updates");
+        if region.is_empty() {
+            return Err(eyre!(
+                "A geofabrik: replication source needs a region, like geofabrik:europe/germany"
+            ));
+        }
+        let cookie_file = cookie_file.ok_or_else(|| {
+            eyre!(
+                "The public Geofabrik extracts have no changeset ids. Pass the cookie of \
+                 Geofabrik's oauth_cookie_client.py as --geofabrik-cookie to replay {} from {}",
+                region,
+                GEOFABRIK_INTERNAL_SERVER
+            )
+        })?;
+        Ok(GeofabrikSource {
+            url: format!("{}/{}-updates", GEOFABRIK_INTERNAL_SERVER, region),
+            cookie_file,
+            downloader,
+        })
+    }
+
+    /// The cookie header of the cookie file
+    ///
+    /// The file has the cookie as `name=value`, or in the Netscape format of curl and
+    /// wget.
+    fn cookie(&self) -> Result<HeaderMap> {
+        let contents = std::fs::read_to_string(&self.cookie_file).map_err(|err| {
+            eyre!(
+                "Unable to read the Geofabrik cookie {}: {}",
+                self.cookie_file.display(),
+                err
+            )
+        })?;
+        let cookies = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| match line.split('\t').collect::<Vec<_>>()[..] {
+                [_, _, _, _, _, name, value] => format!("{}={}", name, value),
+                _ => line.trim_end_matches(';').to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(&cookies)?);
+        Ok(headers)
+    }
+}
+
+#[async_trait]
+impl ReplicationSource for GeofabrikSource {
+    fn root(&self) -> &str {
+        &self.url
+    }
+
+    async fn read_file(&self, location: &str) -> Result<Option<Bytes>> {
+        let file = self
+            .downloader
+            .download_with_headers(location, &|| self.cookie())
+            .await?;
+        // Without a valid cookie the server redirects to its login page
+        if let Some(file) = &file {
+            let start = String::from_utf8_lossy(&file[..file.len().min(512)]).to_lowercase();
+            if start.contains("<html") || start.contains("<!doctype html") {
+                return Err(eyre!(
+                    "Geofabrik answered {} with a web page, the cookie {} has probably expired. Renew it with oauth_cookie_client.py",
+                    location,
+                    self.cookie_file.display()
+                ));
+            }
+        }
+        Ok(file)
+    }
+
+    async fn read_feed_file(&self, interval: Interval, path: &str) -> Result<Option<Bytes>> {
+        if interval != Interval::Day {
+            return Err(eyre!(
+                "Geofabrik only publishes daily replication feeds, not {} ones",
+                interval.name()
+            ));
+        }
+        self.read_file(&self.feed_location(interval, path)).await
+    }
+}
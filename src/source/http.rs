@@ -0,0 +1,32 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use color_eyre::eyre::Result;
+
+use super::ReplicationSource;
+use crate::download::Downloader;
+
+/// A replication server, like https://planet.openstreetmap.org/replication/day
+pub struct HttpSource {
+    url: String,
+    downloader: Downloader,
+}
+
+impl HttpSource {
+    pub fn new(url: &str, downloader: Downloader) -> Self {
+        HttpSource {
+            url: url.trim_end_matches('/').to_string(),
+            downloader,
+        }
+    }
+}
+
+#[async_trait]
+impl ReplicationSource for HttpSource {
+    fn root(&self) -> &str {
+        &self.url
+    }
+
+    async fn read_file(&self, location: &str) -> Result<Option<Bytes>> {
+        self.downloader.download(location).await
+    }
+}
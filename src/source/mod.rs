@@ -0,0 +1,150 @@
+//! Where the replay gets the files of the replication feeds from
+//!
+//! A [`ReplicationSource`] has the layout of a replication server: every feed has a
+//! `state.txt` with its newest sequence, and an `AAA/BBB/CCC.osc.gz` with the changes
+//! and an `AAA/BBB/CCC.state.txt` for every sequence. Sources are an HTTP server like
+//! planet.openstreetmap.org, a local copy of one, an object storage bucket, or the feed
+//! of a Geofabrik extract.
+
+use std::{path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Result};
+
+use crate::{
+    download::Downloader,
+    replication::{feed_base, Interval, SequenceNumber, State},
+};
+
+pub mod dir;
+pub mod failover;
+pub mod geofabrik;
+pub mod http;
+pub mod s3;
+
+pub use dir::DirSource;
+pub use failover::FailoverSource;
+pub use geofabrik::GeofabrikSource;
+pub use http::HttpSource;
+pub use s3::{S3Options, S3Source};
+
+/// The files of the replication feeds
+#[async_trait]
+pub trait ReplicationSource: Send + Sync {
+    /// Where the source is, like the URL of the server. A location ending in /day, /hour
+    /// or /minute is switched to the feed of the replayed interval
+    fn root(&self) -> &str;
+
+    /// Read a file
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Bytes>>` - `None` if the source has no such file
+    async fn read_file(&self, location: &str) -> Result<Option<Bytes>>;
+
+    /// The location of a file of a feed, given by its path in the feed
+    fn feed_location(&self, interval: Interval, path: &str) -> String {
+        format!("{}/{}", interval.feed_url(self.root()), path)
+    }
+
+    /// Whether the source has a feed for every interval to switch between
+    fn has_all_feeds(&self) -> bool {
+        feed_base(self.root()).is_some()
+    }
+
+    /// Read a file of a feed, given by its path in the feed
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Bytes>>` - `None` if the source has no such file
+    async fn read_feed_file(&self, interval: Interval, path: &str) -> Result<Option<Bytes>> {
+        self.read_file(&self.feed_location(interval, path)).await
+    }
+
+    /// The state of the newest sequence of a feed
+    async fn latest_state(&self, interval: Interval) -> Result<State> {
+        let location = self.feed_location(interval, "state.txt");
+        let contents = self
+            .read_feed_file(interval, "state.txt")
+            .await?
+            .ok_or_else(|| eyre!("The replication source has no state file {}", location))?;
+        parse_state(&location, &contents)
+    }
+
+    /// The state of a sequence of a feed
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<State>>` - `None` if the source has no state file for the sequence
+    async fn sequence_state(
+        &self,
+        interval: Interval,
+        sequence: SequenceNumber,
+    ) -> Result<Option<State>> {
+        let path = format!("{}.state.txt", sequence);
+        self.read_feed_file(interval, &path)
+            .await?
+            .map(|contents| parse_state(&self.feed_location(interval, &path), &contents))
+            .transpose()
+    }
+
+    /// The gzipped changes of a sequence of a feed
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Bytes>>` - `None` if the source has no file for the sequence
+    async fn fetch(&self, interval: Interval, sequence: SequenceNumber) -> Result<Option<Bytes>> {
+        self.read_feed_file(interval, &format!("{}.osc.gz", sequence))
+            .await
+    }
+}
+
+/// Parse a state file read from `location`
+pub(crate) fn parse_state(location: &str, contents: &[u8]) -> Result<State> {
+    std::str::from_utf8(contents)
+        .map_err(|err| eyre!(err))
+        .and_then(State::parse)
+        .map_err(|err| eyre!("Invalid state file {}: {}", location, err))
+}
+
+/// The settings of the sources which need more than their location
+#[derive(Debug, Clone)]
+pub struct SourceOptions {
+    pub s3: S3Options,
+    /// The login cookie of a Geofabrik source
+    pub geofabrik_cookie: Option<PathBuf>,
+}
+
+/// Open the source at `location`
+///
+/// `s3://<bucket>/<prefix>` is a bucket of an object storage, `geofabrik:<region>` the
+/// feed of a Geofabrik extract, `file://<path>` or a path without a scheme a local copy of
+/// a replication server, anything else an HTTP server.
+pub fn open_source(
+    location: &str,
+    downloader: &Downloader,
+    options: &SourceOptions,
+) -> Result<Arc<dyn ReplicationSource>> {
+    if let Some(bucket) = location.strip_prefix("s3://") {
+        return Ok(Arc::new(S3Source::new(
+            bucket,
+            &options.s3,
+            downloader.clone(),
+        )?));
+    }
+    if let Some(region) = location.strip_prefix("geofabrik:") {
+        return Ok(Arc::new(GeofabrikSource::new(
+            region,
+            options.geofabrik_cookie.clone(),
+            downloader.clone(),
+        )?));
+    }
+    if let Some(path) = location.strip_prefix("file://") {
+        return Ok(Arc::new(DirSource::new(path)));
+    }
+    if !location.contains("://") {
+        return Ok(Arc::new(DirSource::new(location)));
+    }
+    Ok(Arc::new(HttpSource::new(location, downloader.clone())))
+}
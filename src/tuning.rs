@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+/// The number of replication files a decision is based on
+const SAMPLES_PER_DECISION: usize = 4;
+
+/// The range the prefetch depth may be tuned in
+#[derive(Debug, Clone, Copy)]
+pub struct TuningBounds {
+    pub min_prefetch: usize,
+    pub max_prefetch: usize,
+}
+
+/// A change of the prefetch depth
+#[derive(Debug, Clone, PartialEq)]
+pub struct TuningDecision {
+    pub prefetch_depth: usize,
+    pub reason: String,
+}
+
+/// Adjusts how many upcoming replication files are downloaded in the background
+///
+/// The controller compares the time the replay waits for downloads with the time it
+/// spends applying files. While the replay mostly waits for the network, more files are
+/// downloaded ahead. Once waiting becomes negligible, the depth is lowered again so
+/// fewer files pile up in the cache and the server sees fewer parallel requests.
+#[derive(Debug)]
+pub struct ThroughputController {
+    bounds: TuningBounds,
+    prefetch_depth: usize,
+    waits: Vec<Duration>,
+    applies: Vec<Duration>,
+}
+
+impl ThroughputController {
+    pub fn new(bounds: TuningBounds) -> Self {
+        ThroughputController {
+            bounds,
+            prefetch_depth: bounds.min_prefetch,
+            waits: Vec::new(),
+            applies: Vec::new(),
+        }
+    }
+
+    /// Change the range the prefetch depth is tuned in, moving the depth into it
+    pub fn set_bounds(&mut self, bounds: TuningBounds) {
+        self.bounds = bounds;
+        self.prefetch_depth = self
+            .prefetch_depth
+            .clamp(bounds.min_prefetch, bounds.max_prefetch);
+    }
+
+    /// The number of upcoming replication files to download in the background
+    pub fn prefetch_depth(&self) -> usize {
+        self.prefetch_depth
+    }
+
+    /// Record the latencies of one replication file
+    ///
+    /// # Arguments
+    ///
+    /// * `wait` - How long the replay was blocked on downloading the file
+    /// * `apply` - How long applying the file to the git repo took
+    pub fn record(&mut self, wait: Duration, apply: Duration) -> Option<TuningDecision> {
+        self.waits.push(wait);
+        self.applies.push(apply);
+        if self.waits.len() < SAMPLES_PER_DECISION {
+            return None;
+        }
+
+        let wait = self.waits.drain(..).sum::<Duration>() / SAMPLES_PER_DECISION as u32;
+        let apply = self.applies.drain(..).sum::<Duration>() / SAMPLES_PER_DECISION as u32;
+        // The gap between both thresholds keeps the depth from flapping
+        if wait > apply / 4 && self.prefetch_depth < self.bounds.max_prefetch {
+            self.prefetch_depth += 1;
+            Some(TuningDecision {
+                prefetch_depth: self.prefetch_depth,
+                reason: format!(
+                    "downloads are the bottleneck ({:?} waiting vs. {:?} applying per file)",
+                    wait, apply
+                ),
+            })
+        } else if wait < apply / 20 && self.prefetch_depth > self.bounds.min_prefetch {
+            self.prefetch_depth -= 1;
+            Some(TuningDecision {
+                prefetch_depth: self.prefetch_depth,
+                reason: format!(
+                    "applying is the bottleneck ({:?} waiting vs. {:?} applying per file)",
+                    wait, apply
+                ),
+            })
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::BTreeSet;
+
+use color_eyre::eyre::Result;
+use git2::Repository;
+
+use crate::{
+    git::{
+        notes::{applied_sequences, read_sequence_tag},
+        read_state, STAGING_REF,
+    },
+    osm::{osm_data::OSMObject, snapshot::Snapshot},
+};
+
+/// The findings of `osm-git verify`
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// The number of objects which were read
+    pub objects: usize,
+    /// References to nodes, ways or relations which are not in the git repo
+    ///
+    /// These are expected if the replay didn't start at the very first sequence, as the
+    /// referenced objects were created before the replay started.
+    pub dangling_references: usize,
+    /// Object files which are not in the canonical form of their format, like files
+    /// written by older versions. They are rewritten the next time their object changes,
+    /// which shows up as a diff of the whole file
+    pub non_canonical_files: usize,
+    /// Inconsistencies which need to be repaired
+    pub problems: Vec<String>,
+}
+
+/// Check the git repo for inconsistencies
+///
+/// * Every object file at the snapshot has to be readable and stored where the layout
+///   expects it. Files which are not in the canonical form are counted.
+/// * Every commit recorded in a sequence tag has to be part of the published history.
+/// * The last applied sequence has to be tagged.
+pub fn verify_repository(repository: &Repository, snapshot: &Snapshot) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+
+    let mut objects = Vec::new();
+    for (path, object) in snapshot.objects()? {
+        match object {
+            Ok(object) => {
+                let expected_path =
+                    snapshot
+                        .layout()
+                        .path(object.type_name(), object.id(), snapshot.id_mapper());
+                if expected_path != path {
+                    report.problems.push(format!(
+                        "{} {} is stored at {} instead of {}",
+                        object.type_name(),
+                        object.id(),
+                        path.display(),
+                        expected_path.display()
+                    ));
+                }
+                objects.push(object);
+            }
+            Err(err) => report
+                .problems
+                .push(format!("Unable to read {}: {}", path.display(), err)),
+        }
+    }
+    report.objects = objects.len();
+    report.non_canonical_files = snapshot.non_canonical_files()?.len();
+
+    let existing = objects
+        .iter()
+        .map(|object| (object.type_name(), object.id()))
+        .collect::<BTreeSet<(&str, u64)>>();
+    for object in &objects {
+        let references: Vec<(&str, u64)> = match object {
+            OSMObject::Node(_) => Vec::new(),
+            OSMObject::Way(way) => way.nodes.iter().map(|node| ("node", *node)).collect(),
+            OSMObject::Relation(relation) => relation
+                .member
+                .iter()
+                .map(|member| (member.r#type.as_str(), member.ref_id))
+                .collect(),
+        };
+        report.dangling_references += references
+            .iter()
+            .filter(|reference| !existing.contains(reference))
+            .count();
+    }
+
+    let head = repository.head()?.peel_to_commit()?.id();
+    let sequences = applied_sequences(repository)?;
+    for sequence in &sequences {
+        for (changeset_id, commit) in read_sequence_tag(repository, sequence)?.unwrap_or_default() {
+            if commit != head
+                && !repository
+                    .graph_descendant_of(head, commit)
+                    .unwrap_or(false)
+            {
+                report.problems.push(format!(
+                    "Commit {} of changeset {} in sequence {} is not part of the history",
+                    commit, changeset_id, sequence
+                ));
+            }
+        }
+    }
+
+    if let Some(last_applied) = read_state(repository)? {
+        if !sequences.contains(&last_applied) {
+            report.problems.push(format!(
+                "The last applied sequence {} is not tagged",
+                last_applied
+            ));
+        }
+    }
+    if repository.find_reference(STAGING_REF).is_ok() {
+        report.problems.push(format!(
+            "{} exists. A replay was interrupted and will be resumed by the next run",
+            STAGING_REF
+        ));
+    }
+
+    Ok(report)
+}
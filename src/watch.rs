@@ -0,0 +1,251 @@
+use std::{collections::BTreeMap, fs::OpenOptions, io::Write};
+
+use color_eyre::eyre::{eyre, Result};
+use git2::Oid;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+#[cfg(feature = "notifications")]
+use tracing::warn;
+
+use crate::osm::osm_data::OSMObject;
+
+/// A rule selecting the objects to watch
+///
+/// An object matches if it has all the `tags` of the rule or is listed in `objects`.
+#[derive(Debug, Deserialize)]
+struct WatchRule {
+    name: String,
+    /// Tags an object needs to have. A value of `*` matches any value
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    /// Objects like `node/123` or `relation/62422`
+    #[serde(default)]
+    objects: Vec<String>,
+}
+
+impl WatchRule {
+    fn matches(&self, object: &OSMObject) -> bool {
+        let object_name = format!("{}/{}", object.type_name(), object.id());
+        if self.objects.contains(&object_name) {
+            return true;
+        }
+        !self.tags.is_empty()
+            && self.tags.iter().all(|(key, value)| {
+                object
+                    .tags()
+                    .get(key)
+                    .is_some_and(|object_value| value == "*" || object_value == value)
+            })
+    }
+}
+
+/// A Matrix room to send alerts to
+#[cfg(feature = "notifications")]
+#[derive(Debug, Deserialize)]
+struct MatrixRoom {
+    /// The base URL of the homeserver like `https://matrix.org`
+    homeserver: String,
+    /// The id of the room like `!abcdef:matrix.org`
+    room_id: String,
+    access_token: String,
+}
+
+/// The watch rules and where to report their matches, read from a YAML file. The
+/// `webhook` and `matrix` alerts need the `notifications` feature:
+///
+/// ```yaml
+/// rules:
+///   - name: admin boundaries
+///     tags:
+///       boundary: administrative
+///   - name: london
+///     objects: [relation/65606]
+/// report: watch-report.yaml
+/// webhook: https://example.com/osm-watch
+/// matrix:
+///   homeserver: https://matrix.org
+///   room_id: "!abcdef:matrix.org"
+///   access_token: syt_...
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct WatchConfig {
+    rules: Vec<WatchRule>,
+    /// The file the matches are appended to as a YAML list
+    report: String,
+    /// A URL every match is POSTed to as JSON
+    #[cfg(feature = "notifications")]
+    webhook: Option<String>,
+    #[cfg(feature = "notifications")]
+    matrix: Option<MatrixRoom>,
+}
+
+impl WatchConfig {
+    pub fn load(path: &str) -> Result<Self> {
+        let config: WatchConfig = serde_yaml::from_reader(std::fs::File::open(path)?)?;
+        if let Some(rule) = config
+            .rules
+            .iter()
+            .find(|rule| rule.tags.is_empty() && rule.objects.is_empty())
+        {
+            return Err(eyre!(
+                "Watch rule {:?} in {} has neither tags nor objects",
+                rule.name,
+                path
+            ));
+        }
+        info!("Loaded {} watch rules from {}", config.rules.len(), path);
+        Ok(config)
+    }
+
+    /// Split the config into the rules evaluated during the replay and the reporter
+    pub fn split(
+        self,
+        #[cfg(feature = "notifications")] client: reqwest::Client,
+    ) -> (Watchlist, WatchReporter) {
+        (
+            Watchlist { rules: self.rules },
+            WatchReporter {
+                #[cfg(feature = "notifications")]
+                client,
+                report: self.report,
+                #[cfg(feature = "notifications")]
+                webhook: self.webhook,
+                #[cfg(feature = "notifications")]
+                matrix: self.matrix,
+            },
+        )
+    }
+}
+
+/// The rules objects are checked against while they are committed
+#[derive(Debug)]
+pub struct Watchlist {
+    rules: Vec<WatchRule>,
+}
+
+impl Watchlist {
+    /// The matches of an object changed by `action` (`create`, `modify` or `delete`)
+    pub fn matches(
+        &self,
+        object: &OSMObject,
+        action: &'static str,
+        changeset: u64,
+        commit: Oid,
+    ) -> Vec<WatchMatch> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(object))
+            .map(|rule| WatchMatch {
+                rule: rule.name.clone(),
+                action,
+                object_type: object.type_name(),
+                id: object.id(),
+                changeset,
+                commit: commit.to_string(),
+            })
+            .collect()
+    }
+}
+
+/// A change of a watched object
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WatchMatch {
+    pub rule: String,
+    pub action: &'static str,
+    pub object_type: &'static str,
+    pub id: u64,
+    pub changeset: u64,
+    pub commit: String,
+}
+
+impl std::fmt::Display for WatchMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[{}] {} {}/{} in changeset {} ({})",
+            self.rule, self.action, self.object_type, self.id, self.changeset, self.commit
+        )
+    }
+}
+
+#[cfg(feature = "notifications")]
+#[derive(Debug, Serialize)]
+struct MatrixMessage<'a> {
+    msgtype: &'static str,
+    body: &'a str,
+}
+
+/// Writes matches to the report file and sends the alerts
+pub struct WatchReporter {
+    #[cfg(feature = "notifications")]
+    client: reqwest::Client,
+    report: String,
+    #[cfg(feature = "notifications")]
+    webhook: Option<String>,
+    #[cfg(feature = "notifications")]
+    matrix: Option<MatrixRoom>,
+}
+
+impl WatchReporter {
+    /// Report a match
+    ///
+    /// Failing to send an alert is only logged, so an unreachable endpoint does not stop
+    /// the replay. The match is still in the report file.
+    pub async fn report(&self, watch_match: &WatchMatch) -> Result<()> {
+        let mut report = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.report)?;
+        report.write_all(serde_yaml::to_string(&[watch_match])?.as_bytes())?;
+
+        #[cfg(feature = "notifications")]
+        if let Some(webhook) = &self.webhook {
+            let response = self.client.post(webhook).json(watch_match).send().await;
+            if let Err(err) = response.and_then(|response| response.error_for_status()) {
+                warn!("Unable to send watch alert to {}: {}", webhook, err);
+            }
+        }
+
+        #[cfg(feature = "notifications")]
+        if let Some(matrix) = &self.matrix {
+            // The transaction id makes retries of the same message idempotent
+            let url = format!(
+                "{}/_matrix/client/v3/rooms/{}/send/m.room.message/osm-git-{}-{}-{}-{}",
+                matrix.homeserver.trim_end_matches('/'),
+                encode_path_segment(&matrix.room_id),
+                watch_match.commit,
+                watch_match.object_type,
+                watch_match.id,
+                encode_path_segment(&watch_match.rule)
+            );
+            let response = self
+                .client
+                .put(&url)
+                .bearer_auth(&matrix.access_token)
+                .json(&MatrixMessage {
+                    msgtype: "m.text",
+                    body: &watch_match.to_string(),
+                })
+                .send()
+                .await;
+            if let Err(err) = response.and_then(|response| response.error_for_status()) {
+                warn!("Unable to send watch alert to {}: {}", matrix.room_id, err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Percent-encode everything but unreserved characters
+#[cfg(feature = "notifications")]
+fn encode_path_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
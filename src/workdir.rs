@@ -0,0 +1,317 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
+
+use color_eyre::eyre::{eyre, Result};
+use git2::{ObjectType, Oid, Repository, Status, StatusOptions};
+use notify::{RecursiveMode, Watcher};
+use tracing::{debug, warn};
+
+use crate::osm::{
+    id_mapping::IdMapper,
+    layout::{Layout, ShardBudget},
+    osm_data::{OSMObject, FILE_VERSION},
+};
+
+/// How long the working directory has to be quiet before changed files are checked, as
+/// editors often write a file in several steps
+const SETTLE_TIME: Duration = Duration::from_millis(300);
+/// The most nodes the OSM API accepts in a way
+const MAX_WAY_NODES: usize = 2000;
+/// The most characters the OSM API accepts in a tag key or value
+const MAX_TAG_LENGTH: usize = 255;
+
+/// What happened to a changed file of the working directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditOutcome {
+    /// The file is well-formed and was staged
+    Staged,
+    /// The file was deleted and its removal staged
+    Removed,
+    /// The file was left unstaged because of these problems
+    Rejected(Vec<String>),
+    /// The file is not an object file, or the index has it already
+    Ignored,
+}
+
+/// Checks manual edits of the object files in the working directory of the git repo and
+/// stages the well-formed ones
+///
+/// This supports using the git repo as an editor: objects are edited with any text
+/// editor, and only edits which the OSM API would accept end up in the index.
+pub struct EditValidator<'a> {
+    repository: &'a Repository,
+    id_mapper: &'a dyn IdMapper,
+    layout: Layout,
+    workdir: PathBuf,
+}
+
+impl<'a> EditValidator<'a> {
+    pub fn new(
+        repository: &'a Repository,
+        id_mapper: &'a dyn IdMapper,
+        budget: ShardBudget,
+    ) -> Result<Self> {
+        let workdir = repository
+            .workdir()
+            .map(Path::to_path_buf)
+            .ok_or_else(|| eyre!("The git repository has no working directory"))?;
+        Ok(EditValidator {
+            repository,
+            id_mapper,
+            layout: Layout::load(repository, budget)?,
+            workdir,
+        })
+    }
+
+    /// The id of the object stored in a file, `None` if it is not an object file
+    fn object_id(&self, path: &Path) -> Option<u64> {
+        if path.starts_with(".git")
+            || path.starts_with("meta")
+            || path.extension().and_then(|e| e.to_str())
+                != Some(self.layout.serializer().extension())
+        {
+            return None;
+        }
+        path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| self.id_mapper.id_from_stem(stem))
+    }
+
+    /// The problems of an edited object file, empty if it is well-formed
+    ///
+    /// The file has to match the schema of the current file version, be stored where the
+    /// layout expects its object and stay within the limits of the OSM API. Nodes, ways
+    /// and relations the object references have to exist in the working directory,
+    /// except for references the committed version of the object had already, as a
+    /// mirror which didn't start at the first sequence misses some of them.
+    pub fn validate(&self, path: &Path, contents: &[u8]) -> Vec<String> {
+        let Some(id) = self.object_id(path) else {
+            return vec![format!("{} is not an object file", path.display())];
+        };
+        let mut object = match self.layout.serializer().deserialize(contents) {
+            Ok(object) => object,
+            Err(err) => return vec![format!("The file does not match the schema: {}", err)],
+        };
+        object.set_id(id);
+
+        let mut problems = Vec::new();
+        let expected_path = self.layout.object_path(&object, self.id_mapper);
+        if expected_path != path {
+            problems.push(format!(
+                "{} {} belongs at {}",
+                object.type_name(),
+                id,
+                expected_path.display()
+            ));
+        }
+        if file_version(&object) != FILE_VERSION {
+            problems.push(format!(
+                "The file version is {} instead of {}",
+                file_version(&object),
+                FILE_VERSION
+            ));
+        }
+        for (key, value) in object.tags() {
+            if key.is_empty() {
+                problems.push("A tag has an empty key".to_string());
+            }
+            if key.chars().count() > MAX_TAG_LENGTH || value.chars().count() > MAX_TAG_LENGTH {
+                problems.push(format!(
+                    "The tag {} is longer than {} characters",
+                    key, MAX_TAG_LENGTH
+                ));
+            }
+        }
+        match &object {
+            OSMObject::Node(node) => {
+                if !(-90.0..=90.0).contains(&node.lat) || !(-180.0..=180.0).contains(&node.lon) {
+                    problems.push(format!(
+                        "The coordinates {}, {} are out of range",
+                        node.lat, node.lon
+                    ));
+                }
+            }
+            OSMObject::Way(way) => {
+                if way.nodes.is_empty() {
+                    problems.push("The way has no nodes".to_string());
+                }
+                if way.nodes.len() > MAX_WAY_NODES {
+                    problems.push(format!(
+                        "The way has {} nodes, more than the {} the OSM API accepts",
+                        way.nodes.len(),
+                        MAX_WAY_NODES
+                    ));
+                }
+            }
+            OSMObject::Relation(relation) => {
+                for member in &relation.member {
+                    if !matches!(member.r#type.as_str(), "node" | "way" | "relation") {
+                        problems.push(format!(
+                            "The member {} has the unknown type {}",
+                            member.ref_id, member.r#type
+                        ));
+                    }
+                }
+            }
+        }
+
+        let committed = self.committed_references(path);
+        for (type_name, id) in references(&object) {
+            if committed.contains(&(type_name.clone(), id))
+                || !matches!(type_name.as_str(), "node" | "way" | "relation")
+            {
+                continue;
+            }
+            let referenced = self.layout.path(&type_name, id, self.id_mapper);
+            if !self.workdir.join(referenced).exists() {
+                problems.push(format!(
+                    "References {} {} which does not exist",
+                    type_name, id
+                ));
+            }
+        }
+        problems
+    }
+
+    /// The references of the version of an object file at HEAD
+    fn committed_references(&self, path: &Path) -> HashSet<(String, u64)> {
+        let committed = || -> Result<OSMObject> {
+            let tree = self.repository.head()?.peel_to_tree()?;
+            let blob = tree
+                .get_path(path)?
+                .to_object(self.repository)?
+                .peel_to_blob()?;
+            self.layout.serializer().deserialize(blob.content())
+        };
+        committed()
+            .map(|object| references(&object).into_iter().collect())
+            .unwrap_or_default()
+    }
+
+    /// Check a changed file of the working directory and stage it if it is well-formed
+    ///
+    /// `path` is relative to the working directory. Deleted object files have their
+    /// removal staged without looking for objects still referencing them, which
+    /// `osm-git verify` counts as dangling references.
+    pub fn stage(&self, path: &Path) -> Result<EditOutcome> {
+        if self.object_id(path).is_none() {
+            return Ok(EditOutcome::Ignored);
+        }
+        let mut index = self.repository.index()?;
+        let file = self.workdir.join(path);
+        if !file.exists() {
+            if index.get_path(path, 0).is_none() {
+                return Ok(EditOutcome::Ignored);
+            }
+            index.remove_path(path)?;
+            index.write()?;
+            return Ok(EditOutcome::Removed);
+        }
+
+        let contents = std::fs::read(&file)?;
+        if index.get_path(path, 0).is_some_and(|entry| {
+            Oid::hash_object(ObjectType::Blob, &contents).is_ok_and(|id| id == entry.id)
+        }) {
+            return Ok(EditOutcome::Ignored);
+        }
+        let problems = self.validate(path, &contents);
+        if !problems.is_empty() {
+            return Ok(EditOutcome::Rejected(problems));
+        }
+        index.add_path(path)?;
+        index.write()?;
+        Ok(EditOutcome::Staged)
+    }
+
+    /// The files of the working directory which differ from the index
+    fn unstaged_files(&self) -> Result<Vec<PathBuf>> {
+        let mut options = StatusOptions::new();
+        options.include_untracked(true).recurse_untracked_dirs(true);
+        let statuses = self.repository.statuses(Some(&mut options))?;
+        Ok(statuses
+            .iter()
+            .filter(|entry| {
+                entry
+                    .status()
+                    .intersects(Status::WT_NEW | Status::WT_MODIFIED | Status::WT_DELETED)
+            })
+            .filter_map(|entry| entry.path().map(PathBuf::from))
+            .collect())
+    }
+}
+
+fn file_version(object: &OSMObject) -> &str {
+    match object {
+        OSMObject::Node(node) => &node.file_version,
+        OSMObject::Way(way) => &way.file_version,
+        OSMObject::Relation(relation) => &relation.file_version,
+    }
+}
+
+/// The objects an object references, as type name and id
+fn references(object: &OSMObject) -> Vec<(String, u64)> {
+    match object {
+        OSMObject::Node(_) => Vec::new(),
+        OSMObject::Way(way) => way
+            .nodes
+            .iter()
+            .map(|node| ("node".to_string(), *node))
+            .collect(),
+        OSMObject::Relation(relation) => relation
+            .member
+            .iter()
+            .map(|member| (member.r#type.clone(), member.ref_id))
+            .collect(),
+    }
+}
+
+/// Check and stage the edits of the object files in the working directory until the
+/// process is stopped
+///
+/// Files which were changed before the watch started are checked first. `on_edit` is
+/// called with the outcome of every changed object file.
+pub fn watch_workdir(
+    validator: &EditValidator,
+    on_edit: &mut dyn FnMut(&Path, &EditOutcome),
+) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(&validator.workdir, RecursiveMode::Recursive)?;
+
+    let mut changed = validator
+        .unstaged_files()?
+        .into_iter()
+        .collect::<BTreeSet<PathBuf>>();
+    loop {
+        for path in std::mem::take(&mut changed) {
+            match validator.stage(&path) {
+                Ok(EditOutcome::Ignored) => (),
+                Ok(outcome) => on_edit(&path, &outcome),
+                Err(err) => warn!("Unable to check {}: {}", path.display(), err),
+            }
+        }
+
+        // Wait for the next change, then until the working directory is quiet
+        let mut event = receiver.recv()?;
+        loop {
+            match event {
+                Ok(event) => changed.extend(event.paths.iter().filter_map(|path| {
+                    path.strip_prefix(&validator.workdir)
+                        .ok()
+                        .filter(|path| !path.starts_with(".git"))
+                        .map(Path::to_path_buf)
+                })),
+                Err(err) => warn!("Unable to watch the working directory: {}", err),
+            }
+            match receiver.recv_timeout(SETTLE_TIME) {
+                Ok(next) => event = next,
+                Err(_) => break,
+            }
+        }
+        debug!("{} files changed", changed.len());
+    }
+}
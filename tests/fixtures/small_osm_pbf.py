@@ -0,0 +1,141 @@
+#!/usr/bin/env python3
+"""Writes small.osm.pbf, a hand-built OSM PBF file for the tests of src/osm/pbf.rs
+
+The protobuf messages are encoded by hand, independently of the prost messages of the
+reader, following fileformat.proto and osmformat.proto:
+
+* an OSMHeader block with replication metadata
+* a zlib compressed OSMData block with three dense nodes and a way
+* a block of an unknown type, which readers skip
+* an uncompressed OSMData block with a plain node, using a custom granularity and
+  offset, and a relation
+"""
+import struct
+import zlib
+from pathlib import Path
+
+
+def varint(value):
+    out = bytearray()
+    value &= (1 << 64) - 1
+    while True:
+        byte = value & 0x7F
+        value >>= 7
+        if value:
+            out.append(byte | 0x80)
+        else:
+            out.append(byte)
+            return bytes(out)
+
+
+def zigzag(value):
+    return (value << 1) ^ (value >> 63)
+
+
+def field(number, value):
+    """A varint field"""
+    return varint(number << 3) + varint(value)
+
+
+def sint(number, value):
+    return field(number, zigzag(value))
+
+
+def message(number, payload):
+    """A length delimited field"""
+    if isinstance(payload, str):
+        payload = payload.encode()
+    return varint(number << 3 | 2) + varint(len(payload)) + payload
+
+
+def packed(number, values, signed=False):
+    return message(number, b"".join(varint(zigzag(v) if signed else v) for v in values))
+
+
+def deltas(values):
+    return [value - previous for previous, value in zip([0] + values, values)]
+
+
+def blob(block_type, data, compress):
+    if compress:
+        body = field(2, len(data)) + message(3, zlib.compress(data))
+    else:
+        body = message(1, data)
+    header = message(1, block_type) + field(3, len(body))
+    return struct.pack(">I", len(header)) + header + body
+
+
+STRINGS = ["", "alice", "amenity", "cafe", "name", "Café", "highway", "residential",
+           "outer", "type", "multipolygon", "bob"]
+S = {string: index for index, string in enumerate(STRINGS)}
+string_table = message(1, b"".join(message(1, s) for s in STRINGS))
+
+header = (
+    message(4, "OsmSchema-V0.6")
+    + message(4, "DenseNodes")
+    + message(16, "osm-git fixture")
+    + field(32, 1347408000)
+    + field(33, 1234)
+    + message(34, "https://planet.openstreetmap.org/replication/minute")
+)
+
+# Nodes 1 to 3 at 51.5,-0.1, 51.5000001,-0.1000002 and 51.4999999,-0.0999999 with the
+# default granularity of 100 nanodegrees. Node 3 is anonymous
+dense_info = (
+    packed(1, [1, 2, 1])
+    + packed(2, deltas([1347408000, 1347408060, 1347408120]), signed=True)
+    + packed(3, deltas([10, 11, 11]), signed=True)
+    + packed(4, deltas([5, 5, 0]), signed=True)
+    + packed(5, deltas([S["alice"], S["alice"], 0]), signed=True)
+)
+dense = (
+    packed(1, deltas([1, 2, 3]), signed=True)
+    + message(5, dense_info)
+    + packed(8, deltas([515000000, 515000001, 514999999]), signed=True)
+    + packed(9, deltas([-1000000, -1000002, -999999]), signed=True)
+    + packed(10, [S["amenity"], S["cafe"], S["name"], S["Café"], 0, 0, 0])
+)
+way_info = field(1, 1) + field(2, 1347408120) + field(3, 12) + field(4, 5) + field(5, S["alice"])
+way = (
+    field(1, 10)
+    + packed(2, [S["highway"]])
+    + packed(3, [S["residential"]])
+    + message(4, way_info)
+    + packed(8, deltas([1, 2, 3]), signed=True)
+)
+first_block = string_table + message(2, message(2, dense) + message(3, way))
+
+# Granularity 1000 with an offset of one degree: 1 + 50.5 = 51.5
+node_info = field(1, 2) + field(2, 1347408180) + field(3, 13) + field(4, 6) + field(5, S["bob"])
+node = (
+    sint(1, 4)
+    + packed(2, [S["amenity"]])
+    + packed(3, [S["cafe"]])
+    + message(4, node_info)
+    + sint(8, 50500000)
+    + sint(9, -100000)
+)
+relation_info = field(1, 3) + field(2, 1347408240) + field(3, 13) + field(4, 6) + field(5, S["bob"])
+relation = (
+    field(1, 20)
+    + packed(2, [S["type"]])
+    + packed(3, [S["multipolygon"]])
+    + message(4, relation_info)
+    + packed(8, [S["outer"], 0])
+    + packed(9, deltas([10, 1]), signed=True)
+    + packed(10, [1, 0])
+)
+second_block = (
+    string_table
+    + message(2, message(1, node) + message(4, relation))
+    + field(17, 1000)
+    + field(19, 1000000000)
+)
+
+data = (
+    blob("OSMHeader", header, compress=True)
+    + blob("OSMData", first_block, compress=True)
+    + blob("OSMUnknown", b"skipped", compress=False)
+    + blob("OSMData", second_block, compress=False)
+)
+Path(__file__).with_name("small.osm.pbf").write_bytes(data)